@@ -24,8 +24,12 @@ use zeroize::Zeroizing;
 
 use super::{client::Client, RUNTIME};
 use crate::{
-    authentication::OidcConfiguration, client::ClientSessionDelegate, error::ClientError,
-    helpers::unwrap_or_clone_arc, task_handle::TaskHandle,
+    authentication::OidcConfiguration,
+    client::ClientSessionDelegate,
+    encryption::{CryptoMetricsBridge, CryptoMetricsListener},
+    error::ClientError,
+    helpers::unwrap_or_clone_arc,
+    task_handle::TaskHandle,
 };
 
 /// A list of bytes containing a certificate in DER or PEM form.
@@ -267,6 +271,7 @@ pub struct ClientBuilder {
     encryption_settings: EncryptionSettings,
     room_key_recipient_strategy: CollectStrategy,
     decryption_trust_requirement: TrustRequirement,
+    crypto_metrics: Option<Arc<CryptoMetricsBridge>>,
     request_config: Option<RequestConfig>,
 }
 
@@ -293,9 +298,12 @@ impl ClientBuilder {
                 backup_download_strategy:
                     matrix_sdk::encryption::BackupDownloadStrategy::AfterDecryptionFailure,
                 auto_enable_backups: false,
+                share_history_on_invite: false,
+                encrypt_state_events: false,
             },
             room_key_recipient_strategy: Default::default(),
             decryption_trust_requirement: TrustRequirement::Untrusted,
+            crypto_metrics: None,
             request_config: Default::default(),
         })
     }
@@ -443,6 +451,22 @@ impl ClientBuilder {
         Arc::new(builder)
     }
 
+    /// Share the keys of a room's history with the users we invite to it, if
+    /// the history of the room is visible to invited users.
+    pub fn share_history_on_invite(self: Arc<Self>, share_history_on_invite: bool) -> Arc<Self> {
+        let mut builder = unwrap_or_clone_arc(self);
+        builder.encryption_settings.share_history_on_invite = share_history_on_invite;
+        Arc::new(builder)
+    }
+
+    /// Encrypt the state events we send in encrypted rooms, except for the
+    /// ones the server needs to read to authorize events.
+    pub fn encrypt_state_events(self: Arc<Self>, encrypt_state_events: bool) -> Arc<Self> {
+        let mut builder = unwrap_or_clone_arc(self);
+        builder.encryption_settings.encrypt_state_events = encrypt_state_events;
+        Arc::new(builder)
+    }
+
     /// Set the strategy to be used for picking recipient devices when sending
     /// an encrypted message.
     pub fn room_key_recipient_strategy(self: Arc<Self>, strategy: CollectStrategy) -> Arc<Self> {
@@ -461,6 +485,17 @@ impl ClientBuilder {
         Arc::new(builder)
     }
 
+    /// Set the listener receiving the metrics collected about the
+    /// cryptographic operations of the client.
+    pub fn crypto_metrics_listener(
+        self: Arc<Self>,
+        listener: Box<dyn CryptoMetricsListener>,
+    ) -> Arc<Self> {
+        let mut builder = unwrap_or_clone_arc(self);
+        builder.crypto_metrics = Some(Arc::new(CryptoMetricsBridge(listener)));
+        Arc::new(builder)
+    }
+
     /// Add a default request config to this client.
     pub fn request_config(self: Arc<Self>, config: RequestConfig) -> Arc<Self> {
         let mut builder = unwrap_or_clone_arc(self);
@@ -563,6 +598,10 @@ impl ClientBuilder {
             .with_room_key_recipient_strategy(builder.room_key_recipient_strategy)
             .with_decryption_trust_requirement(builder.decryption_trust_requirement);
 
+        if let Some(crypto_metrics) = builder.crypto_metrics {
+            inner_builder = inner_builder.with_crypto_metrics(crypto_metrics);
+        }
+
         match builder.sliding_sync_version_builder {
             SlidingSyncVersionBuilder::None => {
                 inner_builder = inner_builder
@@ -1,7 +1,8 @@
-use std::sync::Arc;
+use std::{collections::HashMap, fmt, sync::Arc};
 
 use futures_util::StreamExt;
 use matrix_sdk::{
+    crypto::metrics::{CryptoCounter, CryptoHistogram, CryptoMetrics},
     encryption,
     encryption::{backups, recovery},
 };
@@ -44,6 +45,45 @@ pub trait VerificationStateListener: Sync + Send {
     fn on_update(&self, status: VerificationState);
 }
 
+/// A listener receiving the metrics collected about the cryptographic
+/// operations of the client, to be forwarded to a metrics system.
+///
+/// The listener is called inline, it should therefore return quickly.
+#[matrix_sdk_ffi_macros::export(callback_interface)]
+pub trait CryptoMetricsListener: Sync + Send {
+    /// The counter with the given name and labels was incremented by `value`.
+    fn on_counter(&self, name: String, labels: HashMap<String, String>, value: u64);
+
+    /// A new observation was recorded for the histogram with the given name
+    /// and labels.
+    fn on_histogram(&self, name: String, labels: HashMap<String, String>, value: f64);
+}
+
+/// Forwards the metrics of the SDK to a [`CryptoMetricsListener`].
+pub(crate) struct CryptoMetricsBridge(pub(crate) Box<dyn CryptoMetricsListener>);
+
+impl fmt::Debug for CryptoMetricsBridge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CryptoMetricsBridge").finish_non_exhaustive()
+    }
+}
+
+impl CryptoMetrics for CryptoMetricsBridge {
+    fn increment_counter(&self, counter: CryptoCounter, value: u64) {
+        let labels = labels_to_map(counter.labels());
+        self.0.on_counter(counter.name().to_owned(), labels, value);
+    }
+
+    fn observe_histogram(&self, histogram: CryptoHistogram, value: f64) {
+        let labels = labels_to_map(histogram.labels());
+        self.0.on_histogram(histogram.name().to_owned(), labels, value);
+    }
+}
+
+fn labels_to_map(labels: Vec<(&'static str, &'static str)>) -> HashMap<String, String> {
+    labels.into_iter().map(|(key, value)| (key.to_owned(), value.to_owned())).collect()
+}
+
 #[derive(uniffi::Enum)]
 pub enum BackupUploadState {
     Waiting,
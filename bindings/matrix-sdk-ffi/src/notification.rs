@@ -76,6 +76,24 @@ impl NotificationItem {
     }
 }
 
+/// A notification to fetch with [`NotificationClient::get_notifications`].
+#[derive(uniffi::Record)]
+pub struct NotificationRequest {
+    pub room_id: String,
+    pub event_id: String,
+}
+
+/// The result of fetching one of the notifications requested with
+/// [`NotificationClient::get_notifications`].
+#[derive(uniffi::Enum)]
+pub enum BatchNotificationResult {
+    /// The notification has been resolved; it's `None` if it's been filtered
+    /// out by the user's push rules.
+    Ok { notification: Option<NotificationItem> },
+    /// The notification couldn't be resolved.
+    Error { message: String },
+}
+
 #[derive(uniffi::Object)]
 pub struct NotificationClient {
     pub(crate) inner: MatrixNotificationClient,
@@ -109,4 +127,36 @@ impl NotificationClient {
             Ok(None)
         }
     }
+
+    /// Fetch several notifications at once, returning one result per request,
+    /// in the same order.
+    ///
+    /// See also documentation of
+    /// `MatrixNotificationClient::get_notifications`.
+    pub async fn get_notifications(
+        &self,
+        requests: Vec<NotificationRequest>,
+    ) -> Result<Vec<BatchNotificationResult>, ClientError> {
+        let requests = requests
+            .into_iter()
+            .map(|request| Ok((RoomId::parse(request.room_id)?, EventId::parse(request.event_id)?)))
+            .collect::<Result<Vec<_>, ClientError>>()?;
+
+        let requests = requests
+            .iter()
+            .map(|(room_id, event_id)| (room_id.as_ref(), event_id.as_ref()))
+            .collect::<Vec<_>>();
+
+        let results = self.inner.get_notifications(&requests).await.map_err(ClientError::from)?;
+
+        Ok(results
+            .into_iter()
+            .map(|result| match result {
+                Ok(item) => BatchNotificationResult::Ok {
+                    notification: item.map(NotificationItem::from_inner),
+                },
+                Err(err) => BatchNotificationResult::Error { message: err.to_string() },
+            })
+            .collect())
+    }
 }
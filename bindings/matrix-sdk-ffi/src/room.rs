@@ -485,6 +485,14 @@ impl Room {
         Ok(())
     }
 
+    /// Share the keys of this room's history with the given user, so that they
+    /// can decrypt the messages that were sent before they joined.
+    pub async fn share_history(&self, user_id: String) -> Result<(), ClientError> {
+        let user_id = UserId::parse(&user_id)?;
+        self.inner.share_history(&user_id).await?;
+        Ok(())
+    }
+
     pub async fn can_user_redact_own(&self, user_id: String) -> Result<bool, ClientError> {
         let user_id = UserId::parse(&user_id)?;
         Ok(self.inner.can_user_redact_own(&user_id).await?)
@@ -616,6 +624,16 @@ impl Room {
         })))
     }
 
+    /// Pin the current identity of all the members of this room whose identity
+    /// changed since it was pinned.
+    ///
+    /// Members whose identity was verified before it changed are left alone.
+    /// Returns the user IDs of the members whose identity got pinned.
+    pub async fn pin_all_changed_identities(&self) -> Result<Vec<String>, ClientError> {
+        let pinned = self.inner.pin_all_changed_identities().await?;
+        Ok(pinned.into_iter().map(|user_id| user_id.to_string()).collect())
+    }
+
     /// Set (or unset) a flag on the room to indicate that the user has
     /// explicitly marked it as unread.
     pub async fn set_unread_flag(&self, new_value: bool) -> Result<(), ClientError> {
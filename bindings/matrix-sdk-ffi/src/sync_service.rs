@@ -18,8 +18,8 @@ use futures_util::pin_mut;
 use matrix_sdk::{crypto::types::events::UtdCause, Client};
 use matrix_sdk_ui::{
     sync_service::{
-        State as MatrixSyncServiceState, SyncService as MatrixSyncService,
-        SyncServiceBuilder as MatrixSyncServiceBuilder,
+        State as MatrixSyncServiceState, SyncMode as MatrixSyncMode,
+        SyncService as MatrixSyncService, SyncServiceBuilder as MatrixSyncServiceBuilder,
     },
     unable_to_decrypt_hook::{
         UnableToDecryptHook, UnableToDecryptInfo as SdkUnableToDecryptInfo, UtdHookManager,
@@ -38,6 +38,7 @@ pub enum SyncServiceState {
     Running,
     Terminated,
     Error,
+    Offline,
 }
 
 impl From<MatrixSyncServiceState> for SyncServiceState {
@@ -47,6 +48,26 @@ impl From<MatrixSyncServiceState> for SyncServiceState {
             MatrixSyncServiceState::Running => Self::Running,
             MatrixSyncServiceState::Terminated => Self::Terminated,
             MatrixSyncServiceState::Error => Self::Error,
+            MatrixSyncServiceState::Offline => Self::Offline,
+        }
+    }
+}
+
+/// The mode in which the sync service runs.
+#[derive(uniffi::Enum)]
+pub enum SyncMode {
+    /// Both the room list sync and the encryption sync run.
+    Foreground,
+    /// Only the encryption sync runs, e.g. while the application is in the
+    /// background.
+    Background,
+}
+
+impl From<SyncMode> for MatrixSyncMode {
+    fn from(value: SyncMode) -> Self {
+        match value {
+            SyncMode::Foreground => Self::Foreground,
+            SyncMode::Background => Self::Background,
         }
     }
 }
@@ -79,6 +100,27 @@ impl SyncService {
         Ok(self.inner.stop().await?)
     }
 
+    /// Change the mode in which the sync service runs.
+    ///
+    /// If the service is running, it's restarted in the new mode; otherwise,
+    /// the new mode is used the next time it's started.
+    pub async fn set_mode(&self, mode: SyncMode) -> Result<(), ClientError> {
+        Ok(self.inner.set_mode(mode.into()).await?)
+    }
+
+    /// Run the syncs once, for at most `deadline_ms` milliseconds, to catch up
+    /// with what happened while the application was suspended.
+    ///
+    /// Fails if the service is running.
+    pub async fn catch_up(&self, deadline_ms: u64) -> Result<(), ClientError> {
+        Ok(self.inner.catch_up(Duration::from_millis(deadline_ms)).await?)
+    }
+
+    /// Report whether the network is reachable, as observed by the platform.
+    pub async fn set_network_reachable(&self, reachable: bool) -> Result<(), ClientError> {
+        Ok(self.inner.set_network_reachable(reachable).await?)
+    }
+
     pub fn state(&self, listener: Box<dyn SyncServiceStateObserver>) -> Arc<TaskHandle> {
         let state_stream = self.inner.state();
 
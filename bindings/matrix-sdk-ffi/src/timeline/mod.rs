@@ -26,6 +26,7 @@ use matrix_sdk::{
         AttachmentConfig, AttachmentInfo, BaseAudioInfo, BaseFileInfo, BaseImageInfo,
         BaseThumbnailInfo, BaseVideoInfo, Thumbnail,
     },
+    crypto::IdentityState,
     deserialized_responses::{ShieldState as SdkShieldState, ShieldStateCode},
     room::edit::EditedContent as SdkEditedContent,
     Error,
@@ -934,6 +935,13 @@ impl TimelineItem {
         match self.0.as_virtual()? {
             VItem::DayDivider(ts) => Some(VirtualTimelineItem::DayDivider { ts: ts.0.into() }),
             VItem::ReadMarker => Some(VirtualTimelineItem::ReadMarker),
+            VItem::IdentityChange { user_id, changed_to, timestamp } => {
+                Some(VirtualTimelineItem::IdentityChange {
+                    user_id: user_id.to_string(),
+                    changed_to: changed_to.clone(),
+                    ts: timestamp.0.into(),
+                })
+            }
         }
     }
 
@@ -1210,6 +1218,17 @@ pub enum VirtualTimelineItem {
 
     /// The user's own read marker.
     ReadMarker,
+
+    /// The identity of a room member changed in a way the user should be
+    /// warned about.
+    IdentityChange {
+        /// The user whose identity changed.
+        user_id: String,
+        /// The new state of the identity.
+        changed_to: IdentityState,
+        /// When the change was observed, in milliseconds since Unix Epoch.
+        ts: u64,
+    },
 }
 
 /// A [`TimelineItem`](super::TimelineItem) that doesn't correspond to an event.
@@ -28,13 +28,19 @@ use eyeball_im::{Vector, VectorDiff};
 use futures_util::Stream;
 #[cfg(feature = "e2e-encryption")]
 use matrix_sdk_crypto::{
-    store::DynCryptoStore, CollectStrategy, DecryptionSettings, EncryptionSettings,
-    EncryptionSyncChanges, OlmError, OlmMachine, RoomEventDecryptionResult, ToDeviceRequest,
+    metrics::CryptoMetrics, store::DynCryptoStore,
+    types::events::room::encrypted::UNENCRYPTED_STATE_EVENT_TYPES, CollectStrategy,
+    DecryptionSettings, EncryptionSettings, EncryptionSyncChanges, OlmError, OlmMachine,
+    RoomEventDecryptionResult, RoomKeyImportResult, RoomKeyRotationPolicy, ToDeviceRequest,
     TrustRequirement,
 };
 #[cfg(feature = "e2e-encryption")]
 use ruma::events::{
-    room::{history_visibility::HistoryVisibility, message::MessageType},
+    room::{
+        history_visibility::HistoryVisibility,
+        message::MessageType,
+        power_levels::{RoomPowerLevels, SyncRoomPowerLevelsEvent},
+    },
     SyncMessageLikeEvent,
 };
 #[cfg(doc)]
@@ -68,7 +74,7 @@ use tracing::{debug, error, info, instrument, trace, warn};
 #[cfg(all(feature = "e2e-encryption", feature = "experimental-sliding-sync"))]
 use crate::latest_event::{is_suitable_for_latest_event, LatestEvent, PossibleLatestEvent};
 #[cfg(feature = "e2e-encryption")]
-use crate::RoomMemberships;
+use crate::{deserialized_responses::RawAnySyncOrStrippedState, RoomMemberships};
 use crate::{
     deserialized_responses::{RawAnySyncOrStrippedTimelineEvent, SyncTimelineEvent},
     error::{Error, Result},
@@ -127,6 +133,15 @@ pub struct BaseClient {
     /// The trust requirement to use for decrypting events.
     #[cfg(feature = "e2e-encryption")]
     pub decryption_trust_requirement: TrustRequirement,
+
+    /// The sink for the metrics collected by the `OlmMachine`, if any.
+    #[cfg(feature = "e2e-encryption")]
+    pub crypto_metrics: Option<Arc<dyn CryptoMetrics>>,
+
+    /// The custom policy deciding if a room key should be rotated before it's
+    /// shared with new recipients, if any.
+    #[cfg(feature = "e2e-encryption")]
+    pub room_key_rotation_policy: Option<Arc<dyn RoomKeyRotationPolicy>>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -168,6 +183,10 @@ impl BaseClient {
             room_key_recipient_strategy: Default::default(),
             #[cfg(feature = "e2e-encryption")]
             decryption_trust_requirement: TrustRequirement::Untrusted,
+            #[cfg(feature = "e2e-encryption")]
+            crypto_metrics: None,
+            #[cfg(feature = "e2e-encryption")]
+            room_key_rotation_policy: None,
         }
     }
 
@@ -193,6 +212,8 @@ impl BaseClient {
             room_info_notable_update_sender: self.room_info_notable_update_sender.clone(),
             room_key_recipient_strategy: self.room_key_recipient_strategy.clone(),
             decryption_trust_requirement: self.decryption_trust_requirement,
+            crypto_metrics: self.crypto_metrics.clone(),
+            room_key_rotation_policy: self.room_key_rotation_policy.clone(),
         };
 
         if let Some(session_meta) = self.session_meta().cloned() {
@@ -324,6 +345,8 @@ impl BaseClient {
         )
         .await
         .map_err(OlmError::from)?;
+        olm_machine.set_metrics(self.crypto_metrics.clone());
+        olm_machine.set_room_key_rotation_policy(self.room_key_rotation_policy.clone());
 
         *self.olm_machine.write().await = Some(olm_machine);
         Ok(())
@@ -403,6 +426,257 @@ impl BaseClient {
         Ok(Some(event))
     }
 
+    /// Attempt to decrypt the given encrypted state event, as described in
+    /// [MSC3414].
+    ///
+    /// Returns the decrypted state event, or `None` if the event couldn't be
+    /// decrypted, in which case the encrypted event should be kept as is.
+    ///
+    /// [MSC3414]: https://github.com/matrix-org/matrix-spec-proposals/pull/3414
+    #[cfg(feature = "e2e-encryption")]
+    async fn decrypt_state_event(
+        &self,
+        raw_event: &Raw<AnySyncStateEvent>,
+        room_id: &RoomId,
+        changes: &StateChanges,
+    ) -> StoreResult<Option<(Raw<AnySyncStateEvent>, AnySyncStateEvent)>> {
+        let olm = self.olm_machine().await;
+        let Some(olm) = olm.as_ref() else {
+            return Ok(None);
+        };
+
+        let decryption_settings = DecryptionSettings {
+            sender_device_trust_requirement: self.decryption_trust_requirement,
+        };
+
+        match olm.try_decrypt_room_event(raw_event.cast_ref(), room_id, &decryption_settings).await
+        {
+            Ok(RoomEventDecryptionResult::Decrypted(decrypted)) => {
+                let raw_event = decrypted.event.cast::<AnySyncStateEvent>();
+
+                match raw_event.deserialize() {
+                    Ok(event) => {
+                        if self.is_valid_decrypted_state_event(&event, room_id, changes).await? {
+                            Ok(Some((raw_event, event)))
+                        } else {
+                            Ok(None)
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Couldn't deserialize decrypted state event: {e}");
+                        Ok(None)
+                    }
+                }
+            }
+            Ok(RoomEventDecryptionResult::UnableToDecrypt(utd_info)) => {
+                debug!(?utd_info, "Unable to decrypt state event");
+                Ok(None)
+            }
+            Err(e) => {
+                warn!("Error while decrypting state event: {e}");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Check that a decrypted state event may be applied to the room state.
+    ///
+    /// The state events the server needs to authorize events are never
+    /// encrypted, so a decrypted event of one of those types is rejected. The
+    /// server can't check the power level of the sender for the inner event
+    /// type either, so we do it here.
+    #[cfg(feature = "e2e-encryption")]
+    async fn is_valid_decrypted_state_event(
+        &self,
+        event: &AnySyncStateEvent,
+        room_id: &RoomId,
+        changes: &StateChanges,
+    ) -> StoreResult<bool> {
+        let event_type = event.event_type();
+        let sender = event.sender();
+
+        if UNENCRYPTED_STATE_EVENT_TYPES.contains(&event_type.to_string().as_str()) {
+            warn!(
+                %event_type, %sender,
+                "Rejecting an encrypted state event with a type that must not be encrypted"
+            );
+            return Ok(false);
+        }
+
+        let Some(power_levels) = self.room_power_levels(room_id, changes).await? else {
+            warn!(
+                %event_type, %sender,
+                "Rejecting an encrypted state event, the power levels of the room are unknown"
+            );
+            return Ok(false);
+        };
+
+        if !power_levels.user_can_send_state(sender, event_type.clone()) {
+            warn!(
+                %event_type, %sender,
+                "Rejecting an encrypted state event, the sender isn't allowed to send it"
+            );
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Get the power levels of the room, looking at the state changes of the
+    /// current sync first, and at the store otherwise.
+    #[cfg(feature = "e2e-encryption")]
+    async fn room_power_levels(
+        &self,
+        room_id: &RoomId,
+        changes: &StateChanges,
+    ) -> StoreResult<Option<RoomPowerLevels>> {
+        if let Some(event) = changes.state.get(room_id).and_then(|types| {
+            types
+                .get(&StateEventType::RoomPowerLevels)?
+                .get("")?
+                .deserialize_as::<SyncRoomPowerLevelsEvent>()
+                .ok()
+        }) {
+            return Ok(Some(event.power_levels()));
+        }
+
+        Ok(self
+            .store
+            .get_state_event_static::<RoomPowerLevelsEventContent>(room_id)
+            .await?
+            .and_then(|e| e.deserialize().ok())
+            .map(|event| event.power_levels()))
+    }
+
+    /// Apply a decrypted state event to the room state, unless a more recent
+    /// event with the same type and state key is already known.
+    #[cfg(feature = "e2e-encryption")]
+    async fn apply_decrypted_state_event(
+        &self,
+        raw_event: Raw<AnySyncStateEvent>,
+        event: &AnySyncStateEvent,
+        room_info: &mut RoomInfo,
+        changes: &mut StateChanges,
+    ) -> StoreResult<()> {
+        let room_id = room_info.room_id.clone();
+        let event_type = event.event_type();
+        let state_key = event.state_key();
+
+        let known_event = match changes
+            .state
+            .get(&room_id)
+            .and_then(|types| types.get(&event_type)?.get(state_key))
+        {
+            Some(raw) => raw.deserialize().ok(),
+            None => {
+                match self.store.get_state_event(&room_id, event_type.clone(), state_key).await? {
+                    Some(RawAnySyncOrStrippedState::Sync(raw)) => raw.deserialize().ok(),
+                    _ => None,
+                }
+            }
+        };
+
+        if known_event.is_some_and(|known| known.origin_server_ts() > event.origin_server_ts()) {
+            trace!(
+                %event_type,
+                "Not applying a decrypted state event, a more recent one is already known"
+            );
+            return Ok(());
+        }
+
+        room_info.handle_state_event(event);
+
+        changes
+            .state
+            .entry(room_id)
+            .or_default()
+            .entry(event_type)
+            .or_default()
+            .insert(state_key.to_owned(), raw_event);
+
+        Ok(())
+    }
+
+    /// Try again to decrypt the encrypted state events of a room that are in
+    /// the store, after room keys for the given sessions were received.
+    #[cfg(feature = "e2e-encryption")]
+    async fn decrypt_stored_state_events(
+        &self,
+        room: &Room,
+        session_ids: &BTreeSet<String>,
+        changes: &mut StateChanges,
+    ) -> StoreResult<()> {
+        let room_id = room.room_id();
+        let stored_events = self.store.get_state_events(room_id, "m.room.encrypted".into()).await?;
+
+        let mut room_info = None;
+
+        for stored_event in stored_events {
+            let RawAnySyncOrStrippedState::Sync(raw_event) = stored_event else {
+                continue;
+            };
+
+            let session_id = raw_event
+                .get_field::<EncryptedContentSessionId>("content")
+                .ok()
+                .flatten()
+                .map(|content| content.session_id);
+
+            if !session_id.is_some_and(|session_id| session_ids.contains(&session_id)) {
+                continue;
+            }
+
+            let Some((raw_event, event)) =
+                self.decrypt_state_event(&raw_event, room_id, changes).await?
+            else {
+                continue;
+            };
+
+            let room_info = room_info.get_or_insert_with(|| {
+                changes.room_infos.get(room_id).cloned().unwrap_or_else(|| room.clone_info())
+            });
+
+            self.apply_decrypted_state_event(raw_event, &event, room_info, changes).await?;
+        }
+
+        if let Some(room_info) = room_info {
+            changes.add_room(room_info);
+        }
+
+        Ok(())
+    }
+
+    /// Try again to decrypt the encrypted state events of the rooms for which
+    /// room keys were imported outside of a sync, e.g. from a key backup or
+    /// from a room key bundle.
+    ///
+    /// The room keys received over to-device messages in a sync response are
+    /// taken care of while the sync response is processed.
+    #[cfg(feature = "e2e-encryption")]
+    pub async fn decrypt_state_events_with_imported_room_keys(
+        &self,
+        import_result: &RoomKeyImportResult,
+    ) -> Result<()> {
+        let mut changes = StateChanges::default();
+
+        for (room_id, session_ids) in &import_result.keys {
+            let Some(room) = self.get_room(room_id) else {
+                continue;
+            };
+
+            let session_ids = session_ids.values().flatten().cloned().collect();
+            self.decrypt_stored_state_events(&room, &session_ids, &mut changes).await?;
+        }
+
+        if !changes.room_infos.is_empty() {
+            let _sync_lock = self.sync_lock().lock().await;
+            self.store.save_changes(&changes).await?;
+            self.apply_changes(&changes, Default::default());
+        }
+
+        Ok(())
+    }
+
     #[allow(clippy::too_many_arguments)]
     #[instrument(skip_all, fields(room_id = ?room_info.room_id))]
     pub(crate) async fn handle_timeline(
@@ -427,6 +701,30 @@ impl BaseClient {
             // decrypted or UTD event below if necessary.
             let mut event = SyncTimelineEvent::new(raw_event);
 
+            // Encrypted state events need to be decrypted before we look at them, so the
+            // decrypted state ends up in the room state.
+            #[cfg(feature = "e2e-encryption")]
+            if is_encrypted_state_event(event.raw()) {
+                if let Some(e) =
+                    Box::pin(self.decrypt_sync_room_event(event.raw(), room.room_id())).await?
+                {
+                    let is_valid = match (e.encryption_info(), e.raw().deserialize()) {
+                        (Some(_), Ok(AnySyncTimelineEvent::State(state))) => {
+                            self.is_valid_decrypted_state_event(&state, room.room_id(), changes)
+                                .await?
+                        }
+                        // Keep unable-to-decrypt events, they carry the information about why
+                        // the event couldn't be decrypted.
+                        (None, _) => true,
+                        _ => false,
+                    };
+
+                    if is_valid {
+                        event = e;
+                    }
+                }
+            }
+
             match event.raw().deserialize() {
                 Ok(e) => {
                     #[allow(clippy::single_match)]
@@ -647,6 +945,25 @@ impl BaseClient {
 
         changes.state.insert((*room_info.room_id).to_owned(), state_events);
 
+        // Encrypted state events are decrypted once the plaintext state is known, so
+        // the power levels of the room can be checked. The encrypted events are kept
+        // in the state too, so they can be decrypted again if their room key arrives
+        // later.
+        #[cfg(feature = "e2e-encryption")]
+        for (raw_event, event) in iter::zip(raw_events, events) {
+            if event.event_type().to_string() != "m.room.encrypted" {
+                continue;
+            }
+
+            let room_id = room_info.room_id.clone();
+
+            if let Some((raw_event, event)) =
+                self.decrypt_state_event(raw_event, &room_id, changes).await?
+            {
+                self.apply_decrypted_state_event(raw_event, &event, room_info, changes).await?;
+            }
+        }
+
         Ok(user_ids)
     }
 
@@ -768,15 +1085,25 @@ impl BaseClient {
             let (events, room_key_updates) =
                 o.receive_sync_changes(encryption_sync_changes).await?;
 
-            #[cfg(feature = "experimental-sliding-sync")]
+            let mut session_ids_by_room = BTreeMap::<_, BTreeSet<_>>::new();
             for room_key_update in room_key_updates {
-                if let Some(room) = self.get_room(&room_key_update.room_id) {
+                session_ids_by_room
+                    .entry(room_key_update.room_id)
+                    .or_default()
+                    .insert(room_key_update.session_id);
+            }
+
+            for (room_id, session_ids) in session_ids_by_room {
+                if let Some(room) = self.get_room(&room_id) {
+                    #[cfg(feature = "experimental-sliding-sync")]
                     self.decrypt_latest_events(&room, changes, room_info_notable_updates).await;
+
+                    self.decrypt_stored_state_events(&room, &session_ids, changes).await?;
                 }
             }
 
             #[cfg(not(feature = "experimental-sliding-sync"))] // Silence unused variable warnings.
-            let _ = (room_key_updates, changes, room_info_notable_updates);
+            let _ = room_info_notable_updates;
 
             Ok(events)
         } else {
@@ -992,7 +1319,10 @@ impl BaseClient {
                 self.room_info_notable_update_sender.clone(),
             );
 
-            let mut room_info = room.clone_info();
+            // The `RoomInfo` might have been updated while processing the to-device events,
+            // e.g. by decrypting state events.
+            let mut room_info =
+                changes.room_infos.get(&room_id).cloned().unwrap_or_else(|| room.clone_info());
 
             room_info.mark_as_joined();
             room_info.update_from_ruma_summary(&new_info.summary);
@@ -1111,7 +1441,8 @@ impl BaseClient {
                 self.room_info_notable_update_sender.clone(),
             );
 
-            let mut room_info = room.clone_info();
+            let mut room_info =
+                changes.room_infos.get(&room_id).cloned().unwrap_or_else(|| room.clone_info());
             room_info.mark_as_left();
             room_info.mark_state_partially_synced();
 
@@ -1728,6 +2059,21 @@ fn handle_room_member_event_for_profiles(
     }
 }
 
+/// The part of the content of an encrypted event we need to know which room
+/// key it was encrypted with.
+#[cfg(feature = "e2e-encryption")]
+#[derive(serde::Deserialize)]
+struct EncryptedContentSessionId {
+    session_id: String,
+}
+
+/// Is the given timeline event an encrypted state event?
+#[cfg(feature = "e2e-encryption")]
+fn is_encrypted_state_event(event: &Raw<AnySyncTimelineEvent>) -> bool {
+    event.get_field::<&str>("type").ok().flatten() == Some("m.room.encrypted")
+        && event.get_field::<&str>("state_key").ok().flatten().is_some()
+}
+
 #[cfg(test)]
 mod tests {
     use matrix_sdk_test::{
@@ -2131,4 +2477,331 @@ mod tests {
         assert_eq!(member.display_name().unwrap(), "Invited Alice");
         assert_eq!(member.avatar_url().unwrap().to_string(), "mxc://localhost/fewjilfewjil42");
     }
+
+    /// A power levels state event giving the power level 100 to the given user,
+    /// while the other users need the power level 50 to send state events.
+    #[cfg(feature = "e2e-encryption")]
+    fn power_levels_state_event(admin: &UserId) -> StateTestEvent {
+        StateTestEvent::Custom(json!({
+            "content": {
+                "state_default": 50,
+                "users": { admin.as_str(): 100 },
+                "users_default": 0,
+            },
+            "event_id": "$power_levels",
+            "origin_server_ts": 1432135524000u64,
+            "sender": admin,
+            "state_key": "",
+            "type": "m.room.power_levels",
+        }))
+    }
+
+    #[cfg(feature = "e2e-encryption")]
+    #[async_test]
+    async fn test_encrypted_state_events_are_decrypted() {
+        use matrix_sdk_crypto::types::events::room::encrypted::encrypted_state_key;
+        use matrix_sdk_test::JoinedRoomBuilder;
+
+        let user_id = user_id!("@alice:example.org");
+        let room_id = room_id!("!test:example.org");
+
+        let client = logged_in_base_client(Some(user_id)).await;
+
+        let (encrypted_name, encrypted_topic) = {
+            let olm = client.olm_machine().await;
+            let olm = olm.as_ref().unwrap();
+
+            // Sharing a room key creates the matching inbound group session too, so we're
+            // able to decrypt our own events.
+            olm.share_room_key(room_id, std::iter::empty(), Default::default()).await.unwrap();
+
+            let name = Raw::new(&json!({ "name": "Secret room" })).unwrap().cast();
+            let topic = Raw::new(&json!({ "topic": "Secret plans" })).unwrap().cast();
+
+            (
+                olm.encrypt_state_event_raw(room_id, "m.room.name", "", &name).await.unwrap(),
+                olm.encrypt_state_event_raw(room_id, "m.room.topic", "", &topic).await.unwrap(),
+            )
+        };
+
+        let mut sync_builder = SyncResponseBuilder::new();
+        let response = sync_builder
+            .add_joined_room(
+                JoinedRoomBuilder::new(room_id)
+                    .add_state_event(StateTestEvent::Custom(json!({
+                        "content": encrypted_name,
+                        "event_id": "$name",
+                        "origin_server_ts": 1432135524678u64,
+                        "sender": user_id,
+                        "state_key": encrypted_state_key("m.room.name", ""),
+                        "type": "m.room.encrypted",
+                    })))
+                    .add_state_event(power_levels_state_event(user_id))
+                    .add_timeline_event(sync_timeline_event!({
+                        "content": encrypted_topic,
+                        "event_id": "$topic",
+                        "origin_server_ts": 1432135524679u64,
+                        "sender": user_id,
+                        "state_key": encrypted_state_key("m.room.topic", ""),
+                        "type": "m.room.encrypted",
+                    })),
+            )
+            .build_sync_response();
+
+        client.receive_sync_response(response).await.unwrap();
+
+        let room = client.get_room(room_id).unwrap();
+        assert_eq!(room.name().as_deref(), Some("Secret room"));
+        assert_eq!(room.topic().as_deref(), Some("Secret plans"));
+    }
+
+    #[cfg(feature = "e2e-encryption")]
+    #[async_test]
+    async fn test_invalid_encrypted_state_events_are_rejected() {
+        use matrix_sdk_crypto::types::events::room::encrypted::encrypted_state_key;
+        use matrix_sdk_test::JoinedRoomBuilder;
+
+        let user_id = user_id!("@alice:example.org");
+        let other_user_id = user_id!("@bob:example.org");
+        let room_id = room_id!("!test:example.org");
+
+        let client = logged_in_base_client(Some(user_id)).await;
+
+        let (encrypted_power_levels, encrypted_name) = {
+            let olm = client.olm_machine().await;
+            let olm = olm.as_ref().unwrap();
+
+            olm.share_room_key(room_id, std::iter::empty(), Default::default()).await.unwrap();
+
+            let power_levels =
+                Raw::new(&json!({ "users": { other_user_id.as_str(): 100 } })).unwrap().cast();
+            let name = Raw::new(&json!({ "name": "Hijacked room" })).unwrap().cast();
+
+            (
+                olm.encrypt_state_event_raw(room_id, "m.room.power_levels", "", &power_levels)
+                    .await
+                    .unwrap(),
+                olm.encrypt_state_event_raw(room_id, "m.room.name", "", &name).await.unwrap(),
+            )
+        };
+
+        let mut sync_builder = SyncResponseBuilder::new();
+        let response = sync_builder
+            .add_joined_room(
+                JoinedRoomBuilder::new(room_id)
+                    .add_state_event(power_levels_state_event(user_id))
+                    // The power levels are never encrypted.
+                    .add_state_event(StateTestEvent::Custom(json!({
+                        "content": encrypted_power_levels,
+                        "event_id": "$power_levels_override",
+                        "origin_server_ts": 1432135524678u64,
+                        "sender": user_id,
+                        "state_key": encrypted_state_key("m.room.power_levels", ""),
+                        "type": "m.room.encrypted",
+                    })))
+                    // Bob doesn't have the power level to change the name of the room.
+                    .add_timeline_event(sync_timeline_event!({
+                        "content": encrypted_name,
+                        "event_id": "$name",
+                        "origin_server_ts": 1432135524679u64,
+                        "sender": other_user_id,
+                        "state_key": encrypted_state_key("m.room.name", ""),
+                        "type": "m.room.encrypted",
+                    })),
+            )
+            .build_sync_response();
+
+        client.receive_sync_response(response).await.unwrap();
+
+        let room = client.get_room(room_id).unwrap();
+        assert_eq!(room.name(), None);
+
+        let power_levels = room.power_levels().await.unwrap();
+        assert_eq!(power_levels.for_user(other_user_id), 0.into());
+    }
+
+    #[cfg(feature = "e2e-encryption")]
+    #[async_test]
+    async fn test_encrypted_state_events_are_decrypted_when_the_room_key_arrives() {
+        use std::collections::BTreeMap;
+
+        use matrix_sdk_crypto::{
+            types::events::room::encrypted::encrypted_state_key, EncryptionSettings, OlmMachine,
+            OutgoingRequest, OutgoingRequests,
+        };
+        use matrix_sdk_test::JoinedRoomBuilder;
+        use ruma::{
+            api::client::keys::{claim_keys, get_keys, upload_keys},
+            device_id,
+        };
+
+        let user_id = user_id!("@alice:example.org");
+        let room_id = room_id!("!test:example.org");
+
+        let client = logged_in_base_client(Some(user_id)).await;
+
+        // The topic is encrypted by another device, we don't have the room key yet.
+        let other_device = OlmMachine::new(user_id, device_id!("OTHERDEVICE")).await;
+        other_device
+            .share_room_key(room_id, std::iter::empty(), EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        let topic = Raw::new(&json!({ "topic": "Secret plans" })).unwrap().cast();
+        let encrypted_topic = other_device
+            .encrypt_state_event_raw(room_id, "m.room.topic", "", &topic)
+            .await
+            .unwrap();
+
+        let mut sync_builder = SyncResponseBuilder::new();
+        let response = sync_builder
+            .add_joined_room(
+                JoinedRoomBuilder::new(room_id)
+                    .add_state_event(power_levels_state_event(user_id))
+                    .add_state_event(StateTestEvent::Custom(json!({
+                        "content": encrypted_topic,
+                        "event_id": "$topic",
+                        "origin_server_ts": 1432135524678u64,
+                        "sender": user_id,
+                        "state_key": encrypted_state_key("m.room.topic", ""),
+                        "type": "m.room.encrypted",
+                    }))),
+            )
+            .build_sync_response();
+
+        client.receive_sync_response(response).await.unwrap();
+
+        let room = client.get_room(room_id).unwrap();
+        assert_eq!(room.topic(), None);
+
+        // The other device establishes an Olm session with us, using our device keys and
+        // one of our one-time keys.
+        fn keys_upload(requests: &[OutgoingRequest]) -> upload_keys::v3::Request {
+            requests
+                .iter()
+                .find_map(|r| match r.request() {
+                    OutgoingRequests::KeysUpload(request) => Some(request.clone()),
+                    _ => None,
+                })
+                .expect("The machine should want to upload its keys")
+        }
+
+        let our_device_id = client.session_meta().unwrap().device_id.clone();
+        let our_keys = {
+            let olm = client.olm_machine().await;
+            keys_upload(&olm.as_ref().unwrap().outgoing_requests().await.unwrap())
+        };
+
+        let other_device_requests = other_device.outgoing_requests().await.unwrap();
+        let other_device_keys = keys_upload(&other_device_requests);
+        let keys_query_id = other_device_requests
+            .iter()
+            .find(|r| matches!(r.request(), OutgoingRequests::KeysQuery(_)))
+            .expect("The other device should want to query our keys")
+            .request_id()
+            .to_owned();
+
+        let mut keys_query_response = get_keys::v3::Response::new();
+        keys_query_response.device_keys = BTreeMap::from([(
+            user_id.to_owned(),
+            BTreeMap::from([
+                (our_device_id.clone(), our_keys.device_keys.unwrap()),
+                (other_device.device_id().to_owned(), other_device_keys.device_keys.unwrap()),
+            ]),
+        )]);
+        other_device.mark_request_as_sent(&keys_query_id, &keys_query_response).await.unwrap();
+
+        let (keys_claim_id, _) = other_device
+            .get_missing_sessions(std::iter::once(user_id))
+            .await
+            .unwrap()
+            .expect("The other device should claim one of our one-time keys");
+        let keys_claim_response = claim_keys::v3::Response::new(BTreeMap::from([(
+            user_id.to_owned(),
+            BTreeMap::from([(our_device_id, our_keys.one_time_keys.into_iter().take(1).collect())]),
+        )]));
+        other_device.mark_request_as_sent(&keys_claim_id, &keys_claim_response).await.unwrap();
+
+        // The room key arrives in a to-device message of the next sync response.
+        let requests = other_device
+            .share_room_key(room_id, std::iter::once(user_id), EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        let mut response = sync_builder.build_sync_response();
+        for request in requests {
+            for content in request.messages.values().flat_map(BTreeMap::values) {
+                let event = json!({
+                    "content": content,
+                    "sender": user_id,
+                    "type": request.event_type,
+                });
+                response.to_device.events.push(Raw::new(&event).unwrap().cast());
+            }
+        }
+        assert!(!response.to_device.events.is_empty(), "The room key should be sent to us");
+
+        client.receive_sync_response(response).await.unwrap();
+
+        assert_eq!(room.topic().as_deref(), Some("Secret plans"));
+    }
+
+    #[cfg(feature = "e2e-encryption")]
+    #[async_test]
+    async fn test_encrypted_state_events_are_decrypted_with_imported_room_keys() {
+        use matrix_sdk_crypto::{
+            types::events::room::encrypted::encrypted_state_key, EncryptionSettings, OlmMachine,
+        };
+        use matrix_sdk_test::JoinedRoomBuilder;
+        use ruma::device_id;
+
+        let user_id = user_id!("@alice:example.org");
+        let room_id = room_id!("!test:example.org");
+
+        let client = logged_in_base_client(Some(user_id)).await;
+
+        let other_device = OlmMachine::new(user_id, device_id!("OTHERDEVICE")).await;
+        other_device
+            .share_room_key(room_id, std::iter::empty(), EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        let topic = Raw::new(&json!({ "topic": "Secret plans" })).unwrap().cast();
+        let encrypted_topic = other_device
+            .encrypt_state_event_raw(room_id, "m.room.topic", "", &topic)
+            .await
+            .unwrap();
+
+        let response = SyncResponseBuilder::new()
+            .add_joined_room(
+                JoinedRoomBuilder::new(room_id)
+                    .add_state_event(power_levels_state_event(user_id))
+                    .add_state_event(StateTestEvent::Custom(json!({
+                        "content": encrypted_topic,
+                        "event_id": "$topic",
+                        "origin_server_ts": 1432135524678u64,
+                        "sender": user_id,
+                        "state_key": encrypted_state_key("m.room.topic", ""),
+                        "type": "m.room.encrypted",
+                    }))),
+            )
+            .build_sync_response();
+
+        client.receive_sync_response(response).await.unwrap();
+
+        let room = client.get_room(room_id).unwrap();
+        assert_eq!(room.topic(), None);
+
+        // The room key is imported, e.g. from a key backup, outside of a sync.
+        let room_keys = other_device.store().export_room_keys(|_| true).await.unwrap();
+        let import_result = {
+            let olm = client.olm_machine().await;
+            let olm = olm.as_ref().unwrap();
+            olm.store().import_exported_room_keys(room_keys, |_, _| {}).await.unwrap()
+        };
+
+        client.decrypt_state_events_with_imported_room_keys(&import_result).await.unwrap();
+
+        assert_eq!(room.topic().as_deref(), Some("Secret plans"));
+    }
 }
@@ -295,6 +295,11 @@ pub struct EncryptionInfo {
     /// Callers that persist this should mark the state as dirty when a device
     /// change is received down the sync.
     pub verification_state: VerificationState,
+    /// The ID of the Megolm session that was used to encrypt the event, if
+    /// known. It's missing for events which were decrypted before it was
+    /// recorded.
+    #[serde(default)]
+    pub session_id: Option<String>,
 }
 
 /// Represents a matrix room event that has been returned from `/sync`,
@@ -896,6 +901,7 @@ mod tests {
                         sender_claimed_keys: Default::default(),
                     },
                     verification_state: VerificationState::Verified,
+                    session_id: Some("mysessionid".to_owned()),
                 },
                 unsigned_encryption_info: Some(BTreeMap::from([(
                     UnsignedEventLocation::RelationsReplace,
@@ -934,6 +940,7 @@ mod tests {
                                 }
                             },
                             "verification_state": "Verified",
+                            "session_id": "mysessionid",
                         },
                         "unsigned_encryption_info": {
                             "RelationsReplace": {"UnableToDecrypt": {
@@ -982,6 +989,7 @@ mod tests {
             event.encryption_info().unwrap().algorithm_info,
             AlgorithmInfo::MegolmV1AesSha2 { .. }
         );
+        assert_eq!(event.encryption_info().unwrap().session_id, None);
 
         // Test that the previous format, with an undecryptable unsigned event, can also
         // be deserialized.
@@ -36,6 +36,7 @@ use tokio::sync::RwLock;
 use tracing::{debug, info, instrument, trace, warn};
 
 use crate::{
+    metrics::{CryptoCounter, CryptoHistogram},
     olm::{BackedUpRoomKey, ExportedRoomKey, InboundGroupSession, SignedJsonObject},
     store::{BackupDecryptionKey, BackupKeys, Changes, RoomKeyCounts, Store},
     types::{MegolmV1AuthData, RoomKeyBackupInfo, Signatures},
@@ -484,6 +485,13 @@ impl BackupMachine {
 
                 trace!(request_id = ?r.request_id, keys = ?r.sessions, "Marking room keys as backed up");
 
+                let metrics = self.store.metrics();
+                metrics.increment(CryptoCounter::BackupUploadBatch);
+                metrics.observe(
+                    CryptoHistogram::BackupUploadBatchSize,
+                    room_and_session_ids.len() as f64,
+                );
+
                 self.store
                     .mark_inbound_group_sessions_as_backed_up(
                         &r.request.version,
@@ -210,6 +210,14 @@ pub enum EventError {
         decrypted event: expected {0}, got {1:?}"
     )]
     MismatchedRoom(OwnedRoomId, Option<OwnedRoomId>),
+
+    /// The state key of an encrypted state event doesn't match the type and
+    /// state key of the decrypted event.
+    #[error(
+        "the state key of the encrypted state event doesn't match the decrypted \
+        event: got {0}, for a decrypted event of type {1}"
+    )]
+    MismatchedStateKey(String, String),
 }
 
 /// Error type describing different errors that can happen when we create an
@@ -15,28 +15,33 @@
 use std::{
     collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     ops::Deref,
-    sync::Arc,
+    sync::{Arc, Mutex as StdMutex},
     time::Duration,
 };
 
 use futures_util::future::join_all;
 use itertools::Itertools;
-use matrix_sdk_common::{executor::spawn, failures_cache::FailuresCache};
+use matrix_sdk_common::{
+    executor::{spawn, JoinHandle},
+    failures_cache::FailuresCache,
+};
 use ruma::{
-    api::client::keys::get_keys::v3::Response as KeysQueryResponse, serde::Raw, OwnedDeviceId,
-    OwnedServerName, OwnedTransactionId, OwnedUserId, ServerName, TransactionId, UserId,
+    api::client::keys::get_keys::v3::Response as KeysQueryResponse, serde::Raw, time::Instant,
+    OwnedDeviceId, OwnedServerName, OwnedTransactionId, OwnedUserId, ServerName, TransactionId,
+    UserId,
 };
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
 use tracing::{debug, enabled, info, instrument, trace, warn, Level};
 
 use crate::{
     error::OlmResult,
     identities::{DeviceData, OtherUserIdentityData, OwnUserIdentityData, UserIdentityData},
+    metrics::CryptoHistogram,
     olm::{InboundGroupSession, PrivateCrossSigningIdentity, SenderDataFinder, SenderDataType},
     requests::KeysQueryRequest,
     store::{
         caches::SequenceNumber, Changes, DeviceChanges, IdentityChanges, KeyQueryManager,
-        Result as StoreResult, Store, StoreCache, StoreCacheGuard, UserKeyQueryResult,
+        Result as StoreResult, RoomKeyInfo, Store, StoreCache, StoreCacheGuard, UserKeyQueryResult,
     },
     types::{CrossSigningKey, DeviceKeys, MasterPubkey, SelfSigningPubkey, UserSigningPubkey},
     CryptoStoreError, LocalTrust, OwnUserIdentity, SignatureError, UserIdentity,
@@ -70,10 +75,25 @@ pub(crate) struct IdentityManager {
 
     /// Details of the current "in-flight" key query request, if any
     keys_query_request_details: Arc<Mutex<Option<KeysQueryRequestDetails>>>,
+
+    /// The background task updating the sender data of inbound group
+    /// sessions, see [`Self::spawn_sender_data_update`].
+    sender_data_update_task: Arc<StdMutex<Option<SenderDataUpdateTask>>>,
+}
+
+/// A set of devices and users whose inbound group sessions might have their
+/// sender data upgraded.
+type SenderDataUpdate = (Vec<DeviceData>, BTreeSet<OwnedUserId>);
+
+/// The background task processing the [`SenderDataUpdate`]s, one at a time.
+#[derive(Debug)]
+struct SenderDataUpdateTask {
+    sender: mpsc::UnboundedSender<SenderDataUpdate>,
+    handle: JoinHandle<()>,
 }
 
 /// Details of an in-flight key query request
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 struct KeysQueryRequestDetails {
     /// The sequence number, to be passed to
     /// `Store.mark_tracked_users_as_up_to_date`.
@@ -83,6 +103,10 @@ struct KeysQueryRequestDetails {
     /// more actual KeysQueryRequests, each with their own request id. We
     /// record the outstanding request ids here.
     request_ids: HashSet<OwnedTransactionId>,
+
+    /// When the requests were created, used to measure the latency of the key
+    /// queries.
+    created_at: Instant,
 }
 
 // Helper type to handle key query response
@@ -103,6 +127,7 @@ impl IdentityManager {
             key_query_manager: Default::default(),
             failures: Default::default(),
             keys_query_request_details: keys_query_request_details.into(),
+            sender_data_update_task: Default::default(),
         }
     }
 
@@ -163,22 +188,22 @@ impl IdentityManager {
         self.store.save_changes(changes).await?;
 
         // Update the sender data on any existing inbound group sessions based on the
-        // changes in this response.
+        // changes in this response, in the background since there might be a lot of
+        // sessions to go through.
         //
-        // `update_sender_data_from_device_changes` relies on being able to look up the
-        // user identities from the store, so this has to happen *after* the
-        // changes from `handle_cross_signing_keys` are saved.
+        // The update relies on being able to look up the devices and user identities
+        // from the store, so this has to happen *after* the changes from
+        // `handle_cross_signing_keys` are saved.
         //
         // Note: it might be possible for this to race against session creation. If a
         // new session is received at the same time as a `/keys/query` response is being
         // processed, it could be saved without up-to-date sender data, but it might be
-        // saved too late for it to be picked up by
-        // `update_sender_data_from_device_changes`. However, this should be rare,
-        // since, in general, /sync responses which might create a new session
+        // saved too late for it to be picked up by the update. However, this should be
+        // rare, since, in general, /sync responses which might create a new session
         // are not processed at the same time as /keys/query responses (assuming
         // that the application does not call `OlmMachine::receive_sync_changes`
         // at the same time as `OlmMachine::mark_request_as_sent`).
-        self.update_sender_data_from_device_changes(&devices).await?;
+        self.spawn_sender_data_update(&devices, &identities);
 
         // if this request is one of those we expected to be in flight, pass the
         // sequence number back to the store so that it can mark devices up to
@@ -188,6 +213,11 @@ impl IdentityManager {
 
             request_details.as_mut().and_then(|details| {
                 if details.request_ids.remove(request_id) {
+                    self.store.metrics().observe(
+                        CryptoHistogram::KeysQueryDuration,
+                        details.created_at.elapsed().as_secs_f64(),
+                    );
+
                     Some(details.sequence_number)
                 } else {
                     None
@@ -876,7 +906,11 @@ impl IdentityManager {
             // `receive_keys_query_response()` method to figure out if the user can be
             // marked as up-to-date/non-dirty.
             let request_ids = requests.keys().cloned().collect();
-            let request_details = KeysQueryRequestDetails { sequence_number, request_ids };
+            let request_details = KeysQueryRequestDetails {
+                sequence_number,
+                request_ids,
+                created_at: Instant::now(),
+            };
 
             *self.keys_query_request_details.lock().await = Some(request_details);
 
@@ -1038,14 +1072,116 @@ impl IdentityManager {
         }
     }
 
+    /// Spawn a background task updating the [`InboundGroupSession`]s sent
+    /// from the new or changed devices, or from any device of the users whose
+    /// identity is new or changed, which do not have complete sender data.
+    ///
+    /// The sessions whose sender data got upgraded are announced on
+    /// [`Store::sender_data_updates_stream`]. The task is stopped by
+    /// [`Self::abort_sender_data_updates`].
+    fn spawn_sender_data_update(
+        &self,
+        device_changes: &DeviceChanges,
+        identity_changes: &IdentityChanges,
+    ) {
+        let devices: Vec<DeviceData> =
+            device_changes.new.iter().chain(&device_changes.changed).cloned().collect();
+        let users: BTreeSet<OwnedUserId> = identity_changes
+            .new
+            .iter()
+            .chain(&identity_changes.changed)
+            .map(|identity| identity.user_id().to_owned())
+            .collect();
+
+        if devices.is_empty() && users.is_empty() {
+            return;
+        }
+
+        let mut task = self.sender_data_update_task.lock().unwrap();
+
+        // The updates are queued and processed one at a time by a single task, which
+        // is spawned on the first update.
+        let task = task.get_or_insert_with(|| {
+            let (sender, mut receiver) = mpsc::unbounded_channel::<SenderDataUpdate>();
+            let this = self.clone();
+
+            let handle = spawn(async move {
+                while let Some((devices, users)) = receiver.recv().await {
+                    if let Err(e) = this.update_sender_data(devices, users).await {
+                        warn!(
+                            "Failed to update the sender data of existing inbound group sessions: \
+                             {e:?}"
+                        );
+                    }
+                }
+            });
+
+            SenderDataUpdateTask { sender, handle }
+        });
+
+        // Ignore the result, it can only fail if the task panicked.
+        let _ = task.sender.send((devices, users));
+    }
+
+    /// Stop the background task updating the sender data of inbound group
+    /// sessions, dropping the updates it didn't process yet.
+    ///
+    /// The task holds on to this [`IdentityManager`], so it needs to be
+    /// stopped explicitly once the [`OlmMachine`] is dropped.
+    ///
+    /// [`OlmMachine`]: crate::OlmMachine
+    pub(crate) fn abort_sender_data_updates(&self) {
+        if let Some(task) = self.sender_data_update_task.lock().unwrap().take() {
+            task.handle.abort();
+        }
+    }
+
+    /// Update the [`InboundGroupSession`]s sent from the given devices, or
+    /// from any device of the given users, which do not have complete sender
+    /// data.
+    async fn update_sender_data(
+        &self,
+        devices: Vec<DeviceData>,
+        users: BTreeSet<OwnedUserId>,
+    ) -> Result<(), CryptoStoreError> {
+        let mut devices: BTreeMap<(OwnedUserId, OwnedDeviceId), DeviceData> = devices
+            .into_iter()
+            .map(|device| ((device.user_id().to_owned(), device.device_id().to_owned()), device))
+            .collect();
+
+        // A new or changed identity doesn't necessarily come with device changes, but
+        // it might still allow us to upgrade the sender data of all the sessions sent
+        // by the devices of its owner.
+        for user_id in &users {
+            for (device_id, device) in self.store.get_device_data_for_user(user_id).await? {
+                devices.entry((user_id.clone(), device_id)).or_insert(device);
+            }
+        }
+
+        self.update_sender_data_for_devices(devices.values()).await
+    }
+
     /// Given a list of changed devices, update any [`InboundGroupSession`]s
     /// which were sent from those devices and which do not have complete
     /// sender data.
+    #[cfg(test)]
     async fn update_sender_data_from_device_changes(
         &self,
         device_changes: &DeviceChanges,
     ) -> Result<(), CryptoStoreError> {
-        for device in device_changes.new.iter().chain(device_changes.changed.iter()) {
+        self.update_sender_data_for_devices(
+            device_changes.new.iter().chain(device_changes.changed.iter()),
+        )
+        .await
+    }
+
+    /// Update any [`InboundGroupSession`]s which were sent from the given
+    /// devices and which do not have complete sender data.
+    async fn update_sender_data_for_devices(
+        &self,
+        devices: impl Iterator<Item = &DeviceData>,
+    ) -> Result<(), CryptoStoreError> {
+        for device in devices {
             // 1. Look for InboundGroupSessions from the device whose sender_data is
             //    UnknownDevice. For such sessions, we now have the device, and can update
             //    the sender_data accordingly.
@@ -1070,6 +1206,15 @@ impl IdentityManager {
             if device_owner_identity.is_some_and(|id| device.is_cross_signed_by_owner(&id)) {
                 self.update_sender_data_for_sessions_for_device(device, SenderDataType::DeviceInfo)
                     .await?;
+
+                // 3. Similarly, the verification violation of the owner might have been
+                //    resolved, e.g. because they were verified again, in which case the
+                //    sessions in the VerificationViolation state can be upgraded as well.
+                self.update_sender_data_for_sessions_for_device(
+                    device,
+                    SenderDataType::VerificationViolation,
+                )
+                .await?;
             }
         }
 
@@ -1106,35 +1251,95 @@ impl IdentityManager {
             }
 
             last_session_id = None;
-            for session in &mut sessions {
+            let mut updated_sessions = Vec::new();
+            for mut session in sessions {
                 last_session_id = Some(session.session_id().to_owned());
-                self.update_sender_data_for_session(session, device).await?;
+                if self.update_sender_data_for_session(&mut session, device).await? {
+                    updated_sessions.push(session);
+                }
+            }
+
+            if !updated_sessions.is_empty() {
+                let updated_sessions = self.save_upgraded_sender_data(updated_sessions).await?;
+                self.store.notify_sender_data_updates(
+                    updated_sessions.iter().map(RoomKeyInfo::from).collect(),
+                );
+            }
+        }
+    }
+
+    /// Save the given [`InboundGroupSession`]s, whose sender data got
+    /// upgraded.
+    ///
+    /// Finding the new sender data of a batch of sessions takes a while, and a
+    /// newer copy of a session, e.g. with a better ratchet, might have been
+    /// saved in the meantime. So each session is read again, and the upgraded
+    /// sender data is applied to the stored copy, unless the stored copy
+    /// already has sender data which is at least as good. A store transaction
+    /// is held while doing so, so the sessions received in a sync can't be
+    /// saved in between.
+    ///
+    /// Returns the sessions which were saved.
+    async fn save_upgraded_sender_data(
+        &self,
+        sessions: Vec<InboundGroupSession>,
+    ) -> Result<Vec<InboundGroupSession>, CryptoStoreError> {
+        let _transaction = self.store.transaction().await;
+
+        let mut upgraded_sessions = Vec::with_capacity(sessions.len());
+
+        for session in sessions {
+            let Some(mut stored_session) = self
+                .store
+                .get_inbound_group_session(session.room_id(), session.session_id())
+                .await?
+            else {
+                continue;
+            };
+
+            // The sender data was found for the device which created the session, it
+            // doesn't apply to a session with the same ID from someone else.
+            if stored_session.sender_key() != session.sender_key() {
+                continue;
             }
-            self.store.save_inbound_group_sessions(&sessions).await?;
+
+            if session.sender_data.compare_trust_level(&stored_session.sender_data).is_gt() {
+                stored_session.sender_data = session.sender_data;
+                upgraded_sessions.push(stored_session);
+            }
+        }
+
+        if !upgraded_sessions.is_empty() {
+            self.store.save_inbound_group_sessions(&upgraded_sessions).await?;
         }
+
+        Ok(upgraded_sessions)
     }
 
     /// Update the sender data on the given inbound group session, using the
     /// given device data.
+    ///
+    /// Returns `true` if the sender data got upgraded, i.e. the session needs
+    /// to be saved.
     #[instrument(skip(self, device, session), fields(session_id = session.session_id()))]
     async fn update_sender_data_for_session(
         &self,
         session: &mut InboundGroupSession,
         device: &DeviceData,
-    ) -> Result<(), CryptoStoreError> {
+    ) -> Result<bool, CryptoStoreError> {
         use crate::olm::sender_data_finder::SessionDeviceCheckError::*;
 
         match SenderDataFinder::find_using_device_data(&self.store, device.clone(), session).await {
-            Ok(sender_data) => {
+            Ok(sender_data) if sender_data.compare_trust_level(&session.sender_data).is_gt() => {
                 debug!(
                     "Updating existing InboundGroupSession with new SenderData {:?}",
                     sender_data
                 );
                 session.sender_data = sender_data;
+                Ok(true)
             }
-            Err(CryptoStoreError(e)) => {
-                return Err(e);
-            }
+            Ok(_) => Ok(false),
+            Err(CryptoStoreError(e)) => Err(e),
             Err(MismatchedIdentityKeys(e)) => {
                 warn!(
                     ?session,
@@ -1142,10 +1347,9 @@ impl IdentityManager {
                     "cannot update existing InboundGroupSession due to ownership error: {}",
                     e
                 );
+                Ok(false)
             }
-        };
-
-        Ok(())
+        }
     }
 
     /// Mark all tracked users as dirty.
@@ -2502,6 +2706,45 @@ pub(crate) mod tests {
             }
         }
 
+        #[async_test]
+        async fn test_upgraded_sender_data_is_applied_to_the_latest_copy_of_a_session() {
+            let manager = manager_test_helper(user_id(), device_id()).await;
+
+            let account = Account::new(user_id());
+            let session = create_inbound_group_session(&account).await;
+
+            // The update loads a copy of the session with a worse ratchet...
+            let worse_copy =
+                InboundGroupSession::from_export(&session.export_at_index(10).await).unwrap();
+            manager.store.save_inbound_group_sessions(&[worse_copy]).await.unwrap();
+            let mut loaded_session = manager
+                .store
+                .get_inbound_group_session(session.room_id(), session.session_id())
+                .await
+                .unwrap()
+                .unwrap();
+
+            // ... while a better copy of the session is received.
+            manager.store.save_inbound_group_sessions(&[session.clone()]).await.unwrap();
+
+            let device_data = DeviceData::from_account(&account);
+            assert!(manager
+                .update_sender_data_for_session(&mut loaded_session, &device_data)
+                .await
+                .unwrap());
+            manager.save_upgraded_sender_data(vec![loaded_session]).await.unwrap();
+
+            // The better ratchet is kept, and the sender data is upgraded.
+            let stored_session = manager
+                .store
+                .get_inbound_group_session(session.room_id(), session.session_id())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(stored_session.first_known_index(), 0);
+            assert_matches!(stored_session.sender_data, SenderData::DeviceInfo { .. });
+        }
+
         /// Create an InboundGroupSession sent from the given account
         async fn create_inbound_group_session(account: &Account) -> InboundGroupSession {
             let (_, igs) = account
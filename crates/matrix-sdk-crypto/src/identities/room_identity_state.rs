@@ -22,6 +22,7 @@ use ruma::{
     },
     OwnedUserId, UserId,
 };
+use serde::{Deserialize, Serialize};
 
 use super::UserIdentity;
 use crate::store::IdentityUpdates;
@@ -269,7 +270,7 @@ pub struct IdentityStatusChange {
 }
 
 /// The state of an identity - verified, pinned etc.
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 #[cfg_attr(feature = "uniffi", derive(uniffi::Enum))]
 pub enum IdentityState {
     /// The user is verified with us
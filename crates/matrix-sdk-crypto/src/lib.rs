@@ -25,6 +25,7 @@ mod file_encryption;
 mod gossiping;
 mod identities;
 mod machine;
+pub mod metrics;
 pub mod olm;
 pub mod requests;
 pub mod secret_storage;
@@ -90,17 +91,22 @@ pub use identities::{
     Device, DeviceData, LocalTrust, OtherUserIdentity, OtherUserIdentityData, OwnUserIdentity,
     OwnUserIdentityData, UserDevices, UserIdentity, UserIdentityData,
 };
-pub use machine::{CrossSigningBootstrapRequests, EncryptionSyncChanges, OlmMachine};
+pub use machine::{
+    CrossSigningBootstrapRequests, CrossSigningKeyRotationRequests, EncryptionSyncChanges,
+    OlmMachine,
+};
 use matrix_sdk_common::deserialized_responses::{DecryptedRoomEvent, UnableToDecryptInfo};
 #[cfg(feature = "qrcode")]
 pub use matrix_sdk_qrcode;
-pub use olm::{Account, CrossSigningStatus, EncryptionSettings, Session};
+pub use olm::{Account, CrossSigningKeyRotation, CrossSigningStatus, EncryptionSettings, Session};
 pub use requests::{
     IncomingResponse, KeysBackupRequest, KeysQueryRequest, OutgoingRequest, OutgoingRequests,
     OutgoingVerificationRequest, RoomMessageRequest, ToDeviceRequest, UploadSigningKeysRequest,
 };
 use serde::{Deserialize, Serialize};
-pub use session_manager::CollectStrategy;
+pub use session_manager::{
+    CollectStrategy, RoomKeyRecipientChanges, RoomKeyRotationPolicy, RoomKeySharingPolicy,
+};
 pub use store::{
     CrossSigningKeyExport, CryptoStoreError, SecretImportError, SecretInfo, TrackedUser,
 };
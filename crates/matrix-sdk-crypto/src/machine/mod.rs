@@ -13,8 +13,9 @@
 // limitations under the License.
 
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
-    sync::{Arc, RwLock as StdRwLock},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    iter,
+    sync::{Arc, Mutex as StdMutex, RwLock as StdRwLock},
     time::Duration,
 };
 
@@ -41,9 +42,11 @@ use ruma::{
     assign,
     events::{
         secret::request::SecretName, AnyMessageLikeEvent, AnyMessageLikeEventContent,
-        AnyToDeviceEvent, MessageLikeEventContent,
+        AnyStateEventContent, AnyToDeviceEvent, MessageLikeEventContent, StateEventContent,
+        ToDeviceEventType,
     },
     serde::{JsonObject, Raw},
+    to_device::DeviceIdOrAllDevices,
     DeviceId, MilliSecondsSinceUnixEpoch, OneTimeKeyAlgorithm, OwnedDeviceId, OwnedDeviceKeyId,
     OwnedTransactionId, OwnedUserId, RoomId, TransactionId, UInt, UserId,
 };
@@ -64,33 +67,44 @@ use crate::{
     dehydrated_devices::{DehydratedDevices, DehydrationError},
     error::{EventError, MegolmError, MegolmResult, OlmError, OlmResult, SetRoomSettingsError},
     gossiping::GossipMachine,
-    identities::{user::UserIdentity, Device, IdentityManager, UserDevices},
+    identities::{
+        user::UserIdentity, Device, IdentityManager, OtherUserIdentityData, OwnUserIdentityData,
+        UserDevices, UserIdentityData,
+    },
+    metrics::{CryptoCounter, CryptoMetrics},
     olm::{
-        Account, CrossSigningStatus, EncryptionSettings, IdentityKeys, InboundGroupSession,
-        KnownSenderData, OlmDecryptionInfo, PrivateCrossSigningIdentity, SenderData,
-        SenderDataFinder, SessionType, StaticAccountData,
+        Account, CrossSigningKeyRotation, CrossSigningStatus, EncryptionSettings, IdentityKeys,
+        InboundGroupSession, KnownSenderData, OlmDecryptionInfo, OutboundGroupSessionInfo,
+        PrivateCrossSigningIdentity, SenderData, SenderDataFinder, SessionType, StaticAccountData,
+    },
+    requests::{IncomingResponse, OutgoingRequest, OutgoingRequests, UploadSigningKeysRequest},
+    session_manager::{
+        collect_recipient_devices, CollectStrategy, GroupSessionManager, RecipientDevices,
+        RoomKeyRotationPolicy, SessionManager,
     },
-    requests::{IncomingResponse, OutgoingRequest, UploadSigningKeysRequest},
-    session_manager::{GroupSessionManager, SessionManager},
     store::{
+        integrity::{self, StoreIntegrityReport},
+        pruning::{self, SessionPruningReport, SessionPruningSettings},
         Changes, CryptoStoreWrapper, DeviceChanges, IdentityChanges, IntoCryptoStore, MemoryStore,
         PendingChanges, Result as StoreResult, RoomKeyInfo, RoomSettings, SecretImportError, Store,
         StoreCache, StoreTransaction,
     },
     types::{
         events::{
-            olm_v1::{AnyDecryptedOlmEvent, DecryptedRoomKeyEvent},
+            olm_v1::{AnyDecryptedOlmEvent, DecryptedRoomKeyBundleEvent, DecryptedRoomKeyEvent},
             room::encrypted::{
                 EncryptedEvent, EncryptedToDeviceEvent, RoomEncryptedEventContent,
                 RoomEventEncryptionScheme, SupportedEventEncryptionSchemes,
             },
             room_key::{MegolmV1AesSha2Content, RoomKeyContent},
+            room_key_bundle::RoomKeyBundleContent,
             room_key_withheld::{
                 MegolmV1AesSha2WithheldContent, RoomKeyWithheldContent, RoomKeyWithheldEvent,
+                WithheldCode,
             },
-            ToDeviceEvents,
+            CryptoContextInfo, EventType, ToDeviceEvents, UtdCause,
         },
-        EventEncryptionAlgorithm, Signatures,
+        EventEncryptionAlgorithm, Signatures, StoredRoomKeyBundleData,
     },
     utilities::timestamp_to_iso8601,
     verification::{Verification, VerificationMachine, VerificationRequest},
@@ -134,6 +148,31 @@ pub struct OlmMachineInner {
     identity_manager: IdentityManager,
     /// A state machine that handles creating room key backups.
     backup_machine: BackupMachine,
+    /// The number of messages in the to-device requests we handed out and
+    /// which weren't marked as sent yet, only tracked if metrics are enabled.
+    pending_to_device_message_counts: StdRwLock<BTreeMap<OwnedTransactionId, usize>>,
+    /// The rotation of our cross-signing subkeys which is waiting for its new
+    /// keys to be uploaded, see [`OlmMachine::rotate_cross_signing_keys()`].
+    pending_cross_signing_rotation: StdMutex<Option<PendingCrossSigningRotation>>,
+}
+
+/// A rotation of our cross-signing subkeys, kept in memory until the new keys
+/// are uploaded.
+#[derive(Debug)]
+struct PendingCrossSigningRotation {
+    rotation: CrossSigningKeyRotation,
+    identity: PrivateCrossSigningIdentity,
+    own_identity: OwnUserIdentityData,
+    identities: Vec<OtherUserIdentityData>,
+    devices: Vec<DeviceData>,
+    requests: CrossSigningKeyRotationRequests,
+}
+
+impl Drop for OlmMachineInner {
+    fn drop(&mut self) {
+        // Don't let the background updates of the sender data outlive the machine.
+        self.identity_manager.abort_sender_data_updates();
+    }
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -247,6 +286,8 @@ impl OlmMachine {
             key_request_machine,
             identity_manager,
             backup_machine,
+            pending_to_device_message_counts: Default::default(),
+            pending_cross_signing_rotation: Default::default(),
         });
 
         Self { inner }
@@ -418,6 +459,48 @@ impl OlmMachine {
         &self.inner.store
     }
 
+    /// Check the contents of the crypto store for problems which the
+    /// `OlmMachine` can't recover from on its own, see the
+    /// [`integrity`](crate::store::integrity) module for the list of checks.
+    ///
+    /// If `repair` is `true`, the problems which can be repaired are repaired:
+    /// undecodable entries are removed from the store, outbound group
+    /// sessions without a matching inbound group session are invalidated,
+    /// outdated [`SenderData`] is updated and the backup state of the inbound
+    /// group sessions is reset if it's inconsistent.
+    ///
+    /// This can take a while for large stores, and shouldn't run while the
+    /// store is used by another process.
+    pub async fn check_store_integrity(&self, repair: bool) -> StoreResult<StoreIntegrityReport> {
+        let report = integrity::check_store_integrity(self.store(), repair).await?;
+
+        if repair {
+            for room_id in &report.orphaned_outbound_group_sessions {
+                // The session needs to be in the cache for it to be invalidated.
+                self.inner.group_session_manager.get_or_load_outbound_group_session(room_id).await;
+                self.inner.group_session_manager.invalidate_group_session(room_id).await?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Remove the sessions the crypto store doesn't need to keep anymore, see
+    /// the [`pruning`](crate::store::pruning) module for the details.
+    ///
+    /// This can take a while for large stores, and shouldn't run while the
+    /// store is used by another process.
+    pub async fn prune_sessions(
+        &self,
+        settings: &SessionPruningSettings,
+    ) -> StoreResult<SessionPruningReport> {
+        let (report, removed) = pruning::prune_sessions(self.store(), settings).await?;
+
+        self.store().crypto_store().forget_sessions(&removed.olm_sessions).await;
+
+        Ok(report)
+    }
+
     /// The unique user id that owns this `OlmMachine` instance.
     pub fn user_id(&self) -> &UserId {
         &self.inner.user_id
@@ -534,9 +617,40 @@ impl OlmMachine {
         requests.append(&mut self.inner.verification_machine.outgoing_messages());
         requests.append(&mut self.inner.key_request_machine.outgoing_to_device_requests().await?);
 
+        for request in &requests {
+            if let OutgoingRequests::ToDeviceRequest(r) = request.request() {
+                self.track_outgoing_to_device_request(r);
+            }
+        }
+
         Ok(requests)
     }
 
+    /// Remember the number of messages of a to-device request we handed out,
+    /// so they can be counted once the request is marked as sent.
+    fn track_outgoing_to_device_request(&self, request: &ToDeviceRequest) {
+        if self.inner.store.metrics().is_enabled() {
+            self.inner
+                .pending_to_device_message_counts
+                .write()
+                .unwrap()
+                .insert(request.txn_id.clone(), request.message_count());
+        }
+    }
+
+    /// Set the [`CryptoMetrics`] implementation which receives the metrics
+    /// collected about the cryptographic operations of this machine.
+    ///
+    /// Metrics aren't collected if this is never called, passing `None`
+    /// disables them again.
+    pub fn set_metrics(&self, metrics: Option<Arc<dyn CryptoMetrics>>) {
+        if metrics.is_none() {
+            self.inner.pending_to_device_message_counts.write().unwrap().clear();
+        }
+
+        self.inner.store.metrics().set(metrics);
+    }
+
     /// Generate an "out-of-band" key query request for the given set of users.
     ///
     /// This can be useful if we need the results from [`get_identity`] or
@@ -602,6 +716,7 @@ impl OlmMachine {
             }
             IncomingResponse::RoomMessage(_) => {
                 self.inner.verification_machine.mark_request_as_sent(request_id);
+                self.inner.verification_machine.save_pending_requests().await?;
             }
             IncomingResponse::KeysBackup(_) => {
                 Box::pin(self.inner.backup_machine.mark_request_as_sent(request_id)).await?;
@@ -700,6 +815,199 @@ impl OlmMachine {
         })
     }
 
+    /// Replace the self-signing key, the user-signing key, or both, of our
+    /// cross-signing identity, while keeping the master key.
+    ///
+    /// Unlike resetting the identity with
+    /// [`OlmMachine::bootstrap_cross_signing()`], this keeps the trust other
+    /// users placed in our master key. It's meant to recover from the loss or
+    /// the compromise of one of the subkeys.
+    ///
+    /// The private part of the master key is required, as well as the one of
+    /// the subkey which isn't replaced, if any. They can be imported from
+    /// secret storage beforehand with
+    /// [`OlmMachine::import_cross_signing_keys()`].
+    ///
+    /// Our own devices which were signed with the previous self-signing key
+    /// are signed with the new one, and the users we verified with the
+    /// previous user-signing key are signed with the new one.
+    ///
+    /// Nothing is changed locally until
+    /// [`OlmMachine::confirm_cross_signing_key_rotation()`] is called, once
+    /// the new keys have been uploaded. Until then, calling this method again
+    /// with the same `rotation` returns the same requests, so the upload can
+    /// be retried, e.g. with user interactive auth.
+    ///
+    /// # Returns
+    ///
+    /// The requests which should be sent out to the server, in the order they
+    /// appear in the returned [`CrossSigningKeyRotationRequests`]. The first
+    /// one may require user interactive auth.
+    pub async fn rotate_cross_signing_keys(
+        &self,
+        rotation: CrossSigningKeyRotation,
+    ) -> Result<CrossSigningKeyRotationRequests, SignatureError> {
+        // Don't hold the lock, see `bootstrap_cross_signing()`.
+        let identity = self.inner.user_identity.lock().await.clone();
+        let master_key = identity.master_public_key().await;
+
+        // Reuse the keys of a previous attempt, the server might have stored
+        // them already if we failed to get its response.
+        if let Some(pending) = self.inner.pending_cross_signing_rotation.lock().unwrap().as_ref() {
+            if pending.rotation == rotation
+                && master_key.as_ref() == Some(pending.own_identity.master_key())
+            {
+                return Ok(pending.requests.clone());
+            }
+        }
+
+        // The subkey we keep is needed as well, to create our new public identity.
+        let status = identity.status().await;
+        if !status.has_master
+            || !(status.has_self_signing || rotation.rotates_self_signing_key())
+            || !(status.has_user_signing || rotation.rotates_user_signing_key())
+        {
+            return Err(SignatureError::MissingSigningKey);
+        }
+
+        // Only rely on the public identity we know about if it's the one of our
+        // master key, it might be outdated otherwise.
+        let own_identity = self
+            .store()
+            .get_user_identity(self.user_id())
+            .await?
+            .and_then(|i| i.own().cloned())
+            .filter(|i| master_key.as_ref().is_some_and(|key| key == i.master_key()));
+
+        // Collect what the previous subkeys signed before replacing them.
+        let mut devices = Vec::new();
+
+        if rotation.rotates_self_signing_key() {
+            for device in self.store().get_device_data_for_user(self.user_id()).await?.into_values()
+            {
+                let is_signed = own_identity
+                    .as_ref()
+                    .is_some_and(|identity| identity.is_device_signed(&device));
+
+                if device.device_id() == self.device_id() || is_signed {
+                    devices.push(device);
+                }
+            }
+        }
+
+        let mut identities = Vec::new();
+
+        if rotation.rotates_user_signing_key() {
+            if let Some(own_identity) = &own_identity {
+                for user_id in self.tracked_users().await? {
+                    if let Some(UserIdentityData::Other(identity)) =
+                        self.store().get_user_identity(&user_id).await?
+                    {
+                        if own_identity.is_identity_verified(&identity) {
+                            identities.push(identity);
+                        }
+                    }
+                }
+            }
+        }
+
+        info!(
+            ?rotation,
+            devices = devices.len(),
+            users = identities.len(),
+            "Rotating our cross-signing subkeys"
+        );
+
+        let identity = identity.with_rotated_subkeys(rotation).await?;
+
+        let mut device_keys: Vec<_> =
+            devices.iter().map(|device| device.as_device_keys().to_owned()).collect();
+        let mut master_keys: Vec<_> =
+            identities.iter().map(|identity| identity.master_key().to_owned()).collect();
+
+        let upload_signatures_req =
+            identity.sign_devices_and_users(&mut device_keys, &mut master_keys).await?;
+        let upload_signing_keys_req = identity.as_upload_request().await;
+
+        let new_public = identity.to_public_identity().await?;
+
+        let own_identity = match own_identity {
+            Some(mut own_identity) => {
+                own_identity.update(
+                    own_identity.master_key().to_owned(),
+                    new_public.self_signing_key().to_owned(),
+                    new_public.user_signing_key().to_owned(),
+                )?;
+                own_identity.mark_as_verified();
+                own_identity
+            }
+            None => new_public,
+        };
+
+        for (device, device_keys) in devices.iter_mut().zip(&device_keys) {
+            device.update_device(device_keys)?;
+        }
+
+        for (other, master_key) in identities.iter_mut().zip(master_keys) {
+            let self_signing_key = other.self_signing_key().to_owned();
+            other.update(master_key, self_signing_key, Some(own_identity.user_signing_key()))?;
+        }
+
+        let requests =
+            CrossSigningKeyRotationRequests { upload_signing_keys_req, upload_signatures_req };
+
+        *self.inner.pending_cross_signing_rotation.lock().unwrap() =
+            Some(PendingCrossSigningRotation {
+                rotation,
+                identity,
+                own_identity,
+                identities,
+                devices,
+                requests: requests.clone(),
+            });
+
+        Ok(requests)
+    }
+
+    /// Start using the new cross-signing keys created by
+    /// [`OlmMachine::rotate_cross_signing_keys()`].
+    ///
+    /// This should be called once the request to upload the new keys
+    /// succeeded, and before uploading the signatures. Our private identity
+    /// is replaced and our local copies of the devices and identities which
+    /// were signed again are updated, so they remain verified.
+    ///
+    /// If the process stops between the upload and this call, the new keys
+    /// are lost. Rotating the keys again fixes it, since the master key is
+    /// kept.
+    ///
+    /// Does nothing if there isn't any pending rotation.
+    pub async fn confirm_cross_signing_key_rotation(&self) -> StoreResult<()> {
+        let Some(pending) = self.inner.pending_cross_signing_rotation.lock().unwrap().take() else {
+            return Ok(());
+        };
+
+        let PendingCrossSigningRotation { identity, own_identity, identities, devices, .. } =
+            pending;
+
+        identity.mark_as_shared();
+        *self.inner.user_identity.lock().await = identity.clone();
+
+        self.store()
+            .save_changes(Changes {
+                identities: IdentityChanges {
+                    changed: iter::once(own_identity.into())
+                        .chain(identities.into_iter().map(Into::into))
+                        .collect(),
+                    ..Default::default()
+                },
+                devices: DeviceChanges { changed: devices, ..Default::default() },
+                private_identity: Some(identity),
+                ..Default::default()
+            })
+            .await
+    }
+
     /// Upload the device keys for this [`OlmMachine`].
     ///
     /// **Warning**: Do not use this method if
@@ -882,6 +1190,8 @@ impl OlmMachine {
             Ok(mut session) => {
                 Span::current().record("session_id", session.session_id());
 
+                session.shared_history = content.shared_history;
+
                 let sender_data =
                     SenderDataFinder::find_using_event(self.store(), sender_key, event, &session)
                         .await?;
@@ -936,6 +1246,36 @@ impl OlmMachine {
         }
     }
 
+    /// Store the data about a room key bundle that has been sent to us, so
+    /// that the bundle can be imported once we join the room.
+    async fn receive_room_key_bundle_data(
+        &self,
+        sender_key: Curve25519PublicKey,
+        event: &DecryptedRoomKeyBundleEvent,
+    ) -> OlmResult<()> {
+        let room_id = &event.content.room_id;
+        debug!(%room_id, sender = ?event.sender, "Received a room key bundle");
+
+        let Some(device) =
+            self.inner.store.get_device_from_curve_key(&event.sender, sender_key).await?
+        else {
+            warn!(
+                %room_id,
+                sender = ?event.sender,
+                "Ignoring a room key bundle sent by an unknown device"
+            );
+            return Ok(());
+        };
+
+        let data = StoredRoomKeyBundleData {
+            sender_user: device.user_id().to_owned(),
+            sender_device: device.device_id().to_owned(),
+            bundle_data: event.content.clone(),
+        };
+
+        Ok(self.inner.store.save_room_key_bundle_data(&data).await?)
+    }
+
     fn add_withheld_info(&self, changes: &mut Changes, event: &RoomKeyWithheldEvent) {
         debug!(?event.content, "Processing `m.room_key.withheld` event");
 
@@ -1045,6 +1385,63 @@ impl OlmMachine {
         self.inner.group_session_manager.encrypt(room_id, event_type, content).await
     }
 
+    /// Encrypt a state event for the given room, as described in [MSC3414].
+    ///
+    /// The encrypted content should be sent as a `m.room.encrypted` state
+    /// event, with a state key created by [`encrypted_state_key()`].
+    ///
+    /// Beware that a room key needs to be shared before this method
+    /// can be called using the [`OlmMachine::share_room_key`] method.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room for which the state event should be
+    ///   encrypted.
+    ///
+    /// * `content` - The plaintext content of the state event that should be
+    ///   encrypted.
+    ///
+    /// * `state_key` - The plaintext state key of the state event.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a room key for the given room wasn't shared beforehand.
+    ///
+    /// [MSC3414]: https://github.com/matrix-org/matrix-spec-proposals/pull/3414
+    /// [`encrypted_state_key()`]: crate::types::events::room::encrypted::encrypted_state_key
+    pub async fn encrypt_state_event(
+        &self,
+        room_id: &RoomId,
+        content: impl StateEventContent,
+        state_key: &str,
+    ) -> MegolmResult<Raw<RoomEncryptedEventContent>> {
+        let event_type = content.event_type().to_string();
+        let content = Raw::new(&content)?.cast();
+        self.encrypt_state_event_raw(room_id, &event_type, state_key, &content).await
+    }
+
+    /// Encrypt a raw JSON state event content for the given room.
+    ///
+    /// This method is equivalent to the [`OlmMachine::encrypt_state_event()`]
+    /// method but operates on an arbitrary JSON value instead of strongly-typed
+    /// event content struct.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a room key for the given room wasn't shared beforehand.
+    pub async fn encrypt_state_event_raw(
+        &self,
+        room_id: &RoomId,
+        event_type: &str,
+        state_key: &str,
+        content: &Raw<AnyStateEventContent>,
+    ) -> MegolmResult<Raw<RoomEncryptedEventContent>> {
+        self.inner
+            .group_session_manager
+            .encrypt_state(room_id, event_type, state_key, content)
+            .await
+    }
+
     /// Forces the currently active room key, which is used to encrypt messages,
     /// to be rotated.
     ///
@@ -1059,6 +1456,31 @@ impl OlmMachine {
         self.inner.group_session_manager.invalidate_group_session(room_id).await
     }
 
+    /// Get a snapshot of the state of the room key currently used to encrypt
+    /// messages in the given room: when it was created, how many messages it
+    /// encrypted and which devices received it.
+    ///
+    /// Returns `None` if no room key was created for the room yet.
+    pub async fn outbound_group_session_info(
+        &self,
+        room_id: &RoomId,
+    ) -> Option<OutboundGroupSessionInfo> {
+        self.inner
+            .group_session_manager
+            .get_or_load_outbound_group_session(room_id)
+            .await
+            .map(|session| session.info())
+    }
+
+    /// Set the custom policy deciding if the room key of a room should be
+    /// rotated before it's shared with new recipients.
+    ///
+    /// See [`RoomKeyRotationPolicy`] for more details, passing `None` removes
+    /// the policy.
+    pub fn set_room_key_rotation_policy(&self, policy: Option<Arc<dyn RoomKeyRotationPolicy>>) {
+        self.inner.group_session_manager.set_rotation_policy(policy);
+    }
+
     /// Get to-device requests to share a room key with users in a room.
     ///
     /// # Arguments
@@ -1084,7 +1506,175 @@ impl OlmMachine {
         users: impl Iterator<Item = &UserId>,
         encryption_settings: impl Into<EncryptionSettings>,
     ) -> OlmResult<Vec<Arc<ToDeviceRequest>>> {
-        self.inner.group_session_manager.share_room_key(room_id, users, encryption_settings).await
+        let requests = self
+            .inner
+            .group_session_manager
+            .share_room_key(room_id, users, encryption_settings)
+            .await?;
+
+        for request in &requests {
+            self.track_outgoing_to_device_request(request);
+        }
+
+        Ok(requests)
+    }
+
+    /// Get a to-device request to send the data about a room key bundle to all
+    /// the devices of the given user.
+    ///
+    /// The bundle itself, built with [`Store::build_room_key_bundle`], needs
+    /// to be encrypted and uploaded to the media repository beforehand, the
+    /// given `content` pointing to the uploaded file.
+    ///
+    /// The devices of the user are selected with the given [`CollectStrategy`],
+    /// the same way as the recipients of a room key. Olm sessions need to be
+    /// established with the devices of the user beforehand, devices for which
+    /// we don't have a session are skipped.
+    ///
+    /// # Returns
+    ///
+    /// The to-device request that needs to be sent out, or `None` if there
+    /// isn't any device to send the bundle data to.
+    pub async fn share_room_key_bundle_data(
+        &self,
+        user_id: &UserId,
+        content: RoomKeyBundleContent,
+        collect_strategy: &CollectStrategy,
+    ) -> OlmResult<Option<ToDeviceRequest>> {
+        let RecipientDevices { devices, withheld_devices } =
+            collect_recipient_devices(self.store(), iter::once(user_id), collect_strategy, None)
+                .await?;
+
+        if !withheld_devices.is_empty() {
+            debug!(
+                num_devices = withheld_devices.len(),
+                "Not sharing a room key bundle with devices excluded by the collect strategy"
+            );
+        }
+
+        let store = self.inner.store.crypto_store();
+        let mut messages = BTreeMap::new();
+        let mut used_sessions = Vec::new();
+
+        for device in devices.into_values().flatten() {
+            match device.encrypt(&store, content.event_type(), content.clone()).await {
+                Ok((session, encrypted)) => {
+                    messages.insert(
+                        DeviceIdOrAllDevices::DeviceId(device.device_id().to_owned()),
+                        encrypted.cast(),
+                    );
+                    used_sessions.push(session);
+                }
+                Err(OlmError::MissingSession) => {
+                    debug!(
+                        user_id = ?device.user_id(),
+                        device_id = ?device.device_id(),
+                        "Not sharing a room key bundle with a device we have no Olm session with"
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if messages.is_empty() {
+            return Ok(None);
+        }
+
+        self.inner.store.save_sessions(&used_sessions).await?;
+
+        let request = ToDeviceRequest {
+            event_type: ToDeviceEventType::RoomEncrypted,
+            txn_id: TransactionId::new(),
+            messages: BTreeMap::from([(user_id.to_owned(), messages)]),
+        };
+        self.track_outgoing_to_device_request(&request);
+
+        Ok(Some(request))
+    }
+
+    /// Get a to-device request to send a custom event, encrypted with Olm, to
+    /// the given devices.
+    ///
+    /// The devices are selected with the given [`CollectStrategy`], the same
+    /// way as the recipients of a room key, out of the given devices. Olm
+    /// sessions need to be established with the devices beforehand.
+    ///
+    /// # Returns
+    ///
+    /// The to-device request that needs to be sent out, or `None` if the event
+    /// couldn't be encrypted for any device, along with the devices which
+    /// won't receive the event and the reason why. Our own device never
+    /// receives the event.
+    pub async fn encrypt_content_for_devices(
+        &self,
+        devices: Vec<DeviceData>,
+        event_type: &str,
+        content: &Value,
+        collect_strategy: &CollectStrategy,
+    ) -> OlmResult<(Option<ToDeviceRequest>, Vec<(DeviceData, WithheldCode)>)> {
+        let user_ids: BTreeSet<&UserId> = devices.iter().map(|d| d.user_id()).collect();
+        let RecipientDevices { devices: recipients, withheld_devices } =
+            collect_recipient_devices(self.store(), user_ids.into_iter(), collect_strategy, None)
+                .await?;
+
+        // The strategy considers every device of the users, only keep the ones we were
+        // asked about.
+        let mut requested: BTreeMap<(OwnedUserId, OwnedDeviceId), DeviceData> = devices
+            .into_iter()
+            .map(|d| ((d.user_id().to_owned(), d.device_id().to_owned()), d))
+            .collect();
+        let mut take_requested =
+            |d: &DeviceData| requested.remove(&(d.user_id().to_owned(), d.device_id().to_owned()));
+
+        let recipients: Vec<DeviceData> =
+            recipients.into_values().flatten().filter_map(|d| take_requested(&d)).collect();
+        let mut withheld_devices: Vec<(DeviceData, WithheldCode)> = withheld_devices
+            .into_iter()
+            .filter_map(|(d, code)| take_requested(&d).map(|d| (d, code)))
+            .collect();
+
+        // What's left are devices the strategy didn't consider, i.e. our own device.
+        withheld_devices.extend(requested.into_values().map(|d| (d, WithheldCode::Unauthorised)));
+
+        let store = self.inner.store.crypto_store();
+        let mut messages: BTreeMap<OwnedUserId, BTreeMap<_, _>> = BTreeMap::new();
+        let mut used_sessions = Vec::new();
+
+        for device in recipients {
+            match device.encrypt(&store, event_type, content).await {
+                Ok((session, encrypted)) => {
+                    messages.entry(device.user_id().to_owned()).or_default().insert(
+                        DeviceIdOrAllDevices::DeviceId(device.device_id().to_owned()),
+                        encrypted.cast(),
+                    );
+                    used_sessions.push(session);
+                }
+                Err(OlmError::MissingSession) => {
+                    debug!(
+                        user_id = ?device.user_id(),
+                        device_id = ?device.device_id(),
+                        "Not sending a to-device event to a device we have no Olm session with"
+                    );
+                    withheld_devices.push((device, WithheldCode::NoOlm));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if messages.is_empty() {
+            return Ok((None, withheld_devices));
+        }
+
+        self.inner.store.save_sessions(&used_sessions).await?;
+
+        let request = ToDeviceRequest {
+            event_type: ToDeviceEventType::RoomEncrypted,
+            txn_id: TransactionId::new(),
+            messages,
+        };
+        self.track_outgoing_to_device_request(&request);
+
+        Ok((Some(request), withheld_devices))
     }
 
     /// Receive an unencrypted verification event.
@@ -1166,6 +1756,9 @@ impl OlmMachine {
             AnyDecryptedOlmEvent::Dummy(_) => {
                 debug!("Received an `m.dummy` event");
             }
+            AnyDecryptedOlmEvent::RoomKeyBundle(e) => {
+                self.receive_room_key_bundle_data(decrypted.result.sender_key, e).await?;
+            }
             AnyDecryptedOlmEvent::Custom(_) => {
                 warn!("Received an unexpected encrypted to-device event");
             }
@@ -1183,12 +1776,39 @@ impl OlmMachine {
     /// Mark an outgoing to-device requests as sent.
     async fn mark_to_device_request_as_sent(&self, request_id: &TransactionId) -> StoreResult<()> {
         self.inner.verification_machine.mark_request_as_sent(request_id);
+        self.inner.verification_machine.save_pending_requests().await?;
         self.inner.key_request_machine.mark_outgoing_request_as_sent(request_id).await?;
         self.inner.group_session_manager.mark_request_as_sent(request_id).await?;
         self.inner.session_manager.mark_outgoing_request_as_sent(request_id);
+
+        let message_count =
+            self.inner.pending_to_device_message_counts.write().unwrap().remove(request_id);
+        if let Some(message_count) = message_count {
+            self.inner
+                .store
+                .metrics()
+                .increment_by(CryptoCounter::ToDeviceMessageSent, message_count as u64);
+        }
+
         Ok(())
     }
 
+    /// Resume, or cancel, the verification requests that were in progress when
+    /// the client was last shut down.
+    ///
+    /// Requests which didn't yet transition into a concrete verification flow
+    /// are resumed, interrupted SAS or QR code flows are cancelled. Flows which
+    /// are already known to this `OlmMachine` are left alone.
+    ///
+    /// This should be called once, when the client starts up. It shouldn't be
+    /// called when an `OlmMachine` is recreated for a store which is still in
+    /// use, e.g. after another process modified the store, or by a process
+    /// which only decrypts notifications, since that would cancel the
+    /// verification flows which are in progress in the main process.
+    pub async fn restore_pending_verification_requests(&self) -> StoreResult<()> {
+        self.inner.verification_machine.restore_pending_requests().await
+    }
+
     /// Get a verification object for the given user id with the given flow id.
     pub fn get_verification(&self, user_id: &UserId, flow_id: &str) -> Option<Verification> {
         self.inner.verification_machine.get_verification(user_id, flow_id)
@@ -1405,6 +2025,11 @@ impl OlmMachine {
             error!(error = ?e, "Error marking a tracked user as changed");
         }
 
+        self.inner.store.metrics().increment_by(
+            CryptoCounter::ToDeviceMessageReceived,
+            sync_changes.to_device_events.len() as u64,
+        );
+
         for raw_event in sync_changes.to_device_events {
             let raw_event =
                 Box::pin(self.receive_to_device_event(transaction, &mut changes, raw_event)).await;
@@ -1597,6 +2222,7 @@ impl OlmMachine {
                     .collect(),
             },
             verification_state,
+            session_id: Some(session.session_id().to_owned()),
         })
     }
 
@@ -1755,7 +2381,11 @@ impl OlmMachine {
         room_id: &RoomId,
         decryption_settings: &DecryptionSettings,
     ) -> Result<RoomEventDecryptionResult, CryptoStoreError> {
-        match self.decrypt_room_event_inner(raw_event, room_id, true, decryption_settings).await {
+        let result =
+            self.decrypt_room_event_inner(raw_event, room_id, true, decryption_settings).await;
+        self.record_decryption_metrics(raw_event, room_id, &result).await;
+
+        match result {
             Ok(decrypted) => Ok(RoomEventDecryptionResult::Decrypted(decrypted)),
             Err(err) => Ok(RoomEventDecryptionResult::UnableToDecrypt(megolm_error_to_utd_info(
                 raw_event, err,
@@ -1776,7 +2406,88 @@ impl OlmMachine {
         room_id: &RoomId,
         decryption_settings: &DecryptionSettings,
     ) -> MegolmResult<DecryptedRoomEvent> {
-        self.decrypt_room_event_inner(event, room_id, true, decryption_settings).await
+        let result = self.decrypt_room_event_inner(event, room_id, true, decryption_settings).await;
+        self.record_decryption_metrics(event, room_id, &result).await;
+
+        result
+    }
+
+    /// Record the outcome of an attempt to decrypt a room event in the
+    /// metrics, if they are enabled.
+    ///
+    /// Every attempt is recorded, so an event which is retried, e.g. each time
+    /// it's displayed, is counted more than once.
+    async fn record_decryption_metrics(
+        &self,
+        raw_event: &Raw<EncryptedEvent>,
+        room_id: &RoomId,
+        result: &MegolmResult<DecryptedRoomEvent>,
+    ) {
+        let metrics = self.inner.store.metrics();
+
+        if !metrics.is_enabled() {
+            return;
+        }
+
+        let error = match result {
+            Ok(_) => {
+                metrics.increment(CryptoCounter::DecryptionAttemptSuccess);
+                return;
+            }
+            Err(error) => error,
+        };
+
+        // Store errors are a problem with our application, not a UTD.
+        let Some(reason) = megolm_error_to_utd_reason(error) else {
+            return;
+        };
+
+        let unable_to_decrypt_info =
+            UnableToDecryptInfo { session_id: encrypted_event_session_id(raw_event), reason };
+
+        // Only a missing room key can be explained by a withheld notice or by the key
+        // backup, don't hit the store for the other failures.
+        let missing_room_key = matches!(
+            unable_to_decrypt_info.reason,
+            UnableToDecryptReason::MissingMegolmSession
+                | UnableToDecryptReason::UnknownMegolmMessageIndex
+        );
+
+        let withheld_code = match &unable_to_decrypt_info.session_id {
+            Some(session_id)
+                if unable_to_decrypt_info.reason == UnableToDecryptReason::MissingMegolmSession =>
+            {
+                self.inner
+                    .store
+                    .get_withheld_info(room_id, session_id)
+                    .await
+                    .ok()
+                    .flatten()
+                    .map(|event| event.content.withheld_code())
+            }
+            _ => None,
+        };
+
+        // We never ask the server whether a backup exists, so unless one is enabled, we
+        // can't tell whether it could help with historical messages.
+        let is_backup_configured = missing_room_key && self.inner.backup_machine.enabled().await;
+        let backup_exists_on_server =
+            CryptoContextInfo::known_backup_existence(is_backup_configured, None);
+
+        let crypto_context_info = CryptoContextInfo {
+            device_creation_ts: self.device_creation_time(),
+            is_backup_configured,
+            backup_exists_on_server,
+            withheld_code,
+        };
+
+        let cause = UtdCause::determine(
+            Some(raw_event.cast_ref()),
+            crypto_context_info,
+            &unable_to_decrypt_info,
+        );
+
+        metrics.increment(CryptoCounter::DecryptionAttemptFailure(cause));
     }
 
     #[instrument(name = "decrypt_room_event", skip_all, fields(?room_id, event_id, origin_server_ts, sender, algorithm, session_id, message_index, sender_key))]
@@ -2011,6 +2722,32 @@ impl OlmMachine {
         self.get_megolm_encryption_info(room_id, &event, &content).await
     }
 
+    /// Get the encryption info of the events encrypted with the given Megolm
+    /// session.
+    ///
+    /// Like [`OlmMachine::get_room_event_encryption_info()`], this
+    /// recalculates the [`EncryptionInfo`] based on the current verification
+    /// status of the sender, but doesn't need the encrypted event, e.g. to
+    /// refresh the [`EncryptionInfo`] of already decrypted events once
+    /// [`Store::sender_data_updates_stream()`] reports that the session got
+    /// upgraded.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The ID of the room where the events were sent to.
+    /// * `session_id` - The ID of the Megolm session the events were encrypted
+    ///   with.
+    /// * `sender` - The user who sent the events.
+    pub async fn get_session_encryption_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        sender: &UserId,
+    ) -> MegolmResult<EncryptionInfo> {
+        let session = self.get_inbound_group_session_or_error(room_id, session_id).await?;
+        self.get_encryption_info(&session, sender).await
+    }
+
     /// Update the list of tracked users.
     ///
     /// The OlmMachine maintains a list of users whose devices we are keeping
@@ -2393,8 +3130,9 @@ impl OlmMachine {
     /// [`SetRoomSettingsError::EncryptionDowngrade`].
     ///
     /// If the settings are valid, they will be persisted to the crypto store.
-    /// These settings are not used directly by this library, but the saved
-    /// settings can be retrieved via [`OlmMachine::room_settings`].
+    /// Apart from the [`RoomSettings::sharing_policy`], these settings are not
+    /// used directly by this library, but the saved settings can be retrieved
+    /// via [`OlmMachine::room_settings`].
     pub async fn set_room_settings(
         &self,
         room_id: &RoomId,
@@ -2520,6 +3258,12 @@ fn sender_data_to_verification_state(
         SenderData::SenderVerified(KnownSenderData { device_id, .. }) => {
             (VerificationState::Verified, device_id)
         }
+        SenderData::SharedHistory { .. } => (
+            VerificationState::Unverified(VerificationLevel::None(
+                DeviceLinkProblem::InsecureSource,
+            )),
+            None,
+        ),
     }
 }
 
@@ -2547,6 +3291,22 @@ pub struct CrossSigningBootstrapRequests {
     pub upload_signatures_req: UploadSignaturesRequest,
 }
 
+/// The requests to be sent out after rotating our cross-signing subkeys
+/// using [`OlmMachine::rotate_cross_signing_keys`].
+#[derive(Debug, Clone)]
+pub struct CrossSigningKeyRotationRequests {
+    /// Request to upload the new cross-signing keys.
+    ///
+    /// Should be sent first, it may require user interactive auth.
+    pub upload_signing_keys_req: UploadSigningKeysRequest,
+
+    /// Request to upload the signatures of our own devices and of the users
+    /// we verified, made with the new keys.
+    ///
+    /// Should be sent last.
+    pub upload_signatures_req: UploadSignaturesRequest,
+}
+
 /// Data contained from a sync response and that needs to be processed by the
 /// OlmMachine.
 #[derive(Debug)]
@@ -2575,8 +3335,24 @@ fn megolm_error_to_utd_info(
     raw_event: &Raw<EncryptedEvent>,
     error: MegolmError,
 ) -> Result<UnableToDecryptInfo, CryptoStoreError> {
-    use MegolmError::*;
     let reason = match error {
+        // Pass through crypto store errors, which indicate a problem with our
+        // application, rather than a UTD.
+        MegolmError::Store(error) => return Err(error),
+        // Store errors are the only ones without a reason, and they were handled above.
+        error => megolm_error_to_utd_reason(&error).unwrap_or(UnableToDecryptReason::Unknown),
+    };
+
+    Ok(UnableToDecryptInfo { session_id: encrypted_event_session_id(raw_event), reason })
+}
+
+/// Convert a [`MegolmError`] into an [`UnableToDecryptReason`].
+///
+/// Returns `None` for [`MegolmError::Store`], which doesn't represent a
+/// problem with the message itself.
+fn megolm_error_to_utd_reason(error: &MegolmError) -> Option<UnableToDecryptReason> {
+    use MegolmError::*;
+    Some(match error {
         EventError(_) => UnableToDecryptReason::MalformedEncryptedEvent,
         Decode(_) => UnableToDecryptReason::MalformedEncryptedEvent,
         MissingRoomKey(_) => UnableToDecryptReason::MissingMegolmSession,
@@ -2586,21 +3362,21 @@ fn megolm_error_to_utd_info(
         Decryption(_) => UnableToDecryptReason::MegolmDecryptionFailure,
         JsonError(_) => UnableToDecryptReason::PayloadDeserializationFailure,
         MismatchedIdentityKeys(_) => UnableToDecryptReason::MismatchedIdentityKeys,
-        SenderIdentityNotTrusted(level) => UnableToDecryptReason::SenderIdentityNotTrusted(level),
-
-        // Pass through crypto store errors, which indicate a problem with our
-        // application, rather than a UTD.
-        Store(error) => Err(error)?,
-    };
+        SenderIdentityNotTrusted(level) => {
+            UnableToDecryptReason::SenderIdentityNotTrusted(level.clone())
+        }
+        Store(_) => return None,
+    })
+}
 
-    let session_id = raw_event.deserialize().ok().and_then(|ev| match ev.content.scheme {
+/// Get the ID of the Megolm session an encrypted event was encrypted with.
+fn encrypted_event_session_id(raw_event: &Raw<EncryptedEvent>) -> Option<String> {
+    raw_event.deserialize().ok().and_then(|ev| match ev.content.scheme {
         RoomEventEncryptionScheme::MegolmV1AesSha2(s) => Some(s.session_id),
         #[cfg(feature = "experimental-algorithms")]
         RoomEventEncryptionScheme::MegolmV2AesSha2(s) => Some(s.session_id),
         RoomEventEncryptionScheme::Unknown(_) => None,
-    });
-
-    Ok(UnableToDecryptInfo { session_id, reason })
+    })
 }
 
 #[cfg(test)]
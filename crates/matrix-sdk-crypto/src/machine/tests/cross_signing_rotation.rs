@@ -0,0 +1,154 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::iter;
+
+use assert_matches2::assert_matches;
+use matrix_sdk_test::async_test;
+use vodozemac::Ed25519PublicKey;
+
+use crate::{
+    machine::{
+        test_helpers::get_machine_pair_with_session,
+        tests::{
+            alice_device_id, alice_id,
+            decryption_verification_state::mark_alice_identity_as_verified_test_helper,
+            setup_cross_signing_for_machine_test_helper, user_id,
+        },
+    },
+    types::CrossSigningKey,
+    CrossSigningKeyRotation, CrossSigningKeyRotationRequests, OlmMachine, SignatureError,
+};
+
+fn first_key(key: &Option<CrossSigningKey>) -> Option<Ed25519PublicKey> {
+    key.as_ref()?.get_first_key_and_id().map(|(_, key)| key)
+}
+
+#[async_test]
+async fn test_rotating_the_subkeys_keeps_the_master_key_and_the_trust() {
+    let (alice, bob) = get_machine_pair_with_session(alice_id(), user_id(), false).await;
+    setup_cross_signing_for_machine_test_helper(&alice, &bob).await;
+    mark_alice_identity_as_verified_test_helper(&alice, &bob).await;
+    bob.update_tracked_users(iter::once(alice.user_id())).await.unwrap();
+
+    let previous = bob.bootstrap_cross_signing(false).await.unwrap().upload_signing_keys_req;
+    let alice_identity = bob.get_identity(alice.user_id(), None).await.unwrap().unwrap();
+    assert!(alice_identity.is_verified());
+
+    let CrossSigningKeyRotationRequests { upload_signing_keys_req, upload_signatures_req } =
+        bob.rotate_cross_signing_keys(CrossSigningKeyRotation::Both).await.unwrap();
+
+    assert_eq!(first_key(&upload_signing_keys_req.master_key), first_key(&previous.master_key));
+    assert_ne!(
+        first_key(&upload_signing_keys_req.self_signing_key),
+        first_key(&previous.self_signing_key)
+    );
+    assert_ne!(
+        first_key(&upload_signing_keys_req.user_signing_key),
+        first_key(&previous.user_signing_key)
+    );
+
+    // Our own device and Alice are signed again with the new keys.
+    let bob_signed_keys = upload_signatures_req.signed_keys.get(bob.user_id()).unwrap();
+    assert!(bob_signed_keys.iter().any(|(key_id, _)| key_id == bob.device_id().as_str()));
+    assert!(upload_signatures_req.signed_keys.contains_key(alice.user_id()));
+
+    // The local state is updated once the new keys are uploaded, nothing lost
+    // its trust.
+    bob.confirm_cross_signing_key_rotation().await.unwrap();
+
+    let alice_identity = bob.get_identity(alice.user_id(), None).await.unwrap().unwrap();
+    assert!(alice_identity.is_verified());
+    let bob_identity = bob.get_identity(bob.user_id(), None).await.unwrap().unwrap();
+    assert!(bob_identity.is_verified());
+    let bob_device = bob.get_device(bob.user_id(), bob.device_id(), None).await.unwrap().unwrap();
+    assert!(bob_device.is_cross_signed_by_owner());
+}
+
+#[async_test]
+async fn test_rotating_only_the_self_signing_key() {
+    let (alice, bob) = get_machine_pair_with_session(alice_id(), user_id(), false).await;
+    setup_cross_signing_for_machine_test_helper(&alice, &bob).await;
+    mark_alice_identity_as_verified_test_helper(&alice, &bob).await;
+    bob.update_tracked_users(iter::once(alice.user_id())).await.unwrap();
+
+    let previous = bob.bootstrap_cross_signing(false).await.unwrap().upload_signing_keys_req;
+
+    let CrossSigningKeyRotationRequests { upload_signing_keys_req, upload_signatures_req } =
+        bob.rotate_cross_signing_keys(CrossSigningKeyRotation::SelfSigning).await.unwrap();
+
+    assert_ne!(
+        first_key(&upload_signing_keys_req.self_signing_key),
+        first_key(&previous.self_signing_key)
+    );
+    assert_eq!(
+        first_key(&upload_signing_keys_req.user_signing_key),
+        first_key(&previous.user_signing_key)
+    );
+
+    // Alice doesn't need to be signed again.
+    assert!(!upload_signatures_req.signed_keys.contains_key(alice.user_id()));
+    assert!(upload_signatures_req.signed_keys.contains_key(bob.user_id()));
+}
+
+#[async_test]
+async fn test_rotated_keys_are_pending_until_confirmed() {
+    let (alice, bob) = get_machine_pair_with_session(alice_id(), user_id(), false).await;
+    setup_cross_signing_for_machine_test_helper(&alice, &bob).await;
+    bob.bootstrap_cross_signing(false).await.unwrap();
+
+    let previous = bob.export_cross_signing_keys().await.unwrap().unwrap();
+
+    let first = bob.rotate_cross_signing_keys(CrossSigningKeyRotation::Both).await.unwrap();
+
+    // Nothing changed locally, the upload might still fail.
+    let exported = bob.export_cross_signing_keys().await.unwrap().unwrap();
+    assert_eq!(exported.self_signing_key, previous.self_signing_key);
+    assert_eq!(exported.user_signing_key, previous.user_signing_key);
+
+    // Retrying the upload uses the same keys.
+    let second = bob.rotate_cross_signing_keys(CrossSigningKeyRotation::Both).await.unwrap();
+    assert_eq!(
+        first_key(&second.upload_signing_keys_req.self_signing_key),
+        first_key(&first.upload_signing_keys_req.self_signing_key)
+    );
+    assert_eq!(
+        first_key(&second.upload_signing_keys_req.user_signing_key),
+        first_key(&first.upload_signing_keys_req.user_signing_key)
+    );
+
+    bob.confirm_cross_signing_key_rotation().await.unwrap();
+
+    let exported = bob.export_cross_signing_keys().await.unwrap().unwrap();
+    assert_eq!(exported.master_key, previous.master_key);
+    assert_ne!(exported.self_signing_key, previous.self_signing_key);
+    assert_ne!(exported.user_signing_key, previous.user_signing_key);
+
+    // Once confirmed, rotating again creates new keys.
+    let third = bob.rotate_cross_signing_keys(CrossSigningKeyRotation::Both).await.unwrap();
+    assert_ne!(
+        first_key(&third.upload_signing_keys_req.self_signing_key),
+        first_key(&first.upload_signing_keys_req.self_signing_key)
+    );
+}
+
+#[async_test]
+async fn test_rotating_the_subkeys_requires_the_master_key() {
+    let machine = OlmMachine::new(user_id(), alice_device_id()).await;
+
+    assert_matches!(
+        machine.rotate_cross_signing_keys(CrossSigningKeyRotation::Both).await,
+        Err(SignatureError::MissingSigningKey)
+    );
+}
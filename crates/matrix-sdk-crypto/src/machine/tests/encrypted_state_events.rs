@@ -0,0 +1,106 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::iter;
+
+use assert_matches2::{assert_let, assert_matches};
+use matrix_sdk_test::async_test;
+use ruma::{
+    events::{room::topic::RoomTopicEventContent, AnySyncStateEvent, SyncStateEvent},
+    room_id,
+    serde::Raw,
+    MilliSecondsSinceUnixEpoch, RoomId,
+};
+use serde_json::json;
+
+use crate::{
+    machine::tests,
+    types::events::room::encrypted::{encrypted_state_key, EncryptedEvent},
+    utilities::json_convert,
+    DecryptionSettings, EncryptionSettings, EventError, MegolmError, OlmMachine, TrustRequirement,
+};
+
+async fn machine_with_room_key(room_id: &RoomId) -> OlmMachine {
+    let machine = OlmMachine::new(tests::user_id(), tests::alice_device_id()).await;
+
+    // Sharing a room key creates the matching inbound group session as well, so
+    // the machine is able to decrypt its own events.
+    machine.share_room_key(room_id, iter::empty(), EncryptionSettings::default()).await.unwrap();
+
+    machine
+}
+
+fn encrypted_state_event(
+    machine: &OlmMachine,
+    state_key: &str,
+    content: impl serde::Serialize,
+) -> Raw<EncryptedEvent> {
+    json_convert(&json!({
+        "event_id": "$xxxxx:example.org",
+        "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+        "sender": machine.user_id(),
+        "type": "m.room.encrypted",
+        "state_key": state_key,
+        "content": content,
+    }))
+    .unwrap()
+}
+
+#[async_test]
+async fn test_encrypted_state_event_roundtrip() {
+    let room_id = room_id!("!test:localhost");
+    let machine = machine_with_room_key(room_id).await;
+
+    let encrypted_content = machine
+        .encrypt_state_event(room_id, RoomTopicEventContent::new("Secret plans".to_owned()), "")
+        .await
+        .unwrap();
+
+    let event = encrypted_state_event(
+        &machine,
+        &encrypted_state_key("m.room.topic", ""),
+        encrypted_content,
+    );
+
+    let decryption_settings =
+        DecryptionSettings { sender_device_trust_requirement: TrustRequirement::Untrusted };
+    let decrypted =
+        machine.decrypt_room_event(&event, room_id, &decryption_settings).await.unwrap();
+
+    let decrypted = decrypted.event.cast::<AnySyncStateEvent>().deserialize().unwrap();
+    assert_let!(AnySyncStateEvent::RoomTopic(SyncStateEvent::Original(topic)) = decrypted);
+    assert_eq!(topic.state_key, "");
+    assert_eq!(topic.content.topic, "Secret plans");
+}
+
+#[async_test]
+async fn test_encrypted_state_event_with_mismatched_state_key() {
+    let room_id = room_id!("!test:localhost");
+    let machine = machine_with_room_key(room_id).await;
+
+    let encrypted_content = machine
+        .encrypt_state_event(room_id, RoomTopicEventContent::new("Secret plans".to_owned()), "")
+        .await
+        .unwrap();
+
+    // The server moved the event to the room name slot of the room state.
+    let event =
+        encrypted_state_event(&machine, &encrypted_state_key("m.room.name", ""), encrypted_content);
+
+    let decryption_settings =
+        DecryptionSettings { sender_device_trust_requirement: TrustRequirement::Untrusted };
+    let err = machine.decrypt_room_event(&event, room_id, &decryption_settings).await.unwrap_err();
+
+    assert_matches!(err, MegolmError::EventError(EventError::MismatchedStateKey(..)));
+}
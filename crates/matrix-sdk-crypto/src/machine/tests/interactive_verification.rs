@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::Arc;
+
 use matrix_sdk_test::async_test;
 use ruma::{
     api::client::to_device::send_event_to_device::v3::Response as ToDeviceResponse,
@@ -20,7 +22,9 @@ use ruma::{
 
 use crate::{
     machine::{test_helpers::get_machine_pair_with_setup_sessions_test_helper, tests},
+    store::MemoryStore,
     verification::tests::{outgoing_request_to_event, request_to_event},
+    DeviceData, OlmMachine,
 };
 
 #[async_test]
@@ -276,3 +280,50 @@ async fn test_interactive_verification_started_from_request() {
     assert!(alice_sas.is_done());
     assert!(bob_device.is_verified());
 }
+
+#[async_test]
+async fn test_regenerating_the_machine_does_not_cancel_a_verification() {
+    let store = Arc::new(MemoryStore::new());
+    let alice =
+        OlmMachine::with_store(tests::alice_id(), tests::alice_device_id(), store.clone(), None)
+            .await
+            .unwrap();
+    let bob = OlmMachine::new(tests::user_id(), tests::bob_device_id()).await;
+
+    let alice_device = DeviceData::from_machine_test_helper(&alice).await.unwrap();
+    let bob_device = DeviceData::from_machine_test_helper(&bob).await.unwrap();
+    alice.store().save_device_data(&[bob_device]).await.unwrap();
+    bob.store().save_device_data(&[alice_device]).await.unwrap();
+
+    // Alice requests a verification, Bob accepts it, and Alice starts a SAS flow.
+    let bob_device = alice.get_device(bob.user_id(), bob.device_id(), None).await.unwrap().unwrap();
+    let (alice_request, request) =
+        bob_device.request_verification_with_methods(vec![VerificationMethod::SasV1]);
+    let flow_id = alice_request.flow_id().as_str();
+
+    bob.handle_verification_event(&request_to_event(alice.user_id(), &request)).await;
+    let bob_request = bob.get_verification_request(alice.user_id(), flow_id).unwrap();
+    let accept_request = bob_request.accept_with_methods(vec![VerificationMethod::SasV1]).unwrap();
+
+    alice.handle_verification_event(&request_to_event(bob.user_id(), &accept_request)).await;
+    let (alice_sas, _) = alice_request.start_sas().await.unwrap().unwrap();
+    alice.inner.verification_machine.save_pending_requests().await.unwrap();
+
+    // The machine is recreated while the SAS flow is in progress, like
+    // `regenerate_olm` does once another process touched the store.
+    let regenerated =
+        OlmMachine::with_store(tests::alice_id(), tests::alice_device_id(), store.clone(), None)
+            .await
+            .unwrap();
+
+    // The flow isn't cancelled, neither in memory nor towards Bob.
+    assert!(!alice_request.is_cancelled());
+    assert!(!alice_sas.is_cancelled());
+    assert!(regenerated.inner.verification_machine.outgoing_messages().is_empty());
+
+    // Restoring the pending requests on the machine the flow lives in doesn't
+    // cancel it either.
+    alice.restore_pending_verification_requests().await.unwrap();
+    assert!(!alice_request.is_cancelled());
+    assert!(!alice_sas.is_cancelled());
+}
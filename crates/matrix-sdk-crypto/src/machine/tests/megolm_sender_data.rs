@@ -14,11 +14,12 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
-use std::{fmt::Debug, iter, pin::Pin};
+use std::{fmt::Debug, iter, pin::Pin, time::Duration};
 
 use assert_matches::assert_matches;
 use futures_core::Stream;
 use futures_util::{FutureExt, StreamExt};
+use matrix_sdk_common::deserialized_responses::{VerificationLevel, VerificationState};
 use matrix_sdk_test::async_test;
 use ruma::{room_id, user_id, RoomId, TransactionId, UserId};
 use serde::Serialize;
@@ -116,6 +117,7 @@ async fn test_update_unknown_device_senderdata_on_keys_query() {
 
     let (alice, bob) = get_machine_pair().await;
     let mut bob_room_keys_received_stream = Box::pin(bob.store().room_keys_received_stream());
+    let mut bob_sender_data_updates_stream = Box::pin(bob.store().sender_data_updates_stream());
 
     // `get_machine_pair_with_setup_sessions_test_helper` tells Bob about Alice's
     // device keys, so to run this test, we need to make him forget them.
@@ -150,9 +152,10 @@ async fn test_update_unknown_device_senderdata_on_keys_query() {
     .await
     .unwrap();
 
-    // Then Bob should have received an update about the session, and it should now
-    // be `SenderData::DeviceInfo`
-    let room_key_info = get_room_key_received_update(&mut bob_room_keys_received_stream);
+    // Then Bob should have received an update about the session, once the sender
+    // data was updated in the background, and it should now be
+    // `SenderData::DeviceInfo`
+    let room_key_info = wait_for_sender_data_update(&mut bob_sender_data_updates_stream).await;
     let session = get_inbound_group_session_or_panic(&bob, &room_key_info).await;
 
     assert_matches!(
@@ -171,6 +174,7 @@ async fn test_update_device_info_senderdata_on_keys_query() {
 
     let (alice, bob) = get_machine_pair().await;
     let mut bob_room_keys_received_stream = Box::pin(bob.store().room_keys_received_stream());
+    let mut bob_sender_data_updates_stream = Box::pin(bob.store().sender_data_updates_stream());
 
     // Alice starts a megolm session and shares the key with Bob
     let room_id = room_id!("!test:example.org");
@@ -199,12 +203,74 @@ async fn test_update_device_info_senderdata_on_keys_query() {
     let kq_response = bootstrap_requests_to_keys_query_response(bootstrap_requests);
     bob.receive_keys_query_response(&TransactionId::new(), &kq_response).await.unwrap();
 
-    // Then Bob should have received an update about the session, and it should now
-    // be `SenderData::SenderUnverified`
+    // Then Bob should have received an update about the session, once the sender
+    // data was updated in the background, and it should now be
+    // `SenderData::SenderUnverified`
+    let room_key_info = wait_for_sender_data_update(&mut bob_sender_data_updates_stream).await;
+    let session = get_inbound_group_session_or_panic(&bob, &room_key_info).await;
+
+    assert_matches!(session.sender_data, SenderData::SenderUnverified(_));
+}
+
+/// If we have a megolm session from a cross-signed device whose owner's
+/// identity we don't know yet, test what happens when we get a /keys/query
+/// response that only includes the identity.
+#[async_test]
+async fn test_update_device_info_senderdata_on_identity_update() {
+    // Given Bob knows about Alice's cross-signed device, but not about her identity
+    let (alice, bob) = get_machine_pair().await;
+    let mut bob_room_keys_received_stream = Box::pin(bob.store().room_keys_received_stream());
+    let mut bob_sender_data_updates_stream = Box::pin(bob.store().sender_data_updates_stream());
+
+    let bootstrap_requests = alice.bootstrap_cross_signing(false).await.unwrap();
+    let kq_response = bootstrap_requests_to_keys_query_response(bootstrap_requests);
+
+    let mut devices_only_kq_response = kq_response.clone();
+    devices_only_kq_response.master_keys.clear();
+    devices_only_kq_response.self_signing_keys.clear();
+    bob.receive_keys_query_response(&TransactionId::new(), &devices_only_kq_response)
+        .await
+        .unwrap();
+
+    // and Alice shares a megolm session with Bob
+    let room_id = room_id!("!test:example.org");
+
+    let to_device_requests = alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+    let event = ToDeviceEvent::new(
+        alice.user_id().to_owned(),
+        to_device_requests_to_content(to_device_requests),
+    );
+    receive_to_device_event(&bob, &event).await;
+
     let room_key_info = get_room_key_received_update(&mut bob_room_keys_received_stream);
     let session = get_inbound_group_session_or_panic(&bob, &room_key_info).await;
+    assert_matches!(session.sender_data, SenderData::DeviceInfo { .. });
+
+    // When Bob receives a /keys/query response for Alice in which only her identity
+    // is new
+    bob.receive_keys_query_response(&TransactionId::new(), &kq_response).await.unwrap();
+
+    // Then the sender data of the session should be upgraded to
+    // `SenderData::SenderUnverified`
+    let room_key_info = wait_for_sender_data_update(&mut bob_sender_data_updates_stream).await;
+    assert_eq!(room_key_info.session_id, session.session_id());
 
+    let session = get_inbound_group_session_or_panic(&bob, &room_key_info).await;
     assert_matches!(session.sender_data, SenderData::SenderUnverified(_));
+
+    // And the encryption info of the events encrypted with the session reflects it
+    let encryption_info = bob
+        .get_session_encryption_info(room_id, session.session_id(), alice.user_id())
+        .await
+        .unwrap();
+    assert_eq!(encryption_info.session_id.as_deref(), Some(session.session_id()));
+    assert_eq!(
+        encryption_info.verification_state,
+        VerificationState::Unverified(VerificationLevel::UnverifiedIdentity)
+    );
 }
 
 /// Convenience wrapper for [`get_machine_pair_with_setup_sessions_test_helper`]
@@ -316,6 +382,18 @@ fn get_room_key_received_update(
         .expect("Received an empty room key info update")
 }
 
+/// Wait for the next update of the `sender_data_updates_stream`, and pop it.
+async fn wait_for_sender_data_update(
+    sender_data_updates_stream: &mut Pin<Box<impl Stream<Item = Vec<RoomKeyInfo>>>>,
+) -> RoomKeyInfo {
+    tokio::time::timeout(Duration::from_secs(5), sender_data_updates_stream.next())
+        .await
+        .expect("We should have received an update of the sender data")
+        .expect("The sender data updates stream was closed")
+        .pop()
+        .expect("Received an empty sender data update")
+}
+
 /// Load the inbound group session corresponding to an update from the
 /// `room_keys_received_stream` from the given machine's store.
 async fn get_inbound_group_session_or_panic(
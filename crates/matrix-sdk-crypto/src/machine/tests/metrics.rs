@@ -0,0 +1,183 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{iter, sync::Arc};
+
+use assert_matches2::assert_matches;
+use matrix_sdk_test::async_test;
+use ruma::{
+    api::client::to_device::send_event_to_device::v3::Response as ToDeviceResponse,
+    events::{room::message::RoomMessageEventContent, AnyMessageLikeEventContent},
+    room_id, MilliSecondsSinceUnixEpoch,
+};
+use serde_json::json;
+
+use crate::{
+    machine::{
+        test_helpers::get_machine_pair_with_session,
+        tests::{alice_id, to_device_requests_to_content, user_id},
+    },
+    metrics::{tests::MemoryMetrics, CryptoCounter},
+    store::Changes,
+    types::events::{ToDeviceEvent, UtdCause},
+    utilities::json_convert,
+    DecryptionSettings, EncryptionSettings, EncryptionSyncChanges, RoomEventDecryptionResult,
+    TrustRequirement,
+};
+
+#[async_test]
+async fn test_room_key_sharing_and_decryption_metrics() {
+    let (alice, bob) = get_machine_pair_with_session(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+
+    let alice_metrics = Arc::new(MemoryMetrics::default());
+    let bob_metrics = Arc::new(MemoryMetrics::default());
+    alice.set_metrics(Some(alice_metrics.clone()));
+    bob.set_metrics(Some(bob_metrics.clone()));
+
+    let requests = alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+    assert_eq!(alice_metrics.counter(CryptoCounter::MegolmSessionCreated), 1);
+
+    // The messages are only counted once the requests are sent out.
+    assert_eq!(alice_metrics.counter(CryptoCounter::ToDeviceMessageSent), 0);
+    for request in &requests {
+        alice.mark_request_as_sent(&request.txn_id, &ToDeviceResponse::new()).await.unwrap();
+    }
+    assert_eq!(alice_metrics.counter(CryptoCounter::ToDeviceMessageSent), 1);
+
+    let encrypted_content = alice
+        .encrypt_room_event(
+            room_id,
+            AnyMessageLikeEventContent::RoomMessage(RoomMessageEventContent::text_plain(
+                "It is a secret to everybody",
+            )),
+        )
+        .await
+        .unwrap();
+    let event = json_convert(&json!({
+        "event_id": "$xxxxx:example.org",
+        "origin_server_ts": MilliSecondsSinceUnixEpoch::now(),
+        "sender": alice.user_id(),
+        "type": "m.room.encrypted",
+        "content": encrypted_content,
+    }))
+    .unwrap();
+
+    let decryption_settings =
+        DecryptionSettings { sender_device_trust_requirement: TrustRequirement::Untrusted };
+
+    // Bob doesn't have the room key yet.
+    let result = bob.try_decrypt_room_event(&event, room_id, &decryption_settings).await.unwrap();
+    assert_matches!(result, RoomEventDecryptionResult::UnableToDecrypt(_));
+    assert_eq!(bob_metrics.counter(CryptoCounter::DecryptionAttemptFailure(UtdCause::Unknown)), 1);
+    assert_eq!(bob_metrics.counter(CryptoCounter::DecryptionAttemptSuccess), 0);
+
+    let to_device_event = json_convert(&ToDeviceEvent::new(
+        alice.user_id().to_owned(),
+        to_device_requests_to_content(requests),
+    ))
+    .unwrap();
+    bob.receive_sync_changes(EncryptionSyncChanges {
+        to_device_events: vec![to_device_event],
+        changed_devices: &Default::default(),
+        one_time_keys_counts: &Default::default(),
+        unused_fallback_keys: None,
+        next_batch_token: None,
+    })
+    .await
+    .unwrap();
+    assert_eq!(bob_metrics.counter(CryptoCounter::ToDeviceMessageReceived), 1);
+    // The room key came in a pre-key message, which created a new Olm session.
+    assert_eq!(bob_metrics.counter(CryptoCounter::OlmSessionCreated), 1);
+
+    let result = bob.try_decrypt_room_event(&event, room_id, &decryption_settings).await.unwrap();
+    assert_matches!(result, RoomEventDecryptionResult::Decrypted(_));
+    assert_eq!(bob_metrics.counter(CryptoCounter::DecryptionAttemptSuccess), 1);
+
+    // Discarding the room key forces the next share to rotate the session.
+    alice.discard_room_key(room_id).await.unwrap();
+    alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+    assert_eq!(alice_metrics.counter(CryptoCounter::MegolmSessionRotated), 1);
+    assert_eq!(alice_metrics.counter(CryptoCounter::MegolmSessionCreated), 2);
+}
+
+#[async_test]
+async fn test_no_metrics_are_recorded_once_disabled() {
+    let (alice, bob) = get_machine_pair_with_session(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+
+    let metrics = Arc::new(MemoryMetrics::default());
+    alice.set_metrics(Some(metrics.clone()));
+    alice.set_metrics(None);
+
+    alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+
+    assert_eq!(metrics.counter(CryptoCounter::MegolmSessionCreated), 0);
+}
+
+#[async_test]
+async fn test_historical_decryption_failure_with_unknown_backup() {
+    let (alice, bob) = get_machine_pair_with_session(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+
+    let bob_metrics = Arc::new(MemoryMetrics::default());
+    bob.set_metrics(Some(bob_metrics.clone()));
+
+    // Bob knows about a backup version but never asked the server whether the
+    // backup still exists.
+    bob.store()
+        .save_changes(Changes { backup_version: Some("1".to_owned()), ..Default::default() })
+        .await
+        .unwrap();
+
+    alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+    let encrypted_content = alice
+        .encrypt_room_event(
+            room_id,
+            AnyMessageLikeEventContent::RoomMessage(RoomMessageEventContent::text_plain(
+                "It is a secret to everybody",
+            )),
+        )
+        .await
+        .unwrap();
+    // The message was sent before Bob's device existed.
+    let event = json_convert(&json!({
+        "event_id": "$xxxxx:example.org",
+        "origin_server_ts": 1,
+        "sender": alice.user_id(),
+        "type": "m.room.encrypted",
+        "content": encrypted_content,
+    }))
+    .unwrap();
+
+    let decryption_settings =
+        DecryptionSettings { sender_device_trust_requirement: TrustRequirement::Untrusted };
+    let result = bob.try_decrypt_room_event(&event, room_id, &decryption_settings).await.unwrap();
+    assert_matches!(result, RoomEventDecryptionResult::UnableToDecrypt(_));
+
+    // Without a backup enabled, we can't tell whether the key is in the backup.
+    assert_eq!(bob_metrics.counter(CryptoCounter::DecryptionAttemptFailure(UtdCause::Unknown)), 1);
+}
@@ -74,12 +74,18 @@ use crate::{
     OutgoingRequests, RoomEventDecryptionResult, ToDeviceRequest, TrustRequirement,
 };
 
+mod cross_signing_rotation;
 mod decryption_verification_state;
+mod encrypted_state_events;
 mod interactive_verification;
 mod megolm_sender_data;
+mod metrics;
 mod olm_encryption;
+mod room_key_bundle;
 mod room_settings;
 mod send_encrypted_to_device;
+mod session_pruning;
+mod store_integrity;
 
 fn alice_id() -> &'static UserId {
     user_id!("@alice:example.org")
@@ -0,0 +1,287 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use assert_matches2::assert_let;
+use matrix_sdk_test::async_test;
+use ruma::{events::room::history_visibility::HistoryVisibility, room_id, RoomId};
+use serde_json::json;
+
+use crate::{
+    machine::{
+        test_helpers::{get_machine_pair, get_machine_pair_with_session},
+        tests,
+    },
+    olm::{BackedUpRoomKey, ExportedRoomKey, InboundGroupSession, SenderData},
+    types::events::{room_key_bundle::RoomKeyBundleContent, ToDeviceEvent},
+    utilities::json_convert,
+    CollectStrategy, EncryptionSettings, EncryptionSyncChanges, OlmMachine, ToDeviceRequest,
+};
+
+fn bundle_content(room_id: &RoomId) -> RoomKeyBundleContent {
+    serde_json::from_value(json!({
+        "room_id": room_id,
+        "file": {
+            "url": "mxc://example.org/FHyPlCeYUSFFxlgbQYZmoEoe",
+            "key": {
+                "kty": "oct",
+                "key_ops": ["encrypt", "decrypt"],
+                "alg": "A256CTR",
+                "k": "aWF6-32KGYaC3A_FEUCk1Bt0JA37zP0wrStgmdCaW-0",
+                "ext": true
+            },
+            "iv": "X85+XgHN+HEAAAAAAAAAAA",
+            "hashes": {
+                "sha256": "7R6PrrVZzIuuUbzvCQzd0Zf9xYKPKqvdiMJw9z5Kp0s"
+            },
+            "v": "v2"
+        }
+    }))
+    .unwrap()
+}
+
+async fn receive_to_device_request(
+    machine: &OlmMachine,
+    sender: &OlmMachine,
+    request: ToDeviceRequest,
+) {
+    let event = ToDeviceEvent::new(
+        sender.user_id().to_owned(),
+        tests::to_device_requests_to_content(vec![Arc::new(request)]),
+    );
+
+    let sync_changes = EncryptionSyncChanges {
+        to_device_events: vec![json_convert(&event).unwrap()],
+        changed_devices: &Default::default(),
+        one_time_keys_counts: &Default::default(),
+        unused_fallback_keys: None,
+        next_batch_token: None,
+    };
+
+    machine.receive_sync_changes(sync_changes).await.unwrap();
+}
+
+#[async_test]
+async fn test_share_and_receive_room_key_bundle() {
+    let room_id = room_id!("!test:localhost");
+    let other_room_id = room_id!("!other:localhost");
+
+    let (alice, bob) =
+        get_machine_pair_with_session(tests::alice_id(), tests::user_id(), false).await;
+
+    alice.create_outbound_group_session_with_defaults_test_helper(room_id).await.unwrap();
+    alice.create_outbound_group_session_with_defaults_test_helper(other_room_id).await.unwrap();
+
+    // Only the keys of the requested room end up in the bundle.
+    let bundle = alice.store().build_room_key_bundle(room_id).await.unwrap();
+    assert_eq!(bundle.room_keys.len(), 1);
+    assert_eq!(bundle.room_keys[0].room_id, room_id);
+    let session_id = bundle.room_keys[0].session_id.clone();
+
+    let request = alice
+        .share_room_key_bundle_data(bob.user_id(), bundle_content(room_id), &Default::default())
+        .await
+        .unwrap()
+        .expect("We should have a request to send the bundle data");
+
+    receive_to_device_request(&bob, &alice, request).await;
+
+    // Bob stored the bundle data, for the room and sender it was received for.
+    assert!(bob
+        .store()
+        .get_received_room_key_bundle_data(other_room_id, alice.user_id())
+        .await
+        .unwrap()
+        .is_none());
+    let bundle_data = bob
+        .store()
+        .get_received_room_key_bundle_data(room_id, alice.user_id())
+        .await
+        .unwrap()
+        .expect("Bob should have stored the bundle data");
+    assert_eq!(bundle_data.sender_user, alice.user_id());
+    assert_eq!(bundle_data.sender_device, alice.device_id());
+    assert_eq!(bundle_data.bundle_data.room_id, room_id);
+
+    // Bob joined the room before the bundle arrived.
+    bob.store().set_pending_room_key_bundle(room_id, alice.user_id()).await.unwrap();
+    assert_eq!(
+        bob.store().get_pending_room_key_bundle_inviter(room_id).await.unwrap().as_deref(),
+        Some(alice.user_id())
+    );
+
+    // Once downloaded, Bob imports the bundle.
+    let result =
+        bob.store().receive_room_key_bundle(&bundle_data, bundle, |_, _| {}).await.unwrap();
+    assert_eq!(result.imported_count, 1);
+
+    let session =
+        bob.store().get_inbound_group_session(room_id, &session_id).await.unwrap().unwrap();
+    assert_let!(
+        SenderData::SharedHistory { forwarder_user_id, forwarder_device_id } = &session.sender_data
+    );
+    assert_eq!(forwarder_user_id, alice.user_id());
+    assert_eq!(forwarder_device_id, alice.device_id());
+
+    // The bundle data is gone once the bundle has been imported.
+    assert!(bob
+        .store()
+        .get_received_room_key_bundle_data(room_id, alice.user_id())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(bob.store().get_pending_room_key_bundle_inviter(room_id).await.unwrap().is_none());
+}
+
+#[async_test]
+async fn test_room_key_bundle_skips_sessions_without_shared_history() {
+    let room_id = room_id!("!test:localhost");
+
+    let (alice, _) =
+        get_machine_pair_with_session(tests::alice_id(), tests::user_id(), false).await;
+
+    // A session created while the history was shared with new members.
+    alice.create_outbound_group_session_with_defaults_test_helper(room_id).await.unwrap();
+    let bundle = alice.store().build_room_key_bundle(room_id).await.unwrap();
+    assert_eq!(bundle.room_keys.len(), 1);
+    let shared_session_id = bundle.room_keys[0].session_id.clone();
+
+    // A session created while the history was only visible to joined members.
+    let settings =
+        EncryptionSettings { history_visibility: HistoryVisibility::Joined, ..Default::default() };
+    let (_, joined_only_session) = alice
+        .inner
+        .group_session_manager
+        .create_outbound_group_session(room_id, settings, SenderData::unknown())
+        .await
+        .unwrap();
+    assert!(!joined_only_session.shared_history());
+    alice.store().save_inbound_group_sessions(&[joined_only_session]).await.unwrap();
+
+    // Only the shared session ends up in the bundle.
+    let bundle = alice.store().build_room_key_bundle(room_id).await.unwrap();
+    assert_eq!(bundle.room_keys.len(), 1);
+    assert_eq!(bundle.room_keys[0].session_id, shared_session_id);
+}
+
+#[async_test]
+async fn test_shared_history_flag_is_sent_with_the_room_key() {
+    let room_id = room_id!("!test:localhost");
+
+    let (alice, bob) =
+        get_machine_pair_with_session(tests::alice_id(), tests::user_id(), false).await;
+
+    for (history_visibility, shared_history) in [
+        (HistoryVisibility::Shared, true),
+        (HistoryVisibility::Invited, true),
+        (HistoryVisibility::Joined, false),
+        (HistoryVisibility::WorldReadable, false),
+    ] {
+        alice.discard_room_key(room_id).await.unwrap();
+
+        let settings = EncryptionSettings { history_visibility, ..Default::default() };
+        let requests =
+            alice.share_room_key(room_id, [bob.user_id()].into_iter(), settings).await.unwrap();
+
+        for request in requests {
+            receive_to_device_request(&bob, &alice, request.as_ref().clone()).await;
+        }
+
+        let session_id = alice
+            .inner
+            .group_session_manager
+            .get_outbound_group_session(room_id)
+            .expect("Alice should have an outbound session")
+            .session_id()
+            .to_owned();
+        let session =
+            bob.store().get_inbound_group_session(room_id, &session_id).await.unwrap().unwrap();
+
+        assert_eq!(session.shared_history(), shared_history);
+    }
+}
+
+#[async_test]
+async fn test_shared_history_flag_survives_exports_and_backups() {
+    let room_id = room_id!("!test:localhost");
+
+    let (alice, _) =
+        get_machine_pair_with_session(tests::alice_id(), tests::user_id(), false).await;
+
+    alice.create_outbound_group_session_with_defaults_test_helper(room_id).await.unwrap();
+    let session_id = alice
+        .inner
+        .group_session_manager
+        .get_outbound_group_session(room_id)
+        .expect("Alice should have an outbound session")
+        .session_id()
+        .to_owned();
+    let session =
+        alice.store().get_inbound_group_session(room_id, &session_id).await.unwrap().unwrap();
+    assert!(session.shared_history());
+
+    let exported = session.export().await;
+    assert!(exported.shared_history);
+    let exported: ExportedRoomKey =
+        serde_json::from_value(serde_json::to_value(&exported).unwrap()).unwrap();
+    let imported = InboundGroupSession::from_export(&exported).unwrap();
+    assert!(imported.shared_history(), "The flag should survive a key export");
+
+    let backed_up = session.to_backup().await;
+    let backed_up: BackedUpRoomKey =
+        serde_json::from_value(serde_json::to_value(&backed_up).unwrap()).unwrap();
+    let restored = InboundGroupSession::from_export(&ExportedRoomKey::from_backed_up_room_key(
+        room_id.to_owned(),
+        session_id,
+        backed_up,
+    ))
+    .unwrap();
+    assert!(restored.shared_history(), "The flag should survive a key backup");
+}
+
+#[async_test]
+async fn test_share_room_key_bundle_without_session() {
+    let room_id = room_id!("!test:localhost");
+
+    let (alice, bob, _) = get_machine_pair(tests::alice_id(), tests::user_id(), false).await;
+
+    let request = alice
+        .share_room_key_bundle_data(bob.user_id(), bundle_content(room_id), &Default::default())
+        .await
+        .unwrap();
+
+    assert!(request.is_none(), "Devices we have no Olm session with should be skipped");
+}
+
+#[async_test]
+async fn test_share_room_key_bundle_respects_collect_strategy() {
+    let room_id = room_id!("!test:localhost");
+
+    let (alice, bob) =
+        get_machine_pair_with_session(tests::alice_id(), tests::user_id(), false).await;
+
+    // Bob's device isn't verified, so it's excluded when only trusted devices are
+    // allowed.
+    let strategy = CollectStrategy::DeviceBasedStrategy {
+        only_allow_trusted_devices: true,
+        error_on_verified_user_problem: false,
+    };
+    let request = alice
+        .share_room_key_bundle_data(bob.user_id(), bundle_content(room_id), &strategy)
+        .await
+        .unwrap();
+
+    assert!(request.is_none(), "Untrusted devices should be skipped");
+}
@@ -26,6 +26,7 @@ async fn test_stores_and_returns_room_settings() {
         only_allow_trusted_devices: true,
         session_rotation_period: Some(Duration::from_secs(10)),
         session_rotation_period_messages: Some(1234),
+        sharing_policy: None,
     };
 
     machine.set_room_settings(room_id, &settings).await.unwrap();
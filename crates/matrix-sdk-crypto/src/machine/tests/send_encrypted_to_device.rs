@@ -22,9 +22,9 @@ use crate::{
         test_helpers::{get_machine_pair, get_machine_pair_with_session},
         tests,
     },
-    types::events::ToDeviceEvent,
+    types::events::{room_key_withheld::WithheldCode, ToDeviceEvent},
     utilities::json_convert,
-    EncryptionSyncChanges, OlmError, ToDeviceRequest,
+    CollectStrategy, DeviceData, EncryptionSyncChanges, OlmError, OlmMachine, ToDeviceRequest,
 };
 
 #[async_test]
@@ -121,3 +121,96 @@ async fn test_send_encrypted_to_device_no_session() {
 
     assert_matches!(encryption_result, Err(OlmError::MissingSession));
 }
+
+async fn device_data(machine: &OlmMachine, other: &OlmMachine) -> DeviceData {
+    machine.get_device(other.user_id(), other.device_id(), None).await.unwrap().unwrap().inner
+}
+
+#[async_test]
+async fn test_encrypt_content_for_devices() {
+    let (alice, bob) =
+        get_machine_pair_with_session(tests::alice_id(), tests::user_id(), false).await;
+
+    let custom_content = json!({ "secret": "It's a secret to everybody" });
+    let device = device_data(&alice, &bob).await;
+
+    let (request, withheld) = alice
+        .encrypt_content_for_devices(
+            vec![device],
+            "org.example.secret",
+            &custom_content,
+            &CollectStrategy::default(),
+        )
+        .await
+        .unwrap();
+
+    assert!(withheld.is_empty());
+    let request = request.expect("The event should be sent to Bob's device");
+    assert_eq!(request.message_count(), 1);
+
+    let event = ToDeviceEvent::new(
+        alice.user_id().to_owned(),
+        tests::to_device_requests_to_content(vec![request.into()]),
+    );
+    let sync_changes = EncryptionSyncChanges {
+        to_device_events: vec![json_convert(&event).unwrap()],
+        changed_devices: &Default::default(),
+        one_time_keys_counts: &Default::default(),
+        unused_fallback_keys: None,
+        next_batch_token: None,
+    };
+    let (decrypted, _) = bob.receive_sync_changes(sync_changes).await.unwrap();
+
+    assert_eq!(decrypted.len(), 1);
+    let decrypted = decrypted[0].deserialize_as::<serde_json::Value>().unwrap();
+    assert_eq!(decrypted["type"], "org.example.secret");
+    assert_eq!(decrypted["content"], custom_content);
+}
+
+#[async_test]
+async fn test_encrypt_content_for_devices_withholds_untrusted_devices() {
+    let (alice, bob) =
+        get_machine_pair_with_session(tests::alice_id(), tests::user_id(), false).await;
+
+    let device = device_data(&alice, &bob).await;
+    let strategy = CollectStrategy::DeviceBasedStrategy {
+        only_allow_trusted_devices: true,
+        error_on_verified_user_problem: false,
+    };
+
+    let (request, withheld) = alice
+        .encrypt_content_for_devices(
+            vec![device],
+            "org.example.secret",
+            &json!({ "secret": "It's a secret to everybody" }),
+            &strategy,
+        )
+        .await
+        .unwrap();
+
+    assert!(request.is_none(), "Bob's device isn't verified, it shouldn't receive the event");
+    assert_eq!(withheld.len(), 1);
+    assert_eq!(withheld[0].0.device_id(), bob.device_id());
+    assert_eq!(withheld[0].1, WithheldCode::Unverified);
+}
+
+#[async_test]
+async fn test_encrypt_content_for_devices_no_session() {
+    let (alice, bob, _) = get_machine_pair(tests::alice_id(), tests::user_id(), false).await;
+
+    let device = device_data(&alice, &bob).await;
+
+    let (request, withheld) = alice
+        .encrypt_content_for_devices(
+            vec![device],
+            "org.example.secret",
+            &json!({ "secret": "It's a secret to everybody" }),
+            &CollectStrategy::default(),
+        )
+        .await
+        .unwrap();
+
+    assert!(request.is_none());
+    assert_eq!(withheld.len(), 1);
+    assert_eq!(withheld[0].1, WithheldCode::NoOlm);
+}
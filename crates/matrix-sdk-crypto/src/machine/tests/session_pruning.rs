@@ -0,0 +1,185 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::iter;
+
+use matrix_sdk_test::async_test;
+use ruma::room_id;
+
+use crate::{
+    machine::{
+        test_helpers::{create_session, get_machine_pair, get_machine_pair_with_session},
+        tests::{alice_id, user_id},
+    },
+    store::{
+        pruning::{SessionPruningReport, SessionPruningSettings},
+        Changes, DeviceChanges,
+    },
+    EncryptionSettings, OlmMachine,
+};
+
+async fn olm_session_count(machine: &OlmMachine, other: &OlmMachine) -> usize {
+    let sender_key = other.identity_keys().curve25519.to_base64();
+
+    match machine.store().get_sessions(&sender_key).await.unwrap() {
+        Some(sessions) => sessions.lock().await.len(),
+        None => 0,
+    }
+}
+
+#[async_test]
+async fn test_pruning_keeps_the_most_recent_olm_sessions() {
+    let (alice, bob, one_time_keys) = get_machine_pair(alice_id(), user_id(), false).await;
+
+    for (key_id, one_time_key) in one_time_keys.into_iter().take(3) {
+        create_session(&alice, bob.user_id(), bob.device_id(), key_id, one_time_key).await;
+    }
+    assert_eq!(olm_session_count(&alice, &bob).await, 3);
+
+    let settings = SessionPruningSettings {
+        max_olm_sessions_per_device: Some(1),
+        remove_olm_sessions_of_deleted_devices: false,
+        ..Default::default()
+    };
+    let report = alice.prune_sessions(&settings).await.unwrap();
+
+    assert_eq!(report.removed_excess_olm_sessions, 2);
+    assert_eq!(report.removed_olm_sessions_of_deleted_devices, 0);
+    assert!(report.reclaimed_bytes > 0);
+    assert_eq!(olm_session_count(&alice, &bob).await, 1);
+
+    // Pruning again doesn't find anything to remove.
+    assert_eq!(alice.prune_sessions(&settings).await.unwrap(), SessionPruningReport::default());
+}
+
+fn deleted_devices_settings() -> SessionPruningSettings {
+    SessionPruningSettings {
+        max_olm_sessions_per_device: None,
+        remove_olm_sessions_of_deleted_devices: true,
+        ..Default::default()
+    }
+}
+
+async fn delete_device(machine: &OlmMachine, other: &OlmMachine) {
+    let device =
+        machine.get_device(other.user_id(), other.device_id(), None).await.unwrap().unwrap();
+    machine
+        .store()
+        .save_changes(Changes {
+            devices: DeviceChanges { deleted: vec![device.inner], ..Default::default() },
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+}
+
+#[async_test]
+async fn test_pruning_removes_the_olm_sessions_of_deleted_devices() {
+    let (alice, bob) = get_machine_pair_with_session(alice_id(), user_id(), false).await;
+    alice.update_tracked_users(iter::once(bob.user_id())).await.unwrap();
+    alice.store().save_tracked_users(&[(bob.user_id(), false)]).await.unwrap();
+
+    let settings = deleted_devices_settings();
+
+    // Bob's device is known, its session is kept.
+    let report = alice.prune_sessions(&settings).await.unwrap();
+    assert_eq!(report, SessionPruningReport::default());
+    assert_eq!(olm_session_count(&alice, &bob).await, 1);
+
+    delete_device(&alice, &bob).await;
+
+    // The deletion of devices isn't acted upon by default.
+    let report = alice.prune_sessions(&SessionPruningSettings::default()).await.unwrap();
+    assert_eq!(report.removed_olm_sessions_of_deleted_devices, 0);
+    assert_eq!(olm_session_count(&alice, &bob).await, 1);
+
+    let report = alice.prune_sessions(&settings).await.unwrap();
+    assert_eq!(report.removed_olm_sessions_of_deleted_devices, 1);
+    assert_eq!(olm_session_count(&alice, &bob).await, 0);
+}
+
+#[async_test]
+async fn test_pruning_waits_for_the_device_list_to_be_up_to_date() {
+    let (alice, bob) = get_machine_pair_with_session(alice_id(), user_id(), false).await;
+    alice.update_tracked_users(iter::once(bob.user_id())).await.unwrap();
+
+    delete_device(&alice, &bob).await;
+
+    let settings = deleted_devices_settings();
+
+    // Bob's device list is outdated, the device might come back.
+    let report = alice.prune_sessions(&settings).await.unwrap();
+    assert_eq!(report.removed_olm_sessions_of_deleted_devices, 0);
+    assert_eq!(olm_session_count(&alice, &bob).await, 1);
+
+    alice.store().save_tracked_users(&[(bob.user_id(), false)]).await.unwrap();
+
+    let report = alice.prune_sessions(&settings).await.unwrap();
+    assert_eq!(report.removed_olm_sessions_of_deleted_devices, 1);
+    assert_eq!(olm_session_count(&alice, &bob).await, 0);
+}
+
+#[async_test]
+async fn test_pruning_keeps_the_olm_sessions_of_unknown_devices() {
+    let (alice, bob) = get_machine_pair_with_session(alice_id(), user_id(), false).await;
+
+    // Bob isn't tracked, so his device isn't part of any device list we know of,
+    // but it wasn't deleted either.
+    let report = alice.prune_sessions(&deleted_devices_settings()).await.unwrap();
+    assert_eq!(report, SessionPruningReport::default());
+    assert_eq!(olm_session_count(&alice, &bob).await, 1);
+}
+
+#[async_test]
+async fn test_pruning_removes_the_backed_up_room_keys_of_left_rooms() {
+    let (alice, bob) = get_machine_pair_with_session(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+
+    alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+
+    let settings = SessionPruningSettings {
+        max_olm_sessions_per_device: None,
+        remove_olm_sessions_of_deleted_devices: false,
+        left_rooms: vec![room_id.to_owned()],
+    };
+
+    // Without a backup, the room keys are kept.
+    let report = alice.prune_sessions(&settings).await.unwrap();
+    assert_eq!(report.removed_inbound_group_sessions, 0);
+
+    let session_id =
+        alice.store().get_inbound_group_sessions().await.unwrap()[0].session_id().to_owned();
+    alice
+        .store()
+        .save_changes(Changes { backup_version: Some("1".to_owned()), ..Default::default() })
+        .await
+        .unwrap();
+
+    // The room key isn't backed up yet, so it's kept as well.
+    let report = alice.prune_sessions(&settings).await.unwrap();
+    assert_eq!(report.removed_inbound_group_sessions, 0);
+
+    alice
+        .store()
+        .mark_inbound_group_sessions_as_backed_up("1", &[(room_id, &session_id)])
+        .await
+        .unwrap();
+
+    let report = alice.prune_sessions(&settings).await.unwrap();
+    assert_eq!(report.removed_inbound_group_sessions, 1);
+    assert!(alice.store().get_inbound_group_session(room_id, &session_id).await.unwrap().is_none());
+}
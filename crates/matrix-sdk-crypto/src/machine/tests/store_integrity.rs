@@ -0,0 +1,150 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::iter;
+
+use matrix_sdk_test::async_test;
+use ruma::room_id;
+
+use crate::{
+    machine::{
+        test_helpers::get_machine_pair_with_session,
+        tests::{alice_id, user_id},
+    },
+    store::{Changes, DeviceChanges},
+    EncryptionSettings,
+};
+
+#[async_test]
+async fn test_store_integrity_check_of_a_healthy_store() {
+    let (alice, bob) = get_machine_pair_with_session(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+
+    alice
+        .share_room_key(room_id, iter::once(bob.user_id()), EncryptionSettings::default())
+        .await
+        .unwrap();
+
+    let report = alice.check_store_integrity(false).await.unwrap();
+
+    assert!(report.inbound_group_sessions_checked);
+    assert!(!report.has_problems(), "{report:?}");
+    assert!(!report.repaired);
+}
+
+#[async_test]
+async fn test_store_integrity_check_repairs_orphaned_outbound_group_sessions() {
+    let (alice, _) = get_machine_pair_with_session(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+
+    // Save an outbound group session whose inbound counterpart got lost, in a
+    // room we don't have any other inbound group session for.
+    let outbound = {
+        let cache = alice.store().cache().await.unwrap();
+        let account = cache.account().await.unwrap();
+        let (outbound, _) = account.create_group_session_pair_with_defaults(room_id).await;
+        outbound
+    };
+
+    alice
+        .store()
+        .save_changes(Changes { outbound_group_sessions: vec![outbound], ..Default::default() })
+        .await
+        .unwrap();
+
+    let report = alice.check_store_integrity(false).await.unwrap();
+    assert_eq!(report.orphaned_outbound_group_sessions, vec![room_id.to_owned()]);
+    assert!(report.has_problems());
+    assert!(!alice.outbound_group_session_info(room_id).await.unwrap().invalidated);
+
+    let report = alice.check_store_integrity(true).await.unwrap();
+    assert_eq!(report.orphaned_outbound_group_sessions, vec![room_id.to_owned()]);
+    assert!(report.repaired);
+    assert!(alice.outbound_group_session_info(room_id).await.unwrap().invalidated);
+
+    let report = alice.check_store_integrity(false).await.unwrap();
+    assert!(!report.has_problems(), "{report:?}");
+}
+
+#[async_test]
+async fn test_store_integrity_check_repairs_inconsistent_backup_flags() {
+    let (alice, _) = get_machine_pair_with_session(alice_id(), user_id(), false).await;
+    let room_id = room_id!("!test:example.org");
+
+    let (outbound, inbound) = {
+        let cache = alice.store().cache().await.unwrap();
+        let account = cache.account().await.unwrap();
+        account.create_group_session_pair_with_defaults(room_id).await
+    };
+
+    // No backup is enabled, so the session can't have been backed up.
+    inbound.mark_as_backed_up();
+
+    alice
+        .store()
+        .save_changes(Changes {
+            inbound_group_sessions: vec![inbound],
+            outbound_group_sessions: vec![outbound],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+    let report = alice.check_store_integrity(false).await.unwrap();
+    assert_eq!(report.inconsistent_backup_flags, 1);
+
+    let report = alice.check_store_integrity(true).await.unwrap();
+    assert_eq!(report.inconsistent_backup_flags, 1);
+
+    let report = alice.check_store_integrity(false).await.unwrap();
+    assert_eq!(report.inconsistent_backup_flags, 0);
+    assert!(!report.has_problems(), "{report:?}");
+}
+
+#[async_test]
+async fn test_store_integrity_check_reports_olm_sessions_with_unknown_devices() {
+    let (alice, bob) = get_machine_pair_with_session(alice_id(), user_id(), false).await;
+    alice.update_tracked_users(iter::once(bob.user_id())).await.unwrap();
+
+    let report = alice.check_store_integrity(false).await.unwrap();
+    assert_eq!(report.olm_sessions_from_unknown_devices, 0);
+
+    // Alice forgets about the device of Bob, but keeps the Olm session she has
+    // with it.
+    let bob_device =
+        alice.store().get_device_data(bob.user_id(), bob.device_id()).await.unwrap().unwrap();
+    alice
+        .store()
+        .save_changes(Changes {
+            devices: DeviceChanges { deleted: vec![bob_device], ..Default::default() },
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+    let report = alice.check_store_integrity(false).await.unwrap();
+    assert_eq!(report.olm_sessions_from_unknown_devices, 1);
+    assert!(!report.has_problems(), "{report:?}");
+
+    // Nothing gets removed when repairing.
+    let report = alice.check_store_integrity(true).await.unwrap();
+    assert_eq!(report.olm_sessions_from_unknown_devices, 1);
+    let sessions = alice
+        .store()
+        .get_sessions(&bob.identity_keys().curve25519.to_base64())
+        .await
+        .unwrap()
+        .expect("The Olm session with Bob should still be there");
+    assert_eq!(sessions.lock().await.len(), 1);
+}
@@ -0,0 +1,324 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Optional metrics about the cryptographic operations of the [`OlmMachine`].
+//!
+//! The [`OlmMachine`] doesn't collect metrics by default. To enable them, an
+//! implementation of the [`CryptoMetrics`] trait needs to be passed to
+//! [`OlmMachine::set_metrics()`]. The trait receives every counter increment
+//! and histogram observation and can forward them to a metrics system such as
+//! Prometheus or OpenTelemetry.
+//!
+//! Every metric has a stable [name](CryptoCounter::name) and a, possibly
+//! empty, list of [labels](CryptoCounter::labels) which can be used to
+//! register the metric with the metrics system of choice.
+//!
+//! [`OlmMachine`]: crate::OlmMachine
+//! [`OlmMachine::set_metrics()`]: crate::OlmMachine::set_metrics
+
+use std::{
+    fmt,
+    sync::{Arc, RwLock as StdRwLock},
+};
+
+use crate::types::events::UtdCause;
+
+/// A sink for the metrics the [`OlmMachine`] collects.
+///
+/// The methods of this trait are called inline while the [`OlmMachine`]
+/// processes events and requests, implementations should therefore return
+/// quickly and never block.
+///
+/// [`OlmMachine`]: crate::OlmMachine
+pub trait CryptoMetrics: fmt::Debug + Send + Sync {
+    /// Increment the given counter by the given value.
+    fn increment_counter(&self, counter: CryptoCounter, value: u64);
+
+    /// Record a single observation for the given histogram.
+    fn observe_histogram(&self, histogram: CryptoHistogram, value: f64);
+}
+
+/// The counters the [`OlmMachine`] maintains.
+///
+/// [`OlmMachine`]: crate::OlmMachine
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CryptoCounter {
+    /// An attempt to decrypt a room event succeeded.
+    ///
+    /// This counts attempts, not events: an event which is decrypted more than
+    /// once is counted every time.
+    DecryptionAttemptSuccess,
+
+    /// An attempt to decrypt a room event failed, with our best guess at the
+    /// cause.
+    ///
+    /// This counts attempts, not events: an event which is retried, e.g. each
+    /// time it's displayed, is counted every time it fails to decrypt.
+    DecryptionAttemptFailure(UtdCause),
+
+    /// A new outbound Megolm session was created for a room.
+    MegolmSessionCreated,
+
+    /// An outbound Megolm session was replaced by a new one, because it
+    /// expired, was invalidated or the room membership changed.
+    MegolmSessionRotated,
+
+    /// A new Olm session was established with another device.
+    OlmSessionCreated,
+
+    /// A new Olm session was established to replace a wedged one.
+    OlmSessionUnwedged,
+
+    /// To-device messages were sent out.
+    ToDeviceMessageSent,
+
+    /// To-device messages were received in a sync response.
+    ToDeviceMessageReceived,
+
+    /// A batch of room keys was uploaded to the key backup.
+    BackupUploadBatch,
+}
+
+impl CryptoCounter {
+    /// The name under which this counter should be exported.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CryptoCounter::DecryptionAttemptSuccess => {
+                "matrix_crypto_decryption_attempt_success_total"
+            }
+            CryptoCounter::DecryptionAttemptFailure(_) => {
+                "matrix_crypto_decryption_attempt_failure_total"
+            }
+            CryptoCounter::MegolmSessionCreated => "matrix_crypto_megolm_session_created_total",
+            CryptoCounter::MegolmSessionRotated => "matrix_crypto_megolm_session_rotated_total",
+            CryptoCounter::OlmSessionCreated => "matrix_crypto_olm_session_created_total",
+            CryptoCounter::OlmSessionUnwedged => "matrix_crypto_olm_session_unwedged_total",
+            CryptoCounter::ToDeviceMessageSent => "matrix_crypto_to_device_message_sent_total",
+            CryptoCounter::ToDeviceMessageReceived => {
+                "matrix_crypto_to_device_message_received_total"
+            }
+            CryptoCounter::BackupUploadBatch => "matrix_crypto_backup_upload_batch_total",
+        }
+    }
+
+    /// The labels, as key/value pairs, attached to this counter.
+    pub fn labels(&self) -> Vec<(&'static str, &'static str)> {
+        match self {
+            CryptoCounter::DecryptionAttemptFailure(cause) => {
+                vec![("cause", utd_cause_label(*cause))]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// The histograms the [`OlmMachine`] maintains.
+///
+/// [`OlmMachine`]: crate::OlmMachine
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CryptoHistogram {
+    /// The time, in seconds, between the creation of a `/keys/query` request
+    /// and the processing of its response.
+    KeysQueryDuration,
+
+    /// The number of room keys uploaded in a single key backup batch.
+    BackupUploadBatchSize,
+}
+
+impl CryptoHistogram {
+    /// The name under which this histogram should be exported.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CryptoHistogram::KeysQueryDuration => "matrix_crypto_keys_query_duration_seconds",
+            CryptoHistogram::BackupUploadBatchSize => "matrix_crypto_backup_upload_batch_size",
+        }
+    }
+
+    /// The labels, as key/value pairs, attached to this histogram.
+    pub fn labels(&self) -> Vec<(&'static str, &'static str)> {
+        Vec::new()
+    }
+}
+
+fn utd_cause_label(cause: UtdCause) -> &'static str {
+    match cause {
+        UtdCause::Unknown => "unknown",
+        UtdCause::SentBeforeWeJoined => "sent_before_we_joined",
+        UtdCause::VerificationViolation => "verification_violation",
+        UtdCause::UnsignedDevice => "unsigned_device",
+        UtdCause::UnknownDevice => "unknown_device",
+        UtdCause::SentBeforeDeviceExistedWithoutBackup => {
+            "sent_before_device_existed_without_backup"
+        }
+        UtdCause::HistoricalMessageAndBackupIsDisabled => {
+            "historical_message_and_backup_is_disabled"
+        }
+        UtdCause::KeyInBackupNotYetDownloaded => "key_in_backup_not_yet_downloaded",
+        UtdCause::WithheldForUnverifiedOrInsecureDevice => {
+            "withheld_for_unverified_or_insecure_device"
+        }
+        UtdCause::WithheldBySender => "withheld_by_sender",
+    }
+}
+
+/// The shared handle the various state machines use to record metrics.
+///
+/// It's a no-op until a [`CryptoMetrics`] implementation is set.
+#[derive(Clone, Default)]
+pub(crate) struct MetricsRecorder {
+    inner: Arc<StdRwLock<Option<Arc<dyn CryptoMetrics>>>>,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl fmt::Debug for MetricsRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MetricsRecorder").field("enabled", &self.is_enabled()).finish()
+    }
+}
+
+impl MetricsRecorder {
+    pub fn set(&self, metrics: Option<Arc<dyn CryptoMetrics>>) {
+        *self.inner.write().unwrap() = metrics;
+    }
+
+    /// Is a [`CryptoMetrics`] implementation set?
+    ///
+    /// Useful to avoid computing the value of a metric nobody is going to
+    /// look at.
+    pub fn is_enabled(&self) -> bool {
+        self.inner.read().unwrap().is_some()
+    }
+
+    pub fn increment(&self, counter: CryptoCounter) {
+        self.increment_by(counter, 1);
+    }
+
+    pub fn increment_by(&self, counter: CryptoCounter, value: u64) {
+        if value == 0 {
+            return;
+        }
+
+        if let Some(metrics) = self.metrics() {
+            metrics.increment_counter(counter, value);
+        }
+    }
+
+    pub fn observe(&self, histogram: CryptoHistogram, value: f64) {
+        if let Some(metrics) = self.metrics() {
+            metrics.observe_histogram(histogram, value);
+        }
+    }
+
+    /// Get the current [`CryptoMetrics`] implementation, if any.
+    ///
+    /// It's cloned out of the lock, so the implementation can't deadlock by
+    /// calling [`Self::set()`], nor hold up the other recorders.
+    fn metrics(&self) -> Option<Arc<dyn CryptoMetrics>> {
+        self.inner.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{CryptoCounter, CryptoHistogram, CryptoMetrics, MetricsRecorder};
+    use crate::types::events::UtdCause;
+
+    /// A [`CryptoMetrics`] implementation which keeps everything in memory,
+    /// keyed by the metric name and labels.
+    #[derive(Debug, Default)]
+    pub(crate) struct MemoryMetrics {
+        counters: Mutex<BTreeMap<String, u64>>,
+        histograms: Mutex<BTreeMap<String, Vec<f64>>>,
+    }
+
+    impl MemoryMetrics {
+        fn key(name: &str, labels: Vec<(&'static str, &'static str)>) -> String {
+            labels.into_iter().fold(name.to_owned(), |key, (k, v)| format!("{key},{k}={v}"))
+        }
+
+        pub fn counter(&self, counter: CryptoCounter) -> u64 {
+            let key = Self::key(counter.name(), counter.labels());
+            self.counters.lock().unwrap().get(&key).copied().unwrap_or_default()
+        }
+
+        pub fn histogram(&self, histogram: CryptoHistogram) -> Vec<f64> {
+            let key = Self::key(histogram.name(), histogram.labels());
+            self.histograms.lock().unwrap().get(&key).cloned().unwrap_or_default()
+        }
+    }
+
+    impl CryptoMetrics for MemoryMetrics {
+        fn increment_counter(&self, counter: CryptoCounter, value: u64) {
+            let key = Self::key(counter.name(), counter.labels());
+            *self.counters.lock().unwrap().entry(key).or_default() += value;
+        }
+
+        fn observe_histogram(&self, histogram: CryptoHistogram, value: f64) {
+            let key = Self::key(histogram.name(), histogram.labels());
+            self.histograms.lock().unwrap().entry(key).or_default().push(value);
+        }
+    }
+
+    #[test]
+    fn test_recorder_is_a_noop_until_enabled() {
+        let recorder = MetricsRecorder::default();
+        let metrics = Arc::new(MemoryMetrics::default());
+
+        recorder.increment(CryptoCounter::OlmSessionCreated);
+        assert!(!recorder.is_enabled());
+
+        recorder.set(Some(metrics.clone()));
+        assert!(recorder.is_enabled());
+
+        recorder.increment(CryptoCounter::OlmSessionCreated);
+        recorder.increment_by(CryptoCounter::ToDeviceMessageSent, 3);
+        recorder.observe(CryptoHistogram::BackupUploadBatchSize, 10.0);
+
+        assert_eq!(metrics.counter(CryptoCounter::OlmSessionCreated), 1);
+        assert_eq!(metrics.counter(CryptoCounter::ToDeviceMessageSent), 3);
+        assert_eq!(metrics.histogram(CryptoHistogram::BackupUploadBatchSize), vec![10.0]);
+
+        recorder.set(None);
+        recorder.increment(CryptoCounter::OlmSessionCreated);
+        assert_eq!(metrics.counter(CryptoCounter::OlmSessionCreated), 1);
+    }
+
+    #[test]
+    fn test_decryption_failures_are_labelled_with_the_cause() {
+        let metrics = MemoryMetrics::default();
+
+        metrics.increment_counter(CryptoCounter::DecryptionAttemptFailure(UtdCause::Unknown), 1);
+        metrics.increment_counter(
+            CryptoCounter::DecryptionAttemptFailure(UtdCause::WithheldBySender),
+            2,
+        );
+
+        assert_eq!(
+            CryptoCounter::DecryptionAttemptFailure(UtdCause::WithheldBySender).labels(),
+            vec![("cause", "withheld_by_sender")]
+        );
+        assert_eq!(metrics.counter(CryptoCounter::DecryptionAttemptFailure(UtdCause::Unknown)), 1);
+        assert_eq!(
+            metrics.counter(CryptoCounter::DecryptionAttemptFailure(UtdCause::WithheldBySender)),
+            2
+        );
+    }
+}
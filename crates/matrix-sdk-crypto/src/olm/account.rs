@@ -62,6 +62,7 @@ use crate::{
     dehydrated_devices::DehydrationError,
     error::{EventError, OlmResult, SessionCreationError},
     identities::DeviceData,
+    metrics::CryptoCounter,
     olm::SenderData,
     requests::UploadSigningKeysRequest,
     store::{Changes, DeviceChanges, Store},
@@ -1327,6 +1328,7 @@ impl Account {
                 // it to the cache but don't store it.
                 let mut changes =
                     Changes { sessions: vec![result.session.clone()], ..Default::default() };
+                store.metrics().increment(CryptoCounter::OlmSessionCreated);
 
                 // Any new Olm session will bump the Olm wedging index for the
                 // sender's device, if we have their device, which will cause us
@@ -50,7 +50,7 @@ use crate::{
                 ForwardedRoomKeyContent,
             },
             olm_v1::DecryptedForwardedRoomKeyEvent,
-            room::encrypted::{EncryptedEvent, RoomEventEncryptionScheme},
+            room::encrypted::{encrypted_state_key, EncryptedEvent, RoomEventEncryptionScheme},
         },
         serialize_curve_key, EventEncryptionAlgorithm, SigningKeys,
     },
@@ -151,6 +151,13 @@ pub struct InboundGroupSession {
     /// created.
     history_visibility: Arc<Option<HistoryVisibility>>,
 
+    /// Whether the history visibility of the room allowed the keys of this
+    /// session to be shared with users invited to the room later, as described
+    /// in [MSC3061].
+    ///
+    /// [MSC3061]: https://github.com/matrix-org/matrix-spec-proposals/pull/3061
+    pub(crate) shared_history: bool,
+
     /// Was this room key backed up to the server.
     backed_up: Arc<AtomicBool>,
 }
@@ -193,9 +200,12 @@ impl InboundGroupSession {
         let mut keys = SigningKeys::new();
         keys.insert(DeviceKeyAlgorithm::Ed25519, signing_key.into());
 
+        let shared_history = history_visibility.as_ref().is_some_and(is_history_shared);
+
         Ok(InboundGroupSession {
             inner: Arc::new(Mutex::new(session)),
             history_visibility: history_visibility.into(),
+            shared_history,
             session_id: session_id.into(),
             first_known_index,
             creator_info: SessionCreatorInfo {
@@ -239,6 +249,7 @@ impl InboundGroupSession {
             forwarding_curve25519_key_chain: vec![],
             session_key: backup.session_key,
             sender_claimed_keys: backup.sender_claimed_keys,
+            shared_history: backup.shared_history,
         })
     }
 
@@ -260,6 +271,7 @@ impl InboundGroupSession {
             imported: self.imported,
             backed_up: self.backed_up(),
             history_visibility: self.history_visibility.as_ref().clone(),
+            shared_history: self.shared_history,
             algorithm: (*self.algorithm).to_owned(),
         }
     }
@@ -277,6 +289,15 @@ impl InboundGroupSession {
         self.creator_info.curve25519_key
     }
 
+    /// Can the keys of this session be shared with users invited to the room
+    /// later, in a room key bundle?
+    ///
+    /// This is the case if the history visibility of the room was `shared` or
+    /// `invited` when the session was created, see [`is_history_shared()`].
+    pub fn shared_history(&self) -> bool {
+        self.shared_history
+    }
+
     /// Has the session been backed up to the server.
     pub fn backed_up(&self) -> bool {
         self.backed_up.load(SeqCst)
@@ -313,6 +334,7 @@ impl InboundGroupSession {
             forwarding_curve25519_key_chain: vec![],
             sender_claimed_keys: (*self.creator_info.signing_keys).clone(),
             session_key,
+            shared_history: self.shared_history,
         }
     }
 
@@ -341,6 +363,7 @@ impl InboundGroupSession {
             },
             sender_data: pickle.sender_data,
             history_visibility: pickle.history_visibility.into(),
+            shared_history: pickle.shared_history,
             first_known_index,
             room_id: (*pickle.room_id).into(),
             backed_up: AtomicBool::from(pickle.backed_up).into(),
@@ -465,6 +488,34 @@ impl InboundGroupSession {
             return Err(EventError::MismatchedRoom(self.room_id().to_owned(), room_id).into());
         }
 
+        // For encrypted state events, check that the outer state key matches the type
+        // and state key of the decrypted event, otherwise the server could move
+        // the event to another place in the room state.
+        if let Some(outer_state_key) = event.state_key() {
+            let event_type = decrypted_object.get("type").and_then(|t| t.as_str()).unwrap_or("");
+
+            let state_key = match decrypted_object.get("state_key").and_then(|s| s.as_str()) {
+                Some(state_key) => (encrypted_state_key(event_type, state_key) == outer_state_key)
+                    .then(|| state_key.to_owned()),
+                // The sender didn't include the state key in the encrypted payload, all we can
+                // do is check that the type matches.
+                None => outer_state_key
+                    .strip_prefix(event_type)
+                    .and_then(|s| s.strip_prefix(':'))
+                    .map(ToOwned::to_owned),
+            };
+
+            let Some(state_key) = state_key else {
+                return Err(EventError::MismatchedStateKey(
+                    outer_state_key.to_owned(),
+                    event_type.to_owned(),
+                )
+                .into());
+            };
+
+            decrypted_object.insert("state_key".to_owned(), state_key.into());
+        }
+
         decrypted_object.insert(
             "unsigned".to_owned(),
             serde_json::to_value(&event.unsigned).unwrap_or_default(),
@@ -537,6 +588,10 @@ pub struct PickledInboundGroupSession {
     pub backed_up: bool,
     /// History visibility of the room when the session was created.
     pub history_visibility: Option<HistoryVisibility>,
+    /// Flag remembering if the keys of the session can be shared with users
+    /// invited to the room later.
+    #[serde(default)]
+    pub shared_history: bool,
     /// The algorithm of this inbound group session.
     #[serde(default = "default_algorithm")]
     pub algorithm: EventEncryptionAlgorithm,
@@ -546,6 +601,17 @@ fn default_algorithm() -> EventEncryptionAlgorithm {
     EventEncryptionAlgorithm::MegolmV1AesSha2
 }
 
+/// Does the given history visibility allow the keys of the room to be shared
+/// with users invited to the room later?
+///
+/// This is the case for the `shared` and `invited` history visibilities, see
+/// [MSC3061].
+///
+/// [MSC3061]: https://github.com/matrix-org/matrix-spec-proposals/pull/3061
+pub fn is_history_shared(history_visibility: &HistoryVisibility) -> bool {
+    matches!(history_visibility, HistoryVisibility::Shared | HistoryVisibility::Invited)
+}
+
 impl TryFrom<&ExportedRoomKey> for InboundGroupSession {
     type Error = SessionCreationError;
 
@@ -565,6 +631,7 @@ impl TryFrom<&ExportedRoomKey> for InboundGroupSession {
             // See https://github.com/matrix-org/matrix-rust-sdk/issues/3548
             sender_data: SenderData::default(),
             history_visibility: None.into(),
+            shared_history: key.shared_history,
             first_known_index,
             room_id: key.room_id.to_owned(),
             imported: true,
@@ -595,6 +662,7 @@ impl From<&ForwardedMegolmV1AesSha2Content> for InboundGroupSession {
             // See https://github.com/matrix-org/matrix-rust-sdk/issues/3548
             sender_data: SenderData::default(),
             history_visibility: None.into(),
+            shared_history: value.shared_history,
             first_known_index,
             room_id: value.room_id.to_owned(),
             imported: true,
@@ -621,6 +689,7 @@ impl From<&ForwardedMegolmV2AesSha2Content> for InboundGroupSession {
             // See https://github.com/matrix-org/matrix-rust-sdk/issues/3548
             sender_data: SenderData::default(),
             history_visibility: None.into(),
+            shared_history: value.shared_history,
             first_known_index,
             room_id: value.room_id.to_owned(),
             imported: true,
@@ -789,6 +858,7 @@ mod tests {
                 "imported":false,
                 "backed_up":false,
                 "history_visibility":"shared",
+                "shared_history":true,
                 "algorithm":"m.megolm.v1.aes-sha2"
             })
         );
@@ -20,10 +20,11 @@ mod outbound;
 mod sender_data;
 pub(crate) mod sender_data_finder;
 
-pub use inbound::{InboundGroupSession, PickledInboundGroupSession};
+pub use inbound::{is_history_shared, InboundGroupSession, PickledInboundGroupSession};
 pub(crate) use outbound::ShareState;
 pub use outbound::{
-    EncryptionSettings, OutboundGroupSession, PickledOutboundGroupSession, ShareInfo,
+    EncryptionSettings, OutboundGroupSession, OutboundGroupSessionInfo,
+    PickledOutboundGroupSession, ShareInfo,
 };
 pub use sender_data::{KnownSenderData, SenderData, SenderDataType};
 use thiserror::Error;
@@ -98,6 +99,17 @@ pub struct ExportedRoomKey {
         serialize_with = "serialize_curve_key_vec"
     )]
     pub forwarding_curve25519_key_chain: Vec<Curve25519PublicKey>,
+
+    /// Whether the keys of this session can be shared with users invited to
+    /// the room later, as described in [MSC3061].
+    ///
+    /// [MSC3061]: https://github.com/matrix-org/matrix-spec-proposals/pull/3061
+    #[serde(
+        default,
+        rename = "org.matrix.msc3061.shared_history",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub shared_history: bool,
 }
 
 impl ExportedRoomKey {
@@ -117,6 +129,7 @@ impl ExportedRoomKey {
             session_key: room_key.session_key,
             sender_claimed_keys: room_key.sender_claimed_keys,
             forwarding_curve25519_key_chain: room_key.forwarding_curve25519_key_chain,
+            shared_history: room_key.shared_history,
         }
     }
 }
@@ -148,6 +161,17 @@ pub struct BackedUpRoomKey {
         serialize_with = "serialize_curve_key_vec"
     )]
     pub forwarding_curve25519_key_chain: Vec<Curve25519PublicKey>,
+
+    /// Whether the keys of this session can be shared with users invited to
+    /// the room later, as described in [MSC3061].
+    ///
+    /// [MSC3061]: https://github.com/matrix-org/matrix-spec-proposals/pull/3061
+    #[serde(
+        default,
+        rename = "org.matrix.msc3061.shared_history",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub shared_history: bool,
 }
 
 impl TryFrom<ExportedRoomKey> for ForwardedRoomKeyContent {
@@ -180,6 +204,7 @@ impl TryFrom<ExportedRoomKey> for ForwardedRoomKeyContent {
                             forwarding_curve25519_key_chain: room_key
                                 .forwarding_curve25519_key_chain
                                 .clone(),
+                            shared_history: room_key.shared_history,
                             other: Default::default(),
                         }
                         .into(),
@@ -197,6 +222,7 @@ impl TryFrom<ExportedRoomKey> for ForwardedRoomKeyContent {
                         session_key: room_key.session_key,
                         claimed_sender_key: room_key.sender_key,
                         claimed_signing_keys: room_key.sender_claimed_keys,
+                        shared_history: room_key.shared_history,
                         other: Default::default(),
                     }
                     .into(),
@@ -215,6 +241,7 @@ impl From<ExportedRoomKey> for BackedUpRoomKey {
             session_key: k.session_key,
             sender_claimed_keys: k.sender_claimed_keys,
             forwarding_curve25519_key_chain: k.forwarding_curve25519_key_chain,
+            shared_history: k.shared_history,
         }
     }
 }
@@ -240,6 +267,7 @@ impl TryFrom<ForwardedRoomKeyContent> for ExportedRoomKey {
                     sender_claimed_keys,
                     sender_key: content.claimed_sender_key,
                     session_key: content.session_key,
+                    shared_history: content.shared_history,
                 })
             }
             #[cfg(feature = "experimental-algorithms")]
@@ -251,6 +279,7 @@ impl TryFrom<ForwardedRoomKeyContent> for ExportedRoomKey {
                 sender_claimed_keys: content.claimed_signing_keys,
                 sender_key: content.claimed_sender_key,
                 session_key: content.session_key,
+                shared_history: content.shared_history,
             }),
             ForwardedRoomKeyContent::Unknown(c) => Err(SessionExportError::Algorithm(c.algorithm)),
         }
@@ -26,13 +26,14 @@ use std::{
 use ruma::{
     events::{
         room::{encryption::RoomEncryptionEventContent, history_visibility::HistoryVisibility},
-        AnyMessageLikeEventContent,
+        AnyMessageLikeEventContent, AnyStateEventContent,
     },
     serde::Raw,
     DeviceId, OwnedDeviceId, OwnedRoomId, OwnedTransactionId, OwnedUserId, RoomId,
     SecondsSinceUnixEpoch, TransactionId, UserId,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue as RawJsonValue;
 use tokio::sync::RwLock;
 use tracing::{debug, error, info};
 use vodozemac::{megolm::SessionConfig, Curve25519PublicKey};
@@ -42,11 +43,11 @@ pub use vodozemac::{
     PickleError,
 };
 
-use super::SessionCreationError;
+use super::{is_history_shared, SessionCreationError};
 #[cfg(feature = "experimental-algorithms")]
 use crate::types::events::room::encrypted::MegolmV2AesSha2Content;
 use crate::{
-    session_manager::CollectStrategy,
+    session_manager::{CollectStrategy, RoomKeySharingPolicy},
     store::caches::SequenceNumber,
     types::{
         events::{
@@ -100,6 +101,10 @@ pub struct EncryptionSettings {
     /// Default will send to all devices.
     #[serde(default)]
     pub sharing_strategy: CollectStrategy,
+    /// An additional policy restricting which devices receive the room keys.
+    /// If unset, the policy from the room settings, if any, is used.
+    #[serde(default)]
+    pub sharing_policy: Option<RoomKeySharingPolicy>,
 }
 
 impl Default for EncryptionSettings {
@@ -110,6 +115,7 @@ impl Default for EncryptionSettings {
             rotation_period_msgs: ROTATION_MESSAGES,
             history_visibility: HistoryVisibility::Shared,
             sharing_strategy: CollectStrategy::default(),
+            sharing_policy: None,
         }
     }
 }
@@ -133,10 +139,51 @@ impl EncryptionSettings {
             rotation_period_msgs,
             history_visibility,
             sharing_strategy,
+            sharing_policy: None,
         }
     }
 }
 
+/// A snapshot of the state of an [`OutboundGroupSession`], e.g. to audit how
+/// long a room key has been in use and who received it.
+#[derive(Clone, Debug)]
+pub struct OutboundGroupSessionInfo {
+    /// The room the session is used in.
+    pub room_id: OwnedRoomId,
+
+    /// The unique ID of the session.
+    pub session_id: String,
+
+    /// When the session was created.
+    pub created_at: SecondsSinceUnixEpoch,
+
+    /// How long the session has been in use.
+    pub age: Duration,
+
+    /// The number of messages which were encrypted with the session.
+    pub message_count: u64,
+
+    /// The settings the session was created with, among which its rotation
+    /// period.
+    pub settings: EncryptionSettings,
+
+    /// Has the session expired, i.e. it will be rotated the next time a
+    /// message is sent.
+    pub expired: bool,
+
+    /// Has the session been invalidated, e.g. using
+    /// [`OlmMachine::discard_room_key()`].
+    ///
+    /// [`OlmMachine::discard_room_key()`]: crate::OlmMachine::discard_room_key
+    pub invalidated: bool,
+
+    /// The devices which received the session.
+    pub shared_with: BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>>,
+
+    /// The devices the session was withheld from, with the reason.
+    pub withheld_from: BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, WithheldCode>>,
+}
+
 /// Outbound group session.
 ///
 /// Outbound group sessions are used to exchange room messages between a group
@@ -298,6 +345,43 @@ impl OutboundGroupSession {
         &self.settings
     }
 
+    /// Get a snapshot of the state of this session, its age, usage and
+    /// recipients.
+    pub fn info(&self) -> OutboundGroupSessionInfo {
+        let mut shared_with: BTreeMap<OwnedUserId, BTreeSet<OwnedDeviceId>> = BTreeMap::new();
+        let mut withheld_from: BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, WithheldCode>> =
+            BTreeMap::new();
+
+        for (user_id, devices) in self.shared_with_set.read().unwrap().iter() {
+            for (device_id, info) in devices {
+                match info {
+                    ShareInfo::Shared(_) => {
+                        shared_with.entry(user_id.clone()).or_default().insert(device_id.clone());
+                    }
+                    ShareInfo::Withheld(code) => {
+                        withheld_from
+                            .entry(user_id.clone())
+                            .or_default()
+                            .insert(device_id.clone(), code.clone());
+                    }
+                }
+            }
+        }
+
+        OutboundGroupSessionInfo {
+            room_id: self.room_id.clone(),
+            session_id: self.session_id.to_string(),
+            created_at: self.creation_time,
+            age: self.age(),
+            message_count: self.message_count.load(Ordering::SeqCst),
+            settings: self.settings.as_ref().clone(),
+            expired: self.expired(),
+            invalidated: self.invalidated(),
+            shared_with,
+            withheld_from,
+        }
+    }
+
     /// Mark the request with the given request id as sent.
     ///
     /// This removes the request from the queue and marks the set of
@@ -391,23 +475,64 @@ impl OutboundGroupSession {
         &self,
         event_type: &str,
         content: &Raw<AnyMessageLikeEventContent>,
+    ) -> Raw<RoomEncryptedEventContent> {
+        let relates_to = content
+            .get_field::<serde_json::Value>("m.relates_to")
+            .expect("serde_json::Value deserialization with valid JSON input never fails");
+
+        self.encrypt_payload(event_type, None, content.json(), relates_to).await
+    }
+
+    /// Encrypt a state event for the given room.
+    ///
+    /// The outer type of the event will become `m.room.encrypted`, and its
+    /// outer state key should be created using [`encrypted_state_key()`], so
+    /// the receivers can check that the server didn't move the event to
+    /// another type or state key.
+    ///
+    /// Beware that a room key needs to be shared before this method
+    /// can be called using the `share_room_key()` method.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_type` - The plaintext type of the state event.
+    ///
+    /// * `state_key` - The plaintext state key of the state event.
+    ///
+    /// * `content` - The plaintext content of the state event that should be
+    ///   encrypted in raw JSON form.
+    ///
+    /// [`encrypted_state_key()`]: crate::types::events::room::encrypted::encrypted_state_key
+    pub async fn encrypt_state(
+        &self,
+        event_type: &str,
+        state_key: &str,
+        content: &Raw<AnyStateEventContent>,
+    ) -> Raw<RoomEncryptedEventContent> {
+        self.encrypt_payload(event_type, Some(state_key), content.json(), None).await
+    }
+
+    async fn encrypt_payload(
+        &self,
+        event_type: &str,
+        state_key: Option<&str>,
+        content: &RawJsonValue,
+        relates_to: Option<serde_json::Value>,
     ) -> Raw<RoomEncryptedEventContent> {
         #[derive(Serialize)]
         struct Payload<'a> {
             #[serde(rename = "type")]
             event_type: &'a str,
-            content: &'a Raw<AnyMessageLikeEventContent>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            state_key: Option<&'a str>,
+            content: &'a RawJsonValue,
             room_id: &'a RoomId,
         }
 
-        let payload = Payload { event_type, content, room_id: &self.room_id };
+        let payload = Payload { event_type, state_key, content, room_id: &self.room_id };
         let payload_json =
             serde_json::to_string(&payload).expect("payload serialization never fails");
 
-        let relates_to = content
-            .get_field::<serde_json::Value>("m.relates_to")
-            .expect("serde_json::Value deserialization with valid JSON input never fails");
-
         let ciphertext = self.encrypt_helper(payload_json).await;
         let scheme: RoomEventEncryptionScheme = match self.settings.algorithm {
             EventEncryptionAlgorithm::MegolmV1AesSha2 => MegolmV1AesSha2Content {
@@ -432,6 +557,14 @@ impl OutboundGroupSession {
         Raw::new(&content).expect("m.room.encrypted event content can always be serialized")
     }
 
+    /// How long ago the session was created, zero if the clock went
+    /// backwards.
+    fn age(&self) -> Duration {
+        let creation_time = Duration::from_secs(self.creation_time.get().into());
+        let now = Duration::from_secs(SecondsSinceUnixEpoch::now().get().into());
+        now.checked_sub(creation_time).unwrap_or_default()
+    }
+
     fn elapsed(&self) -> bool {
         let creation_time = Duration::from_secs(self.creation_time.get().into());
         let now = Duration::from_secs(SecondsSinceUnixEpoch::now().get().into());
@@ -525,14 +658,14 @@ impl OutboundGroupSession {
     pub(crate) async fn as_content(&self) -> RoomKeyContent {
         let session_key = self.session_key().await;
 
-        RoomKeyContent::MegolmV1AesSha2(
-            MegolmV1AesSha2RoomKeyContent::new(
-                self.room_id().to_owned(),
-                self.session_id().to_owned(),
-                session_key,
-            )
-            .into(),
-        )
+        let mut content = MegolmV1AesSha2RoomKeyContent::new(
+            self.room_id().to_owned(),
+            self.session_id().to_owned(),
+            session_key,
+        );
+        content.shared_history = is_history_shared(&self.settings.history_visibility);
+
+        RoomKeyContent::MegolmV1AesSha2(content.into())
     }
 
     /// Has or will the session be shared with the given user/device pair.
@@ -90,6 +90,21 @@ pub enum SenderData {
     /// the to-device message that established this session, and we have
     /// verified the cross-signing key.
     SenderVerified(KnownSenderData),
+
+    /// The session was shared with us, along with the rest of the room
+    /// history, by a member of the room who invited us (see [MSC4268]).
+    ///
+    /// We can't link such a session to the device that created it, so we
+    /// record who shared it with us instead.
+    ///
+    /// [MSC4268]: https://github.com/matrix-org/matrix-spec-proposals/pull/4268
+    SharedHistory {
+        /// The user who shared the session with us.
+        forwarder_user_id: OwnedUserId,
+
+        /// The device that sent us the key bundle containing the session.
+        forwarder_device_id: OwnedDeviceId,
+    },
 }
 
 impl SenderData {
@@ -143,6 +158,15 @@ impl SenderData {
         })
     }
 
+    /// Create a [`SenderData`] for a session that was shared with us in a key
+    /// bundle, by the given user and device.
+    pub fn shared_history(forwarder_user_id: &UserId, forwarder_device_id: &DeviceId) -> Self {
+        Self::SharedHistory {
+            forwarder_user_id: forwarder_user_id.to_owned(),
+            forwarder_device_id: forwarder_device_id.to_owned(),
+        }
+    }
+
     /// Create a [`SenderData`] which has the legacy flag set. Caution: messages
     /// within sessions with this flag will be displayed in some contexts,
     /// even when we are unable to verify the sender.
@@ -170,7 +194,7 @@ impl SenderData {
     /// compare_trust_level simpler.
     fn trust_number(&self) -> u8 {
         match self {
-            SenderData::UnknownDevice { .. } => 0,
+            SenderData::UnknownDevice { .. } | SenderData::SharedHistory { .. } => 0,
             SenderData::DeviceInfo { .. } => 1,
             SenderData::VerificationViolation(..) => 2,
             SenderData::SenderUnverified(..) => 3,
@@ -186,6 +210,7 @@ impl SenderData {
             Self::VerificationViolation { .. } => SenderDataType::VerificationViolation,
             Self::SenderUnverified { .. } => SenderDataType::SenderUnverified,
             Self::SenderVerified { .. } => SenderDataType::SenderVerified,
+            Self::SharedHistory { .. } => SenderDataType::SharedHistory,
         }
     }
 }
@@ -221,6 +246,11 @@ enum SenderDataReader {
 
     SenderVerified(KnownSenderData),
 
+    SharedHistory {
+        forwarder_user_id: OwnedUserId,
+        forwarder_device_id: OwnedDeviceId,
+    },
+
     // If we read this older variant, it gets changed to SenderUnverified or
     // SenderVerified, depending on the master_key_verified flag.
     SenderKnown {
@@ -243,6 +273,9 @@ impl From<SenderDataReader> for SenderData {
             SenderDataReader::VerificationViolation(data) => Self::VerificationViolation(data),
             SenderDataReader::SenderUnverified(data) => Self::SenderUnverified(data),
             SenderDataReader::SenderVerified(data) => Self::SenderVerified(data),
+            SenderDataReader::SharedHistory { forwarder_user_id, forwarder_device_id } => {
+                Self::SharedHistory { forwarder_user_id, forwarder_device_id }
+            }
             SenderDataReader::SenderKnown {
                 user_id,
                 device_id,
@@ -275,6 +308,8 @@ pub enum SenderDataType {
     SenderUnverified = 4,
     /// The [`SenderData`] is of type `SenderVerified`.
     SenderVerified = 5,
+    /// The [`SenderData`] is of type `SharedHistory`.
+    SharedHistory = 6,
 }
 
 #[cfg(test)]
@@ -25,17 +25,21 @@ mod utility;
 
 pub use account::{Account, OlmMessageHash, PickledAccount, StaticAccountData};
 pub(crate) use account::{OlmDecryptionInfo, SessionType};
+pub use group_sessions::{
+    is_history_shared, BackedUpRoomKey, EncryptionSettings, ExportedRoomKey, InboundGroupSession,
+    KnownSenderData, OutboundGroupSession, OutboundGroupSessionInfo, PickledInboundGroupSession,
+    PickledOutboundGroupSession, SenderData, SenderDataType, SessionCreationError,
+    SessionExportError, SessionKey, ShareInfo,
+};
 pub(crate) use group_sessions::{
     sender_data_finder::{self, SenderDataFinder},
     ShareState,
 };
-pub use group_sessions::{
-    BackedUpRoomKey, EncryptionSettings, ExportedRoomKey, InboundGroupSession, KnownSenderData,
-    OutboundGroupSession, PickledInboundGroupSession, PickledOutboundGroupSession, SenderData,
-    SenderDataType, SessionCreationError, SessionExportError, SessionKey, ShareInfo,
-};
 pub use session::{PickledSession, Session};
-pub use signing::{CrossSigningStatus, PickledCrossSigningIdentity, PrivateCrossSigningIdentity};
+pub use signing::{
+    CrossSigningKeyRotation, CrossSigningStatus, PickledCrossSigningIdentity,
+    PrivateCrossSigningIdentity,
+};
 pub(crate) use utility::{SignedJsonObject, VerifyJson};
 pub use vodozemac::{olm::IdentityKeys, Curve25519PublicKey};
 
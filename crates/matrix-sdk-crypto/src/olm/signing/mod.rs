@@ -14,9 +14,12 @@
 
 mod pk_signing;
 
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 pub use pk_signing::{MasterSigning, PickledSignings, SelfSigning, SigningError, UserSigning};
@@ -115,6 +118,35 @@ impl CrossSigningStatus {
     }
 }
 
+/// The cross-signing keys which should be replaced by
+/// [`OlmMachine::rotate_cross_signing_keys()`].
+///
+/// The master key is never rotated, since replacing it means resetting the
+/// identity and losing the trust other users placed in it.
+///
+/// [`OlmMachine::rotate_cross_signing_keys()`]: crate::OlmMachine::rotate_cross_signing_keys
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrossSigningKeyRotation {
+    /// Only replace the self-signing key, used to sign our own devices.
+    SelfSigning,
+    /// Only replace the user-signing key, used to sign other users.
+    UserSigning,
+    /// Replace both the self-signing and the user-signing keys.
+    Both,
+}
+
+impl CrossSigningKeyRotation {
+    /// Is the self-signing key replaced?
+    pub fn rotates_self_signing_key(&self) -> bool {
+        matches!(self, Self::SelfSigning | Self::Both)
+    }
+
+    /// Is the user-signing key replaced?
+    pub fn rotates_user_signing_key(&self) -> bool {
+        matches!(self, Self::UserSigning | Self::Both)
+    }
+}
+
 impl PrivateCrossSigningIdentity {
     /// Get the user id that this identity belongs to.
     pub fn user_id(&self) -> &UserId {
@@ -487,6 +519,88 @@ impl PrivateCrossSigningIdentity {
         Ok(SignatureUploadRequest::new(signed_keys))
     }
 
+    /// Create a copy of this identity where the subkeys selected by the given
+    /// [`CrossSigningKeyRotation`] are replaced with new random keys, signed
+    /// by our master key.
+    ///
+    /// This identity is left untouched, the copy is marked as not shared
+    /// since its new public keys haven't been uploaded yet.
+    ///
+    /// Returns a [`SignatureError::MissingSigningKey`] if we don't have the
+    /// private part of the master key.
+    pub(crate) async fn with_rotated_subkeys(
+        &self,
+        rotation: CrossSigningKeyRotation,
+    ) -> Result<Self, SignatureError> {
+        let rotated = Self::from_pickle(self.pickle().await)
+            .expect("We should be able to restore a pickle of our own identity");
+        rotated.shared.store(false, Ordering::SeqCst);
+
+        {
+            let master_key = rotated.master_key.lock().await;
+            let master_key = master_key.as_ref().ok_or(SignatureError::MissingSigningKey)?;
+
+            if rotation.rotates_self_signing_key() {
+                *rotated.self_signing_key.lock().await = Some(master_key.new_self_signing_key());
+            }
+
+            if rotation.rotates_user_signing_key() {
+                *rotated.user_signing_key.lock().await = Some(master_key.new_user_signing_key());
+            }
+        }
+
+        Ok(rotated)
+    }
+
+    /// Sign the given device keys of our own devices with our self-signing
+    /// key, and the master keys of the given users with our user-signing key.
+    ///
+    /// The signatures are added to the given keys, next to the existing ones,
+    /// so they can be used to update our local copies of the devices and
+    /// identities. All the signatures are uploaded with a single request.
+    pub(crate) async fn sign_devices_and_users(
+        &self,
+        device_keys: &mut [DeviceKeys],
+        master_keys: &mut [MasterPubkey],
+    ) -> Result<SignatureUploadRequest, SignatureError> {
+        let mut signed_keys: BTreeMap<OwnedUserId, SignedKeys> = BTreeMap::new();
+
+        if !device_keys.is_empty() {
+            let self_signing_key = self.self_signing_key.lock().await;
+            let self_signing_key =
+                self_signing_key.as_ref().ok_or(SignatureError::MissingSigningKey)?;
+
+            for device_keys in device_keys.iter_mut() {
+                self_signing_key.sign_device(device_keys)?;
+                signed_keys
+                    .entry(self.user_id.clone())
+                    .or_insert_with(SignedKeys::new)
+                    .add_device_keys(device_keys.device_id.clone(), device_keys.to_raw());
+            }
+        }
+
+        if !master_keys.is_empty() {
+            let user_signing_key = self.user_signing_key.lock().await;
+            let user_signing_key =
+                user_signing_key.as_ref().ok_or(SignatureError::MissingSigningKey)?;
+
+            for master_key in master_keys.iter_mut() {
+                let key_id = master_key
+                    .get_first_key()
+                    .ok_or(SignatureError::MissingSigningKey)?
+                    .to_base64();
+
+                user_signing_key.sign_master_key(master_key.as_mut())?;
+                signed_keys
+                    .entry(master_key.user_id().to_owned())
+                    .or_insert_with(SignedKeys::new)
+                    .add_cross_signing_keys(key_id.into(), master_key.as_ref().to_raw());
+            }
+        }
+
+        Ok(SignatureUploadRequest::new(signed_keys))
+    }
+
     pub(crate) async fn sign(&self, message: &str) -> Result<Ed25519Signature, SignatureError> {
         Ok(self
             .master_key
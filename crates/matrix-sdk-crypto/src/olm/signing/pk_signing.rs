@@ -116,32 +116,38 @@ impl MasterSigning {
     }
 
     pub(crate) fn new_subkeys(&self) -> (UserSigning, SelfSigning) {
+        (self.new_user_signing_key(), self.new_self_signing_key())
+    }
+
+    /// Create a new random user-signing key, signed by this master key.
+    pub(crate) fn new_user_signing_key(&self) -> UserSigning {
         let user = Signing::new();
         let mut public_key =
             user.cross_signing_key(self.public_key.user_id().to_owned(), KeyUsage::UserSigning);
 
         self.sign_subkey(&mut public_key);
 
-        let user = UserSigning {
+        UserSigning {
             inner: user,
             public_key: public_key
                 .try_into()
                 .expect("We can always create a new random UserSigningPubkey"),
-        };
+        }
+    }
 
+    /// Create a new random self-signing key, signed by this master key.
+    pub(crate) fn new_self_signing_key(&self) -> SelfSigning {
         let self_signing = Signing::new();
         let mut public_key = self_signing
             .cross_signing_key(self.public_key.user_id().to_owned(), KeyUsage::SelfSigning);
         self.sign_subkey(&mut public_key);
 
-        let self_signing = SelfSigning {
+        SelfSigning {
             inner: self_signing,
             public_key: public_key
                 .try_into()
                 .expect("We can always create a new random SelfSigningPubkey"),
-        };
-
-        (user, self_signing)
+        }
     }
 
     pub fn public_key(&self) -> &MasterPubkey {
@@ -258,6 +264,26 @@ impl UserSigning {
         Ok(signatures)
     }
 
+    /// Add a signature of this key to the given master key of another user,
+    /// keeping the existing signatures.
+    pub(crate) fn sign_master_key(
+        &self,
+        master_key: &mut CrossSigningKey,
+    ) -> Result<(), SignatureError> {
+        let signature = self.inner.sign_json(serde_json::to_value(&*master_key)?)?;
+
+        master_key.signatures.add_signature(
+            self.public_key.user_id().to_owned(),
+            DeviceKeyId::from_parts(
+                DeviceKeyAlgorithm::Ed25519,
+                self.inner.public_key.to_base64().as_str().into(),
+            ),
+            signature,
+        );
+
+        Ok(())
+    }
+
     pub fn from_pickle(pickle: PickledUserSigning) -> Result<Self, SigningError> {
         let inner = Signing::from_pickle(pickle.pickle)?;
 
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod rotation_policy;
 mod share_strategy;
 
 use std::{
@@ -23,19 +24,23 @@ use std::{
 use futures_util::future::join_all;
 use itertools::Itertools;
 use matrix_sdk_common::executor::spawn;
+pub use rotation_policy::{RoomKeyRecipientChanges, RoomKeyRotationPolicy};
 use ruma::{
-    events::{AnyMessageLikeEventContent, ToDeviceEventType},
+    events::{AnyMessageLikeEventContent, AnyStateEventContent, ToDeviceEventType},
     serde::Raw,
     to_device::DeviceIdOrAllDevices,
     OwnedDeviceId, OwnedRoomId, OwnedTransactionId, OwnedUserId, RoomId, TransactionId, UserId,
 };
-pub(crate) use share_strategy::CollectRecipientsResult;
-pub use share_strategy::CollectStrategy;
+pub(crate) use share_strategy::{
+    collect_recipient_devices, CollectRecipientsResult, RecipientDevices,
+};
+pub use share_strategy::{CollectStrategy, RoomKeySharingPolicy};
 use tracing::{debug, error, info, instrument, trace};
 
 use crate::{
     error::{EventError, MegolmResult, OlmResult},
     identities::device::MaybeEncryptedRoomKey,
+    metrics::CryptoCounter,
     olm::{
         InboundGroupSession, OutboundGroupSession, SenderData, SenderDataFinder, Session,
         ShareInfo, ShareState,
@@ -129,13 +134,33 @@ pub(crate) struct GroupSessionManager {
     store: Store,
     /// The currently active outbound group sessions.
     sessions: GroupSessionCache,
+    /// The custom policy deciding if a room key should be rotated when it's
+    /// shared with new recipients.
+    rotation_policy: Arc<StdRwLock<Option<Arc<dyn RoomKeyRotationPolicy>>>>,
 }
 
 impl GroupSessionManager {
     const MAX_TO_DEVICE_MESSAGES: usize = 250;
 
     pub fn new(store: Store) -> Self {
-        Self { store: store.clone(), sessions: GroupSessionCache::new(store) }
+        Self {
+            store: store.clone(),
+            sessions: GroupSessionCache::new(store),
+            rotation_policy: Default::default(),
+        }
+    }
+
+    pub fn set_rotation_policy(&self, policy: Option<Arc<dyn RoomKeyRotationPolicy>>) {
+        *self.rotation_policy.write().unwrap() = policy;
+    }
+
+    /// Get the current outbound group session of the given room, loading it
+    /// from the store if needed.
+    pub async fn get_or_load_outbound_group_session(
+        &self,
+        room_id: &RoomId,
+    ) -> Option<OutboundGroupSession> {
+        self.sessions.get_or_load(room_id).await
     }
 
     pub async fn invalidate_group_session(&self, room_id: &RoomId) -> StoreResult<bool> {
@@ -207,6 +232,27 @@ impl GroupSessionManager {
         Ok(content)
     }
 
+    pub async fn encrypt_state(
+        &self,
+        room_id: &RoomId,
+        event_type: &str,
+        state_key: &str,
+        content: &Raw<AnyStateEventContent>,
+    ) -> MegolmResult<Raw<RoomEncryptedEventContent>> {
+        let session =
+            self.sessions.get_or_load(room_id).await.expect("Session wasn't created nor shared");
+
+        assert!(!session.expired(), "Session expired");
+
+        let content = session.encrypt_state(event_type, state_key, content).await;
+
+        let mut changes = Changes::default();
+        changes.outbound_group_sessions.push(session);
+        self.store.save_changes(changes).await?;
+
+        Ok(content)
+    }
+
     /// Create a new outbound group session.
     ///
     /// This also creates a matching inbound group session.
@@ -224,6 +270,8 @@ impl GroupSessionManager {
             .map_err(|_| EventError::UnsupportedAlgorithm)?;
 
         self.sessions.insert(outbound.clone());
+        self.store.metrics().increment(CryptoCounter::MegolmSessionCreated);
+
         Ok((outbound, inbound))
     }
 
@@ -239,6 +287,7 @@ impl GroupSessionManager {
         // create a new one.
         if let Some(s) = outbound_session {
             if s.expired() || s.invalidated() {
+                self.store.metrics().increment(CryptoCounter::MegolmSessionRotated);
                 self.create_outbound_group_session(room_id, settings, own_sender_data)
                     .await
                     .map(|(o, i)| (o, i.into()))
@@ -345,6 +394,57 @@ impl GroupSessionManager {
         share_strategy::collect_session_recipients(&self.store, users, settings, outbound).await
     }
 
+    /// Ask the [`RoomKeyRotationPolicy`], if there is one, whether the
+    /// session should be rotated before it's shared with the given devices.
+    fn rotation_policy_requires_rotation(
+        &self,
+        room_id: &RoomId,
+        outbound: &OutboundGroupSession,
+        devices: &BTreeMap<OwnedUserId, Vec<DeviceData>>,
+    ) -> bool {
+        let Some(policy) = self.rotation_policy.read().unwrap().clone() else {
+            return false;
+        };
+
+        let session = outbound.info();
+
+        let new_devices: BTreeMap<OwnedUserId, Vec<DeviceData>> = devices
+            .iter()
+            .filter_map(|(user_id, devices)| {
+                let devices: Vec<_> = devices
+                    .iter()
+                    .filter(|d| matches!(outbound.is_shared_with(d), ShareState::NotShared))
+                    .cloned()
+                    .collect();
+
+                (!devices.is_empty()).then(|| (user_id.clone(), devices))
+            })
+            .collect();
+
+        if new_devices.is_empty() {
+            return false;
+        }
+
+        let new_users = new_devices
+            .keys()
+            .filter(|user_id| !session.shared_with.contains_key(*user_id))
+            .cloned()
+            .collect();
+
+        let changes =
+            RoomKeyRecipientChanges { room_id, session: &session, new_users, new_devices };
+        let should_rotate = policy.should_rotate(&changes);
+
+        if should_rotate {
+            debug!(
+                new_users = ?changes.new_users,
+                "The room key rotation policy requires the room key to be rotated",
+            );
+        }
+
+        should_rotate
+    }
+
     async fn encrypt_request(
         store: Arc<CryptoStoreWrapper>,
         chunk: Vec<DeviceData>,
@@ -383,6 +483,7 @@ impl GroupSessionManager {
     ) -> OlmResult<OutboundGroupSession> {
         Ok(if should_rotate {
             let old_session_id = outbound.session_id();
+            self.store.metrics().increment(CryptoCounter::MegolmSessionRotated);
 
             let (outbound, mut inbound) = self
                 .create_outbound_group_session(room_id, encryption_settings, SenderData::unknown())
@@ -663,6 +764,7 @@ impl GroupSessionManager {
 
         // Having an inbound group session here means that we created a new
         // group session pair, which we then need to store.
+        let is_new_session = inbound.is_some();
         if let Some(mut inbound) = inbound {
             // Use our own device info to populate the SenderData that validates the
             // InboundGroupSession that we create as a pair to the OutboundGroupSession we
@@ -690,6 +792,12 @@ impl GroupSessionManager {
         let CollectRecipientsResult { should_rotate, devices, mut withheld_devices } =
             self.collect_session_recipients(users, &encryption_settings, &outbound).await?;
 
+        // A session we just created wasn't shared with anybody yet, there's
+        // nothing the custom rotation policy could protect.
+        let should_rotate = should_rotate
+            || (!is_new_session
+                && self.rotation_policy_requires_rotation(room_id, &outbound, &devices));
+
         let outbound = self
             .maybe_rotate_group_session(
                 should_rotate,
@@ -797,7 +905,10 @@ mod tests {
         identities::DeviceData,
         machine::EncryptionSyncChanges,
         olm::{Account, SenderData},
-        session_manager::{group_sessions::CollectRecipientsResult, CollectStrategy},
+        session_manager::{
+            group_sessions::CollectRecipientsResult, CollectStrategy, RoomKeyRecipientChanges,
+            RoomKeyRotationPolicy,
+        },
         types::{
             events::{
                 room::encrypted::EncryptedToDeviceEvent,
@@ -973,6 +1084,85 @@ mod tests {
         assert_eq!(withheld_count, 2);
     }
 
+    #[async_test]
+    async fn test_outbound_group_session_info() {
+        let machine = machine_with_shared_room_key_test_helper().await;
+        let room_id = room_id!("!test:localhost");
+
+        let info = machine.outbound_group_session_info(room_id).await.unwrap();
+        let outbound =
+            machine.inner.group_session_manager.get_outbound_group_session(room_id).unwrap();
+
+        assert_eq!(info.room_id, room_id);
+        assert_eq!(info.session_id, outbound.session_id());
+        assert_eq!(info.message_count, 0);
+        assert!(!info.expired);
+        assert!(!info.invalidated);
+        assert_eq!(info.shared_with[user_id!("@example:localhost")].len(), 148);
+
+        machine.discard_room_key(room_id).await.unwrap();
+        let info = machine.outbound_group_session_info(room_id).await.unwrap();
+        assert!(info.invalidated);
+
+        assert!(machine.outbound_group_session_info(room_id!("!other:localhost")).await.is_none());
+    }
+
+    #[async_test]
+    async fn test_rotation_policy() {
+        #[derive(Debug, Default)]
+        struct RotateOnNewUsers {
+            new_users: std::sync::Mutex<Vec<BTreeSet<ruma::OwnedUserId>>>,
+        }
+
+        impl RoomKeyRotationPolicy for RotateOnNewUsers {
+            fn should_rotate(&self, changes: &RoomKeyRecipientChanges<'_>) -> bool {
+                self.new_users.lock().unwrap().push(changes.new_users.clone());
+                !changes.new_users.is_empty()
+            }
+        }
+
+        let machine = machine_with_shared_room_key_test_helper().await;
+        let room_id = room_id!("!test:localhost");
+        let example = user_id!("@example:localhost");
+        let bob = user_id!("@bob:localhost");
+
+        let policy = Arc::new(RotateOnNewUsers::default());
+        machine.set_room_key_rotation_policy(Some(policy.clone()));
+
+        let session_id = machine.outbound_group_session_info(room_id).await.unwrap().session_id;
+
+        // Sharing with the same users doesn't rotate the room key.
+        machine
+            .share_room_key(room_id, iter::once(example), EncryptionSettings::default())
+            .await
+            .unwrap();
+        assert!(policy.new_users.lock().unwrap().iter().all(BTreeSet::is_empty));
+        let info = machine.outbound_group_session_info(room_id).await.unwrap();
+        assert_eq!(info.session_id, session_id);
+
+        // Bob joined, the policy requires a new room key.
+        let requests = machine
+            .share_room_key(room_id, [example, bob].into_iter(), EncryptionSettings::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            policy.new_users.lock().unwrap().last(),
+            Some(&BTreeSet::from([bob.to_owned()]))
+        );
+
+        let info = machine.outbound_group_session_info(room_id).await.unwrap();
+        assert_ne!(info.session_id, session_id);
+
+        let response = ToDeviceResponse::new();
+        for request in requests {
+            machine.mark_request_as_sent(&request.txn_id, &response).await.unwrap();
+        }
+
+        let info = machine.outbound_group_session_info(room_id).await.unwrap();
+        assert!(info.shared_with.contains_key(bob));
+        assert!(info.shared_with.contains_key(example));
+    }
+
     fn count_withheld_from(requests: &[Arc<ToDeviceRequest>], code: WithheldCode) -> usize {
         requests
             .iter()
@@ -0,0 +1,66 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use ruma::{OwnedUserId, RoomId};
+
+use crate::{olm::OutboundGroupSessionInfo, DeviceData};
+
+/// A custom policy deciding if the room key of a room should be rotated,
+/// before it's shared with new recipients.
+///
+/// The room key is always rotated when it expires, when a user leaves the room,
+/// when one of the devices which received it gets deleted or blacklisted, or
+/// when the encryption settings of the room change. The policy is only
+/// consulted when none of those happened, but the room key is about to be
+/// shared with new devices, giving it a chance to require a rotation anyway,
+/// e.g. when a user from a different homeserver joins the room.
+///
+/// The policy is set with [`OlmMachine::set_room_key_rotation_policy()`].
+///
+/// [`OlmMachine::set_room_key_rotation_policy()`]: crate::OlmMachine::set_room_key_rotation_policy
+pub trait RoomKeyRotationPolicy: fmt::Debug + Send + Sync {
+    /// Should the current room key be rotated, given the new recipients it's
+    /// about to be shared with?
+    ///
+    /// If this returns `true`, a new room key is created and shared with all
+    /// the recipients, so the new recipients can't decrypt the messages which
+    /// were encrypted with the previous room key.
+    fn should_rotate(&self, changes: &RoomKeyRecipientChanges<'_>) -> bool;
+}
+
+/// The new recipients of the room key of a room, passed to a
+/// [`RoomKeyRotationPolicy`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct RoomKeyRecipientChanges<'a> {
+    /// The room the room key is used in.
+    pub room_id: &'a RoomId,
+
+    /// The current state of the room key.
+    pub session: &'a OutboundGroupSessionInfo,
+
+    /// The users who didn't receive the room key yet, typically because they
+    /// just joined the room.
+    pub new_users: BTreeSet<OwnedUserId>,
+
+    /// The devices, per user, which are about to receive the room key for the
+    /// first time. Contains the devices of the new users, as well as new
+    /// devices of the existing recipients.
+    pub new_devices: BTreeMap<OwnedUserId, Vec<DeviceData>>,
+}
@@ -19,7 +19,7 @@ use std::{
 };
 
 use itertools::{Either, Itertools};
-use ruma::{DeviceId, OwnedDeviceId, OwnedUserId, UserId};
+use ruma::{DeviceId, OwnedDeviceId, OwnedServerName, OwnedUserId, UserId};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, instrument, trace};
 
@@ -87,6 +87,130 @@ impl Default for CollectStrategy {
     }
 }
 
+/// A policy restricting which devices receive the room keys of a room.
+///
+/// The policy is evaluated on top of the [`CollectStrategy`]: devices which the
+/// strategy would share the room key with, but which the policy rejects,
+/// receive a withheld code instead. Policies can be combined using
+/// [`RoomKeySharingPolicy::All`] and [`RoomKeySharingPolicy::Any`].
+///
+/// A policy can be set for all the rooms through
+/// [`EncryptionSettings::sharing_policy`], or for a single room through
+/// [`RoomSettings::sharing_policy`](crate::store::RoomSettings::sharing_policy).
+///
+/// Policies based on when a device was last seen, e.g. only sharing with
+/// devices seen within the last N days, aren't supported: the last activity of
+/// the devices of other users isn't known to us.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RoomKeySharingPolicy {
+    /// Only share with devices that are cross-signed by their owner, if we
+    /// have verified the owner.
+    ///
+    /// Rejected devices receive a [`WithheldCode::Unverified`].
+    CrossSignedDevicesOfVerifiedUsers,
+
+    /// Only share with users whose homeserver is in the given list.
+    ///
+    /// Rejected devices receive a [`WithheldCode::Unauthorised`].
+    AllowedServers {
+        /// The servers whose users may receive the room key.
+        servers: Vec<OwnedServerName>,
+    },
+
+    /// Only share with devices which are accepted by all the given policies.
+    ///
+    /// Rejected devices receive the withheld code of the first policy that
+    /// rejected them.
+    All {
+        /// The policies that all need to accept a device.
+        policies: Vec<RoomKeySharingPolicy>,
+    },
+
+    /// Only share with devices which are accepted by at least one of the given
+    /// policies.
+    ///
+    /// Rejected devices receive the withheld code of the first policy that
+    /// rejected them, or [`WithheldCode::Unauthorised`] if there are no
+    /// policies.
+    Any {
+        /// The policies out of which at least one needs to accept a device.
+        policies: Vec<RoomKeySharingPolicy>,
+    },
+}
+
+impl RoomKeySharingPolicy {
+    /// Check if the given device should receive the room key.
+    ///
+    /// Returns the withheld code that should be sent to the device if it
+    /// shouldn't.
+    fn check(
+        &self,
+        device: &DeviceData,
+        own_identity: Option<&OwnUserIdentityData>,
+        device_owner_identity: Option<&UserIdentityData>,
+    ) -> Result<(), WithheldCode> {
+        match self {
+            Self::CrossSignedDevicesOfVerifiedUsers => {
+                let allowed = device_owner_identity.is_some_and(|identity| {
+                    is_user_verified(own_identity, identity)
+                        && device.is_cross_signed_by_owner(identity)
+                });
+
+                if allowed {
+                    Ok(())
+                } else {
+                    Err(WithheldCode::Unverified)
+                }
+            }
+            Self::AllowedServers { servers } => {
+                if servers.iter().any(|server| server == device.user_id().server_name()) {
+                    Ok(())
+                } else {
+                    Err(WithheldCode::Unauthorised)
+                }
+            }
+            Self::All { policies } => policies
+                .iter()
+                .try_for_each(|policy| policy.check(device, own_identity, device_owner_identity)),
+            Self::Any { policies } => {
+                let mut first_code = None;
+
+                for policy in policies {
+                    match policy.check(device, own_identity, device_owner_identity) {
+                        Ok(()) => return Ok(()),
+                        Err(code) => {
+                            first_code.get_or_insert(code);
+                        }
+                    }
+                }
+
+                Err(first_code.unwrap_or(WithheldCode::Unauthorised))
+            }
+        }
+    }
+}
+
+/// Split the devices which the [`CollectStrategy`] selected into the ones which
+/// are accepted by the given sharing policy and the ones which aren't.
+fn apply_sharing_policy(
+    policy: Option<&RoomKeySharingPolicy>,
+    devices: Vec<DeviceData>,
+    own_identity: Option<&OwnUserIdentityData>,
+    device_owner_identity: Option<&UserIdentityData>,
+) -> (Vec<DeviceData>, Vec<(DeviceData, WithheldCode)>) {
+    let Some(policy) = policy else {
+        return (devices, Vec::new());
+    };
+
+    devices.into_iter().partition_map(|device| {
+        match policy.check(&device, own_identity, device_owner_identity) {
+            Ok(()) => Either::Left(device),
+            Err(code) => Either::Right((device, code)),
+        }
+    })
+}
+
 /// Returned by `collect_session_recipients`.
 ///
 /// Information indicating whether the session needs to be rotated
@@ -118,9 +242,6 @@ pub(crate) async fn collect_session_recipients(
     outbound: &OutboundGroupSession,
 ) -> OlmResult<CollectRecipientsResult> {
     let users: BTreeSet<&UserId> = users.collect();
-    let mut devices: BTreeMap<OwnedUserId, Vec<DeviceData>> = Default::default();
-    let mut withheld_devices: Vec<(DeviceData, WithheldCode)> = Default::default();
-    let mut verified_users_with_new_identities: Vec<OwnedUserId> = Default::default();
 
     trace!(?users, ?settings, "Calculating group session recipients");
 
@@ -146,10 +267,76 @@ pub(crate) async fn collect_session_recipients(
     // This is calculated in the following code and stored in this variable.
     let mut should_rotate = user_left || visibility_changed || algorithm_changed;
 
+    // A policy set in the encryption settings takes precedence over the one set
+    // in the room settings.
+    let sharing_policy = match &settings.sharing_policy {
+        Some(policy) => Some(policy.clone()),
+        None => store.get_room_settings(outbound.room_id()).await?.and_then(|s| s.sharing_policy),
+    };
+
+    let RecipientDevices { devices, withheld_devices } = collect_recipient_devices(
+        store,
+        users.iter().copied(),
+        &settings.sharing_strategy,
+        sharing_policy.as_ref(),
+    )
+    .await?;
+
+    // If we haven't already concluded that the session should be rotated for
+    // other reasons, we also need to check whether any of the devices in the
+    // session got deleted or blacklisted in the meantime. If so, we should also
+    // rotate the session.
+    if !should_rotate {
+        should_rotate = devices.iter().any(|(user_id, allowed_devices)| {
+            is_session_overshared_for_user(outbound, user_id, allowed_devices)
+        });
+    }
+
+    if should_rotate {
+        debug!(
+            should_rotate,
+            user_left,
+            visibility_changed,
+            algorithm_changed,
+            "Rotating room key to protect room history",
+        );
+    }
+    trace!(should_rotate, "Done calculating group session recipients");
+
+    Ok(CollectRecipientsResult { should_rotate, devices, withheld_devices })
+}
+
+/// The devices of a set of users, split between the ones which should receive
+/// a secret and the ones which shouldn't.
+#[derive(Debug, Default)]
+pub(crate) struct RecipientDevices {
+    /// The map of user|device that should receive the secret.
+    pub devices: BTreeMap<OwnedUserId, Vec<DeviceData>>,
+    /// The devices that won't receive the secret, with the withheld code.
+    pub withheld_devices: Vec<(DeviceData, WithheldCode)>,
+}
+
+/// Split the devices of the given users between the ones which should receive
+/// a secret, like a room key, and the ones which shouldn't, according to the
+/// given collection strategy and sharing policy.
+///
+/// Every user that was considered has an entry in
+/// [`RecipientDevices::devices`], even if none of their devices is allowed.
+#[instrument(skip_all)]
+pub(crate) async fn collect_recipient_devices(
+    store: &Store,
+    users: impl Iterator<Item = &UserId>,
+    strategy: &CollectStrategy,
+    sharing_policy: Option<&RoomKeySharingPolicy>,
+) -> OlmResult<RecipientDevices> {
+    let mut devices: BTreeMap<OwnedUserId, Vec<DeviceData>> = Default::default();
+    let mut withheld_devices: Vec<(DeviceData, WithheldCode)> = Default::default();
+    let mut verified_users_with_new_identities: Vec<OwnedUserId> = Default::default();
+
     let own_identity = store.get_user_identity(store.user_id()).await?.and_then(|i| i.into_own());
 
     // Get the recipient and withheld devices, based on the collection strategy.
-    match settings.sharing_strategy {
+    match *strategy {
         CollectStrategy::DeviceBasedStrategy {
             only_allow_trusted_devices,
             error_on_verified_user_problem,
@@ -162,13 +349,15 @@ pub(crate) async fn collect_session_recipients(
                 let user_devices = store.get_device_data_for_user_filtered(user_id).await?;
 
                 // We only need the user identity if `only_allow_trusted_devices` or
-                // `error_on_verified_user_problem` is set.
-                let device_owner_identity =
-                    if only_allow_trusted_devices || error_on_verified_user_problem {
-                        store.get_user_identity(user_id).await?
-                    } else {
-                        None
-                    };
+                // `error_on_verified_user_problem` is set, or if there's a sharing policy.
+                let device_owner_identity = if only_allow_trusted_devices
+                    || error_on_verified_user_problem
+                    || sharing_policy.is_some()
+                {
+                    store.get_user_identity(user_id).await?
+                } else {
+                    None
+                };
 
                 if error_on_verified_user_problem
                     && has_identity_verification_violation(
@@ -203,23 +392,16 @@ pub(crate) async fn collect_session_recipients(
                     );
                 }
 
-                // If we haven't already concluded that the session should be
-                // rotated for other reasons, we also need to check whether any
-                // of the devices in the session got deleted or blacklisted in the
-                // meantime. If so, we should also rotate the session.
-                if !should_rotate {
-                    should_rotate = is_session_overshared_for_user(
-                        outbound,
-                        user_id,
-                        &recipient_devices.allowed_devices,
-                    )
-                }
+                let (allowed_devices, denied_by_policy) = apply_sharing_policy(
+                    sharing_policy,
+                    recipient_devices.allowed_devices,
+                    own_identity.as_ref(),
+                    device_owner_identity.as_ref(),
+                );
 
-                devices
-                    .entry(user_id.to_owned())
-                    .or_default()
-                    .extend(recipient_devices.allowed_devices);
+                devices.entry(user_id.to_owned()).or_default().extend(allowed_devices);
                 withheld_devices.extend(recipient_devices.denied_devices_with_code);
+                withheld_devices.extend(denied_by_policy);
             }
 
             // If `error_on_verified_user_problem` is set, then
@@ -270,23 +452,16 @@ pub(crate) async fn collect_session_recipients(
                     &device_owner_identity,
                 );
 
-                // If we haven't already concluded that the session should be
-                // rotated for other reasons, we also need to check whether any
-                // of the devices in the session got deleted or blacklisted in the
-                // meantime. If so, we should also rotate the session.
-                if !should_rotate {
-                    should_rotate = is_session_overshared_for_user(
-                        outbound,
-                        user_id,
-                        &recipient_devices.allowed_devices,
-                    )
-                }
+                let (allowed_devices, denied_by_policy) = apply_sharing_policy(
+                    sharing_policy,
+                    recipient_devices.allowed_devices,
+                    own_identity.as_ref(),
+                    device_owner_identity.as_ref(),
+                );
 
-                devices
-                    .entry(user_id.to_owned())
-                    .or_default()
-                    .extend(recipient_devices.allowed_devices);
+                devices.entry(user_id.to_owned()).or_default().extend(allowed_devices);
                 withheld_devices.extend(recipient_devices.denied_devices_with_code);
+                withheld_devices.extend(denied_by_policy);
             }
         }
     }
@@ -301,18 +476,7 @@ pub(crate) async fn collect_session_recipients(
         ));
     }
 
-    if should_rotate {
-        debug!(
-            should_rotate,
-            user_left,
-            visibility_changed,
-            algorithm_changed,
-            "Rotating room key to protect room history",
-        );
-    }
-    trace!(should_rotate, "Done calculating group session recipients");
-
-    Ok(CollectRecipientsResult { should_rotate, devices, withheld_devices })
+    Ok(RecipientDevices { devices, withheld_devices })
 }
 
 /// Check if the session has been shared with a device belonging to the given
@@ -525,7 +689,8 @@ mod tests {
         },
     };
     use ruma::{
-        device_id, events::room::history_visibility::HistoryVisibility, room_id, TransactionId,
+        device_id, events::room::history_visibility::HistoryVisibility, owned_server_name, room_id,
+        TransactionId,
     };
     use serde_json::json;
 
@@ -533,8 +698,10 @@ mod tests {
         error::SessionRecipientCollectionError,
         olm::OutboundGroupSession,
         session_manager::{
-            group_sessions::share_strategy::collect_session_recipients, CollectStrategy,
+            group_sessions::{share_strategy::collect_session_recipients, CollectRecipientsResult},
+            CollectStrategy, RoomKeySharingPolicy,
         },
+        store::RoomSettings,
         testing::simulate_key_query_response_for_verification,
         types::events::room_key_withheld::WithheldCode,
         CrossSigningKeyExport, EncryptionSettings, LocalTrust, OlmError, OlmMachine,
@@ -622,6 +789,154 @@ mod tests {
         assert_eq!(good_devices_shared.len(), 2);
     }
 
+    /// Collect the recipients of a room key for Dan, Dave and Good, using the
+    /// given settings.
+    async fn collect_recipients_with_settings(
+        machine: &OlmMachine,
+        encryption_settings: &EncryptionSettings,
+    ) -> CollectRecipientsResult {
+        let group_session = create_test_outbound_group_session(machine, encryption_settings);
+
+        collect_session_recipients(
+            machine.store(),
+            vec![
+                KeyDistributionTestData::dan_id(),
+                KeyDistributionTestData::dave_id(),
+                KeyDistributionTestData::good_id(),
+            ]
+            .into_iter(),
+            encryption_settings,
+            &group_session,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[async_test]
+    async fn test_sharing_policy_allowed_servers() {
+        let machine = set_up_test_machine().await;
+
+        let encryption_settings = EncryptionSettings {
+            sharing_policy: Some(RoomKeySharingPolicy::AllowedServers {
+                servers: vec![owned_server_name!("localhost")],
+            }),
+            ..Default::default()
+        };
+        let share_result = collect_recipients_with_settings(&machine, &encryption_settings).await;
+
+        assert_eq!(share_result.devices.values().flatten().count(), 5);
+        assert!(share_result.withheld_devices.is_empty());
+
+        let encryption_settings = EncryptionSettings {
+            sharing_policy: Some(RoomKeySharingPolicy::AllowedServers {
+                servers: vec![owned_server_name!("example.org")],
+            }),
+            ..Default::default()
+        };
+        let share_result = collect_recipients_with_settings(&machine, &encryption_settings).await;
+
+        assert_eq!(share_result.devices.values().flatten().count(), 0);
+        assert_eq!(share_result.withheld_devices.len(), 5);
+        assert!(share_result
+            .withheld_devices
+            .iter()
+            .all(|(_, code)| *code == WithheldCode::Unauthorised));
+    }
+
+    #[async_test]
+    async fn test_sharing_policy_composition() {
+        let machine = set_up_test_machine().await;
+
+        let localhost =
+            RoomKeySharingPolicy::AllowedServers { servers: vec![owned_server_name!("localhost")] };
+        let example_org = RoomKeySharingPolicy::AllowedServers {
+            servers: vec![owned_server_name!("example.org")],
+        };
+
+        let encryption_settings = EncryptionSettings {
+            sharing_policy: Some(RoomKeySharingPolicy::Any {
+                policies: vec![example_org.clone(), localhost.clone()],
+            }),
+            ..Default::default()
+        };
+        let share_result = collect_recipients_with_settings(&machine, &encryption_settings).await;
+        assert_eq!(share_result.devices.values().flatten().count(), 5);
+
+        let encryption_settings = EncryptionSettings {
+            sharing_policy: Some(RoomKeySharingPolicy::All {
+                policies: vec![localhost, example_org],
+            }),
+            ..Default::default()
+        };
+        let share_result = collect_recipients_with_settings(&machine, &encryption_settings).await;
+        assert_eq!(share_result.withheld_devices.len(), 5);
+
+        // Devices which aren't cross-signed by a verified user are withheld as
+        // unverified.
+        let encryption_settings = EncryptionSettings {
+            sharing_policy: Some(RoomKeySharingPolicy::CrossSignedDevicesOfVerifiedUsers),
+            ..Default::default()
+        };
+        let share_result = collect_recipients_with_settings(&machine, &encryption_settings).await;
+        assert!(share_result
+            .withheld_devices
+            .iter()
+            .all(|(_, code)| *code == WithheldCode::Unverified));
+        assert!(!share_result.withheld_devices.is_empty());
+    }
+
+    #[async_test]
+    async fn test_sharing_policy_from_room_settings() {
+        let machine = set_up_test_machine().await;
+        let room_id = room_id!("!roomid:localhost");
+
+        machine
+            .set_room_settings(
+                room_id,
+                &RoomSettings {
+                    sharing_policy: Some(RoomKeySharingPolicy::AllowedServers {
+                        servers: vec![owned_server_name!("example.org")],
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        let share_result =
+            collect_recipients_with_settings(&machine, &EncryptionSettings::default()).await;
+
+        assert_eq!(share_result.devices.values().flatten().count(), 0);
+        assert_eq!(share_result.withheld_devices.len(), 5);
+    }
+
+    #[test]
+    fn test_sharing_policy_serialization() {
+        let policy = RoomKeySharingPolicy::All {
+            policies: vec![
+                RoomKeySharingPolicy::CrossSignedDevicesOfVerifiedUsers,
+                RoomKeySharingPolicy::AllowedServers {
+                    servers: vec![owned_server_name!("example.org")],
+                },
+            ],
+        };
+
+        let json = serde_json::to_value(&policy).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "type": "all",
+                "policies": [
+                    { "type": "cross_signed_devices_of_verified_users" },
+                    { "type": "allowed_servers", "servers": ["example.org"] },
+                ],
+            })
+        );
+
+        let deserialized: RoomKeySharingPolicy = serde_json::from_value(json).unwrap();
+        assert_eq!(deserialized, policy);
+    }
+
     #[async_test]
     async fn test_share_with_per_device_strategy_only_trusted() {
         test_share_only_trusted_helper(false).await;
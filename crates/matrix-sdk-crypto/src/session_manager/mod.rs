@@ -15,6 +15,10 @@
 mod group_sessions;
 mod sessions;
 
-pub use group_sessions::CollectStrategy;
-pub(crate) use group_sessions::{GroupSessionCache, GroupSessionManager};
+pub(crate) use group_sessions::{
+    collect_recipient_devices, GroupSessionCache, GroupSessionManager, RecipientDevices,
+};
+pub use group_sessions::{
+    CollectStrategy, RoomKeyRecipientChanges, RoomKeyRotationPolicy, RoomKeySharingPolicy,
+};
 pub(crate) use sessions::SessionManager;
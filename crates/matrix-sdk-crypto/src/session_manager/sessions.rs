@@ -34,6 +34,7 @@ use vodozemac::Curve25519PublicKey;
 use crate::{
     error::OlmResult,
     gossiping::GossipMachine,
+    metrics::CryptoCounter,
     requests::{OutgoingRequest, ToDeviceRequest},
     store::{Changes, Result as StoreResult, Store},
     types::{events::EventType, EventEncryptionAlgorithm},
@@ -155,6 +156,8 @@ impl SessionManager {
             .get_mut(user_id)
             .is_some_and(|d| d.remove(device_id))
         {
+            self.store.metrics().increment(CryptoCounter::OlmSessionUnwedged);
+
             if let Some(device) = self.store.get_device(user_id, device_id).await? {
                 let (_, content) =
                     device.encrypt("m.dummy", ToDeviceDummyEventContent::new()).await?;
@@ -549,6 +552,7 @@ impl SessionManager {
                 };
 
                 self.key_request_machine.retry_keyshare(user_id, device_id);
+                self.store.metrics().increment(CryptoCounter::OlmSessionCreated);
 
                 if let Err(e) = self.check_if_unwedged(user_id, device_id).await {
                     error!(?user_id, ?device_id, "Error while treating an unwedged device: {e:?}");
@@ -130,6 +130,21 @@ impl GroupSessionStore {
     pub fn get(&self, room_id: &RoomId, session_id: &str) -> Option<InboundGroupSession> {
         self.entries.read().unwrap().get(room_id)?.get(session_id).cloned()
     }
+
+    /// Remove an inbound group session from the store.
+    ///
+    /// Returns the removed session, if it was in the store.
+    pub fn remove(&self, room_id: &RoomId, session_id: &str) -> Option<InboundGroupSession> {
+        let mut entries = self.entries.write().unwrap();
+        let room_sessions = entries.get_mut(room_id)?;
+        let session = room_sessions.remove(session_id);
+
+        if room_sessions.is_empty() {
+            entries.remove(room_id);
+        }
+
+        session
+    }
 }
 
 /// In-memory store holding the devices of users.
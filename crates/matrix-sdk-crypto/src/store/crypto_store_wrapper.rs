@@ -12,7 +12,7 @@ use super::{caches::SessionStore, DeviceChanges, IdentityChanges, LockableCrypto
 use crate::{
     olm::InboundGroupSession,
     store,
-    store::{Changes, DynCryptoStore, IntoCryptoStore, RoomKeyInfo, RoomKeyWithheldInfo},
+    store::{pruning, Changes, DynCryptoStore, IntoCryptoStore, RoomKeyInfo, RoomKeyWithheldInfo},
     CryptoStoreError, GossippedSecret, OwnUserIdentityData, Session, UserIdentityData,
 };
 
@@ -38,6 +38,12 @@ pub(crate) struct CryptoStoreWrapper {
     /// receive an `m.room_key.withheld` message.
     room_keys_withheld_received_sender: broadcast::Sender<Vec<RoomKeyWithheldInfo>>,
 
+    /// The sender side of a broadcast stream that is notified whenever the
+    /// [`SenderData`] of existing inbound group sessions gets upgraded.
+    ///
+    /// [`SenderData`]: crate::olm::SenderData
+    sender_data_updates_sender: broadcast::Sender<Vec<RoomKeyInfo>>,
+
     /// The sender side of a broadcast channel which sends out secrets we
     /// received as a `m.secret.send` event.
     secrets_broadcaster: broadcast::Sender<GossippedSecret>,
@@ -52,6 +58,7 @@ impl CryptoStoreWrapper {
     pub(crate) fn new(user_id: &UserId, device_id: &DeviceId, store: impl IntoCryptoStore) -> Self {
         let room_keys_received_sender = broadcast::Sender::new(10);
         let room_keys_withheld_received_sender = broadcast::Sender::new(10);
+        let sender_data_updates_sender = broadcast::Sender::new(10);
         let secrets_broadcaster = broadcast::Sender::new(10);
         // The identities broadcaster is responsible for user identities as well as
         // devices, that's why we increase the capacity here.
@@ -64,6 +71,7 @@ impl CryptoStoreWrapper {
             sessions: SessionStore::new(),
             room_keys_received_sender,
             room_keys_withheld_received_sender,
+            sender_data_updates_sender,
             secrets_broadcaster,
             identities_broadcaster,
         }
@@ -126,6 +134,12 @@ impl CryptoStoreWrapper {
 
         self.store.save_changes(changes).await?;
 
+        // The stores forget about the deleted devices, remember their keys so their
+        // Olm sessions can be pruned.
+        if !devices.deleted.is_empty() {
+            pruning::record_deleted_devices(&self.store, &devices.deleted).await?;
+        }
+
         // If we updated our own public identity, log it for debugging purposes
         if tracing::level_enabled!(tracing::Level::DEBUG) {
             for updated_identity in
@@ -261,6 +275,21 @@ impl CryptoStoreWrapper {
         Ok(sessions)
     }
 
+    /// Drop the given Olm sessions from the session cache, once they were
+    /// removed from the store.
+    ///
+    /// # Arguments
+    ///
+    /// * `sessions` - The sessions, as pairs of the sender key they belong to
+    ///   and their session ID.
+    pub(crate) async fn forget_sessions(&self, sessions: &[(String, String)]) {
+        for (sender_key, session_id) in sessions {
+            if let Some(cached) = self.sessions.get(sender_key).await {
+                cached.lock().await.retain(|s| s.session_id() != session_id);
+            }
+        }
+    }
+
     /// Save a list of inbound group sessions to the store.
     ///
     /// # Arguments
@@ -314,6 +343,29 @@ impl CryptoStoreWrapper {
         Self::filter_errors_out_of_stream(stream, "room_keys_withheld_received_stream")
     }
 
+    /// Notify the listeners of [`Self::sender_data_updates_stream`] that the
+    /// [`SenderData`] of the given inbound group sessions got upgraded.
+    ///
+    /// [`SenderData`]: crate::olm::SenderData
+    pub fn notify_sender_data_updates(&self, updates: Vec<RoomKeyInfo>) {
+        if !updates.is_empty() {
+            // Ignore the result. It can only fail if there are no listeners.
+            let _ = self.sender_data_updates_sender.send(updates);
+        }
+    }
+
+    /// Receive notifications of inbound group sessions whose [`SenderData`]
+    /// got upgraded, as a [`Stream`].
+    ///
+    /// If the reader of the stream lags too far behind, a warning will be
+    /// logged and items will be dropped.
+    ///
+    /// [`SenderData`]: crate::olm::SenderData
+    pub fn sender_data_updates_stream(&self) -> impl Stream<Item = Vec<RoomKeyInfo>> {
+        let stream = BroadcastStream::new(self.sender_data_updates_sender.subscribe());
+        Self::filter_errors_out_of_stream(stream, "sender_data_updates_stream")
+    }
+
     /// Receive notifications of gossipped secrets being received and stored in
     /// the secret inbox as a [`Stream`].
     pub fn secrets_stream(&self) -> impl Stream<Item = GossippedSecret> {
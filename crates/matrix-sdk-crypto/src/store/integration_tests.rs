@@ -51,8 +51,8 @@ macro_rules! cryptostore_integration_tests {
                     PrivateCrossSigningIdentity, SenderData, SenderDataType, Session
                 },
                 store::{
-                    BackupDecryptionKey, Changes, CryptoStore, DeviceChanges, GossipRequest,
-                    IdentityChanges, PendingChanges, RoomSettings,
+                    pruning::SessionsToRemove, BackupDecryptionKey, Changes, CryptoStore,
+                    DeviceChanges, GossipRequest, IdentityChanges, PendingChanges, RoomSettings,
                 },
                 testing::{get_device, get_other_identity, get_own_identity},
                 types::{
@@ -238,6 +238,85 @@ macro_rules! cryptostore_integration_tests {
                 assert_eq!(&session, &loaded_session, "The loaded session should be the same one we put into the store.");
             }
 
+            #[async_test]
+            async fn test_scan_undecodable_entries_of_a_healthy_store() {
+                let store = get_store("scan_undecodable_entries", None, true).await;
+                let (account, session) = get_account_and_session().await;
+                store
+                    .save_pending_changes(PendingChanges { account: Some(account.deep_clone()) })
+                    .await
+                    .expect("Can't save account");
+
+                let room_id = room_id!("!test:localhost");
+                let (outbound, inbound) = account.create_group_session_pair_with_defaults(room_id).await;
+
+                let changes = Changes {
+                    sessions: vec![session],
+                    inbound_group_sessions: vec![inbound],
+                    outbound_group_sessions: vec![outbound],
+                    devices: DeviceChanges { new: vec![DeviceData::from_account(&account)], ..Default::default() },
+                    identities: IdentityChanges {
+                        new: vec![get_own_identity().into()],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+
+                store.save_changes(changes).await.unwrap();
+
+                assert!(store.scan_undecodable_entries(false).await.unwrap().is_empty());
+                assert!(store.scan_undecodable_entries(true).await.unwrap().is_empty());
+
+                // Nothing got removed.
+                assert_eq!(store.get_inbound_group_sessions().await.unwrap().len(), 1);
+                assert!(store.get_outbound_group_session(room_id).await.unwrap().is_some());
+                assert!(store.get_user_identity(get_own_identity().user_id()).await.unwrap().is_some());
+            }
+
+            #[async_test]
+            async fn test_remove_sessions() {
+                let store = get_store("remove_sessions", None, true).await;
+                let (account, session) = get_account_and_session().await;
+                store
+                    .save_pending_changes(PendingChanges { account: Some(account.deep_clone()) })
+                    .await
+                    .expect("Can't save account");
+
+                let sender_key = session.sender_key.to_base64();
+                let session_id = session.session_id().to_owned();
+
+                let room_id = room_id!("!test:localhost");
+                let (_, inbound) = account.create_group_session_pair_with_defaults(room_id).await;
+                let inbound_session_id = inbound.session_id().to_owned();
+
+                let changes = Changes {
+                    sessions: vec![session],
+                    inbound_group_sessions: vec![inbound],
+                    devices: DeviceChanges { new: vec![DeviceData::from_account(&account)], ..Default::default() },
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.unwrap();
+
+                assert_eq!(store.get_all_sessions().await.unwrap().len(), 1);
+
+                let to_remove = SessionsToRemove {
+                    olm_sessions: vec![(sender_key.clone(), session_id)],
+                    inbound_group_sessions: vec![(room_id.to_owned(), inbound_session_id.clone())],
+                };
+                assert!(store.remove_sessions(to_remove.clone()).await.unwrap() > 0);
+
+                assert!(store.get_all_sessions().await.unwrap().is_empty());
+                assert!(store.get_sessions(&sender_key).await.unwrap().is_none());
+                assert!(store
+                    .get_inbound_group_session(room_id, &inbound_session_id)
+                    .await
+                    .unwrap()
+                    .is_none());
+
+                // Removing sessions which are already gone doesn't reclaim anything.
+                assert_eq!(store.remove_sessions(to_remove).await.unwrap(), 0);
+            }
+
             #[async_test]
             async fn test_add_and_save_session() {
                 let store_name = "add_and_save_session";
@@ -665,6 +744,73 @@ macro_rules! cryptostore_integration_tests {
                 );
             }
 
+            #[async_test]
+            async fn test_fetch_inbound_group_sessions_in_batches() {
+                // Given a store exists, containing inbound group sessions for different rooms
+                let (account, store) =
+                    get_loaded_store("fetch_inbound_group_sessions_in_batches").await;
+
+                let room_1 = room_id!("!room1:localhost");
+                let room_2 = room_id!("!room2:localhost");
+
+                let mut sessions = Vec::new();
+                let mut outbound_sessions = Vec::new();
+
+                for room_id in [room_1, room_1, room_2, room_2, room_2] {
+                    let (outbound, inbound) = account.create_group_session_pair_with_defaults(room_id).await;
+                    sessions.push(inbound);
+                    outbound_sessions.push(outbound);
+                }
+
+                // Only the last outbound group session of each room is kept.
+                let changes = Changes {
+                    inbound_group_sessions: sessions.clone(),
+                    outbound_group_sessions: vec![outbound_sessions[1].clone(), outbound_sessions[4].clone()],
+                    ..Default::default()
+                };
+                store.save_changes(changes).await.expect("Can't save group sessions");
+
+                // When we fetch all the sessions in batches
+                let mut fetched = Vec::new();
+                let mut after_session = None;
+                loop {
+                    let mut batch = store
+                        .get_inbound_group_sessions_batch(after_session, 2)
+                        .await
+                        .expect("Failed to get a batch of sessions");
+
+                    // If there are no results in the batch, we have reached the end of the results.
+                    let Some(last_session) = batch.last() else {
+                        break;
+                    };
+
+                    assert!(batch.len() <= 2);
+                    after_session = Some((last_session.room_id().to_owned(), last_session.session_id().to_owned()));
+                    fetched.append(&mut batch);
+                }
+
+                // Then all of them are returned, exactly once
+                assert_session_lists_eq(fetched, sessions, "batched results");
+
+                // And the outbound group sessions of both rooms can be listed
+                let mut stored_outbound_sessions: Vec<_> = store
+                    .get_outbound_group_sessions()
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|session| (session.room_id().to_owned(), session.session_id().to_owned()))
+                    .collect();
+                stored_outbound_sessions.sort();
+
+                assert_eq!(
+                    stored_outbound_sessions,
+                    vec![
+                        (room_1.to_owned(), outbound_sessions[1].session_id().to_owned()),
+                        (room_2.to_owned(), outbound_sessions[4].session_id().to_owned()),
+                    ]
+                );
+            }
+
             /// Assert that two lists of sessions are the same, modulo ordering.
             ///
             /// There is no requirement for `get_inbound_group_sessions_for_device_batch` to
@@ -1163,6 +1309,7 @@ macro_rules! cryptostore_integration_tests {
                     only_allow_trusted_devices: true,
                     session_rotation_period: Some(Duration::from_secs(10)),
                     session_rotation_period_messages: Some(123),
+                    sharing_policy: None,
                 };
 
                 let room_2 = room_id!("!test_2:localhost");
@@ -1250,6 +1397,7 @@ macro_rules! cryptostore_integration_tests {
                     SenderDataType::VerificationViolation => panic!("VerificationViolation not supported"),
                     SenderDataType::SenderUnverified=> panic!("SenderUnverified not supported"),
                     SenderDataType::SenderVerified => panic!("SenderVerified not supported"),
+                    SenderDataType::SharedHistory => panic!("SharedHistory not supported"),
                 };
 
                 let session_key = GroupSession::new(SessionConfig::default()).session_key();
@@ -0,0 +1,350 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Integrity checks for the contents of a [`CryptoStore`].
+//!
+//! A crypto store can end up in a state the [`OlmMachine`] can't recover
+//! from on its own, for example if some of its entries can't be decrypted
+//! anymore. The checks of this module, run with
+//! [`OlmMachine::check_store_integrity()`], look for:
+//!
+//! * Olm sessions, group sessions, devices and user identities which can't be
+//!   decrypted or deserialized anymore,
+//! * outbound group sessions whose matching inbound group session is missing,
+//!   which means that our other devices can't decrypt the messages we send with
+//!   them,
+//! * Olm sessions with devices we don't know about,
+//! * inbound group sessions sent by devices we don't know about,
+//! * inbound group sessions whose [`SenderData`] would be more trusted if it
+//!   was computed again,
+//! * inbound group sessions marked as backed up while no backup is enabled.
+//!
+//! The checks produce a [`StoreIntegrityReport`], and can optionally repair
+//! the problems they found.
+//!
+//! [`CryptoStore`]: super::CryptoStore
+//! [`OlmMachine`]: crate::OlmMachine
+//! [`OlmMachine::check_store_integrity()`]: crate::OlmMachine::check_store_integrity
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+};
+
+use ruma::{OwnedRoomId, OwnedUserId};
+use tracing::{debug, info, instrument, warn};
+
+use super::{BackupKeys, DynCryptoStore, InboundGroupSessionBatches, Result, Store};
+use crate::{
+    olm::{
+        sender_data_finder::SessionDeviceCheckError, InboundGroupSession, SenderData,
+        SenderDataFinder,
+    },
+    DeviceData,
+};
+
+/// The kind of data an [`UndecodableEntry`] was supposed to hold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum StoreEntryKind {
+    /// An Olm session.
+    OlmSession,
+    /// An inbound group session.
+    InboundGroupSession,
+    /// An outbound group session.
+    OutboundGroupSession,
+    /// The keys of a device.
+    Device,
+    /// The identity of a user.
+    UserIdentity,
+}
+
+/// An entry of a [`CryptoStore`] which can't be decrypted or deserialized
+/// anymore.
+///
+/// [`CryptoStore`]: super::CryptoStore
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UndecodableEntry {
+    /// The kind of data the entry was supposed to hold.
+    pub kind: StoreEntryKind,
+    /// A description of the error we got while decoding the entry.
+    pub error: String,
+}
+
+impl UndecodableEntry {
+    /// Create a new [`UndecodableEntry`] of the given kind, which failed to
+    /// decode with the given error.
+    pub fn new(kind: StoreEntryKind, error: impl fmt::Display) -> Self {
+        Self { kind, error: error.to_string() }
+    }
+}
+
+/// The result of [`OlmMachine::check_store_integrity()`].
+///
+/// [`OlmMachine::check_store_integrity()`]: crate::OlmMachine::check_store_integrity
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreIntegrityReport {
+    /// The entries which can't be decrypted or deserialized anymore.
+    ///
+    /// Repairing removes them from the store.
+    pub undecodable_entries: Vec<UndecodableEntry>,
+
+    /// Were the inbound group sessions checked?
+    ///
+    /// This is `false` if some of the inbound group sessions can't be decoded
+    /// and weren't removed, in which case the checks relying on them were
+    /// skipped.
+    pub inbound_group_sessions_checked: bool,
+
+    /// The rooms whose outbound group session doesn't have a matching inbound
+    /// group session.
+    ///
+    /// Repairing invalidates those sessions, so a new room key gets created
+    /// the next time a message is sent in those rooms.
+    pub orphaned_outbound_group_sessions: Vec<OwnedRoomId>,
+
+    /// The number of Olm sessions whose sender key doesn't belong to any
+    /// device in the store.
+    ///
+    /// Those aren't repaired, since the device might show up later on; the
+    /// Olm sessions of deleted devices can be removed with
+    /// [`OlmMachine::prune_sessions()`].
+    ///
+    /// [`OlmMachine::prune_sessions()`]: crate::OlmMachine::prune_sessions
+    pub olm_sessions_from_unknown_devices: usize,
+
+    /// The number of inbound group sessions sent by a device that isn't in
+    /// the store.
+    ///
+    /// Those can't be repaired, their [`SenderData`] gets updated if the
+    /// device shows up later on.
+    pub inbound_group_sessions_from_unknown_devices: usize,
+
+    /// The number of inbound group sessions whose [`SenderData`] would be
+    /// more trusted if it was computed again, using the devices and
+    /// identities we know about now.
+    ///
+    /// Repairing updates their [`SenderData`].
+    pub outdated_sender_data: usize,
+
+    /// The number of inbound group sessions marked as backed up while no
+    /// backup is enabled.
+    ///
+    /// Repairing resets the backup state of those sessions, so they get
+    /// uploaded once a backup is enabled again.
+    pub inconsistent_backup_flags: usize,
+
+    /// Were the problems that can be repaired repaired?
+    pub repaired: bool,
+}
+
+impl StoreIntegrityReport {
+    /// Did the checks find any problem?
+    ///
+    /// Olm sessions and inbound group sessions from unknown devices aren't
+    /// considered to be a problem, since it's common for them to exist.
+    pub fn has_problems(&self) -> bool {
+        !self.undecodable_entries.is_empty()
+            || !self.inbound_group_sessions_checked
+            || !self.orphaned_outbound_group_sessions.is_empty()
+            || self.outdated_sender_data > 0
+            || self.inconsistent_backup_flags > 0
+    }
+}
+
+/// Run the integrity checks over the given store, see the [module
+/// documentation](self).
+///
+/// Invalidating the orphaned outbound group sessions is left to the caller,
+/// since the sessions might be cached by the `GroupSessionManager`.
+#[instrument(skip(store))]
+pub(crate) async fn check_store_integrity(
+    store: &Store,
+    repair: bool,
+) -> Result<StoreIntegrityReport> {
+    // Some of the methods of the `Store` shadow the ones of the `CryptoStore`
+    // trait, make sure we're using the latter.
+    let crypto_store: &DynCryptoStore = store;
+
+    let mut report = StoreIntegrityReport {
+        undecodable_entries: crypto_store.scan_undecodable_entries(repair).await?,
+        repaired: repair,
+        ..Default::default()
+    };
+
+    let has_undecodable_inbound_group_sessions = report
+        .undecodable_entries
+        .iter()
+        .any(|entry| entry.kind == StoreEntryKind::InboundGroupSession);
+
+    let BackupKeys { backup_version, .. } = crypto_store.load_backup_keys().await?;
+
+    // The store can't be queried for a device by its Curve25519 key alone, so
+    // collect the devices of all the users we know about.
+    let users: BTreeSet<OwnedUserId> = crypto_store
+        .load_tracked_users()
+        .await?
+        .into_iter()
+        .map(|user| user.user_id)
+        .chain(std::iter::once(store.user_id().to_owned()))
+        .collect();
+
+    let mut devices_by_curve_key = HashMap::new();
+
+    for user_id in &users {
+        for device in crypto_store.get_user_devices(user_id).await?.into_values() {
+            if let Some(curve_key) = device.curve25519_key() {
+                devices_by_curve_key.insert(curve_key.to_base64(), device);
+            }
+        }
+    }
+
+    let has_undecodable_olm_sessions =
+        report.undecodable_entries.iter().any(|entry| entry.kind == StoreEntryKind::OlmSession);
+
+    match crypto_store.get_all_sessions().await {
+        Ok(sessions) => {
+            report.olm_sessions_from_unknown_devices = sessions
+                .iter()
+                .filter(|session| {
+                    !devices_by_curve_key.contains_key(&session.sender_key().to_base64())
+                })
+                .count();
+        }
+        Err(e) if has_undecodable_olm_sessions && !repair => {
+            warn!("Skipping the check of the Olm sessions, some of them can't be decoded: {e:?}");
+        }
+        Err(e) => return Err(e),
+    }
+
+    let mut batches = InboundGroupSessionBatches::new(crypto_store);
+
+    loop {
+        let sessions = match batches.next().await {
+            Ok(Some(sessions)) => sessions,
+            Ok(None) => break,
+            Err(e) if has_undecodable_inbound_group_sessions && !repair => {
+                warn!("Skipping the checks of the inbound group sessions, some of them can't be decoded: {e:?}");
+                return Ok(report);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut updated_sessions = Vec::new();
+
+        for mut session in sessions {
+            let mut changed = false;
+
+            if session.backed_up() && backup_version.is_none() {
+                report.inconsistent_backup_flags += 1;
+                changed = true;
+
+                if repair {
+                    session.reset_backup_state();
+                }
+            }
+
+            match devices_by_curve_key.get(&session.sender_key().to_base64()) {
+                Some(device) => {
+                    if let Some(sender_data) =
+                        more_trusted_sender_data(store, device, &session).await?
+                    {
+                        report.outdated_sender_data += 1;
+                        session.sender_data = sender_data;
+                        changed = true;
+                    }
+                }
+                None => {
+                    if matches!(session.sender_data, SenderData::UnknownDevice { .. }) {
+                        report.inbound_group_sessions_from_unknown_devices += 1;
+                    }
+                }
+            }
+
+            if changed && repair {
+                updated_sessions.push(session);
+            }
+        }
+
+        if !updated_sessions.is_empty() {
+            store.save_inbound_group_sessions(&updated_sessions).await?;
+        }
+    }
+
+    report.inbound_group_sessions_checked = true;
+
+    let has_undecodable_outbound_group_sessions = report
+        .undecodable_entries
+        .iter()
+        .any(|entry| entry.kind == StoreEntryKind::OutboundGroupSession);
+
+    match crypto_store.get_outbound_group_sessions().await {
+        Ok(outbound_sessions) => {
+            for outbound in outbound_sessions {
+                if !outbound.invalidated()
+                    && crypto_store
+                        .get_inbound_group_session(outbound.room_id(), outbound.session_id())
+                        .await?
+                        .is_none()
+                {
+                    report.orphaned_outbound_group_sessions.push(outbound.room_id().to_owned());
+                }
+            }
+        }
+        Err(e) if has_undecodable_outbound_group_sessions && !repair => {
+            warn!("Skipping the check of the outbound group sessions, some of them can't be decoded: {e:?}");
+        }
+        Err(e) => return Err(e),
+    }
+
+    if report.has_problems() {
+        info!(?report, "The crypto store integrity check found some problems");
+    } else {
+        debug!("The crypto store integrity check didn't find any problem");
+    }
+
+    Ok(report)
+}
+
+/// Compute the [`SenderData`] of the given session again, using the device
+/// which sent it, and return it if it's more trusted than the current one.
+async fn more_trusted_sender_data(
+    store: &Store,
+    device: &DeviceData,
+    session: &InboundGroupSession,
+) -> Result<Option<SenderData>> {
+    if !matches!(
+        session.sender_data,
+        SenderData::UnknownDevice { .. }
+            | SenderData::DeviceInfo { .. }
+            | SenderData::VerificationViolation(..)
+    ) {
+        return Ok(None);
+    }
+
+    match SenderDataFinder::find_using_device_data(store, device.clone(), session).await {
+        Ok(sender_data) if sender_data.compare_trust_level(&session.sender_data).is_gt() => {
+            Ok(Some(sender_data))
+        }
+        Ok(_) => Ok(None),
+        Err(SessionDeviceCheckError::CryptoStoreError(e)) => Err(e),
+        Err(SessionDeviceCheckError::MismatchedIdentityKeys(e)) => {
+            debug!(
+                session_id = session.session_id(),
+                "The device doesn't own the inbound group session: {e}"
+            );
+            Ok(None)
+        }
+    }
+}
@@ -30,6 +30,8 @@ use vodozemac::Curve25519PublicKey;
 
 use super::{
     caches::{DeviceStore, GroupSessionStore},
+    integrity::{StoreEntryKind, UndecodableEntry},
+    pruning::SessionsToRemove,
     Account, BackupKeys, Changes, CryptoStore, InboundGroupSession, PendingChanges, RoomKeyCounts,
     RoomSettings, Session,
 };
@@ -335,6 +337,10 @@ impl CryptoStore for MemoryStore {
         Ok(self.sessions.read().unwrap().get(sender_key).cloned())
     }
 
+    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        Ok(self.sessions.read().unwrap().values().flatten().cloned().collect())
+    }
+
     async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
@@ -360,6 +366,30 @@ impl CryptoStore for MemoryStore {
         Ok(self.inbound_group_sessions.get_all())
     }
 
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after_session: Option<(OwnedRoomId, String)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        // Sort the sessions by room ID and session ID, so the batches are consistent...
+        let mut sessions = self.inbound_group_sessions.get_all();
+        sessions.sort_by(|a, b| (a.room_id(), a.session_id()).cmp(&(b.room_id(), b.session_id())));
+
+        // ... and start after the last session of the previous batch.
+        let start_index = match after_session {
+            None => 0,
+            Some((room_id, session_id)) => sessions
+                .iter()
+                .position(|session| {
+                    (session.room_id(), session.session_id())
+                        > (room_id.as_ref(), session_id.as_str())
+                })
+                .unwrap_or(sessions.len()),
+        };
+
+        Ok(sessions.drain(start_index..).take(limit).collect())
+    }
+
     async fn inbound_group_session_counts(
         &self,
         backup_version: Option<&str>,
@@ -492,6 +522,10 @@ impl CryptoStore for MemoryStore {
         Ok(self.outbound_group_sessions.read().unwrap().get(room_id).cloned())
     }
 
+    async fn get_outbound_group_sessions(&self) -> Result<Vec<OutboundGroupSession>> {
+        Ok(self.outbound_group_sessions.read().unwrap().values().cloned().collect())
+    }
+
     async fn load_tracked_users(&self) -> Result<Vec<TrackedUser>> {
         Ok(self.tracked_users.read().unwrap().values().cloned().collect())
     }
@@ -625,6 +659,72 @@ impl CryptoStore for MemoryStore {
         Ok(())
     }
 
+    async fn scan_undecodable_entries(&self, remove: bool) -> Result<Vec<UndecodableEntry>> {
+        // Everything but the user identities is kept in its decoded form.
+        let mut entries = Vec::new();
+
+        self.identities.write().unwrap().retain(|_, serialized| {
+            match serde_json::from_str::<UserIdentityData>(serialized) {
+                Ok(_) => true,
+                Err(e) => {
+                    entries.push(UndecodableEntry::new(StoreEntryKind::UserIdentity, e));
+                    !remove
+                }
+            }
+        });
+
+        Ok(entries)
+    }
+
+    async fn remove_sessions(&self, sessions: SessionsToRemove) -> Result<u64> {
+        let mut removed_sessions = Vec::new();
+
+        {
+            let mut session_store = self.sessions.write().unwrap();
+
+            for (sender_key, session_id) in &sessions.olm_sessions {
+                if let Some(entry) = session_store.get_mut(sender_key) {
+                    if let Some(index) = entry.iter().position(|s| s.session_id() == session_id) {
+                        removed_sessions.push(entry.remove(index));
+                    }
+
+                    if entry.is_empty() {
+                        session_store.remove(sender_key);
+                    }
+                }
+            }
+        }
+
+        let removed_inbound_group_sessions: Vec<_> = {
+            let mut backed_up_to = self.inbound_group_sessions_backed_up_to.write().unwrap();
+
+            sessions
+                .inbound_group_sessions
+                .iter()
+                .filter_map(|(room_id, session_id)| {
+                    if let Some(room_sessions) = backed_up_to.get_mut(room_id) {
+                        room_sessions.remove(session_id);
+                    }
+
+                    self.inbound_group_sessions.remove(room_id, session_id)
+                })
+                .collect()
+        };
+
+        // Nothing is serialized here, use the size of the pickles as an estimate.
+        let mut reclaimed_bytes = 0;
+
+        for session in removed_sessions {
+            reclaimed_bytes += serde_json::to_vec(&session.pickle().await).map_or(0, |v| v.len());
+        }
+
+        for session in removed_inbound_group_sessions {
+            reclaimed_bytes += serde_json::to_vec(&session.pickle().await).map_or(0, |v| v.len());
+        }
+
+        Ok(reclaimed_bytes as u64)
+    }
+
     async fn try_take_leased_lock(
         &self,
         lease_duration_ms: u32,
@@ -1142,7 +1242,8 @@ mod integration_tests {
 
     use async_trait::async_trait;
     use ruma::{
-        events::secret::request::SecretName, DeviceId, OwnedDeviceId, RoomId, TransactionId, UserId,
+        events::secret::request::SecretName, DeviceId, OwnedDeviceId, OwnedRoomId, RoomId,
+        TransactionId, UserId,
     };
     use vodozemac::Curve25519PublicKey;
 
@@ -1153,7 +1254,10 @@ mod integration_tests {
             InboundGroupSession, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity,
             SenderDataType, StaticAccountData,
         },
-        store::{BackupKeys, Changes, CryptoStore, PendingChanges, RoomKeyCounts, RoomSettings},
+        store::{
+            integrity::UndecodableEntry, pruning::SessionsToRemove, BackupKeys, Changes,
+            CryptoStore, PendingChanges, RoomKeyCounts, RoomSettings,
+        },
         types::events::room_key_withheld::RoomKeyWithheldEvent,
         Account, DeviceData, GossipRequest, GossippedSecret, SecretInfo, Session, TrackedUser,
         UserIdentityData,
@@ -1244,6 +1348,10 @@ mod integration_tests {
             self.0.get_sessions(sender_key).await
         }
 
+        async fn get_all_sessions(&self) -> Result<Vec<Session>, Self::Error> {
+            self.0.get_all_sessions().await
+        }
+
         async fn get_inbound_group_session(
             &self,
             room_id: &RoomId,
@@ -1266,6 +1374,14 @@ mod integration_tests {
             self.0.get_inbound_group_sessions().await
         }
 
+        async fn get_inbound_group_sessions_batch(
+            &self,
+            after_session: Option<(OwnedRoomId, String)>,
+            limit: usize,
+        ) -> Result<Vec<InboundGroupSession>, Self::Error> {
+            self.0.get_inbound_group_sessions_batch(after_session, limit).await
+        }
+
         async fn inbound_group_session_counts(
             &self,
             backup_version: Option<&str>,
@@ -1323,6 +1439,12 @@ mod integration_tests {
             self.0.get_outbound_group_session(room_id).await
         }
 
+        async fn get_outbound_group_sessions(
+            &self,
+        ) -> Result<Vec<OutboundGroupSession>, Self::Error> {
+            self.0.get_outbound_group_sessions().await
+        }
+
         async fn load_tracked_users(&self) -> Result<Vec<TrackedUser>, Self::Error> {
             self.0.load_tracked_users().await
         }
@@ -1422,6 +1544,17 @@ mod integration_tests {
             self.0.remove_custom_value(key).await
         }
 
+        async fn scan_undecodable_entries(
+            &self,
+            remove: bool,
+        ) -> Result<Vec<UndecodableEntry>, Self::Error> {
+            self.0.scan_undecodable_entries(remove).await
+        }
+
+        async fn remove_sessions(&self, sessions: SessionsToRemove) -> Result<u64, Self::Error> {
+            self.0.remove_sessions(sessions).await
+        }
+
         async fn try_take_leased_lock(
             &self,
             lease_duration_ms: u32,
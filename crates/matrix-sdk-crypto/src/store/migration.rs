@@ -0,0 +1,493 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Utilities to copy the contents of one [`CryptoStore`] into another one.
+//!
+//! This is useful to move an existing crypto store from one backend to another
+//! one, for example from an IndexedDB store into a SQLite store, or from a
+//! [`MemoryStore`] into a persistent store.
+//!
+//! The migration copies:
+//!
+//! * the Olm account and the private cross-signing identity,
+//! * the backup version and backup decryption key,
+//! * the list of tracked users, their devices and their identities,
+//! * the Olm sessions established with known devices,
+//! * the inbound group sessions, including their backup state and their
+//!   [`SenderData`](crate::olm::SenderData),
+//! * the outbound group sessions,
+//! * the room settings of the rooms we have room keys for, and of the rooms
+//!   the caller asks for,
+//! * the secrets inbox for the well-known secrets,
+//! * the sync token and any caller-provided custom values.
+//!
+//! The inbound group sessions are copied in batches, so the migration doesn't
+//! need to hold all of them in memory at once.
+//!
+//! Some data can't be enumerated through the [`CryptoStore`] trait and is
+//! therefore *not* migrated: withheld room key information, the hashes of
+//! already received Olm messages, outgoing key requests and Olm sessions with
+//! devices that aren't in the store anymore.
+//!
+//! [`MemoryStore`]: super::MemoryStore
+
+use std::collections::BTreeSet;
+
+use ruma::{events::secret::request::SecretName, OwnedRoomId, OwnedUserId, RoomId};
+use thiserror::Error;
+use tracing::{debug, info, instrument};
+
+use super::{
+    BackupKeys, Changes, CryptoStoreError, DeviceChanges, DynCryptoStore, IdentityChanges,
+    InboundGroupSessionBatches, PendingChanges, RoomKeyCounts,
+};
+
+/// The secrets whose inbox gets migrated.
+const MIGRATED_SECRETS: [SecretName; 4] = [
+    SecretName::CrossSigningMasterKey,
+    SecretName::CrossSigningSelfSigningKey,
+    SecretName::CrossSigningUserSigningKey,
+    SecretName::RecoveryKey,
+];
+
+/// Error type for [`migrate_crypto_store`].
+#[derive(Debug, Error)]
+pub enum MigrationError {
+    /// The source store doesn't contain an account, there is nothing to
+    /// migrate.
+    #[error("the source store doesn't contain an account")]
+    MissingAccount,
+
+    /// The target store already contains the account of another device,
+    /// migrating into it would mix data from two different devices.
+    #[error("the target store already contains an account")]
+    TargetNotEmpty,
+
+    /// Once the migration finished, the number of items in the target store
+    /// didn't match the number of items in the source store.
+    #[error("the number of {kind} doesn't match after the migration: source {source_count}, target {target_count}")]
+    CountMismatch {
+        /// The kind of data whose count didn't match.
+        kind: &'static str,
+        /// The number of items found in the source store.
+        source_count: usize,
+        /// The number of items found in the target store.
+        target_count: usize,
+    },
+
+    /// One of the stores returned an error.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
+}
+
+/// The progress of a running [`migrate_crypto_store`] call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MigrationProgress {
+    /// The account, private identity and backup keys have been migrated.
+    Account,
+    /// Devices, identities and Olm sessions of tracked users are being
+    /// migrated.
+    Users {
+        /// The number of users that have been migrated so far.
+        migrated: usize,
+        /// The total number of users that need to be migrated.
+        total: usize,
+    },
+    /// Inbound group sessions are being migrated.
+    InboundGroupSessions {
+        /// The number of sessions that have been migrated so far.
+        migrated: usize,
+        /// The total number of sessions that need to be migrated.
+        total: usize,
+    },
+    /// Outbound group sessions and room settings have been migrated.
+    Rooms,
+    /// The secrets inbox and custom values have been migrated.
+    Secrets,
+    /// The target store has been verified, the migration is done.
+    Done,
+}
+
+/// A summary of the data that [`migrate_crypto_store`] copied over.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// The number of tracked users.
+    pub tracked_users: usize,
+    /// The number of devices.
+    pub devices: usize,
+    /// The number of user identities.
+    pub identities: usize,
+    /// The number of Olm sessions.
+    pub olm_sessions: usize,
+    /// The number of inbound group sessions.
+    pub inbound_group_sessions: usize,
+    /// The number of inbound group sessions that were marked as backed up.
+    pub backed_up_inbound_group_sessions: usize,
+    /// The number of outbound group sessions.
+    pub outbound_group_sessions: usize,
+    /// The number of rooms with custom room settings.
+    pub room_settings: usize,
+    /// The number of secrets found in the secrets inbox.
+    pub secrets: usize,
+    /// The number of custom values.
+    pub custom_values: usize,
+}
+
+/// Copy the contents of the `source` crypto store into the `target` one.
+///
+/// The `target` store must not contain the account of another device. The
+/// `source` store is left untouched.
+///
+/// If the migration fails, e.g. because it got interrupted, the `target` store
+/// is left partially written and must not be used as is: either call this
+/// function again with the same `source` store, which copies all the data
+/// again, or delete the `target` store.
+///
+/// Custom values can't be enumerated, so the keys of the custom values that
+/// should be copied over need to be passed in as `custom_value_keys`.
+///
+/// Room settings can't be enumerated either: the settings of the rooms we have
+/// inbound or outbound group sessions for are copied over, as well as the
+/// ones of the rooms passed in as `room_ids`. Passing all the rooms known to
+/// the state store makes sure that the settings of the encrypted rooms we
+/// don't have room keys for yet are migrated too.
+///
+/// The `progress_listener` is called every time a step of the migration is
+/// done. Once all the data has been copied, the number of tracked users,
+/// devices, Olm sessions and inbound group sessions in the `target` store is
+/// compared to the `source` store, and a [`MigrationError::CountMismatch`] is
+/// returned if they differ.
+#[instrument(skip_all)]
+pub async fn migrate_crypto_store(
+    source: &DynCryptoStore,
+    target: &DynCryptoStore,
+    custom_value_keys: &[&str],
+    room_ids: &[&RoomId],
+    progress_listener: impl Fn(MigrationProgress),
+) -> Result<MigrationReport, MigrationError> {
+    let account = source.load_account().await?.ok_or(MigrationError::MissingAccount)?;
+    let own_user_id = account.user_id().to_owned();
+
+    if let Some(target_account) = target.load_account().await? {
+        let source_keys = account.identity_keys();
+        let target_keys = target_account.identity_keys();
+
+        if source_keys.curve25519 != target_keys.curve25519
+            || source_keys.ed25519 != target_keys.ed25519
+        {
+            return Err(MigrationError::TargetNotEmpty);
+        }
+
+        // A previous migration of the same account didn't finish, everything is
+        // copied again.
+        info!("The target store already contains our account, resuming the migration");
+    }
+
+    info!(user_id = ?own_user_id, device_id = ?account.device_id(), "Migrating the crypto store");
+
+    let mut report = MigrationReport::default();
+
+    // The account needs to be in the target store first, some backends refuse
+    // to store sessions otherwise.
+    target.save_pending_changes(PendingChanges { account: Some(account) }).await?;
+
+    let BackupKeys { decryption_key, backup_version } = source.load_backup_keys().await?;
+
+    target
+        .save_changes(Changes {
+            private_identity: source.load_identity().await?,
+            backup_version: backup_version.clone(),
+            backup_decryption_key: decryption_key,
+            next_batch_token: source.next_batch_token().await?,
+            ..Default::default()
+        })
+        .await?;
+
+    progress_listener(MigrationProgress::Account);
+
+    // Tracked users, with their devices, identities and Olm sessions.
+    let tracked_users = source.load_tracked_users().await?;
+    let tracked: Vec<_> = tracked_users.iter().map(|u| (u.user_id.as_ref(), u.dirty)).collect();
+    target.save_tracked_users(&tracked).await?;
+    report.tracked_users = tracked_users.len();
+
+    let users: BTreeSet<OwnedUserId> = tracked_users
+        .into_iter()
+        .map(|u| u.user_id)
+        .chain(std::iter::once(own_user_id.clone()))
+        .collect();
+
+    let total_users = users.len();
+
+    for (migrated, user_id) in users.iter().enumerate() {
+        let devices = source.get_user_devices(user_id).await?;
+        let identity = source.get_user_identity(user_id).await?;
+
+        let mut sessions = Vec::new();
+
+        for device in devices.values() {
+            if let Some(curve_key) = device.curve25519_key() {
+                if let Some(device_sessions) = source.get_sessions(&curve_key.to_base64()).await? {
+                    sessions.extend(device_sessions);
+                }
+            }
+        }
+
+        report.devices += devices.len();
+        report.identities += usize::from(identity.is_some());
+        report.olm_sessions += sessions.len();
+
+        target
+            .save_changes(Changes {
+                devices: DeviceChanges {
+                    new: devices.into_values().collect(),
+                    ..Default::default()
+                },
+                identities: IdentityChanges {
+                    new: identity.into_iter().collect(),
+                    ..Default::default()
+                },
+                sessions,
+                ..Default::default()
+            })
+            .await?;
+
+        progress_listener(MigrationProgress::Users { migrated: migrated + 1, total: total_users });
+    }
+
+    // Inbound group sessions, keeping track of which ones are backed up. They
+    // are read in batches, so we don't hold all of them in memory at once.
+    let total_sessions =
+        source.inbound_group_session_counts(backup_version.as_deref()).await?.total;
+    let mut rooms: BTreeSet<OwnedRoomId> =
+        room_ids.iter().map(|&room_id| room_id.to_owned()).collect();
+    let mut batches = InboundGroupSessionBatches::new(source);
+    let mut migrated = 0;
+
+    progress_listener(MigrationProgress::InboundGroupSessions { migrated, total: total_sessions });
+
+    while let Some(batch) = batches.next().await? {
+        let batch_len = batch.len();
+        rooms.extend(batch.iter().map(|session| session.room_id().to_owned()));
+
+        let (backed_up, not_backed_up): (Vec<_>, Vec<_>) =
+            batch.into_iter().partition(|session| session.backed_up());
+
+        report.backed_up_inbound_group_sessions += backed_up.len();
+
+        if !backed_up.is_empty() {
+            target.save_inbound_group_sessions(backed_up, backup_version.as_deref()).await?;
+        }
+
+        if !not_backed_up.is_empty() {
+            target.save_inbound_group_sessions(not_backed_up, None).await?;
+        }
+
+        migrated += batch_len;
+        progress_listener(MigrationProgress::InboundGroupSessions {
+            migrated,
+            total: total_sessions,
+        });
+    }
+
+    report.inbound_group_sessions = migrated;
+
+    // Outbound group sessions, and the room settings of the rooms we know about.
+    let outbound_group_sessions = source.get_outbound_group_sessions().await?;
+    rooms.extend(outbound_group_sessions.iter().map(|session| session.room_id().to_owned()));
+
+    let mut changes = Changes { outbound_group_sessions, ..Default::default() };
+
+    for room_id in rooms {
+        if let Some(settings) = source.get_room_settings(&room_id).await? {
+            changes.room_settings.insert(room_id, settings);
+        }
+    }
+
+    report.outbound_group_sessions = changes.outbound_group_sessions.len();
+    report.room_settings = changes.room_settings.len();
+    target.save_changes(changes).await?;
+
+    progress_listener(MigrationProgress::Rooms);
+
+    // The secrets inbox and the custom values.
+    let mut secrets = Vec::new();
+
+    for secret_name in &MIGRATED_SECRETS {
+        secrets.extend(source.get_secrets_from_inbox(secret_name).await?);
+    }
+
+    report.secrets = secrets.len();
+    target.save_changes(Changes { secrets, ..Default::default() }).await?;
+
+    for key in custom_value_keys {
+        if let Some(value) = source.get_custom_value(key).await? {
+            target.set_custom_value(key, value).await?;
+            report.custom_values += 1;
+        }
+    }
+
+    progress_listener(MigrationProgress::Secrets);
+
+    verify(source, target, &users, backup_version.as_deref()).await?;
+
+    debug!(?report, "Crypto store migration done");
+    progress_listener(MigrationProgress::Done);
+
+    Ok(report)
+}
+
+/// Check that the `target` store contains as many items as the `source` one.
+async fn verify(
+    source: &DynCryptoStore,
+    target: &DynCryptoStore,
+    users: &BTreeSet<OwnedUserId>,
+    backup_version: Option<&str>,
+) -> Result<(), MigrationError> {
+    fn check(kind: &'static str, source: usize, target: usize) -> Result<(), MigrationError> {
+        if source == target {
+            Ok(())
+        } else {
+            Err(MigrationError::CountMismatch { kind, source_count: source, target_count: target })
+        }
+    }
+
+    check(
+        "tracked users",
+        source.load_tracked_users().await?.len(),
+        target.load_tracked_users().await?.len(),
+    )?;
+
+    let mut source_devices = 0;
+    let mut target_devices = 0;
+    let mut source_sessions = 0;
+    let mut target_sessions = 0;
+
+    for user_id in users {
+        let devices = source.get_user_devices(user_id).await?;
+        source_devices += devices.len();
+        target_devices += target.get_user_devices(user_id).await?.len();
+
+        for device in devices.values() {
+            if let Some(curve_key) = device.curve25519_key() {
+                let curve_key = curve_key.to_base64();
+                source_sessions += source.get_sessions(&curve_key).await?.map_or(0, |s| s.len());
+                target_sessions += target.get_sessions(&curve_key).await?.map_or(0, |s| s.len());
+            }
+        }
+    }
+
+    check("devices", source_devices, target_devices)?;
+    check("Olm sessions", source_sessions, target_sessions)?;
+
+    let RoomKeyCounts { total: source_total, backed_up: source_backed_up } =
+        source.inbound_group_session_counts(backup_version).await?;
+    let RoomKeyCounts { total: target_total, backed_up: target_backed_up } =
+        target.inbound_group_session_counts(backup_version).await?;
+
+    check("inbound group sessions", source_total, target_total)?;
+    check("backed up inbound group sessions", source_backed_up, target_backed_up)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use matrix_sdk_test::async_test;
+    use ruma::{room_id, user_id};
+
+    use super::{migrate_crypto_store, MigrationError, MigrationProgress};
+    use crate::{
+        machine::test_helpers::get_machine_pair_with_session,
+        store::{Changes, CryptoStore, IntoCryptoStore, MemoryStore, RoomSettings},
+        EncryptionSettings,
+    };
+
+    #[async_test]
+    async fn test_migrate_memory_store() {
+        let (alice, bob) = get_machine_pair_with_session(
+            user_id!("@alice:localhost"),
+            user_id!("@bob:localhost"),
+            false,
+        )
+        .await;
+
+        alice.update_tracked_users([bob.user_id()]).await.unwrap();
+
+        let room_id = room_id!("!test:localhost");
+        alice
+            .share_room_key(room_id, [bob.user_id()].into_iter(), EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        // An encrypted room we don't have any room key for.
+        let other_room_id = room_id!("!other:localhost");
+        let settings = RoomSettings { only_allow_trusted_devices: true, ..Default::default() };
+
+        let source = alice.store().crypto_store();
+        source
+            .save_changes(Changes {
+                room_settings: [(other_room_id.to_owned(), settings.clone())].into(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let target = MemoryStore::new().into_crypto_store();
+
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let report = migrate_crypto_store(&**source, &*target, &[], &[other_room_id], {
+            let progress = progress.clone();
+            move |p| progress.lock().unwrap().push(p)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(report.tracked_users, 1);
+        assert_eq!(report.olm_sessions, 1);
+        assert_eq!(report.inbound_group_sessions, 1);
+        assert_eq!(report.outbound_group_sessions, 1);
+
+        let migrated_account = target.load_account().await.unwrap().unwrap();
+        assert_eq!(migrated_account.identity_keys().curve25519, alice.identity_keys().curve25519);
+        assert!(target.get_outbound_group_session(room_id).await.unwrap().is_some());
+        assert_eq!(target.get_room_settings(other_room_id).await.unwrap(), Some(settings));
+
+        let progress = progress.lock().unwrap();
+        assert_eq!(progress.first(), Some(&MigrationProgress::Account));
+        assert_eq!(progress.last(), Some(&MigrationProgress::Done));
+
+        // Migrating again into the same store, e.g. after an interrupted migration,
+        // copies everything again.
+        let report =
+            migrate_crypto_store(&**source, &*target, &[], &[other_room_id], |_| {}).await.unwrap();
+        assert_eq!(report.inbound_group_sessions, 1);
+
+        // Migrating into a store which contains the account of another device is
+        // refused.
+        let other_target = bob.store().crypto_store();
+        let result = migrate_crypto_store(&**source, &**other_target, &[], &[], |_| {}).await;
+        assert!(matches!(result, Err(MigrationError::TargetNotEmpty)));
+    }
+
+    #[async_test]
+    async fn test_migrate_empty_store() {
+        let source = MemoryStore::new().into_crypto_store();
+        let target = MemoryStore::new().into_crypto_store();
+
+        let result = migrate_crypto_store(&*source, &*target, &[], &[], |_| {}).await;
+
+        assert!(matches!(result, Err(MigrationError::MissingAccount)));
+    }
+}
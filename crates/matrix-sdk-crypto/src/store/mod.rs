@@ -52,7 +52,7 @@ use futures_core::Stream;
 use futures_util::StreamExt;
 use ruma::{
     encryption::KeyUsage, events::secret::request::SecretName, DeviceId, OwnedDeviceId,
-    OwnedRoomId, OwnedUserId, UserId,
+    OwnedRoomId, OwnedUserId, RoomId, UserId,
 };
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
@@ -66,13 +66,16 @@ use crate::{backups::BackupMachine, identities::OwnUserIdentity};
 use crate::{
     gossiping::GossippedSecret,
     identities::{user::UserIdentity, Device, DeviceData, UserDevices, UserIdentityData},
+    metrics::MetricsRecorder,
     olm::{
         Account, ExportedRoomKey, InboundGroupSession, OlmMessageHash, OutboundGroupSession,
-        PrivateCrossSigningIdentity, Session, StaticAccountData,
+        PrivateCrossSigningIdentity, SenderData, Session, StaticAccountData,
     },
+    session_manager::RoomKeySharingPolicy,
     types::{
         events::room_key_withheld::RoomKeyWithheldEvent, BackupSecrets, CrossSigningSecrets,
-        EventEncryptionAlgorithm, MegolmBackupV1Curve25519AesSha2Secrets, SecretsBundle,
+        EventEncryptionAlgorithm, MegolmBackupV1Curve25519AesSha2Secrets, RoomKeyBundle,
+        SecretsBundle, StoredRoomKeyBundleData,
     },
     verification::VerificationMachine,
     CrossSigningStatus, OwnUserIdentityData, RoomKeyImportResult,
@@ -81,7 +84,10 @@ use crate::{
 pub mod caches;
 mod crypto_store_wrapper;
 mod error;
+pub mod integrity;
 mod memorystore;
+pub mod migration;
+pub mod pruning;
 mod traits;
 
 #[cfg(any(test, feature = "testing"))]
@@ -490,6 +496,9 @@ struct StoreInner {
     /// Static account data that never changes (and thus can be loaded once and
     /// for all when creating the store).
     static_account: StaticAccountData,
+
+    /// The optional metrics collected about our cryptographic operations.
+    metrics: MetricsRecorder,
 }
 
 /// Aggregated changes to be saved in the database.
@@ -854,6 +863,49 @@ pub(crate) enum UserKeyQueryResult {
     TimeoutExpired,
 }
 
+/// The number of inbound group sessions that are loaded from the store at
+/// once when going through all of them.
+pub(crate) const INBOUND_GROUP_SESSIONS_BATCH_SIZE: usize = 1000;
+
+/// Helper to go through all the inbound group sessions of a store in batches
+/// of [`INBOUND_GROUP_SESSIONS_BATCH_SIZE`], so we don't hold all of them in
+/// memory at once.
+pub(crate) struct InboundGroupSessionBatches<'a> {
+    store: &'a DynCryptoStore,
+    after_session: Option<(OwnedRoomId, String)>,
+}
+
+impl<'a> InboundGroupSessionBatches<'a> {
+    /// Start going through the inbound group sessions of the given store.
+    ///
+    /// This takes the [`CryptoStore`] trait object rather than the [`Store`],
+    /// since some of the methods of the latter shadow the ones of the trait.
+    pub(crate) fn new(store: &'a DynCryptoStore) -> Self {
+        Self { store, after_session: None }
+    }
+
+    /// Load the next batch of inbound group sessions, or `None` if all of them
+    /// have been loaded.
+    pub(crate) async fn next(&mut self) -> Result<Option<Vec<InboundGroupSession>>> {
+        let sessions = self
+            .store
+            .get_inbound_group_sessions_batch(
+                self.after_session.take(),
+                INBOUND_GROUP_SESSIONS_BATCH_SIZE,
+            )
+            .await?;
+
+        let Some(last_session) = sessions.last() else {
+            return Ok(None);
+        };
+
+        self.after_session =
+            Some((last_session.room_id().to_owned(), last_session.session_id().to_owned()));
+
+        Ok(Some(sessions))
+    }
+}
+
 /// Room encryption settings which are modified by state events or user options
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct RoomSettings {
@@ -871,6 +923,13 @@ pub struct RoomSettings {
     /// The maximum number of messages an encryption session should be used for,
     /// before it is rotated.
     pub session_rotation_period_messages: Option<usize>,
+
+    /// A policy restricting which devices receive the room keys of the room,
+    /// used if the [`EncryptionSettings`] don't contain one.
+    ///
+    /// [`EncryptionSettings`]: crate::EncryptionSettings
+    #[serde(default)]
+    pub sharing_policy: Option<RoomKeySharingPolicy>,
 }
 
 impl Default for RoomSettings {
@@ -880,6 +939,7 @@ impl Default for RoomSettings {
             only_allow_trusted_devices: false,
             session_rotation_period: None,
             session_rotation_period_messages: None,
+            sharing_policy: None,
         }
     }
 }
@@ -942,6 +1002,7 @@ impl Store {
                 identity,
                 store: store.clone(),
                 verification_machine,
+                metrics: MetricsRecorder::default(),
                 cache: Arc::new(RwLock::new(StoreCache {
                     store,
                     tracked_users: Default::default(),
@@ -967,6 +1028,11 @@ impl Store {
         &self.inner.static_account
     }
 
+    /// The recorder for the metrics of our cryptographic operations.
+    pub(crate) fn metrics(&self) -> &MetricsRecorder {
+        &self.inner.metrics
+    }
+
     pub(crate) async fn cache(&self) -> Result<StoreCacheGuard> {
         // TODO: (bnjbvr, #2624) If configured with a cross-process lock:
         // - try to take the lock,
@@ -1541,6 +1607,38 @@ impl Store {
         self.inner.store.room_keys_withheld_received_stream()
     }
 
+    /// Receive notifications of inbound group sessions whose [`SenderData`]
+    /// got upgraded, as a [`Stream`].
+    ///
+    /// The [`SenderData`] of an inbound group session is computed when the
+    /// session is received, and upgraded in the background once a
+    /// `/keys/query` response tells us more about the device which sent it,
+    /// e.g. that it's cross-signed by its owner. The [`EncryptionInfo`] of
+    /// the events encrypted with those sessions can then be refreshed using
+    /// [`OlmMachine::get_session_encryption_info()`], e.g. to update their
+    /// shields.
+    ///
+    /// If the reader of the stream lags too far behind, a warning will be
+    /// logged and items will be dropped.
+    ///
+    /// The stream will terminate once all references to the underlying
+    /// `CryptoStoreWrapper` are dropped.
+    ///
+    /// [`SenderData`]: crate::olm::SenderData
+    /// [`EncryptionInfo`]: matrix_sdk_common::deserialized_responses::EncryptionInfo
+    /// [`OlmMachine::get_session_encryption_info()`]: crate::OlmMachine::get_session_encryption_info
+    pub fn sender_data_updates_stream(&self) -> impl Stream<Item = Vec<RoomKeyInfo>> {
+        self.inner.store.sender_data_updates_stream()
+    }
+
+    /// Notify the listeners of [`Self::sender_data_updates_stream`] that the
+    /// [`SenderData`] of the given inbound group sessions got upgraded.
+    ///
+    /// [`SenderData`]: crate::olm::SenderData
+    pub(crate) fn notify_sender_data_updates(&self, updates: Vec<RoomKeyInfo>) {
+        self.inner.store.notify_sender_data_updates(updates)
+    }
+
     /// Returns a stream of user identity updates, allowing users to listen for
     /// notifications about new or changed user identities.
     ///
@@ -1725,6 +1823,24 @@ impl Store {
         exported_keys: Vec<ExportedRoomKey>,
         from_backup_version: Option<&str>,
         progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult> {
+        self.import_room_keys_with_sender_data(
+            exported_keys,
+            from_backup_version,
+            None,
+            progress_listener,
+        )
+        .await
+    }
+
+    /// Import the given room keys into the store, overriding the
+    /// [`SenderData`] of the imported sessions if one is provided.
+    async fn import_room_keys_with_sender_data(
+        &self,
+        exported_keys: Vec<ExportedRoomKey>,
+        from_backup_version: Option<&str>,
+        sender_data: Option<SenderData>,
+        progress_listener: impl Fn(usize, usize),
     ) -> Result<RoomKeyImportResult> {
         let mut sessions = Vec::new();
 
@@ -1744,7 +1860,11 @@ impl Store {
 
         for (i, key) in exported_keys.into_iter().enumerate() {
             match InboundGroupSession::from_export(&key) {
-                Ok(session) => {
+                Ok(mut session) => {
+                    if let Some(sender_data) = &sender_data {
+                        session.sender_data = sender_data.clone();
+                    }
+
                     let old_session = self
                         .inner
                         .store
@@ -1908,6 +2028,142 @@ impl Store {
         Ok(futures_util::stream::iter(sessions.into_iter().filter(predicate))
             .then(|session| async move { session.export().await }))
     }
+
+    /// Build a bundle of the keys of the given room's history, to share it with
+    /// a user we're inviting to the room.
+    ///
+    /// Only the sessions which were created while the history visibility of
+    /// the room allowed sharing the history are included, see
+    /// [`InboundGroupSession::shared_history()`].
+    ///
+    /// See [`OlmMachine::share_room_key_bundle_data`] for the next steps.
+    ///
+    /// [`OlmMachine::share_room_key_bundle_data`]: crate::OlmMachine::share_room_key_bundle_data
+    pub async fn build_room_key_bundle(&self, room_id: &RoomId) -> Result<RoomKeyBundle> {
+        let mut room_keys = Vec::new();
+
+        // Go through the sessions in batches, so only the ones of the room are held in
+        // memory at once.
+        let mut batches = InboundGroupSessionBatches::new(self);
+
+        while let Some(sessions) = batches.next().await? {
+            for session in sessions {
+                if session.room_id() == room_id && session.shared_history() {
+                    room_keys.push(session.export().await);
+                }
+            }
+        }
+
+        Ok(RoomKeyBundle { room_keys })
+    }
+
+    fn room_key_bundle_data_key(room_id: &RoomId, user_id: &UserId) -> String {
+        format!("room_key_bundle_data|{room_id}|{user_id}")
+    }
+
+    /// Store the data about a room key bundle that has been sent to us.
+    ///
+    /// A bundle received from the same user for the same room replaces the
+    /// previous one.
+    pub(crate) async fn save_room_key_bundle_data(
+        &self,
+        data: &StoredRoomKeyBundleData,
+    ) -> Result<()> {
+        self.set_value(
+            &Self::room_key_bundle_data_key(&data.bundle_data.room_id, &data.sender_user),
+            data,
+        )
+        .await
+    }
+
+    /// Get the data about the room key bundle that the given user sent us for
+    /// the given room, if any.
+    ///
+    /// This should be called when joining a room we've been invited to, with
+    /// the user who invited us: only bundles from the inviter are meant to be
+    /// accepted.
+    pub async fn get_received_room_key_bundle_data(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<Option<StoredRoomKeyBundleData>> {
+        self.get_value(&Self::room_key_bundle_data_key(room_id, user_id)).await
+    }
+
+    fn pending_room_key_bundle_key(room_id: &RoomId) -> String {
+        format!("pending_room_key_bundle|{room_id}")
+    }
+
+    /// Remember that we joined the given room after being invited by the given
+    /// user, before their room key bundle arrived.
+    ///
+    /// The bundle should be imported when it arrives, see
+    /// [`Store::get_pending_room_key_bundle_inviter`].
+    pub async fn set_pending_room_key_bundle(
+        &self,
+        room_id: &RoomId,
+        inviter: &UserId,
+    ) -> Result<()> {
+        self.set_value(&Self::pending_room_key_bundle_key(room_id), &inviter.to_owned()).await
+    }
+
+    /// Get the user who invited us to the given room, if we joined the room
+    /// and are still waiting for the room key bundle they should send us.
+    pub async fn get_pending_room_key_bundle_inviter(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<OwnedUserId>> {
+        self.get_value(&Self::pending_room_key_bundle_key(room_id)).await
+    }
+
+    /// Import the keys of a room key bundle that has been sent to us, once it
+    /// has been downloaded and decrypted.
+    ///
+    /// Only the keys that belong to the room the bundle was sent for are
+    /// imported, and the imported sessions record who shared them with us, in
+    /// a [`SenderData::SharedHistory`]. The stored data about the bundle, and
+    /// the pending bundle set with [`Store::set_pending_room_key_bundle`], are
+    /// then removed.
+    pub async fn receive_room_key_bundle(
+        &self,
+        bundle_info: &StoredRoomKeyBundleData,
+        bundle: RoomKeyBundle,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<RoomKeyImportResult> {
+        let room_id = &bundle_info.bundle_data.room_id;
+
+        let (room_keys, other_keys): (Vec<_>, Vec<_>) =
+            bundle.room_keys.into_iter().partition(|key| key.room_id == *room_id);
+
+        if !other_keys.is_empty() {
+            warn!(
+                %room_id,
+                num_keys = other_keys.len(),
+                "Ignoring keys of other rooms found in a room key bundle"
+            );
+        }
+
+        let sender_data =
+            SenderData::shared_history(&bundle_info.sender_user, &bundle_info.sender_device);
+
+        let result = self
+            .import_room_keys_with_sender_data(
+                room_keys,
+                None,
+                Some(sender_data),
+                progress_listener,
+            )
+            .await?;
+
+        self.remove_custom_value(&Self::room_key_bundle_data_key(
+            room_id,
+            &bundle_info.sender_user,
+        ))
+        .await?;
+        self.remove_custom_value(&Self::pending_room_key_bundle_key(room_id)).await?;
+
+        Ok(result)
+    }
 }
 
 impl Deref for Store {
@@ -0,0 +1,344 @@
+// Copyright 2025 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Removal of the sessions a [`CryptoStore`] doesn't need to keep anymore.
+//!
+//! The store keeps every Olm session we ever established with a device, and
+//! every room key we ever received. The pruning, run with
+//! [`OlmMachine::prune_sessions()`], removes:
+//!
+//! * the least recently used Olm sessions of a device, once the device has more
+//!   sessions than configured,
+//! * optionally, the Olm sessions of devices which were removed from the
+//!   device list of their owner,
+//! * optionally, the inbound group sessions of rooms we left, if they are
+//!   backed up, so they can be restored if we join those rooms again.
+//!
+//! [`CryptoStore`]: super::CryptoStore
+//! [`OlmMachine::prune_sessions()`]: crate::OlmMachine::prune_sessions
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use ruma::{OwnedRoomId, OwnedUserId, RoomId};
+use tracing::{debug, info, instrument};
+
+use super::{BackupKeys, CryptoStoreError, DynCryptoStore, InboundGroupSessionBatches, Result};
+use crate::DeviceData;
+
+/// The key under which the Curve25519 keys of the devices which got removed
+/// from the device list of their owner are stored, with their owner.
+const DELETED_DEVICE_KEYS_STORE_KEY: &str = "deleted_device_keys";
+
+/// The default value of
+/// [`SessionPruningSettings::max_olm_sessions_per_device`].
+pub const DEFAULT_MAX_OLM_SESSIONS_PER_DEVICE: usize = 10;
+
+/// The settings of [`OlmMachine::prune_sessions()`].
+///
+/// [`OlmMachine::prune_sessions()`]: crate::OlmMachine::prune_sessions
+#[derive(Clone, Debug)]
+pub struct SessionPruningSettings {
+    /// The maximum number of Olm sessions to keep for a single device, the
+    /// most recently used ones are kept.
+    ///
+    /// `None` keeps all of them.
+    pub max_olm_sessions_per_device: Option<usize>,
+
+    /// Should the Olm sessions of devices which were removed from the device
+    /// list of their owner be removed?
+    ///
+    /// Only the devices a `/keys/query` response reported as deleted are
+    /// considered, while the device list of their owner is up to date. The
+    /// sessions of devices we simply don't know about are always kept.
+    ///
+    /// Defaults to `false`.
+    pub remove_olm_sessions_of_deleted_devices: bool,
+
+    /// The rooms we left, whose inbound group sessions should be removed.
+    ///
+    /// Only the sessions which are backed up are removed, the other ones
+    /// would be lost for good.
+    pub left_rooms: Vec<OwnedRoomId>,
+}
+
+impl Default for SessionPruningSettings {
+    fn default() -> Self {
+        Self {
+            max_olm_sessions_per_device: Some(DEFAULT_MAX_OLM_SESSIONS_PER_DEVICE),
+            remove_olm_sessions_of_deleted_devices: false,
+            left_rooms: Vec::new(),
+        }
+    }
+}
+
+/// The sessions to remove from a [`CryptoStore`], with
+/// [`CryptoStore::remove_sessions()`].
+///
+/// [`CryptoStore`]: super::CryptoStore
+/// [`CryptoStore::remove_sessions()`]: super::CryptoStore::remove_sessions
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionsToRemove {
+    /// The Olm sessions, as pairs of the Curve25519 key of the other device,
+    /// encoded as base64, and the ID of the session.
+    pub olm_sessions: Vec<(String, String)>,
+
+    /// The inbound group sessions, as pairs of their room ID and session ID.
+    pub inbound_group_sessions: Vec<(OwnedRoomId, String)>,
+}
+
+impl SessionsToRemove {
+    /// Is there nothing to remove?
+    pub fn is_empty(&self) -> bool {
+        self.olm_sessions.is_empty() && self.inbound_group_sessions.is_empty()
+    }
+}
+
+/// The result of [`OlmMachine::prune_sessions()`].
+///
+/// [`OlmMachine::prune_sessions()`]: crate::OlmMachine::prune_sessions
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionPruningReport {
+    /// The number of Olm sessions removed because their device had too many
+    /// of them.
+    pub removed_excess_olm_sessions: usize,
+
+    /// The number of Olm sessions removed because their device was deleted.
+    pub removed_olm_sessions_of_deleted_devices: usize,
+
+    /// The number of inbound group sessions of left rooms which were removed.
+    pub removed_inbound_group_sessions: usize,
+
+    /// An estimate of the space the removed sessions took in the store, in
+    /// bytes.
+    pub reclaimed_bytes: u64,
+}
+
+/// Remove the sessions the store doesn't need to keep anymore, see the
+/// [module documentation](self).
+///
+/// Dropping the removed Olm sessions from the session cache is left to the
+/// caller.
+#[instrument(skip(store))]
+pub(crate) async fn prune_sessions(
+    store: &DynCryptoStore,
+    settings: &SessionPruningSettings,
+) -> Result<(SessionPruningReport, SessionsToRemove)> {
+    let mut report = SessionPruningReport::default();
+    let mut to_remove = SessionsToRemove::default();
+    let mut deleted_device_keys = None;
+
+    if settings.max_olm_sessions_per_device.is_some()
+        || settings.remove_olm_sessions_of_deleted_devices
+    {
+        if settings.remove_olm_sessions_of_deleted_devices {
+            deleted_device_keys = Some(find_deleted_device_keys(store).await?);
+        }
+        let deleted_sender_keys = deleted_device_keys.as_ref().map(|(removable, _)| removable);
+
+        let mut sessions_per_device = BTreeMap::new();
+
+        for session in store.get_all_sessions().await? {
+            sessions_per_device
+                .entry(session.sender_key().to_base64())
+                .or_insert_with(Vec::new)
+                .push(session);
+        }
+
+        for (sender_key, mut sessions) in sessions_per_device {
+            if deleted_sender_keys.is_some_and(|keys| keys.contains(&sender_key)) {
+                debug!(
+                    sender_key,
+                    count = sessions.len(),
+                    "Removing the Olm sessions of a deleted device"
+                );
+
+                report.removed_olm_sessions_of_deleted_devices += sessions.len();
+                to_remove.olm_sessions.extend(
+                    sessions.iter().map(|s| (sender_key.clone(), s.session_id().to_owned())),
+                );
+
+                continue;
+            }
+
+            if let Some(max) = settings.max_olm_sessions_per_device {
+                if sessions.len() > max {
+                    // Most recently used first, those are the ones we keep.
+                    sessions.sort_by_key(|s| std::cmp::Reverse((s.last_use_time, s.creation_time)));
+
+                    let excess = &sessions[max..];
+                    debug!(
+                        sender_key,
+                        count = excess.len(),
+                        "Removing the least recently used Olm sessions"
+                    );
+
+                    report.removed_excess_olm_sessions += excess.len();
+                    to_remove.olm_sessions.extend(
+                        excess.iter().map(|s| (sender_key.clone(), s.session_id().to_owned())),
+                    );
+                }
+            }
+        }
+    }
+
+    if !settings.left_rooms.is_empty() {
+        let BackupKeys { backup_version, .. } = store.load_backup_keys().await?;
+
+        // Without a backup, nothing can be restored if we join the room again.
+        if backup_version.is_some() {
+            let left_rooms: BTreeSet<&RoomId> =
+                settings.left_rooms.iter().map(AsRef::as_ref).collect();
+
+            let mut batches = InboundGroupSessionBatches::new(store);
+
+            while let Some(sessions) = batches.next().await? {
+                to_remove.inbound_group_sessions.extend(
+                    sessions
+                        .iter()
+                        .filter(|s| s.backed_up() && left_rooms.contains(s.room_id()))
+                        .map(|s| (s.room_id().to_owned(), s.session_id().to_owned())),
+                );
+            }
+
+            report.removed_inbound_group_sessions = to_remove.inbound_group_sessions.len();
+        } else {
+            debug!("Not removing the room keys of the left rooms, no backup is enabled");
+        }
+    }
+
+    if !to_remove.is_empty() {
+        report.reclaimed_bytes = store.remove_sessions(to_remove.clone()).await?;
+        info!(?report, "Pruned the sessions of the crypto store");
+    } else {
+        debug!("No session needed to be pruned");
+    }
+
+    // Only forget about the deleted devices once their Olm sessions are gone, so
+    // they're pruned the next time if removing the sessions failed.
+    if let Some((_, handled_keys)) = deleted_device_keys {
+        forget_deleted_device_keys(store, &handled_keys).await?;
+    }
+
+    Ok((report, to_remove))
+}
+
+/// Remember the Curve25519 keys of the given devices, which were removed from
+/// the device list of their owner, so their Olm sessions can be pruned.
+pub(crate) async fn record_deleted_devices(
+    store: &DynCryptoStore,
+    devices: &[DeviceData],
+) -> Result<()> {
+    let mut deleted_keys = load_deleted_device_keys(store).await?;
+
+    for device in devices {
+        if let Some(curve_key) = device.curve25519_key() {
+            deleted_keys.insert(curve_key.to_base64(), device.user_id().to_owned());
+        }
+    }
+
+    save_deleted_device_keys(store, &deleted_keys).await
+}
+
+/// Find the Curve25519 keys of the deleted devices whose Olm sessions can be
+/// removed.
+///
+/// Returns the keys whose Olm sessions can be removed, and the keys which can
+/// be forgotten once that's done. The latter also contains the keys which were
+/// added back to the device list of their owner, which don't need to be
+/// pruned. The devices of users whose device list is outdated are kept for a
+/// later pruning, and are in neither of them.
+async fn find_deleted_device_keys(
+    store: &DynCryptoStore,
+) -> Result<(BTreeSet<String>, BTreeSet<String>)> {
+    let deleted_keys = load_deleted_device_keys(store).await?;
+
+    if deleted_keys.is_empty() {
+        return Ok(Default::default());
+    }
+
+    let dirty_users: BTreeSet<OwnedUserId> = store
+        .load_tracked_users()
+        .await?
+        .into_iter()
+        .filter(|user| user.dirty)
+        .map(|user| user.user_id)
+        .collect();
+
+    let users: BTreeSet<OwnedUserId> = deleted_keys.values().cloned().collect();
+    let mut current_keys = BTreeSet::new();
+
+    for user_id in users.iter().filter(|user_id| !dirty_users.contains(*user_id)) {
+        for device in store.get_user_devices(user_id).await?.into_values() {
+            if let Some(curve_key) = device.curve25519_key() {
+                current_keys.insert(curve_key.to_base64());
+            }
+        }
+    }
+
+    let mut removable_keys = BTreeSet::new();
+    let mut handled_keys = BTreeSet::new();
+
+    for (sender_key, user_id) in deleted_keys {
+        if dirty_users.contains(&user_id) {
+            debug!(?user_id, "Not pruning a deleted device yet, its device list is outdated");
+        } else {
+            if !current_keys.contains(&sender_key) {
+                removable_keys.insert(sender_key.clone());
+            }
+            handled_keys.insert(sender_key);
+        }
+    }
+
+    Ok((removable_keys, handled_keys))
+}
+
+/// Forget about the given Curve25519 keys of deleted devices, once they have
+/// been handled.
+///
+/// The stored keys are loaded again, so the devices which were deleted in the
+/// meantime are kept.
+async fn forget_deleted_device_keys(
+    store: &DynCryptoStore,
+    handled_keys: &BTreeSet<String>,
+) -> Result<()> {
+    if handled_keys.is_empty() {
+        return Ok(());
+    }
+
+    let mut deleted_keys = load_deleted_device_keys(store).await?;
+    deleted_keys.retain(|sender_key, _| !handled_keys.contains(sender_key));
+
+    save_deleted_device_keys(store, &deleted_keys).await
+}
+
+async fn load_deleted_device_keys(store: &DynCryptoStore) -> Result<BTreeMap<String, OwnedUserId>> {
+    let Some(value) = store.get_custom_value(DELETED_DEVICE_KEYS_STORE_KEY).await? else {
+        return Ok(BTreeMap::new());
+    };
+
+    rmp_serde::from_slice(&value).map_err(|e| CryptoStoreError::Backend(e.into()))
+}
+
+async fn save_deleted_device_keys(
+    store: &DynCryptoStore,
+    deleted_keys: &BTreeMap<String, OwnedUserId>,
+) -> Result<()> {
+    if deleted_keys.is_empty() {
+        store.remove_custom_value(DELETED_DEVICE_KEYS_STORE_KEY).await
+    } else {
+        let value = rmp_serde::to_vec_named(deleted_keys)
+            .map_err(|e| CryptoStoreError::Backend(e.into()))?;
+        store.set_custom_value(DELETED_DEVICE_KEYS_STORE_KEY, value).await
+    }
+}
@@ -17,12 +17,14 @@ use std::{collections::HashMap, fmt, sync::Arc};
 use async_trait::async_trait;
 use matrix_sdk_common::AsyncTraitDeps;
 use ruma::{
-    events::secret::request::SecretName, DeviceId, OwnedDeviceId, RoomId, TransactionId, UserId,
+    events::secret::request::SecretName, DeviceId, OwnedDeviceId, OwnedRoomId, RoomId,
+    TransactionId, UserId,
 };
 use vodozemac::Curve25519PublicKey;
 
 use super::{
-    BackupKeys, Changes, CryptoStoreError, PendingChanges, Result, RoomKeyCounts, RoomSettings,
+    integrity::UndecodableEntry, pruning::SessionsToRemove, BackupKeys, Changes, CryptoStoreError,
+    PendingChanges, Result, RoomKeyCounts, RoomSettings,
 };
 #[cfg(doc)]
 use crate::olm::SenderData;
@@ -89,6 +91,9 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// * `sender_key` - The sender key that was used to establish the sessions.
     async fn get_sessions(&self, sender_key: &str) -> Result<Option<Vec<Session>>, Self::Error>;
 
+    /// Get all the Olm sessions we have stored, for all the devices.
+    async fn get_all_sessions(&self) -> Result<Vec<Session>, Self::Error>;
+
     /// Get the inbound group session from our store.
     ///
     /// # Arguments
@@ -117,6 +122,30 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Get all the inbound group sessions we have stored.
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>, Self::Error>;
 
+    /// Get a batch of all the inbound group sessions we have stored.
+    ///
+    /// Sessions are not necessarily returned in any specific order, but the
+    /// returned batches are consistent: if this function is called repeatedly
+    /// with `after_session` set to the room ID and session ID of the last
+    /// session from the previous call, until an empty result is returned,
+    /// then eventually all the sessions are returned. (New sessions that are
+    /// added in the course of iteration may or may not be returned.)
+    ///
+    /// This is used to go through all the sessions without loading all of
+    /// them in memory at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `after_session` - return the sessions after the session with this
+    ///   room ID and session ID, or start at the earliest if this is None.
+    ///
+    /// * `limit` - return a maximum of this many sessions.
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after_session: Option<(OwnedRoomId, String)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>, Self::Error>;
+
     /// Get the number inbound group sessions we have and how many of them are
     /// backed up.
     async fn inbound_group_session_counts(
@@ -202,6 +231,10 @@ pub trait CryptoStore: AsyncTraitDeps {
         room_id: &RoomId,
     ) -> Result<Option<OutboundGroupSession>, Self::Error>;
 
+    /// Get all the outbound group sessions we have stored, one per room at
+    /// most.
+    async fn get_outbound_group_sessions(&self) -> Result<Vec<OutboundGroupSession>, Self::Error>;
+
     /// Provide the list of users whose devices we are keeping track of, and
     /// whether they are considered dirty/outdated.
     async fn load_tracked_users(&self) -> Result<Vec<TrackedUser>, Self::Error>;
@@ -337,6 +370,28 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// * `key` - The key to insert data into
     async fn remove_custom_value(&self, key: &str) -> Result<(), Self::Error>;
 
+    /// Find the Olm sessions, group sessions, devices and user identities
+    /// which can't be decrypted or deserialized anymore.
+    ///
+    /// Depending on the implementation, such entries either make the methods
+    /// loading them fail or get silently skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `remove` - Should the entries which can't be decoded be removed from
+    ///   the store.
+    async fn scan_undecodable_entries(
+        &self,
+        remove: bool,
+    ) -> Result<Vec<UndecodableEntry>, Self::Error>;
+
+    /// Remove the given Olm sessions and inbound group sessions from the
+    /// store.
+    ///
+    /// Returns an estimate of the space the removed sessions took in the
+    /// store, in bytes.
+    async fn remove_sessions(&self, sessions: SessionsToRemove) -> Result<u64, Self::Error>;
+
     /// Try to take a leased lock.
     ///
     /// This attempts to take a lock for the given lease duration.
@@ -402,6 +457,10 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
         self.0.get_sessions(sender_key).await.map_err(Into::into)
     }
 
+    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        self.0.get_all_sessions().await.map_err(Into::into)
+    }
+
     async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
@@ -432,6 +491,14 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
             .map_err(Into::into)
     }
 
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after_session: Option<(OwnedRoomId, String)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        self.0.get_inbound_group_sessions_batch(after_session, limit).await.map_err(Into::into)
+    }
+
     async fn inbound_group_session_counts(
         &self,
         backup_version: Option<&str>,
@@ -472,6 +539,10 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
         self.0.get_outbound_group_session(room_id).await.map_err(Into::into)
     }
 
+    async fn get_outbound_group_sessions(&self) -> Result<Vec<OutboundGroupSession>> {
+        self.0.get_outbound_group_sessions().await.map_err(Into::into)
+    }
+
     async fn load_tracked_users(&self) -> Result<Vec<TrackedUser>> {
         self.0.load_tracked_users().await.map_err(Into::into)
     }
@@ -564,6 +635,14 @@ impl<T: CryptoStore> CryptoStore for EraseCryptoStoreError<T> {
         self.0.remove_custom_value(key).await.map_err(Into::into)
     }
 
+    async fn scan_undecodable_entries(&self, remove: bool) -> Result<Vec<UndecodableEntry>> {
+        self.0.scan_undecodable_entries(remove).await.map_err(Into::into)
+    }
+
+    async fn remove_sessions(&self, sessions: SessionsToRemove) -> Result<u64> {
+        self.0.remove_sessions(sessions).await.map_err(Into::into)
+    }
+
     async fn try_take_leased_lock(
         &self,
         lease_duration_ms: u32,
@@ -23,6 +23,7 @@ pub mod forwarded_room_key;
 pub mod olm_v1;
 pub mod room;
 pub mod room_key;
+pub mod room_key_bundle;
 pub mod room_key_request;
 pub mod room_key_withheld;
 pub mod secret_send;
@@ -31,7 +32,7 @@ mod utd_cause;
 
 use ruma::serde::Raw;
 pub use to_device::{ToDeviceCustomEvent, ToDeviceEvent, ToDeviceEvents};
-pub use utd_cause::UtdCause;
+pub use utd_cause::{CryptoContextInfo, UtdCause};
 
 /// A trait for event contents to define their event type.
 pub trait EventType {
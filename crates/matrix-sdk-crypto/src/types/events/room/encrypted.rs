@@ -63,6 +63,46 @@ impl EncryptedEvent {
             RoomEventEncryptionScheme::Unknown(_) => None,
         }
     }
+
+    /// Get the state key of this event, if it's an encrypted state event.
+    ///
+    /// The state key of an encrypted state event packs the type and the state
+    /// key of the plaintext event, see [`encrypted_state_key()`].
+    pub fn state_key(&self) -> Option<&str> {
+        self.other.get("state_key").and_then(Value::as_str)
+    }
+}
+
+/// State event types which are never encrypted, as described in [MSC3414],
+/// because the server needs to read them to authorize events in the room.
+///
+/// Encrypted state events which decrypt to one of these types must be
+/// rejected.
+///
+/// [MSC3414]: https://github.com/matrix-org/matrix-spec-proposals/pull/3414
+pub const UNENCRYPTED_STATE_EVENT_TYPES: &[&str] = &[
+    "m.room.create",
+    "m.room.member",
+    "m.room.power_levels",
+    "m.room.join_rules",
+    "m.room.third_party_invite",
+    "m.room.history_visibility",
+    "m.room.guest_access",
+    "m.room.encryption",
+    "m.room.server_acl",
+    "m.room.tombstone",
+];
+
+/// Create the state key of an encrypted state event, as described in
+/// [MSC3414].
+///
+/// The type and the state key of the plaintext state event are packed into the
+/// state key of the `m.room.encrypted` state event, so that events with
+/// different types or state keys don't replace each other in the room state.
+///
+/// [MSC3414]: https://github.com/matrix-org/matrix-spec-proposals/pull/3414
+pub fn encrypted_state_key(event_type: &str, state_key: &str) -> String {
+    format!("{event_type}:{state_key}")
 }
 
 /// An m.room.encrypted to-device event.
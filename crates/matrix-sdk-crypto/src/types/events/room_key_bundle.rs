@@ -0,0 +1,77 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types for `io.element.msc4268.room_key_bundle` to-device events, as
+//! defined in [MSC4268].
+//!
+//! [MSC4268]: https://github.com/matrix-org/matrix-spec-proposals/pull/4268
+
+use ruma::{events::room::EncryptedFile, OwnedRoomId};
+use serde::{Deserialize, Serialize};
+
+use super::EventType;
+
+/// The content of an `io.element.msc4268.room_key_bundle` to-device event,
+/// sent over Olm to the devices of a user we invited to a room.
+///
+/// It points to an encrypted [`RoomKeyBundle`](crate::types::RoomKeyBundle)
+/// uploaded to the media repository, containing the keys of the room's
+/// history.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RoomKeyBundleContent {
+    /// The room to which the keys in the bundle belong.
+    pub room_id: OwnedRoomId,
+
+    /// The location and encryption info of the bundle.
+    pub file: EncryptedFile,
+}
+
+impl EventType for RoomKeyBundleContent {
+    const EVENT_TYPE: &'static str = "io.element.msc4268.room_key_bundle";
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::RoomKeyBundleContent;
+
+    #[test]
+    fn serialization() {
+        let json = json!({
+            "room_id": "!Cuyf34gef24t:localhost",
+            "file": {
+                "url": "mxc://example.org/FHyPlCeYUSFFxlgbQYZmoEoe",
+                "key": {
+                    "kty": "oct",
+                    "key_ops": ["encrypt", "decrypt"],
+                    "alg": "A256CTR",
+                    "k": "aWF6-32KGYaC3A_FEUCk1Bt0JA37zP0wrStgmdCaW-0",
+                    "ext": true
+                },
+                "iv": "X85+XgHN+HEAAAAAAAAAAA",
+                "hashes": {
+                    "sha256": "7R6PrrVZzIuuUbzvCQzd0Zf9xYKPKqvdiMJw9z5Kp0s"
+                },
+                "v": "v2"
+            }
+        });
+
+        let content: RoomKeyBundleContent = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(content.room_id, "!Cuyf34gef24t:localhost");
+
+        let serialized = serde_json::to_value(content).unwrap();
+        assert_eq!(serialized, json);
+    }
+}
@@ -15,12 +15,17 @@
 use matrix_sdk_common::deserialized_responses::{
     UnableToDecryptInfo, UnableToDecryptReason, VerificationLevel,
 };
-use ruma::{events::AnySyncTimelineEvent, serde::Raw};
+use ruma::{events::AnySyncTimelineEvent, serde::Raw, MilliSecondsSinceUnixEpoch};
 use serde::Deserialize;
 
+use super::room_key_withheld::WithheldCode;
+
 /// Our best guess at the reason why an event can't be decrypted.
+///
+/// New causes may be added as we learn to tell more cases apart.
 #[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
 #[cfg_attr(feature = "uniffi", derive(uniffi::Enum))]
+#[non_exhaustive]
 pub enum UtdCause {
     /// We don't have an explanation for why this UTD happened - it is probably
     /// a bug, or a network split between the two homeservers.
@@ -47,6 +52,80 @@ pub enum UtdCause {
     /// data was obtained from an insecure source (imported from a file,
     /// obtained from a legacy (asymmetric) backup, unsafe key forward, etc.)
     UnknownDevice = 4,
+
+    /// We are missing the keys for this event, the event was sent before the
+    /// current device was created, and there is no key backup on the server
+    /// we could retrieve the keys from.
+    ///
+    /// Verifying this device with another one of our devices is the only way
+    /// to get hold of the keys.
+    SentBeforeDeviceExistedWithoutBackup = 5,
+
+    /// We are missing the keys for this event, the event was sent before the
+    /// current device was created, and there is a key backup on the server
+    /// but this device hasn't been configured to use it.
+    ///
+    /// Enabling the key backup on this device, e.g. by entering the recovery
+    /// key, should fix this.
+    HistoricalMessageAndBackupIsDisabled = 6,
+
+    /// We are missing the keys for this event, the event was sent before the
+    /// current device was created, and the key backup is enabled on this
+    /// device.
+    ///
+    /// The key most likely is in the backup but hasn't been downloaded yet,
+    /// waiting should fix this.
+    KeyInBackupNotYetDownloaded = 7,
+
+    /// The sender of the event withheld the keys from us because our device
+    /// is not verified, or because they only share keys with verified
+    /// devices.
+    WithheldForUnverifiedOrInsecureDevice = 8,
+
+    /// The sender of the event deliberately withheld the keys from us, for any
+    /// other reason than our device being unverified (e.g. because we are
+    /// blacklisted, or because they couldn't establish a secure channel with
+    /// our device).
+    WithheldBySender = 9,
+}
+
+/// Information about the state of our own device, and of the key backup,
+/// which is used to refine the [`UtdCause`] of an event.
+#[derive(Clone, Debug)]
+pub struct CryptoContextInfo {
+    /// The (local) time at which the current device was created.
+    pub device_creation_ts: MilliSecondsSinceUnixEpoch,
+
+    /// Whether the key backup is enabled on this device, i.e. we have the
+    /// backup decryption key and are able to download room keys from it.
+    pub is_backup_configured: bool,
+
+    /// Whether a key backup exists on the server, or `None` if we don't know,
+    /// e.g. because the homeserver couldn't be reached.
+    pub backup_exists_on_server: Option<bool>,
+
+    /// The code the sender used when withholding the room key for the event,
+    /// if we received such a notice from them.
+    pub withheld_code: Option<WithheldCode>,
+}
+
+impl CryptoContextInfo {
+    /// Whether a key backup exists on the server as far as we know, to be used
+    /// as [`CryptoContextInfo::backup_exists_on_server`].
+    ///
+    /// A backup which is enabled on this device exists on the server.
+    /// Otherwise, we only know if we asked the server, `last_known` being its
+    /// latest answer, if any.
+    pub fn known_backup_existence(
+        is_backup_configured: bool,
+        last_known: Option<bool>,
+    ) -> Option<bool> {
+        if is_backup_configured {
+            Some(true)
+        } else {
+            last_known
+        }
+    }
 }
 
 /// MSC4115 membership info in the unsigned area.
@@ -69,12 +148,21 @@ impl UtdCause {
     /// Decide the cause of this UTD, based on the evidence we have.
     pub fn determine(
         raw_event: Option<&Raw<AnySyncTimelineEvent>>,
+        crypto_context_info: CryptoContextInfo,
         unable_to_decrypt_info: &UnableToDecryptInfo,
     ) -> Self {
-        // TODO: in future, use more information to give a richer answer. E.g.
         match unable_to_decrypt_info.reason {
             UnableToDecryptReason::MissingMegolmSession
             | UnableToDecryptReason::UnknownMegolmMessageIndex => {
+                // The sender told us they didn't want to share the key with us, this is the
+                // most precise explanation we can get.
+                if let Some(code) = &crypto_context_info.withheld_code {
+                    return match code {
+                        WithheldCode::Unverified => UtdCause::WithheldForUnverifiedOrInsecureDevice,
+                        _ => UtdCause::WithheldBySender,
+                    };
+                }
+
                 // Look in the unsigned area for a `membership` field.
                 if let Some(raw_event) = raw_event {
                     if let Ok(Some(unsigned)) =
@@ -85,7 +173,18 @@ impl UtdCause {
                             return UtdCause::SentBeforeWeJoined;
                         }
                     }
+
+                    // If the event was sent before this device existed, it's a historical
+                    // message and the key backup is our only hope.
+                    if let Ok(Some(origin_server_ts)) =
+                        raw_event.get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts")
+                    {
+                        if origin_server_ts < crypto_context_info.device_creation_ts {
+                            return Self::determine_historical(&crypto_context_info);
+                        }
+                    }
                 }
+
                 UtdCause::Unknown
             }
 
@@ -104,6 +203,20 @@ impl UtdCause {
             _ => UtdCause::Unknown,
         }
     }
+
+    /// Decide the cause of a UTD for an event which was sent before the
+    /// current device was created.
+    fn determine_historical(crypto_context_info: &CryptoContextInfo) -> Self {
+        match crypto_context_info.backup_exists_on_server {
+            Some(false) => UtdCause::SentBeforeDeviceExistedWithoutBackup,
+            Some(true) if !crypto_context_info.is_backup_configured => {
+                UtdCause::HistoricalMessageAndBackupIsDisabled
+            }
+            Some(true) => UtdCause::KeyInBackupNotYetDownloaded,
+            // We can't tell whether the backup could help.
+            None => UtdCause::Unknown,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -111,10 +224,11 @@ mod tests {
     use matrix_sdk_common::deserialized_responses::{
         DeviceLinkProblem, UnableToDecryptInfo, UnableToDecryptReason, VerificationLevel,
     };
-    use ruma::{events::AnySyncTimelineEvent, serde::Raw};
+    use ruma::{events::AnySyncTimelineEvent, serde::Raw, uint, MilliSecondsSinceUnixEpoch};
     use serde_json::{json, value::to_raw_value};
 
-    use crate::types::events::UtdCause;
+    use super::CryptoContextInfo;
+    use crate::types::events::{room_key_withheld::WithheldCode, UtdCause};
 
     #[test]
     fn test_a_missing_raw_event_means_we_guess_unknown() {
@@ -123,6 +237,7 @@ mod tests {
         assert_eq!(
             UtdCause::determine(
                 None,
+                some_crypto_context_info(),
                 &UnableToDecryptInfo {
                     session_id: None,
                     reason: UnableToDecryptReason::MissingMegolmSession,
@@ -138,6 +253,7 @@ mod tests {
         assert_eq!(
             UtdCause::determine(
                 Some(&raw_event(json!({}))),
+                some_crypto_context_info(),
                 &UnableToDecryptInfo {
                     session_id: None,
                     reason: UnableToDecryptReason::MissingMegolmSession
@@ -154,6 +270,7 @@ mod tests {
         assert_eq!(
             UtdCause::determine(
                 Some(&raw_event(json!({ "unsigned": { "membership": 3 } }))),
+                some_crypto_context_info(),
                 &UnableToDecryptInfo {
                     session_id: None,
                     reason: UnableToDecryptReason::MissingMegolmSession
@@ -170,6 +287,7 @@ mod tests {
         assert_eq!(
             UtdCause::determine(
                 Some(&raw_event(json!({ "unsigned": { "membership": "invite" } }),)),
+                some_crypto_context_info(),
                 &UnableToDecryptInfo {
                     session_id: None,
                     reason: UnableToDecryptReason::MissingMegolmSession
@@ -186,6 +304,7 @@ mod tests {
         assert_eq!(
             UtdCause::determine(
                 Some(&raw_event(json!({ "unsigned": { "membership": "join" } }))),
+                some_crypto_context_info(),
                 &UnableToDecryptInfo {
                     session_id: None,
                     reason: UnableToDecryptReason::MissingMegolmSession
@@ -202,6 +321,7 @@ mod tests {
         assert_eq!(
             UtdCause::determine(
                 Some(&raw_event(json!({ "unsigned": { "membership": "leave" } }))),
+                some_crypto_context_info(),
                 &UnableToDecryptInfo {
                     session_id: None,
                     reason: UnableToDecryptReason::MissingMegolmSession
@@ -219,6 +339,7 @@ mod tests {
         assert_eq!(
             UtdCause::determine(
                 Some(&raw_event(json!({ "unsigned": { "membership": "leave" } }))),
+                some_crypto_context_info(),
                 &UnableToDecryptInfo {
                     session_id: None,
                     reason: UnableToDecryptReason::MalformedEncryptedEvent
@@ -236,6 +357,7 @@ mod tests {
                 Some(&raw_event(
                     json!({ "unsigned": { "io.element.msc4115.membership": "leave" } })
                 )),
+                some_crypto_context_info(),
                 &UnableToDecryptInfo {
                     session_id: None,
                     reason: UnableToDecryptReason::MissingMegolmSession
@@ -250,6 +372,7 @@ mod tests {
         assert_eq!(
             UtdCause::determine(
                 Some(&raw_event(json!({}))),
+                some_crypto_context_info(),
                 &UnableToDecryptInfo {
                     session_id: None,
                     reason: UnableToDecryptReason::SenderIdentityNotTrusted(
@@ -266,6 +389,7 @@ mod tests {
         assert_eq!(
             UtdCause::determine(
                 Some(&raw_event(json!({}))),
+                some_crypto_context_info(),
                 &UnableToDecryptInfo {
                     session_id: None,
                     reason: UnableToDecryptReason::SenderIdentityNotTrusted(
@@ -282,6 +406,7 @@ mod tests {
         assert_eq!(
             UtdCause::determine(
                 Some(&raw_event(json!({}))),
+                some_crypto_context_info(),
                 &UnableToDecryptInfo {
                     session_id: None,
                     reason: UnableToDecryptReason::SenderIdentityNotTrusted(
@@ -293,6 +418,162 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_withheld_for_unverified_device_is_reported() {
+        // If the sender withheld the key because our device is unverified, we report
+        // this, no matter what the membership was.
+        assert_eq!(
+            UtdCause::determine(
+                Some(&raw_event(json!({ "unsigned": { "membership": "leave" } }))),
+                CryptoContextInfo {
+                    withheld_code: Some(WithheldCode::Unverified),
+                    ..some_crypto_context_info()
+                },
+                &UnableToDecryptInfo {
+                    session_id: None,
+                    reason: UnableToDecryptReason::MissingMegolmSession
+                }
+            ),
+            UtdCause::WithheldForUnverifiedOrInsecureDevice
+        );
+    }
+
+    #[test]
+    fn test_withheld_for_other_reasons_is_reported() {
+        assert_eq!(
+            UtdCause::determine(
+                Some(&raw_event(json!({}))),
+                CryptoContextInfo {
+                    withheld_code: Some(WithheldCode::Blacklisted),
+                    ..some_crypto_context_info()
+                },
+                &UnableToDecryptInfo {
+                    session_id: None,
+                    reason: UnableToDecryptReason::MissingMegolmSession
+                }
+            ),
+            UtdCause::WithheldBySender
+        );
+    }
+
+    #[test]
+    fn test_historical_message_without_backup_on_server() {
+        // The event was sent before our device was created, and there is no backup.
+        assert_eq!(
+            UtdCause::determine(
+                Some(&raw_event(json!({ "origin_server_ts": 10 }))),
+                CryptoContextInfo {
+                    device_creation_ts: MilliSecondsSinceUnixEpoch(uint!(100)),
+                    is_backup_configured: false,
+                    backup_exists_on_server: Some(false),
+                    withheld_code: None,
+                },
+                &UnableToDecryptInfo {
+                    session_id: None,
+                    reason: UnableToDecryptReason::MissingMegolmSession
+                }
+            ),
+            UtdCause::SentBeforeDeviceExistedWithoutBackup
+        );
+    }
+
+    #[test]
+    fn test_historical_message_with_backup_disabled() {
+        // The event was sent before our device was created, a backup exists but we
+        // haven't enabled it.
+        assert_eq!(
+            UtdCause::determine(
+                Some(&raw_event(json!({ "origin_server_ts": 10 }))),
+                CryptoContextInfo {
+                    device_creation_ts: MilliSecondsSinceUnixEpoch(uint!(100)),
+                    is_backup_configured: false,
+                    backup_exists_on_server: Some(true),
+                    withheld_code: None,
+                },
+                &UnableToDecryptInfo {
+                    session_id: None,
+                    reason: UnableToDecryptReason::MissingMegolmSession
+                }
+            ),
+            UtdCause::HistoricalMessageAndBackupIsDisabled
+        );
+    }
+
+    #[test]
+    fn test_historical_message_with_backup_enabled() {
+        // The event was sent before our device was created and the backup is enabled,
+        // so the key should arrive from the backup.
+        assert_eq!(
+            UtdCause::determine(
+                Some(&raw_event(json!({ "origin_server_ts": 10 }))),
+                CryptoContextInfo {
+                    device_creation_ts: MilliSecondsSinceUnixEpoch(uint!(100)),
+                    is_backup_configured: true,
+                    backup_exists_on_server: Some(true),
+                    withheld_code: None,
+                },
+                &UnableToDecryptInfo {
+                    session_id: None,
+                    reason: UnableToDecryptReason::UnknownMegolmMessageIndex
+                }
+            ),
+            UtdCause::KeyInBackupNotYetDownloaded
+        );
+    }
+
+    #[test]
+    fn test_historical_message_with_unknown_backup_state() {
+        // The event was sent before our device was created, but we couldn't find out
+        // whether a backup exists on the server, so we can't tell more.
+        assert_eq!(
+            UtdCause::determine(
+                Some(&raw_event(json!({ "origin_server_ts": 10 }))),
+                CryptoContextInfo {
+                    device_creation_ts: MilliSecondsSinceUnixEpoch(uint!(100)),
+                    is_backup_configured: false,
+                    backup_exists_on_server: None,
+                    withheld_code: None,
+                },
+                &UnableToDecryptInfo {
+                    session_id: None,
+                    reason: UnableToDecryptReason::MissingMegolmSession
+                }
+            ),
+            UtdCause::Unknown
+        );
+    }
+
+    #[test]
+    fn test_message_sent_after_device_creation_is_not_historical() {
+        // The event was sent after our device was created, so the backup can't
+        // explain why we are missing the key.
+        assert_eq!(
+            UtdCause::determine(
+                Some(&raw_event(json!({ "origin_server_ts": 200 }))),
+                CryptoContextInfo {
+                    device_creation_ts: MilliSecondsSinceUnixEpoch(uint!(100)),
+                    is_backup_configured: false,
+                    backup_exists_on_server: Some(false),
+                    withheld_code: None,
+                },
+                &UnableToDecryptInfo {
+                    session_id: None,
+                    reason: UnableToDecryptReason::MissingMegolmSession
+                }
+            ),
+            UtdCause::Unknown
+        );
+    }
+
+    fn some_crypto_context_info() -> CryptoContextInfo {
+        CryptoContextInfo {
+            device_creation_ts: MilliSecondsSinceUnixEpoch(uint!(42)),
+            is_backup_configured: false,
+            backup_exists_on_server: Some(false),
+            withheld_code: None,
+        }
+    }
+
     fn raw_event(value: serde_json::Value) -> Raw<AnySyncTimelineEvent> {
         Raw::from_json(to_raw_value(&value).unwrap())
     }
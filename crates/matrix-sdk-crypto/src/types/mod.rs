@@ -47,8 +47,9 @@ mod device_keys;
 pub mod events;
 mod one_time_keys;
 pub mod qr_login;
+mod room_history;
 
-pub use self::{backup::*, cross_signing::*, device_keys::*, one_time_keys::*};
+pub use self::{backup::*, cross_signing::*, device_keys::*, one_time_keys::*, room_history::*};
 use crate::store::BackupDecryptionKey;
 
 macro_rules! from_base64 {
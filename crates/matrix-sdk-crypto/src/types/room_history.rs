@@ -0,0 +1,56 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types for sharing the history of a room with the users we invite to it, as
+//! defined in [MSC4268].
+//!
+//! [MSC4268]: https://github.com/matrix-org/matrix-spec-proposals/pull/4268
+
+use ruma::{OwnedDeviceId, OwnedUserId};
+use serde::{Deserialize, Serialize};
+
+use crate::{olm::ExportedRoomKey, types::events::room_key_bundle::RoomKeyBundleContent};
+
+/// A bundle of the keys of a room's history.
+///
+/// It is serialized as JSON, encrypted, and uploaded to the media repository;
+/// its location and encryption key are then sent to the invited user in an
+/// `io.element.msc4268.room_key_bundle` to-device event.
+#[derive(Default, Deserialize, Serialize)]
+#[allow(missing_debug_implementations)]
+pub struct RoomKeyBundle {
+    /// The keys of the room's history.
+    pub room_keys: Vec<ExportedRoomKey>,
+}
+
+impl RoomKeyBundle {
+    /// Whether the bundle doesn't contain any key.
+    pub fn is_empty(&self) -> bool {
+        self.room_keys.is_empty()
+    }
+}
+
+/// The data about a room key bundle that has been sent to us, stored until
+/// we join the room, at which point the bundle can be downloaded and imported.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StoredRoomKeyBundleData {
+    /// The user who sent us the bundle.
+    pub sender_user: OwnedUserId,
+
+    /// The device of the user who sent us the bundle.
+    pub sender_device: OwnedDeviceId,
+
+    /// The content of the to-device event pointing to the bundle.
+    pub bundle_data: RoomKeyBundleContent,
+}
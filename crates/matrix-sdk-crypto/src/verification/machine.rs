@@ -14,7 +14,7 @@
 
 use std::{
     collections::HashMap,
-    sync::{Arc, RwLock as StdRwLock},
+    sync::{Arc, Mutex as StdMutex, RwLock as StdRwLock},
 };
 
 use ruma::{
@@ -32,7 +32,7 @@ use tracing::{debug, info, instrument, trace, warn, Span};
 use super::{
     cache::{RequestInfo, VerificationCache},
     event_enums::{AnyEvent, AnyVerificationContent, OutgoingContent},
-    requests::VerificationRequest,
+    requests::{PendingVerificationRequest, PendingVerificationRequestState, VerificationRequest},
     sas::Sas,
     FlowId, Verification, VerificationResult, VerificationStore,
 };
@@ -49,9 +49,16 @@ pub struct VerificationMachine {
     pub(crate) store: VerificationStore,
     verifications: VerificationCache,
     requests: Arc<StdRwLock<HashMap<OwnedUserId, HashMap<String, VerificationRequest>>>>,
+    /// The verification requests we last persisted, used to avoid needlessly
+    /// writing to the store.
+    saved_requests: Arc<StdMutex<Option<Vec<PendingVerificationRequest>>>>,
 }
 
 impl VerificationMachine {
+    /// The key under which the in-progress verification requests are persisted
+    /// in the store.
+    const PENDING_VERIFICATION_REQUESTS_KEY: &'static str = "pending_verification_requests";
+
     pub(crate) fn new(
         account: StaticAccountData,
         identity: Arc<Mutex<PrivateCrossSigningIdentity>>,
@@ -61,6 +68,7 @@ impl VerificationMachine {
             store: VerificationStore { account, private_identity: identity, inner: store },
             verifications: VerificationCache::new(),
             requests: Default::default(),
+            saved_requests: Default::default(),
         }
     }
 
@@ -202,6 +210,116 @@ impl VerificationMachine {
         user_requests.insert(request.flow_id().as_str().to_owned(), request);
     }
 
+    /// Persist the verification requests which are still in progress, so they
+    /// can be restored using
+    /// [`VerificationMachine::restore_pending_requests()`] after a restart.
+    pub(crate) async fn save_pending_requests(&self) -> Result<(), CryptoStoreError> {
+        let mut pending: Vec<_> = self
+            .requests
+            .read()
+            .unwrap()
+            .values()
+            .flat_map(HashMap::values)
+            .filter_map(VerificationRequest::to_pending)
+            .collect();
+        pending.sort_by(|a, b| a.flow_id.as_str().cmp(b.flow_id.as_str()));
+
+        if self.saved_requests.lock().unwrap().as_ref() == Some(&pending) {
+            return Ok(());
+        }
+
+        let value = serde_json::to_vec(&pending)?;
+        self.store.inner().set_custom_value(Self::PENDING_VERIFICATION_REQUESTS_KEY, value).await?;
+
+        *self.saved_requests.lock().unwrap() = Some(pending);
+
+        Ok(())
+    }
+
+    /// Restore the verification requests which were in progress when the
+    /// client was shut down.
+    ///
+    /// Requests which didn't yet transition into a concrete verification flow
+    /// are resumed. The ephemeral keys of a concrete verification flow, i.e. a
+    /// SAS verification, are only kept in memory, so those flows are cancelled
+    /// with an `m.user` cancel code, letting the other side know that it should
+    /// not wait for us anymore. Requests which are still live in memory are
+    /// left untouched.
+    pub(crate) async fn restore_pending_requests(&self) -> Result<(), CryptoStoreError> {
+        let Some(value) =
+            self.store.inner().get_custom_value(Self::PENDING_VERIFICATION_REQUESTS_KEY).await?
+        else {
+            return Ok(());
+        };
+
+        let pending: Vec<PendingVerificationRequest> = match serde_json::from_slice(&value) {
+            Ok(pending) => pending,
+            Err(e) => {
+                warn!("Couldn't deserialize the persisted verification requests: {e:?}");
+                Vec::new()
+            }
+        };
+
+        for pending in pending {
+            // A flow which is known to this machine is still live, restoring it would
+            // replace, or worse, cancel it.
+            if self.get_request(&pending.other_user_id, pending.flow_id.as_str()).is_some() {
+                debug!(
+                    flow_id = pending.flow_id.as_str(),
+                    "Not restoring a verification request which is still in progress"
+                );
+                continue;
+            }
+
+            let other_device_data = match &pending.other_device_id {
+                Some(device_id) => self.store.get_device(&pending.other_user_id, device_id).await?,
+                None => None,
+            };
+
+            let flow_id = pending.flow_id.as_str().to_owned();
+            let state = pending.state;
+
+            let Some(request) = VerificationRequest::from_pending(
+                self.verifications.clone(),
+                self.store.clone(),
+                pending,
+                other_device_data,
+            ) else {
+                warn!(
+                    flow_id = flow_id.as_str(),
+                    "Couldn't restore a verification request, the other device is gone"
+                );
+                continue;
+            };
+
+            if state == PendingVerificationRequestState::Transitioned {
+                info!(
+                    flow_id = flow_id.as_str(),
+                    "Cancelling a verification flow that was interrupted by a restart"
+                );
+
+                if let Some(r) = request.cancel() {
+                    self.verifications.add_verification_request(r);
+                }
+            } else {
+                debug!(
+                    flow_id = flow_id.as_str(),
+                    ?state,
+                    "Restored a pending verification request"
+                );
+            }
+
+            self.requests
+                .write()
+                .unwrap()
+                .entry(request.other_user().to_owned())
+                .or_default()
+                .insert(flow_id, request);
+        }
+
+        self.save_pending_requests().await
+    }
+
     pub fn get_verification(&self, user_id: &UserId, flow_id: &str) -> Option<Verification> {
         self.verifications.get(user_id, flow_id)
     }
@@ -306,13 +424,24 @@ impl VerificationMachine {
         Ok(())
     }
 
-    #[instrument(skip_all, fields(flow_id))]
     pub async fn receive_any_event(
         &self,
         event: impl Into<AnyEvent<'_>>,
     ) -> Result<(), CryptoStoreError> {
         let event = event.into();
+        let is_verification_event = FlowId::try_from(&event).is_ok();
+
+        self.receive_any_event_helper(event).await?;
 
+        if is_verification_event {
+            self.save_pending_requests().await?;
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(flow_id))]
+    async fn receive_any_event_helper(&self, event: AnyEvent<'_>) -> Result<(), CryptoStoreError> {
         let Ok(flow_id) = FlowId::try_from(&event) else {
             // This isn't a verification event, return early.
             return Ok(());
@@ -540,8 +669,12 @@ impl VerificationMachine {
 mod tests {
     use std::sync::Arc;
 
+    use assert_matches2::{assert_let, assert_matches};
     use matrix_sdk_test::async_test;
-    use ruma::TransactionId;
+    use ruma::{
+        events::{key::verification::cancel::CancelCode, AnyToDeviceEventContent},
+        TransactionId,
+    };
     use tokio::sync::Mutex;
 
     use super::{Sas, VerificationMachine};
@@ -554,7 +687,7 @@ mod tests {
             tests::{alice_device_id, alice_id, setup_stores, wrap_any_to_device_content},
             FlowId, VerificationStore,
         },
-        Account, VerificationRequest,
+        Account, VerificationRequest, VerificationRequestState,
     };
 
     async fn verification_machine() -> (VerificationMachine, VerificationStore) {
@@ -564,6 +697,7 @@ mod tests {
             store,
             verifications: VerificationCache::new(),
             requests: Default::default(),
+            saved_requests: Default::default(),
         };
 
         (machine, bob_store)
@@ -837,4 +971,107 @@ mod tests {
         assert!(!first_request.is_cancelled());
         assert!(!second_request.is_cancelled());
     }
+
+    fn restart(machine: &VerificationMachine) -> VerificationMachine {
+        VerificationMachine {
+            store: machine.store.clone(),
+            verifications: VerificationCache::new(),
+            requests: Default::default(),
+            saved_requests: Default::default(),
+        }
+    }
+
+    async fn receive_request_from_bob(
+        machine: &VerificationMachine,
+        bob_store: VerificationStore,
+    ) -> VerificationRequest {
+        let bob_request = VerificationRequest::new(
+            VerificationCache::new(),
+            bob_store,
+            FlowId::ToDevice(TransactionId::new()),
+            alice_id(),
+            vec![alice_device_id().to_owned()],
+            None,
+        );
+
+        let content: OutgoingContent = bob_request.request_to_device().try_into().unwrap();
+        machine
+            .receive_any_event(&wrap_any_to_device_content(bob_request.own_user_id(), content))
+            .await
+            .unwrap();
+
+        machine.get_request(bob_request.own_user_id(), bob_request.flow_id().as_str()).unwrap()
+    }
+
+    #[async_test]
+    async fn test_pending_request_is_restored() {
+        let (machine, bob_store) = verification_machine().await;
+        let request = receive_request_from_bob(&machine, bob_store).await;
+
+        let machine = restart(&machine);
+        machine.restore_pending_requests().await.unwrap();
+
+        let restored = machine
+            .get_request(request.other_user(), request.flow_id().as_str())
+            .expect("The verification request should have been restored");
+
+        assert_matches!(restored.state(), VerificationRequestState::Requested { .. });
+        assert!(!restored.we_started());
+        assert_eq!(restored.other_device_id(), request.other_device_id());
+        assert!(machine.verifications.outgoing_requests().is_empty());
+
+        // The restored request can be accepted as usual.
+        assert!(restored.accept().is_some());
+        assert!(restored.is_ready());
+    }
+
+    #[async_test]
+    async fn test_interrupted_verification_is_cancelled() {
+        let (machine, bob_store) = verification_machine().await;
+        let request = receive_request_from_bob(&machine, bob_store).await;
+
+        request.accept().unwrap();
+        request.start_sas().await.unwrap().unwrap();
+        machine.save_pending_requests().await.unwrap();
+
+        let machine = restart(&machine);
+        machine.restore_pending_requests().await.unwrap();
+
+        let restored =
+            machine.get_request(request.other_user(), request.flow_id().as_str()).unwrap();
+        assert_eq!(restored.cancel_info().unwrap().cancel_code(), &CancelCode::User);
+
+        // The other side is told that the verification has been cancelled.
+        let outgoing = machine.verifications.outgoing_requests().first().cloned().unwrap();
+        let content = OutgoingContent::try_from(outgoing).unwrap();
+        assert_let!(
+            OutgoingContent::ToDevice(AnyToDeviceEventContent::KeyVerificationCancel(content)) =
+                content
+        );
+        assert_eq!(content.code, CancelCode::User);
+
+        // Cancelled requests aren't persisted anymore.
+        let machine = restart(&machine);
+        machine.restore_pending_requests().await.unwrap();
+        assert!(machine.get_requests(request.other_user()).is_empty());
+    }
+
+    #[async_test]
+    async fn test_live_verification_is_not_cancelled_when_restoring() {
+        let (machine, bob_store) = verification_machine().await;
+        let request = receive_request_from_bob(&machine, bob_store).await;
+
+        request.accept().unwrap();
+        let (sas, _) = request.start_sas().await.unwrap().unwrap();
+        machine.save_pending_requests().await.unwrap();
+        let outgoing_requests = machine.verifications.outgoing_requests().len();
+
+        // The flow is still in progress on this machine, restoring the persisted
+        // requests must not touch it.
+        machine.restore_pending_requests().await.unwrap();
+
+        assert!(!request.is_cancelled());
+        assert!(!sas.is_cancelled());
+        assert_eq!(machine.verifications.outgoing_requests().len(), outgoing_requests);
+    }
 }
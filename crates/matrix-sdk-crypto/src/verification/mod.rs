@@ -46,6 +46,7 @@ use ruma::{
     UserId,
 };
 pub use sas::{AcceptSettings, AcceptedProtocols, EmojiShortAuthString, Sas, SasState};
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 use tracing::{debug, error, info, warn};
 
@@ -404,7 +405,7 @@ impl Cancelled {
 /// A key verification can be requested and started by a to-device
 /// request or a room event. `FlowId` helps to represent both
 /// usecases.
-#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Serialize, Deserialize)]
 pub enum FlowId {
     /// The flow ID comes from a to-device request.
     ToDevice(OwnedTransactionId),
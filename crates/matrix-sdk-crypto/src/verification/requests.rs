@@ -38,6 +38,7 @@ use ruma::{
     DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedUserId, RoomId, TransactionId,
     UserId,
 };
+use serde::{Deserialize, Serialize};
 #[cfg(feature = "qrcode")]
 use tracing::debug;
 use tracing::{info, trace, warn};
@@ -155,10 +156,39 @@ pub struct VerificationRequest {
     other_user_id: OwnedUserId,
     inner: SharedObservable<InnerRequest>,
     creation_time: Arc<Instant>,
+    created_at: MilliSecondsSinceUnixEpoch,
     we_started: bool,
     recipient_devices: Arc<Vec<OwnedDeviceId>>,
 }
 
+/// The state of a [`VerificationRequest`] as it is persisted in the store, so
+/// that the request survives a restart of the client.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PendingVerificationRequest {
+    pub flow_id: FlowId,
+    pub other_user_id: OwnedUserId,
+    pub other_device_id: Option<OwnedDeviceId>,
+    pub we_started: bool,
+    pub recipient_devices: Vec<OwnedDeviceId>,
+    pub our_methods: Option<Vec<VerificationMethod>>,
+    pub their_methods: Option<Vec<VerificationMethod>>,
+    pub state: PendingVerificationRequestState,
+    pub created_at: MilliSecondsSinceUnixEpoch,
+}
+
+/// The persisted counterpart of the states a [`VerificationRequest`] can be in
+/// while it's still in progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum PendingVerificationRequestState {
+    Created,
+    Requested,
+    Ready,
+    /// The request has transitioned into a concrete verification flow. The
+    /// ephemeral keys of the flow only live in memory, so such a request can't
+    /// be resumed.
+    Transitioned,
+}
+
 /// A handle to a request so child verification flows can cancel the request.
 ///
 /// A verification flow can branch off into different types of verification
@@ -217,6 +247,7 @@ impl VerificationRequest {
             inner,
             other_user_id: other_user.into(),
             creation_time: Instant::now().into(),
+            created_at: MilliSecondsSinceUnixEpoch::now(),
             we_started: true,
             recipient_devices: recipient_devices.into(),
         }
@@ -494,10 +525,137 @@ impl VerificationRequest {
             flow_id: flow_id.into(),
             we_started: false,
             creation_time: Instant::now().into(),
+            created_at: MilliSecondsSinceUnixEpoch::now(),
             recipient_devices: vec![].into(),
         }
     }
 
+    /// Restore a verification request from its persisted state.
+    ///
+    /// Returns `None` if the request needs to know about the other device, but
+    /// we don't have the data of the device anymore.
+    ///
+    /// A request which was persisted in the
+    /// [`PendingVerificationRequestState::Transitioned`] state is restored in
+    /// the ready state, it should be cancelled since the concrete verification
+    /// flow can't be resumed.
+    pub(crate) fn from_pending(
+        cache: VerificationCache,
+        store: VerificationStore,
+        pending: PendingVerificationRequest,
+        other_device_data: Option<DeviceData>,
+    ) -> Option<Self> {
+        let account = store.account.clone();
+        let flow_id = Arc::new(pending.flow_id);
+        let other_user_id = pending.other_user_id;
+
+        let inner = match (pending.state, other_device_data) {
+            (PendingVerificationRequestState::Created, _) => InnerRequest::Created(RequestState {
+                verification_cache: cache.clone(),
+                store,
+                flow_id: flow_id.clone(),
+                other_user_id: other_user_id.clone(),
+                state: Created {
+                    our_methods: pending.our_methods.unwrap_or_else(|| SUPPORTED_METHODS.to_vec()),
+                },
+            }),
+            (PendingVerificationRequestState::Requested, Some(other_device_data)) => {
+                InnerRequest::Requested(RequestState {
+                    verification_cache: cache.clone(),
+                    store,
+                    flow_id: flow_id.clone(),
+                    other_user_id: other_user_id.clone(),
+                    state: Requested {
+                        their_methods: pending.their_methods.unwrap_or_default(),
+                        other_device_data,
+                    },
+                })
+            }
+            (
+                PendingVerificationRequestState::Ready
+                | PendingVerificationRequestState::Transitioned,
+                Some(other_device_data),
+            ) => InnerRequest::Ready(RequestState {
+                verification_cache: cache.clone(),
+                store,
+                flow_id: flow_id.clone(),
+                other_user_id: other_user_id.clone(),
+                state: Ready {
+                    their_methods: pending.their_methods.unwrap_or_default(),
+                    our_methods: pending.our_methods.unwrap_or_default(),
+                    other_device_data,
+                },
+            }),
+            (_, None) => return None,
+        };
+
+        // Take the time we spent being shut down into account, otherwise a restored
+        // request would never time out.
+        let elapsed =
+            MilliSecondsSinceUnixEpoch::now().get().saturating_sub(pending.created_at.get());
+        let elapsed = Duration::from_millis(elapsed.into());
+        let creation_time = Instant::now().checked_sub(elapsed).unwrap_or_else(Instant::now);
+
+        Some(Self {
+            verification_cache: cache,
+            account,
+            flow_id,
+            other_user_id,
+            inner: SharedObservable::new(inner),
+            creation_time: creation_time.into(),
+            created_at: pending.created_at,
+            we_started: pending.we_started,
+            recipient_devices: pending.recipient_devices.into(),
+        })
+    }
+
+    /// Get the state of this request that should be persisted in the store.
+    ///
+    /// Returns `None` if the request isn't in progress anymore.
+    pub(crate) fn to_pending(&self) -> Option<PendingVerificationRequest> {
+        let (state, other_device_id, our_methods, their_methods) = match &*self.inner.read() {
+            InnerRequest::Created(s) => (
+                PendingVerificationRequestState::Created,
+                None,
+                Some(s.state.our_methods.clone()),
+                None,
+            ),
+            InnerRequest::Requested(s) => (
+                PendingVerificationRequestState::Requested,
+                Some(s.state.other_device_data.device_id().to_owned()),
+                None,
+                Some(s.state.their_methods.clone()),
+            ),
+            InnerRequest::Ready(s) => (
+                PendingVerificationRequestState::Ready,
+                Some(s.state.other_device_data.device_id().to_owned()),
+                Some(s.state.our_methods.clone()),
+                Some(s.state.their_methods.clone()),
+            ),
+            InnerRequest::Transitioned(s) => (
+                PendingVerificationRequestState::Transitioned,
+                Some(s.state.ready.other_device_data.device_id().to_owned()),
+                Some(s.state.ready.our_methods.clone()),
+                Some(s.state.ready.their_methods.clone()),
+            ),
+            InnerRequest::Passive(_) | InnerRequest::Done(_) | InnerRequest::Cancelled(_) => {
+                return None
+            }
+        };
+
+        Some(PendingVerificationRequest {
+            flow_id: self.flow_id.as_ref().to_owned(),
+            other_user_id: self.other_user_id.clone(),
+            other_device_id,
+            we_started: self.we_started,
+            recipient_devices: self.recipient_devices.to_vec(),
+            our_methods,
+            their_methods,
+            state,
+            created_at: self.created_at,
+        })
+    }
+
     /// Accept the verification request signaling that our client supports the
     /// given verification methods.
     ///
@@ -23,11 +23,12 @@ use gloo_utils::format::JsValueSerdeExt;
 use matrix_sdk_crypto::CryptoStoreError;
 use matrix_sdk_store_encryption::{EncryptedValueBase64, StoreCipher};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::value::RawValue as RawJsonValue;
 use wasm_bindgen::JsValue;
 use web_sys::IdbKeyRange;
 use zeroize::Zeroizing;
 
-use crate::{safe_encode::SafeEncode, IndexeddbCryptoStoreError};
+use crate::{safe_encode::SafeEncode, store_cipher::SharedStoreCipher, IndexeddbCryptoStoreError};
 
 type Result<A, E = IndexeddbCryptoStoreError> = std::result::Result<A, E>;
 
@@ -36,10 +37,10 @@ const BASE64: GeneralPurpose = GeneralPurpose::new(&alphabet::STANDARD, general_
 /// Handles the functionality of serializing and encrypting data for the
 /// indexeddb store.
 pub struct IndexeddbSerializer {
-    store_cipher: Option<Arc<StoreCipher>>,
+    store_cipher: SharedStoreCipher,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum MaybeEncrypted {
     Encrypted(EncryptedValueBase64),
@@ -48,9 +49,20 @@ pub enum MaybeEncrypted {
 
 impl IndexeddbSerializer {
     pub fn new(store_cipher: Option<Arc<StoreCipher>>) -> Self {
+        Self::with_shared_store_cipher(SharedStoreCipher::new(store_cipher))
+    }
+
+    /// Create a serializer using a store cipher which is shared with other
+    /// stores.
+    pub(crate) fn with_shared_store_cipher(store_cipher: SharedStoreCipher) -> Self {
         Self { store_cipher }
     }
 
+    /// The store cipher used by this serializer.
+    pub(crate) fn store_cipher(&self) -> &SharedStoreCipher {
+        &self.store_cipher
+    }
+
     /// Hash the given key securely for the given tablename, using the store
     /// cipher.
     ///
@@ -80,8 +92,8 @@ impl IndexeddbSerializer {
     where
         T: SafeEncode,
     {
-        match &self.store_cipher {
-            Some(cipher) => key.as_secure_string(table_name, cipher),
+        match self.store_cipher.get() {
+            Some(cipher) => key.as_secure_string(table_name, &cipher),
             None => key.as_encoded_string(),
         }
     }
@@ -94,8 +106,8 @@ impl IndexeddbSerializer {
     where
         T: SafeEncode,
     {
-        match &self.store_cipher {
-            Some(cipher) => key.encode_to_range_secure(table_name, cipher),
+        match self.store_cipher.get() {
+            Some(cipher) => key.encode_to_range_secure(table_name, &cipher),
             None => key.encode_to_range(),
         }
         .map_err(|e| IndexeddbCryptoStoreError::DomException {
@@ -130,7 +142,7 @@ impl IndexeddbSerializer {
         &self,
         value: &impl Serialize,
     ) -> Result<Vec<u8>, CryptoStoreError> {
-        match &self.store_cipher {
+        match self.store_cipher.get() {
             Some(cipher) => cipher.encrypt_value(value).map_err(CryptoStoreError::backend),
             None => serde_json::to_vec(value).map_err(CryptoStoreError::backend),
         }
@@ -154,7 +166,7 @@ impl IndexeddbSerializer {
         let serialized = serde_json::to_vec(&value).map_err(CryptoStoreError::backend)?;
 
         // Then either encrypt the JSON, or just base64-encode it.
-        Ok(match &self.store_cipher {
+        Ok(match self.store_cipher.get() {
             Some(cipher) => MaybeEncrypted::Encrypted(
                 cipher.encrypt_value_base64_data(serialized).map_err(CryptoStoreError::backend)?,
             ),
@@ -222,7 +234,15 @@ impl IndexeddbSerializer {
         &self,
         value: JsValue,
     ) -> Result<T, IndexeddbCryptoStoreError> {
-        match &self.store_cipher {
+        self.store_cipher
+            .decrypt(|cipher| Self::deserialize_legacy_value_with(cipher, value.clone()))
+    }
+
+    fn deserialize_legacy_value_with<T: DeserializeOwned>(
+        cipher: Option<&StoreCipher>,
+        value: JsValue,
+    ) -> Result<T, IndexeddbCryptoStoreError> {
+        match cipher {
             Some(cipher) => {
                 if !value.is_array() {
                     return Err(IndexeddbCryptoStoreError::CryptoStoreError(
@@ -262,11 +282,13 @@ impl IndexeddbSerializer {
         &self,
         value: &[u8],
     ) -> Result<T, CryptoStoreError> {
-        if let Some(cipher) = &self.store_cipher {
-            cipher.decrypt_value(value).map_err(CryptoStoreError::backend)
-        } else {
-            serde_json::from_slice(value).map_err(CryptoStoreError::backend)
-        }
+        self.store_cipher.decrypt(|cipher| {
+            if let Some(cipher) = cipher {
+                cipher.decrypt_value(value).map_err(CryptoStoreError::backend)
+            } else {
+                serde_json::from_slice(value).map_err(CryptoStoreError::backend)
+            }
+        })
     }
 
     /// Decode a value that was previously encoded with
@@ -275,20 +297,47 @@ impl IndexeddbSerializer {
         &self,
         value: MaybeEncrypted,
     ) -> Result<T, CryptoStoreError> {
-        // First extract the plaintext JSON, either by decrypting or un-base64-ing.
-        let plaintext = Zeroizing::new(match (&self.store_cipher, value) {
-            (Some(cipher), MaybeEncrypted::Encrypted(enc)) => {
-                cipher.decrypt_value_base64_data(enc).map_err(CryptoStoreError::backend)?
-            }
-            (None, MaybeEncrypted::Unencrypted(unc)) => {
-                BASE64.decode(unc).map_err(CryptoStoreError::backend)?
-            }
+        self.store_cipher.decrypt(|cipher| {
+            // First extract the plaintext JSON, either by decrypting or un-base64-ing.
+            let plaintext = Zeroizing::new(match (cipher, value.clone()) {
+                (Some(cipher), MaybeEncrypted::Encrypted(enc)) => {
+                    cipher.decrypt_value_base64_data(enc).map_err(CryptoStoreError::backend)?
+                }
+                (None, MaybeEncrypted::Unencrypted(unc)) => {
+                    BASE64.decode(unc).map_err(CryptoStoreError::backend)?
+                }
 
-            _ => return Err(CryptoStoreError::UnpicklingError),
-        });
+                _ => return Err(CryptoStoreError::UnpicklingError),
+            });
+
+            // Then deserialize the JSON.
+            Ok(serde_json::from_slice(&plaintext)?)
+        })
+    }
+
+    /// Encrypt a value that was previously encoded with
+    /// [`Self::serialize_value`] again, using the current store cipher.
+    pub fn reencrypt_value(&self, value: JsValue) -> Result<JsValue, IndexeddbCryptoStoreError> {
+        let value: Box<RawJsonValue> = self.deserialize_value(value)?;
+        self.serialize_value(&value)
+    }
 
-        // Then deserialize the JSON.
-        Ok(serde_json::from_slice(&plaintext)?)
+    /// Encrypt a value that was previously encoded with
+    /// [`Self::maybe_encrypt_value`] again, using the current store cipher.
+    pub fn reencrypt_maybe_encrypted_value(
+        &self,
+        value: MaybeEncrypted,
+    ) -> Result<MaybeEncrypted, CryptoStoreError> {
+        let value: Box<RawJsonValue> = self.maybe_decrypt_value(value)?;
+        self.maybe_encrypt_value(value)
+    }
+
+    /// Encrypt a value that was previously encoded with
+    /// [`Self::serialize_value_as_bytes`] again, using the current store
+    /// cipher.
+    pub fn reencrypt_value_bytes(&self, value: &[u8]) -> Result<Vec<u8>, CryptoStoreError> {
+        let value: Box<RawJsonValue> = self.deserialize_value_from_bytes(value)?;
+        self.serialize_value_as_bytes(&value)
     }
 }
 
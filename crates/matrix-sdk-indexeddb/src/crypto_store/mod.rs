@@ -25,10 +25,12 @@ use js_sys::Array;
 use matrix_sdk_crypto::{
     olm::{
         Curve25519PublicKey, InboundGroupSession, OlmMessageHash, OutboundGroupSession,
-        PickledInboundGroupSession, PrivateCrossSigningIdentity, SenderDataType, Session,
-        StaticAccountData,
+        PickledInboundGroupSession, PickledOutboundGroupSession, PickledSession,
+        PrivateCrossSigningIdentity, SenderDataType, Session, StaticAccountData,
     },
     store::{
+        integrity::{StoreEntryKind, UndecodableEntry},
+        pruning::SessionsToRemove,
         BackupKeys, Changes, CryptoStore, CryptoStoreError, PendingChanges, RoomKeyCounts,
         RoomSettings,
     },
@@ -39,7 +41,7 @@ use matrix_sdk_crypto::{
 use matrix_sdk_store_encryption::StoreCipher;
 use ruma::{
     events::secret::request::SecretName, DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
-    RoomId, TransactionId, UserId,
+    OwnedRoomId, RoomId, TransactionId, UserId,
 };
 use sha2::Sha256;
 use tokio::sync::Mutex;
@@ -48,8 +50,9 @@ use wasm_bindgen::JsValue;
 use web_sys::IdbKeyRange;
 
 use self::indexeddb_serializer::MaybeEncrypted;
-use crate::crypto_store::{
-    indexeddb_serializer::IndexeddbSerializer, migrations::open_and_upgrade_db,
+use crate::{
+    crypto_store::{indexeddb_serializer::IndexeddbSerializer, migrations::open_and_upgrade_db},
+    store_cipher::SharedStoreCipher,
 };
 
 mod indexeddb_serializer;
@@ -85,6 +88,20 @@ mod keys {
 
     pub const DIRECT_WITHHELD_INFO: &str = "direct_withheld_info";
 
+    /// The stores whose values are all encoded with
+    /// `IndexeddbSerializer::serialize_value`.
+    pub const SERIALIZED_VALUE_STORES: &[&str] = &[
+        CORE,
+        SESSION,
+        OUTBOUND_GROUP_SESSIONS,
+        DEVICES,
+        IDENTITIES,
+        BACKUP_KEYS,
+        ROOM_SETTINGS,
+        SECRETS_INBOX,
+        DIRECT_WITHHELD_INFO,
+    ];
+
     // keys
     pub const STORE_CIPHER: &str = "store_cipher";
     pub const ACCOUNT: &str = "account";
@@ -112,6 +129,7 @@ mod keys {
 /// [IndexedDB]: https://developer.mozilla.org/en-US/docs/Web/API/IndexedDB_API
 pub struct IndexeddbCryptoStore {
     static_account: RwLock<Option<StaticAccountData>>,
+    prefix: String,
     name: String,
     pub(crate) inner: IdbDatabase,
 
@@ -143,6 +161,10 @@ pub enum IndexeddbCryptoStoreError {
     CryptoStoreError(#[from] CryptoStoreError),
     #[error("The schema version of the crypto store is too new. Existing version: {current_version}; max supported version: {max_supported_version}")]
     SchemaTooNewError { max_supported_version: u32, current_version: u32 },
+    #[error("The crypto store doesn't have a store cipher")]
+    UnencryptedStore,
+    #[error("The store cipher of the crypto store is shared with another store")]
+    SharedStoreCipher,
 }
 
 impl From<web_sys::DomException> for IndexeddbCryptoStoreError {
@@ -260,14 +282,22 @@ impl IndexeddbCryptoStore {
     pub(crate) async fn open_with_store_cipher(
         prefix: &str,
         store_cipher: Option<Arc<StoreCipher>>,
+    ) -> Result<Self> {
+        Self::open_with_shared_store_cipher(prefix, SharedStoreCipher::new(store_cipher)).await
+    }
+
+    pub(crate) async fn open_with_shared_store_cipher(
+        prefix: &str,
+        store_cipher: SharedStoreCipher,
     ) -> Result<Self> {
         let name = format!("{prefix:0}::matrix-sdk-crypto");
 
-        let serializer = IndexeddbSerializer::new(store_cipher);
+        let serializer = IndexeddbSerializer::with_shared_store_cipher(store_cipher);
         debug!("IndexedDbCryptoStore: opening main store {name}");
         let db = open_and_upgrade_db(&name, &serializer).await?;
 
         Ok(Self {
+            prefix: prefix.to_owned(),
             name,
             inner: db,
             serializer,
@@ -383,6 +413,177 @@ impl IndexeddbCryptoStore {
         IndexeddbCryptoStore::open_with_store_cipher(name, None).await
     }
 
+    /// Change the passphrase of this store.
+    ///
+    /// Only the store cipher, which holds the keys that encrypt the data of
+    /// the store, is encrypted again using the new passphrase. The data of the
+    /// store stays encrypted with the same keys.
+    ///
+    /// Use [`IndexeddbCryptoStore::reencrypt`] to also encrypt the data with
+    /// new keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_passphrase` - The passphrase the store was opened with, as passed
+    ///   to [`IndexeddbCryptoStore::open_with_passphrase`].
+    /// * `new_passphrase` - The passphrase that should be used from now on.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<()> {
+        self.update_store_cipher(old_passphrase, new_passphrase, false).await
+    }
+
+    /// Encrypt all the data of this store again with new keys, and protect
+    /// them with a new passphrase.
+    ///
+    /// Values are re-encrypted in a single transaction, while the changes
+    /// being saved by this store wait for it to finish. Other operations can
+    /// keep using the store in the meantime. If this fails, calling it again
+    /// resumes the re-encryption with the same keys.
+    ///
+    /// Returns [`IndexeddbCryptoStoreError::SharedStoreCipher`] if the store
+    /// was opened with [`crate::open_stores_with_name`], use
+    /// [`crate::reencrypt_stores`] instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_passphrase` - The passphrase the store was opened with, as passed
+    ///   to [`IndexeddbCryptoStore::open_with_passphrase`].
+    /// * `new_passphrase` - The passphrase that should be used from now on.
+    pub async fn reencrypt(&self, old_passphrase: &str, new_passphrase: &str) -> Result<()> {
+        self.update_store_cipher(old_passphrase, new_passphrase, true).await
+    }
+
+    /// Save the store cipher in the meta store, encrypted with
+    /// `new_passphrase`, after re-encrypting all the values of the store with
+    /// a new store cipher if `reencrypt` is set.
+    async fn update_store_cipher(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+        reencrypt: bool,
+    ) -> Result<()> {
+        let store_cipher = self.serializer.store_cipher();
+
+        if store_cipher.is_shared() {
+            return Err(IndexeddbCryptoStoreError::SharedStoreCipher);
+        }
+
+        let db = open_meta_db(&self.prefix).await?;
+
+        let result = async {
+            let cipher =
+                load_store_cipher(&db).await?.ok_or(IndexeddbCryptoStoreError::UnencryptedStore)?;
+            let cipher = StoreCipher::import(old_passphrase, &cipher)
+                .map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+            let cipher = if reencrypt {
+                let new_cipher = store_cipher
+                    .begin_rotation()
+                    .map_err(CryptoStoreError::backend)?
+                    .ok_or(IndexeddbCryptoStoreError::UnencryptedStore)?;
+
+                debug!("IndexedDbCryptoStore: re-encrypting the store with a new store cipher");
+                self.reencrypt_values().await?;
+
+                new_cipher
+            } else {
+                Arc::new(cipher)
+            };
+
+            #[cfg(not(test))]
+            let export = cipher.export(new_passphrase);
+            #[cfg(test)]
+            let export = cipher._insecure_export_fast_for_testing(new_passphrase);
+
+            let export = export.map_err(CryptoStoreError::backend)?;
+
+            debug!("IndexedDbCryptoStore: saving store cipher encrypted with the new passphrase");
+            save_store_cipher(&db, &export).await
+        }
+        .await;
+
+        // Must release the database access manually as it's not done when
+        // dropping it.
+        db.close();
+
+        if reencrypt && result.is_ok() {
+            store_cipher.finish_rotation();
+        }
+
+        result
+    }
+
+    /// Whether this store uses the given store cipher.
+    pub(crate) fn uses_store_cipher(&self, store_cipher: &SharedStoreCipher) -> bool {
+        self.serializer.store_cipher().ptr_eq(store_cipher)
+    }
+
+    /// Encrypt all the values of this store again with the current store
+    /// cipher.
+    ///
+    /// Every value is re-encrypted in a single transaction, so either all of
+    /// them are re-encrypted or none.
+    pub(crate) async fn reencrypt_values(&self) -> Result<()> {
+        // Changes are encrypted before the transaction saving them is created,
+        // so wait for the ones in flight: their values are re-encrypted below
+        // if they used the previous cipher.
+        let _guard = self.save_changes_lock.lock().await;
+
+        let stores: Vec<_> = keys::SERIALIZED_VALUE_STORES
+            .iter()
+            .copied()
+            .chain([keys::INBOUND_GROUP_SESSIONS_V3, keys::GOSSIP_REQUESTS])
+            .collect();
+        let tx =
+            self.inner.transaction_on_multi_with_mode(&stores, IdbTransactionMode::Readwrite)?;
+
+        for store_name in keys::SERIALIZED_VALUE_STORES {
+            if let Some(cursor) = tx.object_store(store_name)?.open_cursor()?.await? {
+                loop {
+                    cursor.update(&self.serializer.reencrypt_value(cursor.value())?)?.await?;
+
+                    if !cursor.continue_cursor()?.await? {
+                        break;
+                    }
+                }
+            }
+        }
+
+        if let Some(cursor) =
+            tx.object_store(keys::INBOUND_GROUP_SESSIONS_V3)?.open_cursor()?.await?
+        {
+            loop {
+                let mut idb_object: InboundGroupSessionIndexedDbObject =
+                    serde_wasm_bindgen::from_value(cursor.value())?;
+                idb_object.pickled_session =
+                    self.serializer.reencrypt_maybe_encrypted_value(idb_object.pickled_session)?;
+                cursor.update(&serde_wasm_bindgen::to_value(&idb_object)?)?.await?;
+
+                if !cursor.continue_cursor()?.await? {
+                    break;
+                }
+            }
+        }
+
+        if let Some(cursor) = tx.object_store(keys::GOSSIP_REQUESTS)?.open_cursor()?.await? {
+            loop {
+                let mut idb_object: GossipRequestIndexedDbObject =
+                    serde_wasm_bindgen::from_value(cursor.value())?;
+                idb_object.request = self.serializer.reencrypt_value_bytes(&idb_object.request)?;
+                cursor.update(&serde_wasm_bindgen::to_value(&idb_object)?)?.await?;
+
+                if !cursor.continue_cursor()?.await? {
+                    break;
+                }
+            }
+        }
+
+        Ok(tx.await.into_result()?)
+    }
+
     /// Delete the IndexedDB databases for the given name.
     #[cfg(test)]
     pub fn delete_stores(prefix: &str) -> Result<()> {
@@ -431,6 +632,41 @@ impl IndexeddbCryptoStore {
         Ok(session)
     }
 
+    /// Check that a value of the given kind can be decoded, the same way it
+    /// is when it's loaded from the store.
+    fn check_value(&self, kind: StoreEntryKind, value: JsValue) -> Result<()> {
+        match kind {
+            StoreEntryKind::OlmSession => {
+                self.serializer.deserialize_value::<PickledSession>(value)?;
+            }
+            StoreEntryKind::InboundGroupSession => {
+                self.deserialize_inbound_group_session(value)?;
+            }
+            StoreEntryKind::OutboundGroupSession => {
+                let pickle: PickledOutboundGroupSession =
+                    self.serializer.deserialize_value(value)?;
+
+                if let Some(account_info) = self.get_static_account() {
+                    OutboundGroupSession::from_pickle(
+                        account_info.device_id,
+                        account_info.identity_keys,
+                        pickle,
+                    )
+                    .map_err(CryptoStoreError::from)?;
+                }
+            }
+            StoreEntryKind::Device => {
+                self.serializer.deserialize_value::<DeviceData>(value)?;
+            }
+            StoreEntryKind::UserIdentity => {
+                self.serializer.deserialize_value::<UserIdentityData>(value)?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
     /// Transform a [`GossipRequest`] into a `JsValue` holding a
     /// [`GossipRequestIndexedDbObject`], ready for storing.
     fn serialize_gossip_request(&self, gossip_request: &GossipRequest) -> Result<JsValue> {
@@ -802,6 +1038,31 @@ impl_crypto_store! {
         }
     }
 
+    async fn get_outbound_group_sessions(&self) -> Result<Vec<OutboundGroupSession>> {
+        let account_info = self.get_static_account().ok_or(CryptoStoreError::AccountUnset)?;
+        let values = self
+            .inner
+            .transaction_on_one_with_mode(
+                keys::OUTBOUND_GROUP_SESSIONS,
+                IdbTransactionMode::Readonly,
+            )?
+            .object_store(keys::OUTBOUND_GROUP_SESSIONS)?
+            .get_all()?
+            .await?;
+
+        values
+            .iter()
+            .map(|value| -> Result<_> {
+                Ok(OutboundGroupSession::from_pickle(
+                    account_info.device_id.clone(),
+                    account_info.identity_keys.clone(),
+                    self.serializer.deserialize_value(value)?,
+                )
+                .map_err(CryptoStoreError::from)?)
+            })
+            .collect()
+    }
+
     async fn get_outgoing_secret_requests(
         &self,
         request_id: &TransactionId,
@@ -901,6 +1162,24 @@ impl_crypto_store! {
         }
     }
 
+    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        let device_keys = self.get_own_device().await?.as_device_keys().clone();
+
+        self
+            .inner
+            .transaction_on_one_with_mode(keys::SESSION, IdbTransactionMode::Readonly)?
+            .object_store(keys::SESSION)?
+            .get_all()?
+            .await?
+            .iter()
+            .map(|value| {
+                let pickle = self.serializer.deserialize_value(value)?;
+                Session::from_pickle(device_keys.clone(), pickle)
+                    .map_err(|_| IndexeddbCryptoStoreError::CryptoStoreError(CryptoStoreError::AccountUnset))
+            })
+            .collect()
+    }
+
     async fn get_inbound_group_session(
         &self,
         room_id: &RoomId,
@@ -987,6 +1266,47 @@ impl_crypto_store! {
         Ok(result)
     }
 
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after_session: Option<(OwnedRoomId, String)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>> {
+        // The sessions are returned in the order of their keys. The empty string is
+        // before all keys in Indexed DB - first batch starts there.
+        let after_key = after_session
+            .map(|(room_id, session_id)| {
+                self.serializer.encode_key(keys::INBOUND_GROUP_SESSIONS_V3, (&room_id, &session_id))
+            })
+            .unwrap_or("".into());
+        let range = IdbKeyRange::lower_bound_with_open(&after_key, true).expect("Key was not valid!");
+
+        let tx = self
+            .inner
+            .transaction_on_one_with_mode(
+                keys::INBOUND_GROUP_SESSIONS_V3,
+                IdbTransactionMode::Readonly,
+            )?;
+
+        let object_store = tx.object_store(keys::INBOUND_GROUP_SESSIONS_V3)?;
+        let mut serialized_sessions = Vec::new();
+
+        if let Some(cursor) = object_store.open_cursor_with_range(&range)?.await? {
+            while serialized_sessions.len() < limit {
+                serialized_sessions.push(cursor.value());
+
+                if !cursor.continue_cursor()?.await? {
+                    break;
+                }
+            }
+        }
+
+        // Deserialize and decrypt after the transaction is complete.
+        serialized_sessions
+            .into_iter()
+            .map(|value| self.deserialize_inbound_group_session(value))
+            .collect()
+    }
+
     async fn inbound_group_session_counts(&self, _backup_version: Option<&str>) -> Result<RoomKeyCounts> {
         let tx = self
             .inner
@@ -1397,6 +1717,87 @@ impl_crypto_store! {
             }
         }
     }
+
+    async fn scan_undecodable_entries(&self, remove: bool) -> Result<Vec<UndecodableEntry>> {
+        let stores = [
+            (StoreEntryKind::OlmSession, keys::SESSION),
+            (StoreEntryKind::InboundGroupSession, keys::INBOUND_GROUP_SESSIONS_V3),
+            (StoreEntryKind::OutboundGroupSession, keys::OUTBOUND_GROUP_SESSIONS),
+            (StoreEntryKind::Device, keys::DEVICES),
+            (StoreEntryKind::UserIdentity, keys::IDENTITIES),
+        ];
+        let store_names: Vec<_> = stores.iter().map(|(_, name)| *name).collect();
+        let mode = if remove { IdbTransactionMode::Readwrite } else { IdbTransactionMode::Readonly };
+
+        let tx = self.inner.transaction_on_multi_with_mode(&store_names, mode)?;
+        let mut entries = Vec::new();
+
+        for (kind, store_name) in stores {
+            if let Some(cursor) = tx.object_store(store_name)?.open_cursor()?.await? {
+                loop {
+                    if let Err(e) = self.check_value(kind, cursor.value()) {
+                        entries.push(UndecodableEntry::new(kind, e));
+
+                        if remove {
+                            cursor.delete()?.await?;
+                        }
+                    }
+
+                    if !cursor.continue_cursor()?.await? {
+                        break;
+                    }
+                }
+            }
+        }
+
+        tx.await.into_result()?;
+
+        if !entries.is_empty() {
+            warn!(count = entries.len(), removed = remove, "Found undecodable entries in the store");
+        }
+
+        Ok(entries)
+    }
+
+    async fn remove_sessions(&self, sessions: SessionsToRemove) -> Result<u64> {
+        let keys = sessions
+            .olm_sessions
+            .iter()
+            .map(|(sender_key, session_id)| {
+                (keys::SESSION, self.serializer.encode_key(keys::SESSION, (sender_key, session_id)))
+            })
+            .chain(sessions.inbound_group_sessions.iter().map(|(room_id, session_id)| {
+                (
+                    keys::INBOUND_GROUP_SESSIONS_V3,
+                    self.serializer.encode_key(keys::INBOUND_GROUP_SESSIONS_V3, (room_id, session_id)),
+                )
+            }));
+
+        let tx = self.inner.transaction_on_multi_with_mode(
+            &[keys::SESSION, keys::INBOUND_GROUP_SESSIONS_V3],
+            IdbTransactionMode::Readwrite,
+        )?;
+        let mut reclaimed_bytes = 0;
+
+        for (store_name, key) in keys {
+            let object_store = tx.object_store(store_name)?;
+
+            if let Some(value) = object_store.get(&key)?.await? {
+                // IndexedDB doesn't tell how much space a value takes, its JSON
+                // representation is a good enough estimate.
+                reclaimed_bytes += js_sys::JSON::stringify(&value)
+                    .ok()
+                    .and_then(|json| json.as_string())
+                    .map_or(0, |json| json.len() as u64);
+
+                object_store.delete(&key)?;
+            }
+        }
+
+        tx.await.into_result()?;
+
+        Ok(reclaimed_bytes)
+    }
 }
 
 impl Drop for IndexeddbCryptoStore {
@@ -1969,4 +2370,72 @@ mod encrypted_tests {
             store.load_account().await.expect("Can't load account").expect("Account was not saved");
         assert_eq!(loaded_account.user_id, user_id!("@alice:example.org"));
     }
+
+    #[async_test]
+    async fn test_change_passphrase() {
+        let store_name = "test_change_passphrase";
+
+        IndexeddbCryptoStore::delete_stores(store_name).unwrap();
+        let store = IndexeddbCryptoStore::open_with_passphrase(store_name, "old")
+            .await
+            .expect("Can't create a passphrase-protected store");
+        store.set_custom_value("key", b"value".to_vec()).await.unwrap();
+
+        store
+            .change_passphrase("wrong", "new")
+            .await
+            .expect_err("Changing the passphrase with the wrong passphrase should fail");
+        store.change_passphrase("old", "new").await.expect("Can't change the passphrase");
+
+        IndexeddbCryptoStore::open_with_passphrase(store_name, "old")
+            .await
+            .expect_err("The old passphrase should not work anymore");
+
+        let store = IndexeddbCryptoStore::open_with_passphrase(store_name, "new")
+            .await
+            .expect("Can't open the store with the new passphrase");
+        assert_eq!(store.get_custom_value("key").await.unwrap().as_deref(), Some(&b"value"[..]));
+    }
+
+    #[async_test]
+    async fn test_reencrypt() {
+        let store_name = "test_reencrypt";
+
+        IndexeddbCryptoStore::delete_stores(store_name).unwrap();
+        let store = IndexeddbCryptoStore::open_with_passphrase(store_name, "old")
+            .await
+            .expect("Can't create a passphrase-protected store");
+        let account = Account::with_device_id(user_id!("@alice:example.org"), device_id!("ALICE"));
+        store
+            .save_pending_changes(PendingChanges { account: Some(account) })
+            .await
+            .expect("Can't save account");
+        store.set_custom_value("key", b"value".to_vec()).await.unwrap();
+
+        store
+            .reencrypt("wrong", "new")
+            .await
+            .expect_err("Re-encrypting with the wrong passphrase should fail");
+        store.reencrypt("old", "new").await.expect("Can't re-encrypt the store");
+
+        // The open store keeps working with the new store cipher.
+        assert_eq!(store.get_custom_value("key").await.unwrap().as_deref(), Some(&b"value"[..]));
+        store.set_custom_value("other_key", b"other_value".to_vec()).await.unwrap();
+        drop(store);
+
+        IndexeddbCryptoStore::open_with_passphrase(store_name, "old")
+            .await
+            .expect_err("The old passphrase should not work anymore");
+
+        let store = IndexeddbCryptoStore::open_with_passphrase(store_name, "new")
+            .await
+            .expect("Can't open the store with the new passphrase");
+        let account = store.load_account().await.unwrap().expect("The account should be loaded");
+        assert_eq!(account.user_id, user_id!("@alice:example.org"));
+        assert_eq!(store.get_custom_value("key").await.unwrap().as_deref(), Some(&b"value"[..]));
+        assert_eq!(
+            store.get_custom_value("other_key").await.unwrap().as_deref(),
+            Some(&b"other_value"[..])
+        );
+    }
 }
@@ -11,6 +11,8 @@ mod safe_encode;
 mod serialize_bool_for_indexeddb;
 #[cfg(feature = "state-store")]
 mod state_store;
+#[cfg(any(feature = "e2e-encryption", feature = "state-store"))]
+mod store_cipher;
 
 #[cfg(feature = "e2e-encryption")]
 pub use crypto_store::{IndexeddbCryptoStore, IndexeddbCryptoStoreError};
@@ -34,12 +36,42 @@ pub async fn open_stores_with_name(
 
     let state_store = builder.build().await.map_err(StoreError::from)?;
     let crypto_store =
-        IndexeddbCryptoStore::open_with_store_cipher(name, state_store.store_cipher.clone())
+        IndexeddbCryptoStore::open_with_shared_store_cipher(name, state_store.store_cipher.clone())
             .await?;
 
     Ok((state_store, crypto_store))
 }
 
+/// Encrypt all the data of an [`IndexeddbStateStore`] and an
+/// [`IndexeddbCryptoStore`] again with new keys, and protect them with a new
+/// passphrase.
+///
+/// The stores returned by [`open_stores_with_name`] share their keys, so they
+/// must be re-encrypted together with this function. Other stores are
+/// re-encrypted one after the other with [`IndexeddbStateStore::reencrypt`]
+/// and [`IndexeddbCryptoStore::reencrypt`].
+#[cfg(all(feature = "e2e-encryption", feature = "state-store"))]
+pub async fn reencrypt_stores(
+    state_store: &IndexeddbStateStore,
+    crypto_store: &IndexeddbCryptoStore,
+    old_passphrase: &str,
+    new_passphrase: &str,
+) -> Result<(), OpenStoreError> {
+    if !crypto_store.uses_store_cipher(&state_store.store_cipher) {
+        state_store.reencrypt(old_passphrase, new_passphrase).await.map_err(StoreError::from)?;
+        crypto_store.reencrypt(old_passphrase, new_passphrase).await?;
+
+        return Ok(());
+    }
+
+    state_store.begin_reencryption(old_passphrase).await.map_err(StoreError::from)?;
+    crypto_store.reencrypt_values().await?;
+    state_store.reencrypt_values().await.map_err(StoreError::from)?;
+    state_store.finish_reencryption(new_passphrase).await.map_err(StoreError::from)?;
+
+    Ok(())
+}
+
 /// Create an [`IndexeddbStateStore`].
 ///
 /// If a `passphrase` is given, the store will be encrypted using a key derived
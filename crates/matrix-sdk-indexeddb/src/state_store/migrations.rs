@@ -21,7 +21,8 @@ use gloo_utils::format::JsValueSerdeExt;
 use indexed_db_futures::{prelude::*, request::OpenDbRequest, IdbDatabase, IdbVersionChangeEvent};
 use js_sys::Date as JsDate;
 use matrix_sdk_base::{
-    deserialized_responses::SyncOrStrippedState, store::migration_helpers::RoomInfoV1,
+    deserialized_responses::SyncOrStrippedState,
+    store::{migration_helpers::RoomInfoV1, StoreError},
     StateStoreDataKey,
 };
 use matrix_sdk_store_encryption::StoreCipher;
@@ -137,6 +138,46 @@ pub async fn upgrade_meta_db(
     Ok((meta_db, store_cipher))
 }
 
+/// Load the store cipher saved in the meta database, and decrypt it with the
+/// given passphrase.
+pub async fn import_meta_db_cipher(meta_db: &IdbDatabase, passphrase: &str) -> Result<StoreCipher> {
+    let tx: IdbTransaction<'_> =
+        meta_db.transaction_on_one_with_mode(keys::INTERNAL_STATE, IdbTransactionMode::Readonly)?;
+    let ob = tx.object_store(keys::INTERNAL_STATE)?;
+
+    let Some(StoreKeyWrapper(inner)) =
+        ob.get(&JsValue::from_str(keys::STORE_KEY))?.await?.map(|v| v.into_serde()).transpose()?
+    else {
+        return Err(StoreError::UnencryptedStore.into());
+    };
+
+    Ok(StoreCipher::import(passphrase, &inner)?)
+}
+
+/// Save the store cipher in the meta database, encrypted with the given
+/// passphrase.
+pub async fn save_meta_db_cipher(
+    meta_db: &IdbDatabase,
+    cipher: &StoreCipher,
+    passphrase: &str,
+) -> Result<()> {
+    #[cfg(not(test))]
+    let export = cipher.export(passphrase)?;
+    #[cfg(test)]
+    let export = cipher._insecure_export_fast_for_testing(passphrase)?;
+
+    let tx: IdbTransaction<'_> = meta_db
+        .transaction_on_one_with_mode(keys::INTERNAL_STATE, IdbTransactionMode::Readwrite)?;
+    tx.object_store(keys::INTERNAL_STATE)?.put_key_val(
+        &JsValue::from_str(keys::STORE_KEY),
+        &JsValue::from_serde(&StoreKeyWrapper(export))?,
+    )?;
+
+    tx.await.into_result()?;
+
+    Ok(())
+}
+
 /// Helper struct for upgrading the inner DB.
 #[derive(Debug, Clone, Default)]
 pub struct OngoingMigration {
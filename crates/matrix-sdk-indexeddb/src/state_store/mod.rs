@@ -12,10 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{
-    collections::{BTreeMap, BTreeSet, HashSet},
-    sync::Arc,
-};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -48,6 +45,7 @@ use ruma::{
     OwnedUserId, RoomId, RoomVersionId, TransactionId, UserId,
 };
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::value::RawValue as RawJsonValue;
 use tracing::{debug, warn};
 use wasm_bindgen::JsValue;
 use web_sys::IdbKeyRange;
@@ -55,8 +53,10 @@ use web_sys::IdbKeyRange;
 mod migrations;
 
 pub use self::migrations::MigrationConflictStrategy;
-use self::migrations::{upgrade_inner_db, upgrade_meta_db};
-use crate::safe_encode::SafeEncode;
+use self::migrations::{
+    import_meta_db_cipher, save_meta_db_cipher, upgrade_inner_db, upgrade_meta_db,
+};
+use crate::{safe_encode::SafeEncode, store_cipher::SharedStoreCipher};
 
 #[derive(Debug, thiserror::Error)]
 pub enum IndexeddbStateStoreError {
@@ -70,6 +70,8 @@ pub enum IndexeddbStateStoreError {
     StoreError(#[from] StoreError),
     #[error("Can't migrate {name} from {old_version} to {new_version} without deleting data. See MigrationConflictStrategy for ways to configure.")]
     MigrationConflict { name: String, old_version: u32, new_version: u32 },
+    #[error("The store cipher of the state store is shared with another store")]
+    SharedStoreCipher,
 }
 
 impl From<web_sys::DomException> for IndexeddbStateStoreError {
@@ -252,7 +254,12 @@ impl IndexeddbStateStoreBuilder {
         let inner =
             upgrade_inner_db(&name, store_cipher.as_deref(), migration_strategy, &meta).await?;
 
-        Ok(IndexeddbStateStore { name, inner, meta, store_cipher })
+        Ok(IndexeddbStateStore {
+            name,
+            inner,
+            meta,
+            store_cipher: SharedStoreCipher::new(store_cipher),
+        })
     }
 }
 
@@ -260,7 +267,7 @@ pub struct IndexeddbStateStore {
     name: String,
     pub(crate) inner: IdbDatabase,
     pub(crate) meta: IdbDatabase,
-    pub(crate) store_cipher: Option<Arc<StoreCipher>>,
+    pub(crate) store_cipher: SharedStoreCipher,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -288,6 +295,104 @@ impl IndexeddbStateStore {
         self.meta.version() as u32
     }
 
+    /// Change the passphrase used to encrypt this store.
+    ///
+    /// Only the store cipher, which holds the keys that encrypt the data of
+    /// the store, is encrypted again using the new passphrase. The data of the
+    /// store stays encrypted with the same keys.
+    ///
+    /// Use [`IndexeddbStateStore::reencrypt`] to also encrypt the data with new
+    /// keys.
+    ///
+    /// Returns [`StoreError::UnencryptedStore`] if the store isn't encrypted.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<()> {
+        if self.store_cipher.get().is_none() {
+            return Err(StoreError::UnencryptedStore.into());
+        }
+
+        let cipher = import_meta_db_cipher(&self.meta, old_passphrase).await?;
+        save_meta_db_cipher(&self.meta, &cipher, new_passphrase).await
+    }
+
+    /// Encrypt all the data of this store again with new keys, and protect
+    /// them with a new passphrase.
+    ///
+    /// Values are re-encrypted in a single transaction, and other operations
+    /// can keep using the store in the meantime. If this fails, calling it
+    /// again resumes the re-encryption with the same keys.
+    ///
+    /// Returns [`StoreError::UnencryptedStore`] if the store isn't encrypted,
+    /// and [`IndexeddbStateStoreError::SharedStoreCipher`] if it was opened
+    /// with [`crate::open_stores_with_name`], use [`crate::reencrypt_stores`]
+    /// instead.
+    pub async fn reencrypt(&self, old_passphrase: &str, new_passphrase: &str) -> Result<()> {
+        if self.store_cipher.is_shared() {
+            return Err(IndexeddbStateStoreError::SharedStoreCipher);
+        }
+
+        self.begin_reencryption(old_passphrase).await?;
+        self.reencrypt_values().await?;
+        self.finish_reencryption(new_passphrase).await
+    }
+
+    /// Check the passphrase of the store, and start encrypting new values with
+    /// a new store cipher.
+    pub(crate) async fn begin_reencryption(&self, old_passphrase: &str) -> Result<()> {
+        if self.store_cipher.get().is_none() {
+            return Err(StoreError::UnencryptedStore.into());
+        }
+
+        import_meta_db_cipher(&self.meta, old_passphrase).await?;
+        self.store_cipher.begin_rotation()?;
+
+        Ok(())
+    }
+
+    /// Encrypt all the values of this store again with the current store
+    /// cipher.
+    ///
+    /// Every value is re-encrypted in a single transaction, so either all of
+    /// them are re-encrypted or none. IndexedDB runs the transactions that
+    /// write to the same stores in the order they were created, and values
+    /// are encrypted when the transaction saving them is created or later, so
+    /// no value encrypted with the previous cipher can be saved after this.
+    pub(crate) async fn reencrypt_values(&self) -> Result<()> {
+        // Every store holds values encoded with `serialize_value`.
+        let tx =
+            self.inner.transaction_on_multi_with_mode(ALL_STORES, IdbTransactionMode::Readwrite)?;
+
+        for store_name in ALL_STORES {
+            if let Some(cursor) = tx.object_store(store_name)?.open_cursor()?.await? {
+                loop {
+                    let value: Box<RawJsonValue> = self.deserialize_value(&cursor.value())?;
+                    cursor.update(&self.serialize_value(&value)?)?.await?;
+
+                    if !cursor.continue_cursor()?.await? {
+                        break;
+                    }
+                }
+            }
+        }
+
+        tx.await.into_result()?;
+
+        Ok(())
+    }
+
+    /// Save the new store cipher, encrypted with the new passphrase, once all
+    /// the values were re-encrypted.
+    pub(crate) async fn finish_reencryption(&self, new_passphrase: &str) -> Result<()> {
+        let cipher = self.store_cipher.get().ok_or(StoreError::UnencryptedStore)?;
+        save_meta_db_cipher(&self.meta, &cipher, new_passphrase).await?;
+        self.store_cipher.finish_rotation();
+
+        Ok(())
+    }
+
     /// Whether this database has any migration backups
     pub async fn has_backups(&self) -> Result<bool> {
         Ok(self
@@ -312,26 +417,26 @@ impl IndexeddbStateStore {
 
     /// Encrypt (if needs be) then JSON-serialize a value.
     fn serialize_value(&self, event: &impl Serialize) -> Result<JsValue> {
-        serialize_value(self.store_cipher.as_deref(), event)
+        serialize_value(self.store_cipher.get().as_deref(), event)
     }
 
     /// Deserialize a JSON value and then decrypt it (if needs be).
     fn deserialize_value<T: DeserializeOwned>(&self, event: &JsValue) -> Result<T> {
-        deserialize_value(self.store_cipher.as_deref(), event)
+        self.store_cipher.decrypt(|store_cipher| deserialize_value(store_cipher, event))
     }
 
     fn encode_key<T>(&self, table_name: &str, key: T) -> JsValue
     where
         T: SafeEncode,
     {
-        encode_key(self.store_cipher.as_deref(), table_name, key)
+        encode_key(self.store_cipher.get().as_deref(), table_name, key)
     }
 
     fn encode_to_range<T>(&self, table_name: &str, key: T) -> Result<IdbKeyRange>
     where
         T: SafeEncode,
     {
-        encode_to_range(self.store_cipher.as_deref(), table_name, key)
+        encode_to_range(self.store_cipher.get().as_deref(), table_name, key)
     }
 
     /// Get user IDs for the given room with the given memberships and stripped
@@ -1756,7 +1861,8 @@ mod encrypted_tests {
     #[cfg(target_arch = "wasm32")]
     wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
 
-    use matrix_sdk_base::statestore_integration_tests;
+    use matrix_sdk_base::{statestore_integration_tests, store::StateStore};
+    use matrix_sdk_test::async_test;
     use uuid::Uuid;
 
     use super::{IndexeddbStateStore, Result};
@@ -1768,4 +1874,36 @@ mod encrypted_tests {
     }
 
     statestore_integration_tests!();
+
+    #[async_test]
+    async fn test_reencrypt() {
+        let db_name = format!("test-state-reencrypt-{}", Uuid::new_v4().as_hyphenated());
+        let open_store = |passphrase: &str| {
+            IndexeddbStateStore::builder()
+                .name(db_name.clone())
+                .passphrase(passphrase.to_owned())
+                .build()
+        };
+
+        let store = open_store("old").await.unwrap();
+        store.set_custom_value(b"key", b"value".to_vec()).await.unwrap();
+
+        store.reencrypt("wrong", "new").await.expect_err("The passphrase should be checked");
+        store.reencrypt("old", "new").await.unwrap();
+
+        // The open store keeps working with the new store cipher.
+        assert_eq!(store.get_custom_value(b"key").await.unwrap().as_deref(), Some(&b"value"[..]));
+        store.set_custom_value(b"other_key", b"other_value".to_vec()).await.unwrap();
+        store.inner.close();
+        store.meta.close();
+
+        open_store("old").await.expect_err("The old passphrase should not work anymore");
+
+        let store = open_store("new").await.unwrap();
+        assert_eq!(store.get_custom_value(b"key").await.unwrap().as_deref(), Some(&b"value"[..]));
+        assert_eq!(
+            store.get_custom_value(b"other_key").await.unwrap().as_deref(),
+            Some(&b"other_value"[..])
+        );
+    }
 }
@@ -0,0 +1,107 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, RwLock};
+
+use matrix_sdk_store_encryption::{Error as EncryptionError, StoreCipher};
+
+/// The [`StoreCipher`] of a store, which can be replaced while the store is
+/// open to re-encrypt it with a new key.
+///
+/// Clones share the same cipher, so stores opened with the same cipher, like
+/// the ones from [`crate::open_stores_with_name`], are re-encrypted together.
+#[derive(Clone, Default)]
+pub(crate) struct SharedStoreCipher {
+    inner: Arc<RwLock<Ciphers>>,
+}
+
+#[derive(Default)]
+struct Ciphers {
+    /// The cipher new values are encrypted with.
+    current: Option<Arc<StoreCipher>>,
+
+    /// The cipher the store was encrypted with before the re-encryption that
+    /// is in progress, if any.
+    previous: Option<Arc<StoreCipher>>,
+}
+
+impl SharedStoreCipher {
+    pub fn new(store_cipher: Option<Arc<StoreCipher>>) -> Self {
+        Self { inner: Arc::new(RwLock::new(Ciphers { current: store_cipher, previous: None })) }
+    }
+
+    /// The cipher new values should be encrypted with.
+    pub fn get(&self) -> Option<Arc<StoreCipher>> {
+        self.inner.read().unwrap().current.clone()
+    }
+
+    /// Whether this cipher is used by another store.
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.inner) > 1
+    }
+
+    /// Whether both handles share the same cipher.
+    pub fn ptr_eq(&self, other: &SharedStoreCipher) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+
+    /// Decrypt a value with the current cipher.
+    ///
+    /// While a re-encryption is in progress, the store contains values
+    /// encrypted with both the previous and the current cipher, so the
+    /// previous cipher is tried if the current one fails.
+    pub fn decrypt<T, E>(
+        &self,
+        decrypt: impl Fn(Option<&StoreCipher>) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let (current, previous) = {
+            let ciphers = self.inner.read().unwrap();
+            (ciphers.current.clone(), ciphers.previous.clone())
+        };
+
+        match decrypt(current.as_deref()) {
+            Err(_) if previous.is_some() => decrypt(previous.as_deref()),
+            result => result,
+        }
+    }
+
+    /// Start encrypting new values with a new key, while still decrypting the
+    /// values encrypted with the current one.
+    ///
+    /// If a previous re-encryption didn't finish, its key is reused, so that
+    /// the values it already re-encrypted stay readable.
+    ///
+    /// Returns `None` if the store isn't encrypted.
+    pub fn begin_rotation(&self) -> Result<Option<Arc<StoreCipher>>, EncryptionError> {
+        let mut ciphers = self.inner.write().unwrap();
+
+        if ciphers.previous.is_none() {
+            let Some(current) = ciphers.current.clone() else {
+                return Ok(None);
+            };
+
+            ciphers.current = Some(Arc::new(current.rotate_encryption_key()?));
+            ciphers.previous = Some(current);
+        }
+
+        Ok(ciphers.current.clone())
+    }
+
+    /// Stop decrypting values with the key that was replaced by
+    /// [`SharedStoreCipher::begin_rotation`], once every value was
+    /// re-encrypted.
+    pub fn finish_rotation(&self) {
+        self.inner.write().unwrap().previous = None;
+    }
+}
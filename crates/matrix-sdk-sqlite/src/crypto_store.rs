@@ -25,16 +25,20 @@ use deadpool_sqlite::{Object as SqliteAsyncConn, Pool as SqlitePool, Runtime};
 use matrix_sdk_crypto::{
     olm::{
         InboundGroupSession, OutboundGroupSession, PickledInboundGroupSession,
-        PrivateCrossSigningIdentity, SenderDataType, Session, StaticAccountData,
+        PickledOutboundGroupSession, PickledSession, PrivateCrossSigningIdentity, SenderDataType,
+        Session, StaticAccountData,
+    },
+    store::{
+        integrity::{StoreEntryKind, UndecodableEntry},
+        pruning::SessionsToRemove,
+        BackupKeys, Changes, CryptoStore, PendingChanges, RoomKeyCounts, RoomSettings,
     },
-    store::{BackupKeys, Changes, CryptoStore, PendingChanges, RoomKeyCounts, RoomSettings},
     types::events::room_key_withheld::RoomKeyWithheldEvent,
     Account, DeviceData, GossipRequest, GossippedSecret, SecretInfo, TrackedUser, UserIdentityData,
 };
-use matrix_sdk_store_encryption::StoreCipher;
 use ruma::{
     events::secret::request::SecretName, DeviceId, MilliSecondsSinceUnixEpoch, OwnedDeviceId,
-    RoomId, TransactionId, UserId,
+    OwnedRoomId, RoomId, TransactionId, UserId,
 };
 use rusqlite::{named_params, params_from_iter, OptionalExtension};
 use serde::{de::DeserializeOwned, Serialize};
@@ -45,16 +49,36 @@ use vodozemac::Curve25519PublicKey;
 use crate::{
     error::{Error, Result},
     utils::{
-        repeat_vars, Key, SqliteAsyncConnExt, SqliteKeyValueStoreAsyncConnExt,
-        SqliteKeyValueStoreConnExt,
+        repeat_vars, EncryptedColumn, Key, SharedStoreCipher, SqliteAsyncConnExt, SqliteConn,
+        SqliteKeyValueStoreAsyncConnExt, SqliteKeyValueStoreConnExt,
     },
     OpenStoreError,
 };
 
+/// The columns holding values encrypted with the store cipher.
+const ENCRYPTED_COLUMNS: &[EncryptedColumn] = &[
+    EncryptedColumn {
+        table: "kv",
+        column: "value",
+        condition: "key NOT IN ('version', 'cipher')",
+        has_unencrypted_values: false,
+    },
+    EncryptedColumn::new("session", "data"),
+    EncryptedColumn::new("inbound_group_session", "data"),
+    EncryptedColumn::new("outbound_group_session", "data"),
+    EncryptedColumn::new("device", "data"),
+    EncryptedColumn::new("identity", "data"),
+    EncryptedColumn::new("tracked_user", "data"),
+    EncryptedColumn::new("key_requests", "data"),
+    EncryptedColumn::new("room_settings", "data"),
+    EncryptedColumn::new("direct_withheld_info", "data"),
+    EncryptedColumn::new("secrets", "data"),
+];
+
 /// A sqlite based cryptostore.
 #[derive(Clone)]
 pub struct SqliteCryptoStore {
-    store_cipher: Option<Arc<StoreCipher>>,
+    store_cipher: SharedStoreCipher,
     pool: SqlitePool,
 
     // DB values cached in memory
@@ -94,20 +118,62 @@ impl SqliteCryptoStore {
         let version = conn.db_version().await?;
         run_migrations(&conn, version).await?;
         let store_cipher = match passphrase {
-            Some(p) => Some(Arc::new(conn.get_or_create_store_cipher(p).await?)),
+            Some(p) => Some(conn.get_or_create_store_cipher(p).await?),
             None => None,
         };
 
         Ok(SqliteCryptoStore {
-            store_cipher,
+            store_cipher: SharedStoreCipher::new(store_cipher),
             pool,
             static_account: Arc::new(RwLock::new(None)),
             save_changes_lock: Default::default(),
         })
     }
 
+    /// Change the passphrase used to encrypt this store.
+    ///
+    /// Only the store cipher, which holds the keys that encrypt the data of
+    /// the store, is encrypted again using the new passphrase. This makes this
+    /// a cheap operation, but the data stays encrypted with the same keys. Use
+    /// [`SqliteCryptoStore::reencrypt()`] if those keys might have been
+    /// compromised.
+    ///
+    /// Returns [`OpenStoreError::MissingCipher`] if the store was opened
+    /// without a passphrase.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<(), OpenStoreError> {
+        self.store_cipher.change_passphrase(&self.pool, old_passphrase, new_passphrase, None).await
+    }
+
+    /// Change the passphrase used to encrypt this store, and encrypt all of
+    /// its data again using a new encryption key.
+    ///
+    /// Unlike [`SqliteCryptoStore::change_passphrase()`], this rewrites every
+    /// encrypted value of the store in a single transaction, which can take a
+    /// while for large stores. Keys are hashed and can't be recomputed, so the
+    /// key used to hash them is kept.
+    ///
+    /// The operations in progress on this store and its clones are awaited
+    /// first, and new ones wait for the re-encryption to be done. All of them
+    /// use the new encryption key afterwards.
+    ///
+    /// Returns [`OpenStoreError::MissingCipher`] if the store was opened
+    /// without a passphrase.
+    pub async fn reencrypt(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<(), OpenStoreError> {
+        self.store_cipher
+            .change_passphrase(&self.pool, old_passphrase, new_passphrase, Some(ENCRYPTED_COLUMNS))
+            .await
+    }
+
     fn encode_value(&self, value: Vec<u8>) -> Result<Vec<u8>> {
-        if let Some(key) = &self.store_cipher {
+        if let Some(key) = self.store_cipher.get() {
             let encrypted = key.encrypt_value_data(value)?;
             Ok(rmp_serde::to_vec_named(&encrypted)?)
         } else {
@@ -116,7 +182,7 @@ impl SqliteCryptoStore {
     }
 
     fn decode_value<'a>(&self, value: &'a [u8]) -> Result<Cow<'a, [u8]>> {
-        if let Some(key) = &self.store_cipher {
+        if let Some(key) = self.store_cipher.get() {
             let encrypted = rmp_serde::from_slice(value)?;
             let decrypted = key.decrypt_value_data(encrypted)?;
             Ok(Cow::Owned(decrypted))
@@ -161,6 +227,40 @@ impl SqliteCryptoStore {
         Ok(InboundGroupSession::from_pickle(pickle)?)
     }
 
+    /// Check that a value of the given kind can be decoded, the same way it
+    /// is when it's loaded from the store.
+    fn check_value(&self, kind: StoreEntryKind, value: &[u8]) -> Result<()> {
+        match kind {
+            StoreEntryKind::OlmSession => {
+                self.deserialize_value::<PickledSession>(value)?;
+            }
+            StoreEntryKind::InboundGroupSession => {
+                self.deserialize_and_unpickle_inbound_group_session(value.to_owned(), false)?;
+            }
+            StoreEntryKind::OutboundGroupSession => {
+                let pickle: PickledOutboundGroupSession = self.deserialize_json(value)?;
+
+                if let Some(account_info) = self.get_static_account() {
+                    OutboundGroupSession::from_pickle(
+                        account_info.device_id,
+                        account_info.identity_keys,
+                        pickle,
+                    )
+                    .map_err(|_| Error::Unpickle)?;
+                }
+            }
+            StoreEntryKind::Device => {
+                self.deserialize_value::<DeviceData>(value)?;
+            }
+            StoreEntryKind::UserIdentity => {
+                self.deserialize_value::<UserIdentityData>(value)?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
     fn deserialize_key_request(&self, value: &[u8], sent_out: bool) -> Result<GossipRequest> {
         let mut request: GossipRequest = self.deserialize_value(value)?;
         // sent_out SQL column is source of truth, sent_out field in serialized value
@@ -171,7 +271,7 @@ impl SqliteCryptoStore {
 
     fn encode_key(&self, table_name: &str, key: impl AsRef<[u8]>) -> Key {
         let bytes = key.as_ref();
-        if let Some(store_cipher) = &self.store_cipher {
+        if let Some(store_cipher) = self.store_cipher.get() {
             Key::Hashed(store_cipher.hash_key(table_name, bytes))
         } else {
             Key::Plain(bytes.to_owned())
@@ -182,8 +282,8 @@ impl SqliteCryptoStore {
         self.static_account.read().unwrap().clone()
     }
 
-    async fn acquire(&self) -> Result<SqliteAsyncConn> {
-        Ok(self.pool.get().await?)
+    async fn acquire(&self) -> Result<SqliteConn> {
+        Ok(self.store_cipher.acquire(&self.pool).await?)
     }
 }
 
@@ -544,6 +644,36 @@ trait SqliteObjectCryptoStoreExt: SqliteAsyncConnExt {
             .await?)
     }
 
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after_session_id: Option<Key>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, bool)>> {
+        Ok(self
+            .prepare(
+                "
+                SELECT data, backed_up
+                FROM inbound_group_session
+                WHERE session_id > :after_session_id
+                ORDER BY session_id
+                LIMIT :limit
+                ",
+                move |mut stmt| {
+                    // If we are not provided with an `after_session_id`, use a key which will sort
+                    // before all real keys: the empty string.
+                    let after_session_id = after_session_id.unwrap_or(Key::Plain(Vec::new()));
+
+                    stmt.query(named_params! {
+                        ":after_session_id": after_session_id,
+                        ":limit": limit,
+                    })?
+                    .mapped(|row| Ok((row.get(0)?, row.get(1)?)))
+                    .collect()
+                },
+            )
+            .await?)
+    }
+
     async fn get_inbound_group_sessions_for_backup(&self, limit: usize) -> Result<Vec<Vec<u8>>> {
         Ok(self
             .prepare(
@@ -590,6 +720,14 @@ trait SqliteObjectCryptoStoreExt: SqliteAsyncConnExt {
             .optional()?)
     }
 
+    async fn get_outbound_group_sessions(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .prepare("SELECT data FROM outbound_group_session", |mut stmt| {
+                stmt.query(())?.mapped(|row| row.get(0)).collect()
+            })
+            .await?)
+    }
+
     async fn get_device(&self, user_id: Key, device_id: Key) -> Result<Option<Vec<u8>>> {
         Ok(self
             .query_row(
@@ -721,6 +859,61 @@ trait SqliteObjectCryptoStoreExt: SqliteAsyncConnExt {
             .await
             .optional()?)
     }
+
+    async fn get_rows_batch(
+        &self,
+        table: &'static str,
+        after_row_id: i64,
+        limit: usize,
+    ) -> Result<Vec<(i64, Vec<u8>)>> {
+        Ok(self
+            .prepare(
+                format!("SELECT rowid, data FROM {table} WHERE rowid > ? ORDER BY rowid LIMIT ?"),
+                move |mut stmt| {
+                    stmt.query((after_row_id, limit))?
+                        .mapped(|row| Ok((row.get(0)?, row.get(1)?)))
+                        .collect()
+                },
+            )
+            .await?)
+    }
+
+    async fn delete_rows(&self, table: &'static str, row_ids: Vec<i64>) -> Result<()> {
+        self.with_transaction(move |txn| {
+            let mut stmt = txn.prepare(&format!("DELETE FROM {table} WHERE rowid = ?"))?;
+
+            for row_id in row_ids {
+                stmt.execute((row_id,))?;
+            }
+
+            Ok::<_, Error>(())
+        })
+        .await
+    }
+
+    /// Delete the sessions with the given IDs from the given table, returning
+    /// the size of their data.
+    async fn delete_sessions(&self, table: &'static str, session_ids: Vec<Key>) -> Result<u64> {
+        self.with_transaction(move |txn| {
+            let mut select =
+                txn.prepare(&format!("SELECT length(data) FROM {table} WHERE session_id = ?"))?;
+            let mut delete = txn.prepare(&format!("DELETE FROM {table} WHERE session_id = ?"))?;
+            let mut reclaimed_bytes = 0;
+
+            for session_id in session_ids {
+                let size: Option<i64> =
+                    select.query_row((&session_id,), |row| row.get(0)).optional()?;
+
+                if let Some(size) = size {
+                    delete.execute((&session_id,))?;
+                    reclaimed_bytes += size as u64;
+                }
+            }
+
+            Ok::<_, Error>(reclaimed_bytes)
+        })
+        .await
+    }
 }
 
 #[async_trait]
@@ -970,6 +1163,21 @@ impl CryptoStore for SqliteCryptoStore {
         }
     }
 
+    async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        let device_keys = self.get_own_device().await?.as_device_keys().clone();
+
+        self.acquire()
+            .await?
+            .get_all_rows("session")
+            .await?
+            .into_iter()
+            .map(|(_, bytes)| {
+                let pickle = self.deserialize_value(&bytes)?;
+                Session::from_pickle(device_keys.clone(), pickle).map_err(|_| Error::AccountUnset)
+            })
+            .collect()
+    }
+
     #[instrument(skip(self))]
     async fn get_inbound_group_session(
         &self,
@@ -1031,6 +1239,26 @@ impl CryptoStore for SqliteCryptoStore {
             .collect()
     }
 
+    async fn get_inbound_group_sessions_batch(
+        &self,
+        after_session: Option<(OwnedRoomId, String)>,
+        limit: usize,
+    ) -> Result<Vec<InboundGroupSession>, Self::Error> {
+        // The sessions are ordered by session ID, the room ID isn't needed.
+        let after_session_id = after_session
+            .map(|(_, session_id)| self.encode_key("inbound_group_session", session_id));
+
+        self.acquire()
+            .await?
+            .get_inbound_group_sessions_batch(after_session_id, limit)
+            .await?
+            .into_iter()
+            .map(|(value, backed_up)| {
+                self.deserialize_and_unpickle_inbound_group_session(value, backed_up)
+            })
+            .collect()
+    }
+
     async fn inbound_group_session_counts(
         &self,
         backup_version: Option<&str>,
@@ -1096,7 +1324,8 @@ impl CryptoStore for SqliteCryptoStore {
         room_id: &RoomId,
     ) -> Result<Option<OutboundGroupSession>> {
         let room_id = self.encode_key("outbound_group_session", room_id.as_bytes());
-        let Some(value) = self.acquire().await?.get_outbound_group_session(room_id).await? else {
+        let conn = self.acquire().await?;
+        let Some(value) = conn.get_outbound_group_session(room_id).await? else {
             return Ok(None);
         };
 
@@ -1113,6 +1342,30 @@ impl CryptoStore for SqliteCryptoStore {
         return Ok(Some(session));
     }
 
+    async fn get_outbound_group_sessions(&self) -> Result<Vec<OutboundGroupSession>> {
+        let values = self.acquire().await?.get_outbound_group_sessions().await?;
+
+        if values.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let account_info = self.get_static_account().ok_or(Error::AccountUnset)?;
+
+        values
+            .iter()
+            .map(|value| {
+                let pickle = self.deserialize_json(value)?;
+
+                OutboundGroupSession::from_pickle(
+                    account_info.device_id.clone(),
+                    account_info.identity_keys.clone(),
+                    pickle,
+                )
+                .map_err(|_| Error::Unpickle)
+            })
+            .collect()
+    }
+
     async fn load_tracked_users(&self) -> Result<Vec<TrackedUser>> {
         self.acquire()
             .await?
@@ -1124,6 +1377,7 @@ impl CryptoStore for SqliteCryptoStore {
     }
 
     async fn save_tracked_users(&self, tracked_users: &[(&UserId, bool)]) -> Result<()> {
+        let conn = self.acquire().await?;
         let users: Vec<(Key, Vec<u8>)> = tracked_users
             .iter()
             .map(|(u, d)| {
@@ -1134,7 +1388,7 @@ impl CryptoStore for SqliteCryptoStore {
             })
             .collect::<Result<_>>()?;
 
-        Ok(self.acquire().await?.add_tracked_users(users).await?)
+        Ok(conn.add_tracked_users(users).await?)
     }
 
     async fn get_device(
@@ -1216,7 +1470,8 @@ impl CryptoStore for SqliteCryptoStore {
         &self,
         key_info: &SecretInfo,
     ) -> Result<Option<GossipRequest>> {
-        let requests = self.acquire().await?.get_outgoing_secret_requests().await?;
+        let conn = self.acquire().await?;
+        let requests = conn.get_outgoing_secret_requests().await?;
         for (request, sent_out) in requests {
             let request = self.deserialize_key_request(&request, sent_out)?;
             if request.info == *key_info {
@@ -1285,7 +1540,8 @@ impl CryptoStore for SqliteCryptoStore {
 
     async fn get_room_settings(&self, room_id: &RoomId) -> Result<Option<RoomSettings>> {
         let room_id = self.encode_key("room_settings", room_id.as_bytes());
-        let Some(value) = self.acquire().await?.get_room_settings(room_id).await? else {
+        let conn = self.acquire().await?;
+        let Some(value) = conn.get_room_settings(room_id).await? else {
             return Ok(None);
         };
 
@@ -1295,10 +1551,11 @@ impl CryptoStore for SqliteCryptoStore {
     }
 
     async fn get_custom_value(&self, key: &str) -> Result<Option<Vec<u8>>> {
-        let Some(serialized) = self.acquire().await?.get_kv(key).await? else {
+        let conn = self.acquire().await?;
+        let Some(serialized) = conn.get_kv(key).await? else {
             return Ok(None);
         };
-        let value = if let Some(cipher) = &self.store_cipher {
+        let value = if let Some(cipher) = self.store_cipher.get() {
             let encrypted = rmp_serde::from_slice(&serialized)?;
             cipher.decrypt_value_data(encrypted)?
         } else {
@@ -1309,14 +1566,15 @@ impl CryptoStore for SqliteCryptoStore {
     }
 
     async fn set_custom_value(&self, key: &str, value: Vec<u8>) -> Result<()> {
-        let serialized = if let Some(cipher) = &self.store_cipher {
+        let conn = self.acquire().await?;
+        let serialized = if let Some(cipher) = self.store_cipher.get() {
             let encrypted = cipher.encrypt_value_data(value)?;
             rmp_serde::to_vec_named(&encrypted)?
         } else {
             value
         };
 
-        self.acquire().await?.set_kv(key, serialized).await?;
+        conn.set_kv(key, serialized).await?;
         Ok(())
     }
 
@@ -1363,6 +1621,80 @@ impl CryptoStore for SqliteCryptoStore {
         Ok(num_touched == 1)
     }
 
+    async fn scan_undecodable_entries(&self, remove: bool) -> Result<Vec<UndecodableEntry>> {
+        const TABLES: [(StoreEntryKind, &str); 5] = [
+            (StoreEntryKind::OlmSession, "session"),
+            (StoreEntryKind::InboundGroupSession, "inbound_group_session"),
+            (StoreEntryKind::OutboundGroupSession, "outbound_group_session"),
+            (StoreEntryKind::Device, "device"),
+            (StoreEntryKind::UserIdentity, "identity"),
+        ];
+        const SCAN_BATCH_SIZE: usize = 1000;
+
+        // Don't let a concurrent `save_changes` call write an entry we are about to
+        // remove.
+        let _guard = self.save_changes_lock.lock().await;
+        let conn = self.acquire().await?;
+        let mut entries = Vec::new();
+
+        for (kind, table) in TABLES {
+            let mut undecodable_row_ids = Vec::new();
+            let mut after_row_id = i64::MIN;
+
+            // Go through the table in batches, so we don't load all of it in memory.
+            loop {
+                let rows = conn.get_rows_batch(table, after_row_id, SCAN_BATCH_SIZE).await?;
+
+                let Some((last_row_id, _)) = rows.last() else {
+                    break;
+                };
+                after_row_id = *last_row_id;
+
+                for (row_id, value) in rows {
+                    if let Err(e) = self.check_value(kind, &value) {
+                        undecodable_row_ids.push(row_id);
+                        entries.push(UndecodableEntry::new(kind, e));
+                    }
+                }
+            }
+
+            if remove && !undecodable_row_ids.is_empty() {
+                conn.delete_rows(table, undecodable_row_ids).await?;
+            }
+        }
+
+        if !entries.is_empty() {
+            warn!(
+                count = entries.len(),
+                removed = remove,
+                "Found undecodable entries in the store"
+            );
+        }
+
+        Ok(entries)
+    }
+
+    async fn remove_sessions(&self, sessions: SessionsToRemove) -> Result<u64> {
+        let olm_session_ids = sessions
+            .olm_sessions
+            .iter()
+            .map(|(_, session_id)| self.encode_key("session", session_id))
+            .collect();
+        let inbound_group_session_ids = sessions
+            .inbound_group_sessions
+            .iter()
+            .map(|(_, session_id)| self.encode_key("inbound_group_session", session_id))
+            .collect();
+
+        let _guard = self.save_changes_lock.lock().await;
+        let conn = self.acquire().await?;
+
+        let reclaimed_bytes = conn.delete_sessions("session", olm_session_ids).await?
+            + conn.delete_sessions("inbound_group_session", inbound_group_session_ids).await?;
+
+        Ok(reclaimed_bytes)
+    }
+
     async fn next_batch_token(&self) -> Result<Option<String>, Self::Error> {
         let conn = self.acquire().await?;
         if let Some(token) = conn.get_kv("next_batch_token").await? {
@@ -1379,15 +1711,22 @@ mod tests {
     use std::path::PathBuf;
 
     use matrix_sdk_crypto::{
-        cryptostore_integration_tests, cryptostore_integration_tests_time, store::CryptoStore,
+        cryptostore_integration_tests, cryptostore_integration_tests_time,
+        store::{
+            integrity::StoreEntryKind, migration::migrate_crypto_store, Changes, CryptoStore,
+            DeviceChanges, IntoCryptoStore, MemoryStore, PendingChanges, RoomSettings,
+        },
+        Account, DeviceData,
     };
     use matrix_sdk_test::async_test;
     use once_cell::sync::Lazy;
+    use ruma::{device_id, room_id, user_id};
     use similar_asserts::assert_eq;
     use tempfile::{tempdir, TempDir};
     use tokio::fs;
 
     use super::SqliteCryptoStore;
+    use crate::utils::SqliteAsyncConnExt;
 
     static TMP_DIR: Lazy<TempDir> = Lazy::new(|| tempdir().unwrap());
 
@@ -1483,6 +1822,108 @@ mod tests {
         assert_eq!(master_key.to_base64(), "iCUEtB1RwANeqRa5epDrblLk4mer/36sylwQ5hYY3oE");
     }
 
+    #[async_test]
+    async fn test_migrate_into_memory_store() {
+        let dir = tempdir().unwrap();
+        let store = SqliteCryptoStore::open(dir.path(), Some("secret")).await.unwrap();
+
+        let account =
+            Account::with_device_id(user_id!("@alice:localhost"), device_id!("ALICEDEVICE"));
+        store
+            .save_pending_changes(PendingChanges { account: Some(account.deep_clone()) })
+            .await
+            .unwrap();
+
+        let room_id = room_id!("!test:localhost");
+        let mut inbound_group_sessions = Vec::new();
+        let mut outbound_group_sessions = Vec::new();
+
+        for _ in 0..3 {
+            let (outbound, inbound) =
+                account.create_group_session_pair_with_defaults(room_id).await;
+            inbound_group_sessions.push(inbound);
+            outbound_group_sessions = vec![outbound];
+        }
+
+        // An encrypted room we don't have any room key for.
+        let other_room_id = room_id!("!other:localhost");
+        let settings = RoomSettings { only_allow_trusted_devices: true, ..Default::default() };
+
+        store
+            .save_changes(Changes {
+                inbound_group_sessions,
+                outbound_group_sessions,
+                room_settings: [(other_room_id.to_owned(), settings.clone())].into(),
+                devices: DeviceChanges {
+                    new: vec![DeviceData::from_account(&account)],
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let source = store.into_crypto_store();
+        let target = MemoryStore::new().into_crypto_store();
+
+        let report = migrate_crypto_store(&*source, &*target, &[], &[other_room_id], |_| {})
+            .await
+            .expect("We should be able to migrate the SQLite store");
+
+        assert_eq!(report.devices, 1);
+        assert_eq!(report.inbound_group_sessions, 3);
+        assert_eq!(report.outbound_group_sessions, 1);
+        assert_eq!(report.room_settings, 1);
+
+        let migrated_account = target.load_account().await.unwrap().unwrap();
+        assert_eq!(migrated_account.identity_keys().curve25519, account.identity_keys().curve25519);
+        assert_eq!(target.inbound_group_session_counts(None).await.unwrap().total, 3);
+        assert!(target.get_outbound_group_session(room_id).await.unwrap().is_some());
+        assert_eq!(target.get_room_settings(other_room_id).await.unwrap(), Some(settings));
+    }
+
+    #[async_test]
+    async fn test_scan_undecodable_entries() {
+        let TestDb { dir: _, database } = get_test_db().await;
+        database.load_account().await.unwrap();
+
+        assert!(database.scan_undecodable_entries(false).await.unwrap().is_empty());
+
+        let device_count = count_devices(&database).await;
+
+        // Corrupt a single device, `C0` is a MessagePack encoded `nil`.
+        database
+            .acquire()
+            .await
+            .unwrap()
+            .execute(
+                "UPDATE device SET data = X'C0' WHERE rowid = (SELECT MAX(rowid) FROM device)",
+                (),
+            )
+            .await
+            .unwrap();
+
+        let entries = database.scan_undecodable_entries(false).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].kind, StoreEntryKind::Device);
+        assert_eq!(count_devices(&database).await, device_count);
+
+        // Once removed, the entry isn't found anymore.
+        assert_eq!(database.scan_undecodable_entries(true).await.unwrap().len(), 1);
+        assert!(database.scan_undecodable_entries(false).await.unwrap().is_empty());
+        assert_eq!(count_devices(&database).await, device_count - 1);
+    }
+
+    async fn count_devices(database: &SqliteCryptoStore) -> i64 {
+        database
+            .acquire()
+            .await
+            .unwrap()
+            .query_row("SELECT count(*) FROM device", (), |row| row.get(0))
+            .await
+            .unwrap()
+    }
+
     async fn get_store(
         name: &str,
         passphrase: Option<&str>,
@@ -1505,12 +1946,18 @@ mod tests {
 
 #[cfg(test)]
 mod encrypted_tests {
-    use matrix_sdk_crypto::{cryptostore_integration_tests, cryptostore_integration_tests_time};
+    use assert_matches::assert_matches;
+    use matrix_sdk_crypto::{
+        cryptostore_integration_tests, cryptostore_integration_tests_time, store::CryptoStore,
+    };
+    use matrix_sdk_test::async_test;
     use once_cell::sync::Lazy;
+    use ruma::user_id;
     use tempfile::{tempdir, TempDir};
     use tokio::fs;
 
     use super::SqliteCryptoStore;
+    use crate::OpenStoreError;
 
     static TMP_DIR: Lazy<TempDir> = Lazy::new(|| tempdir().unwrap());
 
@@ -1531,6 +1978,56 @@ mod encrypted_tests {
             .expect("Can't create a passphrase protected store")
     }
 
+    #[async_test]
+    async fn test_change_passphrase() {
+        let path = TMP_DIR.path().join("change_passphrase");
+        let store = SqliteCryptoStore::open(&path, Some("old")).await.unwrap();
+        store.set_custom_value("key", b"value".to_vec()).await.unwrap();
+
+        assert_matches!(
+            store.change_passphrase("wrong", "new").await,
+            Err(OpenStoreError::InitCipher(_))
+        );
+        store.change_passphrase("old", "new").await.unwrap();
+
+        // The store keeps working with the same keys.
+        assert_eq!(store.get_custom_value("key").await.unwrap().as_deref(), Some(&b"value"[..]));
+        drop(store);
+
+        assert!(SqliteCryptoStore::open(&path, Some("old")).await.is_err());
+
+        let store = SqliteCryptoStore::open(&path, Some("new")).await.unwrap();
+        assert_eq!(store.get_custom_value("key").await.unwrap().as_deref(), Some(&b"value"[..]));
+    }
+
+    #[async_test]
+    async fn test_reencrypt() {
+        let path = TMP_DIR.path().join("reencrypt");
+        let store = SqliteCryptoStore::open(&path, Some("old")).await.unwrap();
+        let clone = store.clone();
+        store.set_custom_value("key", b"value".to_vec()).await.unwrap();
+        store.save_tracked_users(&[(user_id!("@alice:localhost"), true)]).await.unwrap();
+
+        assert_matches!(store.reencrypt("wrong", "new").await, Err(OpenStoreError::InitCipher(_)));
+        store.reencrypt("old", "new").await.unwrap();
+        assert_eq!(store.get_custom_value("key").await.unwrap().as_deref(), Some(&b"value"[..]));
+
+        // The clones of the store use the new encryption key too.
+        clone.set_custom_value("other_key", b"other_value".to_vec()).await.unwrap();
+        drop(store);
+        drop(clone);
+
+        assert!(SqliteCryptoStore::open(&path, Some("old")).await.is_err());
+
+        let store = SqliteCryptoStore::open(&path, Some("new")).await.unwrap();
+        assert_eq!(store.get_custom_value("key").await.unwrap().as_deref(), Some(&b"value"[..]));
+        assert_eq!(
+            store.get_custom_value("other_key").await.unwrap().as_deref(),
+            Some(&b"other_value"[..])
+        );
+        assert_eq!(store.load_tracked_users().await.unwrap().len(), 1);
+    }
+
     cryptostore_integration_tests!();
     cryptostore_integration_tests_time!();
 }
@@ -65,6 +65,14 @@ pub enum OpenStoreError {
     /// Failed to save the store cipher to the DB.
     #[error("Failed to save the store cipher to the DB")]
     SaveCipher(#[source] rusqlite::Error),
+
+    /// The database isn't encrypted, so its passphrase can't be changed.
+    #[error("The database doesn't have a store cipher")]
+    MissingCipher,
+
+    /// Failed to re-encrypt the values of the DB with a new store cipher.
+    #[error("Failed to re-encrypt the database")]
+    ReEncrypt(#[source] Error),
 }
 
 #[derive(Debug, Error)]
@@ -1,4 +1,4 @@
-use std::{borrow::Cow, fmt, path::Path, sync::Arc};
+use std::{borrow::Cow, fmt, path::Path};
 
 use async_trait::async_trait;
 use deadpool_sqlite::{Object as SqliteAsyncConn, Pool as SqlitePool, Runtime};
@@ -6,14 +6,16 @@ use matrix_sdk_base::{
     event_cache_store::EventCacheStore,
     media::{MediaRequest, UniqueKey},
 };
-use matrix_sdk_store_encryption::StoreCipher;
 use rusqlite::OptionalExtension;
 use tokio::fs;
 use tracing::debug;
 
 use crate::{
     error::{Error, Result},
-    utils::{Key, SqliteAsyncConnExt, SqliteKeyValueStoreAsyncConnExt, SqliteKeyValueStoreConnExt},
+    utils::{
+        EncryptedColumn, Key, SharedStoreCipher, SqliteAsyncConnExt, SqliteConn,
+        SqliteKeyValueStoreAsyncConnExt, SqliteKeyValueStoreConnExt,
+    },
     OpenStoreError,
 };
 
@@ -29,10 +31,13 @@ mod keys {
 /// the [`SqliteEventCacheStore::run_migrations`] function.
 const DATABASE_VERSION: u8 = 1;
 
+/// The columns holding values encrypted with the store cipher.
+const ENCRYPTED_COLUMNS: &[EncryptedColumn] = &[EncryptedColumn::new(keys::MEDIA, "data")];
+
 /// A SQLite-based event cache store.
 #[derive(Clone)]
 pub struct SqliteEventCacheStore {
-    store_cipher: Option<Arc<StoreCipher>>,
+    store_cipher: SharedStoreCipher,
     pool: SqlitePool,
 }
 
@@ -66,15 +71,57 @@ impl SqliteEventCacheStore {
         run_migrations(&conn, version).await?;
 
         let store_cipher = match passphrase {
-            Some(p) => Some(Arc::new(conn.get_or_create_store_cipher(p).await?)),
+            Some(p) => Some(conn.get_or_create_store_cipher(p).await?),
             None => None,
         };
 
-        Ok(Self { store_cipher, pool })
+        Ok(Self { store_cipher: SharedStoreCipher::new(store_cipher), pool })
+    }
+
+    /// Change the passphrase used to encrypt this store.
+    ///
+    /// Only the store cipher, which holds the keys that encrypt the data of
+    /// the store, is encrypted again using the new passphrase. This makes this
+    /// a cheap operation, but the data stays encrypted with the same keys. Use
+    /// [`SqliteEventCacheStore::reencrypt()`] if those keys might have been
+    /// compromised.
+    ///
+    /// Returns [`OpenStoreError::MissingCipher`] if the store was opened
+    /// without a passphrase.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<(), OpenStoreError> {
+        self.store_cipher.change_passphrase(&self.pool, old_passphrase, new_passphrase, None).await
+    }
+
+    /// Change the passphrase used to encrypt this store, and encrypt all of
+    /// its data again using a new encryption key.
+    ///
+    /// Unlike [`SqliteEventCacheStore::change_passphrase()`], this rewrites
+    /// every encrypted value of the store in a single transaction, which
+    /// can take a while for large stores. Keys are hashed and can't be
+    /// recomputed, so the key used to hash them is kept.
+    ///
+    /// The operations in progress on this store and its clones are awaited
+    /// first, and new ones wait for the re-encryption to be done. All of them
+    /// use the new encryption key afterwards.
+    ///
+    /// Returns [`OpenStoreError::MissingCipher`] if the store was opened
+    /// without a passphrase.
+    pub async fn reencrypt(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<(), OpenStoreError> {
+        self.store_cipher
+            .change_passphrase(&self.pool, old_passphrase, new_passphrase, Some(ENCRYPTED_COLUMNS))
+            .await
     }
 
     fn encode_value(&self, value: Vec<u8>) -> Result<Vec<u8>> {
-        if let Some(key) = &self.store_cipher {
+        if let Some(key) = self.store_cipher.get() {
             let encrypted = key.encrypt_value_data(value)?;
             Ok(rmp_serde::to_vec_named(&encrypted)?)
         } else {
@@ -83,7 +130,7 @@ impl SqliteEventCacheStore {
     }
 
     fn decode_value<'a>(&self, value: &'a [u8]) -> Result<Cow<'a, [u8]>> {
-        if let Some(key) = &self.store_cipher {
+        if let Some(key) = self.store_cipher.get() {
             let encrypted = rmp_serde::from_slice(value)?;
             let decrypted = key.decrypt_value_data(encrypted)?;
             Ok(Cow::Owned(decrypted))
@@ -94,15 +141,15 @@ impl SqliteEventCacheStore {
 
     fn encode_key(&self, table_name: &str, key: impl AsRef<[u8]>) -> Key {
         let bytes = key.as_ref();
-        if let Some(store_cipher) = &self.store_cipher {
+        if let Some(store_cipher) = self.store_cipher.get() {
             Key::Hashed(store_cipher.hash_key(table_name, bytes))
         } else {
             Key::Plain(bytes.to_owned())
         }
     }
 
-    async fn acquire(&self) -> Result<SqliteAsyncConn> {
-        Ok(self.pool.get().await?)
+    async fn acquire(&self) -> Result<SqliteConn> {
+        Ok(self.store_cipher.acquire(&self.pool).await?)
     }
 }
 
@@ -143,9 +190,9 @@ impl EventCacheStore for SqliteEventCacheStore {
     async fn add_media_content(&self, request: &MediaRequest, content: Vec<u8>) -> Result<()> {
         let uri = self.encode_key(keys::MEDIA, request.source.unique_key());
         let format = self.encode_key(keys::MEDIA, request.format.unique_key());
+        let conn = self.acquire().await?;
         let data = self.encode_value(content)?;
 
-        let conn = self.acquire().await?;
         conn.execute(
             "INSERT OR REPLACE INTO media (uri, format, data, last_access) VALUES (?, ?, ?, CAST(strftime('%s') as INT))",
             (uri, format, data),
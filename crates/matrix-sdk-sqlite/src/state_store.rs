@@ -3,7 +3,6 @@ use std::{
     collections::{BTreeMap, BTreeSet},
     fmt, iter,
     path::Path,
-    sync::Arc,
 };
 
 use async_trait::async_trait;
@@ -18,7 +17,6 @@ use matrix_sdk_base::{
     MinimalRoomMemberEvent, RoomInfo, RoomMemberships, RoomState, StateChanges, StateStore,
     StateStoreDataKey, StateStoreDataValue,
 };
-use matrix_sdk_store_encryption::StoreCipher;
 use ruma::{
     canonical_json::{redact, RedactedBecause},
     events::{
@@ -43,8 +41,8 @@ use tracing::{debug, warn};
 use crate::{
     error::{Error, Result},
     utils::{
-        repeat_vars, Key, SqliteAsyncConnExt, SqliteKeyValueStoreAsyncConnExt,
-        SqliteKeyValueStoreConnExt,
+        repeat_vars, EncryptedColumn, Key, SharedStoreCipher, SqliteAsyncConnExt, SqliteConn,
+        SqliteKeyValueStoreAsyncConnExt, SqliteKeyValueStoreConnExt,
     },
     OpenStoreError,
 };
@@ -71,10 +69,34 @@ mod keys {
 /// the [`SqliteStateStore::run_migrations`] function..
 const DATABASE_VERSION: u8 = 8;
 
+/// The columns holding values encrypted with the store cipher.
+const ENCRYPTED_COLUMNS: &[EncryptedColumn] = &[
+    // The custom values aren't encrypted.
+    EncryptedColumn {
+        table: keys::KV_BLOB,
+        column: "value",
+        condition: "TRUE",
+        has_unencrypted_values: true,
+    },
+    EncryptedColumn::new(keys::ROOM_INFO, "data"),
+    EncryptedColumn::new(keys::STATE_EVENT, "data"),
+    EncryptedColumn::new(keys::GLOBAL_ACCOUNT_DATA, "data"),
+    EncryptedColumn::new(keys::ROOM_ACCOUNT_DATA, "data"),
+    EncryptedColumn::new(keys::MEMBER, "data"),
+    EncryptedColumn::new(keys::PROFILE, "data"),
+    EncryptedColumn::new(keys::RECEIPT, "data"),
+    EncryptedColumn::new(keys::DISPLAY_NAME, "data"),
+    EncryptedColumn::new(keys::SEND_QUEUE, "room_id_val"),
+    EncryptedColumn::new(keys::SEND_QUEUE, "content"),
+    EncryptedColumn::new(keys::SEND_QUEUE, "wedge_reason"),
+    EncryptedColumn::new(keys::DEPENDENTS_SEND_QUEUE, "event_id"),
+    EncryptedColumn::new(keys::DEPENDENTS_SEND_QUEUE, "content"),
+];
+
 /// A sqlite based cryptostore.
 #[derive(Clone)]
 pub struct SqliteStateStore {
-    store_cipher: Option<Arc<StoreCipher>>,
+    store_cipher: SharedStoreCipher,
     pool: SqlitePool,
 }
 
@@ -112,15 +134,57 @@ impl SqliteStateStore {
         }
 
         let store_cipher = match passphrase {
-            Some(p) => Some(Arc::new(conn.get_or_create_store_cipher(p).await?)),
+            Some(p) => Some(conn.get_or_create_store_cipher(p).await?),
             None => None,
         };
-        let this = Self { store_cipher, pool };
+        let this = Self { store_cipher: SharedStoreCipher::new(store_cipher), pool };
         this.run_migrations(&conn, version, None).await?;
 
         Ok(this)
     }
 
+    /// Change the passphrase used to encrypt this store.
+    ///
+    /// Only the store cipher, which holds the keys that encrypt the data of
+    /// the store, is encrypted again using the new passphrase. This makes this
+    /// a cheap operation, but the data stays encrypted with the same keys. Use
+    /// [`SqliteStateStore::reencrypt()`] if those keys might have been
+    /// compromised.
+    ///
+    /// Returns [`OpenStoreError::MissingCipher`] if the store was opened
+    /// without a passphrase.
+    pub async fn change_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<(), OpenStoreError> {
+        self.store_cipher.change_passphrase(&self.pool, old_passphrase, new_passphrase, None).await
+    }
+
+    /// Change the passphrase used to encrypt this store, and encrypt all of
+    /// its data again using a new encryption key.
+    ///
+    /// Unlike [`SqliteStateStore::change_passphrase()`], this rewrites every
+    /// encrypted value of the store in a single transaction, which can take a
+    /// while for large stores. Keys are hashed and can't be recomputed, so the
+    /// key used to hash them is kept.
+    ///
+    /// The operations in progress on this store and its clones are awaited
+    /// first, and new ones wait for the re-encryption to be done. All of them
+    /// use the new encryption key afterwards.
+    ///
+    /// Returns [`OpenStoreError::MissingCipher`] if the store was opened
+    /// without a passphrase.
+    pub async fn reencrypt(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+    ) -> Result<(), OpenStoreError> {
+        self.store_cipher
+            .change_passphrase(&self.pool, old_passphrase, new_passphrase, Some(ENCRYPTED_COLUMNS))
+            .await
+    }
+
     /// Run database migrations from the given `from` version to the given `to`
     /// version
     ///
@@ -301,7 +365,7 @@ impl SqliteStateStore {
     }
 
     fn encode_value(&self, value: Vec<u8>) -> Result<Vec<u8>> {
-        if let Some(key) = &self.store_cipher {
+        if let Some(key) = self.store_cipher.get() {
             let encrypted = key.encrypt_value_data(value)?;
             Ok(rmp_serde::to_vec_named(&encrypted)?)
         } else {
@@ -320,7 +384,7 @@ impl SqliteStateStore {
     }
 
     fn decode_value<'a>(&self, value: &'a [u8]) -> Result<Cow<'a, [u8]>> {
-        if let Some(key) = &self.store_cipher {
+        if let Some(key) = self.store_cipher.get() {
             let encrypted = rmp_serde::from_slice(value)?;
             let decrypted = key.decrypt_value_data(encrypted)?;
             Ok(Cow::Owned(decrypted))
@@ -341,7 +405,7 @@ impl SqliteStateStore {
 
     fn encode_key(&self, table_name: &str, key: impl AsRef<[u8]>) -> Key {
         let bytes = key.as_ref();
-        if let Some(store_cipher) = &self.store_cipher {
+        if let Some(store_cipher) = self.store_cipher.get() {
             Key::Hashed(store_cipher.hash_key(table_name, bytes))
         } else {
             Key::Plain(bytes.to_owned())
@@ -384,8 +448,8 @@ impl SqliteStateStore {
         self.encode_key(keys::KV_BLOB, full_key)
     }
 
-    async fn acquire(&self) -> Result<SqliteAsyncConn> {
-        Ok(self.pool.get().await?)
+    async fn acquire(&self) -> Result<SqliteConn> {
+        Ok(self.store_cipher.acquire(&self.pool).await?)
     }
 
     fn remove_maybe_stripped_room_data(
@@ -977,6 +1041,8 @@ impl StateStore for SqliteStateStore {
         key: StateStoreDataKey<'_>,
         value: StateStoreDataValue,
     ) -> Result<()> {
+        let conn = self.acquire().await?;
+
         let serialized_value = match key {
             StateStoreDataKey::SyncToken => self.serialize_value(
                 &value.into_sync_token().expect("Session data not a sync token"),
@@ -1003,10 +1069,7 @@ impl StateStore for SqliteStateStore {
             )?,
         };
 
-        self.acquire()
-            .await?
-            .set_kv_blob(self.encode_state_store_data_key(key), serialized_value)
-            .await
+        conn.set_kv_blob(self.encode_state_store_data_key(key), serialized_value).await
     }
 
     async fn remove_kv_data(&self, key: StateStoreDataKey<'_>) -> Result<()> {
@@ -1676,6 +1739,8 @@ impl StateStore for SqliteStateStore {
         transaction_id: OwnedTransactionId,
         content: SerializableEventContent,
     ) -> Result<(), Self::Error> {
+        let conn = self.acquire().await?;
+
         let room_id_key = self.encode_key(keys::SEND_QUEUE, room_id);
         let room_id_value = self.serialize_value(&room_id.to_owned())?;
 
@@ -1686,8 +1751,7 @@ impl StateStore for SqliteStateStore {
         // it (with encode_key) or encrypt it (through serialize_value). After
         // all, it carries no personal information, so this is considered fine.
 
-        self.acquire()
-            .await?
+        conn
             .with_transaction(move |txn| {
                 txn.prepare_cached("INSERT INTO send_queue_events (room_id, room_id_val, transaction_id, content) VALUES (?, ?, ?, ?)")?.execute((room_id_key, room_id_value, transaction_id.to_string(), content))?;
                 Ok(())
@@ -1701,6 +1765,8 @@ impl StateStore for SqliteStateStore {
         transaction_id: &TransactionId,
         content: SerializableEventContent,
     ) -> Result<bool, Self::Error> {
+        let conn = self.acquire().await?;
+
         let room_id = self.encode_key(keys::SEND_QUEUE, room_id);
 
         let content = self.serialize_json(&content)?;
@@ -1708,8 +1774,7 @@ impl StateStore for SqliteStateStore {
         // transaction id is neither encrypted or hashed.
         let transaction_id = transaction_id.to_string();
 
-        let num_updated = self.acquire()
-            .await?
+        let num_updated = conn
             .with_transaction(move |txn| {
                 txn.prepare_cached("UPDATE send_queue_events SET wedge_reason = NULL, content = ? WHERE room_id = ? AND transaction_id = ?")?.execute((content, room_id, transaction_id))
             })
@@ -1782,6 +1847,8 @@ impl StateStore for SqliteStateStore {
         transaction_id: &TransactionId,
         error: Option<QueueWedgeError>,
     ) -> Result<(), Self::Error> {
+        let conn = self.acquire().await?;
+
         let room_id = self.encode_key(keys::SEND_QUEUE, room_id);
 
         // See comment in `save_send_queue_event`.
@@ -1790,8 +1857,7 @@ impl StateStore for SqliteStateStore {
         // Serialize the error to json bytes (encrypted if option is enabled) if set.
         let error_value = error.map(|e| self.serialize_value(&e)).transpose()?;
 
-        self.acquire()
-            .await?
+        conn
             .with_transaction(move |txn| {
                 txn.prepare_cached("UPDATE send_queue_events SET wedge_reason = ? WHERE room_id = ? AND transaction_id = ?")?.execute((error_value, room_id, transaction_id))?;
                 Ok(())
@@ -1829,6 +1895,8 @@ impl StateStore for SqliteStateStore {
         own_txn_id: ChildTransactionId,
         content: DependentQueuedRequestKind,
     ) -> Result<()> {
+        let conn = self.acquire().await?;
+
         let room_id = self.encode_key(keys::DEPENDENTS_SEND_QUEUE, room_id);
         let content = self.serialize_json(&content)?;
 
@@ -1836,18 +1904,16 @@ impl StateStore for SqliteStateStore {
         let parent_txn_id = parent_txn_id.to_string();
         let own_txn_id = own_txn_id.to_string();
 
-        self.acquire()
-            .await?
-            .with_transaction(move |txn| {
-                txn.prepare_cached(
-                    r#"INSERT INTO dependent_send_queue_events
+        conn.with_transaction(move |txn| {
+            txn.prepare_cached(
+                r#"INSERT INTO dependent_send_queue_events
                          (room_id, parent_transaction_id, own_transaction_id, content)
                        VALUES (?, ?, ?, ?)"#,
-                )?
-                .execute((room_id, parent_txn_id, own_txn_id, content))?;
-                Ok(())
-            })
-            .await
+            )?
+            .execute((room_id, parent_txn_id, own_txn_id, content))?;
+            Ok(())
+        })
+        .await
     }
 
     async fn update_dependent_queued_request(
@@ -1856,14 +1922,15 @@ impl StateStore for SqliteStateStore {
         parent_txn_id: &TransactionId,
         event_id: OwnedEventId,
     ) -> Result<usize> {
+        let conn = self.acquire().await?;
+
         let room_id = self.encode_key(keys::DEPENDENTS_SEND_QUEUE, room_id);
         let event_id = self.serialize_value(&event_id)?;
 
         // See comment in `save_send_queue_event`.
         let parent_txn_id = parent_txn_id.to_string();
 
-        self.acquire()
-            .await?
+        conn
             .with_transaction(move |txn| {
                 Ok(txn.prepare_cached(
                     "UPDATE dependent_send_queue_events SET event_id = ? WHERE parent_transaction_id = ? and room_id = ?",
@@ -1967,7 +2034,11 @@ mod tests {
 mod encrypted_tests {
     use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
 
-    use matrix_sdk_base::{statestore_integration_tests, StateStore, StoreError};
+    use matrix_sdk_base::{
+        statestore_integration_tests, StateStore, StateStoreDataKey, StateStoreDataValue,
+        StoreError,
+    };
+    use matrix_sdk_test::async_test;
     use once_cell::sync::Lazy;
     use tempfile::{tempdir, TempDir};
 
@@ -1987,6 +2058,45 @@ mod encrypted_tests {
             .unwrap())
     }
 
+    #[async_test]
+    async fn test_reencrypt() {
+        let path = TMP_DIR.path().join("reencrypt");
+        let store = SqliteStateStore::open(&path, Some("old")).await.unwrap();
+        let clone = store.clone();
+
+        store
+            .set_kv_data(
+                StateStoreDataKey::SyncToken,
+                StateStoreDataValue::SyncToken("token".to_owned()),
+            )
+            .await
+            .unwrap();
+        // Custom values aren't encrypted.
+        store.set_custom_value(b"key", b"value".to_vec()).await.unwrap();
+
+        store.reencrypt("old", "new").await.unwrap();
+
+        // The clones of the store use the new encryption key too.
+        clone
+            .set_kv_data(
+                StateStoreDataKey::Filter("filter"),
+                StateStoreDataValue::Filter("filter_id".to_owned()),
+            )
+            .await
+            .unwrap();
+        drop(store);
+        drop(clone);
+
+        assert!(SqliteStateStore::open(&path, Some("old")).await.is_err());
+
+        let store = SqliteStateStore::open(&path, Some("new")).await.unwrap();
+        let sync_token = store.get_kv_data(StateStoreDataKey::SyncToken).await.unwrap();
+        assert_eq!(sync_token.unwrap().into_sync_token().as_deref(), Some("token"));
+        let filter = store.get_kv_data(StateStoreDataKey::Filter("filter")).await.unwrap();
+        assert_eq!(filter.unwrap().into_filter().as_deref(), Some("filter_id"));
+        assert_eq!(store.get_custom_value(b"key").await.unwrap().as_deref(), Some(&b"value"[..]));
+    }
+
     statestore_integration_tests!();
 }
 
@@ -1994,10 +2104,7 @@ mod encrypted_tests {
 mod migration_tests {
     use std::{
         path::{Path, PathBuf},
-        sync::{
-            atomic::{AtomicU32, Ordering::SeqCst},
-            Arc,
-        },
+        sync::atomic::{AtomicU32, Ordering::SeqCst},
     };
 
     use assert_matches::assert_matches;
@@ -2023,7 +2130,7 @@ mod migration_tests {
     use super::{create_pool, init, keys, SqliteStateStore};
     use crate::{
         error::{Error, Result},
-        utils::{SqliteAsyncConnExt, SqliteKeyValueStoreAsyncConnExt},
+        utils::{SharedStoreCipher, SqliteAsyncConnExt, SqliteKeyValueStoreAsyncConnExt},
     };
 
     static TMP_DIR: Lazy<TempDir> = Lazy::new(|| tempdir().unwrap());
@@ -2041,8 +2148,8 @@ mod migration_tests {
 
         init(&conn).await?;
 
-        let store_cipher = Some(Arc::new(conn.get_or_create_store_cipher(SECRET).await.unwrap()));
-        let this = SqliteStateStore { store_cipher, pool };
+        let store_cipher = Some(conn.get_or_create_store_cipher(SECRET).await.unwrap());
+        let this = SqliteStateStore { store_cipher: SharedStoreCipher::new(store_cipher), pool };
         this.run_migrations(&conn, 1, Some(version)).await?;
 
         Ok(this)
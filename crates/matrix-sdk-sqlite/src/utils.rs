@@ -13,13 +13,20 @@
 // limitations under the License.
 
 use core::fmt;
-use std::{borrow::Borrow, cmp::min, iter, ops::Deref};
+use std::{
+    borrow::Borrow,
+    cmp::min,
+    iter,
+    ops::Deref,
+    sync::{Arc, RwLock as StdRwLock},
+};
 
 use async_trait::async_trait;
-use deadpool_sqlite::Object as SqliteAsyncConn;
+use deadpool_sqlite::{Object as SqliteAsyncConn, Pool as SqlitePool, PoolError};
 use itertools::Itertools;
-use matrix_sdk_store_encryption::StoreCipher;
+use matrix_sdk_store_encryption::{EncryptedValue, StoreCipher};
 use rusqlite::{limits::Limit, OptionalExtension, Params, Row, Statement, Transaction};
+use tokio::sync::{OwnedRwLockReadGuard, RwLock};
 
 use crate::{
     error::{Error, Result},
@@ -273,6 +280,188 @@ impl SqliteKeyValueStoreConnExt for rusqlite::Connection {
     }
 }
 
+/// A column of the database holding values encrypted with the
+/// [`StoreCipher`] of the store.
+pub(crate) struct EncryptedColumn {
+    /// The name of the table.
+    pub table: &'static str,
+    /// The name of the column.
+    pub column: &'static str,
+    /// An SQL condition selecting the rows of the table whose value is
+    /// encrypted.
+    pub condition: &'static str,
+    /// Whether the column also holds values which aren't encrypted, which
+    /// can't be told apart using `condition`, like the custom values of the
+    /// state store. The values which can't be decrypted are left untouched.
+    pub has_unencrypted_values: bool,
+}
+
+impl EncryptedColumn {
+    /// All the values of the given column are encrypted.
+    pub const fn new(table: &'static str, column: &'static str) -> Self {
+        Self { table, column, condition: "TRUE", has_unencrypted_values: false }
+    }
+}
+
+/// The number of values [`SqliteReEncryptTransactionExt::reencrypt_values()`]
+/// loads from the database at once.
+const REENCRYPT_CHUNK_SIZE: i64 = 1000;
+
+pub(crate) trait SqliteReEncryptTransactionExt {
+    /// Decrypt the values of the given columns using `old_cipher` and encrypt
+    /// them again using `new_cipher`.
+    ///
+    /// The values are loaded in chunks of [`REENCRYPT_CHUNK_SIZE`], so the
+    /// whole column is never held in memory at once.
+    ///
+    /// Returns the number of re-encrypted values.
+    fn reencrypt_values(
+        &self,
+        columns: &[EncryptedColumn],
+        old_cipher: &StoreCipher,
+        new_cipher: &StoreCipher,
+    ) -> Result<usize>;
+}
+
+impl<'a> SqliteReEncryptTransactionExt for Transaction<'a> {
+    fn reencrypt_values(
+        &self,
+        columns: &[EncryptedColumn],
+        old_cipher: &StoreCipher,
+        new_cipher: &StoreCipher,
+    ) -> Result<usize> {
+        let mut count = 0;
+
+        for EncryptedColumn { table, column, condition, has_unencrypted_values } in columns {
+            // Go through the values in chunks, so we don't hold the whole column in
+            // memory at once.
+            let mut select = self.prepare(&format!(
+                "SELECT rowid, \"{column}\" FROM \"{table}\" \
+                 WHERE rowid > ?1 AND \"{column}\" IS NOT NULL AND ({condition}) \
+                 ORDER BY rowid LIMIT ?2"
+            ))?;
+            let mut last_rowid = i64::MIN;
+
+            loop {
+                let values = select
+                    .query_map((last_rowid, REENCRYPT_CHUNK_SIZE), |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+                    })?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+
+                let Some(&(rowid, _)) = values.last() else {
+                    break;
+                };
+                last_rowid = rowid;
+
+                for (rowid, value) in values {
+                    let decrypted = rmp_serde::from_slice::<EncryptedValue>(&value)
+                        .map_err(Error::from)
+                        .and_then(|encrypted| Ok(old_cipher.decrypt_value_data(encrypted)?));
+
+                    let decrypted = match decrypted {
+                        Ok(decrypted) => decrypted,
+                        Err(_) if *has_unencrypted_values => continue,
+                        Err(e) => return Err(e),
+                    };
+
+                    let value =
+                        rmp_serde::to_vec_named(&new_cipher.encrypt_value_data(decrypted)?)?;
+
+                    self.execute(
+                        &format!("UPDATE \"{table}\" SET \"{column}\" = ?1 WHERE rowid = ?2"),
+                        (value, rowid),
+                    )?;
+
+                    count += 1;
+                }
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+/// The [`StoreCipher`] of a store, shared between all the clones of the
+/// store.
+///
+/// The cipher is only replaced, when the store is re-encrypted, while holding
+/// the write side of a lock whose read side is held by the connections
+/// returned by [`SharedStoreCipher::acquire()`]. Values must be encrypted
+/// while holding such a connection, so a value encrypted with a previous
+/// cipher can't be written after the store was re-encrypted.
+#[derive(Clone)]
+pub(crate) struct SharedStoreCipher {
+    cipher: Arc<StdRwLock<Option<Arc<StoreCipher>>>>,
+    lock: Arc<RwLock<()>>,
+}
+
+impl SharedStoreCipher {
+    pub fn new(cipher: Option<StoreCipher>) -> Self {
+        Self {
+            cipher: Arc::new(StdRwLock::new(cipher.map(Arc::new))),
+            lock: Arc::new(RwLock::new(())),
+        }
+    }
+
+    /// The current cipher, if the store is encrypted.
+    pub fn get(&self) -> Option<Arc<StoreCipher>> {
+        self.cipher.read().unwrap().clone()
+    }
+
+    /// Get a connection from the given pool, which prevents the cipher from
+    /// being replaced while it's in use.
+    pub async fn acquire(&self, pool: &SqlitePool) -> Result<SqliteConn, PoolError> {
+        let guard = self.lock.clone().read_owned().await;
+        let conn = pool.get().await?;
+
+        Ok(SqliteConn { conn, _guard: guard })
+    }
+
+    /// Encrypt the cipher of the database with a new passphrase, and
+    /// optionally re-encrypt the values of the given columns with a new
+    /// encryption key, see
+    /// [`SqliteKeyValueStoreAsyncConnExt::change_store_cipher_passphrase()`].
+    ///
+    /// This waits for the connections of the store to be released, and
+    /// replaces the cipher used by all the clones of the store.
+    pub async fn change_passphrase(
+        &self,
+        pool: &SqlitePool,
+        old_passphrase: &str,
+        new_passphrase: &str,
+        reencrypt: Option<&'static [EncryptedColumn]>,
+    ) -> Result<(), OpenStoreError> {
+        if self.get().is_none() {
+            return Err(OpenStoreError::MissingCipher);
+        }
+
+        let _guard = self.lock.write().await;
+
+        let conn = pool.get().await?;
+        let cipher =
+            conn.change_store_cipher_passphrase(old_passphrase, new_passphrase, reencrypt).await?;
+
+        *self.cipher.write().unwrap() = Some(Arc::new(cipher));
+
+        Ok(())
+    }
+}
+
+/// A connection of the pool of a store, see [`SharedStoreCipher::acquire()`].
+pub(crate) struct SqliteConn {
+    conn: SqliteAsyncConn,
+    _guard: OwnedRwLockReadGuard<()>,
+}
+
+impl Deref for SqliteConn {
+    type Target = SqliteAsyncConn;
+
+    fn deref(&self) -> &Self::Target {
+        &self.conn
+    }
+}
+
 /// Extension trait for an [`SqliteAsyncConn`] that contains a key-value
 /// table named `kv`.
 ///
@@ -333,16 +522,72 @@ pub(crate) trait SqliteKeyValueStoreAsyncConnExt: SqliteAsyncConnExt {
             StoreCipher::import(passphrase, &encrypted)?
         } else {
             let cipher = StoreCipher::new()?;
-            #[cfg(not(test))]
-            let export = cipher.export(passphrase);
-            #[cfg(test)]
-            let export = cipher._insecure_export_fast_for_testing(passphrase);
+            let export = export_store_cipher(&cipher, passphrase);
             self.set_kv("cipher", export?).await.map_err(OpenStoreError::SaveCipher)?;
             cipher
         };
 
         Ok(cipher)
     }
+
+    /// Encrypt the [`StoreCipher`] of the database with a new passphrase.
+    ///
+    /// If the columns holding encrypted values are given, the store cipher gets
+    /// a new encryption key, see [`StoreCipher::rotate_encryption_key()`], and
+    /// the values of those columns are encrypted again using it, in the same
+    /// transaction that saves the new store cipher.
+    ///
+    /// Returns the store cipher that needs to be used from now on.
+    async fn change_store_cipher_passphrase(
+        &self,
+        old_passphrase: &str,
+        new_passphrase: &str,
+        reencrypt: Option<&'static [EncryptedColumn]>,
+    ) -> Result<StoreCipher, OpenStoreError> {
+        let encrypted = self
+            .get_kv("cipher")
+            .await
+            .map_err(OpenStoreError::LoadCipher)?
+            .ok_or(OpenStoreError::MissingCipher)?;
+
+        // Importing the store cipher also checks that the old passphrase is correct.
+        let cipher = StoreCipher::import(old_passphrase, &encrypted)?;
+
+        if let Some(columns) = reencrypt {
+            let new_cipher = cipher.rotate_encryption_key()?;
+            let export = export_store_cipher(&new_cipher, new_passphrase)?;
+
+            self.with_transaction(move |txn| {
+                txn.reencrypt_values(columns, &cipher, &new_cipher)?;
+                txn.set_kv("cipher", &export)?;
+
+                Ok::<_, Error>(new_cipher)
+            })
+            .await
+            .map_err(OpenStoreError::ReEncrypt)
+        } else {
+            let export = export_store_cipher(&cipher, new_passphrase)?;
+
+            // This is a single statement, so the store cipher is either replaced
+            // or left untouched.
+            self.set_kv("cipher", export).await.map_err(OpenStoreError::SaveCipher)?;
+
+            Ok(cipher)
+        }
+    }
+}
+
+/// Encrypt the given [`StoreCipher`] with the given passphrase, so it can be
+/// stored in the `kv` table.
+fn export_store_cipher(
+    cipher: &StoreCipher,
+    passphrase: &str,
+) -> Result<Vec<u8>, matrix_sdk_store_encryption::Error> {
+    if cfg!(test) {
+        cipher._insecure_export_fast_for_testing(passphrase)
+    } else {
+        cipher.export(passphrase)
+    }
 }
 
 #[async_trait]
@@ -345,6 +345,45 @@ impl StoreCipher {
         Self::import_helper(key, encrypted)
     }
 
+    /// Create a new `StoreCipher` with a fresh, random, encryption key.
+    ///
+    /// The key used to hash keys, see [`StoreCipher::hash_key()`], is kept
+    /// as is, since hashed keys can't be recomputed without knowing the
+    /// original keys. Values that were encrypted with the current
+    /// `StoreCipher` need to be decrypted and encrypted again using the new
+    /// one.
+    ///
+    /// This is useful if the encryption key of a store might have been
+    /// compromised.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # let example = || {
+    /// use matrix_sdk_store_encryption::StoreCipher;
+    /// use serde_json::{json, value::Value};
+    ///
+    /// let store_cipher = StoreCipher::new()?;
+    /// let encrypted = store_cipher.encrypt_value(&json!({ "some": "data" }))?;
+    ///
+    /// let new_cipher = store_cipher.rotate_encryption_key()?;
+    ///
+    /// let value: Value = store_cipher.decrypt_value(&encrypted)?;
+    /// let encrypted = new_cipher.encrypt_value(&value)?;
+    ///
+    /// assert_eq!(
+    ///     store_cipher.hash_key("table", b"key"),
+    ///     new_cipher.hash_key("table", b"key")
+    /// );
+    /// # anyhow::Ok(()) };
+    /// ```
+    pub fn rotate_encryption_key(&self) -> Result<Self, Error> {
+        let mut keys = Keys::new()?;
+        keys.mac_key_seed.copy_from_slice(self.inner.mac_key_seed.as_slice());
+
+        Ok(Self { inner: keys })
+    }
+
     /// Hash a key before it is inserted into the key/value store.
     ///
     /// This prevents the key names from leaking to parties which do not have
@@ -698,7 +737,7 @@ impl TryFrom<EncryptedValueBase64> for EncryptedValue {
 
 /// Encrypted value, ready for storage, as created by the
 /// [`StoreCipher::encrypt_value_base64_data()`]
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct EncryptedValueBase64 {
     version: u8,
     ciphertext: String,
@@ -956,6 +995,30 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn rotating_the_encryption_key() -> Result<(), Error> {
+        let value = json!({ "some": "data" });
+
+        let store_cipher = StoreCipher::new()?;
+        let encrypted = store_cipher.encrypt_value(&value)?;
+
+        let new_cipher = store_cipher.rotate_encryption_key()?;
+
+        assert!(new_cipher.decrypt_value::<Value>(&encrypted).is_err());
+        assert_eq!(
+            store_cipher.hash_key("some_table", b"key"),
+            new_cipher.hash_key("some_table", b"key")
+        );
+
+        let decrypted: Value = store_cipher.decrypt_value(&encrypted)?;
+        let encrypted = new_cipher.encrypt_value(&decrypted)?;
+        let decrypted: Value = new_cipher.decrypt_value(&encrypted)?;
+
+        assert_eq!(value, decrypted);
+
+        Ok(())
+    }
+
     #[test]
     fn encrypting_keys() -> Result<(), Error> {
         let store_cipher = StoreCipher::new()?;
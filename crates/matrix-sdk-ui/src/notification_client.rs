@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::{
+    collections::{BTreeMap, BTreeSet},
     sync::{Arc, Mutex},
     time::Duration,
 };
@@ -146,6 +147,42 @@ impl NotificationClient {
         }
     }
 
+    /// Fetches the content of several notifications at once.
+    ///
+    /// This is similar to [`Self::get_notification`], but all the events are
+    /// retrieved with a single sliding sync subscribing to all the rooms, and
+    /// the events that are still encrypted are decrypted together, after a
+    /// single encryption sync. The events that couldn't be found with the
+    /// sliding sync are then fetched one by one with a `/context` query.
+    ///
+    /// The results are in the same order as the requests. An outer error means
+    /// that the sliding sync couldn't be run at all; an inner one means the
+    /// notification couldn't be resolved, and a `None` inner result means the
+    /// notification has been filtered out by the user's push rules, just like
+    /// for [`Self::get_notification`].
+    #[instrument(skip_all, fields(num_notifications = requests.len()))]
+    pub async fn get_notifications(
+        &self,
+        requests: &[(&RoomId, &EventId)],
+    ) -> Result<Vec<Result<Option<NotificationItem>, Error>>, Error> {
+        let statuses = self.get_notifications_with_sliding_sync(requests).await?;
+
+        let mut items = Vec::with_capacity(requests.len());
+
+        for ((room_id, event_id), status) in requests.iter().zip(statuses) {
+            items.push(match status {
+                Ok(NotificationStatus::Event(event)) => Ok(Some(event)),
+                Ok(NotificationStatus::EventFilteredOut) => Ok(None),
+                Ok(NotificationStatus::EventNotFound) => {
+                    self.get_notification_with_context(room_id, event_id).await
+                }
+                Err(err) => Err(err),
+            });
+        }
+
+        Ok(items)
+    }
+
     /// Run an encryption sync loop, in case an event is still encrypted.
     ///
     /// Will return true if and only:
@@ -166,10 +203,46 @@ impl NotificationClient {
             return Ok(None);
         }
 
+        self.retry_decryption_batch(&[(room, raw_event)]).await.pop().unwrap_or(Ok(None))
+    }
+
+    /// Run an encryption sync loop once for all the events that are still
+    /// encrypted, and try to decrypt them.
+    ///
+    /// The results are in the same order as the events; an item is `Some` if
+    /// and only if the event was encrypted and could be decrypted, and `None`
+    /// if it couldn't be decrypted, so that the still encrypted event is used
+    /// instead. An error is only returned when waiting for another encryption
+    /// sync to decrypt the event failed, which doesn't prevent the other
+    /// events from being decrypted.
+    #[instrument(skip_all, fields(num_events = events.len()))]
+    async fn retry_decryption_batch(
+        &self,
+        events: &[(&Room, &Raw<AnySyncTimelineEvent>)],
+    ) -> Vec<Result<Option<TimelineEvent>, Error>> {
+        let mut decrypted_events = events.iter().map(|_| Ok(None)).collect::<Vec<_>>();
+
+        // Events that can't be deserialized will be rejected later, when building the
+        // notification item.
+        let mut encrypted_events = events
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, raw_event))| {
+                raw_event
+                    .deserialize()
+                    .is_ok_and(|event: AnySyncTimelineEvent| is_event_encrypted(event.event_type()))
+            })
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+
+        if encrypted_events.is_empty() {
+            return decrypted_events;
+        }
+
         // Serialize calls to this function.
         let _guard = self.encryption_sync_mutex.lock().await;
 
-        // The message is still encrypted, and the client is configured to retry
+        // The messages are still encrypted, and the client is configured to retry
         // decryption.
         //
         // Spawn an `EncryptionSync` that runs two iterations of the sliding sync loop:
@@ -198,7 +271,7 @@ impl NotificationClient {
                     permit_guard
                 } else {
                     // There's already a sync service active, thus the encryption sync is already
-                    // running elsewhere. As a matter of fact, if the event was encrypted, that
+                    // running elsewhere. As a matter of fact, if the events were encrypted, that
                     // means we were racing against the encryption sync. Wait a bit, attempt to
                     // decrypt, and carry on.
 
@@ -213,30 +286,51 @@ impl NotificationClient {
 
                         tokio::time::sleep(Duration::from_millis(wait)).await;
 
-                        let new_event = room.decrypt_event(raw_event.cast_ref()).await?;
-
-                        match new_event.kind {
-                            matrix_sdk::deserialized_responses::TimelineEventKind::UnableToDecrypt {
-                                utd_info, ..} => {
-                                if utd_info.reason.is_missing_room_key() {
-                                    // Decryption error that could be caused by a missing room
-                                    // key; retry in a few.
-                                    wait *= 2;
-                                } else {
-                                    debug!("Event could not be decrypted, but waiting longer is unlikely to help: {:?}", utd_info.reason);
-                                    return Ok(None);
+                        let mut still_encrypted_events = Vec::new();
+
+                        for index in encrypted_events {
+                            let (room, raw_event) = events[index];
+                            let new_event = match room.decrypt_event(raw_event.cast_ref()).await {
+                                Ok(new_event) => new_event,
+                                Err(err) => {
+                                    // Waiting longer won't help; carry on with the other events.
+                                    decrypted_events[index] = Err(err.into());
+                                    continue;
+                                }
+                            };
+
+                            match new_event.kind {
+                                matrix_sdk::deserialized_responses::TimelineEventKind::UnableToDecrypt {
+                                    utd_info, ..} => {
+                                    if utd_info.reason.is_missing_room_key() {
+                                        // Decryption error that could be caused by a missing room
+                                        // key; retry in a few.
+                                        still_encrypted_events.push(index);
+                                    } else {
+                                        debug!("Event could not be decrypted, but waiting longer is unlikely to help: {:?}", utd_info.reason);
+                                    }
+                                }
+                                _ => {
+                                    trace!("Waiting succeeded and event could be decrypted!");
+                                    decrypted_events[index] = Ok(Some(new_event));
                                 }
                             }
-                            _ => {
-                                trace!("Waiting succeeded and event could be decrypted!");
-                                return Ok(Some(new_event));
-                            }
                         }
+
+                        if still_encrypted_events.is_empty() {
+                            return decrypted_events;
+                        }
+
+                        encrypted_events = still_encrypted_events;
+                        wait *= 2;
                     }
 
-                    // We couldn't decrypt the event after waiting a few times, abort.
-                    debug!("Timeout waiting for the encryption sync to decrypt notification.");
-                    return Ok(None);
+                    // We couldn't decrypt the events after waiting a few times, abort.
+                    debug!(
+                        num_events = encrypted_events.len(),
+                        "Timeout waiting for the encryption sync to decrypt notifications."
+                    );
+                    return decrypted_events;
                 }
             }
         };
@@ -255,50 +349,56 @@ impl NotificationClient {
 
         match encryption_sync {
             Ok(sync) => match sync.run_fixed_iterations(2, sync_permit_guard).await {
-                Ok(()) => match room.decrypt_event(raw_event.cast_ref()).await {
-                    Ok(new_event) => match new_event.kind {
-                        matrix_sdk::deserialized_responses::TimelineEventKind::UnableToDecrypt {
-                            utd_info, ..
-                        } => {
-                            trace!(
-                                "Encryption sync failed to decrypt the event: {:?}",
-                                utd_info.reason
-                            );
-                            Ok(None)
-                        }
-                        _ => {
-                            trace!("Encryption sync managed to decrypt the event.");
-                            Ok(Some(new_event))
+                Ok(()) => {
+                    for index in encrypted_events {
+                        let (room, raw_event) = events[index];
+
+                        match room.decrypt_event(raw_event.cast_ref()).await {
+                            Ok(new_event) => match new_event.kind {
+                                matrix_sdk::deserialized_responses::TimelineEventKind::UnableToDecrypt {
+                                    utd_info, ..
+                                } => {
+                                    trace!(
+                                        "Encryption sync failed to decrypt the event: {:?}",
+                                        utd_info.reason
+                                    );
+                                }
+                                _ => {
+                                    trace!("Encryption sync managed to decrypt the event.");
+                                    decrypted_events[index] = Ok(Some(new_event));
+                                }
+                            },
+                            Err(err) => {
+                                // Keep the encrypted event, an "encrypted message"
+                                // notification is still better than no notification.
+                                trace!("Encryption sync failed to decrypt the event: {err}");
+                            }
                         }
-                    },
-                    Err(err) => {
-                        trace!("Encryption sync failed to decrypt the event: {err}");
-                        Ok(None)
                     }
-                },
+                }
                 Err(err) => {
                     warn!("Encryption sync error: {err:#}");
-                    Ok(None)
                 }
             },
             Err(err) => {
                 warn!("Encryption sync build error: {err:#}",);
-                Ok(None)
             }
         }
+
+        decrypted_events
     }
 
-    /// Try to run a sliding sync (without encryption) to retrieve the event
-    /// from the notification.
+    /// Try to run a sliding sync (without encryption) to retrieve the events
+    /// from the notifications.
     ///
-    /// The event can either be:
+    /// Each event can either be:
     /// - an invite event,
     /// - or a non-invite event.
     ///
     /// In case it's a non-invite event, it's rather easy: we'll request
     /// explicit state that'll be useful for building the
-    /// `NotificationItem`, and subscribe to the room which the notification
-    /// relates to.
+    /// `NotificationItem`, and subscribe to the rooms which the notifications
+    /// relate to.
     ///
     /// In case it's an invite-event, it's trickier because the stripped event
     /// may not contain the event id, so we can't just match on it. Rather,
@@ -306,34 +406,40 @@ impl NotificationClient {
     /// match the current user and are invites), and if the SDK concludes the
     /// room was in the invited state, and we didn't find the event by id,
     /// *then* we'll use that stripped room member event.
+    ///
+    /// The results are in the same order as the requests.
     #[instrument(skip_all)]
     async fn try_sliding_sync(
         &self,
-        room_id: &RoomId,
-        event_id: &EventId,
-    ) -> Result<Option<RawNotificationEvent>, Error> {
+        requests: &[(&RoomId, &EventId)],
+    ) -> Result<Vec<Option<RawNotificationEvent>>, Error> {
         // Serialize all the calls to this method by taking a lock at the beginning,
         // that will be dropped later.
         let _guard = self.notification_sync_mutex.lock().await;
 
-        // Set up a sliding sync that only subscribes to the room that had the
-        // notification, so we can figure out the full event and associated
+        // Set up a sliding sync that only subscribes to the rooms that had the
+        // notifications, so we can figure out the full events and associated
         // information.
 
-        let raw_notification = Arc::new(Mutex::new(None));
+        let raw_notifications = Arc::new(Mutex::new(BTreeMap::new()));
 
-        let handler_raw_notification = raw_notification.clone();
-        let target_event_id = event_id.to_owned();
+        let handler_raw_notifications = raw_notifications.clone();
+        let target_event_ids = Arc::new(
+            requests.iter().map(|(_, event_id)| (*event_id).to_owned()).collect::<BTreeSet<_>>(),
+        );
+        let handler_target_event_ids = target_event_ids.clone();
 
         let timeline_event_handler =
             self.client.add_event_handler(move |raw: Raw<AnySyncTimelineEvent>| async move {
                 match raw.get_field::<OwnedEventId>("event_id") {
                     Ok(Some(event_id)) => {
-                        if event_id == target_event_id {
+                        if handler_target_event_ids.contains(&event_id) {
                             // found it! There shouldn't be a previous event before, but if there
                             // is, that should be ok to just replace it.
-                            *handler_raw_notification.lock().unwrap() =
-                                Some(RawNotificationEvent::Timeline(raw));
+                            handler_raw_notifications
+                                .lock()
+                                .unwrap()
+                                .insert(event_id, RawNotificationEvent::Timeline(raw));
                         }
                     }
                     Ok(None) => {
@@ -345,15 +451,15 @@ impl NotificationClient {
                 }
             });
 
-        // We'll only use this event if the room is in the invited state.
-        let raw_invite = Arc::new(Mutex::new(None));
+        // We'll only use these events if their room is in the invited state.
+        let raw_invites = Arc::new(Mutex::new(BTreeMap::new()));
 
-        let target_event_id = event_id.to_owned();
+        let handler_target_event_ids = target_event_ids.clone();
         let user_id = self.client.user_id().unwrap().to_owned();
-        let handler_raw_invite = raw_invite.clone();
-        let handler_raw_notification = raw_notification.clone();
-        let stripped_member_handler =
-            self.client.add_event_handler(move |raw: Raw<StrippedRoomMemberEvent>| async move {
+        let handler_raw_invites = raw_invites.clone();
+        let handler_raw_notifications = raw_notifications.clone();
+        let stripped_member_handler = self.client.add_event_handler(
+            move |raw: Raw<StrippedRoomMemberEvent>, room: Room| async move {
                 let deserialized = match raw.deserialize() {
                     Ok(d) => d,
                     Err(err) => {
@@ -368,11 +474,13 @@ impl NotificationClient {
                 // shouldn't receive it, so that's a first attempt.
                 match raw.get_field::<OwnedEventId>("event_id") {
                     Ok(Some(event_id)) => {
-                        if event_id == target_event_id {
+                        if handler_target_event_ids.contains(&event_id) {
                             // found it! There shouldn't be a previous event before, but if there
                             // is, that should be ok to just replace it.
-                            *handler_raw_notification.lock().unwrap() =
-                                Some(RawNotificationEvent::Invite(raw));
+                            handler_raw_notifications
+                                .lock()
+                                .unwrap()
+                                .insert(event_id, RawNotificationEvent::Invite(raw));
                             return;
                         }
                     }
@@ -392,11 +500,12 @@ impl NotificationClient {
                     // This could be it! There might be several of these following each other, so
                     // assume it's the latest one (in sync ordering), and override a previous one if
                     // present.
-                    *handler_raw_invite.lock().unwrap() = Some(RawNotificationEvent::Invite(raw));
+                    handler_raw_invites.lock().unwrap().insert(room.room_id().to_owned(), raw);
                 } else {
                     debug!("not an invite event, or not for the current user");
                 }
-            });
+            },
+        );
 
         // Room power levels are necessary to build the push context.
         let required_state = vec![
@@ -429,8 +538,15 @@ impl NotificationClient {
             .build()
             .await?;
 
+        let room_ids = requests
+            .iter()
+            .map(|(room_id, _)| *room_id)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+
         sync.subscribe_to_rooms(
-            &[room_id],
+            &room_ids,
             Some(assign!(http::request::RoomSubscription::default(), {
                 required_state,
                 timeline_limit: uint!(16)
@@ -449,8 +565,17 @@ impl NotificationClient {
                 break;
             }
 
-            if raw_notification.lock().unwrap().is_some() || raw_invite.lock().unwrap().is_some() {
-                // We got the event.
+            let all_found = {
+                let raw_notifications = raw_notifications.lock().unwrap();
+                let raw_invites = raw_invites.lock().unwrap();
+
+                requests.iter().all(|(room_id, event_id)| {
+                    raw_notifications.contains_key(*event_id) || raw_invites.contains_key(*room_id)
+                })
+            };
+
+            if all_found {
+                // We got all the events.
                 break;
             }
 
@@ -464,25 +589,39 @@ impl NotificationClient {
         self.client.remove_event_handler(stripped_member_handler);
         self.client.remove_event_handler(timeline_event_handler);
 
-        let mut maybe_event = raw_notification.lock().unwrap().take();
-
-        if maybe_event.is_none() {
-            trace!("we didn't have a non-invite event, looking for invited room now");
-            if let Some(room) = self.client.get_room(room_id) {
-                if room.state() == RoomState::Invited {
-                    maybe_event = raw_invite.lock().unwrap().take();
-                } else {
-                    debug!("the room isn't in the invited state");
+        let mut raw_notifications = raw_notifications.lock().unwrap();
+        let raw_invites = raw_invites.lock().unwrap();
+
+        Ok(requests
+            .iter()
+            .map(|(room_id, event_id)| {
+                let mut maybe_event = raw_notifications.remove(*event_id);
+
+                if maybe_event.is_none() {
+                    trace!(
+                        %event_id,
+                        "we didn't have a non-invite event, looking for invited room now"
+                    );
+                    if let Some(room) = self.client.get_room(room_id) {
+                        if room.state() == RoomState::Invited {
+                            maybe_event = raw_invites
+                                .get(*room_id)
+                                .cloned()
+                                .map(RawNotificationEvent::Invite);
+                        } else {
+                            debug!("the room isn't in the invited state");
+                        }
+                    } else {
+                        debug!("the room isn't an invite");
+                    }
                 }
-            } else {
-                debug!("the room isn't an invite");
-            }
-        }
 
-        let found = if maybe_event.is_some() { "" } else { "not " };
-        trace!("the notification event has been {found}found");
+                let found = if maybe_event.is_some() { "" } else { "not " };
+                trace!(%event_id, "the notification event has been {found}found");
 
-        Ok(maybe_event)
+                maybe_event
+            })
+            .collect())
     }
 
     /// Get a full notification, given a room id and event id.
@@ -494,19 +633,98 @@ impl NotificationClient {
         room_id: &RoomId,
         event_id: &EventId,
     ) -> Result<NotificationStatus, Error> {
-        let Some(mut raw_event) = self.try_sliding_sync(room_id, event_id).await? else {
-            return Ok(NotificationStatus::EventNotFound);
-        };
+        self.get_notifications_with_sliding_sync(&[(room_id, event_id)])
+            .await?
+            .pop()
+            .unwrap_or(Ok(NotificationStatus::EventNotFound))
+    }
 
-        // At this point it should have been added by the sync, if it's not, give up.
-        let Some(room) = self.client.get_room(room_id) else { return Err(Error::UnknownRoom) };
+    /// Get several full notifications, given their room ids and event ids.
+    ///
+    /// This will run a single small sliding sync to retrieve the content of all
+    /// the events, along with extra data to form rich notification contexts,
+    /// and decrypt the events that are still encrypted together.
+    ///
+    /// The results are in the same order as the requests. An outer error means
+    /// that the sliding sync couldn't be run at all.
+    pub async fn get_notifications_with_sliding_sync(
+        &self,
+        requests: &[(&RoomId, &EventId)],
+    ) -> Result<Vec<Result<NotificationStatus, Error>>, Error> {
+        let raw_events = self.try_sliding_sync(requests).await?;
+
+        // At this point the rooms should have been added by the sync; if one isn't,
+        // give up on its notification.
+        let raw_events = requests
+            .iter()
+            .zip(raw_events)
+            .map(|((room_id, _), raw_event)| {
+                let raw_event = raw_event?;
+                Some(
+                    self.client
+                        .get_room(room_id)
+                        .ok_or(Error::UnknownRoom)
+                        .map(|room| (room, raw_event)),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // Timeline events may be encrypted, so make sure they get decrypted first, all
+        // at once.
+        let timeline_events = raw_events
+            .iter()
+            .enumerate()
+            .filter_map(|(index, raw_event)| match raw_event {
+                Some(Ok((room, RawNotificationEvent::Timeline(timeline_event)))) => {
+                    Some((index, (room, timeline_event)))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let decrypted_events = self
+            .retry_decryption_batch(
+                &timeline_events.iter().map(|(_, event)| *event).collect::<Vec<_>>(),
+            )
+            .await;
+
+        let mut decrypted_events = timeline_events
+            .iter()
+            .map(|(index, _)| *index)
+            .zip(decrypted_events)
+            .collect::<BTreeMap<_, _>>();
+
+        let mut statuses = Vec::with_capacity(requests.len());
+
+        for (index, raw_event) in raw_events.into_iter().enumerate() {
+            let status = match raw_event {
+                None => Ok(NotificationStatus::EventNotFound),
+                Some(Err(err)) => Err(err),
+                Some(Ok((room, raw_event))) => match decrypted_events.remove(&index).transpose() {
+                    Ok(decrypted_event) => {
+                        self.notification_status(&room, raw_event, decrypted_event.flatten()).await
+                    }
+                    Err(err) => Err(err),
+                },
+            };
 
+            statuses.push(status);
+        }
+
+        Ok(statuses)
+    }
+
+    /// Compute the status of a notification, once its event has been retrieved
+    /// (and decrypted, if it was possible).
+    async fn notification_status(
+        &self,
+        room: &Room,
+        mut raw_event: RawNotificationEvent,
+        decrypted_event: Option<TimelineEvent>,
+    ) -> Result<NotificationStatus, Error> {
         let push_actions = match &raw_event {
             RawNotificationEvent::Timeline(timeline_event) => {
-                // Timeline events may be encrypted, so make sure they get decrypted first.
-                if let Some(mut timeline_event) =
-                    self.retry_decryption(&room, timeline_event).await?
-                {
+                if let Some(mut timeline_event) = decrypted_event {
                     let push_actions = timeline_event.push_actions.take();
                     raw_event = RawNotificationEvent::Timeline(timeline_event.into_raw());
                     push_actions
@@ -527,7 +745,7 @@ impl NotificationClient {
         }
 
         Ok(NotificationStatus::Event(
-            NotificationItem::new(&room, raw_event, push_actions.as_deref(), Vec::new()).await?,
+            NotificationItem::new(room, raw_event, push_actions.as_deref(), Vec::new()).await?,
         ))
     }
 
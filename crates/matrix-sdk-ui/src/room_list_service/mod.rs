@@ -63,8 +63,8 @@ use async_stream::stream;
 use eyeball::Subscriber;
 use futures_util::{pin_mut, Stream, StreamExt};
 use matrix_sdk::{
-    event_cache::EventCacheError, Client, Error as SlidingSyncError, SlidingSync, SlidingSyncList,
-    SlidingSyncMode,
+    event_cache::EventCacheError, presence::PresenceExtension, Client, Error as SlidingSyncError,
+    SlidingSync, SlidingSyncList, SlidingSyncMode,
 };
 use matrix_sdk_base::sliding_sync::http;
 pub use room::*;
@@ -122,7 +122,7 @@ impl RoomListService {
     /// This won't start an encryption sync, and it's the user's responsibility
     /// to create one in this case using `EncryptionSync`.
     pub async fn new(client: Client) -> Result<Self, Error> {
-        Self::new_internal(client, false).await
+        Self::new_internal(client, false, None).await
     }
 
     /// Create a new `RoomList` that enables encryption.
@@ -130,10 +130,18 @@ impl RoomListService {
     /// This will include syncing the encryption information, so there must not
     /// be any instance of `EncryptionSync` running in the background.
     pub async fn new_with_encryption(client: Client) -> Result<Self, Error> {
-        Self::new_internal(client, true).await
+        Self::new_internal(client, true, None).await
     }
 
-    async fn new_internal(client: Client, with_encryption: bool) -> Result<Self, Error> {
+    /// Create a new `RoomList`, optionally enabling encryption and the presence
+    /// extension.
+    ///
+    /// The presence extension must only be enabled if the server supports it.
+    pub(crate) async fn new_internal(
+        client: Client,
+        with_encryption: bool,
+        presence_extension: Option<PresenceExtension>,
+    ) -> Result<Self, Error> {
         let mut builder = client
             .sliding_sync("room-list")
             .map_err(Error::SlidingSync)?
@@ -158,6 +166,12 @@ impl RoomListService {
                 );
         }
 
+        if let Some(presence_extension) = presence_extension {
+            builder = builder
+                .with_extension(presence_extension)
+                .map_err(|error| Error::SlidingSync(error.into()))?;
+        }
+
         let sliding_sync = builder
             .add_cached_list(
                 SlidingSyncList::builder(ALL_ROOMS_LIST_NAME)
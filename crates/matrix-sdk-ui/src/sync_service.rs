@@ -21,23 +21,34 @@
 //! The sync service will signal errors via its
 //! [`state`](SyncService::state) that the user
 //! MUST observe. Whenever an error/termination is observed, the user MUST call
-//! [`SyncService::start()`] again to restart the room list sync.
+//! [`SyncService::start()`] again to restart the room list sync. Network
+//! errors are the exception: the service goes offline, and restarts by itself
+//! when the network is back.
+//!
+//! When the application goes into the background, the service can keep only
+//! the encryption sync running with [`SyncMode::Background`], or be stopped and
+//! run a bounded [`SyncService::catch_up`] when the application is woken up.
 
-use std::sync::{Arc, Mutex};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use eyeball::{SharedObservable, Subscriber};
 use futures_core::Future;
-use futures_util::{pin_mut, StreamExt as _};
-use matrix_sdk::Client;
+use futures_util::{future::try_join, pin_mut, StreamExt as _};
+use matrix_sdk::{presence::PresenceExtension, Client};
 use thiserror::Error;
 use tokio::{
+    select,
     sync::{
         mpsc::{Receiver, Sender},
         Mutex as AsyncMutex, OwnedMutexGuard,
     },
     task::{spawn, JoinHandle},
+    time::{sleep, timeout},
 };
-use tracing::{error, info, instrument, trace, warn, Instrument, Level};
+use tracing::{debug, error, info, instrument, trace, warn, Instrument, Level};
 
 use crate::{
     encryption_sync_service::{self, EncryptionSyncPermit, EncryptionSyncService, WithLocking},
@@ -51,6 +62,13 @@ use crate::{
 /// state `Terminated` (if it gracefully exited) or `Error` (in case any of the
 /// underlying syncs ran into an error).
 ///
+/// If the syncs can't reach the server, because of a network error or because
+/// the platform reported that the network is unreachable (see
+/// [`SyncService::set_network_reachable`]), the service goes into the
+/// `Offline` state, and restarts by itself once the network is back. It goes
+/// back to `Running` once the syncs received a response again; every attempt
+/// to reconnect that fails reports `Offline` again.
+///
 /// It is the responsibility of the caller to restart the application using the
 /// [`SyncService::start`] method, in case it terminated, gracefully or not.
 ///
@@ -65,42 +83,62 @@ pub enum State {
     Terminated,
     /// Any of the underlying syncs has ran into an error.
     Error,
+    /// The server can't be reached; the underlying syncs are stopped, or are
+    /// trying to reconnect.
+    Offline,
 }
 
-pub struct SyncService {
-    /// Room list service used to synchronize the rooms state.
-    room_list_service: Arc<RoomListService>,
+/// The mode in which the [`SyncService`] runs.
+///
+/// It can be changed at any time with [`SyncService::set_mode`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Both the room list sync and the encryption sync are running.
+    ///
+    /// This is the mode to use when the application is in the foreground.
+    #[default]
+    Foreground,
 
-    /// Encryption sync taking care of e2ee events.
-    encryption_sync_service: Arc<EncryptionSyncService>,
+    /// Only the encryption sync is running, so that to-device messages (and
+    /// room keys in particular) are still received.
+    ///
+    /// This is the mode to use when the application is in the background and
+    /// is allowed to keep a connection open. If the sync service has been
+    /// built [with the cross-process
+    /// lock](SyncServiceBuilder::with_cross_process_lock), the encryption sync
+    /// only runs while it holds the lock.
+    Background,
+}
+
+/// The initial delay before trying to restart the syncs, after they went
+/// offline.
+const INITIAL_RECONNECTION_DELAY: Duration = Duration::from_secs(1);
+
+/// The maximum delay between two attempts to restart the syncs, after they
+/// went offline.
+const MAX_RECONNECTION_DELAY: Duration = Duration::from_secs(5 * 60);
+
+pub struct SyncService {
+    /// The underlying syncs and their tasks.
+    tasks: SyncTasks,
 
     /// What's the state of this sync service?
     state: SharedObservable<State>,
 
+    /// Is the network reachable, as reported by the platform?
+    network_reachable: SharedObservable<bool>,
+
     /// Use a mutex everytime to modify the `state` value, otherwise it would be
     /// possible to have race conditions when starting or pausing the
     /// service multiple times really quickly.
     modifying_state: AsyncMutex<()>,
 
-    /// Task running the room list service.
-    room_list_task: Arc<Mutex<Option<JoinHandle<()>>>>,
-
-    /// Task running the encryption sync.
-    encryption_sync_task: Arc<Mutex<Option<JoinHandle<()>>>>,
-
-    /// Global lock to allow using at most one `EncryptionSyncService` at all
-    /// times.
-    ///
-    /// This ensures that there's only one ever existing in the application's
-    /// lifetime (under the assumption that there is at most one
-    /// `SyncService` per application).
-    encryption_sync_permit: Arc<AsyncMutex<EncryptionSyncPermit>>,
-
     /// Scheduler task ensuring proper termination.
     ///
     /// This task is waiting for a `TerminationReport` from any of the other two
     /// tasks, or from a user request via [`Self::stop()`]. It makes sure
-    /// that the two services are properly shut up and just interrupted.
+    /// that the two services are properly shut up and just interrupted. If the
+    /// syncs went offline, it also takes care of restarting them.
     ///
     /// This is set at the same time as the other two tasks.
     scheduler_task: Arc<Mutex<Option<JoinHandle<()>>>>,
@@ -120,7 +158,7 @@ impl SyncService {
     /// Get the underlying `RoomListService` instance for easier access to its
     /// methods.
     pub fn room_list_service(&self) -> Arc<RoomListService> {
-        self.room_list_service.clone()
+        self.tasks.room_list_service.clone()
     }
 
     /// Returns the state of the sync service.
@@ -128,119 +166,229 @@ impl SyncService {
         self.state.subscribe()
     }
 
+    /// Returns the mode in which the sync service runs, or will run once
+    /// started.
+    pub fn mode(&self) -> SyncMode {
+        *self.tasks.mode.lock().unwrap()
+    }
+
+    /// Change the mode in which the sync service runs.
+    ///
+    /// If the service is running, it's stopped and restarted in the new mode,
+    /// so the state goes through [`State::Idle`] before going back to
+    /// [`State::Running`]. If it's offline, the new mode is used when the
+    /// syncs are restarted. Otherwise, the new mode is used the next time
+    /// [`Self::start`] is called.
+    #[instrument(skip(self))]
+    pub async fn set_mode(&self, mode: SyncMode) -> Result<(), Error> {
+        // Hold the lock for the whole operation, so that concurrent calls to `start`
+        // or `stop` can't interleave with the restart.
+        let _guard = self.modifying_state.lock().await;
+
+        let previous_mode = std::mem::replace(&mut *self.tasks.mode.lock().unwrap(), mode);
+
+        if previous_mode == mode {
+            return Ok(());
+        }
+
+        if matches!(self.state.get(), State::Running) {
+            self.stop_locked().await?;
+            self.start_locked().await;
+        }
+
+        Ok(())
+    }
+
     /// The role of the scheduler task is to wait for a termination message
     /// (`TerminationReport`), sent either because we wanted to stop both
     /// syncs, or because one of the syncs failed (in which case we'll stop
     /// the other one too).
+    ///
+    /// If the syncs went offline, the scheduler task waits for the network to
+    /// be back, with an exponential backoff, and restarts them.
     fn spawn_scheduler_task(
         &self,
         mut receiver: Receiver<TerminationReport>,
+        sender: Sender<TerminationReport>,
     ) -> impl Future<Output = ()> {
-        let encryption_sync_task = self.encryption_sync_task.clone();
-        let encryption_sync = self.encryption_sync_service.clone();
-        let room_list_service = self.room_list_service.clone();
-        let room_list_task = self.room_list_task.clone();
+        let tasks = self.tasks.clone();
         let state = self.state.clone();
+        let network_reachable = self.network_reachable.clone();
 
         async move {
-            let Some(report) = receiver.recv().await else {
-                info!("internal channel has been closed?");
-                return;
-            };
+            let mut reconnection_delay = INITIAL_RECONNECTION_DELAY;
+
+            // Are the syncs trying to reconnect, after they went offline?
+            let mut reconnecting = false;
+
+            loop {
+                let report = if reconnecting {
+                    select! {
+                        biased;
+
+                        report = receiver.recv() => report,
+                        _ = Self::wait_for_sync(&tasks.has_synced) => {
+                            // The syncs are back; requests that failed while we were offline may
+                            // be sent again.
+                            reconnecting = false;
+                            tasks.room_list_service.client().send_queue().set_enabled(true).await;
+                            state.set(State::Running);
+
+                            continue;
+                        }
+                    }
+                } else {
+                    receiver.recv().await
+                };
+
+                let Some(report) = report else {
+                    info!("internal channel has been closed?");
+                    return;
+                };
+
+                tasks.stop(&report).await;
+
+                if report.is_offline {
+                    // The syncs went well since the last time we were offline, so the backoff
+                    // can start over.
+                    if tasks.has_synced.set(false) {
+                        reconnection_delay = INITIAL_RECONNECTION_DELAY;
+                    }
 
-            // If one service failed, make sure to request stopping the other one.
-            let (stop_room_list, stop_encryption) = match &report.origin {
-                TerminationOrigin::EncryptionSync => (true, false),
-                TerminationOrigin::RoomList => (false, true),
-                TerminationOrigin::Scheduler => (true, true),
-            };
+                    state.set(State::Offline);
 
-            // Stop both services, and wait for the streams to properly finish: at some
-            // point they'll return `None` and will exit their infinite loops,
-            // and their tasks will gracefully terminate.
+                    info!(?reconnection_delay, "sync service is offline, waiting to reconnect");
 
-            if stop_room_list {
-                if let Err(err) = room_list_service.stop_sync() {
-                    warn!(?report, "unable to stop room list service: {err:#}");
-                }
-            }
+                    let must_stop = select! {
+                        biased;
 
-            {
-                let task = room_list_task.lock().unwrap().take();
-                if let Some(task) = task {
-                    if let Err(err) = task.await {
-                        error!("when awaiting room list service: {err:#}");
+                        must_stop = Self::wait_for_stop_request(&mut receiver) => must_stop,
+                        _ = Self::wait_for_reconnection(&network_reachable, reconnection_delay) => false,
+                    };
+
+                    if must_stop {
+                        state.set(State::Idle);
+                        return;
                     }
+
+                    reconnection_delay = (reconnection_delay * 2).min(MAX_RECONNECTION_DELAY);
+
+                    trace!("restarting sync service after going offline");
+
+                    // Stay offline until the syncs received a response.
+                    tasks.spawn(sender.clone()).await;
+                    reconnecting = true;
+
+                    continue;
                 }
-            }
 
-            if stop_encryption {
-                if let Err(err) = encryption_sync.stop_sync() {
-                    warn!(?report, "unable to stop encryption sync: {err:#}");
+                if report.is_error {
+                    state.set(State::Error);
+                } else if matches!(report.origin, TerminationOrigin::Scheduler) {
+                    state.set(State::Idle);
+                } else {
+                    state.set(State::Terminated);
                 }
+
+                return;
             }
+        }
+        .instrument(tracing::span!(Level::WARN, "scheduler task"))
+    }
 
-            {
-                let task = encryption_sync_task.lock().unwrap().take();
-                if let Some(task) = task {
-                    if let Err(err) = task.await {
-                        error!("when awaiting encryption sync: {err:#}");
-                    }
-                }
+    /// Wait until any of the underlying syncs received a response.
+    async fn wait_for_sync(has_synced: &SharedObservable<bool>) {
+        let mut has_synced = has_synced.subscribe();
+
+        while !has_synced.get() {
+            if has_synced.next().await.is_none() {
+                // The observable can't be dropped while we're holding it.
+                return;
             }
+        }
+    }
 
-            if report.is_error {
-                if report.has_expired {
-                    if stop_room_list {
-                        room_list_service.expire_sync_session().await;
-                    }
-                    if stop_encryption {
-                        encryption_sync.expire_sync_session().await;
-                    }
-                }
+    /// Wait until a request to stop the sync service is received, while the
+    /// syncs are offline.
+    ///
+    /// Returns `true` if the service must stop, `false` if the internal channel
+    /// has been closed.
+    async fn wait_for_stop_request(receiver: &mut Receiver<TerminationReport>) -> bool {
+        while let Some(report) = receiver.recv().await {
+            if matches!(report.origin, TerminationOrigin::Scheduler) {
+                return true;
+            }
+            // Other reports (e.g. the network being reported unreachable again)
+            // don't matter while the syncs are stopped.
+        }
+
+        false
+    }
 
-                state.set(State::Error);
-            } else if matches!(report.origin, TerminationOrigin::Scheduler) {
-                state.set(State::Idle);
+    /// Wait until it's time to try to restart the syncs.
+    ///
+    /// If the network is reported unreachable, wait for it to be reachable
+    /// again; otherwise, wait for the given delay, or for the network to be
+    /// reported reachable, whatever comes first.
+    async fn wait_for_reconnection(network_reachable: &SharedObservable<bool>, delay: Duration) {
+        let mut network_reachable = network_reachable.subscribe();
+
+        loop {
+            if network_reachable.get() {
+                select! {
+                    _ = sleep(delay) => return,
+                    reachable = network_reachable.next() => match reachable {
+                        // The network was reported reachable again; reconnect immediately.
+                        Some(true) => return,
+                        Some(false) => continue,
+                        None => return,
+                    },
+                }
             } else {
-                state.set(State::Terminated);
+                match network_reachable.next().await {
+                    Some(true) | None => return,
+                    Some(false) => continue,
+                }
             }
         }
-        .instrument(tracing::span!(Level::WARN, "scheduler task"))
     }
 
     async fn encryption_sync_task(
         encryption_sync: Arc<EncryptionSyncService>,
         sender: Sender<TerminationReport>,
         sync_permit_guard: OwnedMutexGuard<EncryptionSyncPermit>,
+        has_synced: SharedObservable<bool>,
     ) {
         let encryption_sync_stream = encryption_sync.sync(sync_permit_guard);
         pin_mut!(encryption_sync_stream);
 
-        let (is_error, has_expired) = loop {
+        let (is_error, has_expired, is_offline) = loop {
             let res = encryption_sync_stream.next().await;
             match res {
                 Some(Ok(())) => {
-                    // Carry on.
+                    has_synced.set_if_not_eq(true);
                 }
                 Some(Err(err)) => {
                     // If the encryption sync error was an expired session, also expire the
                     // room list sync.
-                    let has_expired = if let encryption_sync_service::Error::SlidingSync(err) = &err
-                    {
-                        err.client_api_error_kind()
-                            == Some(&ruma::api::client::error::ErrorKind::UnknownPos)
-                    } else {
-                        false
-                    };
-                    if !has_expired {
+                    let (has_expired, is_offline) =
+                        if let encryption_sync_service::Error::SlidingSync(err) = &err {
+                            (
+                                err.client_api_error_kind()
+                                    == Some(&ruma::api::client::error::ErrorKind::UnknownPos),
+                                is_network_error(err),
+                            )
+                        } else {
+                            (false, false)
+                        };
+                    if !has_expired && !is_offline {
                         error!("Error while processing encryption in sync service: {err:#}");
                     }
-                    break (true, has_expired);
+                    break (true, has_expired, is_offline);
                 }
                 None => {
                     // The stream has ended.
-                    break (false, false);
+                    break (false, false, false);
                 }
             }
         };
@@ -249,6 +397,7 @@ impl SyncService {
             .send(TerminationReport {
                 is_error,
                 has_expired,
+                is_offline,
                 origin: TerminationOrigin::EncryptionSync,
             })
             .await
@@ -260,39 +409,49 @@ impl SyncService {
     async fn room_list_sync_task(
         room_list_service: Arc<RoomListService>,
         sender: Sender<TerminationReport>,
+        has_synced: SharedObservable<bool>,
     ) {
         let room_list_stream = room_list_service.sync();
         pin_mut!(room_list_stream);
 
-        let (is_error, has_expired) = loop {
+        let (is_error, has_expired, is_offline) = loop {
             let res = room_list_stream.next().await;
             match res {
                 Some(Ok(())) => {
-                    // Carry on.
+                    has_synced.set_if_not_eq(true);
                 }
                 Some(Err(err)) => {
                     // If the room list error was an expired session, also expire the
                     // encryption sync.
-                    let has_expired = if let room_list_service::Error::SlidingSync(err) = &err {
-                        err.client_api_error_kind()
-                            == Some(&ruma::api::client::error::ErrorKind::UnknownPos)
-                    } else {
-                        false
-                    };
-                    if !has_expired {
+                    let (has_expired, is_offline) =
+                        if let room_list_service::Error::SlidingSync(err) = &err {
+                            (
+                                err.client_api_error_kind()
+                                    == Some(&ruma::api::client::error::ErrorKind::UnknownPos),
+                                is_network_error(err),
+                            )
+                        } else {
+                            (false, false)
+                        };
+                    if !has_expired && !is_offline {
                         error!("Error while processing room list in sync service: {err:#}");
                     }
-                    break (true, has_expired);
+                    break (true, has_expired, is_offline);
                 }
                 None => {
                     // The stream has ended.
-                    break (false, false);
+                    break (false, false, false);
                 }
             }
         };
 
         if let Err(err) = sender
-            .send(TerminationReport { is_error, has_expired, origin: TerminationOrigin::RoomList })
+            .send(TerminationReport {
+                is_error,
+                has_expired,
+                is_offline,
+                origin: TerminationOrigin::RoomList,
+            })
             .await
         {
             error!("Error while sending termination report: {err:#}");
@@ -305,11 +464,18 @@ impl SyncService {
     /// - if the stream is still properly running, it won't be restarted.
     /// - if the stream has been aborted before, it will be properly cleaned up
     ///   and restarted.
+    /// - if the service is offline, it will restart by itself when the network
+    ///   is back, so nothing happens.
     pub async fn start(&self) {
         let _guard = self.modifying_state.lock().await;
+        self.start_locked().await;
+    }
 
+    /// Implementation of [`Self::start`], to be called with the
+    /// `modifying_state` lock held.
+    async fn start_locked(&self) {
         // Only (re)start the tasks if any was stopped.
-        if matches!(self.state.get(), State::Running) {
+        if matches!(self.state.get(), State::Running | State::Offline) {
             // It was already true, so we can skip the restart.
             return;
         }
@@ -318,21 +484,12 @@ impl SyncService {
 
         let (sender, receiver) = tokio::sync::mpsc::channel(16);
 
-        // First, take care of the room list.
-        *self.room_list_task.lock().unwrap() =
-            Some(spawn(Self::room_list_sync_task(self.room_list_service.clone(), sender.clone())));
-
-        // Then, take care of the encryption sync.
-        let sync_permit_guard = self.encryption_sync_permit.clone().lock_owned().await;
-        *self.encryption_sync_task.lock().unwrap() = Some(spawn(Self::encryption_sync_task(
-            self.encryption_sync_service.clone(),
-            sender.clone(),
-            sync_permit_guard,
-        )));
+        self.tasks.spawn(sender.clone()).await;
 
         // Spawn the scheduler task.
-        *self.scheduler_sender.lock().unwrap() = Some(sender);
-        *self.scheduler_task.lock().unwrap() = Some(spawn(self.spawn_scheduler_task(receiver)));
+        *self.scheduler_sender.lock().unwrap() = Some(sender.clone());
+        *self.scheduler_task.lock().unwrap() =
+            Some(spawn(self.spawn_scheduler_task(receiver, sender)));
 
         self.state.set(State::Running);
     }
@@ -345,13 +502,18 @@ impl SyncService {
     #[instrument(skip_all)]
     pub async fn stop(&self) -> Result<(), Error> {
         let _guard = self.modifying_state.lock().await;
+        self.stop_locked().await
+    }
 
+    /// Implementation of [`Self::stop`], to be called with the
+    /// `modifying_state` lock held.
+    async fn stop_locked(&self) -> Result<(), Error> {
         match self.state.get() {
             State::Idle | State::Terminated | State::Error => {
                 // No need to stop if we were not running.
                 return Ok(());
             }
-            State::Running => {}
+            State::Running | State::Offline => {}
         };
 
         trace!("pausing sync service");
@@ -360,34 +522,144 @@ impl SyncService {
         // later, so that we're in a clean state independently of the request to
         // stop.
 
-        let sender = self.scheduler_sender.lock().unwrap().clone();
-        sender
+        self.send_termination_report(TerminationReport {
+            is_error: false,
+            has_expired: false,
+            is_offline: false,
+            origin: TerminationOrigin::Scheduler,
+        })
+        .await?;
+
+        let scheduler_task = self.scheduler_task.lock().unwrap().take();
+        scheduler_task
             .ok_or_else(|| {
-                error!("missing sender");
+                error!("missing scheduler task");
                 Error::InternalSchedulerError
             })?
-            .send(TerminationReport {
-                is_error: false,
-                has_expired: false,
-                origin: TerminationOrigin::Scheduler,
-            })
             .await
             .map_err(|err| {
-                error!("when sending termination report: {err}");
+                error!("couldn't finish scheduler task: {err}");
                 Error::InternalSchedulerError
             })?;
 
-        let scheduler_task = self.scheduler_task.lock().unwrap().take();
-        scheduler_task
+        Ok(())
+    }
+
+    /// Report whether the network is reachable, as observed by the platform.
+    ///
+    /// If the network becomes unreachable while the syncs are running, they're
+    /// stopped and the service goes into the [`State::Offline`] state. If the
+    /// network becomes reachable while the service is offline, the syncs are
+    /// restarted immediately.
+    ///
+    /// Without this, the service still detects network errors by itself, and
+    /// tries to restart the syncs with an exponential backoff.
+    #[instrument(skip(self))]
+    pub async fn set_network_reachable(&self, reachable: bool) -> Result<(), Error> {
+        let _guard = self.modifying_state.lock().await;
+
+        if self.network_reachable.set_if_not_eq(reachable).is_none() {
+            // Nothing changed.
+            return Ok(());
+        }
+
+        if !reachable && matches!(self.state.get(), State::Running) {
+            self.send_termination_report(TerminationReport {
+                is_error: false,
+                has_expired: false,
+                is_offline: true,
+                origin: TerminationOrigin::Network,
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a `TerminationReport` to the scheduler task.
+    async fn send_termination_report(&self, report: TerminationReport) -> Result<(), Error> {
+        let sender = self.scheduler_sender.lock().unwrap().clone();
+        sender
             .ok_or_else(|| {
-                error!("missing scheduler task");
+                error!("missing sender");
                 Error::InternalSchedulerError
             })?
+            .send(report)
             .await
             .map_err(|err| {
-                error!("couldn't finish scheduler task: {err}");
+                error!("when sending termination report: {err}");
                 Error::InternalSchedulerError
-            })?;
+            })
+    }
+
+    /// Run the underlying syncs once, to catch up with what happened since the
+    /// last sync, and stop them.
+    ///
+    /// This is meant to be used when the application is woken up in the
+    /// background for a limited amount of time: the room list sync runs until
+    /// the room list is up-to-date, and the encryption sync runs until it has
+    /// received a response, whatever comes last. Both are stopped when the
+    /// deadline is reached; what has been received until then is kept. If the
+    /// encryption sync permit is held elsewhere, it's waited for until the
+    /// deadline too.
+    ///
+    /// This doesn't change the [state](Self::state) of the service, and fails
+    /// with [`Error::AlreadyRunning`] if it's running (or offline). The
+    /// cross-process lock is honoured, if it's enabled.
+    #[instrument(skip(self))]
+    pub async fn catch_up(&self, deadline: Duration) -> Result<(), Error> {
+        let _guard = self.modifying_state.lock().await;
+
+        if matches!(self.state.get(), State::Running | State::Offline) {
+            return Err(Error::AlreadyRunning);
+        }
+
+        trace!("catching up");
+
+        let room_list_service = &self.tasks.room_list_service;
+        let encryption_sync_service = &self.tasks.encryption_sync_service;
+        let encryption_sync_permit = self.tasks.encryption_sync_permit.clone();
+
+        let room_list = async {
+            let room_list_stream = room_list_service.sync();
+            pin_mut!(room_list_stream);
+
+            while let Some(result) = room_list_stream.next().await {
+                result?;
+
+                if matches!(room_list_service.state().get(), room_list_service::State::Running) {
+                    break;
+                }
+            }
+
+            Ok::<_, Error>(())
+        };
+
+        let encryption_sync = async {
+            // The permit may be held by someone else (e.g. a notification client): wait
+            // for it within the deadline too.
+            let sync_permit_guard = encryption_sync_permit.lock_owned().await;
+            let encryption_sync_stream = encryption_sync_service.sync(sync_permit_guard);
+            pin_mut!(encryption_sync_stream);
+
+            if let Some(result) = encryption_sync_stream.next().await {
+                result?;
+            }
+
+            Ok::<_, Error>(())
+        };
+
+        // The streams are dropped once done, which stops the syncs: the responses that
+        // have been received are still fully handled, though.
+        match timeout(deadline, try_join(room_list, encryption_sync)).await {
+            Ok(result) => {
+                result?;
+                trace!("caught up");
+            }
+            Err(_) => {
+                debug!("deadline reached before catching up");
+            }
+        }
 
         Ok(())
     }
@@ -398,21 +670,165 @@ impl SyncService {
     /// This ensures there is at most one [`EncryptionSyncService`] active at
     /// any time, per application.
     pub fn try_get_encryption_sync_permit(&self) -> Option<OwnedMutexGuard<EncryptionSyncPermit>> {
-        self.encryption_sync_permit.clone().try_lock_owned().ok()
+        self.tasks.encryption_sync_permit.clone().try_lock_owned().ok()
     }
 }
 
+/// The underlying syncs, and the tasks running them.
+///
+/// This is shared between the [`SyncService`] and its scheduler task, so that
+/// the latter can restart the syncs after they went offline.
+#[derive(Clone)]
+struct SyncTasks {
+    /// Room list service used to synchronize the rooms state.
+    room_list_service: Arc<RoomListService>,
+
+    /// Encryption sync taking care of e2ee events.
+    encryption_sync_service: Arc<EncryptionSyncService>,
+
+    /// Which syncs must be running?
+    mode: Arc<Mutex<SyncMode>>,
+
+    /// Task running the room list service.
+    room_list_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+
+    /// Task running the encryption sync.
+    encryption_sync_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+
+    /// Interval at which the presence of direct targets must be polled, if
+    /// presence is enabled but the server doesn't support the presence
+    /// sliding sync extension.
+    presence_poll_interval: Option<Duration>,
+
+    /// Task polling the presence of direct targets, if any.
+    presence_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+
+    /// Global lock to allow using at most one `EncryptionSyncService` at all
+    /// times.
+    ///
+    /// This ensures that there's only one ever existing in the application's
+    /// lifetime (under the assumption that there is at most one
+    /// `SyncService` per application).
+    encryption_sync_permit: Arc<AsyncMutex<EncryptionSyncPermit>>,
+
+    /// Has any of the syncs received a response since the last time they went
+    /// offline?
+    has_synced: SharedObservable<bool>,
+}
+
+impl SyncTasks {
+    /// Spawn the tasks running the underlying syncs.
+    async fn spawn(&self, sender: Sender<TerminationReport>) {
+        let mode = *self.mode.lock().unwrap();
+
+        // First, take care of the room list, which is only needed in the foreground.
+        if mode == SyncMode::Foreground {
+            *self.room_list_task.lock().unwrap() = Some(spawn(SyncService::room_list_sync_task(
+                self.room_list_service.clone(),
+                sender.clone(),
+                self.has_synced.clone(),
+            )));
+        }
+
+        // Then, take care of the encryption sync.
+        let sync_permit_guard = self.encryption_sync_permit.clone().lock_owned().await;
+        *self.encryption_sync_task.lock().unwrap() =
+            Some(spawn(SyncService::encryption_sync_task(
+                self.encryption_sync_service.clone(),
+                sender,
+                sync_permit_guard,
+                self.has_synced.clone(),
+            )));
+
+        // Poll presence, if needs be.
+        if let Some(interval) = self.presence_poll_interval.filter(|_| mode == SyncMode::Foreground)
+        {
+            *self.presence_task.lock().unwrap() =
+                Some(self.room_list_service.client().presence().poll_direct_targets(interval));
+        }
+    }
+
+    /// Stop the tasks running the underlying syncs, after a termination report
+    /// has been received.
+    async fn stop(&self, report: &TerminationReport) {
+        // Presence polling doesn't make sense if the syncs aren't running.
+        if let Some(task) = self.presence_task.lock().unwrap().take() {
+            task.abort();
+        }
+
+        // If one service failed, make sure to request stopping the other one.
+        let (stop_room_list, stop_encryption) = match &report.origin {
+            TerminationOrigin::EncryptionSync => (true, false),
+            TerminationOrigin::RoomList => (false, true),
+            TerminationOrigin::Scheduler | TerminationOrigin::Network => (true, true),
+        };
+
+        // Stop both services, and wait for the streams to properly finish: at some
+        // point they'll return `None` and will exit their infinite loops,
+        // and their tasks will gracefully terminate.
+
+        // The room list doesn't run in the background.
+        let stop_room_list = stop_room_list && self.room_list_task.lock().unwrap().is_some();
+
+        if stop_room_list {
+            if let Err(err) = self.room_list_service.stop_sync() {
+                warn!(?report, "unable to stop room list service: {err:#}");
+            }
+        }
+
+        {
+            let task = self.room_list_task.lock().unwrap().take();
+            if let Some(task) = task {
+                if let Err(err) = task.await {
+                    error!("when awaiting room list service: {err:#}");
+                }
+            }
+        }
+
+        if stop_encryption {
+            if let Err(err) = self.encryption_sync_service.stop_sync() {
+                warn!(?report, "unable to stop encryption sync: {err:#}");
+            }
+        }
+
+        {
+            let task = self.encryption_sync_task.lock().unwrap().take();
+            if let Some(task) = task {
+                if let Err(err) = task.await {
+                    error!("when awaiting encryption sync: {err:#}");
+                }
+            }
+        }
+
+        if report.is_error && report.has_expired {
+            if stop_room_list {
+                self.room_list_service.expire_sync_session().await;
+            }
+            if stop_encryption {
+                self.encryption_sync_service.expire_sync_session().await;
+            }
+        }
+    }
+}
+
+/// Is this error caused by the network, i.e. the server couldn't be reached?
+fn is_network_error(error: &matrix_sdk::Error) -> bool {
+    matches!(error, matrix_sdk::Error::Http(error) if error.is_network_error())
+}
+
 #[derive(Debug)]
 enum TerminationOrigin {
     EncryptionSync,
     RoomList,
     Scheduler,
+    Network,
 }
 
 #[derive(Debug)]
 struct TerminationReport {
     is_error: bool,
     has_expired: bool,
+    is_offline: bool,
     origin: TerminationOrigin,
 }
 
@@ -422,8 +838,8 @@ impl SyncService {
     /// Return the existential states of internal tasks.
     pub fn task_states(&self) -> (bool, bool) {
         (
-            self.encryption_sync_task.lock().unwrap().is_some(),
-            self.room_list_task.lock().unwrap().is_some(),
+            self.tasks.encryption_sync_task.lock().unwrap().is_some(),
+            self.tasks.room_list_task.lock().unwrap().is_some(),
         )
     }
 }
@@ -439,11 +855,21 @@ pub struct SyncServiceBuilder {
     /// Application identifier, used as the cross-process lock value, if
     /// applicable.
     identifier: String,
+
+    /// Is presence enabled? If so, the name of the presence sliding sync
+    /// extension, and the interval at which presence is polled when the
+    /// server doesn't support it.
+    presence: Option<(String, Duration)>,
 }
 
 impl SyncServiceBuilder {
     fn new(client: Client) -> Self {
-        Self { client, with_cross_process_lock: false, identifier: "app".to_owned() }
+        Self {
+            client,
+            with_cross_process_lock: false,
+            identifier: "app".to_owned(),
+            presence: None,
+        }
     }
 
     /// Enables the cross-process lock, if the sync service is being built in a
@@ -465,6 +891,21 @@ impl SyncServiceBuilder {
         self
     }
 
+    /// Enables presence.
+    ///
+    /// If the server advertises `extension_name` in the `unstable_features` of
+    /// its `/versions` response, presence is received through the room list
+    /// sync with a [`PresenceExtension`] of that name. Otherwise, the presence
+    /// of the users we share a direct room with is polled at the given
+    /// interval while the sync service is running.
+    ///
+    /// Presence can then be observed with
+    /// [`Presence::subscribe`](matrix_sdk::presence::Presence::subscribe).
+    pub fn with_presence(mut self, extension_name: String, poll_interval: Duration) -> Self {
+        self.presence = Some((extension_name, poll_interval));
+        self
+    }
+
     /// Finish setting up the `SyncService`.
     ///
     /// This creates the underlying sliding syncs, and will *not* start them in
@@ -473,7 +914,28 @@ impl SyncServiceBuilder {
     pub async fn build(self) -> Result<SyncService, Error> {
         let encryption_sync_permit = Arc::new(AsyncMutex::new(EncryptionSyncPermit::new()));
 
-        let room_list = RoomListService::new(self.client.clone()).await?;
+        let mut presence_extension = None;
+        let mut presence_poll_interval = None;
+
+        if let Some((extension_name, poll_interval)) = self.presence {
+            let supported = match self.client.unstable_features().await {
+                Ok(features) => features.get(&extension_name).copied().unwrap_or(false),
+                Err(err) => {
+                    warn!("Couldn't check whether the presence extension is supported: {err}");
+                    false
+                }
+            };
+
+            if supported {
+                // The server supports presence via sliding sync, no need to poll.
+                presence_extension = Some(PresenceExtension::new(extension_name));
+            } else {
+                presence_poll_interval = Some(poll_interval);
+            }
+        }
+
+        let room_list =
+            RoomListService::new_internal(self.client.clone(), false, presence_extension).await?;
 
         let encryption_sync = Arc::new(
             EncryptionSyncService::new(
@@ -486,15 +948,22 @@ impl SyncServiceBuilder {
         );
 
         Ok(SyncService {
-            room_list_service: Arc::new(room_list),
-            encryption_sync_service: encryption_sync,
-            encryption_sync_task: Arc::new(Mutex::new(None)),
-            room_list_task: Arc::new(Mutex::new(None)),
+            tasks: SyncTasks {
+                room_list_service: Arc::new(room_list),
+                encryption_sync_service: encryption_sync,
+                mode: Arc::new(Mutex::new(SyncMode::default())),
+                room_list_task: Arc::new(Mutex::new(None)),
+                encryption_sync_task: Arc::new(Mutex::new(None)),
+                presence_poll_interval,
+                presence_task: Arc::new(Mutex::new(None)),
+                encryption_sync_permit,
+                has_synced: SharedObservable::new(false),
+            },
             scheduler_task: Arc::new(Mutex::new(None)),
             scheduler_sender: Mutex::new(None),
             state: SharedObservable::new(State::Idle),
+            network_reachable: SharedObservable::new(true),
             modifying_state: AsyncMutex::new(()),
-            encryption_sync_permit,
         })
     }
 }
@@ -512,4 +981,8 @@ pub enum Error {
 
     #[error("the scheduler channel has run into an unexpected error")]
     InternalSchedulerError,
+
+    /// The operation can't be done while the service is running.
+    #[error("the sync service is running")]
+    AlreadyRunning,
 }
@@ -96,6 +96,25 @@ impl TimelineBuilder {
         self
     }
 
+    /// Enable the insertion of [`VirtualTimelineItem::IdentityChange`] items
+    /// when the identity of a room member changes in a way the user should
+    /// be warned about.
+    ///
+    /// The changes are persisted, so their items are inserted at the same
+    /// place when the timeline is created again. Only a live timeline records
+    /// new changes; a timeline with another focus shows the ones which have
+    /// been persisted. All the changes of a room can be acknowledged with
+    /// [`Room::pin_all_changed_identities()`].
+    ///
+    /// This has no effect on WebAssembly, where the identity changes of the
+    /// room members can't be observed yet.
+    ///
+    /// [`VirtualTimelineItem::IdentityChange`]: super::VirtualTimelineItem::IdentityChange
+    pub fn track_identity_changes(mut self) -> Self {
+        self.settings.track_identity_changes = true;
+        self
+    }
+
     /// Use the given filter to choose whether to add events to the timeline.
     ///
     /// # Arguments
@@ -158,6 +177,9 @@ impl TimelineBuilder {
         let (_, mut event_subscriber) = room_event_cache.subscribe().await?;
 
         let is_pinned_events = matches!(focus, TimelineFocus::PinnedEvents { .. });
+        #[cfg(not(target_arch = "wasm32"))]
+        let track_identity_changes =
+            settings.track_identity_changes && matches!(focus, TimelineFocus::Live);
         let is_room_encrypted = room.is_encrypted().await.ok();
 
         let controller = TimelineController::new(
@@ -195,6 +217,31 @@ impl TimelineBuilder {
             None
         };
 
+        #[cfg(not(target_arch = "wasm32"))]
+        let identity_changes_join_handle = track_identity_changes.then(|| {
+            let inner = controller.clone();
+            let room = room.clone();
+
+            spawn(async move {
+                let stream = match room.subscribe_to_identity_status_changes().await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!(
+                            "Couldn't subscribe to the identity changes of the room members: {e}"
+                        );
+                        return;
+                    }
+                };
+                pin_mut!(stream);
+
+                while let Some(changes) = stream.next().await {
+                    inner.handle_identity_status_changes(changes).await;
+                }
+            })
+        });
+        #[cfg(target_arch = "wasm32")]
+        let identity_changes_join_handle = None;
+
         let encryption_changes_handle = spawn({
             let inner = controller.clone();
             async move {
@@ -426,6 +473,38 @@ impl TimelineBuilder {
             })
         };
 
+        let sender_data_updates_join_handle = {
+            let inner = controller.clone();
+            let encryption = client.encryption();
+
+            spawn(async move {
+                let stream = match encryption.sender_data_updates_stream().await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Couldn't subscribe to the sender data updates: {e}");
+                        return;
+                    }
+                };
+                pin_mut!(stream);
+
+                while let Some(infos) = stream.next().await {
+                    let room = inner.room();
+
+                    // The shields of the events encrypted with those room keys might have
+                    // changed.
+                    let session_ids: BTreeSet<_> = infos
+                        .into_iter()
+                        .filter(|info| info.room_id == *room.room_id())
+                        .map(|info| info.session_id)
+                        .collect();
+
+                    if !session_ids.is_empty() {
+                        inner.retry_event_encryption_info(room, session_ids).await;
+                    }
+                }
+            })
+        };
+
         let timeline = Timeline {
             controller,
             event_cache: room_event_cache,
@@ -436,9 +515,11 @@ impl TimelineBuilder {
                 pinned_events_join_handle,
                 room_key_from_backups_join_handle,
                 room_key_backup_enabled_join_handle,
+                sender_data_updates_join_handle,
                 local_echo_listener_handle,
                 _event_cache_drop_handle: event_cache_drop,
                 encryption_changes_handle,
+                identity_changes_join_handle,
             }),
         };
 
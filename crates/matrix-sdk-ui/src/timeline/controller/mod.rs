@@ -12,7 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::BTreeSet, fmt, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::Arc,
+};
 
 use as_variant::as_variant;
 use eyeball_im::{ObservableVectorEntry, VectorDiff};
@@ -22,6 +26,7 @@ use imbl::Vector;
 #[cfg(test)]
 use matrix_sdk::crypto::OlmMachine;
 use matrix_sdk::{
+    crypto::{types::events::CryptoContextInfo, IdentityStatusChange},
     deserialized_responses::SyncTimelineEvent,
     event_cache::{paginator::Paginator, RoomEventCache},
     send_queue::{
@@ -41,8 +46,8 @@ use ruma::{
         AnySyncTimelineEvent, MessageLikeEventType,
     },
     serde::Raw,
-    EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedTransactionId, RoomVersionId,
-    TransactionId, UserId,
+    EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedTransactionId, OwnedUserId,
+    RoomVersionId, TransactionId, UserId,
 };
 #[cfg(test)]
 use ruma::{events::receipt::ReceiptEventContent, RoomId};
@@ -51,6 +56,7 @@ use tracing::{
     debug, error, field, field::debug, info, info_span, instrument, trace, warn, Instrument as _,
 };
 
+use self::state::with_crypto_context_infos;
 pub(super) use self::state::{
     EventMeta, FullEventMeta, PendingEdit, PendingEditKind, TimelineEnd, TimelineMetadata,
     TimelineState, TimelineStateTransaction,
@@ -58,6 +64,7 @@ pub(super) use self::state::{
 use super::{
     event_handler::TimelineEventKind,
     event_item::{ReactionStatus, RemoteEventOrigin},
+    identity_changes::merge_records,
     item::TimelineUniqueId,
     traits::{Decryptor, RoomDataProvider},
     util::{rfind_event_by_id, rfind_event_item, RelativePosition},
@@ -127,6 +134,9 @@ pub(super) struct TimelineSettings {
     pub(super) event_filter: Arc<TimelineEventFilterFn>,
     /// Are unparsable events added as timeline items of their own kind?
     pub(super) add_failed_to_parse: bool,
+    /// Should the identity changes of the room members be inserted as timeline
+    /// items?
+    pub(super) track_identity_changes: bool,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -135,6 +145,7 @@ impl fmt::Debug for TimelineSettings {
         f.debug_struct("TimelineSettings")
             .field("track_read_receipts", &self.track_read_receipts)
             .field("add_failed_to_parse", &self.add_failed_to_parse)
+            .field("track_identity_changes", &self.track_identity_changes)
             .finish_non_exhaustive()
     }
 }
@@ -145,6 +156,7 @@ impl Default for TimelineSettings {
             track_read_receipts: false,
             event_filter: Arc::new(default_event_filter),
             add_failed_to_parse: true,
+            track_identity_changes: false,
         }
     }
 }
@@ -636,6 +648,9 @@ impl<P: RoomDataProvider> TimelineController<P> {
             return Default::default();
         }
 
+        let events = events.into_iter().map(Into::into).collect::<Vec<_>>();
+        let events = self.load_crypto_context_for(events).await;
+
         let mut state = self.state.write().await;
         state
             .add_remote_events_at(
@@ -648,6 +663,26 @@ impl<P: RoomDataProvider> TimelineController<P> {
             .await
     }
 
+    /// If any of the given events couldn't be decrypted, find out the crypto
+    /// context needed to explain why before taking the state lock, since
+    /// finding it out may require contacting the homeserver and looking into
+    /// the crypto store.
+    async fn load_crypto_context_for(
+        &self,
+        events: Vec<SyncTimelineEvent>,
+    ) -> Vec<(SyncTimelineEvent, Option<CryptoContextInfo>)> {
+        if events.iter().any(|event| {
+            matches!(
+                event.kind,
+                matrix_sdk::deserialized_responses::TimelineEventKind::UnableToDecrypt { .. }
+            )
+        }) {
+            self.room_data_provider.load_crypto_context().await;
+        }
+
+        with_crypto_context_infos(events, &self.room_data_provider).await
+    }
+
     pub(super) async fn clear(&self) {
         self.state.write().await.clear();
     }
@@ -664,6 +699,8 @@ impl<P: RoomDataProvider> TimelineController<P> {
         events: Vec<SyncTimelineEvent>,
         origin: RemoteEventOrigin,
     ) {
+        let events = self.load_crypto_context_for(events).await;
+
         let mut state = self.state.write().await;
 
         let track_read_markers = self.settings.track_read_receipts;
@@ -698,12 +735,42 @@ impl<P: RoomDataProvider> TimelineController<P> {
                 state.handle_fully_read_marker(fully_read_event_id);
             }
         }
+
+        if self.settings.track_identity_changes {
+            let records = self.room_data_provider.load_identity_changes().await;
+            state.set_identity_changes(records);
+        }
     }
 
     pub(super) async fn handle_fully_read_marker(&self, fully_read_event_id: OwnedEventId) {
         self.state.write().await.handle_fully_read_marker(fully_read_event_id);
     }
 
+    /// Inserts items for the given identity changes of the room members, and
+    /// persists them.
+    ///
+    /// Only a live timeline records changes, since they're placed after its
+    /// latest event; other timelines only show the persisted ones.
+    pub(super) async fn handle_identity_status_changes(&self, changes: Vec<IdentityStatusChange>) {
+        if !self.is_live().await {
+            return;
+        }
+
+        let records = {
+            let mut state = self.state.write().await;
+            if !state.handle_identity_status_changes(changes) {
+                return;
+            }
+            state.meta.identity_changes.records().to_vec()
+        };
+
+        // Another live timeline of the same room may have persisted changes too, don't
+        // overwrite them.
+        let persisted = self.room_data_provider.load_identity_changes().await;
+        let records = merge_records(persisted, &records);
+        self.room_data_provider.save_identity_changes(records).await;
+    }
+
     pub(super) async fn handle_ephemeral_events(
         &self,
         events: Vec<Raw<AnySyncEphemeralRoomEvent>>,
@@ -1100,6 +1167,95 @@ impl<P: RoomDataProvider> TimelineController<P> {
         });
     }
 
+    /// Refresh the encryption info of the decrypted events which were
+    /// encrypted with one of the given Megolm sessions, e.g. because the
+    /// sender data of those sessions got upgraded.
+    #[instrument(skip(self, room), fields(room_id = ?room.room_id()))]
+    pub(super) async fn retry_event_encryption_info(
+        &self,
+        room: &Room,
+        session_ids: BTreeSet<String>,
+    ) {
+        self.retry_event_encryption_info_inner(room.to_owned(), session_ids).await
+    }
+
+    #[cfg(test)]
+    pub(super) async fn retry_event_encryption_info_test(
+        &self,
+        room_id: &RoomId,
+        olm_machine: OlmMachine,
+        session_ids: BTreeSet<String>,
+    ) {
+        self.retry_event_encryption_info_inner((olm_machine, room_id.to_owned()), session_ids).await
+    }
+
+    async fn retry_event_encryption_info_inner(
+        &self,
+        decryptor: impl Decryptor,
+        session_ids: BTreeSet<String>,
+    ) {
+        // Only hold the lock to find the events to refresh, not while computing their
+        // new encryption info. All the events sent by a user with the same session
+        // share the same encryption info.
+        let keys: BTreeSet<(String, OwnedUserId)> = self
+            .state
+            .read()
+            .await
+            .items
+            .iter()
+            .filter_map(|item| {
+                let event_item = item.as_event()?;
+                let session_id = event_item.encryption_info()?.session_id.as_ref()?;
+                session_ids
+                    .contains(session_id)
+                    .then(|| (session_id.clone(), event_item.sender().to_owned()))
+            })
+            .collect();
+
+        if keys.is_empty() {
+            return;
+        }
+
+        debug!("Refreshing the encryption info of events");
+
+        let mut encryption_infos = BTreeMap::new();
+
+        for (session_id, sender) in keys {
+            match decryptor.get_encryption_info_impl(&session_id, &sender).await {
+                Ok(encryption_info) => {
+                    encryption_infos.insert((session_id, sender), encryption_info);
+                }
+                Err(e) => {
+                    warn!(?session_id, "Failed to get the encryption info of events: {e}");
+                }
+            }
+        }
+
+        self.state.write().await.items.for_each(|mut entry| {
+            let Some(event_item) = entry.as_event() else { return };
+            let Some(remote_event) = event_item.as_remote() else { return };
+            let Some(previous) = &remote_event.encryption_info else { return };
+            let Some(session_id) = previous.session_id.clone() else { return };
+
+            let Some(encryption_info) =
+                encryption_infos.get(&(session_id, event_item.sender().to_owned()))
+            else {
+                return;
+            };
+
+            if encryption_info.verification_state == previous.verification_state {
+                return;
+            }
+
+            let new_item = entry.with_kind(TimelineItemKind::Event(event_item.with_kind(
+                EventTimelineItemKind::Remote(
+                    remote_event.with_encryption_info(Some(encryption_info.clone())),
+                ),
+            )));
+            ObservableVectorEntry::set(&mut entry, new_item);
+        });
+    }
+
     pub(super) async fn set_sender_profiles_pending(&self) {
         self.set_non_ready_sender_profiles(TimelineDetails::Pending).await;
     }
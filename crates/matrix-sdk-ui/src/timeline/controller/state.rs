@@ -22,7 +22,10 @@ use std::{
 use eyeball_im::{ObservableVector, ObservableVectorTransaction, ObservableVectorTransactionEntry};
 use itertools::Itertools as _;
 use matrix_sdk::{
-    deserialized_responses::SyncTimelineEvent, ring_buffer::RingBuffer, send_queue::SendHandle,
+    crypto::{types::events::CryptoContextInfo, IdentityStatusChange},
+    deserialized_responses::SyncTimelineEvent,
+    ring_buffer::RingBuffer,
+    send_queue::SendHandle,
 };
 use matrix_sdk_base::deserialized_responses::TimelineEvent;
 #[cfg(test)]
@@ -54,6 +57,7 @@ use crate::{
             TimelineItemPosition,
         },
         event_item::{PollState, RemoteEventOrigin, ResponseData},
+        identity_changes::{IdentityChangeRecord, IdentityChanges},
         item::TimelineUniqueId,
         reactions::Reactions,
         read_receipts::ReadReceipts,
@@ -64,6 +68,47 @@ use crate::{
     unable_to_decrypt_hook::UtdHookManager,
 };
 
+/// Pair each of the given events with the [`CryptoContextInfo`] needed to
+/// explain why it couldn't be decrypted, if it couldn't.
+///
+/// The room data provider is asked only once per Megolm session. This needs to
+/// look into the crypto store, so it should be done before taking the timeline
+/// state lock, or at least before a [`TimelineStateTransaction`] is started,
+/// rather than while handling each event.
+pub(super) async fn with_crypto_context_infos<P: RoomDataProvider>(
+    events: Vec<impl Into<SyncTimelineEvent>>,
+    room_data_provider: &P,
+) -> Vec<(SyncTimelineEvent, Option<CryptoContextInfo>)> {
+    let mut crypto_context_infos: HashMap<Option<String>, CryptoContextInfo> = HashMap::new();
+    let mut result = Vec::with_capacity(events.len());
+
+    for event in events {
+        let event = event.into();
+
+        let crypto_context_info = match &event.kind {
+            matrix_sdk::deserialized_responses::TimelineEventKind::UnableToDecrypt {
+                utd_info,
+                ..
+            } => {
+                let session_id = &utd_info.session_id;
+
+                if let Some(info) = crypto_context_infos.get(session_id) {
+                    Some(info.clone())
+                } else {
+                    let info = room_data_provider.crypto_context_info(session_id.as_deref()).await;
+                    crypto_context_infos.insert(session_id.clone(), info.clone());
+                    Some(info)
+                }
+            }
+            _ => None,
+        };
+
+        result.push((event, crypto_context_info));
+    }
+
+    result
+}
+
 /// Which end of the timeline should an event be added to?
 ///
 /// This is a simplification of `TimelineItemPosition` which doesn't contain the
@@ -112,13 +157,16 @@ impl TimelineState {
 
     /// Add the given remote events at the given end of the timeline.
     ///
+    /// The events come along with the [`CryptoContextInfo`] of the ones that
+    /// couldn't be decrypted, see [`with_crypto_context_infos()`].
+    ///
     /// Note: when the `position` is [`TimelineEnd::Front`], prepended events
     /// should be ordered in *reverse* topological order, that is, `events[0]`
     /// is the most recent.
     #[tracing::instrument(skip(self, events, room_data_provider, settings))]
     pub(super) async fn add_remote_events_at<P: RoomDataProvider>(
         &mut self,
-        events: Vec<impl Into<SyncTimelineEvent>>,
+        events: Vec<(SyncTimelineEvent, Option<CryptoContextInfo>)>,
         position: TimelineEnd,
         origin: RemoteEventOrigin,
         room_data_provider: &P,
@@ -144,6 +192,42 @@ impl TimelineState {
         txn.commit();
     }
 
+    /// Replaces the known identity changes with the given ones, loaded from
+    /// the store, and inserts their items.
+    pub(super) fn set_identity_changes(&mut self, records: Vec<IdentityChangeRecord>) {
+        let mut txn = self.transaction();
+        txn.meta.identity_changes.set_records(records);
+        txn.meta.identity_changes.update_items(&mut txn.items);
+        txn.commit();
+    }
+
+    /// Records the given identity changes, observed after the latest remote
+    /// event of the timeline.
+    ///
+    /// Returns `true` if any change was recorded.
+    pub(super) fn handle_identity_status_changes(
+        &mut self,
+        changes: Vec<IdentityStatusChange>,
+    ) -> bool {
+        let latest_event_id = self.items.iter().rev().find_map(|item| {
+            let event = item.as_event()?;
+            if event.is_local_echo() {
+                None
+            } else {
+                event.event_id().map(ToOwned::to_owned)
+            }
+        });
+
+        let mut txn = self.transaction();
+        let recorded = txn.meta.identity_changes.record(changes, latest_event_id);
+        if recorded {
+            txn.meta.identity_changes.update_items(&mut txn.items);
+        }
+        txn.commit();
+
+        recorded
+    }
+
     #[instrument(skip_all)]
     pub(super) async fn handle_ephemeral_events<P: RoomDataProvider>(
         &mut self,
@@ -220,6 +304,24 @@ impl TimelineState {
     ) where
         Fut: Future<Output = Option<TimelineEvent>>,
     {
+        let mut retried_indices = Vec::new();
+        let mut retried_events = Vec::new();
+
+        for idx in retry_indices {
+            let Some(mut event) = retry_one(self.items[idx].clone()).await else {
+                continue;
+            };
+
+            event.push_actions = push_rules_context.as_ref().map(|(push_rules, push_context)| {
+                push_rules.get_actions(event.raw(), push_context).to_owned()
+            });
+
+            retried_indices.push(idx);
+            retried_events.push(event);
+        }
+
+        let retried_events = with_crypto_context_infos(retried_events, room_data_provider).await;
+
         let mut txn = self.transaction();
 
         let mut day_divider_adjuster = DayDividerAdjuster::default();
@@ -228,19 +330,13 @@ impl TimelineState {
         // before the event being edited, if both were UTD. Keep track of
         // index change as UTDs are removed instead of updated.
         let mut offset = 0;
-        for idx in retry_indices {
+        for (idx, (event, crypto_context_info)) in retried_indices.into_iter().zip(retried_events) {
             let idx = idx - offset;
-            let Some(mut event) = retry_one(txn.items[idx].clone()).await else {
-                continue;
-            };
-
-            event.push_actions = push_rules_context.as_ref().map(|(push_rules, push_context)| {
-                push_rules.get_actions(event.raw(), push_context).to_owned()
-            });
 
             let handle_one_res = txn
                 .handle_remote_event(
-                    event.into(),
+                    event,
+                    crypto_context_info,
                     TimelineItemPosition::Update(idx),
                     room_data_provider,
                     settings,
@@ -279,12 +375,15 @@ impl TimelineState {
 
     /// Replaces the existing events in the timeline with the given remote ones.
     ///
+    /// The events come along with the [`CryptoContextInfo`] of the ones that
+    /// couldn't be decrypted, see [`with_crypto_context_infos()`].
+    ///
     /// Note: when the `position` is [`TimelineEnd::Front`], prepended events
     /// should be ordered in *reverse* topological order, that is, `events[0]`
     /// is the most recent.
     pub(super) async fn replace_with_remote_events<P: RoomDataProvider>(
         &mut self,
-        events: Vec<SyncTimelineEvent>,
+        events: Vec<(SyncTimelineEvent, Option<CryptoContextInfo>)>,
         position: TimelineEnd,
         origin: RemoteEventOrigin,
         room_data_provider: &P,
@@ -343,10 +442,13 @@ impl TimelineStateTransaction<'_> {
     /// Note: when the `position` is [`TimelineEnd::Front`], prepended events
     /// should be ordered in *reverse* topological order, that is, `events[0]`
     /// is the most recent.
+    ///
+    /// The events come along with the [`CryptoContextInfo`] of the ones that
+    /// couldn't be decrypted, see [`with_crypto_context_infos()`].
     #[tracing::instrument(skip(self, events, room_data_provider, settings))]
     pub(super) async fn add_remote_events_at<P: RoomDataProvider>(
         &mut self,
-        events: Vec<impl Into<SyncTimelineEvent>>,
+        events: Vec<(SyncTimelineEvent, Option<CryptoContextInfo>)>,
         position: TimelineEnd,
         origin: RemoteEventOrigin,
         room_data_provider: &P,
@@ -369,10 +471,11 @@ impl TimelineStateTransaction<'_> {
         // and A is the oldest: we prepend C, then prepend B, then prepend A,
         // resulting in [A, B, C, (previous events)], which is what we want.
 
-        for event in events {
+        for (event, crypto_context_info) in events {
             let handle_one_res = self
                 .handle_remote_event(
-                    event.into(),
+                    event,
+                    crypto_context_info,
                     position,
                     room_data_provider,
                     settings,
@@ -413,10 +516,14 @@ impl TimelineStateTransaction<'_> {
 
     /// Handle a remote event.
     ///
+    /// `crypto_context_info` must be provided if the event couldn't be
+    /// decrypted.
+    ///
     /// Returns the number of timeline updates that were made.
     async fn handle_remote_event<P: RoomDataProvider>(
         &mut self,
         event: SyncTimelineEvent,
+        crypto_context_info: Option<CryptoContextInfo>,
         position: TimelineItemPosition,
         room_data_provider: &P,
         settings: &TimelineSettings,
@@ -433,6 +540,10 @@ impl TimelineStateTransaction<'_> {
             _ => (kind.into_raw(), None),
         };
 
+        // For events we couldn't decrypt, what we know about our own device and the key
+        // backup helps explaining the failure.
+        let utd_info = utd_info.zip(crypto_context_info);
+
         let (event_id, sender, timestamp, txn_id, event_kind, should_add) = match raw.deserialize()
         {
             Ok(event) => {
@@ -611,9 +722,9 @@ impl TimelineStateTransaction<'_> {
         // `VectorDiff::Clear` should be much more efficient to process for
         // subscribers.
         if has_local_echoes {
-            // Remove all remote events and the read marker
+            // Remove all remote events, the read marker and the identity changes
             self.items.for_each(|entry| {
-                if entry.is_remote_event() || entry.is_read_marker() {
+                if entry.is_remote_event() || entry.is_read_marker() || entry.is_identity_change() {
                     ObservableVectorTransactionEntry::remove(entry);
                 }
             });
@@ -906,6 +1017,10 @@ pub(in crate::timeline) struct TimelineMetadata {
     /// - The fully-read marker item would be the last item in the timeline.
     pub has_up_to_date_read_marker_item: bool,
 
+    /// The identity changes of the room members, and where to insert them in
+    /// the timeline.
+    pub identity_changes: IdentityChanges,
+
     /// Read receipts related state.
     ///
     /// TODO: move this over to the event cache (see also #3058).
@@ -935,6 +1050,7 @@ impl TimelineMetadata {
             // It doesn't make sense to set this to false until we fill the `fully_read_event`
             // field, otherwise we'll keep on exiting early in `Self::update_read_marker`.
             has_up_to_date_read_marker_item: true,
+            identity_changes: Default::default(),
             read_receipts: Default::default(),
             room_version,
             unable_to_decrypt_hook,
@@ -954,6 +1070,8 @@ impl TimelineMetadata {
         // We forgot about the fully read marker right above, so wait for a new one
         // before attempting to update it for each new timeline item.
         self.has_up_to_date_read_marker_item = true;
+        // The identity changes are kept, but their items need to be inserted again.
+        self.identity_changes.clear();
         self.read_receipts.clear();
     }
 
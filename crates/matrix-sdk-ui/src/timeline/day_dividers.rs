@@ -130,7 +130,9 @@ impl DayDividerAdjuster {
                     latest_event_ts = Some(ts);
                 }
 
-                TimelineItemKind::Virtual(VirtualTimelineItem::ReadMarker) => {
+                TimelineItemKind::Virtual(
+                    VirtualTimelineItem::ReadMarker | VirtualTimelineItem::IdentityChange { .. },
+                ) => {
                     // Nothing to do.
                 }
             }
@@ -219,8 +221,10 @@ impl DayDividerAdjuster {
                 return true;
             }
 
-            TimelineItemKind::Virtual(VirtualTimelineItem::ReadMarker) => {
-                // Nothing to do for read markers.
+            TimelineItemKind::Virtual(
+                VirtualTimelineItem::ReadMarker | VirtualTimelineItem::IdentityChange { .. },
+            ) => {
+                // Nothing to do for read markers and identity changes.
             }
         }
 
@@ -278,7 +282,9 @@ impl DayDividerAdjuster {
                 }
             }
 
-            TimelineItemKind::Virtual(VirtualTimelineItem::ReadMarker) => {
+            TimelineItemKind::Virtual(
+                VirtualTimelineItem::ReadMarker | VirtualTimelineItem::IdentityChange { .. },
+            ) => {
                 // Nothing to do.
             }
         }
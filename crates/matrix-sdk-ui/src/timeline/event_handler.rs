@@ -18,7 +18,7 @@ use as_variant::as_variant;
 use eyeball_im::{ObservableVectorTransaction, ObservableVectorTransactionEntry};
 use indexmap::IndexMap;
 use matrix_sdk::{
-    crypto::types::events::UtdCause,
+    crypto::types::events::{CryptoContextInfo, UtdCause},
     deserialized_responses::{EncryptionInfo, UnableToDecryptInfo},
     ring_buffer::RingBuffer,
     send_queue::SendHandle,
@@ -144,6 +144,7 @@ pub(super) enum TimelineEventKind {
     UnableToDecrypt {
         content: RoomEncryptedEventContent,
         unable_to_decrypt_info: UnableToDecryptInfo,
+        crypto_context_info: CryptoContextInfo,
     },
 
     /// Some remote event that was redacted a priori, i.e. we never had the
@@ -184,7 +185,7 @@ impl TimelineEventKind {
     pub fn from_event(
         event: AnySyncTimelineEvent,
         room_version: &RoomVersionId,
-        unable_to_decrypt_info: Option<UnableToDecryptInfo>,
+        unable_to_decrypt_info: Option<(UnableToDecryptInfo, CryptoContextInfo)>,
     ) -> Self {
         match event {
             AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomRedaction(ev)) => {
@@ -197,8 +198,14 @@ impl TimelineEventKind {
             AnySyncTimelineEvent::MessageLike(ev) => match ev.original_content() {
                 Some(AnyMessageLikeEventContent::RoomEncrypted(content)) => {
                     // An event which is still encrypted.
-                    if let Some(unable_to_decrypt_info) = unable_to_decrypt_info {
-                        Self::UnableToDecrypt { content, unable_to_decrypt_info }
+                    if let Some((unable_to_decrypt_info, crypto_context_info)) =
+                        unable_to_decrypt_info
+                    {
+                        Self::UnableToDecrypt {
+                            content,
+                            unable_to_decrypt_info,
+                            crypto_context_info,
+                        }
                     } else {
                         // If we get here, it means that some part of the code has created a
                         // `SyncTimelineEvent` containing an `m.room.encrypted` event
@@ -416,10 +423,15 @@ impl<'a, 'o> TimelineEventHandler<'a, 'o> {
                 }
             },
 
-            TimelineEventKind::UnableToDecrypt { content, unable_to_decrypt_info } => {
+            TimelineEventKind::UnableToDecrypt {
+                content,
+                unable_to_decrypt_info,
+                crypto_context_info,
+            } => {
                 // TODO: Handle replacements if the replaced event is also UTD
                 let raw_event = self.ctx.flow.raw_event();
-                let cause = UtdCause::determine(raw_event, &unable_to_decrypt_info);
+                let cause =
+                    UtdCause::determine(raw_event, crypto_context_info, &unable_to_decrypt_info);
                 self.add_item(TimelineItemContent::unable_to_decrypt(content, cause), None);
 
                 // Let the hook know that we ran into an unable-to-decrypt that is added to the
@@ -1169,7 +1181,17 @@ impl<'a, 'o> TimelineEventHandler<'a, 'o> {
 
                 // Insert the next item after the latest event item that's not a
                 // pending local echo, or at the start if there is no such item.
-                let insert_idx = latest_event_idx.map_or(0, |idx| idx + 1);
+                // The identity changes observed after the latest event stay
+                // before the new one.
+                let insert_idx = latest_event_idx.map_or(0, |idx| {
+                    idx + 1
+                        + self
+                            .items
+                            .iter()
+                            .skip(idx + 1)
+                            .take_while(|item| item.is_identity_change())
+                            .count()
+                });
 
                 trace!("Adding new remote timeline item after all non-pending events");
                 let new_item = match removed_event_item_id {
@@ -1201,6 +1223,11 @@ impl<'a, 'o> TimelineEventHandler<'a, 'o> {
         if !self.meta.has_up_to_date_read_marker_item {
             self.meta.update_read_marker(self.items);
         }
+
+        // Same for the identity changes whose event wasn't loaded yet.
+        if self.meta.identity_changes.has_unplaced_items() {
+            self.meta.identity_changes.update_items(self.items);
+        }
     }
 
     fn pending_reactions(
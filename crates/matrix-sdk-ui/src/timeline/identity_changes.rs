@@ -0,0 +1,273 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashSet, sync::Arc};
+
+use eyeball_im::ObservableVectorTransaction;
+use matrix_sdk::crypto::{IdentityState, IdentityStatusChange};
+use ruma::{MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedUserId};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace};
+
+use super::{item::TimelineUniqueId, util::rfind_event_by_id, TimelineItem};
+
+/// The maximum number of identity changes we remember for a room.
+///
+/// The oldest ones are forgotten first, but the latest change of each user is
+/// kept as long as possible, since it's needed to tell whether a new change
+/// must be recorded.
+pub(super) const MAX_IDENTITY_CHANGE_RECORDS: usize = 100;
+
+/// An identity change of a room member, as observed by the timeline.
+///
+/// Those are persisted by the [`RoomDataProvider`], so the matching
+/// [`VirtualTimelineItem::IdentityChange`] items can be inserted at the same
+/// place when the timeline is created again.
+///
+/// [`RoomDataProvider`]: super::traits::RoomDataProvider
+/// [`VirtualTimelineItem::IdentityChange`]: super::VirtualTimelineItem::IdentityChange
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct IdentityChangeRecord {
+    /// The user whose identity changed.
+    pub user_id: OwnedUserId,
+
+    /// The new state of the identity.
+    pub changed_to: IdentityState,
+
+    /// The latest remote event of the timeline when the change was observed,
+    /// if any.
+    pub after_event_id: Option<OwnedEventId>,
+
+    /// When the change was observed.
+    pub observed_at: MilliSecondsSinceUnixEpoch,
+}
+
+impl IdentityChangeRecord {
+    /// Does this change need a timeline item to warn the user about it?
+    fn needs_item(&self) -> bool {
+        matches!(
+            self.changed_to,
+            IdentityState::PinViolation | IdentityState::VerificationViolation
+        )
+    }
+
+    /// The unique id of the timeline item of this change.
+    ///
+    /// It's derived from the record itself, so the item keeps the same id
+    /// across restarts.
+    pub fn item_id(&self) -> TimelineUniqueId {
+        TimelineUniqueId(format!("__identity_change_{}_{}", self.user_id, self.observed_at.get()))
+    }
+}
+
+/// The identity changes of the room members observed by a timeline.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IdentityChanges {
+    /// All the changes we know about, from the oldest to the most recent.
+    records: Vec<IdentityChangeRecord>,
+
+    /// Whether some of the changes which need an item don't have one in the
+    /// timeline yet, because the event they should be inserted after hasn't
+    /// been loaded yet.
+    has_unplaced_items: bool,
+}
+
+impl IdentityChanges {
+    /// All the changes we know about, from the oldest to the most recent.
+    pub fn records(&self) -> &[IdentityChangeRecord] {
+        &self.records
+    }
+
+    /// Replace the known changes with the given ones, typically loaded from
+    /// the store.
+    pub fn set_records(&mut self, records: Vec<IdentityChangeRecord>) {
+        self.records = records;
+        self.has_unplaced_items = self.records.iter().any(IdentityChangeRecord::needs_item);
+    }
+
+    /// Whether [`Self::update_items()`] should be called after new events were
+    /// added to the timeline.
+    pub fn has_unplaced_items(&self) -> bool {
+        self.has_unplaced_items
+    }
+
+    /// The timeline items were cleared, all the items need to be inserted
+    /// again once their event shows up.
+    pub fn clear(&mut self) {
+        self.has_unplaced_items = self.records.iter().any(IdentityChangeRecord::needs_item);
+    }
+
+    /// Record the given identity changes, observed right now, after the given
+    /// event.
+    ///
+    /// Changes to a state we already know about for the user are ignored,
+    /// which happens for the initial state of the room members every time the
+    /// timeline is created.
+    ///
+    /// Returns `true` if any change was recorded, in which case the records
+    /// need to be persisted again.
+    pub fn record(
+        &mut self,
+        changes: Vec<IdentityStatusChange>,
+        after_event_id: Option<OwnedEventId>,
+    ) -> bool {
+        let observed_at = MilliSecondsSinceUnixEpoch::now();
+        let mut recorded = false;
+
+        for change in changes {
+            // Before we see any change, identities are pinned.
+            let previous_state = self
+                .records
+                .iter()
+                .rev()
+                .find(|record| record.user_id == change.user_id)
+                .map_or(IdentityState::Pinned, |record| record.changed_to.clone());
+
+            if previous_state == change.changed_to {
+                trace!(user_id = ?change.user_id, "Ignoring already known identity state");
+                continue;
+            }
+
+            debug!(user_id = ?change.user_id, changed_to = ?change.changed_to, "Recording identity change");
+
+            self.records.push(IdentityChangeRecord {
+                user_id: change.user_id,
+                changed_to: change.changed_to,
+                after_event_id: after_event_id.clone(),
+                observed_at,
+            });
+            recorded = true;
+        }
+
+        if recorded {
+            prune_records(&mut self.records);
+            self.has_unplaced_items = true;
+        }
+
+        recorded
+    }
+
+    /// Insert the items of the changes which don't have one yet, if the event
+    /// they should follow is in the timeline.
+    pub fn update_items(&mut self, items: &mut ObservableVectorTransaction<'_, Arc<TimelineItem>>) {
+        let mut has_unplaced_items = false;
+
+        for record in self.records.iter().filter(|record| record.needs_item()) {
+            let item_id = record.item_id();
+            if items.iter().any(|item| *item.unique_id() == item_id) {
+                continue;
+            }
+
+            let Some((event_idx, _)) = record
+                .after_event_id
+                .as_deref()
+                .and_then(|event_id| rfind_event_by_id(items, event_id))
+            else {
+                // Without an event the change can't be placed, it will only be placed if it
+                // was observed after an event which gets loaded later.
+                has_unplaced_items |= record.after_event_id.is_some();
+                continue;
+            };
+
+            // Keep the changes observed after the same event in chronological order.
+            let idx = event_idx
+                + 1
+                + items
+                    .iter()
+                    .skip(event_idx + 1)
+                    .take_while(|item| item.is_identity_change())
+                    .count();
+
+            trace!(idx, user_id = ?record.user_id, "Inserting identity change item");
+            items.insert(idx, TimelineItem::identity_change(record));
+        }
+
+        self.has_unplaced_items = has_unplaced_items;
+    }
+}
+
+/// Merge the records of a timeline with the ones that were persisted, e.g. by
+/// another timeline of the same room.
+///
+/// The persisted records which the timeline knows about already aren't
+/// duplicated. The result is in chronological order, and pruned like the
+/// records of a timeline.
+pub(crate) fn merge_records(
+    persisted: Vec<IdentityChangeRecord>,
+    records: &[IdentityChangeRecord],
+) -> Vec<IdentityChangeRecord> {
+    let mut known = records.iter().collect::<Vec<_>>();
+    let mut merged = Vec::with_capacity(persisted.len() + records.len());
+
+    for record in persisted {
+        match known.iter().position(|known| **known == record) {
+            Some(idx) => {
+                known.swap_remove(idx);
+            }
+            None => merged.push(record),
+        }
+    }
+
+    merged.extend_from_slice(records);
+
+    // The sort is stable, so the records observed at the same time keep their
+    // order.
+    merged.sort_by_key(|record| record.observed_at);
+    prune_records(&mut merged);
+
+    merged
+}
+
+/// Forget the oldest records, so that at most [`MAX_IDENTITY_CHANGE_RECORDS`]
+/// remain.
+///
+/// The records that aren't the latest of their user go first, then the latest
+/// ones that went back to the pinned state, which is what we assume when we
+/// don't know about a user. The latest warnings are only forgotten as a last
+/// resort.
+fn prune_records(records: &mut Vec<IdentityChangeRecord>) {
+    let mut excess = records.len().saturating_sub(MAX_IDENTITY_CHANGE_RECORDS);
+    if excess == 0 {
+        return;
+    }
+
+    let mut users = HashSet::new();
+    let mut is_latest_of_user =
+        records.iter().rev().map(|record| users.insert(&record.user_id)).collect::<Vec<_>>();
+    is_latest_of_user.reverse();
+
+    let mut forget = vec![false; records.len()];
+
+    for pass in 0..3 {
+        for (idx, record) in records.iter().enumerate() {
+            if excess == 0 {
+                break;
+            }
+
+            let can_forget = match pass {
+                0 => !is_latest_of_user[idx],
+                1 => is_latest_of_user[idx] && record.changed_to == IdentityState::Pinned,
+                _ => true,
+            };
+
+            if can_forget && !forget[idx] {
+                forget[idx] = true;
+                excess -= 1;
+            }
+        }
+    }
+
+    let mut forget = forget.into_iter();
+    records.retain(|_| !forget.next().unwrap_or_default());
+}
@@ -16,7 +16,7 @@ use std::{ops::Deref, sync::Arc};
 
 use as_variant::as_variant;
 
-use super::{EventTimelineItem, VirtualTimelineItem};
+use super::{identity_changes::IdentityChangeRecord, EventTimelineItem, VirtualTimelineItem};
 
 /// Opaque unique identifier for a timeline item.
 ///
@@ -93,6 +93,17 @@ impl TimelineItem {
         })
     }
 
+    pub(crate) fn identity_change(record: &IdentityChangeRecord) -> Arc<TimelineItem> {
+        Arc::new(Self {
+            kind: TimelineItemKind::Virtual(VirtualTimelineItem::IdentityChange {
+                user_id: record.user_id.clone(),
+                changed_to: record.changed_to.clone(),
+                timestamp: record.observed_at,
+            }),
+            internal_id: record.item_id(),
+        })
+    }
+
     pub(crate) fn is_local_echo(&self) -> bool {
         matches!(&self.kind, TimelineItemKind::Event(ev) if ev.is_local_echo())
     }
@@ -114,6 +125,10 @@ impl TimelineItem {
     pub(crate) fn is_read_marker(&self) -> bool {
         matches!(self.kind, TimelineItemKind::Virtual(VirtualTimelineItem::ReadMarker))
     }
+
+    pub(crate) fn is_identity_change(&self) -> bool {
+        matches!(self.kind, TimelineItemKind::Virtual(VirtualTimelineItem::IdentityChange { .. }))
+    }
 }
 
 impl Deref for TimelineItem {
@@ -65,6 +65,7 @@ mod event_handler;
 mod event_item;
 pub mod event_type_filter;
 pub mod futures;
+mod identity_changes;
 mod item;
 mod pagination;
 mod pinned_events_loader;
@@ -789,9 +790,11 @@ struct TimelineDropHandle {
     pinned_events_join_handle: Option<JoinHandle<()>>,
     room_key_from_backups_join_handle: JoinHandle<()>,
     room_key_backup_enabled_join_handle: JoinHandle<()>,
+    sender_data_updates_join_handle: JoinHandle<()>,
     local_echo_listener_handle: JoinHandle<()>,
     _event_cache_drop_handle: Arc<EventCacheDropHandles>,
     encryption_changes_handle: JoinHandle<()>,
+    identity_changes_join_handle: Option<JoinHandle<()>>,
 }
 
 impl Drop for TimelineDropHandle {
@@ -808,7 +811,12 @@ impl Drop for TimelineDropHandle {
         self.room_update_join_handle.abort();
         self.room_key_from_backups_join_handle.abort();
         self.room_key_backup_enabled_join_handle.abort();
+        self.sender_data_updates_join_handle.abort();
         self.encryption_changes_handle.abort();
+
+        if let Some(handle) = self.identity_changes_join_handle.take() {
+            handle.abort()
+        };
     }
 }
 
@@ -27,7 +27,10 @@ use matrix_sdk::{
     crypto::{decrypt_room_key_export, types::events::UtdCause, OlmMachine},
     test_utils::test_client_builder,
 };
-use matrix_sdk_base::deserialized_responses::{SyncTimelineEvent, UnableToDecryptReason};
+use matrix_sdk_base::deserialized_responses::{
+    AlgorithmInfo, DecryptedRoomEvent, EncryptionInfo, SyncTimelineEvent, UnableToDecryptReason,
+    VerificationState,
+};
 use matrix_sdk_test::{async_test, BOB};
 use ruma::{
     assign,
@@ -40,31 +43,36 @@ use ruma::{
     user_id,
 };
 use serde_json::{json, value::to_raw_value};
-use stream_assert::assert_next_matches;
+use stream_assert::{assert_next_matches, assert_pending};
 
-use super::TestTimeline;
+use super::{TestRoomDataProvider, TestTimeline};
 use crate::{
-    timeline::{EncryptedMessage, TimelineItemContent},
+    timeline::{
+        controller::TimelineEnd, event_item::RemoteEventOrigin, EncryptedMessage,
+        TimelineItemContent,
+    },
     unable_to_decrypt_hook::{UnableToDecryptHook, UnableToDecryptInfo, UtdHookManager},
 };
 
+// The room key used to encrypt the message of `test_retry_message_decryption()`,
+// which is also used by `test_retry_encryption_info()`.
+const MESSAGE_SESSION_ID: &str = "gM8i47Xhu0q52xLfgUXzanCMpLinoyVyH7R58cBuVBU";
+const MESSAGE_SESSION_KEY: &[u8] = b"\
+    -----BEGIN MEGOLM SESSION DATA-----\n\
+    ASKcWoiAVUM97482UAi83Avce62hSLce7i5JhsqoF6xeAAAACqt2Cg3nyJPRWTTMXxXH7TXnkfdlmBXbQtq5\
+    bpHo3LRijcq2Gc6TXilESCmJN14pIsfKRJrWjZ0squ/XsoTFytuVLWwkNaW3QF6obeg2IoVtJXLMPdw3b2vO\
+    vgwGY3OMP0XafH13j1vcb6YLzvgLkZQLnYvd47hv3yK/9GmKS9tokuaQ7dCVYckYcIOS09EDTs70YdxUd5WG\
+    rQynATCLFP1p/NAGv70r9MK7Cy/mNpjD0r4qC7UEDIoi1kOWzHgnLo19wtvwsb8Fg8ATxcs3Wmtj8hIUYpDx\
+    ia4sM10zbytUuaPUAfCDf42IyxdmOnGe1CueXhgI71y+RW0s0argNqUt7jB70JT0o9CyX6UBGRaqLk2MPY9T\
+    hUu5J8X3UgIa6rcbWigzohzWm9rdbEHFrSWqjpfQYMaAKQQgETrjSy4XTrp2RhC2oNqG/hylI4ab+F4X6fpH\
+    DYP1NqNMP5g36xNu7LhDnrUB5qsPjYOmWORxGLfudpF3oLYCSlr3DgHqEIB6HjQblLZ3KQuPBse3zxyROTnS\
+    AhdPH4a/z1wioFtKNVph3hecsiKEdqnz4Y2coSIdhz58mJ9JWNQoFAENE5CSsoEZAGvafYZVpW4C75YY2zq1\
+    wIeiFi1dT43/jLAUGkslsi1VvnyfUu8qO404RxYO3XHoGLMFoFLOO+lZ+VGci2Vz10AhxJhEBHxRKxw4k2uB\
+    HztoSJUr/2Y\n\
+    -----END MEGOLM SESSION DATA-----";
+
 #[async_test]
 async fn test_retry_message_decryption() {
-    const SESSION_ID: &str = "gM8i47Xhu0q52xLfgUXzanCMpLinoyVyH7R58cBuVBU";
-    const SESSION_KEY: &[u8] = b"\
-        -----BEGIN MEGOLM SESSION DATA-----\n\
-        ASKcWoiAVUM97482UAi83Avce62hSLce7i5JhsqoF6xeAAAACqt2Cg3nyJPRWTTMXxXH7TXnkfdlmBXbQtq5\
-        bpHo3LRijcq2Gc6TXilESCmJN14pIsfKRJrWjZ0squ/XsoTFytuVLWwkNaW3QF6obeg2IoVtJXLMPdw3b2vO\
-        vgwGY3OMP0XafH13j1vcb6YLzvgLkZQLnYvd47hv3yK/9GmKS9tokuaQ7dCVYckYcIOS09EDTs70YdxUd5WG\
-        rQynATCLFP1p/NAGv70r9MK7Cy/mNpjD0r4qC7UEDIoi1kOWzHgnLo19wtvwsb8Fg8ATxcs3Wmtj8hIUYpDx\
-        ia4sM10zbytUuaPUAfCDf42IyxdmOnGe1CueXhgI71y+RW0s0argNqUt7jB70JT0o9CyX6UBGRaqLk2MPY9T\
-        hUu5J8X3UgIa6rcbWigzohzWm9rdbEHFrSWqjpfQYMaAKQQgETrjSy4XTrp2RhC2oNqG/hylI4ab+F4X6fpH\
-        DYP1NqNMP5g36xNu7LhDnrUB5qsPjYOmWORxGLfudpF3oLYCSlr3DgHqEIB6HjQblLZ3KQuPBse3zxyROTnS\
-        AhdPH4a/z1wioFtKNVph3hecsiKEdqnz4Y2coSIdhz58mJ9JWNQoFAENE5CSsoEZAGvafYZVpW4C75YY2zq1\
-        wIeiFi1dT43/jLAUGkslsi1VvnyfUu8qO404RxYO3XHoGLMFoFLOO+lZ+VGci2Vz10AhxJhEBHxRKxw4k2uB\
-        HztoSJUr/2Y\n\
-        -----END MEGOLM SESSION DATA-----";
-
     #[derive(Debug, Default)]
     struct DummyUtdHook {
         utds: Mutex<Vec<UnableToDecryptInfo>>,
@@ -99,7 +107,7 @@ async fn test_retry_message_decryption() {
                             .to_owned(),
                         sender_key: "DeHIg4gwhClxzFYcmNntPNF9YtsdZbmMy8+3kzCMXHA".to_owned(),
                         device_id: "NLAZCWIOCO".into(),
-                        session_id: SESSION_ID.into(),
+                        session_id: MESSAGE_SESSION_ID.into(),
                     }
                     .into(),
                 ),
@@ -120,7 +128,7 @@ async fn test_retry_message_decryption() {
             ..
         }) = event.content()
     );
-    assert_eq!(session_id, SESSION_ID);
+    assert_eq!(session_id, MESSAGE_SESSION_ID);
 
     assert_next_matches!(stream, VectorDiff::PushFront { value } => {
         assert!(value.is_day_divider());
@@ -134,7 +142,7 @@ async fn test_retry_message_decryption() {
     }
 
     let own_user_id = user_id!("@example:morheus.localhost");
-    let exported_keys = decrypt_room_key_export(Cursor::new(SESSION_KEY), "1234").unwrap();
+    let exported_keys = decrypt_room_key_export(Cursor::new(MESSAGE_SESSION_KEY), "1234").unwrap();
 
     let olm_machine = OlmMachine::new(own_user_id, "SomeDeviceId".into()).await;
     olm_machine.store().import_exported_room_keys(exported_keys, |_, _| {}).await.unwrap();
@@ -144,7 +152,7 @@ async fn test_retry_message_decryption() {
         .retry_event_decryption_test(
             room_id!("!DovneieKSTkdHKpIXy:morpheus.localhost"),
             olm_machine,
-            Some(iter::once(SESSION_ID.to_owned()).collect()),
+            Some(iter::once(MESSAGE_SESSION_ID.to_owned()).collect()),
         )
         .await;
 
@@ -168,6 +176,78 @@ async fn test_retry_message_decryption() {
     }
 }
 
+#[async_test]
+async fn test_retry_encryption_info() {
+    let timeline = TestTimeline::new();
+    let mut stream = timeline.subscribe().await;
+
+    // The message was decrypted while its sender looked verified.
+    let encryption_info = EncryptionInfo {
+        sender: (*BOB).into(),
+        sender_device: None,
+        algorithm_info: AlgorithmInfo::MegolmV1AesSha2 {
+            curve25519_key: "DeHIg4gwhClxzFYcmNntPNF9YtsdZbmMy8+3kzCMXHA".to_owned(),
+            sender_claimed_keys: Default::default(),
+        },
+        verification_state: VerificationState::Verified,
+        session_id: Some(MESSAGE_SESSION_ID.to_owned()),
+    };
+
+    let f = &timeline.factory;
+    let event: SyncTimelineEvent = DecryptedRoomEvent {
+        event: f.text_msg("It's a secret to everybody").sender(&BOB).into_raw_timeline().cast(),
+        encryption_info,
+        unsigned_encryption_info: None,
+    }
+    .into();
+    timeline.handle_live_event(event).await;
+
+    let item = assert_next_matches!(stream, VectorDiff::PushBack { value } => value);
+    let event = item.as_event().unwrap();
+    assert_eq!(event.encryption_info().unwrap().verification_state, VerificationState::Verified);
+    assert_next_matches!(stream, VectorDiff::PushFront { value } => {
+        assert!(value.is_day_divider());
+    });
+
+    let own_user_id = user_id!("@example:morheus.localhost");
+    let exported_keys = decrypt_room_key_export(Cursor::new(MESSAGE_SESSION_KEY), "1234").unwrap();
+
+    let olm_machine = OlmMachine::new(own_user_id, "SomeDeviceId".into()).await;
+    olm_machine.store().import_exported_room_keys(exported_keys, |_, _| {}).await.unwrap();
+
+    // Refreshing the encryption info of events encrypted with other sessions
+    // doesn't change anything.
+    timeline
+        .controller
+        .retry_event_encryption_info_test(
+            room_id!("!DovneieKSTkdHKpIXy:morpheus.localhost"),
+            olm_machine.clone(),
+            iter::once("other_session".to_owned()).collect(),
+        )
+        .await;
+    assert_pending!(stream);
+
+    // But the message's encryption info now reflects that its sender isn't verified.
+    timeline
+        .controller
+        .retry_event_encryption_info_test(
+            room_id!("!DovneieKSTkdHKpIXy:morpheus.localhost"),
+            olm_machine,
+            iter::once(MESSAGE_SESSION_ID.to_owned()).collect(),
+        )
+        .await;
+
+    let item = assert_next_matches!(stream, VectorDiff::Set { index: 1, value } => value);
+    let event = item.as_event().unwrap();
+    assert_let!(TimelineItemContent::Message(message) = event.content());
+    assert_eq!(message.body(), "It's a secret to everybody");
+    assert_matches!(
+        event.encryption_info().unwrap().verification_state,
+        VerificationState::Unverified(_)
+    );
+    assert_pending!(stream);
+}
+
 #[async_test]
 async fn test_retry_edit_decryption() {
     const SESSION1_KEY: &[u8] = b"\
@@ -557,10 +637,35 @@ async fn test_utd_cause_for_missing_membership_is_unknown() {
     assert_eq!(*cause, UtdCause::Unknown);
 }
 
+#[async_test]
+async fn test_crypto_context_info_is_gathered_once_per_session() {
+    let room_data_provider = TestRoomDataProvider::default();
+    let timeline = TestTimeline::with_room_data_provider(room_data_provider.clone());
+
+    // A batch of events we couldn't decrypt, most of them using the same room key.
+    let events = vec![
+        utd_event("$a", "SESSION_ID", json!({})),
+        utd_event("$b", "SESSION_ID", json!({})),
+        utd_event("$c", "OTHER_SESSION_ID", json!({})),
+        utd_event("$d", "SESSION_ID", json!({})),
+    ];
+    timeline.controller.add_events_at(events, TimelineEnd::Back, RemoteEventOrigin::Sync).await;
+
+    let items = timeline.controller.items().await;
+    assert_eq!(items.iter().filter(|item| item.as_event().is_some()).count(), 4);
+
+    let requests = room_data_provider.crypto_context_info_requests.read().await.clone();
+    assert_eq!(requests, vec![Some("SESSION_ID".to_owned()), Some("OTHER_SESSION_ID".to_owned())]);
+}
+
 fn utd_event_with_unsigned(unsigned: serde_json::Value) -> SyncTimelineEvent {
+    utd_event("$myevent", "SESSION_ID", unsigned)
+}
+
+fn utd_event(event_id: &str, session_id: &str, unsigned: serde_json::Value) -> SyncTimelineEvent {
     let raw = Raw::from_json(
         to_raw_value(&json!({
-            "event_id": "$myevent",
+            "event_id": event_id,
             "sender": "@u:s",
             "origin_server_ts": 3,
             "type": "m.room.encrypted",
@@ -569,7 +674,7 @@ fn utd_event_with_unsigned(unsigned: serde_json::Value) -> SyncTimelineEvent {
                 "ciphertext": "NOT_REAL_CIPHERTEXT",
                 "sender_key": "SENDER_KEY",
                 "device_id": "DEVICE_ID",
-                "session_id":  "SESSION_ID",
+                "session_id": session_id,
             },
             "unsigned": unsigned
 
@@ -580,7 +685,7 @@ fn utd_event_with_unsigned(unsigned: serde_json::Value) -> SyncTimelineEvent {
     SyncTimelineEvent::new_utd_event(
         raw,
         matrix_sdk::deserialized_responses::UnableToDecryptInfo {
-            session_id: Some("SESSION_ID".into()),
+            session_id: Some(session_id.to_owned()),
             reason: UnableToDecryptReason::MissingMegolmSession,
         },
     )
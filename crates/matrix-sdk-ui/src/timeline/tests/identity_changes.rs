@@ -0,0 +1,216 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use assert_matches2::assert_let;
+use matrix_sdk::crypto::{IdentityState, IdentityStatusChange};
+use matrix_sdk_test::{async_test, ALICE, BOB};
+use ruma::{event_id, UserId};
+
+use super::{TestRoomDataProvider, TestTimeline};
+use crate::timeline::{
+    controller::{TimelineController, TimelineEnd, TimelineSettings},
+    event_item::RemoteEventOrigin,
+    identity_changes::MAX_IDENTITY_CHANGE_RECORDS,
+    TimelineFocus, VirtualTimelineItem,
+};
+
+fn identity_change(user_id: &UserId, changed_to: IdentityState) -> IdentityStatusChange {
+    IdentityStatusChange { user_id: user_id.to_owned(), changed_to }
+}
+
+#[async_test]
+async fn test_identity_change_is_inserted_after_the_latest_event() {
+    let timeline = TestTimeline::new();
+    let f = &timeline.factory;
+
+    timeline.handle_live_event(f.text_msg("A").sender(*ALICE).event_id(event_id!("$a"))).await;
+
+    timeline
+        .controller
+        .handle_identity_status_changes(vec![identity_change(*BOB, IdentityState::PinViolation)])
+        .await;
+
+    // A new event goes after the identity change.
+    timeline.handle_live_event(f.text_msg("B").sender(*BOB).event_id(event_id!("$b"))).await;
+
+    let items = timeline.controller.items().await;
+    assert_eq!(items.len(), 4);
+    assert!(items[0].is_day_divider());
+    assert_eq!(items[1].as_event().unwrap().event_id(), Some(event_id!("$a")));
+    assert_let!(
+        Some(VirtualTimelineItem::IdentityChange { user_id, changed_to, .. }) =
+            items[2].as_virtual()
+    );
+    assert_eq!(*user_id, (*BOB).to_owned());
+    assert_eq!(*changed_to, IdentityState::PinViolation);
+    assert_eq!(items[3].as_event().unwrap().event_id(), Some(event_id!("$b")));
+
+    // The change was persisted.
+    let records = timeline.data().identity_changes.read().await.clone();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].user_id, (*BOB).to_owned());
+    assert_eq!(records[0].after_event_id.as_deref(), Some(event_id!("$a")));
+}
+
+#[async_test]
+async fn test_only_warnings_get_an_item() {
+    let timeline = TestTimeline::new();
+    let f = &timeline.factory;
+
+    timeline.handle_live_event(f.text_msg("A").sender(*ALICE).event_id(event_id!("$a"))).await;
+
+    timeline
+        .controller
+        .handle_identity_status_changes(vec![identity_change(*BOB, IdentityState::PinViolation)])
+        .await;
+    // The same state again, e.g. the initial state of the room after a restart, is
+    // ignored.
+    timeline
+        .controller
+        .handle_identity_status_changes(vec![identity_change(*BOB, IdentityState::PinViolation)])
+        .await;
+    // Acknowledging the change is recorded, but doesn't need a warning.
+    timeline
+        .controller
+        .handle_identity_status_changes(vec![identity_change(*BOB, IdentityState::Pinned)])
+        .await;
+
+    let items = timeline.controller.items().await;
+    assert_eq!(items.iter().filter(|item| item.is_identity_change()).count(), 1);
+
+    let records = timeline.data().identity_changes.read().await.clone();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[1].changed_to, IdentityState::Pinned);
+}
+
+#[async_test]
+async fn test_identity_changes_are_restored() {
+    let room_data_provider = TestRoomDataProvider::default();
+
+    let timeline = TestTimeline::with_room_data_provider(room_data_provider.clone());
+    let f = &timeline.factory;
+    let event_a = f.text_msg("A").sender(*ALICE).event_id(event_id!("$a")).into_sync();
+    let event_b = f.text_msg("B").sender(*BOB).event_id(event_id!("$b")).into_sync();
+
+    timeline.handle_live_event(event_a.clone()).await;
+    timeline
+        .controller
+        .handle_identity_status_changes(vec![identity_change(
+            *BOB,
+            IdentityState::VerificationViolation,
+        )])
+        .await;
+    drop(timeline);
+
+    // A new timeline for the same room places the change after the same event, once
+    // it's loaded.
+    let timeline = TestTimeline::with_room_data_provider(room_data_provider)
+        .with_settings(TimelineSettings { track_identity_changes: true, ..Default::default() });
+    timeline
+        .controller
+        .replace_with_initial_remote_events(vec![event_b.clone()], RemoteEventOrigin::Cache)
+        .await;
+    assert!(!timeline.controller.items().await.iter().any(|item| item.is_identity_change()));
+
+    timeline
+        .controller
+        .add_events_at(vec![event_a], TimelineEnd::Front, RemoteEventOrigin::Pagination)
+        .await;
+
+    let items = timeline.controller.items().await;
+    assert_eq!(items.len(), 4);
+    assert!(items[0].is_day_divider());
+    assert_eq!(items[1].as_event().unwrap().event_id(), Some(event_id!("$a")));
+    assert_let!(
+        Some(VirtualTimelineItem::IdentityChange { user_id, changed_to, .. }) =
+            items[2].as_virtual()
+    );
+    assert_eq!(*user_id, (*BOB).to_owned());
+    assert_eq!(*changed_to, IdentityState::VerificationViolation);
+    assert_eq!(items[3].as_event().unwrap().event_id(), Some(event_id!("$b")));
+}
+
+#[async_test]
+async fn test_only_live_timelines_record_identity_changes() {
+    let room_data_provider = TestRoomDataProvider::default();
+
+    let controller = TimelineController::new(
+        room_data_provider.clone(),
+        TimelineFocus::Event { target: event_id!("$a").to_owned(), num_context_events: 0 },
+        None,
+        None,
+        Some(false),
+    );
+    controller
+        .handle_identity_status_changes(vec![identity_change(*BOB, IdentityState::PinViolation)])
+        .await;
+
+    assert!(room_data_provider.identity_changes.read().await.is_empty());
+}
+
+#[async_test]
+async fn test_identity_changes_of_several_timelines_are_merged() {
+    let room_data_provider = TestRoomDataProvider::default();
+
+    let timeline_a = TestTimeline::with_room_data_provider(room_data_provider.clone());
+    let timeline_b = TestTimeline::with_room_data_provider(room_data_provider.clone());
+
+    timeline_a
+        .controller
+        .handle_identity_status_changes(vec![identity_change(*BOB, IdentityState::PinViolation)])
+        .await;
+    // The second timeline doesn't know about the first change, but doesn't erase it.
+    timeline_b
+        .controller
+        .handle_identity_status_changes(vec![identity_change(*ALICE, IdentityState::PinViolation)])
+        .await;
+
+    let records = room_data_provider.identity_changes.read().await.clone();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].user_id, (*BOB).to_owned());
+    assert_eq!(records[1].user_id, (*ALICE).to_owned());
+}
+
+#[async_test]
+async fn test_the_latest_identity_change_of_a_user_is_kept() {
+    let timeline = TestTimeline::new();
+
+    timeline
+        .controller
+        .handle_identity_status_changes(vec![identity_change(*BOB, IdentityState::PinViolation)])
+        .await;
+
+    // Many changes of another user push the number of records over the limit.
+    for i in 0..MAX_IDENTITY_CHANGE_RECORDS {
+        let changed_to =
+            if i % 2 == 0 { IdentityState::PinViolation } else { IdentityState::Pinned };
+        timeline
+            .controller
+            .handle_identity_status_changes(vec![identity_change(*ALICE, changed_to)])
+            .await;
+    }
+
+    let records = timeline.data().identity_changes.read().await.clone();
+    assert_eq!(records.len(), MAX_IDENTITY_CHANGE_RECORDS);
+    assert_eq!(records[0].user_id, (*BOB).to_owned());
+
+    // Since we still know about the change of Bob, seeing the same state again
+    // isn't recorded as a new change.
+    timeline
+        .controller
+        .handle_identity_status_changes(vec![identity_change(*BOB, IdentityState::PinViolation)])
+        .await;
+
+    assert_eq!(timeline.data().identity_changes.read().await.clone(), records);
+}
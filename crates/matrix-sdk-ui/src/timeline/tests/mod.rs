@@ -27,6 +27,7 @@ use futures_util::FutureExt as _;
 use indexmap::IndexMap;
 use matrix_sdk::{
     config::RequestConfig,
+    crypto::types::events::CryptoContextInfo,
     deserialized_responses::{SyncTimelineEvent, TimelineEvent},
     event_cache::paginator::{PaginableRoom, PaginatorError},
     room::{EventWithContextResponse, Messages, MessagesOptions},
@@ -59,6 +60,7 @@ use super::{
     controller::{TimelineEnd, TimelineSettings},
     event_handler::TimelineEventKind,
     event_item::RemoteEventOrigin,
+    identity_changes::IdentityChangeRecord,
     traits::RoomDataProvider,
     util::rfind_event_by_item_id,
     EventTimelineItem, Profile, TimelineController, TimelineEventItemId, TimelineFocus,
@@ -73,6 +75,7 @@ mod echo;
 mod edit;
 mod encryption;
 mod event_filter;
+mod identity_changes;
 mod invalid;
 mod polls;
 mod reactions;
@@ -313,6 +316,12 @@ struct TestRoomDataProvider {
 
     /// Events redacted with that room data providier.
     pub redacted: Arc<RwLock<Vec<OwnedEventId>>>,
+
+    /// Identity changes saved with that room data provider.
+    pub identity_changes: Arc<RwLock<Vec<IdentityChangeRecord>>>,
+
+    /// Megolm sessions the crypto context info was requested for.
+    pub crypto_context_info_requests: Arc<RwLock<Vec<Option<String>>>>,
 }
 
 impl TestRoomDataProvider {
@@ -427,6 +436,17 @@ impl RoomDataProvider for TestRoomDataProvider {
         ready(self.fully_read_marker.clone()).boxed()
     }
 
+    fn load_identity_changes(&self) -> BoxFuture<'_, Vec<IdentityChangeRecord>> {
+        async move { self.identity_changes.read().await.clone() }.boxed()
+    }
+
+    fn save_identity_changes(&self, records: Vec<IdentityChangeRecord>) -> BoxFuture<'_, ()> {
+        async move {
+            *self.identity_changes.write().await = records;
+        }
+        .boxed()
+    }
+
     fn send(&self, content: AnyMessageLikeEventContent) -> BoxFuture<'_, Result<(), super::Error>> {
         async move {
             self.sent_events.write().await.push(content);
@@ -452,4 +472,25 @@ impl RoomDataProvider for TestRoomDataProvider {
         let info = RoomInfo::new(*DEFAULT_TEST_ROOM_ID, RoomState::Joined);
         SharedObservable::new(info).subscribe()
     }
+
+    fn crypto_context_info<'a>(
+        &'a self,
+        session_id: Option<&'a str>,
+    ) -> BoxFuture<'a, CryptoContextInfo> {
+        async move {
+            self.crypto_context_info_requests.write().await.push(session_id.map(ToOwned::to_owned));
+
+            CryptoContextInfo {
+                device_creation_ts: MilliSecondsSinceUnixEpoch(uint!(0)),
+                is_backup_configured: false,
+                backup_exists_on_server: Some(false),
+                withheld_code: None,
+            }
+        }
+        .boxed()
+    }
+
+    fn load_crypto_context(&self) -> BoxFuture<'_, ()> {
+        ready(()).boxed()
+    }
 }
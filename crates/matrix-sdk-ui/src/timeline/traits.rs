@@ -20,8 +20,10 @@ use indexmap::IndexMap;
 #[cfg(test)]
 use matrix_sdk::crypto::{DecryptionSettings, TrustRequirement};
 use matrix_sdk::{
-    deserialized_responses::TimelineEvent, event_cache::paginator::PaginableRoom, BoxFuture,
-    Result, Room,
+    crypto::types::events::CryptoContextInfo,
+    deserialized_responses::{EncryptionInfo, TimelineEvent},
+    event_cache::paginator::PaginableRoom,
+    BoxFuture, Result, Room,
 };
 use matrix_sdk_base::{latest_event::LatestEvent, RoomInfo};
 use ruma::{
@@ -32,11 +34,11 @@ use ruma::{
     },
     push::{PushConditionRoomCtx, Ruleset},
     serde::Raw,
-    EventId, OwnedEventId, OwnedTransactionId, OwnedUserId, RoomVersionId, UserId,
+    EventId, OwnedEventId, OwnedTransactionId, OwnedUserId, RoomId, RoomVersionId, UserId,
 };
 use tracing::{debug, error};
 
-use super::{Profile, RedactError, TimelineBuilder};
+use super::{identity_changes::IdentityChangeRecord, Profile, RedactError, TimelineBuilder};
 use crate::timeline::{self, pinned_events_loader::PinnedEventsRoom, Timeline};
 
 pub trait RoomExt {
@@ -96,6 +98,12 @@ pub(super) trait RoomDataProvider:
     /// Load the current fully-read event id, from storage.
     fn load_fully_read_marker(&self) -> BoxFuture<'_, Option<OwnedEventId>>;
 
+    /// Load the identity changes observed in this room, from storage.
+    fn load_identity_changes(&self) -> BoxFuture<'_, Vec<IdentityChangeRecord>>;
+
+    /// Save the identity changes observed in this room, to storage.
+    fn save_identity_changes(&self, records: Vec<IdentityChangeRecord>) -> BoxFuture<'_, ()>;
+
     fn push_rules_and_context(&self) -> BoxFuture<'_, Option<(Ruleset, PushConditionRoomCtx)>>;
 
     /// Send an event to that room.
@@ -110,6 +118,20 @@ pub(super) trait RoomDataProvider:
     ) -> BoxFuture<'a, Result<(), super::Error>>;
 
     fn room_info(&self) -> Subscriber<RoomInfo>;
+
+    /// Gather the information needed to explain why an event encrypted with
+    /// the given Megolm session couldn't be decrypted.
+    fn crypto_context_info<'a>(
+        &'a self,
+        session_id: Option<&'a str>,
+    ) -> BoxFuture<'a, CryptoContextInfo>;
+
+    /// Find out the part of the [crypto context](Self::crypto_context_info)
+    /// that requires contacting the homeserver, i.e. whether a key backup
+    /// exists on the server, and remember it.
+    ///
+    /// This must not be called while holding the timeline state lock.
+    fn load_crypto_context(&self) -> BoxFuture<'_, ()>;
 }
 
 impl RoomDataProvider for Room {
@@ -251,6 +273,44 @@ impl RoomDataProvider for Room {
         .boxed()
     }
 
+    fn load_identity_changes(&self) -> BoxFuture<'_, Vec<IdentityChangeRecord>> {
+        async {
+            let key = identity_changes_store_key(self.room_id());
+            match self.client().store().get_custom_value(key.as_bytes()).await {
+                Ok(Some(value)) => serde_json::from_slice(&value).unwrap_or_else(|e| {
+                    error!("Failed to deserialize the identity changes: {e}");
+                    Vec::new()
+                }),
+                Ok(None) => Vec::new(),
+                Err(e) => {
+                    error!("Failed to get the identity changes from the store: {e}");
+                    Vec::new()
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn save_identity_changes(&self, records: Vec<IdentityChangeRecord>) -> BoxFuture<'_, ()> {
+        async move {
+            let key = identity_changes_store_key(self.room_id());
+            let value = match serde_json::to_vec(&records) {
+                Ok(value) => value,
+                Err(e) => {
+                    error!("Failed to serialize the identity changes: {e}");
+                    return;
+                }
+            };
+
+            if let Err(e) =
+                self.client().store().set_custom_value_no_read(key.as_bytes(), value).await
+            {
+                error!("Failed to save the identity changes to the store: {e}");
+            }
+        }
+        .boxed()
+    }
+
     fn send(&self, content: AnyMessageLikeEventContent) -> BoxFuture<'_, Result<(), super::Error>> {
         async move {
             let _ = self.send_queue().send(content).await?;
@@ -279,6 +339,30 @@ impl RoomDataProvider for Room {
     fn room_info(&self) -> Subscriber<RoomInfo> {
         self.subscribe_info()
     }
+
+    fn crypto_context_info<'a>(
+        &'a self,
+        session_id: Option<&'a str>,
+    ) -> BoxFuture<'a, CryptoContextInfo> {
+        async move { Room::crypto_context_info(self, session_id).await }.boxed()
+    }
+
+    fn load_crypto_context(&self) -> BoxFuture<'_, ()> {
+        async move {
+            // The answer is cached once we got it; if we can't get it, the undecryptable
+            // events will report it as unknown.
+            if let Err(err) = self.client().encryption().backups().fast_exists_on_server().await {
+                debug!("Couldn't check whether a key backup exists on the server: {err}");
+            }
+        }
+        .boxed()
+    }
+}
+
+/// The key under which the identity changes observed in the given room are
+/// saved in the state store.
+fn identity_changes_store_key(room_id: &RoomId) -> String {
+    format!("timeline_identity_changes:{room_id}")
 }
 
 // Internal helper to make most of retry_event_decryption independent of a room
@@ -288,12 +372,28 @@ pub(super) trait Decryptor: Clone + Send + Sync + 'static {
         &self,
         raw: &Raw<AnySyncTimelineEvent>,
     ) -> impl Future<Output = Result<TimelineEvent>> + Send;
+
+    /// Get the current encryption info of the events sent by `sender`, which
+    /// were encrypted with the given Megolm session.
+    fn get_encryption_info_impl(
+        &self,
+        session_id: &str,
+        sender: &UserId,
+    ) -> impl Future<Output = Result<EncryptionInfo>> + Send;
 }
 
 impl Decryptor for Room {
     async fn decrypt_event_impl(&self, raw: &Raw<AnySyncTimelineEvent>) -> Result<TimelineEvent> {
         self.decrypt_event(raw.cast_ref()).await
     }
+
+    async fn get_encryption_info_impl(
+        &self,
+        session_id: &str,
+        sender: &UserId,
+    ) -> Result<EncryptionInfo> {
+        self.get_encryption_info(session_id, sender).await
+    }
 }
 
 #[cfg(test)]
@@ -306,4 +406,13 @@ impl Decryptor for (matrix_sdk_base::crypto::OlmMachine, ruma::OwnedRoomId) {
             olm_machine.decrypt_room_event(raw.cast_ref(), room_id, &decryption_settings).await?;
         Ok(event.into())
     }
+
+    async fn get_encryption_info_impl(
+        &self,
+        session_id: &str,
+        sender: &UserId,
+    ) -> Result<EncryptionInfo> {
+        let (olm_machine, room_id) = self;
+        Ok(olm_machine.get_session_encryption_info(room_id, session_id, sender).await?)
+    }
 }
@@ -12,7 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use ruma::MilliSecondsSinceUnixEpoch;
+use matrix_sdk::crypto::IdentityState;
+use ruma::{MilliSecondsSinceUnixEpoch, OwnedUserId};
 
 /// A [`TimelineItem`](super::TimelineItem) that doesn't correspond to an event.
 #[derive(Clone, Debug)]
@@ -25,4 +26,22 @@ pub enum VirtualTimelineItem {
 
     /// The user's own read marker.
     ReadMarker,
+
+    /// The identity of a room member changed in a way the user should be
+    /// warned about.
+    ///
+    /// It's inserted after the latest event that was received when the change
+    /// was observed, see [`TimelineBuilder::track_identity_changes()`].
+    ///
+    /// [`TimelineBuilder::track_identity_changes()`]: super::TimelineBuilder::track_identity_changes
+    IdentityChange {
+        /// The user whose identity changed.
+        user_id: OwnedUserId,
+        /// The new state of the identity, either
+        /// [`IdentityState::PinViolation`] or
+        /// [`IdentityState::VerificationViolation`].
+        changed_to: IdentityState,
+        /// When the change was observed.
+        timestamp: MilliSecondsSinceUnixEpoch,
+    },
 }
@@ -4,7 +4,11 @@ use std::{
 };
 
 use assert_matches::assert_matches;
-use matrix_sdk::{config::SyncSettings, test_utils::logged_in_client_with_server};
+use matrix_sdk::{
+    config::SyncSettings,
+    crypto::{EncryptionSettings, OlmMachine},
+    test_utils::logged_in_client_with_server,
+};
 use matrix_sdk_test::{
     async_test, mocks::mock_encryption_state, sync_timeline_event, JoinedRoomBuilder,
     SyncResponseBuilder,
@@ -15,11 +19,15 @@ use matrix_sdk_ui::{
     },
     sync_service::SyncService,
 };
-use ruma::{event_id, events::TimelineEventType, room_id, user_id};
+use ruma::{
+    device_id, event_id,
+    events::{room::message::RoomMessageEventContent, TimelineEventType},
+    room_id, user_id, EventId, RoomId,
+};
 use serde_json::json;
 use wiremock::{
     matchers::{header, method, path},
-    Mock, Request, ResponseTemplate,
+    Match as _, Mock, Request, ResponseTemplate,
 };
 
 use crate::{
@@ -306,3 +314,223 @@ async fn test_notification_client_sliding_sync() {
     assert_eq!(item.room_computed_display_name, sender_display_name);
     assert_eq!(item.is_noisy, Some(false));
 }
+
+#[async_test]
+async fn test_notification_client_batch() {
+    let first_room_id = room_id!("!a98sd12bjh:example.org");
+    let second_room_id = room_id!("!b12ke39ckl:example.org");
+    let (client, server) = logged_in_client_with_server().await;
+
+    let first_event_id = event_id!("$first_event_id");
+    let second_event_id = event_id!("$second_event_id");
+    let missing_event_id = event_id!("$missing_event_id");
+    let sender = user_id!("@user:example.org");
+
+    let message = |room_id, event_id| {
+        json!({
+            "content": {
+                "body": "Hello world!",
+                "msgtype": "m.text",
+            },
+            "room_id": room_id,
+            "event_id": event_id,
+            "origin_server_ts": 152049794,
+            "sender": sender,
+            "type": "m.room.message",
+        })
+    };
+    let first_event_json = message(first_room_id, first_event_id);
+    let second_event_json = message(second_room_id, second_event_id);
+
+    let pos = Mutex::new(0);
+    Mock::given(SlidingSyncMatcher)
+        .respond_with(move |request: &Request| {
+            let partial_request: PartialSlidingSyncRequest = request.body_json().unwrap();
+            let mut pos = pos.lock().unwrap();
+            *pos += 1;
+            let pos_as_str = (*pos).to_string();
+            ResponseTemplate::new(200).set_body_json(json!({
+                "txn_id": partial_request.txn_id,
+                "pos": pos_as_str,
+                "rooms": {
+                    first_room_id: {
+                        "name": "First room",
+                        "initial": true,
+                        "timeline": [first_event_json.clone()],
+                    },
+                    second_room_id: {
+                        "name": "Second room",
+                        "initial": true,
+                        "timeline": [second_event_json.clone()],
+                    },
+                },
+                "extensions": {
+                    "account_data": {}
+                }
+            }))
+        })
+        .mount(&server)
+        .await;
+
+    let dummy_sync_service = Arc::new(SyncService::builder(client.clone()).build().await.unwrap());
+    let process_setup =
+        NotificationProcessSetup::SingleProcess { sync_service: dummy_sync_service };
+    let notification_client = NotificationClient::new(client, process_setup).await.unwrap();
+
+    let statuses = notification_client
+        .get_notifications_with_sliding_sync(&[
+            (first_room_id, first_event_id),
+            (second_room_id, second_event_id),
+            (first_room_id, missing_event_id),
+        ])
+        .await
+        .unwrap();
+
+    // A single sliding sync subscribed to both rooms.
+    let sliding_sync_requests = server
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|request| SlidingSyncMatcher.matches(request))
+        .collect::<Vec<_>>();
+    assert_eq!(sliding_sync_requests.len(), 3);
+
+    let first_request: PartialSlidingSyncRequest = sliding_sync_requests[0].body_json().unwrap();
+    assert_eq!(first_request.conn_id.as_deref(), Some("notifications"));
+    let first_request: serde_json::Value = sliding_sync_requests[0].body_json().unwrap();
+    let subscriptions = first_request["room_subscriptions"].as_object().unwrap();
+    assert!(subscriptions.contains_key(first_room_id.as_str()));
+    assert!(subscriptions.contains_key(second_room_id.as_str()));
+
+    // Results are in the same order as the requests.
+    assert_eq!(statuses.len(), 3);
+
+    let mut statuses = statuses.into_iter();
+
+    assert_matches!(statuses.next(), Some(Ok(NotificationStatus::Event(item))) => {
+        assert_eq!(item.room_computed_display_name, "First room");
+    });
+    assert_matches!(statuses.next(), Some(Ok(NotificationStatus::Event(item))) => {
+        assert_eq!(item.room_computed_display_name, "Second room");
+    });
+    assert_matches!(statuses.next(), Some(Ok(NotificationStatus::EventNotFound)));
+}
+
+#[async_test]
+async fn test_notification_client_batch_with_encrypted_events() {
+    let decryptable_room_id = room_id!("!a98sd12bjh:example.org");
+    let undecryptable_room_id = room_id!("!b12ke39ckl:example.org");
+    let (client, server) = logged_in_client_with_server().await;
+
+    let decryptable_event_id = event_id!("$decryptable_event_id");
+    let undecryptable_event_id = event_id!("$undecryptable_event_id");
+    let sender = user_id!("@bob:example.org");
+
+    // Bob encrypts a message in both rooms, but we only get the room key of the
+    // first one.
+    let bob = OlmMachine::new(sender, device_id!("BOBDEVICE")).await;
+
+    async fn encrypted_message(
+        bob: &OlmMachine,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> serde_json::Value {
+        bob.share_room_key(room_id, std::iter::empty(), EncryptionSettings::default())
+            .await
+            .unwrap();
+        let content = bob
+            .encrypt_room_event(room_id, RoomMessageEventContent::text_plain("Hello world!"))
+            .await
+            .unwrap();
+
+        json!({
+            "content": content,
+            "room_id": room_id,
+            "event_id": event_id,
+            "origin_server_ts": 152049794,
+            "sender": bob.user_id(),
+            "type": "m.room.encrypted",
+        })
+    }
+
+    let decryptable_event_json =
+        encrypted_message(&bob, decryptable_room_id, decryptable_event_id).await;
+    let undecryptable_event_json =
+        encrypted_message(&bob, undecryptable_room_id, undecryptable_event_id).await;
+
+    let room_keys =
+        bob.store().export_room_keys(|session| session.room_id() == decryptable_room_id).await;
+    client
+        .olm_machine_for_testing()
+        .await
+        .as_ref()
+        .unwrap()
+        .store()
+        .import_exported_room_keys(room_keys.unwrap(), |_, _| ())
+        .await
+        .unwrap();
+
+    let pos = Mutex::new(0);
+    Mock::given(SlidingSyncMatcher)
+        .respond_with(move |request: &Request| {
+            let partial_request: PartialSlidingSyncRequest = request.body_json().unwrap();
+            let mut pos = pos.lock().unwrap();
+            *pos += 1;
+            let pos_as_str = (*pos).to_string();
+            ResponseTemplate::new(200).set_body_json(json!({
+                "txn_id": partial_request.txn_id,
+                "pos": pos_as_str,
+                "rooms": {
+                    decryptable_room_id: {
+                        "name": "First room",
+                        "initial": true,
+                        "timeline": [decryptable_event_json.clone()],
+                    },
+                    undecryptable_room_id: {
+                        "name": "Second room",
+                        "initial": true,
+                        "timeline": [undecryptable_event_json.clone()],
+                    },
+                },
+                "extensions": {
+                    "account_data": {}
+                }
+            }))
+        })
+        .mount(&server)
+        .await;
+
+    let dummy_sync_service = Arc::new(SyncService::builder(client.clone()).build().await.unwrap());
+    let process_setup =
+        NotificationProcessSetup::SingleProcess { sync_service: dummy_sync_service };
+    let notification_client = NotificationClient::new(client, process_setup).await.unwrap();
+
+    let statuses = notification_client
+        .get_notifications_with_sliding_sync(&[
+            (decryptable_room_id, decryptable_event_id),
+            (undecryptable_room_id, undecryptable_event_id),
+        ])
+        .await
+        .unwrap();
+
+    assert_eq!(statuses.len(), 2);
+
+    let mut statuses = statuses.into_iter();
+
+    // The event whose room key we have is decrypted.
+    assert_matches!(statuses.next(), Some(Ok(NotificationStatus::Event(item))) => {
+        assert_matches!(item.event, NotificationEvent::Timeline(event) => {
+            assert_eq!(event.event_type(), TimelineEventType::RoomMessage);
+        });
+    });
+
+    // The other one is still reported, with the encrypted event, so that an
+    // "encrypted message" notification can be shown.
+    assert_matches!(statuses.next(), Some(Ok(NotificationStatus::Event(item))) => {
+        assert_matches!(item.event, NotificationEvent::Timeline(event) => {
+            assert_eq!(event.event_type(), TimelineEventType::RoomEncrypted);
+        });
+        assert_eq!(item.room_computed_display_name, "Second room");
+    });
+}
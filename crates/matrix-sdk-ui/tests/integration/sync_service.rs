@@ -17,9 +17,11 @@ use std::{
     time::Duration,
 };
 
+use assert_matches::assert_matches;
+use futures_util::{FutureExt as _, Stream, StreamExt as _};
 use matrix_sdk::test_utils::logged_in_client_with_server;
 use matrix_sdk_test::async_test;
-use matrix_sdk_ui::sync_service::{State, SyncService};
+use matrix_sdk_ui::sync_service::{self, State, SyncMode, SyncService};
 use serde_json::json;
 use stream_assert::{assert_next_matches, assert_pending};
 use wiremock::{Match as _, Mock, MockGuard, MockServer, Request, ResponseTemplate};
@@ -202,3 +204,238 @@ async fn test_sync_service_state() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[async_test]
+async fn test_sync_service_offline_and_reconnection() -> anyhow::Result<()> {
+    let (client, server) = logged_in_client_with_server().await;
+
+    let encryption_pos = Arc::new(Mutex::new(0));
+    let room_pos = Arc::new(Mutex::new(0));
+    let _guard =
+        setup_mocking_sliding_sync_server(&server, encryption_pos.clone(), room_pos.clone()).await;
+
+    let sync_service = SyncService::builder(client).build().await.unwrap();
+    let mut state_stream = sync_service.state();
+
+    sync_service.start().await;
+    assert_next_matches!(state_stream, State::Running);
+
+    // When the network is reported unreachable, both syncs are stopped.
+    sync_service.set_network_reachable(false).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_next_matches!(state_stream, State::Offline);
+    assert_eq!(sync_service.task_states(), (false, false));
+
+    // Starting again doesn't do anything while offline.
+    sync_service.start().await;
+    assert_pending!(state_stream);
+
+    // When the network is back, the syncs are restarted immediately.
+    sync_service.set_network_reachable(true).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_next_matches!(state_stream, State::Running);
+    assert_eq!(sync_service.task_states(), (true, true));
+
+    // Going offline and stopping the service makes it idle.
+    sync_service.set_network_reachable(false).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_next_matches!(state_stream, State::Offline);
+
+    sync_service.stop().await?;
+    assert_next_matches!(state_stream, State::Idle);
+    assert_eq!(sync_service.task_states(), (false, false));
+    assert!(sync_service.try_get_encryption_sync_permit().is_some());
+
+    Ok(())
+}
+
+/// Wait for the next state of the sync service, without letting the paused
+/// tokio clock advance by itself.
+///
+/// The runtime moves a paused clock forward whenever it has nothing to do;
+/// yielding keeps it busy while the HTTP requests are processed by the mock
+/// server, so that only the test decides when time passes.
+async fn next_state_without_advancing_time(
+    state_stream: &mut (impl Stream<Item = State> + Unpin),
+) -> Option<State> {
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+
+    while std::time::Instant::now() < deadline {
+        if let Some(state) = state_stream.next().now_or_never() {
+            return state;
+        }
+
+        tokio::task::yield_now().await;
+    }
+
+    panic!("the sync service state didn't change in time");
+}
+
+#[async_test]
+async fn test_sync_service_reconnects_with_backoff() -> anyhow::Result<()> {
+    let (client, server) = logged_in_client_with_server().await;
+
+    let encryption_pos = Arc::new(Mutex::new(0));
+    let room_pos = Arc::new(Mutex::new(0));
+    let _guard =
+        setup_mocking_sliding_sync_server(&server, encryption_pos.clone(), room_pos.clone()).await;
+
+    // A gateway error is handled as a network error, and takes precedence over the
+    // successful responses as long as it's mounted.
+    let unreachable = || {
+        Mock::given(SlidingSyncMatcher).respond_with(ResponseTemplate::new(502)).with_priority(1)
+    };
+    let unreachable_guard = unreachable().mount_as_scoped(&server).await;
+
+    // In the background, only the encryption sync is running.
+    let sync_service = SyncService::builder(client).build().await.unwrap();
+    sync_service.set_mode(SyncMode::Background).await?;
+    let mut state_stream = sync_service.state();
+
+    tokio::time::pause();
+
+    sync_service.start().await;
+    assert_next_matches!(state_stream, State::Running);
+
+    // The sync fails, the service goes offline by itself.
+    assert_eq!(next_state_without_advancing_time(&mut state_stream).await, Some(State::Offline));
+
+    // It tries to reconnect after a delay that doubles every time, and stays offline
+    // while the attempts fail.
+    for delay in [Duration::from_secs(1), Duration::from_secs(2)] {
+        tokio::time::advance(delay - Duration::from_millis(1)).await;
+        assert_pending!(state_stream);
+
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(
+            next_state_without_advancing_time(&mut state_stream).await,
+            Some(State::Offline)
+        );
+    }
+
+    // The server is back: the next attempt succeeds, and the service only goes back
+    // to running once it got a response.
+    drop(unreachable_guard);
+
+    tokio::time::advance(Duration::from_secs(4)).await;
+    assert_eq!(next_state_without_advancing_time(&mut state_stream).await, Some(State::Running));
+    assert!(*encryption_pos.lock().unwrap() > 0);
+    assert_eq!(*room_pos.lock().unwrap(), 0);
+
+    // Since the sync went well, the backoff starts over the next time the service goes
+    // offline.
+    let _unreachable_guard = unreachable().mount_as_scoped(&server).await;
+    assert_eq!(next_state_without_advancing_time(&mut state_stream).await, Some(State::Offline));
+
+    tokio::time::advance(Duration::from_millis(999)).await;
+    assert_pending!(state_stream);
+
+    tokio::time::advance(Duration::from_millis(1)).await;
+    assert_eq!(next_state_without_advancing_time(&mut state_stream).await, Some(State::Offline));
+
+    sync_service.stop().await?;
+    assert_next_matches!(state_stream, State::Idle);
+
+    Ok(())
+}
+
+#[async_test]
+async fn test_sync_service_background_mode() -> anyhow::Result<()> {
+    let (client, server) = logged_in_client_with_server().await;
+
+    let encryption_pos = Arc::new(Mutex::new(0));
+    let room_pos = Arc::new(Mutex::new(0));
+    let _guard =
+        setup_mocking_sliding_sync_server(&server, encryption_pos.clone(), room_pos.clone()).await;
+
+    let sync_service = SyncService::builder(client).build().await.unwrap();
+    let mut state_stream = sync_service.state();
+
+    assert_eq!(sync_service.mode(), SyncMode::Foreground);
+    sync_service.set_mode(SyncMode::Background).await?;
+    assert_pending!(state_stream);
+
+    // In the background, only the encryption sync is running.
+    sync_service.start().await;
+    assert_next_matches!(state_stream, State::Running);
+    assert_eq!(sync_service.task_states(), (true, false));
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    assert!(*encryption_pos.lock().unwrap() > 0);
+    assert_eq!(*room_pos.lock().unwrap(), 0);
+
+    // Going back to the foreground restarts the service with both syncs.
+    sync_service.set_mode(SyncMode::Foreground).await?;
+    // (The intermediate `Idle` state is skipped by the subscriber.)
+    assert_next_matches!(state_stream, State::Running);
+    assert_pending!(state_stream);
+    assert_eq!(sync_service.task_states(), (true, true));
+
+    // Catching up isn't possible while running.
+    assert_matches!(
+        sync_service.catch_up(Duration::from_secs(1)).await,
+        Err(sync_service::Error::AlreadyRunning)
+    );
+
+    sync_service.stop().await?;
+    assert_next_matches!(state_stream, State::Idle);
+
+    Ok(())
+}
+
+#[async_test]
+async fn test_sync_service_catch_up() -> anyhow::Result<()> {
+    let (client, server) = logged_in_client_with_server().await;
+
+    let encryption_pos = Arc::new(Mutex::new(0));
+    let room_pos = Arc::new(Mutex::new(0));
+    let _guard =
+        setup_mocking_sliding_sync_server(&server, encryption_pos.clone(), room_pos.clone()).await;
+
+    let sync_service = SyncService::builder(client).build().await.unwrap();
+    let mut state_stream = sync_service.state();
+
+    sync_service.catch_up(Duration::from_secs(5)).await?;
+
+    // Both syncs have run, and have been stopped.
+    assert!(*encryption_pos.lock().unwrap() > 0);
+    assert!(*room_pos.lock().unwrap() > 0);
+    assert_eq!(sync_service.task_states(), (false, false));
+    assert!(sync_service.try_get_encryption_sync_permit().is_some());
+
+    // The state of the service hasn't changed.
+    assert_eq!(state_stream.get(), State::Idle);
+    assert_pending!(state_stream);
+
+    Ok(())
+}
+
+#[async_test]
+async fn test_sync_service_catch_up_honours_deadline_when_permit_is_busy() -> anyhow::Result<()> {
+    let (client, server) = logged_in_client_with_server().await;
+
+    let encryption_pos = Arc::new(Mutex::new(0));
+    let room_pos = Arc::new(Mutex::new(0));
+    let _guard =
+        setup_mocking_sliding_sync_server(&server, encryption_pos.clone(), room_pos.clone()).await;
+
+    let sync_service = SyncService::builder(client).build().await.unwrap();
+
+    // Someone else (e.g. a notification client) holds the encryption sync permit.
+    let permit = sync_service.try_get_encryption_sync_permit().unwrap();
+
+    // Catching up doesn't wait for the permit past the deadline.
+    tokio::time::timeout(Duration::from_secs(2), sync_service.catch_up(Duration::from_millis(300)))
+        .await
+        .expect("catching up should stop at the deadline")?;
+
+    // The encryption sync couldn't run, and the state can be changed again.
+    assert_eq!(*encryption_pos.lock().unwrap(), 0);
+    assert_eq!(sync_service.task_states(), (false, false));
+
+    drop(permit);
+    sync_service.set_mode(SyncMode::Background).await?;
+
+    Ok(())
+}
@@ -0,0 +1,802 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    future::IntoFuture,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use eyeball::SharedObservable;
+use futures_core::Stream;
+use matrix_sdk_base::{
+    boxed_into_future,
+    crypto::types::{
+        qr_login::{QrCodeData, QrCodeMode},
+        SecretsBundle,
+    },
+};
+use ruma::OwnedDeviceId;
+use thiserror::Error;
+use tokio::{sync::oneshot, time::Instant};
+use tracing::trace;
+use url::Url;
+use vodozemac::ecies::CheckCode;
+
+use super::{
+    messages::{AuthorizationGrant, LoginFailureReason, LoginProtocolType},
+    secure_channel::{EstablishedSecureChannel, SecureChannel},
+    QRCodeGrantLoginError, SecureChannelError,
+};
+#[cfg(doc)]
+use crate::oidc::Oidc;
+use crate::{authentication::qrcode::messages::QrAuthMessage, Client};
+
+/// How long we wait for the homeserver to list the new device once the new
+/// device told us that it has logged in.
+const NEW_DEVICE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often we ask the homeserver for our list of devices while we're waiting
+/// for the new device to show up.
+const NEW_DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+async fn send_failure(
+    channel: &mut EstablishedSecureChannel,
+    reason: LoginFailureReason,
+) -> Result<(), SecureChannelError> {
+    channel.send_json(QrAuthMessage::LoginFailure { reason, homeserver: None }).await
+}
+
+/// Type telling us about the progress of granting a login to a new device
+/// using a QR code.
+#[derive(Clone, Debug, Default)]
+pub enum GrantLoginProgress {
+    /// We're just starting up, this is the default and initial state.
+    #[default]
+    Starting,
+    /// We have generated the QR code, it now needs to be displayed so the new
+    /// device can scan it.
+    QrCodeReady {
+        /// The data which needs to be encoded in the QR code.
+        qr_code_data: QrCodeData,
+    },
+    /// The new device has scanned our QR code and established the secure
+    /// channel, we now need to confirm that the channel is indeed secure by
+    /// entering the [`CheckCode`] the new device is displaying.
+    WaitingForCheckCode {
+        /// The sender which needs to be used to input the check code displayed
+        /// on the new device.
+        check_code_sender: CheckCodeSender,
+    },
+    /// We have established the secure channel by scanning the QR code of the
+    /// new device, but the new device needs to be told about the
+    /// [`CheckCode`] so it can verify that the secure channel is indeed
+    /// secure.
+    EstablishingSecureChannel {
+        /// The check code we need to, out of band, send to the new device.
+        check_code: CheckCode,
+    },
+    /// The new device has requested a device authorization grant from the
+    /// OIDC provider, the user now needs to open the given URL to allow the
+    /// login of the new device.
+    WaitingForAuth {
+        /// The URL the user should open to approve the login of the new
+        /// device.
+        verification_uri: Url,
+    },
+    /// The new device has been logged in, we're sending over our end-to-end
+    /// encryption secrets.
+    SyncingSecrets,
+    /// The login has been granted, the new device is now logged in and fully
+    /// set up.
+    Done,
+}
+
+/// Error type for the failures of the [`CheckCodeSender::send()`] method.
+#[derive(Debug, Error)]
+pub enum CheckCodeSenderError {
+    /// The check code has already been sent.
+    #[error("The check code has already been sent")]
+    AlreadySent,
+    /// The login has been aborted, nobody is waiting for the check code
+    /// anymore.
+    #[error("The login has been aborted, the check code can't be sent")]
+    CannotSend,
+}
+
+/// Handle used to input the [`CheckCode`] displayed on the new device into the
+/// existing device.
+#[derive(Clone, Debug)]
+pub struct CheckCodeSender {
+    inner: Arc<Mutex<Option<oneshot::Sender<u8>>>>,
+}
+
+impl CheckCodeSender {
+    fn new() -> (Self, oneshot::Receiver<u8>) {
+        let (sender, receiver) = oneshot::channel();
+
+        (Self { inner: Arc::new(Mutex::new(Some(sender))) }, receiver)
+    }
+
+    /// Send the check code, as displayed on the new device, to the login
+    /// process.
+    ///
+    /// The login will fail with a [`SecureChannelError::InvalidCheckCode`]
+    /// error if the check code doesn't match.
+    pub fn send(&self, check_code: u8) -> Result<(), CheckCodeSenderError> {
+        let sender = self.inner.lock().unwrap().take().ok_or(CheckCodeSenderError::AlreadySent)?;
+        sender.send(check_code).map_err(|_| CheckCodeSenderError::CannotSend)
+    }
+}
+
+/// Named future for the [`Oidc::grant_login_with_generated_qr_code()`]
+/// method.
+#[derive(Debug)]
+pub struct GrantLoginWithGeneratedQrCode<'a> {
+    client: &'a Client,
+    state: SharedObservable<GrantLoginProgress>,
+}
+
+impl<'a> GrantLoginWithGeneratedQrCode<'a> {
+    pub(crate) fn new(client: &'a Client) -> GrantLoginWithGeneratedQrCode<'a> {
+        GrantLoginWithGeneratedQrCode { client, state: Default::default() }
+    }
+
+    /// Subscribe to the progress of the QR code login.
+    ///
+    /// It's necessary to subscribe to this to receive the QR code which needs
+    /// to be displayed, and to input the [`CheckCode`] the new device is
+    /// displaying.
+    pub fn subscribe_to_progress(&self) -> impl Stream<Item = GrantLoginProgress> {
+        self.state.subscribe()
+    }
+}
+
+impl<'a> IntoFuture for GrantLoginWithGeneratedQrCode<'a> {
+    type Output = Result<(), QRCodeGrantLoginError>;
+    boxed_into_future!(extra_bounds: 'a);
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(async move {
+            // Export the secrets first, there's no point in letting a new device log in if
+            // we can't hand over our secrets to it afterwards.
+            let bundle = export_secrets_bundle(self.client).await?;
+
+            let server_name = self
+                .client
+                .user_id()
+                .ok_or(QRCodeGrantLoginError::NotLoggedIn)?
+                .server_name()
+                .to_string();
+
+            let http_client = self.client.inner.http_client.clone();
+            let channel =
+                SecureChannel::reciprocate(http_client, &self.client.homeserver(), server_name)
+                    .await?;
+
+            self.state.set(GrantLoginProgress::QrCodeReady {
+                qr_code_data: channel.qr_code_data().clone(),
+            });
+
+            // Wait for the new device to scan the QR code and to connect to us.
+            trace!("Waiting for the new device to connect to the secure channel.");
+            let channel = channel.connect().await?;
+
+            // The new device has scanned our QR code, but we can't be sure that it's the
+            // device we think it is, let the user confirm this using the check code.
+            let (check_code_sender, check_code_receiver) = CheckCodeSender::new();
+            self.state.set(GrantLoginProgress::WaitingForCheckCode { check_code_sender });
+
+            let check_code =
+                check_code_receiver.await.map_err(|_| QRCodeGrantLoginError::CheckCodeCancelled)?;
+            let channel = channel.confirm(check_code)?;
+
+            trace!("Established the secure channel.");
+
+            // The new device already knows about our homeserver since it's part of the QR
+            // code, so it's going to pick a login protocol right away.
+            grant_login(self.client, channel, bundle, &self.state).await
+        })
+    }
+}
+
+/// Named future for the [`Oidc::grant_login_with_scanned_qr_code()`] method.
+#[derive(Debug)]
+pub struct GrantLoginWithScannedQrCode<'a> {
+    client: &'a Client,
+    qr_code_data: &'a QrCodeData,
+    state: SharedObservable<GrantLoginProgress>,
+}
+
+impl<'a> GrantLoginWithScannedQrCode<'a> {
+    pub(crate) fn new(
+        client: &'a Client,
+        qr_code_data: &'a QrCodeData,
+    ) -> GrantLoginWithScannedQrCode<'a> {
+        GrantLoginWithScannedQrCode { client, qr_code_data, state: Default::default() }
+    }
+
+    /// Subscribe to the progress of the QR code login.
+    ///
+    /// It's usually necessary to subscribe to this to let the new device know
+    /// about the [`CheckCode`] which is used to verify that the two devices
+    /// are communicating in a secure manner.
+    pub fn subscribe_to_progress(&self) -> impl Stream<Item = GrantLoginProgress> {
+        self.state.subscribe()
+    }
+}
+
+impl<'a> IntoFuture for GrantLoginWithScannedQrCode<'a> {
+    type Output = Result<(), QRCodeGrantLoginError>;
+    boxed_into_future!(extra_bounds: 'a);
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(async move {
+            // We're the existing device, so the QR code needs to come from a new device
+            // which wants to log in.
+            if self.qr_code_data.mode() != QrCodeMode::Login {
+                return Err(SecureChannelError::InvalidIntent.into());
+            }
+
+            let bundle = export_secrets_bundle(self.client).await?;
+
+            // Since we're the one that scanned the QR code, we're certain that the secure
+            // channel is secure, under the assumption that we didn't scan the wrong QR
+            // code.
+            let http_client = self.client.inner.http_client.inner.clone();
+            let mut channel = EstablishedSecureChannel::from_qr_code(
+                http_client,
+                self.qr_code_data,
+                QrCodeMode::Reciprocate,
+            )
+            .await?;
+
+            trace!("Established the secure channel.");
+
+            // The new device isn't yet sure that it's talking to the right device, show a
+            // check code so it can confirm.
+            let check_code = channel.check_code().to_owned();
+            self.state.set(GrantLoginProgress::EstablishingSecureChannel { check_code });
+
+            // The QR code of the new device doesn't contain a homeserver, so we need to
+            // tell it which one to use and which login protocols we support.
+            trace!("Letting the new device know about the supported login protocols.");
+            channel
+                .send_json(QrAuthMessage::LoginProtocols {
+                    protocols: vec![LoginProtocolType::DeviceAuthorizationGrant],
+                    homeserver: self.client.homeserver(),
+                })
+                .await?;
+
+            grant_login(self.client, channel, bundle, &self.state).await
+        })
+    }
+}
+
+async fn export_secrets_bundle(client: &Client) -> Result<SecretsBundle, QRCodeGrantLoginError> {
+    let olm_machine = client.olm_machine().await;
+    let olm_machine = olm_machine.as_ref().ok_or(QRCodeGrantLoginError::NotLoggedIn)?;
+
+    Ok(olm_machine.store().export_secrets_bundle().await?)
+}
+
+/// Check if the given device exists in the list of devices of our own user.
+async fn device_exists(
+    client: &Client,
+    device_id: &OwnedDeviceId,
+) -> Result<bool, QRCodeGrantLoginError> {
+    let response = client.devices().await?;
+
+    Ok(response.devices.iter().any(|device| &device.device_id == device_id))
+}
+
+/// Wait for the given device to show up in the list of devices of our own
+/// user.
+///
+/// The new device considers itself logged in as soon as it received an access
+/// token, the homeserver might take a bit longer to list it. Returns `false` if
+/// the device didn't show up within the [`NEW_DEVICE_TIMEOUT`].
+async fn wait_for_device(
+    client: &Client,
+    device_id: &OwnedDeviceId,
+) -> Result<bool, QRCodeGrantLoginError> {
+    let deadline = Instant::now() + NEW_DEVICE_TIMEOUT;
+
+    loop {
+        if device_exists(client, device_id).await? {
+            return Ok(true);
+        }
+
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+
+        trace!("The new device isn't known by the homeserver yet, checking again shortly.");
+        tokio::time::sleep(NEW_DEVICE_POLL_INTERVAL).await;
+    }
+}
+
+/// The part of the login process that is shared between both ways of
+/// establishing the secure channel.
+async fn grant_login(
+    client: &Client,
+    mut channel: EstablishedSecureChannel,
+    bundle: SecretsBundle,
+    state: &SharedObservable<GrantLoginProgress>,
+) -> Result<(), QRCodeGrantLoginError> {
+    // Let's see which protocol the new device picked.
+    trace!("Waiting for the new device to pick a login protocol.");
+    let (device_authorization_grant, device_id) = match channel.receive_json().await? {
+        QrAuthMessage::LoginProtocol { device_authorization_grant, protocol, device_id } => {
+            if protocol != LoginProtocolType::DeviceAuthorizationGrant {
+                send_failure(&mut channel, LoginFailureReason::UnsupportedProtocol).await?;
+                return Err(QRCodeGrantLoginError::UnsupportedProtocol(protocol));
+            }
+
+            (device_authorization_grant, OwnedDeviceId::from(device_id.to_base64()))
+        }
+        QrAuthMessage::LoginFailure { reason, homeserver } => {
+            return Err(QRCodeGrantLoginError::LoginFailure { reason, homeserver });
+        }
+        message => {
+            send_failure(&mut channel, LoginFailureReason::UnexpectedMessageReceived).await?;
+
+            return Err(QRCodeGrantLoginError::UnexpectedMessage {
+                expected: "m.login.protocol",
+                received: message,
+            });
+        }
+    };
+
+    // The new device must not be able to take over one of our existing devices.
+    if device_exists(client, &device_id).await? {
+        send_failure(&mut channel, LoginFailureReason::DeviceAlreadyExists).await?;
+        return Err(QRCodeGrantLoginError::DeviceIdAlreadyInUse(device_id));
+    }
+
+    // The verification URL comes from the new device, make sure it points to the
+    // OIDC provider of our homeserver before we show it to the user.
+    let issuer = Url::parse(&client.oidc().fetch_authentication_issuer().await?)?;
+    let Some(verification_uri) = verification_uri(&device_authorization_grant, &issuer) else {
+        send_failure(&mut channel, LoginFailureReason::UnexpectedMessageReceived).await?;
+        return Err(QRCodeGrantLoginError::UntrustedVerificationUri);
+    };
+
+    trace!("Accepting the login protocol picked by the new device.");
+    channel.send_json(QrAuthMessage::LoginProtocolAccepted).await?;
+
+    // The user now needs to approve the login with the OIDC provider.
+    state.set(GrantLoginProgress::WaitingForAuth { verification_uri });
+
+    trace!("Waiting for the new device to receive an access token.");
+    match channel.receive_json().await? {
+        QrAuthMessage::LoginSuccess => (),
+        QrAuthMessage::LoginDeclined => return Err(QRCodeGrantLoginError::LoginDeclined),
+        QrAuthMessage::LoginFailure { reason, homeserver } => {
+            return Err(QRCodeGrantLoginError::LoginFailure { reason, homeserver });
+        }
+        message => {
+            send_failure(&mut channel, LoginFailureReason::UnexpectedMessageReceived).await?;
+
+            return Err(QRCodeGrantLoginError::UnexpectedMessage {
+                expected: "m.login.success",
+                received: message,
+            });
+        }
+    }
+
+    // Make sure the new device is indeed the one that got logged in before we send
+    // our secrets to it.
+    if !wait_for_device(client, &device_id).await? {
+        send_failure(&mut channel, LoginFailureReason::DeviceNotFound).await?;
+        return Err(QRCodeGrantLoginError::DeviceNotFound(device_id));
+    }
+
+    trace!("Sending the secrets bundle to the new device.");
+    state.set(GrantLoginProgress::SyncingSecrets);
+    channel.send_json(QrAuthMessage::LoginSecrets(bundle)).await?;
+
+    trace!("Successfully granted the login to the new device.");
+    state.set(GrantLoginProgress::Done);
+
+    Ok(())
+}
+
+/// Get the URL the user should open to approve the login, preferring the one
+/// with the user code pre-filled.
+///
+/// Returns `None` if any of the URLs the new device sent us doesn't share the
+/// origin of the given OIDC issuer.
+fn verification_uri(grant: &AuthorizationGrant, issuer: &Url) -> Option<Url> {
+    let is_trusted = |uri: &Url| uri.origin() == issuer.origin();
+
+    let verification_uri = grant.verification_uri.url();
+
+    if !is_trusted(verification_uri) {
+        return None;
+    }
+
+    match &grant.verification_uri_complete {
+        Some(uri) => Url::parse(uri.secret()).ok().filter(is_trusted),
+        None => Some(verification_uri.clone()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches2::{assert_let, assert_matches};
+    use futures_util::{join, StreamExt};
+    use matrix_sdk_test::async_test;
+    use serde_json::json;
+    use similar_asserts::assert_eq;
+    use vodozemac::Curve25519PublicKey;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::{
+        authentication::qrcode::secure_channel::test::MockedRendezvousServer,
+        http_client::HttpClient, test_utils::logged_in_client,
+    };
+
+    /// Mock the device list of our user, the new device only shows up once it
+    /// has logged in and after the homeserver answered `lagging_requests` more
+    /// requests without it.
+    async fn mock_devices(
+        server: &MockServer,
+        existing_device_id: Curve25519PublicKey,
+        new_device_id: Curve25519PublicKey,
+        lagging_requests: u64,
+    ) {
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/r0/devices"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "devices": [{ "device_id": existing_device_id.to_base64() }],
+            })))
+            .up_to_n_times(1 + lagging_requests)
+            .mount(server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/r0/devices"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "devices": [
+                    { "device_id": existing_device_id.to_base64() },
+                    { "device_id": new_device_id.to_base64() },
+                ],
+            })))
+            .mount(server)
+            .await;
+    }
+
+    /// Mock the OIDC issuer advertised by the homeserver.
+    async fn mock_auth_issuer(server: &MockServer) {
+        Mock::given(method("GET"))
+            .and(path("/_matrix/client/unstable/org.matrix.msc2965/auth_issuer"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "issuer": "https://id.matrix.org/",
+            })))
+            .mount(server)
+            .await;
+    }
+
+    fn authorization_grant() -> AuthorizationGrant {
+        serde_json::from_value(json!({
+            "verification_uri": "https://id.matrix.org/link",
+            "verification_uri_complete": "https://id.matrix.org/link?code=N32YVC",
+        }))
+        .expect("We should be able to deserialize the authorization grant")
+    }
+
+    /// An authorization grant pointing to a page which doesn't belong to the
+    /// OIDC provider of the homeserver.
+    fn untrusted_authorization_grant() -> AuthorizationGrant {
+        serde_json::from_value(json!({
+            "verification_uri": "https://id.matrix.org/link",
+            "verification_uri_complete": "https://evil.example.org/link?code=N32YVC",
+        }))
+        .expect("We should be able to deserialize the authorization grant")
+    }
+
+    /// Create the existing device, which is going to grant the login.
+    async fn existing_device(server: &MockServer) -> Client {
+        let alice = logged_in_client(Some(server.uri())).await;
+        alice
+            .olm_machine()
+            .await
+            .as_ref()
+            .unwrap()
+            .bootstrap_cross_signing(false)
+            .await
+            .expect("Alice should be able to bootstrap cross-signing");
+
+        alice
+    }
+
+    /// This is the new device side of the QR login dance, minus the
+    /// communication with the OIDC provider.
+    async fn login(
+        qr_code_data: QrCodeData,
+        check_code_sender: oneshot::Sender<CheckCode>,
+        device_id: Curve25519PublicKey,
+        grant: AuthorizationGrant,
+    ) -> Option<SecretsBundle> {
+        let bob = EstablishedSecureChannel::from_qr_code(
+            reqwest::Client::new(),
+            &qr_code_data,
+            QrCodeMode::Login,
+        )
+        .await
+        .expect("Bob should be able to establish the secure channel");
+
+        check_code_sender
+            .send(bob.check_code().to_owned())
+            .expect("Bob should be able to send the check code to Alice");
+
+        finish_login(bob, device_id, grant).await
+    }
+
+    /// This is the new device side of the QR login dance if the new device is
+    /// displaying the QR code, minus the communication with the OIDC provider.
+    async fn login_with_displayed_qr_code(
+        channel: SecureChannel,
+        check_code_receiver: oneshot::Receiver<CheckCode>,
+        device_id: Curve25519PublicKey,
+    ) -> Option<SecretsBundle> {
+        let bob =
+            channel.connect().await.expect("Bob should be able to connect the secure channel");
+
+        let check_code =
+            check_code_receiver.await.expect("Alice should have shown us the check code");
+        let mut bob = bob
+            .confirm(check_code.to_digit())
+            .expect("Bob should be able to confirm the secure channel");
+
+        let message = bob.receive_json().await.unwrap();
+        assert_let!(QrAuthMessage::LoginProtocols { protocols, .. } = message);
+        assert_eq!(protocols, vec![LoginProtocolType::DeviceAuthorizationGrant]);
+
+        finish_login(bob, device_id, authorization_grant()).await
+    }
+
+    /// The part of the new device side of the QR login dance that takes place
+    /// once the secure channel is established.
+    async fn finish_login(
+        mut bob: EstablishedSecureChannel,
+        device_id: Curve25519PublicKey,
+        grant: AuthorizationGrant,
+    ) -> Option<SecretsBundle> {
+        let message = QrAuthMessage::authorization_grant_login_protocol(grant, device_id);
+        bob.send_json(message).await.unwrap();
+
+        match bob.receive_json().await.unwrap() {
+            QrAuthMessage::LoginProtocolAccepted => (),
+            QrAuthMessage::LoginFailure { reason, .. } => {
+                assert_matches!(
+                    reason,
+                    LoginFailureReason::DeviceAlreadyExists
+                        | LoginFailureReason::UnexpectedMessageReceived
+                );
+                return None;
+            }
+            message => panic!("Bob received an unexpected message {message:?}"),
+        }
+
+        bob.send_json(QrAuthMessage::LoginSuccess).await.unwrap();
+
+        let message = bob.receive_json().await.unwrap();
+        assert_let!(QrAuthMessage::LoginSecrets(bundle) = message);
+
+        Some(bundle)
+    }
+
+    async fn grant_login(
+        reuse_existing_device_id: bool,
+        lagging_requests: u64,
+        authorization_grant: AuthorizationGrant,
+    ) -> (Result<(), QRCodeGrantLoginError>, Option<SecretsBundle>) {
+        let server = MockServer::start().await;
+        let _rendezvous_server = MockedRendezvousServer::new(&server, "abcdEFG12345").await;
+
+        let existing_device_id = vodozemac::olm::Account::new().identity_keys().curve25519;
+        let new_device_id = if reuse_existing_device_id {
+            existing_device_id
+        } else {
+            vodozemac::olm::Account::new().identity_keys().curve25519
+        };
+
+        mock_devices(&server, existing_device_id, new_device_id, lagging_requests).await;
+        mock_auth_issuer(&server).await;
+
+        let alice = existing_device(&server).await;
+
+        let oidc = alice.oidc();
+        let grant = oidc.grant_login_with_generated_qr_code();
+        let mut updates = grant.subscribe_to_progress();
+
+        let progress = async move {
+            let (sender, receiver) = oneshot::channel();
+            let mut sender = Some(sender);
+            let mut receiver = Some(receiver);
+            let mut authorization_grant = Some(authorization_grant);
+            let mut bob_task = None;
+
+            while let Some(update) = updates.next().await {
+                match update {
+                    GrantLoginProgress::QrCodeReady { qr_code_data } => {
+                        let sender = sender.take().expect("The QR code should be ready only once");
+                        let grant = authorization_grant.take().unwrap();
+                        bob_task =
+                            Some(tokio::spawn(login(qr_code_data, sender, new_device_id, grant)));
+                    }
+                    GrantLoginProgress::WaitingForCheckCode { check_code_sender } => {
+                        let check_code = receiver
+                            .take()
+                            .expect("The check code should be requested only once")
+                            .await
+                            .expect("Bob should have sent us the check code");
+
+                        check_code_sender
+                            .send(check_code.to_digit())
+                            .expect("Alice should be able to input the check code");
+                    }
+                    GrantLoginProgress::WaitingForAuth { verification_uri } => {
+                        assert_eq!(
+                            verification_uri.as_str(),
+                            "https://id.matrix.org/link?code=N32YVC"
+                        );
+                    }
+                    GrantLoginProgress::Done => break,
+                    _ => (),
+                }
+            }
+
+            bob_task.expect("Bob should have been started").await.unwrap()
+        };
+
+        join!(grant.into_future(), progress)
+    }
+
+    #[async_test]
+    async fn test_grant_login_with_generated_qr_code() {
+        let (result, bundle) = grant_login(false, 0, authorization_grant()).await;
+
+        result.expect("Alice should be able to grant the login");
+        let bundle = bundle.expect("Bob should have received the secrets bundle");
+        assert!(bundle.backup.is_none(), "Alice didn't have a backup key to send");
+    }
+
+    #[async_test]
+    async fn test_grant_login_waits_for_the_new_device() {
+        // The homeserver doesn't list the new device right after it logged in.
+        let (result, bundle) = grant_login(false, 2, authorization_grant()).await;
+
+        result.expect("Alice should be able to grant the login");
+        bundle.expect("Bob should have received the secrets bundle");
+    }
+
+    #[async_test]
+    async fn test_grant_login_with_existing_device_id() {
+        let (result, bundle) = grant_login(true, 0, authorization_grant()).await;
+
+        assert_matches!(result, Err(QRCodeGrantLoginError::DeviceIdAlreadyInUse(_)));
+        assert!(bundle.is_none(), "Bob should not have received the secrets bundle");
+    }
+
+    #[async_test]
+    async fn test_grant_login_with_untrusted_verification_uri() {
+        let (result, bundle) = grant_login(false, 0, untrusted_authorization_grant()).await;
+
+        assert_matches!(result, Err(QRCodeGrantLoginError::UntrustedVerificationUri));
+        assert!(bundle.is_none(), "Bob should not have received the secrets bundle");
+    }
+
+    #[test]
+    fn test_verification_uri_must_share_the_origin_of_the_issuer() {
+        let issuer = Url::parse("https://id.matrix.org/").unwrap();
+
+        assert_eq!(
+            verification_uri(&authorization_grant(), &issuer).unwrap().as_str(),
+            "https://id.matrix.org/link?code=N32YVC"
+        );
+        assert!(verification_uri(&untrusted_authorization_grant(), &issuer).is_none());
+
+        let other_issuer = Url::parse("https://auth.example.org/").unwrap();
+        assert!(verification_uri(&authorization_grant(), &other_issuer).is_none());
+    }
+
+    #[async_test]
+    async fn test_grant_login_with_scanned_qr_code() {
+        let server = MockServer::start().await;
+        let rendezvous_server = MockedRendezvousServer::new(&server, "abcdEFG12345").await;
+
+        let existing_device_id = vodozemac::olm::Account::new().identity_keys().curve25519;
+        let new_device_id = vodozemac::olm::Account::new().identity_keys().curve25519;
+        mock_devices(&server, existing_device_id, new_device_id, 0).await;
+        mock_auth_issuer(&server).await;
+
+        let alice = existing_device(&server).await;
+
+        // Bob is the new device and is displaying the QR code this time.
+        let http_client = HttpClient::new(reqwest::Client::new(), Default::default());
+        let channel = SecureChannel::login(http_client, &rendezvous_server.homeserver_url)
+            .await
+            .expect("Bob should be able to create a secure channel");
+        let qr_code_data = channel.qr_code_data().clone();
+
+        let (sender, receiver) = oneshot::channel();
+        let bob_task = tokio::spawn(login_with_displayed_qr_code(channel, receiver, new_device_id));
+
+        let oidc = alice.oidc();
+        let grant = oidc.grant_login_with_scanned_qr_code(&qr_code_data);
+        let mut updates = grant.subscribe_to_progress();
+
+        let progress = async move {
+            let mut sender = Some(sender);
+
+            while let Some(update) = updates.next().await {
+                match update {
+                    GrantLoginProgress::EstablishingSecureChannel { check_code } => {
+                        sender
+                            .take()
+                            .expect("The check code should be shown only once")
+                            .send(check_code)
+                            .expect("Alice should be able to show the check code to Bob");
+                    }
+                    GrantLoginProgress::Done => break,
+                    _ => (),
+                }
+            }
+        };
+
+        let (result, ()) = join!(grant.into_future(), progress);
+
+        result.expect("Alice should be able to grant the login");
+        let bundle = bob_task.await.unwrap().expect("Bob should have received the secrets bundle");
+        assert!(bundle.backup.is_none(), "Alice didn't have a backup key to send");
+    }
+
+    #[async_test]
+    async fn test_grant_login_with_scanned_reciprocate_qr_code() {
+        let server = MockServer::start().await;
+        let rendezvous_server = MockedRendezvousServer::new(&server, "abcdEFG12345").await;
+
+        let alice = existing_device(&server).await;
+
+        // This QR code is displayed by another existing device, it can't be used to
+        // log a new device in.
+        let http_client = HttpClient::new(reqwest::Client::new(), Default::default());
+        let channel = SecureChannel::new(http_client, &rendezvous_server.homeserver_url)
+            .await
+            .expect("We should be able to create a secure channel");
+
+        let result = alice.oidc().grant_login_with_scanned_qr_code(channel.qr_code_data()).await;
+
+        assert_matches!(
+            result,
+            Err(QRCodeGrantLoginError::SecureChannel(SecureChannelError::InvalidIntent))
+        );
+    }
+
+    #[async_test]
+    async fn test_check_code_can_only_be_sent_once() {
+        let (sender, receiver) = CheckCodeSender::new();
+
+        sender.send(42).unwrap();
+        assert_matches!(sender.send(42), Err(CheckCodeSenderError::AlreadySent));
+        assert_eq!(receiver.await.unwrap(), 42);
+    }
+}
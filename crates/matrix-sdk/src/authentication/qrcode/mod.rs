@@ -20,14 +20,19 @@
 //!
 //! This currently only implements the case where the new device is scanning the
 //! QR code. To log in using a QR code, please take a look at the
-//! [`Oidc::login_with_qr_code()`] method
+//! [`Oidc::login_with_qr_code()`] method.
+//!
+//! To grant the login to a new device from an already logged in device, please
+//! take a look at the [`Oidc::grant_login_with_generated_qr_code()`] and
+//! [`Oidc::grant_login_with_scanned_qr_code()`] methods.
 
 use as_variant::as_variant;
-use matrix_sdk_base::crypto::SecretImportError;
+use matrix_sdk_base::crypto::{store::SecretsBundleExportError, SecretImportError};
 pub use openidconnect::{
     core::CoreErrorResponseType, ConfigurationError, DeviceCodeErrorResponseType, DiscoveryError,
     HttpClientError, RequestTokenError, StandardErrorResponse,
 };
+use ruma::OwnedDeviceId;
 use thiserror::Error;
 use url::Url;
 pub use vodozemac::ecies::{Error as EciesError, MessageDecodeError};
@@ -36,6 +41,7 @@ pub use vodozemac::ecies::{Error as EciesError, MessageDecodeError};
 use crate::oidc::Oidc;
 use crate::{oidc::CrossProcessRefreshLockError, HttpError};
 
+mod grant;
 mod login;
 mod messages;
 mod oidc_client;
@@ -47,6 +53,10 @@ pub use matrix_sdk_base::crypto::types::qr_login::{
 };
 
 pub use self::{
+    grant::{
+        CheckCodeSender, CheckCodeSenderError, GrantLoginProgress, GrantLoginWithGeneratedQrCode,
+        GrantLoginWithScannedQrCode,
+    },
     login::{LoginProgress, LoginWithQrCode},
     messages::{LoginFailureReason, LoginProtocolType, QrAuthMessage},
 };
@@ -106,6 +116,80 @@ pub enum QRCodeLoginError {
     SecretImport(#[from] SecretImportError),
 }
 
+/// The error type for failures while trying to grant the login to a new device
+/// using a QR code.
+#[derive(Debug, Error)]
+#[cfg_attr(feature = "uniffi", derive(uniffi::Error), uniffi(flat_error))]
+pub enum QRCodeGrantLoginError {
+    /// The new device has signaled to us that the login has failed.
+    #[error("The login failed, reason: {reason}")]
+    LoginFailure {
+        /// The reason, as signaled by the new device, for the login failure.
+        reason: LoginFailureReason,
+        /// The homeserver that we attempted to log in to.
+        homeserver: Option<Url>,
+    },
+
+    /// The OIDC provider has declined to log the new device in, i.e. because
+    /// the user declined the login.
+    #[error("The login of the new device was declined")]
+    LoginDeclined,
+
+    /// An unexpected message was received from the new device.
+    #[error("We have received an unexpected message, expected: {expected}, got {received:?}")]
+    UnexpectedMessage {
+        /// The message we expected.
+        expected: &'static str,
+        /// The message we received instead.
+        received: QrAuthMessage,
+    },
+
+    /// The new device picked a login protocol we don't support.
+    #[error("The new device picked an unsupported login protocol: {0}")]
+    UnsupportedProtocol(LoginProtocolType),
+
+    /// The device ID the new device wants to use is already used by one of our
+    /// existing devices.
+    #[error("The device ID {0} is already in use")]
+    DeviceIdAlreadyInUse(OwnedDeviceId),
+
+    /// The new device claimed to be logged in, but the homeserver doesn't know
+    /// about it.
+    #[error("The new device {0} could not be found on the homeserver")]
+    DeviceNotFound(OwnedDeviceId),
+
+    /// The check code was never provided using the [`CheckCodeSender`].
+    #[error("The check code was never provided")]
+    CheckCodeCancelled,
+
+    /// The client isn't logged in, only a logged in device can grant the login
+    /// to a new device.
+    #[error("The client needs to be logged in to grant a login")]
+    NotLoggedIn,
+
+    /// The new device sent us a verification URL which doesn't point to the
+    /// OIDC provider of our homeserver.
+    #[error("The verification URL doesn't point to the OIDC provider of the homeserver")]
+    UntrustedVerificationUri,
+
+    /// The OIDC issuer URL advertised by the homeserver failed to be parsed.
+    #[error(transparent)]
+    InvalidIssuerUrl(#[from] url::ParseError),
+
+    /// An error happened while exchanging messages with the new device.
+    #[error(transparent)]
+    SecureChannel(#[from] SecureChannelError),
+
+    /// An error happened while we were talking to the homeserver.
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
+    /// Our secrets could not be exported, the new device wouldn't be able to
+    /// become a fully E2EE enabled device without them.
+    #[error(transparent)]
+    SecretsBundleExport(#[from] SecretsBundleExportError),
+}
+
 /// Error type describing failures in the interaction between the device
 /// attempting to log in and the OIDC provider.
 #[derive(Debug, Error)]
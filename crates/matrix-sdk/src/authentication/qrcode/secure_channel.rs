@@ -12,16 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(test)]
-use matrix_sdk_base::crypto::types::qr_login::QrCodeModeData;
-use matrix_sdk_base::crypto::types::qr_login::{QrCodeData, QrCodeMode};
+use matrix_sdk_base::crypto::types::qr_login::{QrCodeData, QrCodeMode, QrCodeModeData};
 use serde::{de::DeserializeOwned, Serialize};
 use tracing::{instrument, trace};
-#[cfg(test)]
 use url::Url;
-use vodozemac::ecies::{CheckCode, Ecies, EstablishedEcies, Message, OutboundCreationResult};
-#[cfg(test)]
-use vodozemac::ecies::{InboundCreationResult, InitialMessage};
+use vodozemac::ecies::{
+    CheckCode, Ecies, EstablishedEcies, InboundCreationResult, InitialMessage, Message,
+    OutboundCreationResult,
+};
 
 use super::{
     rendezvous_channel::{InboundChannelCreationResult, RendezvousChannel},
@@ -32,27 +30,45 @@ use crate::{config::RequestConfig, http_client::HttpClient};
 const LOGIN_INITIATE_MESSAGE: &str = "MATRIX_QR_CODE_LOGIN_INITIATE";
 const LOGIN_OK_MESSAGE: &str = "MATRIX_QR_CODE_LOGIN_OK";
 
-#[cfg(test)]
 pub(super) struct SecureChannel {
     channel: RendezvousChannel,
     qr_code_data: QrCodeData,
     ecies: Ecies,
 }
 
-// This is only used in tests because we're only supporting the new device part
-// of the QR login flow. It will be needed once we support reciprocating of the
-// login.
-//
-// It's still very much useful to have this, as we're testing the whole flow by
-// mocking the reciprocation.
-#[cfg(test)]
 impl SecureChannel {
-    pub(super) async fn new(http_client: HttpClient, homeserver_url: &Url) -> Result<Self, Error> {
+    /// Create a new secure channel for an existing device which wants to
+    /// reciprocate the login of a new device.
+    ///
+    /// The returned [`QrCodeData`] needs to be displayed as a QR code so the
+    /// new device can scan it and connect to the secure channel.
+    pub(super) async fn reciprocate(
+        http_client: HttpClient,
+        homeserver_url: &Url,
+        server_name: String,
+    ) -> Result<Self, Error> {
+        let mode_data = QrCodeModeData::Reciprocate { server_name };
+        Self::with_mode_data(http_client, homeserver_url, mode_data).await
+    }
+
+    /// Create a new secure channel for a new device which wants to log in, this
+    /// is used in tests to mock the new device when the existing device scans
+    /// the QR code.
+    #[cfg(test)]
+    pub(super) async fn login(
+        http_client: HttpClient,
+        homeserver_url: &Url,
+    ) -> Result<Self, Error> {
+        Self::with_mode_data(http_client, homeserver_url, QrCodeModeData::Login).await
+    }
+
+    async fn with_mode_data(
+        http_client: HttpClient,
+        homeserver_url: &Url,
+        mode_data: QrCodeModeData,
+    ) -> Result<Self, Error> {
         let channel = RendezvousChannel::create_outbound(http_client, homeserver_url).await?;
         let rendezvous_url = channel.rendezvous_url().to_owned();
-        // We're a bit abusing the QR code data here, since we're passing the homeserver
-        // URL, but for our tests this is fine.
-        let mode_data = QrCodeModeData::Reciprocate { server_name: homeserver_url.to_string() };
 
         let ecies = Ecies::new();
         let public_key = ecies.public_key();
@@ -62,6 +78,13 @@ impl SecureChannel {
         Ok(Self { channel, qr_code_data, ecies })
     }
 
+    #[cfg(test)]
+    pub(super) async fn new(http_client: HttpClient, homeserver_url: &Url) -> Result<Self, Error> {
+        // We're a bit abusing the QR code data here, since we're passing the homeserver
+        // URL, but for our tests this is fine.
+        Self::reciprocate(http_client, homeserver_url, homeserver_url.to_string()).await
+    }
+
     pub(super) fn qr_code_data(&self) -> &QrCodeData {
         &self.qr_code_data
     }
@@ -98,13 +121,10 @@ impl SecureChannel {
 }
 
 /// An SecureChannel that is yet to be confirmed as with the [`CheckCode`].
-/// Same deal as for the [`SecureChannel`], not used for now.
-#[cfg(test)]
 pub(super) struct AlmostEstablishedSecureChannel {
     secure_channel: EstablishedSecureChannel,
 }
 
-#[cfg(test)]
 impl AlmostEstablishedSecureChannel {
     /// Confirm that the secure channel is indeed secure.
     ///
@@ -29,7 +29,9 @@ use tracing::{debug, field::debug, instrument, Span};
 
 use super::{Client, ClientInner};
 #[cfg(feature = "e2e-encryption")]
-use crate::crypto::{CollectStrategy, TrustRequirement};
+use crate::crypto::{
+    metrics::CryptoMetrics, CollectStrategy, RoomKeyRotationPolicy, TrustRequirement,
+};
 #[cfg(feature = "e2e-encryption")]
 use crate::encryption::EncryptionSettings;
 #[cfg(not(target_arch = "wasm32"))]
@@ -101,6 +103,10 @@ pub struct ClientBuilder {
     room_key_recipient_strategy: CollectStrategy,
     #[cfg(feature = "e2e-encryption")]
     decryption_trust_requirement: TrustRequirement,
+    #[cfg(feature = "e2e-encryption")]
+    crypto_metrics: Option<Arc<dyn CryptoMetrics>>,
+    #[cfg(feature = "e2e-encryption")]
+    room_key_rotation_policy: Option<Arc<dyn RoomKeyRotationPolicy>>,
 }
 
 impl ClientBuilder {
@@ -122,6 +128,10 @@ impl ClientBuilder {
             room_key_recipient_strategy: Default::default(),
             #[cfg(feature = "e2e-encryption")]
             decryption_trust_requirement: TrustRequirement::Untrusted,
+            #[cfg(feature = "e2e-encryption")]
+            crypto_metrics: None,
+            #[cfg(feature = "e2e-encryption")]
+            room_key_rotation_policy: None,
         }
     }
 
@@ -421,6 +431,30 @@ impl ClientBuilder {
         self
     }
 
+    /// Set the sink for the metrics collected about the cryptographic
+    /// operations of the client, e.g. decryption failures or the latency of
+    /// key queries.
+    ///
+    /// See the [`metrics`] module for the list of collected metrics.
+    ///
+    /// [`metrics`]: crate::crypto::metrics
+    #[cfg(feature = "e2e-encryption")]
+    pub fn with_crypto_metrics(mut self, metrics: Arc<dyn CryptoMetrics>) -> Self {
+        self.crypto_metrics = Some(metrics);
+        self
+    }
+
+    /// Set a custom policy deciding if the room key of a room should be
+    /// rotated before it's shared with new recipients, e.g. when a user from
+    /// another homeserver joins the room.
+    ///
+    /// See [`RoomKeyRotationPolicy`] for more details.
+    #[cfg(feature = "e2e-encryption")]
+    pub fn with_room_key_rotation_policy(mut self, policy: Arc<dyn RoomKeyRotationPolicy>) -> Self {
+        self.room_key_rotation_policy = Some(policy);
+        self
+    }
+
     /// Create a [`Client`] with the options set on this builder.
     ///
     /// # Errors
@@ -460,6 +494,8 @@ impl ClientBuilder {
             {
                 client.room_key_recipient_strategy = self.room_key_recipient_strategy;
                 client.decryption_trust_requirement = self.decryption_trust_requirement;
+                client.crypto_metrics = self.crypto_metrics;
+                client.room_key_rotation_policy = self.room_key_rotation_policy;
             }
             client
         };
@@ -1064,6 +1100,29 @@ pub(crate) mod tests {
         );
     }
 
+    #[async_test]
+    #[cfg(feature = "e2e-encryption")]
+    async fn test_set_up_crypto_metrics() {
+        use crate::crypto::metrics::{CryptoCounter, CryptoHistogram};
+
+        #[derive(Debug)]
+        struct NoopMetrics;
+
+        impl CryptoMetrics for NoopMetrics {
+            fn increment_counter(&self, _: CryptoCounter, _: u64) {}
+            fn observe_histogram(&self, _: CryptoHistogram, _: f64) {}
+        }
+
+        let homeserver = make_mock_homeserver().await;
+
+        let builder = ClientBuilder::new()
+            .server_name_or_homeserver_url(homeserver.uri())
+            .with_crypto_metrics(Arc::new(NoopMetrics));
+
+        let client = builder.build().await.unwrap();
+        assert!(client.base_client().crypto_metrics.is_some());
+    }
+
     /* Helper functions */
 
     async fn make_mock_homeserver() -> MockServer {
@@ -65,6 +65,7 @@ use ruma::{
         MatrixVersion, OutgoingRequest,
     },
     assign,
+    presence::PresenceState,
     push::Ruleset,
     time::Instant,
     DeviceId, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedRoomOrAliasId, OwnedServerName,
@@ -92,6 +93,7 @@ use crate::{
     http_client::HttpClient,
     matrix_auth::MatrixAuth,
     notification_settings::NotificationSettings,
+    presence::{Presence, PresenceObservables},
     room_preview::RoomPreview,
     send_queue::SendQueueData,
     sync::{RoomUpdate, SyncResponse},
@@ -319,6 +321,11 @@ pub(crate) struct ClientInner {
     ///
     /// [`SendQueue`]: crate::send_queue::SendQueue
     pub(crate) send_queue_data: Arc<SendQueueData>,
+
+    /// The observables for the presence of other users.
+    ///
+    /// See [`Presence::subscribe`].
+    pub(crate) presence_observables: PresenceObservables,
 }
 
 impl ClientInner {
@@ -362,6 +369,7 @@ impl ClientInner {
             sync_beat: event_listener::Event::new(),
             event_cache,
             send_queue_data: send_queue,
+            presence_observables: Default::default(),
             #[cfg(feature = "e2e-encryption")]
             e2ee: EncryptionData::new(encryption_settings),
             #[cfg(feature = "e2e-encryption")]
@@ -620,6 +628,22 @@ impl Client {
         Pusher::new(self.clone())
     }
 
+    /// Get the presence manager of the client.
+    pub fn presence(&self) -> Presence {
+        Presence::new(self.clone())
+    }
+
+    /// Set the presence of the current user.
+    ///
+    /// This is a shortcut for [`Presence::set`].
+    pub async fn set_presence(
+        &self,
+        presence: PresenceState,
+        status_msg: Option<String>,
+    ) -> Result<()> {
+        self.presence().set(presence, status_msg).await
+    }
+
     /// Access the OpenID Connect API of the client.
     #[cfg(feature = "experimental-oidc")]
     pub fn oidc(&self) -> Oidc {
@@ -28,11 +28,13 @@ use matrix_sdk_base::crypto::{
     backups::MegolmV1BackupKey, store::BackupDecryptionKey, types::RoomKeyBackupInfo,
     KeysBackupRequest, OlmMachine, RoomKeyImportResult,
 };
+use matrix_sdk_common::executor::spawn;
 use ruma::{
     api::client::{
         backup::{
-            add_backup_keys, create_backup_version, get_backup_keys, get_backup_keys_for_room,
-            get_backup_keys_for_session, get_latest_backup_info, RoomKeyBackup,
+            add_backup_keys, create_backup_version, get_backup_info, get_backup_keys,
+            get_backup_keys_for_room, get_backup_keys_for_session, get_latest_backup_info,
+            RoomKeyBackup,
         },
         error::ErrorKind,
     },
@@ -49,9 +51,9 @@ use tracing::{error, info, instrument, trace, warn, Span};
 pub mod futures;
 pub(crate) mod types;
 
-pub use types::{BackupState, UploadState};
+pub use types::{BackupState, DownloadProgress, UploadState};
 
-use self::futures::WaitForSteadyState;
+use self::{futures::WaitForSteadyState, types::StoredDownloadProgress};
 use crate::{
     crypto::olm::ExportedRoomKey, encryption::BackupDownloadStrategy, Client, Error, Room,
 };
@@ -135,6 +137,7 @@ impl Backups {
             let request = create_backup_version::v3::Request::new(algorithm);
             let response = self.client.send(request, Default::default()).await?;
             let version = response.version;
+            self.set_backup_exists_on_server(true);
 
             // Reset any state we might have had before the new backup was created.
             // TODO: This should remove the old stored key and version.
@@ -388,6 +391,202 @@ impl Backups {
         Ok(self.get_current_version().await?.is_some())
     }
 
+    /// Does a backup exist on the server, using the cached answer if we have
+    /// one?
+    ///
+    /// This behaves like [`Backups::exists_on_server()`], but only contacts the
+    /// homeserver if we haven't learned about the existence of a backup yet,
+    /// either from a previous request or from creating or deleting a backup
+    /// ourselves.
+    pub async fn fast_exists_on_server(&self) -> Result<bool, Error> {
+        match self.cached_exists_on_server() {
+            Some(exists) => Ok(exists),
+            None => self.exists_on_server().await,
+        }
+    }
+
+    /// Does a backup exist on the server, as far as we know?
+    ///
+    /// This never contacts the homeserver; `None` means we haven't found out
+    /// yet.
+    pub(crate) fn cached_exists_on_server(&self) -> Option<bool> {
+        *self.client.inner.e2ee.backup_state.backup_exists_on_server.read().unwrap()
+    }
+
+    /// Download all the room keys from the server-side key backup, and import
+    /// them one room at a time.
+    ///
+    /// The room keys are downloaded for each of the rooms the [`Client`]
+    /// knows about, since a backup can't be listed without downloading all of
+    /// its room keys. The room keys of each room are then decrypted and
+    /// imported into the crypto store before moving on to the next room, so
+    /// only the room keys of one room are held in memory at a time.
+    ///
+    /// The room keys of the rooms the [`Client`] doesn't know about, e.g. rooms
+    /// we left, or any room before the first sync, aren't downloaded: their
+    /// number is reported in [`DownloadProgress::keys_not_covered`]. The
+    /// download is then only complete once they have been covered as well:
+    /// calling this method again, or restoring the [`Client`] with the
+    /// [`BackupDownloadStrategy::OneShot`] strategy, downloads the room keys
+    /// of the rooms we learned about in the meantime.
+    ///
+    /// The progress of the download can be observed with the
+    /// [`Backups::download_progress_stream()`] method. It is also persisted
+    /// after each room: if the download gets interrupted, e.g. because the
+    /// application has been killed, calling this method again downloads the
+    /// rooms that haven't been imported yet, one room at a time, as long as
+    /// the backup version didn't change. With the
+    /// [`BackupDownloadStrategy::OneShot`] strategy, an interrupted download
+    /// is resumed automatically when the [`Client`] is restored. Once the
+    /// download is complete, a new call downloads all the room keys again.
+    ///
+    /// Returns an [`Error::BackupNotEnabled`] error if we don't have a backup
+    /// recovery key and backup version.
+    #[instrument(skip(self))]
+    pub async fn download_all_room_keys(&self) -> Result<(), Error> {
+        let (decryption_key, version, mut progress) = {
+            let olm_machine = self.client.olm_machine().await;
+            let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
+
+            let backup_keys = olm_machine.store().load_backup_keys().await?;
+
+            let (Some(decryption_key), Some(version)) =
+                (backup_keys.decryption_key, backup_keys.backup_version)
+            else {
+                return Err(Error::BackupNotEnabled);
+            };
+
+            let progress = self
+                .load_download_progress(olm_machine, &version)
+                .await?
+                .filter(|progress| !progress.complete)
+                .unwrap_or_else(|| StoredDownloadProgress::new(version.clone()));
+
+            (decryption_key, version, progress)
+        };
+
+        // There's no way to list the rooms of a backup without downloading all of its
+        // room keys, so use the rooms we know about instead. Rooms may have been joined
+        // since the download was interrupted, so refresh the list when resuming.
+        let rooms: BTreeSet<_> = progress
+            .rooms
+            .iter()
+            .flatten()
+            .cloned()
+            .chain(self.client.rooms().iter().map(|room| room.room_id().to_owned()))
+            .collect();
+        progress.rooms = Some(rooms.clone());
+
+        let remaining_rooms: Vec<OwnedRoomId> = rooms
+            .iter()
+            .filter(|room_id| !progress.done_rooms.contains(*room_id))
+            .cloned()
+            .collect();
+
+        let total_rooms = rooms.len();
+        let download_progress = &self.client.inner.e2ee.backup_state.download_progress;
+        download_progress.set(DownloadProgress {
+            rooms_done: total_rooms - remaining_rooms.len(),
+            total_rooms,
+            keys_imported: progress.keys_imported,
+            keys_not_covered: 0,
+        });
+
+        info!(
+            remaining_rooms = remaining_rooms.len(),
+            total_rooms, "Downloading all the room keys from the backup"
+        );
+
+        for room_id in remaining_rooms {
+            let request =
+                get_backup_keys_for_room::v3::Request::new(version.clone(), room_id.clone());
+
+            let room_keys = match self.client.send(request, Default::default()).await {
+                Ok(response) => RoomKeyBackup::new(response.sessions),
+                // Some homeservers reply with a not found error if the backup doesn't contain
+                // any room keys for this room.
+                Err(e) if e.client_api_error_kind() == Some(&ErrorKind::NotFound) => {
+                    RoomKeyBackup::new(BTreeMap::new())
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let room_key_count = room_keys.sessions.len();
+
+            // Transform the room keys to the standard format (map of room ID -> room key).
+            let response =
+                get_backup_keys::v3::Response::new(BTreeMap::from([(room_id.clone(), room_keys)]));
+
+            // Don't hold on to the `OlmMachine` during the network requests, only while
+            // we're importing the room keys and persisting our progress.
+            let result = self
+                .import_downloaded_room_keys(response, decryption_key.clone(), &version)
+                .await?;
+
+            progress.done_rooms.insert(room_id);
+            progress.keys_downloaded += room_key_count;
+            progress.keys_imported += result.imported_count;
+
+            let olm_machine = self.client.olm_machine().await;
+            let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
+            self.save_download_progress(olm_machine, &progress).await?;
+
+            download_progress.set(DownloadProgress {
+                rooms_done: progress.done_rooms.len(),
+                total_rooms,
+                keys_imported: progress.keys_imported,
+                keys_not_covered: 0,
+            });
+        }
+
+        // The backup may contain room keys for rooms we don't know about, e.g. rooms we
+        // left, or any room before the first sync. Downloading the whole backup to get
+        // them would hold all of its room keys in memory at once, so only report them.
+        let request = get_backup_info::v3::Request::new(version.clone());
+        let backup_key_count = self.client.send(request, Default::default()).await?.count;
+        let keys_not_covered = usize::try_from(u64::from(backup_key_count))
+            .unwrap_or(usize::MAX)
+            .saturating_sub(progress.keys_downloaded);
+
+        download_progress.set(DownloadProgress {
+            rooms_done: progress.done_rooms.len(),
+            total_rooms,
+            keys_imported: progress.keys_imported,
+            keys_not_covered,
+        });
+
+        if keys_not_covered > 0 {
+            info!(
+                keys_not_covered,
+                "The backup contains room keys for rooms we don't know about, they will be \
+                 downloaded once we know about their room"
+            );
+        } else {
+            // Remember that the download is complete, so it isn't resumed when the client
+            // is restored, and the next call starts from scratch.
+            progress.complete = true;
+        }
+
+        let olm_machine = self.client.olm_machine().await;
+        let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
+        self.save_download_progress(olm_machine, &progress).await?;
+
+        info!(
+            keys_imported = progress.keys_imported,
+            "Done downloading the room keys from the backup"
+        );
+
+        Ok(())
+    }
+
+    /// Subscribe to the progress of the download of all the room keys from the
+    /// backup, started with [`Backups::download_all_room_keys()`].
+    pub fn download_progress_stream(
+        &self,
+    ) -> impl Stream<Item = Result<DownloadProgress, BroadcastStreamRecvError>> {
+        self.client.inner.e2ee.backup_state.download_progress.subscribe()
+    }
+
     /// Subscribe to a stream that notifies when a room key for the specified
     /// room is downloaded from the key backup.
     pub fn room_keys_for_room_stream(
@@ -419,27 +618,21 @@ impl Backups {
     /// Download all room keys for a certain room from the server-side key
     /// backup.
     pub async fn download_room_keys_for_room(&self, room_id: &RoomId) -> Result<(), Error> {
-        let olm_machine = self.client.olm_machine().await;
-        let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
-
-        let backup_keys = olm_machine.store().load_backup_keys().await?;
+        let Some((decryption_key, version)) = self.load_decryption_key_and_version().await? else {
+            return Ok(());
+        };
 
-        if let Some(decryption_key) = backup_keys.decryption_key {
-            if let Some(version) = backup_keys.backup_version {
-                let request =
-                    get_backup_keys_for_room::v3::Request::new(version.clone(), room_id.to_owned());
-                let response = self.client.send(request, Default::default()).await?;
+        let request =
+            get_backup_keys_for_room::v3::Request::new(version.clone(), room_id.to_owned());
+        let response = self.client.send(request, Default::default()).await?;
 
-                // Transform response to standard format (map of room ID -> room key).
-                let response = get_backup_keys::v3::Response::new(BTreeMap::from([(
-                    room_id.to_owned(),
-                    RoomKeyBackup::new(response.sessions),
-                )]));
+        // Transform response to standard format (map of room ID -> room key).
+        let response = get_backup_keys::v3::Response::new(BTreeMap::from([(
+            room_id.to_owned(),
+            RoomKeyBackup::new(response.sessions),
+        )]));
 
-                self.handle_downloaded_room_keys(response, decryption_key, &version, olm_machine)
-                    .await?;
-            }
-        }
+        self.import_downloaded_room_keys(response, decryption_key, &version).await?;
 
         Ok(())
     }
@@ -455,39 +648,73 @@ impl Backups {
         room_id: &RoomId,
         session_id: &str,
     ) -> Result<bool, Error> {
+        let Some((decryption_key, version)) = self.load_decryption_key_and_version().await? else {
+            return Ok(false);
+        };
+
+        let request = get_backup_keys_for_session::v3::Request::new(
+            version.clone(),
+            room_id.to_owned(),
+            session_id.to_owned(),
+        );
+        let response = self.client.send(request, Default::default()).await?;
+
+        // Transform response to standard format (map of room ID -> room key).
+        let response = get_backup_keys::v3::Response::new(BTreeMap::from([(
+            room_id.to_owned(),
+            RoomKeyBackup::new(BTreeMap::from([(session_id.to_owned(), response.key_data)])),
+        )]));
+
+        self.import_downloaded_room_keys(response, decryption_key, &version).await?;
+
+        Ok(true)
+    }
+
+    /// Load the backup decryption key and the backup version from the crypto
+    /// store, if we have both.
+    async fn load_decryption_key_and_version(
+        &self,
+    ) -> Result<Option<(BackupDecryptionKey, String)>, Error> {
         let olm_machine = self.client.olm_machine().await;
         let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
 
         let backup_keys = olm_machine.store().load_backup_keys().await?;
 
-        if let Some(decryption_key) = backup_keys.decryption_key {
-            if let Some(version) = backup_keys.backup_version {
-                let request = get_backup_keys_for_session::v3::Request::new(
-                    version.clone(),
-                    room_id.to_owned(),
-                    session_id.to_owned(),
-                );
-                let response = self.client.send(request, Default::default()).await?;
+        Ok(backup_keys.decryption_key.zip(backup_keys.backup_version))
+    }
 
-                // Transform response to standard format (map of room ID -> room key).
-                let response = get_backup_keys::v3::Response::new(BTreeMap::from([(
-                    room_id.to_owned(),
-                    RoomKeyBackup::new(BTreeMap::from([(
-                        session_id.to_owned(),
-                        response.key_data,
-                    )])),
-                )]));
+    /// Import the room keys we downloaded from the backup, and try again to
+    /// decrypt the encrypted state events they're for.
+    ///
+    /// The `OlmMachine` is only held while the room keys are imported.
+    async fn import_downloaded_room_keys(
+        &self,
+        backed_up_keys: get_backup_keys::v3::Response,
+        backup_decryption_key: BackupDecryptionKey,
+        backup_version: &str,
+    ) -> Result<RoomKeyImportResult, Error> {
+        let result = {
+            let olm_machine = self.client.olm_machine().await;
+            let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
 
-                self.handle_downloaded_room_keys(response, decryption_key, &version, olm_machine)
-                    .await?;
+            self.handle_downloaded_room_keys(
+                backed_up_keys,
+                backup_decryption_key,
+                backup_version,
+                olm_machine,
+            )
+            .await?
+        };
 
-                Ok(true)
-            } else {
-                Ok(false)
-            }
-        } else {
-            Ok(false)
-        }
+        self.client.encryption().decrypt_state_events_with_imported_room_keys(&result).await;
+
+        Ok(result)
+    }
+
+    /// Remember whether a backup exists on the server.
+    fn set_backup_exists_on_server(&self, exists: bool) {
+        *self.client.inner.e2ee.backup_state.backup_exists_on_server.write().unwrap() =
+            Some(exists);
     }
 
     /// Set the state of the backup.
@@ -523,7 +750,7 @@ impl Backups {
         backup_decryption_key: BackupDecryptionKey,
         backup_version: &str,
         olm_machine: &OlmMachine,
-    ) -> Result<(), Error> {
+    ) -> Result<RoomKeyImportResult, Error> {
         let mut decrypted_room_keys: Vec<_> = Vec::new();
 
         for (room_id, room_keys) in backed_up_keys.rooms {
@@ -566,24 +793,79 @@ impl Backups {
 
         // Since we can't use the usual room keys stream from the `OlmMachine`
         // we're going to send things out in our own custom broadcaster.
-        let _ = self.client.inner.e2ee.backup_state.room_keys_broadcaster.send(result);
+        let _ = self.client.inner.e2ee.backup_state.room_keys_broadcaster.send(result.clone());
 
-        Ok(())
+        Ok(result)
     }
 
-    /// Download all room keys from the backup on the homeserver.
-    async fn download_all_room_keys(
+    /// Load the persisted progress of a previous call to
+    /// [`Backups::download_all_room_keys()`], if it was downloading from the
+    /// given backup version.
+    async fn load_download_progress(
         &self,
-        decryption_key: BackupDecryptionKey,
-        version: String,
+        olm_machine: &OlmMachine,
+        version: &str,
+    ) -> Result<Option<StoredDownloadProgress>, Error> {
+        let stored =
+            olm_machine.store().get_custom_value(StoredDownloadProgress::STORE_KEY).await?;
+
+        let progress = stored
+            .and_then(|bytes| match serde_json::from_slice::<StoredDownloadProgress>(&bytes) {
+                Ok(progress) => Some(progress),
+                Err(e) => {
+                    warn!("Couldn't deserialize the stored backup download progress: {e:?}");
+                    None
+                }
+            })
+            // A download from another backup version can't be resumed, start again.
+            .filter(|progress| progress.version == version);
+
+        Ok(progress)
+    }
+
+    /// Persist the progress of [`Backups::download_all_room_keys()`].
+    async fn save_download_progress(
+        &self,
+        olm_machine: &OlmMachine,
+        progress: &StoredDownloadProgress,
     ) -> Result<(), Error> {
-        let request = get_backup_keys::v3::Request::new(version.clone());
-        let response = self.client.send(request, Default::default()).await?;
+        olm_machine
+            .store()
+            .set_custom_value(StoredDownloadProgress::STORE_KEY, serde_json::to_vec(progress)?)
+            .await?;
 
-        let olm_machine = self.client.olm_machine().await;
-        let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
+        Ok(())
+    }
+
+    /// Resume a download of all the room keys from the backup that got
+    /// interrupted, e.g. because the application was killed, if the client is
+    /// configured to download all the room keys.
+    async fn maybe_resume_download(&self, olm_machine: &OlmMachine) -> Result<(), Error> {
+        if self.client.inner.e2ee.encryption_settings.backup_download_strategy
+            != BackupDownloadStrategy::OneShot
+        {
+            return Ok(());
+        }
+
+        let Some(version) = olm_machine.store().load_backup_keys().await?.backup_version else {
+            return Ok(());
+        };
+
+        let interrupted = self
+            .load_download_progress(olm_machine, &version)
+            .await?
+            .is_some_and(|progress| !progress.complete);
 
-        self.handle_downloaded_room_keys(response, decryption_key, &version, olm_machine).await?;
+        if interrupted {
+            info!("Resuming the interrupted download of the room keys from the backup");
+
+            let backups = self.clone();
+            spawn(async move {
+                if let Err(e) = backups.download_all_room_keys().await {
+                    warn!("Couldn't resume the download of the room keys from the backup: {e:?}");
+                }
+            });
+        }
 
         Ok(())
     }
@@ -601,10 +883,14 @@ impl Backups {
         let request = get_latest_backup_info::v3::Request::new();
 
         match self.client.send(request, None).await {
-            Ok(r) => Ok(Some(r)),
+            Ok(r) => {
+                self.set_backup_exists_on_server(true);
+                Ok(Some(r))
+            }
             Err(e) => {
                 if let Some(kind) = e.client_api_error_kind() {
                     if kind == &ErrorKind::NotFound {
+                        self.set_backup_exists_on_server(false);
                         Ok(None)
                     } else {
                         Err(e.into())
@@ -619,6 +905,10 @@ impl Backups {
     async fn delete_backup_from_server(&self, version: String) -> Result<(), Error> {
         let request = ruma::api::client::backup::delete_backup_version::v3::Request::new(version);
 
+        // Another backup version might still exist on the server, so forget what we
+        // knew and let the next check ask the server again.
+        *self.client.inner.e2ee.backup_state.backup_exists_on_server.write().unwrap() = None;
+
         match self.client.send(request, Default::default()).await {
             Ok(_) => Ok(()),
             Err(e) => {
@@ -816,22 +1106,16 @@ impl Backups {
                     .await?;
                 backup_machine.enable_backup_v1(backup_key).await?;
 
-                // If the user has set up the client to download any room keys, do so now. This
-                // is not really useful in a real scenario since the API to
-                // download room keys is not paginated.
-                //
-                // You need to download all room keys at once, parse a potentially huge JSON
-                // response and decrypt all the room keys found in the backup.
-                //
-                // This doesn't work for any sizeable account.
+                // If the user has set up the client to download any room keys, do so now. The
+                // room keys are downloaded one room at a time, since the API to download room
+                // keys isn't paginated and downloading the whole backup at once doesn't work
+                // for any sizeable account.
                 if self.client.inner.e2ee.encryption_settings.backup_download_strategy
                     == BackupDownloadStrategy::OneShot
                 {
                     self.set_state(BackupState::Downloading);
 
-                    if let Err(e) =
-                        self.download_all_room_keys(decryption_key, current_version.version).await
-                    {
+                    if let Err(e) = self.download_all_room_keys().await {
                         warn!("Couldn't automatically download all room keys from backup: {e:?}");
                     }
                 }
@@ -921,7 +1205,9 @@ impl Backups {
 
         // Let us first check if we have a stored backup recovery key and a backup
         // version.
-        if !self.resume_backup_from_stored_backup_key(olm_machine).await? {
+        if self.resume_backup_from_stored_backup_key(olm_machine).await? {
+            self.maybe_resume_download(olm_machine).await?;
+        } else {
             // We didn't manage to enable backups from a stored backup recovery key, let us
             // check our secret inbox. Perhaps we can find a valid key there.
             self.maybe_resume_from_secret_inbox(olm_machine).await?;
@@ -1005,6 +1291,7 @@ mod test {
     use std::time::Duration;
 
     use matrix_sdk_test::async_test;
+    use ruma::owned_room_id;
     use serde_json::json;
     use wiremock::{
         matchers::{header, method, path},
@@ -1012,7 +1299,10 @@ mod test {
     };
 
     use super::*;
-    use crate::test_utils::logged_in_client;
+    use crate::{
+        encryption::EncryptionSettings,
+        test_utils::{logged_in_client, set_client_session, test_client_builder},
+    };
 
     fn room_key() -> ExportedRoomKey {
         let json = json!({
@@ -1206,6 +1496,34 @@ mod test {
         server.verify().await;
     }
 
+    #[async_test]
+    async fn test_fast_exists_on_server_caches_the_answer() {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+
+        Mock::given(method("GET"))
+            .and(path("_matrix/client/r0/room_keys/version"))
+            .and(header("authorization", "Bearer 1234"))
+            .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+                "errcode": "M_NOT_FOUND",
+                "error": "No current backup version"
+            })))
+            // Only the first call should hit the server.
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let backups = client.encryption().backups();
+
+        assert!(!backups.fast_exists_on_server().await.unwrap());
+        assert!(
+            !backups.fast_exists_on_server().await.unwrap(),
+            "The second call should use the cached answer"
+        );
+
+        server.verify().await;
+    }
+
     #[async_test]
     async fn test_waiting_for_steady_state_resets_the_delay() {
         let server = MockServer::start().await;
@@ -1280,4 +1598,124 @@ mod test {
 
         server.verify().await;
     }
+
+    #[async_test]
+    async fn test_interrupted_download_is_resumed_on_startup() {
+        let server = MockServer::start().await;
+        let client = test_client_builder(Some(server.uri()))
+            .with_encryption_settings(EncryptionSettings {
+                backup_download_strategy: BackupDownloadStrategy::OneShot,
+                ..Default::default()
+            })
+            .build()
+            .await
+            .unwrap();
+        set_client_session(&client).await;
+
+        let done_room_id = owned_room_id!("!done:localhost");
+        let remaining_room_id = owned_room_id!("!remaining:localhost");
+        let backups = client.encryption().backups();
+
+        {
+            let olm_machine = client.olm_machine_for_testing().await;
+            let olm_machine = olm_machine.as_ref().unwrap();
+
+            olm_machine
+                .backup_machine()
+                .save_decryption_key(
+                    Some(BackupDecryptionKey::new().unwrap()),
+                    Some("1".to_owned()),
+                )
+                .await
+                .unwrap();
+
+            // A previous download got interrupted after the first room.
+            let progress = StoredDownloadProgress {
+                version: "1".to_owned(),
+                rooms: Some(BTreeSet::from([done_room_id.clone(), remaining_room_id.clone()])),
+                done_rooms: BTreeSet::from([done_room_id.clone()]),
+                keys_downloaded: 3,
+                keys_imported: 3,
+                complete: false,
+            };
+            backups.save_download_progress(olm_machine, &progress).await.unwrap();
+        }
+
+        // Only the room keys of the remaining room are downloaded.
+        Mock::given(method("GET"))
+            .and(path("_matrix/client/r0/room_keys/keys/!remaining:localhost"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "sessions": {} })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        // The rooms cover the whole backup, so it isn't downloaded at once.
+        Mock::given(method("GET"))
+            .and(path("_matrix/client/r0/room_keys/version/1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "algorithm": "m.megolm_backup.v1.curve25519-aes-sha2",
+                "auth_data": {
+                    "public_key": "hdx5rSn94rBuvJI5cwnhKAVmFyZgfJjk7vwEBD6mIHc",
+                    "signatures": {}
+                },
+                "count": 3,
+                "etag": "1",
+                "version": "1"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("_matrix/client/r0/room_keys/keys"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let mut progress_stream = backups.download_progress_stream();
+
+        backups.maybe_resume_backups().await.unwrap();
+        assert_eq!(backups.state(), BackupState::Enabled);
+
+        while let Some(progress) = progress_stream.next().await {
+            let progress = progress.unwrap();
+
+            if progress.rooms_done == progress.total_rooms {
+                assert_eq!(
+                    progress,
+                    DownloadProgress {
+                        rooms_done: 2,
+                        total_rooms: 2,
+                        keys_imported: 3,
+                        keys_not_covered: 0,
+                    }
+                );
+                break;
+            }
+        }
+
+        // Wait for the progress to be persisted after the last room.
+        loop {
+            let olm_machine = client.olm_machine_for_testing().await;
+            let progress = backups
+                .load_download_progress(olm_machine.as_ref().unwrap(), "1")
+                .await
+                .unwrap()
+                .unwrap();
+
+            if progress.complete {
+                assert_eq!(
+                    progress.done_rooms,
+                    BTreeSet::from([done_room_id.clone(), remaining_room_id.clone()])
+                );
+                break;
+            }
+
+            drop(olm_machine);
+            tokio::task::yield_now().await;
+        }
+
+        server.verify().await;
+    }
 }
@@ -13,11 +13,14 @@
 // limitations under the License.
 
 use std::{
+    collections::BTreeSet,
     sync::{Arc, RwLock},
     time::Duration,
 };
 
 use matrix_sdk_base::crypto::{store::RoomKeyCounts, RoomKeyImportResult};
+use ruma::OwnedRoomId;
+use serde::{Deserialize, Serialize};
 use tokio::sync::broadcast;
 
 use crate::utils::ChannelObservable;
@@ -53,6 +56,10 @@ pub(crate) struct BackupClientState {
     pub(crate) upload_progress: ChannelObservable<UploadState>,
     pub(super) global_state: ChannelObservable<BackupState>,
     pub(super) room_keys_broadcaster: broadcast::Sender<RoomKeyImportResult>,
+    /// Whether a backup exists on the server, as last seen by us. `None` if
+    /// we haven't asked the server yet.
+    pub(super) backup_exists_on_server: RwLock<Option<bool>>,
+    pub(super) download_progress: ChannelObservable<DownloadProgress>,
 }
 
 const DEFAULT_BACKUP_UPLOAD_DELAY: Duration = Duration::from_millis(100);
@@ -64,6 +71,8 @@ impl Default for BackupClientState {
             upload_progress: ChannelObservable::new(UploadState::Idle),
             global_state: Default::default(),
             room_keys_broadcaster: broadcast::Sender::new(100),
+            backup_exists_on_server: RwLock::new(None),
+            download_progress: Default::default(),
         }
     }
 }
@@ -119,3 +128,62 @@ pub enum BackupState {
     /// has been disabled, we're going to transition into the `Unknown` state.
     Disabling,
 }
+
+/// The progress of a download of all the room keys from the backup.
+///
+/// You can listen to the progress using the
+/// [`Backups::download_progress_stream()`] method.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DownloadProgress {
+    /// The number of rooms whose room keys have been downloaded and imported.
+    pub rooms_done: usize,
+    /// The total number of rooms we're downloading room keys for.
+    pub total_rooms: usize,
+    /// The number of room keys that have been imported so far.
+    pub keys_imported: usize,
+    /// The number of room keys of the backup which weren't downloaded,
+    /// because they're for rooms we don't know about.
+    ///
+    /// This is only known once the room keys of all the known rooms have been
+    /// downloaded, and is `0` until then.
+    pub keys_not_covered: usize,
+}
+
+/// The progress of a download of all the room keys from the backup, as
+/// persisted in the crypto store to be able to resume the download after a
+/// restart.
+#[derive(Debug, Deserialize, Serialize)]
+pub(super) struct StoredDownloadProgress {
+    /// The backup version the room keys are downloaded from.
+    pub version: String,
+    /// The rooms of the backup, once they have been listed.
+    #[serde(default)]
+    pub rooms: Option<BTreeSet<OwnedRoomId>>,
+    /// The rooms whose room keys have already been downloaded and imported.
+    pub done_rooms: BTreeSet<OwnedRoomId>,
+    /// The number of room keys that have been downloaded from the rooms
+    /// in `done_rooms` so far, imported or not.
+    #[serde(default)]
+    pub keys_downloaded: usize,
+    /// The number of room keys that have been imported so far.
+    pub keys_imported: usize,
+    /// Whether the room keys of the whole backup have been downloaded.
+    #[serde(default)]
+    pub complete: bool,
+}
+
+impl StoredDownloadProgress {
+    /// The key under which the progress is stored in the crypto store.
+    pub const STORE_KEY: &'static str = "backup_download_progress";
+
+    pub fn new(version: String) -> Self {
+        Self {
+            version,
+            rooms: None,
+            done_rooms: Default::default(),
+            keys_downloaded: 0,
+            keys_imported: 0,
+            complete: false,
+        }
+    }
+}
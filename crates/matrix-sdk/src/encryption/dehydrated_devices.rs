@@ -0,0 +1,449 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dehydrated devices support
+//!
+//! This module implements support for dehydrated devices, as defined in
+//! [MSC3814]. A dehydrated device is a device that lives on the homeserver
+//! and receives the room keys that are sent to us while none of our real
+//! devices is logged in. Once we log in again, the dehydrated device is
+//! rehydrated, and the room keys it received are imported into our new device.
+//!
+//! The private parts of the dehydrated device are encrypted with a pickle key,
+//! which is stored in secret storage, so that a new device can get it when
+//! it recovers its secrets.
+//!
+//! The dehydrated device is rehydrated automatically once a newly logged in
+//! device imports the pickle key from secret storage, using
+//! [`SecretStore::import_secrets()`], it is then replaced with a new one.
+//!
+//! **Note**: If you are using the [`Recovery`] subsystem, dehydrated devices
+//! are created when recovery is enabled, and rehydrated when recovering, you
+//! shouldn't need to use this module directly.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # use matrix_sdk::{Client, encryption::dehydrated_devices::DEFAULT_ROTATION_PERIOD};
+//! # use url::Url;
+//! # async {
+//! # let homeserver = Url::parse("http://example.com")?;
+//! # let client = Client::new(homeserver).await?;
+//! let secret_store = client
+//!     .encryption()
+//!     .secret_storage()
+//!     .open_secret_store("It's a secret to everybody")
+//!     .await?;
+//!
+//! let dehydrated_devices = client.encryption().dehydrated_devices();
+//!
+//! // Create a dehydrated device, its pickle key is stored in secret storage.
+//! dehydrated_devices.create(&secret_store).await?;
+//!
+//! // Replace the dehydrated device every week.
+//! dehydrated_devices.start_rotation(DEFAULT_ROTATION_PERIOD);
+//! # anyhow::Ok(()) };
+//! ```
+//!
+//! [MSC3814]: https://github.com/matrix-org/matrix-spec-proposals/pull/3814
+//! [`Recovery`]: crate::encryption::recovery::Recovery
+//! [`SecretStore::import_secrets()`]: crate::encryption::secret_storage::SecretStore::import_secrets
+
+use std::time::Duration;
+
+use matrix_sdk_base::crypto::{dehydrated_devices::DehydrationError, OlmError};
+use matrix_sdk_common::executor::spawn;
+use rand::RngCore;
+use ruma::{
+    api::client::{
+        dehydrated_device::{
+            delete_dehydrated_device, get_dehydrated_device, get_events, put_dehydrated_device,
+        },
+        error::ErrorKind,
+    },
+    assign,
+};
+use thiserror::Error;
+use tracing::{debug, info, instrument, warn};
+use vodozemac::{base64_decode, base64_encode};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{
+    client::WeakClient,
+    encryption::secret_storage::{SecretStorageError, SecretStore},
+    Client, HttpError,
+};
+
+/// The name of the secret containing the pickle key of the dehydrated device.
+pub(crate) const PICKLE_KEY_SECRET_NAME: &str = "org.matrix.msc3814";
+
+/// The key under which the pickle key is cached in the crypto store.
+const PICKLE_KEY_STORE_KEY: &str = "dehydrated_device_pickle_key";
+
+/// The display name of the dehydrated devices we create.
+const DEHYDRATED_DEVICE_DISPLAY_NAME: &str = "Dehydrated device";
+
+/// The default period after which the dehydrated device is replaced by a new
+/// one, see [`DehydratedDevices::start_rotation()`].
+pub const DEFAULT_ROTATION_PERIOD: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Error type for the [`DehydratedDevices`] subsystem.
+#[derive(Debug, Error)]
+pub enum DehydratedDevicesError {
+    /// We don't have the pickle key of the dehydrated device, it needs to be
+    /// imported from secret storage first.
+    #[error("The pickle key of the dehydrated device is missing")]
+    MissingPickleKey,
+
+    /// The pickle key found in secret storage is not a valid key.
+    #[error("The pickle key of the dehydrated device is malformed")]
+    InvalidPickleKey,
+
+    /// The dehydrated device could not be created or rehydrated.
+    #[error(transparent)]
+    Dehydration(#[from] DehydrationError),
+
+    /// The to-device events of the rehydrated device could not be processed.
+    #[error(transparent)]
+    Olm(#[from] OlmError),
+
+    /// Error in the secret storage subsystem.
+    #[error(transparent)]
+    SecretStorage(#[from] SecretStorageError),
+
+    /// Error doing an HTTP request.
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
+    /// A typical SDK error.
+    #[error(transparent)]
+    Sdk(#[from] crate::Error),
+}
+
+/// Result type alias for the [`DehydratedDevices`] subsystem.
+pub type Result<T, E = DehydratedDevicesError> = std::result::Result<T, E>;
+
+/// The dehydrated devices manager for the [`Client`].
+#[derive(Debug, Clone)]
+pub struct DehydratedDevices {
+    pub(super) client: Client,
+}
+
+impl DehydratedDevices {
+    /// Create a new dehydrated device and upload it to the homeserver,
+    /// replacing the existing one, if any.
+    ///
+    /// The private parts of the device are encrypted with the pickle key we
+    /// know about, or the one found in the given secret store. If there is
+    /// none, a new pickle key is created and stored in the secret store.
+    #[instrument(skip_all)]
+    pub async fn create(&self, secret_store: &SecretStore) -> Result<()> {
+        let (pickle_key, is_new) = if let Some(pickle_key) = self.pickle_key().await? {
+            (pickle_key, false)
+        } else if let Some(pickle_key) = self.import_pickle_key(secret_store).await? {
+            (pickle_key, false)
+        } else {
+            info!("Creating a new pickle key for the dehydrated device");
+
+            let mut pickle_key = Zeroizing::new([0u8; 32]);
+            rand::thread_rng().fill_bytes(pickle_key.as_mut_slice());
+
+            (pickle_key, true)
+        };
+
+        // Store a new pickle key before uploading the device: a device whose pickle key
+        // got lost could never be rehydrated.
+        if is_new {
+            let mut encoded = base64_encode(pickle_key.as_slice());
+            let ret = secret_store.put_secret(PICKLE_KEY_SECRET_NAME, &encoded).await;
+            encoded.zeroize();
+            ret?;
+
+            self.save_pickle_key(&pickle_key).await?;
+        }
+
+        self.upload(&pickle_key).await
+    }
+
+    /// Replace the dehydrated device with a new one, using the pickle key we
+    /// know about.
+    ///
+    /// Fails with [`DehydratedDevicesError::MissingPickleKey`] if we don't know
+    /// the pickle key, see [`DehydratedDevices::create()`].
+    #[instrument(skip_all)]
+    pub async fn rotate(&self) -> Result<()> {
+        let pickle_key =
+            self.pickle_key().await?.ok_or(DehydratedDevicesError::MissingPickleKey)?;
+        self.upload(&pickle_key).await
+    }
+
+    /// Rehydrate the dehydrated device that lives on the homeserver, and
+    /// import the room keys it received.
+    ///
+    /// The to-device events of the dehydrated device are downloaded until
+    /// there are none left. The dehydrated device should then be replaced
+    /// with a new one, using [`DehydratedDevices::create()`] or
+    /// [`DehydratedDevices::rotate()`].
+    ///
+    /// Returns the number of imported room keys, which is zero if there is no
+    /// dehydrated device on the homeserver.
+    ///
+    /// Fails with [`DehydratedDevicesError::MissingPickleKey`] if we don't know
+    /// the pickle key, it should be imported from secret storage first, for
+    /// instance with [`SecretStore::import_secrets()`].
+    #[instrument(skip_all)]
+    pub async fn rehydrate(&self) -> Result<usize> {
+        let pickle_key =
+            self.pickle_key().await?.ok_or(DehydratedDevicesError::MissingPickleKey)?;
+
+        let request = get_dehydrated_device::unstable::Request::new();
+        let response = match self.client.send(request, None).await {
+            Ok(response) => response,
+            Err(e) if e.client_api_error_kind() == Some(&ErrorKind::NotFound) => {
+                info!("There is no dehydrated device to rehydrate");
+                return Ok(0);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let device_id = response.device_id;
+
+        let rehydrated = {
+            let olm_machine = self.client.olm_machine().await;
+            let olm_machine = olm_machine.as_ref().ok_or(crate::Error::NoOlmMachine)?;
+
+            olm_machine
+                .dehydrated_devices()
+                .rehydrate(&pickle_key, &device_id, response.device_data)
+                .await?
+        };
+
+        info!(?device_id, "Rehydrated the dehydrated device, fetching its to-device events");
+
+        let mut next_batch = None;
+        let mut imported_room_keys = 0;
+
+        loop {
+            let request = assign!(get_events::unstable::Request::new(device_id.clone()), {
+                next_batch: next_batch.take(),
+            });
+            let response = self.client.send(request, None).await?;
+
+            if response.events.is_empty() {
+                break;
+            }
+
+            imported_room_keys += rehydrated.receive_events(response.events).await?.len();
+
+            // Without a batch token we can't ask for the next events, we would only get the
+            // same ones again.
+            let Some(batch) = response.next_batch else {
+                break;
+            };
+            next_batch = Some(batch);
+        }
+
+        info!(imported_room_keys, "Done importing the room keys of the dehydrated device");
+
+        Ok(imported_room_keys)
+    }
+
+    /// Rehydrate the dehydrated device, if we know its pickle key, and replace
+    /// it with a new one which is then replaced periodically.
+    ///
+    /// The dehydrated device is only replaced if it has been rehydrated, or if
+    /// there is none: replacing it otherwise would lose the room keys it
+    /// received. Failures are only logged, as the homeserver might not support
+    /// dehydrated devices.
+    pub(crate) async fn rehydrate_and_rotate(&self) {
+        match self.has_pickle_key().await {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                warn!("Could not check for the pickle key of the dehydrated device: {e:?}");
+                return;
+            }
+        }
+
+        if let Err(e) = self.rehydrate().await {
+            warn!("Could not rehydrate the dehydrated device, not replacing it: {e:?}");
+            return;
+        }
+
+        match self.rotate().await {
+            Ok(()) => self.start_rotation(DEFAULT_ROTATION_PERIOD),
+            Err(e) => warn!("Could not replace the dehydrated device: {e:?}"),
+        }
+    }
+
+    /// Delete the dehydrated device from the homeserver, and forget about its
+    /// pickle key.
+    #[instrument(skip_all)]
+    pub async fn delete(&self) -> Result<()> {
+        self.stop_rotation();
+
+        let request = delete_dehydrated_device::unstable::Request::new();
+        match self.client.send(request, None).await {
+            Ok(_) => {}
+            Err(e) if e.client_api_error_kind() == Some(&ErrorKind::NotFound) => {
+                debug!("There is no dehydrated device to delete");
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let olm_machine = self.client.olm_machine().await;
+        let olm_machine = olm_machine.as_ref().ok_or(crate::Error::NoOlmMachine)?;
+        olm_machine
+            .store()
+            .remove_custom_value(PICKLE_KEY_STORE_KEY)
+            .await
+            .map_err(crate::Error::from)?;
+
+        Ok(())
+    }
+
+    /// Replace the dehydrated device with a new one periodically, in a
+    /// background task.
+    ///
+    /// Replacing the dehydrated device regularly limits the number of to-device
+    /// events that have to be downloaded when it is rehydrated. The task
+    /// replaces a previously started one, and is stopped when the
+    /// dehydrated device is deleted.
+    pub fn start_rotation(&self, period: Duration) {
+        let weak_client = WeakClient::from_client(&self.client);
+
+        let join_handle = spawn(async move {
+            loop {
+                #[cfg(not(target_arch = "wasm32"))]
+                tokio::time::sleep(period).await;
+                #[cfg(target_arch = "wasm32")]
+                gloo_timers::future::sleep(period).await;
+
+                let Some(client) = weak_client.get() else {
+                    debug!("Client got dropped, stopping the dehydrated device rotation");
+                    break;
+                };
+
+                if let Err(e) = client.encryption().dehydrated_devices().rotate().await {
+                    warn!("Couldn't rotate the dehydrated device: {e:?}");
+                }
+            }
+        });
+
+        let mut tasks = self.client.inner.e2ee.tasks.lock().unwrap();
+        if let Some(previous) = tasks.rotate_dehydrated_device.replace(join_handle) {
+            #[cfg(not(target_arch = "wasm32"))]
+            previous.abort();
+            #[cfg(target_arch = "wasm32")]
+            drop(previous);
+        }
+    }
+
+    /// Stop replacing the dehydrated device periodically, see
+    /// [`DehydratedDevices::start_rotation()`].
+    pub fn stop_rotation(&self) {
+        let mut tasks = self.client.inner.e2ee.tasks.lock().unwrap();
+        if let Some(_join_handle) = tasks.rotate_dehydrated_device.take() {
+            #[cfg(not(target_arch = "wasm32"))]
+            _join_handle.abort();
+        }
+    }
+
+    /// Do we know the pickle key of the dehydrated device?
+    pub async fn has_pickle_key(&self) -> Result<bool> {
+        Ok(self.pickle_key().await?.is_some())
+    }
+
+    /// Fetch the pickle key of the dehydrated device from the given secret
+    /// store, and remember it locally.
+    pub(crate) async fn import_pickle_key(
+        &self,
+        secret_store: &SecretStore,
+    ) -> Result<Option<Zeroizing<[u8; 32]>>> {
+        let Some(mut secret) = secret_store.get_secret(PICKLE_KEY_SECRET_NAME).await? else {
+            debug!("No pickle key for the dehydrated device found in secret storage");
+            return Ok(None);
+        };
+
+        let decoded = base64_decode(&secret).map(Zeroizing::new);
+        secret.zeroize();
+
+        let decoded = decoded.map_err(|_| DehydratedDevicesError::InvalidPickleKey)?;
+        let pickle_key = Zeroizing::new(
+            <[u8; 32]>::try_from(decoded.as_slice())
+                .map_err(|_| DehydratedDevicesError::InvalidPickleKey)?,
+        );
+
+        self.save_pickle_key(&pickle_key).await?;
+
+        Ok(Some(pickle_key))
+    }
+
+    /// Store the pickle key of the dehydrated device we know about in the given
+    /// secret store, if any.
+    pub(crate) async fn export_pickle_key(&self, secret_store: &SecretStore) -> Result<()> {
+        if let Some(pickle_key) = self.pickle_key().await? {
+            let mut encoded = base64_encode(pickle_key.as_slice());
+            let ret = secret_store.put_secret(PICKLE_KEY_SECRET_NAME, &encoded).await;
+            encoded.zeroize();
+            ret?;
+        }
+
+        Ok(())
+    }
+
+    async fn pickle_key(&self) -> Result<Option<Zeroizing<[u8; 32]>>> {
+        let olm_machine = self.client.olm_machine().await;
+        let olm_machine = olm_machine.as_ref().ok_or(crate::Error::NoOlmMachine)?;
+
+        let pickle_key = olm_machine
+            .store()
+            .get_custom_value(PICKLE_KEY_STORE_KEY)
+            .await
+            .map_err(crate::Error::from)?
+            .map(Zeroizing::new);
+
+        Ok(pickle_key.and_then(|key| <[u8; 32]>::try_from(key.as_slice()).ok().map(Zeroizing::new)))
+    }
+
+    async fn save_pickle_key(&self, pickle_key: &[u8; 32]) -> Result<()> {
+        let olm_machine = self.client.olm_machine().await;
+        let olm_machine = olm_machine.as_ref().ok_or(crate::Error::NoOlmMachine)?;
+
+        olm_machine
+            .store()
+            .set_custom_value(PICKLE_KEY_STORE_KEY, pickle_key.to_vec())
+            .await
+            .map_err(crate::Error::from)?;
+
+        Ok(())
+    }
+
+    async fn upload(&self, pickle_key: &[u8; 32]) -> Result<()> {
+        let request = {
+            let olm_machine = self.client.olm_machine().await;
+            let olm_machine = olm_machine.as_ref().ok_or(crate::Error::NoOlmMachine)?;
+
+            let device = olm_machine.dehydrated_devices().create().await?;
+            device.keys_for_upload(DEHYDRATED_DEVICE_DISPLAY_NAME.to_owned(), pickle_key).await?
+        };
+
+        let response: put_dehydrated_device::unstable::Response =
+            self.client.send(request, None).await?;
+
+        info!(device_id = ?response.device_id, "Uploaded a new dehydrated device");
+
+        Ok(())
+    }
+}
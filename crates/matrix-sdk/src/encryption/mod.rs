@@ -30,8 +30,16 @@ use futures_util::{
     future::try_join,
     stream::{self, StreamExt},
 };
-use matrix_sdk_base::crypto::{
-    CrossSigningBootstrapRequests, OlmMachine, OutgoingRequest, RoomMessageRequest, ToDeviceRequest,
+use matrix_sdk_base::{
+    crypto::{
+        store::RoomKeyInfo,
+        types::events::{
+            room_key_bundle::RoomKeyBundleContent, room_key_withheld::WithheldCode, EventType,
+        },
+        CrossSigningBootstrapRequests, CrossSigningKeyRotationRequests, OlmMachine,
+        OutgoingRequest, RoomMessageRequest, ToDeviceRequest,
+    },
+    RoomState,
 };
 use matrix_sdk_common::executor::spawn;
 use ruma::{
@@ -47,7 +55,11 @@ use ruma::{
         uiaa::{AuthData, UiaaInfo},
     },
     assign,
-    events::room::{MediaSource, ThumbnailInfo},
+    events::{
+        room::{MediaSource, ThumbnailInfo},
+        AnyToDeviceEvent, AnyToDeviceEventContent,
+    },
+    serde::Raw,
     DeviceId, OwnedDeviceId, OwnedUserId, TransactionId, UserId,
 };
 use serde::Deserialize;
@@ -58,6 +70,7 @@ use vodozemac::Curve25519PublicKey;
 
 use self::{
     backups::{types::BackupClientState, Backups},
+    dehydrated_devices::DehydratedDevices,
     futures::UploadEncryptedFile,
     identities::{Device, DeviceUpdates, IdentityUpdates, UserDevices, UserIdentity},
     recovery::{Recovery, RecoveryState},
@@ -74,6 +87,7 @@ use crate::{
 };
 
 pub mod backups;
+pub mod dehydrated_devices;
 pub mod futures;
 pub mod identities;
 pub mod recovery;
@@ -86,9 +100,9 @@ pub use matrix_sdk_base::crypto::{
         SessionCreationError as MegolmSessionCreationError,
         SessionExportError as OlmSessionExportError,
     },
-    vodozemac, CrossSigningStatus, CryptoStoreError, DecryptorError, EventError, KeyExportError,
-    LocalTrust, MediaEncryptionInfo, MegolmError, OlmError, RoomKeyImportResult, SecretImportError,
-    SessionCreationError, SignatureError, VERSION,
+    vodozemac, CrossSigningKeyRotation, CrossSigningStatus, CryptoStoreError, DecryptorError,
+    EventError, KeyExportError, LocalTrust, MediaEncryptionInfo, MegolmError, OlmError,
+    RoomKeyImportResult, SecretImportError, SessionCreationError, SignatureError, VERSION,
 };
 
 pub use crate::error::RoomKeyImportError;
@@ -166,6 +180,26 @@ pub struct EncryptionSettings {
 
     /// Automatically create a backup version if no backup exists.
     pub auto_enable_backups: bool,
+
+    /// Share the keys of a room's history with the users we invite to it, if
+    /// the history of the room is visible to invited users.
+    ///
+    /// See [`Room::share_history`](crate::Room::share_history) for more
+    /// details.
+    pub share_history_on_invite: bool,
+
+    /// Encrypt the state events sent in encrypted rooms, as described in
+    /// [MSC3414].
+    ///
+    /// State events the server needs to read to authorize events, like the
+    /// room membership or the power levels, are still sent in the clear. The
+    /// room name and topic, for example, are encrypted.
+    ///
+    /// Other clients need to support encrypted state events to read the
+    /// encrypted state, so this is disabled by default.
+    ///
+    /// [MSC3414]: https://github.com/matrix-org/matrix-spec-proposals/pull/3414
+    pub encrypt_state_events: bool,
 }
 
 /// Settings for end-to-end encryption features.
@@ -678,6 +712,62 @@ impl Client {
 
         Ok(())
     }
+
+    /// Import the room key bundles, received in the given to-device events,
+    /// of rooms we already joined while waiting for them.
+    pub(crate) async fn import_pending_room_key_bundles(
+        &self,
+        to_device_events: &[Raw<AnyToDeviceEvent>],
+    ) {
+        #[derive(Deserialize)]
+        struct BundleEvent {
+            sender: OwnedUserId,
+            content: RoomKeyBundleContent,
+        }
+
+        for raw_event in to_device_events {
+            if raw_event.get_field::<String>("type").ok().flatten().as_deref()
+                != Some(RoomKeyBundleContent::EVENT_TYPE)
+            {
+                continue;
+            }
+
+            let Ok(event) = raw_event.deserialize_as::<BundleEvent>() else {
+                continue;
+            };
+
+            let room_id = &event.content.room_id;
+
+            let pending_inviter = {
+                let olm_machine = self.olm_machine().await;
+                let Some(olm_machine) = olm_machine.as_ref() else {
+                    return;
+                };
+
+                match olm_machine.store().get_pending_room_key_bundle_inviter(room_id).await {
+                    Ok(inviter) => inviter,
+                    Err(e) => {
+                        warn!(?room_id, "Couldn't load the pending room key bundle: {e}");
+                        continue;
+                    }
+                }
+            };
+
+            if pending_inviter.as_ref() != Some(&event.sender) {
+                continue;
+            }
+
+            let Some(room) =
+                self.get_room(room_id).filter(|room| room.state() == RoomState::Joined)
+            else {
+                continue;
+            };
+
+            if let Err(e) = room.import_shared_history(&event.sender).await {
+                warn!(?room_id, "Couldn't import the room key bundle: {e}");
+            }
+        }
+    }
 }
 
 #[cfg(any(feature = "testing", test))]
@@ -904,6 +994,101 @@ impl Encryption {
         Ok(UserDevices { inner: devices, client: self.client.clone() })
     }
 
+    /// Encrypt and send a custom to-device event to the given devices.
+    ///
+    /// The event is encrypted for every device using Olm, Olm sessions are
+    /// established with the devices we don't have one with yet. The event is
+    /// then sent as a `m.room.encrypted` to-device event.
+    ///
+    /// The devices which receive the event are selected the same way as the
+    /// recipients of a room key, using the [`CollectStrategy`] configured with
+    /// [`ClientBuilder::with_room_key_recipient_strategy()`]. Blacklisted
+    /// devices and our own device are never sent the event.
+    ///
+    /// Returns the devices the event couldn't be sent to, with the
+    /// [`WithheldCode`] explaining why, e.g. because they weren't trusted
+    /// enough or because no Olm session could be established with them.
+    ///
+    /// # Arguments
+    ///
+    /// * `devices` - The devices the event should be sent to.
+    ///
+    /// * `event_type` - The type of the event which will be encrypted.
+    ///
+    /// * `content` - The content of the event which will be encrypted.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, ruma::{device_id, serde::Raw, user_id}};
+    /// # use serde_json::json;
+    /// # use url::Url;
+    /// # async {
+    /// # let alice = user_id!("@alice:example.org");
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// let devices = client.encryption().get_user_devices(alice).await?;
+    /// let devices: Vec<_> = devices.devices().collect();
+    ///
+    /// let content = Raw::new(&json!({ "secret": "It's a secret to everybody" }))?.cast();
+    ///
+    /// let failures = client
+    ///     .encryption()
+    ///     .encrypt_and_send_to_device(devices.iter().collect(), "org.example.secret", content)
+    ///     .await?;
+    ///
+    /// for ((user_id, device_id), code) in failures {
+    ///     println!("Couldn't send the secret to {user_id}'s device {device_id}: {code}");
+    /// }
+    /// # anyhow::Ok(()) };
+    /// ```
+    ///
+    /// [`CollectStrategy`]: matrix_sdk_base::crypto::CollectStrategy
+    /// [`ClientBuilder::with_room_key_recipient_strategy()`]: crate::ClientBuilder::with_room_key_recipient_strategy
+    #[instrument(skip_all, fields(event_type))]
+    pub async fn encrypt_and_send_to_device(
+        &self,
+        devices: Vec<&Device>,
+        event_type: &str,
+        content: Raw<AnyToDeviceEventContent>,
+    ) -> Result<BTreeMap<(OwnedUserId, OwnedDeviceId), WithheldCode>> {
+        let content = content.deserialize_as::<serde_json::Value>()?;
+
+        // Establish Olm sessions with the devices we don't have one with yet.
+        let user_ids: HashSet<&UserId> = devices.iter().map(|d| d.user_id()).collect();
+        self.client.claim_one_time_keys(user_ids.into_iter()).await?;
+
+        let (request, withheld_devices) = {
+            let olm = self.client.olm_machine().await;
+            let olm = olm.as_ref().ok_or(Error::NoOlmMachine)?;
+            let devices = devices.into_iter().map(|d| (**d).clone()).collect();
+            let strategy = &self.client.base_client().room_key_recipient_strategy;
+
+            olm.encrypt_content_for_devices(devices, event_type, &content, strategy).await?
+        };
+
+        for (device, code) in &withheld_devices {
+            debug!(
+                user_id = ?device.user_id(),
+                device_id = ?device.device_id(),
+                ?code,
+                "Not sending the to-device event to a device"
+            );
+        }
+
+        if let Some(request) = request {
+            let response = self.client.send_to_device(&request).await?;
+            self.client.mark_request_as_sent(&request.txn_id, &response).await?;
+        }
+
+        Ok(withheld_devices
+            .into_iter()
+            .map(|(device, code)| {
+                ((device.user_id().to_owned(), device.device_id().to_owned()), code)
+            })
+            .collect())
+    }
+
     /// Get the E2EE identity of a user from the crypto store.
     ///
     /// Usually, we only have the E2EE identity of a user locally if the user
@@ -1077,6 +1262,23 @@ impl Encryption {
             .map(move |updates| IdentityUpdates::new(client.to_owned(), updates)))
     }
 
+    /// Returns a stream of the room keys whose sender data got upgraded,
+    /// e.g. because we found out that the device which sent them is
+    /// cross-signed by its owner.
+    ///
+    /// The [`EncryptionInfo`] of the events encrypted with those room keys can
+    /// then be refreshed with [`Room::get_encryption_info()`], e.g. to update
+    /// their shields.
+    ///
+    /// [`EncryptionInfo`]: matrix_sdk_base::deserialized_responses::EncryptionInfo
+    /// [`Room::get_encryption_info()`]: crate::Room::get_encryption_info
+    pub async fn sender_data_updates_stream(&self) -> Result<impl Stream<Item = Vec<RoomKeyInfo>>> {
+        let olm = self.client.olm_machine().await;
+        let olm = olm.as_ref().ok_or(Error::NoOlmMachine)?;
+
+        Ok(olm.store().sender_data_updates_stream())
+    }
+
     /// Create and upload a new cross signing identity.
     ///
     /// # Arguments
@@ -1141,6 +1343,50 @@ impl Encryption {
         Ok(())
     }
 
+    /// Replace the self-signing key, the user-signing key, or both, of our
+    /// cross-signing identity, while keeping the master key.
+    ///
+    /// Unlike [`Encryption::reset_cross_signing()`], this doesn't change our
+    /// master key, so other users keep trusting our identity. Our own devices
+    /// and the users we verified are signed again with the new keys.
+    ///
+    /// The private part of the master key is required. If this device doesn't
+    /// have it, it can be fetched from secret storage first, using
+    /// [`SecretStore::import_secrets()`]. The new keys should be stored in
+    /// secret storage afterwards, otherwise other devices won't be able to
+    /// use them.
+    ///
+    /// Uploading the new keys might require user interactive auth, in which
+    /// case the method needs to be called again with some `auth_data`, like
+    /// [`Encryption::bootstrap_cross_signing()`]. The same new keys are used
+    /// then, and they only replace the current ones once they're uploaded.
+    ///
+    /// [`SecretStore::import_secrets()`]: crate::encryption::secret_storage::SecretStore::import_secrets
+    pub async fn rotate_cross_signing_keys(
+        &self,
+        rotation: CrossSigningKeyRotation,
+        auth_data: Option<AuthData>,
+    ) -> Result<()> {
+        let olm = self.client.olm_machine().await;
+        let olm = olm.as_ref().ok_or(Error::NoOlmMachine)?;
+
+        let CrossSigningKeyRotationRequests { upload_signing_keys_req, upload_signatures_req } =
+            olm.rotate_cross_signing_keys(rotation).await?;
+
+        let upload_signing_keys_req = assign!(UploadSigningKeysRequest::new(), {
+            auth: auth_data,
+            master_key: upload_signing_keys_req.master_key.map(|c| c.to_raw()),
+            self_signing_key: upload_signing_keys_req.self_signing_key.map(|c| c.to_raw()),
+            user_signing_key: upload_signing_keys_req.user_signing_key.map(|c| c.to_raw()),
+        });
+
+        self.client.send(upload_signing_keys_req, None).await?;
+        olm.confirm_cross_signing_key_rotation().await?;
+        self.client.send(upload_signatures_req, None).await?;
+
+        Ok(())
+    }
+
     /// Reset the cross-signing keys.
     ///
     /// # Example
@@ -1416,25 +1662,48 @@ impl Encryption {
         path: PathBuf,
         passphrase: &str,
     ) -> Result<RoomKeyImportResult, RoomKeyImportError> {
-        let olm = self.client.olm_machine().await;
-        let olm = olm.as_ref().ok_or(RoomKeyImportError::StoreClosed)?;
-        let passphrase = zeroize::Zeroizing::new(passphrase.to_owned());
-
-        let decrypt = move || {
-            let file = std::fs::File::open(path)?;
-            matrix_sdk_base::crypto::decrypt_room_key_export(file, &passphrase)
-        };
+        let ret = {
+            let olm = self.client.olm_machine().await;
+            let olm = olm.as_ref().ok_or(RoomKeyImportError::StoreClosed)?;
+            let passphrase = zeroize::Zeroizing::new(passphrase.to_owned());
+
+            let decrypt = move || {
+                let file = std::fs::File::open(path)?;
+                matrix_sdk_base::crypto::decrypt_room_key_export(file, &passphrase)
+            };
 
-        let task = tokio::task::spawn_blocking(decrypt);
-        let import = task.await.expect("Task join error")?;
+            let task = tokio::task::spawn_blocking(decrypt);
+            let import = task.await.expect("Task join error")?;
 
-        let ret = olm.store().import_exported_room_keys(import, |_, _| {}).await?;
+            olm.store().import_exported_room_keys(import, |_, _| {}).await?
+        };
 
         self.backups().maybe_trigger_backup();
+        self.decrypt_state_events_with_imported_room_keys(&ret).await;
 
         Ok(ret)
     }
 
+    /// Try again to decrypt the encrypted state events of the rooms for which
+    /// room keys were imported outside of a sync, e.g. from a key backup, a
+    /// key export, or a room key bundle.
+    ///
+    /// A failure only means that some of the state stays encrypted, which
+    /// shouldn't fail the import of the room keys, so it's only logged.
+    pub(crate) async fn decrypt_state_events_with_imported_room_keys(
+        &self,
+        import_result: &RoomKeyImportResult,
+    ) {
+        if let Err(e) = self
+            .client
+            .base_client()
+            .decrypt_state_events_with_imported_room_keys(import_result)
+            .await
+        {
+            warn!("Couldn't decrypt the encrypted state events with the imported room keys: {e}");
+        }
+    }
+
     /// Get the secret storage manager of the client.
     pub fn secret_storage(&self) -> SecretStorage {
         SecretStorage { client: self.client.to_owned() }
@@ -1450,6 +1719,11 @@ impl Encryption {
         Recovery { client: self.client.to_owned() }
     }
 
+    /// Get the dehydrated devices manager of the client.
+    pub fn dehydrated_devices(&self) -> DehydratedDevices {
+        DehydratedDevices { client: self.client.to_owned() }
+    }
+
     /// Enables the crypto-store cross-process lock.
     ///
     /// This may be required if there are multiple processes that may do writes
@@ -1605,6 +1879,15 @@ impl Encryption {
 
         let this = self.clone();
         tasks.setup_e2ee = Some(spawn(async move {
+            // Resume, or cancel, the verification requests that were in progress when we
+            // were last shut down. This is only done here, and not whenever the
+            // `OlmMachine` gets recreated, so flows which are in progress aren't cancelled.
+            if let Some(olm_machine) = this.client.olm_machine().await.as_ref() {
+                if let Err(e) = olm_machine.restore_pending_verification_requests().await {
+                    error!("Couldn't restore the pending verification requests {e:?}");
+                }
+            }
+
             if this.settings().auto_enable_cross_signing {
                 if let Err(e) = this.bootstrap_cross_signing_if_needed(auth_data).await {
                     error!("Couldn't bootstrap cross signing {e:?}");
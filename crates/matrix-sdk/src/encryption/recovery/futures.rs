@@ -109,6 +109,8 @@ impl<'a> IntoFuture for Enable<'a> {
 
             let store: SecretStore = create_store.await?;
 
+            recovery.enable_dehydrated_device(&store).await;
+
             if wait_for_backups_upload {
                 let backups = recovery.client.encryption().backups();
                 let upload_future = backups.wait_for_steady_state();
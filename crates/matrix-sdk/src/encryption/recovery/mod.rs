@@ -102,11 +102,15 @@ use ruma::{
 use tracing::{error, info, instrument, warn};
 
 #[cfg(doc)]
-use crate::encryption::{
-    backups::Backups,
-    secret_storage::{SecretStorage, SecretStore},
+use crate::encryption::{backups::Backups, secret_storage::SecretStorage};
+use crate::{
+    client::WeakClient,
+    encryption::{
+        backups::BackupState, dehydrated_devices::DEFAULT_ROTATION_PERIOD,
+        secret_storage::SecretStore, AuthData, CrossSigningResetAuthType, CrossSigningResetHandle,
+    },
+    Client,
 };
-use crate::{client::WeakClient, encryption::backups::BackupState, Client};
 
 pub mod futures;
 mod types;
@@ -115,7 +119,6 @@ use self::{
     futures::{Enable, RecoverAndReset, Reset},
     types::{BackupDisabledContent, SecretStorageDisabledContent},
 };
-use crate::encryption::{AuthData, CrossSigningResetAuthType, CrossSigningResetHandle};
 
 /// The recovery manager for the [`Client`].
 #[derive(Debug)]
@@ -167,6 +170,9 @@ impl Recovery {
     /// doesn't already exist. It will then upload all the locally cached
     /// secrets, including the backup recovery key, to the new secret store.
     ///
+    /// A dehydrated device is created as well, if the homeserver supports them,
+    /// its pickle key being stored in the new secret store.
+    ///
     /// This method will throw an error if a backup already exists on the
     /// homeserver but this [`Client`] isn't connected to the existing backup.
     ///
@@ -266,6 +272,8 @@ impl Recovery {
     /// 4. Set a global account data event so clients won't attempt to
     ///    automatically re-enable a backup.
     ///
+    /// The dehydrated device is deleted as well, if there is one.
+    ///
     /// # Examples
     ///
     /// ```no_run
@@ -285,6 +293,11 @@ impl Recovery {
     #[instrument(skip_all)]
     pub async fn disable(&self) -> Result<()> {
         self.client.encryption().backups().disable().await?;
+
+        if let Err(e) = self.client.encryption().dehydrated_devices().delete().await {
+            warn!("Could not delete the dehydrated device: {e:?}");
+        }
+
         // Why oh why, can't we delete account data events?
         self.client.account().set_account_data(SecretStorageDisabledContent {}).await?;
         self.client.account().set_account_data(BackupDisabledContent { disabled: true }).await?;
@@ -428,6 +441,9 @@ impl Recovery {
     /// In short, this method will turn a newly created [`Client`] into a fully
     /// end-to-end encryption enabled client.
     ///
+    /// If a dehydrated device was set up, the room keys it received are
+    /// imported, and it is replaced with a new one.
+    ///
     /// # Examples
     ///
     /// ```no_run
@@ -497,6 +513,19 @@ impl Recovery {
         }
     }
 
+    /// Create a dehydrated device, and replace it periodically.
+    ///
+    /// Failures are only logged, as the homeserver might not support dehydrated
+    /// devices.
+    async fn enable_dehydrated_device(&self, store: &SecretStore) {
+        let dehydrated_devices = self.client.encryption().dehydrated_devices();
+
+        match dehydrated_devices.create(store).await {
+            Ok(()) => dehydrated_devices.start_rotation(DEFAULT_ROTATION_PERIOD),
+            Err(e) => warn!("Could not create a dehydrated device: {e:?}"),
+        }
+    }
+
     async fn should_auto_enable_backups(&self) -> Result<bool> {
         // If we didn't already enable backups, we don't see a backup version on the
         // server, and finally if backups have not been marked to be explicitly
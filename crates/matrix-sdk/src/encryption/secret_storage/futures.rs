@@ -16,9 +16,19 @@ use std::{future::IntoFuture, pin::Pin};
 
 use futures_core::Future;
 use matrix_sdk_base::crypto::secret_storage::SecretStorageKey;
-use ruma::events::secret_storage::default_key::SecretStorageDefaultKeyEventContent;
-
-use super::{Result, SecretStorage, SecretStore};
+use ruma::{
+    events::{
+        secret::request::SecretName,
+        secret_storage::default_key::SecretStorageDefaultKeyEventContent,
+        GlobalAccountDataEventType,
+    },
+    serde::Raw,
+};
+use serde_json::{json, value::to_raw_value};
+use zeroize::Zeroize;
+
+use super::{Result, SecretStorage, SecretStorageError, SecretStore};
+use crate::encryption::dehydrated_devices::PICKLE_KEY_SECRET_NAME;
 
 /// Future returned by [`SecretStorage::create_secret_store()`].
 #[derive(Debug)]
@@ -79,3 +89,155 @@ impl<'a> IntoFuture for CreateStore<'a> {
         })
     }
 }
+
+/// Future returned by [`SecretStore::rotate_key()`].
+#[derive(Debug)]
+pub struct RotateKey<'a> {
+    secret_store: &'a SecretStore,
+    passphrase: Option<&'a str>,
+    secrets: Vec<SecretName>,
+    delete_old_key: bool,
+}
+
+impl<'a> RotateKey<'a> {
+    pub(super) fn new(secret_store: &'a SecretStore) -> Self {
+        Self { secret_store, passphrase: None, secrets: Vec::new(), delete_old_key: false }
+    }
+
+    /// Set the passphrase for the new [`SecretStore`].
+    pub fn with_passphrase(mut self, passphrase: &'a str) -> Self {
+        self.passphrase = Some(passphrase);
+
+        self
+    }
+
+    /// Re-encrypt the given custom secrets as well, on top of the well-known
+    /// secrets listed in the documentation of the
+    /// [`SecretStore::rotate_key()`] method.
+    pub fn with_secrets(
+        mut self,
+        secret_names: impl IntoIterator<Item = impl Into<SecretName>>,
+    ) -> Self {
+        self.secrets.extend(secret_names.into_iter().map(Into::into));
+
+        self
+    }
+
+    /// Delete the old secret storage key once all the secrets have been
+    /// re-encrypted.
+    ///
+    /// The copies of the secrets encrypted under the old key are removed, and
+    /// the description of the old key is emptied, since account data events
+    /// can't be deleted.
+    ///
+    /// Before anything is deleted, the secrets which are re-encrypted are
+    /// checked to be encrypted under the new key. If some of them are still
+    /// only encrypted under the old key, the rotation fails with a
+    /// [`SecretStorageError::SecretsNotReEncrypted`] error, and the old key
+    /// stays the default key.
+    ///
+    /// **Warning**: Secrets can't be listed, so only the well-known secrets and
+    /// the ones passed to [`RotateKey::with_secrets()`] are re-encrypted. Any
+    /// other secret which is only encrypted under the old key can't be
+    /// decrypted anymore once the old key is deleted.
+    ///
+    /// [`SecretStorageError::SecretsNotReEncrypted`]: super::SecretStorageError::SecretsNotReEncrypted
+    pub fn delete_old_key(mut self) -> Self {
+        self.delete_old_key = true;
+
+        self
+    }
+}
+
+impl<'a> IntoFuture for RotateKey<'a> {
+    type Output = Result<SecretStore>;
+    #[cfg(target_arch = "wasm32")]
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + 'a>>;
+    #[cfg(not(target_arch = "wasm32"))]
+    type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send + 'a>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        let Self { secret_store: old_store, passphrase, secrets, delete_old_key } = self;
+
+        Box::pin(async move {
+            // Prevent this from racing with the creation of a new secret store.
+            let client_copy = old_store.client.to_owned();
+            let _guard = client_copy.locks().open_secret_store_lock.lock().await;
+
+            let new_key = if let Some(passphrase) = passphrase {
+                SecretStorageKey::new_from_passphrase(passphrase)
+            } else {
+                SecretStorageKey::new()
+            };
+
+            let content = new_key.event_content().to_owned();
+            old_store.client.account().set_account_data(content).await?;
+
+            let new_store = SecretStore { client: old_store.client.to_owned(), key: new_key };
+
+            let secret_names: Vec<SecretName> = [
+                SecretName::CrossSigningMasterKey,
+                SecretName::CrossSigningSelfSigningKey,
+                SecretName::CrossSigningUserSigningKey,
+                SecretName::RecoveryKey,
+                SecretName::from(PICKLE_KEY_SECRET_NAME),
+            ]
+            .into_iter()
+            .chain(secrets)
+            .collect();
+
+            // Copy every secret we can decrypt with the old key to the new key, the
+            // old copies are left in place until the new key becomes the default one.
+            for secret_name in &secret_names {
+                if let Some(mut secret) = old_store.get_secret(secret_name.to_owned()).await? {
+                    new_store.put_secret(secret_name.to_owned(), &secret).await?;
+                    secret.zeroize();
+                }
+            }
+
+            // Make sure we're not going to lose any of the secrets we know about before
+            // the new key becomes the default one and the old one is deleted.
+            if delete_old_key {
+                let secret_storage = old_store.client.encryption().secret_storage();
+                let mut secrets_left_behind = Vec::new();
+
+                for secret_name in &secret_names {
+                    let key_ids = secret_storage.secret_key_ids(secret_name.to_owned()).await?;
+
+                    if key_ids.iter().any(|key_id| key_id == old_store.key_id())
+                        && !key_ids.iter().any(|key_id| key_id == new_store.key_id())
+                    {
+                        secrets_left_behind.push(secret_name.to_owned());
+                    }
+                }
+
+                if !secrets_left_behind.is_empty() {
+                    return Err(SecretStorageError::SecretsNotReEncrypted {
+                        secret_names: secrets_left_behind,
+                    });
+                }
+            }
+
+            let default_key_content =
+                SecretStorageDefaultKeyEventContent::new(new_store.key.key_id().to_owned());
+            new_store.client.account().set_account_data(default_key_content).await?;
+
+            if delete_old_key {
+                for secret_name in secret_names {
+                    old_store.forget_secret(secret_name).await?;
+                }
+
+                // Why oh why, can't we delete account data events?
+                let event_type =
+                    GlobalAccountDataEventType::SecretStorageKey(old_store.key_id().to_owned());
+                new_store
+                    .client
+                    .account()
+                    .set_account_data_raw(event_type, Raw::from_json(to_raw_value(&json!({}))?))
+                    .await?;
+            }
+
+            Ok(new_store)
+        })
+    }
+}
@@ -67,8 +67,10 @@ use matrix_sdk_base::crypto::{
     CryptoStoreError, SecretImportError,
 };
 use ruma::events::{
+    secret::request::SecretName,
     secret_storage::{
         default_key::SecretStorageDefaultKeyEventContent, key::SecretStorageKeyEventContent,
+        secret::SecretEventContent,
     },
     EventContentFromType, GlobalAccountDataEventType,
 };
@@ -81,7 +83,7 @@ use crate::Client;
 mod futures;
 mod secret_store;
 
-pub use futures::CreateStore;
+pub use futures::{CreateStore, RotateKey};
 pub use secret_store::SecretStore;
 
 /// Convenicence type alias for the secret-storage specific results.
@@ -134,6 +136,14 @@ pub enum SecretStorageError {
     /// Error describing a decryption failure of a secret.
     #[error(transparent)]
     Decryption(#[from] DecryptionError),
+
+    /// The old secret storage key wasn't deleted while rotating it, because
+    /// some secrets are still only encrypted under it.
+    #[error("Some secrets are only encrypted under the old secret storage key: {secret_names:?}")]
+    SecretsNotReEncrypted {
+        /// The names of the secrets which would have been lost.
+        secret_names: Vec<SecretName>,
+    },
 }
 
 /// Error type describing decryption failures of the secret-storage system.
@@ -186,6 +196,50 @@ impl SecretStorage {
     /// # anyhow::Ok(()) };
     /// ```
     pub async fn open_secret_store(&self, secret_storage_key: &str) -> Result<SecretStore> {
+        if let Some(default_key_id) = self.default_key_id().await? {
+            self.open_secret_store_with_key_id(&default_key_id, secret_storage_key).await
+        } else {
+            Err(SecretStorageError::MissingKeyInfo { key_id: None })
+        }
+    }
+
+    /// Open the [`SecretStore`] for the secret storage key with the given key
+    /// ID, which doesn't need to be the default key.
+    ///
+    /// This allows to read the secrets which are still encrypted under an old
+    /// secret storage key, for example after the key has been rotated with
+    /// [`SecretStore::rotate_key()`] without deleting the old key.
+    ///
+    /// The `secret_storage_key` can be a passphrase or a Base58 encoded secret
+    /// storage key.
+    pub async fn open_secret_store_with_key_id(
+        &self,
+        key_id: &str,
+        secret_storage_key: &str,
+    ) -> Result<SecretStore> {
+        let event_type = GlobalAccountDataEventType::SecretStorageKey(key_id.to_owned());
+        let secret_key = self.client.account().fetch_account_data(event_type.to_owned()).await?;
+
+        if let Some(secret_key_content) = secret_key {
+            let event_type = event_type.to_string();
+            let secret_key_content = to_raw_value(&secret_key_content)?;
+
+            let secret_key_content =
+                SecretStorageKeyEventContent::from_parts(&event_type, &secret_key_content)?;
+
+            let key = SecretStorageKey::from_account_data(secret_storage_key, secret_key_content)?;
+
+            Ok(SecretStore { client: self.client.to_owned(), key })
+        } else {
+            Err(SecretStorageError::MissingKeyInfo { key_id: Some(key_id.to_owned()) })
+        }
+    }
+
+    /// Run a network request to fetch the ID of the default secret storage
+    /// key, as found in the `m.secret_storage.default_key` event.
+    ///
+    /// Returns `None` if secret storage isn't set up for this user.
+    pub async fn default_key_id(&self) -> Result<Option<String>> {
         let maybe_default_key_id = self
             .client
             .account()
@@ -196,27 +250,27 @@ impl SecretStorage {
             let default_key_id =
                 default_key_id.deserialize_as::<SecretStorageDefaultKeyEventContent>()?;
 
-            let event_type =
-                GlobalAccountDataEventType::SecretStorageKey(default_key_id.key_id.to_owned());
-            let secret_key =
-                self.client.account().fetch_account_data(event_type.to_owned()).await?;
-
-            if let Some(secret_key_content) = secret_key {
-                let event_type = event_type.to_string();
-                let secret_key_content = to_raw_value(&secret_key_content)?;
-
-                let secret_key_content =
-                    SecretStorageKeyEventContent::from_parts(&event_type, &secret_key_content)?;
+            Ok(Some(default_key_id.key_id))
+        } else {
+            Ok(None)
+        }
+    }
 
-                let key =
-                    SecretStorageKey::from_account_data(secret_storage_key, secret_key_content)?;
+    /// Run a network request to list the IDs of the secret storage keys the
+    /// given secret is encrypted with.
+    ///
+    /// A secret can be encrypted under multiple secret storage keys, the
+    /// [`SecretStore`] for any of those keys can be opened with the
+    /// [`SecretStorage::open_secret_store_with_key_id()`] method to read the
+    /// secret.
+    pub async fn secret_key_ids(&self, secret_name: impl Into<SecretName>) -> Result<Vec<String>> {
+        let event_type = GlobalAccountDataEventType::from(secret_name.into());
 
-                Ok(SecretStore { client: self.client.to_owned(), key })
-            } else {
-                Err(SecretStorageError::MissingKeyInfo { key_id: Some(default_key_id.key_id) })
-            }
+        if let Some(secret_content) = self.client.account().fetch_account_data(event_type).await? {
+            let secret_content = secret_content.deserialize_as::<SecretEventContent>()?;
+            Ok(secret_content.encrypted.into_keys().collect())
         } else {
-            Err(SecretStorageError::MissingKeyInfo { key_id: None })
+            Ok(Vec::new())
         }
     }
 
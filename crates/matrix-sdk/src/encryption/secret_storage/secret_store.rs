@@ -14,8 +14,12 @@
 
 use std::fmt;
 
-use matrix_sdk_base::crypto::{secret_storage::SecretStorageKey, CrossSigningKeyExport};
+use matrix_sdk_base::crypto::{
+    secret_storage::SecretStorageKey, CrossSigningKeyExport, CrossSigningKeyRotation,
+};
+use matrix_sdk_common::executor::spawn;
 use ruma::{
+    api::client::uiaa::AuthData,
     events::{
         secret::request::SecretName, secret_storage::secret::SecretEventContent,
         GlobalAccountDataEventType,
@@ -30,7 +34,7 @@ use tracing::{
 };
 use zeroize::Zeroize;
 
-use super::{DecryptionError, Result};
+use super::{DecryptionError, Result, RotateKey};
 use crate::Client;
 
 #[cfg_attr(doc, aquamarine::aquamarine)]
@@ -110,6 +114,62 @@ impl SecretStore {
         self.key.to_base58()
     }
 
+    /// Get the ID of the [`SecretStorageKey`] of this [`SecretStore`].
+    pub fn key_id(&self) -> &str {
+        self.key.key_id()
+    }
+
+    /// Rotate the secret storage key.
+    ///
+    /// This creates a new [`SecretStorageKey`], re-encrypts all the secrets
+    /// this [`SecretStore`] has access to under it, and makes it the default
+    /// key in the `m.secret_storage.default_key` event. The following secrets
+    /// are re-encrypted, if they exist:
+    ///
+    /// - `m.cross_signing.master`: The master cross-signing key.
+    /// - `m.cross_signing.self_signing`: The self-signing cross-signing key.
+    /// - `m.cross_signing.user_signing`: The user-signing cross-signing key.
+    /// - `m.megolm_backup.v1`: The backup recovery key.
+    /// - `org.matrix.msc3814`: The pickle key of the dehydrated device.
+    ///
+    /// Custom secrets can be re-encrypted as well using the
+    /// [`RotateKey::with_secrets()`] method, and the old key can be deleted
+    /// using the [`RotateKey::delete_old_key()`] method. Custom secrets which
+    /// aren't passed to [`RotateKey::with_secrets()`] can't be decrypted
+    /// anymore once the old key is deleted.
+    ///
+    /// Unlike [`SecretStorage::create_secret_store()`], the secrets are copied
+    /// from the secret store, so they don't need to be known locally, and the
+    /// cross-signing identity doesn't need to be reset.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # async {
+    /// # let homeserver = Url::parse("http://example.com")?;
+    /// # let client = Client::new(homeserver).await?;
+    /// let secret_store = client
+    ///     .encryption()
+    ///     .secret_storage()
+    ///     .open_secret_store("It's a secret to everybody")
+    ///     .await?;
+    ///
+    /// let new_secret_store = secret_store
+    ///     .rotate_key()
+    ///     .with_passphrase("It's a new secret to everybody")
+    ///     .with_secrets(["m.treasure"])
+    ///     .delete_old_key()
+    ///     .await?;
+    /// # anyhow::Ok(()) };
+    /// ```
+    ///
+    /// [`SecretStorage::create_secret_store()`]: super::SecretStorage::create_secret_store
+    pub fn rotate_key(&self) -> RotateKey<'_> {
+        RotateKey::new(self)
+    }
+
     /// Retrieve a secret from the homeserver's account data
     ///
     /// This method allows you to retrieve a secret from the account data stored
@@ -262,6 +322,29 @@ impl SecretStore {
         Ok(())
     }
 
+    /// Remove the copy of a secret which is encrypted under the key of this
+    /// [`SecretStore`], leaving the copies encrypted under other keys intact.
+    pub(super) async fn forget_secret(&self, secret_name: impl Into<SecretName>) -> Result<()> {
+        // See the comment in `put_secret()` for why we're taking this lock.
+        let _guard = self.client.locks().store_secret_lock.lock().await;
+
+        let secret_name = secret_name.into();
+        let event_type = GlobalAccountDataEventType::from(secret_name);
+
+        if let Some(secret_content) =
+            self.client.account().fetch_account_data(event_type.to_owned()).await?
+        {
+            let mut secret_content = secret_content.deserialize_as::<SecretEventContent>()?;
+
+            if secret_content.encrypted.remove(self.key.key_id()).is_some() {
+                let secret_content = Raw::from_json(to_raw_value(&secret_content)?);
+                self.client.account().set_account_data_raw(event_type, secret_content).await?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Get all the well-known private parts/keys of the [`OwnUserIdentity`] as
     /// a [`CrossSigningKeyExport`].
     ///
@@ -323,6 +406,8 @@ impl SecretStore {
     /// - `m.cross_signing.self_signing`: The self-signing cross-signing key.
     /// - `m.cross_signing.user_signing`: The user-signing cross-signing key.
     /// - `m.megolm_backup.v1`: The backup recovery key.
+    /// - `org.matrix.msc3814`: The pickle key of the dehydrated device, see
+    ///   [`DehydratedDevices`].
     ///
     /// If the `m.cross_signing.self_signing` key is successfully imported, it
     /// is used to sign our own [`Device`], marking it as verified. This step is
@@ -361,6 +446,7 @@ impl SecretStore {
     /// ```
     ///
     /// [`Device`]: crate::encryption::identities::Device
+    /// [`DehydratedDevices`]: crate::encryption::dehydrated_devices::DehydratedDevices
     #[instrument(fields(user_id, device_id, cross_signing_status))]
     pub async fn import_secrets(&self) -> Result<()> {
         let olm_machine = self.client.olm_machine().await;
@@ -416,6 +502,61 @@ impl SecretStore {
 
         self.maybe_enable_backups().await?;
 
+        let dehydrated_devices = self.client.encryption().dehydrated_devices();
+        let knew_pickle_key = dehydrated_devices.has_pickle_key().await.unwrap_or(true);
+
+        match dehydrated_devices.import_pickle_key(self).await {
+            // A newly logged in device learns about the pickle key here, the room keys the
+            // dehydrated device received while we were logged out can now be imported. This
+            // can take a while, so don't make the import of the secrets wait for it.
+            Ok(Some(_)) if !knew_pickle_key => {
+                spawn(async move { dehydrated_devices.rehydrate_and_rotate().await });
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Could not import the pickle key of the dehydrated device: {e:?}"),
+        }
+
+        Ok(())
+    }
+
+    /// Replace the self-signing key, the user-signing key, or both, of our
+    /// cross-signing identity, using the master key stored in this
+    /// [`SecretStore`].
+    ///
+    /// The private cross-signing keys are imported from the secret store
+    /// first, see [`SecretStore::import_secrets()`], then rotated with
+    /// [`Encryption::rotate_cross_signing_keys()`]. The new keys are put back
+    /// into the secret store, so our other devices can import them.
+    ///
+    /// The master key is kept, so other users keep trusting our identity.
+    ///
+    /// [`Encryption::rotate_cross_signing_keys()`]: crate::encryption::Encryption::rotate_cross_signing_keys
+    #[instrument(skip(self, auth_data))]
+    pub async fn rotate_cross_signing_keys(
+        &self,
+        rotation: CrossSigningKeyRotation,
+        auth_data: Option<AuthData>,
+    ) -> Result<()> {
+        let olm_machine = self.client.olm_machine().await;
+        let olm_machine = olm_machine.as_ref().ok_or(crate::Error::NoOlmMachine)?;
+
+        let export = self.get_cross_signing_keys().await?;
+
+        // Make sure the public parts of our keys are up to date, the private parts
+        // are only imported if they match.
+        let (request_id, request) = olm_machine.query_keys_for_users([olm_machine.user_id()]);
+        self.client.keys_query(&request_id, request.device_keys).await?;
+
+        olm_machine.import_cross_signing_keys(export).await?;
+
+        self.client.encryption().rotate_cross_signing_keys(rotation, auth_data).await?;
+
+        if let Some(export) = olm_machine.export_cross_signing_keys().await? {
+            self.put_cross_signing_keys(export).await?;
+        }
+
+        info!("Rotated the cross-signing keys and updated the secret store");
+
         Ok(())
     }
 
@@ -436,6 +577,11 @@ impl SecretStore {
             key.zeroize();
         }
 
+        if let Err(e) = self.client.encryption().dehydrated_devices().export_pickle_key(self).await
+        {
+            warn!("Could not export the pickle key of the dehydrated device: {e:?}");
+        }
+
         Ok(())
     }
 }
@@ -22,7 +22,7 @@ use ruma::{
 };
 use tokio::sync::{
     mpsc::{self, UnboundedReceiver},
-    Mutex,
+    Mutex, Semaphore,
 };
 use tracing::{debug, trace, warn};
 
@@ -44,6 +44,8 @@ pub(crate) struct ClientTasks {
     pub(crate) download_room_keys: Option<BackupDownloadTask>,
     #[cfg(feature = "e2e-encryption")]
     pub(crate) update_recovery_state_after_backup: Option<JoinHandle<()>>,
+    #[cfg(feature = "e2e-encryption")]
+    pub(crate) rotate_dehydrated_device: Option<JoinHandle<()>>,
     pub(crate) setup_e2ee: Option<JoinHandle<()>>,
 }
 
@@ -143,6 +145,10 @@ impl BackupDownloadTask {
     #[cfg(not(test))]
     const DOWNLOAD_DELAY_MILLIS: u64 = 100;
 
+    /// The maximum number of room keys we download from the backup at the
+    /// same time.
+    const MAX_CONCURRENT_DOWNLOADS: usize = 4;
+
     pub(crate) fn new(client: WeakClient) -> Self {
         let (sender, receiver) = mpsc::unbounded_channel();
 
@@ -194,14 +200,14 @@ impl BackupDownloadTask {
                 break;
             }
 
-            // Check that we don't already have a task to process this event, and fire one
-            // off else if not.
-            let event_id = &room_key_download_request.event_id;
-            if !state_guard.active_tasks.contains_key(event_id) {
-                let event_id = event_id.to_owned();
+            // Check that we don't already have a task to download the room key of this
+            // event, and fire one off else if not. Many undecryptable events usually share
+            // the same room key, so this avoids queuing the same download over and over.
+            let room_key_info = room_key_download_request.to_room_key_info();
+            if !state_guard.active_tasks.contains_key(&room_key_info) {
                 let task =
                     spawn(Self::handle_download_request(state.clone(), room_key_download_request));
-                state_guard.active_tasks.insert(event_id, task);
+                state_guard.active_tasks.insert(room_key_info, task);
             }
         }
     }
@@ -220,7 +226,7 @@ impl BackupDownloadTask {
 
         // Now take the lock, and check that we still want to do a download. If we do,
         // keep hold of a strong reference to the `Client`.
-        let client = {
+        let (client, download_permits) = {
             let mut state = state.lock().await;
 
             let Some(client) = state.client.get() else {
@@ -231,9 +237,9 @@ impl BackupDownloadTask {
 
             // Check that we still want to do a download.
             if !state.should_download(&client, &download_request).await {
-                // We decided against doing a download. Mark the job done for this event before
-                // dropping the lock.
-                state.active_tasks.remove(&download_request.event_id);
+                // We decided against doing a download. Mark the job done for this room key
+                // before dropping the lock.
+                state.active_tasks.remove(&download_request.to_room_key_info());
                 return;
             }
 
@@ -241,9 +247,13 @@ impl BackupDownloadTask {
             // room key, that we're going to go ahead and do a download.
             state.downloaded_room_keys.insert(download_request.to_room_key_info());
 
-            client
+            (client, state.download_permits.clone())
         };
 
+        // Don't flood the homeserver with requests if we get a burst of undecryptable
+        // events, wait for our turn to do the download. The semaphore is never closed.
+        let _permit = download_permits.acquire_owned().await;
+
         // Do the download without holding the lock.
         let result = client
             .encryption()
@@ -272,11 +282,11 @@ impl BackupDownloadTask {
                     // back off from more requests, and also remove the entry from the list of
                     // room keys that we are downloading.
                     state.downloaded_room_keys.remove(std::iter::once(&room_key_info));
-                    state.failures_cache.insert(room_key_info);
+                    state.failures_cache.insert(room_key_info.clone());
                 }
             }
 
-            state.active_tasks.remove(&download_request.event_id);
+            state.active_tasks.remove(&room_key_info);
         }
     }
 }
@@ -290,8 +300,11 @@ struct BackupDownloadTaskListenerState {
     /// A record of backup download attempts that have recently failed.
     failures_cache: FailuresCache<RoomKeyInfo>,
 
-    /// Map from event ID to download task
-    active_tasks: BTreeMap<OwnedEventId, JoinHandle<()>>,
+    /// Map from room key to download task.
+    active_tasks: BTreeMap<RoomKeyInfo, JoinHandle<()>>,
+
+    /// Limits the number of room keys we download at the same time.
+    download_permits: Arc<Semaphore>,
 
     /// A list of room keys that we have already downloaded, or are about to
     /// download.
@@ -314,6 +327,9 @@ impl BackupDownloadTaskListenerState {
             client,
             failures_cache: FailuresCache::with_settings(Duration::from_secs(60 * 60 * 24), 60),
             active_tasks: Default::default(),
+            download_permits: Arc::new(Semaphore::new(
+                BackupDownloadTask::MAX_CONCURRENT_DOWNLOADS,
+            )),
             downloaded_room_keys: DownloadCache::with_settings(
                 Duration::from_secs(60 * 60 * 24),
                 60,
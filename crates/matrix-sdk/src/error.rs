@@ -22,7 +22,7 @@ use http::StatusCode;
 use matrix_sdk_base::crypto::ScanError;
 #[cfg(feature = "e2e-encryption")]
 use matrix_sdk_base::crypto::{
-    CryptoStoreError, DecryptorError, KeyExportError, MegolmError, OlmError,
+    CryptoStoreError, DecryptorError, KeyExportError, MegolmError, OlmError, SignatureError,
 };
 use matrix_sdk_base::{
     event_cache_store::EventCacheStoreError, Error as SdkBaseError, QueueWedgeError, RoomState,
@@ -152,13 +152,33 @@ impl HttpError {
         self.as_ruma_api_error().and_then(as_variant!(RumaApiError::Uiaa))
     }
 
+    /// Whether the homeserver couldn't be reached.
+    ///
+    /// This is the case for errors at the network layer (e.g. no connection,
+    /// or a timeout), and for gateway errors returned by a reverse proxy in
+    /// front of a homeserver that is down. Other errors of the HTTP client,
+    /// like a response body that can't be decoded, aren't network errors.
+    pub fn is_network_error(&self) -> bool {
+        let status_code = match self.as_ruma_api_error() {
+            Some(RumaApiError::ClientApi(error)) => error.status_code,
+            Some(RumaApiError::Other(error)) => error.status_code,
+            Some(RumaApiError::Uiaa(_)) => return false,
+            None => return matches!(self, HttpError::Reqwest(error) if is_network_failure(error)),
+        };
+
+        matches!(
+            status_code,
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+        )
+    }
+
     /// Returns whether an HTTP error response should be qualified as transient
     /// or permanent.
     pub(crate) fn retry_kind(&self) -> RetryKind {
         match self {
             // If it was a plain network error, it's either that we're disconnected from the
             // internet, or that the remote is, so retry a few times.
-            HttpError::Reqwest(_) => RetryKind::NetworkFailure,
+            HttpError::Reqwest(error) if is_network_failure(error) => RetryKind::NetworkFailure,
 
             HttpError::Api(FromHttpResponseError::Server(api_error)) => {
                 RetryKind::from_api_error(api_error)
@@ -168,6 +188,19 @@ impl HttpError {
     }
 }
 
+/// Whether an error of the HTTP client happened at the network layer, i.e.
+/// while connecting to the homeserver or while sending the request, or because
+/// the request timed out.
+fn is_network_failure(error: &ReqwestError) -> bool {
+    // Connection errors aren't reported separately on WebAssembly.
+    #[cfg(not(target_arch = "wasm32"))]
+    if error.is_connect() {
+        return true;
+    }
+
+    error.is_timeout() || error.is_request()
+}
+
 /// How should we behave with respect to retry behavior after an [`HttpError`]
 /// happened?
 pub(crate) enum RetryKind {
@@ -312,6 +345,11 @@ pub enum Error {
     #[error(transparent)]
     DecryptorError(#[from] DecryptorError),
 
+    /// An error occurred while signing with our cross-signing keys.
+    #[cfg(feature = "e2e-encryption")]
+    #[error(transparent)]
+    SignatureError(#[from] SignatureError),
+
     /// An error occurred in the state store.
     #[error(transparent)]
     StateStore(#[from] StoreError),
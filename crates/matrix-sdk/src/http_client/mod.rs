@@ -294,14 +294,19 @@ impl tower::Service<http::Request<Bytes>> for HttpClient {
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
     use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
         num::NonZeroUsize,
         sync::{
             atomic::{AtomicU8, Ordering},
             Arc,
         },
+        thread,
         time::Duration,
     };
 
+    use assert_matches2::assert_let;
+
     use matrix_sdk_test::{async_test, test_json};
     use wiremock::{
         matchers::{method, path},
@@ -310,7 +315,8 @@ mod tests {
 
     use crate::{
         http_client::RequestConfig,
-        test_utils::{set_client_session, test_client_builder_with_server},
+        test_utils::{set_client_session, test_client_builder, test_client_builder_with_server},
+        HttpError,
     };
 
     #[async_test]
@@ -397,4 +403,49 @@ mod tests {
         assert_eq!(counter.load(Ordering::SeqCst), 254, "Not all requests passed through");
         bg_task.abort();
     }
+
+    #[async_test]
+    async fn test_response_body_error_is_not_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let homeserver = format!("http://{}", listener.local_addr().unwrap());
+
+        let counter = Arc::new(AtomicU8::new(0));
+        let inner_counter = counter.clone();
+
+        // A homeserver which announces a longer response body than the one it sends,
+        // before closing the connection.
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                inner_counter.fetch_add(1, Ordering::SeqCst);
+
+                // Read the whole request first, the requests we send have no body.
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|read| read > 2) {
+                    line.clear();
+                }
+
+                let _ = stream.write_all(
+                    b"HTTP/1.1 200 OK\r\n\
+                      content-type: application/json\r\n\
+                      content-length: 100\r\n\r\n{}",
+                );
+            }
+        });
+
+        let client = test_client_builder(Some(homeserver))
+            .request_config(RequestConfig::new().retry_limit(3))
+            .build()
+            .await
+            .unwrap();
+        set_client_session(&client).await;
+
+        let error = client.whoami().await.unwrap_err();
+
+        assert_let!(HttpError::Reqwest(reqwest_error) = &error);
+        assert!(reqwest_error.is_body() || reqwest_error.is_decode());
+        assert!(!error.is_network_error());
+        assert_eq!(counter.load(Ordering::SeqCst), 1, "The request shouldn't have been retried");
+    }
 }
@@ -49,6 +49,7 @@ pub mod media;
 pub mod notification_settings;
 #[cfg(feature = "experimental-oidc")]
 pub mod oidc;
+pub mod presence;
 pub mod pusher;
 pub mod room;
 pub mod room_directory_search;
@@ -81,6 +82,7 @@ pub use matrix_sdk_sqlite::SqliteCryptoStore;
 #[cfg(feature = "sqlite")]
 pub use matrix_sdk_sqlite::SqliteStateStore;
 pub use media::Media;
+pub use presence::Presence;
 pub use pusher::Pusher;
 pub use room::Room;
 pub use ruma::{IdParseError, OwnedServerName, ServerName};
@@ -218,8 +218,13 @@ use self::{
     backend::{server::OidcServer, OidcBackend},
     cross_process::{CrossProcessRefreshLockGuard, CrossProcessRefreshManager},
 };
+#[cfg(all(doc, feature = "e2e-encryption", not(target_arch = "wasm32")))]
+use crate::authentication::qrcode::{CheckCodeSender, GrantLoginProgress};
 use crate::{
-    authentication::{qrcode::LoginWithQrCode, AuthData},
+    authentication::{
+        qrcode::{GrantLoginWithGeneratedQrCode, GrantLoginWithScannedQrCode, LoginWithQrCode},
+        AuthData,
+    },
     client::SessionChange,
     oidc::registrations::{ClientId, OidcRegistrations},
     Client, HttpError, RefreshTokenError, Result,
@@ -437,6 +442,81 @@ impl Oidc {
         LoginWithQrCode::new(&self.client, client_metadata, data)
     }
 
+    /// Grant the login to a new device by displaying a QR code.
+    ///
+    /// This method allows an already logged in device to log in a new device,
+    /// the QR code data will be available in the
+    /// [`GrantLoginProgress::QrCodeReady`] progress update and needs to be
+    /// displayed so the new device can scan it. The new device will then
+    /// display a check code, which needs to be passed to the
+    /// [`CheckCodeSender`] that is part of the
+    /// [`GrantLoginProgress::WaitingForCheckCode`] progress update.
+    ///
+    /// Once the secure channel is established, the user needs to approve the
+    /// login of the new device with the OIDC provider by opening the URL found
+    /// in the [`GrantLoginProgress::WaitingForAuth`] progress update. After the
+    /// new device has been logged in, the private cross-signing keys and the
+    /// backup key are sent to it.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use futures_util::StreamExt;
+    /// use matrix_sdk::{authentication::qrcode::GrantLoginProgress, Client};
+    /// # _ = async {
+    /// # let client: Client = unimplemented!();
+    /// let oidc = client.oidc();
+    ///
+    /// let grant = oidc.grant_login_with_generated_qr_code();
+    /// let mut progress = grant.subscribe_to_progress();
+    ///
+    /// let task = tokio::spawn(async move {
+    ///     while let Some(state) = progress.next().await {
+    ///         match state {
+    ///             GrantLoginProgress::QrCodeReady { qr_code_data } => {
+    ///                 // Display the QR code, using a different library to render it.
+    ///                 let bytes = qr_code_data.to_bytes();
+    ///             }
+    ///             GrantLoginProgress::WaitingForCheckCode { check_code_sender } => {
+    ///                 // Ask the user for the check code the new device is displaying.
+    ///                 # let check_code = 0;
+    ///                 check_code_sender.send(check_code).unwrap();
+    ///             }
+    ///             GrantLoginProgress::WaitingForAuth { verification_uri } => {
+    ///                 println!("Please open {verification_uri} to allow the new device to log in");
+    ///             }
+    ///             GrantLoginProgress::Done => break,
+    ///             _ => (),
+    ///         }
+    ///     }
+    /// });
+    ///
+    /// grant.await?;
+    /// task.abort();
+    /// # anyhow::Ok(()) };
+    /// ```
+    #[cfg(all(feature = "e2e-encryption", not(target_arch = "wasm32")))]
+    pub fn grant_login_with_generated_qr_code(&self) -> GrantLoginWithGeneratedQrCode<'_> {
+        GrantLoginWithGeneratedQrCode::new(&self.client)
+    }
+
+    /// Grant the login to a new device by scanning the QR code the new device
+    /// is displaying.
+    ///
+    /// The new device can't be sure that it's talking to us, the check code
+    /// found in the [`GrantLoginProgress::EstablishingSecureChannel`] progress
+    /// update needs to be entered on the new device.
+    ///
+    /// The rest of the process is the same as for the
+    /// [`Oidc::grant_login_with_generated_qr_code()`] method.
+    #[cfg(all(feature = "e2e-encryption", not(target_arch = "wasm32")))]
+    pub fn grant_login_with_scanned_qr_code<'a>(
+        &'a self,
+        data: &'a QrCodeData,
+    ) -> GrantLoginWithScannedQrCode<'a> {
+        GrantLoginWithScannedQrCode::new(&self.client, data)
+    }
+
     /// A higher level wrapper around the configuration and login methods that
     /// will take some client metadata, register the client if needed and begin
     /// the login process, returning the authorization data required to show a
@@ -0,0 +1,378 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! High-level presence API.
+//!
+//! Presence updates are received through the `presence` section of a sync v2
+//! response, through the [`PresenceExtension`] of a sliding sync, or by
+//! polling the presence of some users with
+//! [`Presence::poll_direct_targets`]. In all cases, the last known presence
+//! of a user can be observed with [`Presence::subscribe`].
+
+use std::{collections::BTreeMap, sync::RwLock as StdRwLock, time::Duration};
+
+use eyeball::{SharedObservable, Subscriber};
+use matrix_sdk_base::StateChanges;
+use matrix_sdk_common::executor::{spawn, JoinHandle};
+use ruma::{
+    api::client::presence::{get_presence, set_presence},
+    assign,
+    events::presence::{PresenceEvent, PresenceEventContent},
+    presence::PresenceState,
+    serde::Raw,
+    OwnedUserId, UInt, UserId,
+};
+use tracing::{debug, warn};
+
+use crate::{Client, Error, Result};
+
+/// The last known presence of the users someone is observing, as observables.
+///
+/// The observables are dropped once nobody subscribes to them anymore.
+#[derive(Debug, Default)]
+pub(crate) struct PresenceObservables {
+    observables: StdRwLock<BTreeMap<OwnedUserId, SharedObservable<Option<PresenceEventContent>>>>,
+}
+
+impl PresenceObservables {
+    /// Subscribe to the presence of a user, if it's already observed.
+    fn subscribe(&self, user_id: &UserId) -> Option<Subscriber<Option<PresenceEventContent>>> {
+        self.observables.read().unwrap().get(user_id).map(|observable| observable.subscribe())
+    }
+
+    /// Subscribe to the presence of a user, starting to observe it with the
+    /// given initial value if it isn't already.
+    ///
+    /// The observables of the users nobody observes anymore are dropped at the
+    /// same time.
+    fn subscribe_or_insert(
+        &self,
+        user_id: &UserId,
+        initial: Option<PresenceEventContent>,
+    ) -> Subscriber<Option<PresenceEventContent>> {
+        let mut observables = self.observables.write().unwrap();
+        observables.retain(|_, observable| observable.subscriber_count() > 0);
+
+        observables
+            .entry(user_id.to_owned())
+            .or_insert_with(|| SharedObservable::new(initial))
+            .subscribe()
+    }
+
+    /// Update the presence of a user, if someone is observing it.
+    fn update(&self, event: PresenceEvent) {
+        let observables = self.observables.read().unwrap();
+        let Some(observable) = observables.get(&event.sender) else {
+            return;
+        };
+
+        if observable.subscriber_count() > 0 {
+            observable.set(Some(event.content));
+            return;
+        }
+
+        drop(observables);
+
+        // Nobody observes this user anymore, unless someone subscribed in the meantime.
+        let mut observables = self.observables.write().unwrap();
+        if observables.get(&event.sender).is_some_and(|o| o.subscriber_count() == 0) {
+            observables.remove(&event.sender);
+        }
+    }
+}
+
+/// A high-level API to manage presence.
+#[derive(Debug, Clone)]
+pub struct Presence {
+    /// The underlying HTTP client.
+    client: Client,
+}
+
+impl Presence {
+    pub(crate) fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    /// Set the presence of the current user.
+    ///
+    /// # Arguments
+    ///
+    /// * `presence` - The new presence state.
+    ///
+    /// * `status_msg` - An optional status message to attach to the presence.
+    pub async fn set(&self, presence: PresenceState, status_msg: Option<String>) -> Result<()> {
+        let user_id = self.client.user_id().ok_or(Error::AuthenticationRequired)?;
+        let request = assign!(set_presence::v3::Request::new(user_id.to_owned(), presence), {
+            status_msg,
+        });
+        self.client.send(request, None).await?;
+        Ok(())
+    }
+
+    /// Fetch the presence of a user from the server.
+    ///
+    /// The received presence is persisted in the store, and forwarded to the
+    /// observers of this user's presence.
+    pub async fn fetch(&self, user_id: &UserId) -> Result<PresenceEventContent> {
+        let request = get_presence::v3::Request::new(user_id.to_owned());
+        let response = self.client.send(request, None).await?;
+
+        let content = assign!(PresenceEventContent::new(response.presence), {
+            status_msg: response.status_msg,
+            currently_active: response.currently_active,
+            last_active_ago: response
+                .last_active_ago
+                .and_then(|ago| UInt::new(ago.as_millis().try_into().ok()?)),
+        });
+
+        let event = PresenceEvent { content: content.clone(), sender: user_id.to_owned() };
+        self.handle_presence_events(&[Raw::new(&event)?]).await?;
+
+        Ok(content)
+    }
+
+    /// Get the last known presence of a user, from the store.
+    pub async fn get(&self, user_id: &UserId) -> Result<Option<PresenceEventContent>> {
+        Ok(self
+            .client
+            .store()
+            .get_presence_event(user_id)
+            .await?
+            .and_then(|event| event.deserialize().ok())
+            .map(|event| event.content))
+    }
+
+    /// Subscribe to the presence of a user.
+    ///
+    /// The initial value is the last known presence of the user, from the
+    /// store, if any. The presence of the user stops being observed once all
+    /// the subscribers are dropped.
+    pub async fn subscribe(
+        &self,
+        user_id: &UserId,
+    ) -> Result<Subscriber<Option<PresenceEventContent>>> {
+        let observables = &self.client.inner.presence_observables;
+
+        if let Some(subscriber) = observables.subscribe(user_id) {
+            return Ok(subscriber);
+        }
+
+        let initial = self.get(user_id).await?;
+
+        Ok(observables.subscribe_or_insert(user_id, initial))
+    }
+
+    /// Spawn a task that periodically fetches the presence of all the users
+    /// we're sharing a direct room with.
+    ///
+    /// This is useful when presence can't be received through the sync, e.g.
+    /// with a sliding sync server that doesn't support the
+    /// [`PresenceExtension`]. The task runs until the returned handle is
+    /// aborted.
+    pub fn poll_direct_targets(&self, interval: Duration) -> JoinHandle<()> {
+        let this = self.clone();
+
+        spawn(async move {
+            loop {
+                let mut user_ids = this
+                    .client
+                    .joined_rooms()
+                    .iter()
+                    .flat_map(|room| room.direct_targets())
+                    .collect::<Vec<_>>();
+                user_ids.sort();
+                user_ids.dedup();
+
+                debug!(num_users = user_ids.len(), "Polling presence of direct targets");
+
+                for user_id in user_ids {
+                    if let Err(error) = this.fetch(&user_id).await {
+                        warn!(%user_id, ?error, "Couldn't fetch the presence of a user");
+                    }
+                }
+
+                #[cfg(target_arch = "wasm32")]
+                gloo_timers::future::sleep(interval).await;
+
+                #[cfg(not(target_arch = "wasm32"))]
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+
+    /// Persist presence events in the store, and forward them to the
+    /// observers.
+    pub(crate) async fn handle_presence_events(&self, events: &[Raw<PresenceEvent>]) -> Result<()> {
+        let events = events
+            .iter()
+            .filter_map(|raw| Some((raw.deserialize().ok()?, raw.clone())))
+            .collect::<Vec<_>>();
+
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        let changes = assign!(StateChanges::default(), {
+            presence: events
+                .iter()
+                .map(|(event, raw)| (event.sender.clone(), raw.clone()))
+                .collect(),
+        });
+        self.client.store().save_changes(&changes).await?;
+
+        self.notify(events.into_iter().map(|(event, _)| event));
+
+        Ok(())
+    }
+
+    /// Forward presence events to the observers, without persisting them.
+    pub(crate) fn notify(&self, events: impl IntoIterator<Item = PresenceEvent>) {
+        for event in events {
+            self.client.inner.presence_observables.update(event);
+        }
+    }
+}
+
+#[cfg(feature = "experimental-sliding-sync")]
+mod sliding_sync {
+    use matrix_sdk_common::BoxFuture;
+    use ruma::{events::presence::PresenceEvent, serde::Raw};
+    use serde::Deserialize;
+    use serde_json::{json, Map as JsonMap, Value as JsonValue};
+
+    use crate::{sliding_sync::SlidingSyncExtension, Client, Result};
+
+    /// An experimental sliding sync extension to receive presence updates, for
+    /// servers that support it.
+    ///
+    /// Neither MSC4186 nor any other MSC defines a presence extension yet, so
+    /// its name is provided by the caller, and the extension must only be
+    /// enabled if the server advertises it, e.g. in the `unstable_features` of
+    /// its `/versions` response.
+    ///
+    /// The response has the same shape as the `presence` section of a sync v2
+    /// response, i.e. `{"events": [...]}`.
+    #[derive(Debug, Clone)]
+    pub struct PresenceExtension {
+        name: String,
+    }
+
+    impl PresenceExtension {
+        /// Create a new `PresenceExtension` with the given name, used in the
+        /// `extensions` object of requests and responses.
+        pub fn new(name: impl Into<String>) -> Self {
+            Self { name: name.into() }
+        }
+    }
+
+    impl SlidingSyncExtension for PresenceExtension {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn sticky_request(&self) -> JsonMap<String, JsonValue> {
+            let mut request = JsonMap::new();
+            request.insert("enabled".to_owned(), json!(true));
+            request
+        }
+
+        fn handle_response<'a>(
+            &'a self,
+            client: &'a Client,
+            response: Raw<JsonValue>,
+        ) -> BoxFuture<'a, Result<()>> {
+            #[derive(Deserialize)]
+            struct PresenceResponse {
+                #[serde(default)]
+                events: Vec<Raw<PresenceEvent>>,
+            }
+
+            Box::pin(async move {
+                let response = response.deserialize_as::<PresenceResponse>()?;
+                client.presence().handle_presence_events(&response.events).await
+            })
+        }
+    }
+}
+
+#[cfg(feature = "experimental-sliding-sync")]
+pub use sliding_sync::PresenceExtension;
+
+// The http mocking library is not supported for wasm32
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use matrix_sdk_test::async_test;
+    use ruma::{
+        events::presence::{PresenceEvent, PresenceEventContent},
+        presence::PresenceState,
+        user_id,
+    };
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::test_utils::logged_in_client;
+
+    #[async_test]
+    async fn test_fetch_presence_updates_subscribers() {
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+        let user_id = user_id!("@alice:example.org");
+
+        Mock::given(method("GET"))
+            .and(path(format!("/_matrix/client/r0/presence/{user_id}/status")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "presence": "online",
+                "status_msg": "Baking",
+                "currently_active": true,
+            })))
+            .mount(&server)
+            .await;
+
+        let presence = client.presence();
+        let mut subscriber = presence.subscribe(user_id).await.unwrap();
+        assert!(subscriber.get().is_none());
+
+        let content = presence.fetch(user_id).await.unwrap();
+        assert_eq!(content.presence, PresenceState::Online);
+
+        let observed = subscriber.next().await.unwrap().unwrap();
+        assert_eq!(observed.presence, PresenceState::Online);
+        assert_eq!(observed.status_msg.as_deref(), Some("Baking"));
+
+        // It's been persisted too.
+        let stored = presence.get(user_id).await.unwrap().unwrap();
+        assert_eq!(stored.currently_active, Some(true));
+    }
+
+    #[async_test]
+    async fn test_unobserved_presence_is_dropped() {
+        let client = logged_in_client(None).await;
+        let user_id = user_id!("@alice:example.org");
+        let presence = client.presence();
+
+        let subscriber = presence.subscribe(user_id).await.unwrap();
+        drop(subscriber);
+
+        presence.notify([PresenceEvent {
+            content: PresenceEventContent::new(PresenceState::Online),
+            sender: user_id.to_owned(),
+        }]);
+
+        // Nobody observes Alice's presence anymore, so the observable is gone.
+        assert!(client.inner.presence_observables.observables.read().unwrap().is_empty());
+    }
+}
@@ -570,6 +570,46 @@ mod tests {
         assert_eq!(change.len(), 1);
     }
 
+    #[async_test]
+    async fn test_pinning_all_changed_identities_pins_unpinned_members() {
+        // Given a room containing Bob, who is unpinned
+        let t = TestSetup::new_room_with_other_bob().await;
+        t.unpin_bob().await;
+
+        // And we are listening for identity changes
+        let changes = t.subscribe_to_identity_status_changes().await;
+        let mut changes = pin!(changes);
+        let change = next_change(&mut changes).await;
+        assert_eq!(change[0].changed_to, IdentityState::PinViolation);
+
+        // When we acknowledge all the identity changes of the room
+        let pinned = t.pin_all_changed_identities().await;
+
+        // Then Bob got pinned
+        assert_eq!(pinned, vec![t.bob_user_id().to_owned()]);
+        assert!(t.bob_is_pinned().await);
+
+        // And we were notified about it
+        let change = next_change(&mut changes).await;
+        assert_eq!(change[0].user_id, t.bob_user_id());
+        assert_eq!(change[0].changed_to, IdentityState::Pinned);
+        assert_eq!(change.len(), 1);
+    }
+
+    #[async_test]
+    async fn test_pinning_all_changed_identities_ignores_verification_violations() {
+        // Given a room containing Bob, who was verified before his identity changed
+        let t = TestSetup::new_room_with_other_bob().await;
+        t.verify_bob().await;
+        t.unpin_bob().await;
+
+        // When we acknowledge all the identity changes of the room
+        let pinned = t.pin_all_changed_identities().await;
+
+        // Then Bob's identity was left alone
+        assert!(pinned.is_empty());
+    }
+
     // TODO: I (andyb) haven't figured out how to test room membership changes that
     // affect our own user (they should not be shown). Specifically, I haven't
     // figure out how to get out own user into a non-pinned state.
@@ -858,7 +898,14 @@ mod tests {
                 };
             }
 
-            async fn bob_is_pinned(&self) -> bool {
+            pub(super) async fn pin_all_changed_identities(&self) -> Vec<OwnedUserId> {
+                self.room
+                    .pin_all_changed_identities()
+                    .await
+                    .expect("Should be able to pin the changed identities")
+            }
+
+            pub(super) async fn bob_is_pinned(&self) -> bool {
                 !self.bob_crypto_other_identity().await.identity_needs_user_approval()
             }
 
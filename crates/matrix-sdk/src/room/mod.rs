@@ -33,9 +33,22 @@ use futures_util::{
 #[cfg(all(feature = "e2e-encryption", not(target_arch = "wasm32")))]
 pub use identity_status_changes::IdentityStatusChanges;
 #[cfg(feature = "e2e-encryption")]
-use matrix_sdk_base::crypto::{DecryptionSettings, RoomEventDecryptionResult};
+use matrix_sdk_base::crypto::{
+    olm::{is_history_shared, OutboundGroupSessionInfo},
+    types::{
+        events::{
+            room::encrypted::{encrypted_state_key, UNENCRYPTED_STATE_EVENT_TYPES},
+            room_key_bundle::RoomKeyBundleContent,
+            CryptoContextInfo,
+        },
+        RoomKeyBundle,
+    },
+    DecryptionSettings, RoomEventDecryptionResult,
+};
 #[cfg(all(feature = "e2e-encryption", not(target_arch = "wasm32")))]
 use matrix_sdk_base::crypto::{IdentityStatusChange, RoomIdentityProvider, UserIdentity};
+#[cfg(feature = "e2e-encryption")]
+use matrix_sdk_base::deserialized_responses::EncryptionInfo;
 use matrix_sdk_base::{
     deserialized_responses::{
         RawAnySyncOrStrippedState, RawSyncOrStrippedState, SyncOrStrippedState, TimelineEvent,
@@ -99,9 +112,9 @@ use ruma::{
         space::{child::SpaceChildEventContent, parent::SpaceParentEventContent},
         tag::{TagInfo, TagName},
         typing::SyncTypingEvent,
-        AnyRoomAccountDataEvent, AnyRoomAccountDataEventContent, AnyTimelineEvent, EmptyStateKey,
-        Mentions, MessageLikeEventContent, MessageLikeEventType, OriginalSyncStateEvent,
-        RedactContent, RedactedStateEventContent, RoomAccountDataEvent,
+        AnyRoomAccountDataEvent, AnyRoomAccountDataEventContent, AnyStateEventContent,
+        AnyTimelineEvent, EmptyStateKey, Mentions, MessageLikeEventContent, MessageLikeEventType,
+        OriginalSyncStateEvent, RedactContent, RedactedStateEventContent, RoomAccountDataEvent,
         RoomAccountDataEventContent, RoomAccountDataEventType, StateEventContent, StateEventType,
         StaticEventContent, StaticStateEventContent, SyncStateEvent,
     },
@@ -209,12 +222,162 @@ impl Room {
                 false
             });
 
+        // Remember who invited us, the room history they may have shared with us is
+        // imported once we've joined.
+        #[cfg(feature = "e2e-encryption")]
+        let inviter = if prev_room_state == RoomState::Invited {
+            match self.invite_details().await {
+                Ok(invite) => invite.inviter.map(|inviter| inviter.user_id().to_owned()),
+                Err(e) => {
+                    warn!(room_id = ?self.room_id(), "invite_details() failed: {e}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
         self.client.join_room_by_id(self.room_id()).await?;
 
         if mark_as_direct {
             self.set_is_direct(true).await?;
         }
 
+        #[cfg(feature = "e2e-encryption")]
+        if let Some(inviter) = inviter {
+            if let Err(e) = self.import_shared_history(&inviter).await {
+                warn!(
+                    room_id = ?self.room_id(),
+                    "Failed to import the room history shared by the inviter: {e}"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Import the keys of the room's history that the given user shared with
+    /// us when inviting us, if any.
+    ///
+    /// If the data about the bundle hasn't arrived yet, the inviter is
+    /// remembered, so the bundle can be imported when it arrives.
+    #[cfg(feature = "e2e-encryption")]
+    #[instrument(skip_all, fields(room_id = ?self.room_id(), ?inviter))]
+    pub(crate) async fn import_shared_history(&self, inviter: &UserId) -> Result<()> {
+        let bundle_info = {
+            let olm_machine = self.client.olm_machine().await;
+            let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
+            let store = olm_machine.store();
+
+            let bundle_info =
+                store.get_received_room_key_bundle_data(self.room_id(), inviter).await?;
+
+            if bundle_info.is_none() {
+                store.set_pending_room_key_bundle(self.room_id(), inviter).await?;
+            }
+
+            bundle_info
+        };
+
+        let Some(bundle_info) = bundle_info else {
+            debug!("The inviter didn't share the room history with us yet");
+            return Ok(());
+        };
+
+        let request = MediaRequest {
+            source: MediaSource::Encrypted(Box::new(bundle_info.bundle_data.file.clone())),
+            format: MediaFormat::File,
+        };
+        let bundle_content = self.client.media().get_media_content(&request, false).await?;
+        let bundle: RoomKeyBundle = serde_json::from_slice(&bundle_content)?;
+
+        let result = {
+            let olm_machine = self.client.olm_machine().await;
+            let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
+            olm_machine.store().receive_room_key_bundle(&bundle_info, bundle, |_, _| {}).await?
+        };
+
+        self.client.encryption().decrypt_state_events_with_imported_room_keys(&result).await;
+
+        info!(
+            imported_count = result.imported_count,
+            total_count = result.total_count,
+            "Imported the room history shared by the inviter"
+        );
+
+        Ok(())
+    }
+
+    /// Share the keys of this room's history with the given user, so that they
+    /// can decrypt the messages that were sent before they joined.
+    ///
+    /// The keys are bundled, encrypted and uploaded to the media repository,
+    /// and the location and decryption key of the bundle are sent to the
+    /// devices of the user over Olm, as described in [MSC4268]. The devices
+    /// are selected with the [`CollectStrategy`] configured with
+    /// [`ClientBuilder::with_room_key_recipient_strategy()`], like the
+    /// recipients of room keys. The bundle is imported by the user when they
+    /// join the room, or when it arrives if they already joined.
+    ///
+    /// Only the keys of the messages sent while the history visibility of the
+    /// room allowed sharing the history are shared.
+    ///
+    /// This is done automatically when inviting a user to a room whose history
+    /// is visible to invited users, if
+    /// [`EncryptionSettings::share_history_on_invite`] is enabled.
+    ///
+    /// [MSC4268]: https://github.com/matrix-org/matrix-spec-proposals/pull/4268
+    /// [`CollectStrategy`]: matrix_sdk_base::crypto::CollectStrategy
+    /// [`ClientBuilder::with_room_key_recipient_strategy()`]: crate::ClientBuilder::with_room_key_recipient_strategy
+    /// [`EncryptionSettings::share_history_on_invite`]: crate::encryption::EncryptionSettings::share_history_on_invite
+    #[cfg(feature = "e2e-encryption")]
+    #[instrument(skip_all, fields(room_id = ?self.room_id(), ?user_id))]
+    pub async fn share_history(&self, user_id: &UserId) -> Result<()> {
+        let bundle = {
+            let olm_machine = self.client.olm_machine().await;
+            let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
+            olm_machine.store().build_room_key_bundle(self.room_id()).await?
+        };
+
+        if bundle.is_empty() {
+            debug!("No room keys to share");
+            return Ok(());
+        }
+
+        let bundle = serde_json::to_vec(&bundle)?;
+        let file = self
+            .client
+            .upload_encrypted_file(&mime::APPLICATION_OCTET_STREAM, &mut bundle.as_slice())
+            .await?;
+
+        // The user might not share an encrypted room with us yet, so make sure we
+        // track them and know about their devices.
+        {
+            let olm_machine = self.client.olm_machine().await;
+            let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
+            olm_machine.update_tracked_users(std::iter::once(user_id)).await?;
+
+            let (request_id, request) = olm_machine.query_keys_for_users(std::iter::once(user_id));
+            self.client.keys_query(&request_id, request.device_keys).await?;
+        }
+
+        self.client.claim_one_time_keys(std::iter::once(user_id)).await?;
+
+        let request = {
+            let olm_machine = self.client.olm_machine().await;
+            let olm_machine = olm_machine.as_ref().ok_or(Error::NoOlmMachine)?;
+            let content = RoomKeyBundleContent { room_id: self.room_id().to_owned(), file };
+            let strategy = &self.client.base_client().room_key_recipient_strategy;
+            olm_machine.share_room_key_bundle_data(user_id, content, strategy).await?
+        };
+
+        if let Some(request) = request {
+            let response = self.client.send_to_device(&request).await?;
+            self.client.mark_request_as_sent(&request.txn_id, &response).await?;
+        } else {
+            debug!("The user has no device we can share the room history with");
+        }
+
         Ok(())
     }
 
@@ -421,6 +584,55 @@ impl Room {
         IdentityStatusChanges::create_stream(self.clone()).await
     }
 
+    /// Pin the current identity of all the members of this room whose identity
+    /// changed since it was pinned, i.e. the members in
+    /// [`IdentityState::PinViolation`].
+    ///
+    /// This acknowledges all the identity changes of the room at once, once
+    /// the user has been warned about them, for instance with the identity
+    /// change items of the timeline.
+    ///
+    /// The members whose identity was verified before it changed, in
+    /// [`IdentityState::VerificationViolation`], are left alone: they need to
+    /// be verified again, or their verification needs to be withdrawn with
+    /// [`UserIdentity::withdraw_verification()`].
+    ///
+    /// Returns the members whose identity got pinned.
+    ///
+    /// [`IdentityState::PinViolation`]: matrix_sdk_base::crypto::IdentityState::PinViolation
+    /// [`IdentityState::VerificationViolation`]: matrix_sdk_base::crypto::IdentityState::VerificationViolation
+    /// [`UserIdentity::withdraw_verification()`]: crate::encryption::identities::UserIdentity::withdraw_verification
+    #[cfg(feature = "e2e-encryption")]
+    pub async fn pin_all_changed_identities(&self) -> Result<Vec<OwnedUserId>> {
+        let members = self.members(RoomMemberships::JOIN | RoomMemberships::INVITE).await?;
+        let mut pinned = Vec::new();
+
+        for member in members {
+            let user_id = member.user_id();
+            if user_id == self.own_user_id() {
+                continue;
+            }
+
+            let Some(identity) = self.client.encryption().get_user_identity(user_id).await? else {
+                continue;
+            };
+
+            if let matrix_sdk_base::crypto::UserIdentity::Other(identity) =
+                identity.underlying_identity()
+            {
+                if identity.identity_needs_user_approval() && !identity.has_verification_violation()
+                {
+                    identity.pin_current_master_key().await?;
+                    pinned.push(user_id.to_owned());
+                }
+            }
+        }
+
+        debug!(room_id = ?self.room_id(), ?pinned, "Pinned the changed identities of the room members");
+
+        Ok(pinned)
+    }
+
     /// Returns a wrapping `TimelineEvent` for the input `AnyTimelineEvent`,
     /// decrypted if needs be.
     ///
@@ -1259,6 +1471,82 @@ impl Room {
         Ok(event)
     }
 
+    /// Get the current [`EncryptionInfo`] of the events sent by `sender` in
+    /// this room, which were encrypted with the Megolm session with the given
+    /// ID.
+    ///
+    /// Unlike the [`EncryptionInfo`] computed when the events were decrypted,
+    /// this takes the current verification status of the sender into account.
+    #[cfg(feature = "e2e-encryption")]
+    pub async fn get_encryption_info(
+        &self,
+        session_id: &str,
+        sender: &UserId,
+    ) -> Result<EncryptionInfo> {
+        let machine = self.client.olm_machine().await;
+        let machine = machine.as_ref().ok_or(Error::NoOlmMachine)?;
+
+        Ok(machine.get_session_encryption_info(self.room_id(), session_id, sender).await?)
+    }
+
+    /// Gather information about our own device and the key backup, which is
+    /// used to explain why an event in this room couldn't be decrypted.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_id` - The ID of the Megolm session the undecryptable event
+    ///   was encrypted with, if known. It is used to look up whether the sender
+    ///   withheld the room key from us.
+    ///
+    /// This never contacts the homeserver: whether a key backup exists on the
+    /// server is only known if it has been found out before, e.g. with
+    /// [`Backups::fast_exists_on_server()`], and reported as unknown otherwise.
+    ///
+    /// See [`UtdCause::determine()`] for how this information is used.
+    ///
+    /// [`UtdCause::determine()`]: matrix_sdk_base::crypto::types::events::UtdCause::determine
+    /// [`Backups::fast_exists_on_server()`]: crate::encryption::backups::Backups::fast_exists_on_server
+    #[cfg(feature = "e2e-encryption")]
+    pub async fn crypto_context_info(&self, session_id: Option<&str>) -> CryptoContextInfo {
+        let encryption = self.client.encryption();
+
+        let (device_creation_ts, withheld_code) = {
+            let machine = self.client.olm_machine().await;
+
+            match machine.as_ref() {
+                Some(machine) => {
+                    let withheld_code = match session_id {
+                        Some(session_id) => machine
+                            .store()
+                            .get_withheld_info(self.room_id(), session_id)
+                            .await
+                            .ok()
+                            .flatten()
+                            .map(|event| event.content.withheld_code()),
+                        None => None,
+                    };
+
+                    (machine.device_creation_time(), withheld_code)
+                }
+                None => (ruma::MilliSecondsSinceUnixEpoch::now(), None),
+            }
+        };
+
+        let backups = encryption.backups();
+        let is_backup_configured = backups.are_enabled().await;
+        let backup_exists_on_server = CryptoContextInfo::known_backup_existence(
+            is_backup_configured,
+            backups.cached_exists_on_server(),
+        );
+
+        CryptoContextInfo {
+            device_creation_ts,
+            is_backup_configured,
+            backup_exists_on_server,
+            withheld_code,
+        }
+    }
+
     /// Forces the currently active room key, which is used to encrypt messages,
     /// to be rotated.
     ///
@@ -1282,6 +1570,19 @@ impl Room {
         }
     }
 
+    /// Get a snapshot of the room key currently used to encrypt messages in
+    /// this room: when it was created, how many messages it encrypted, and
+    /// which devices received it or had it withheld.
+    ///
+    /// Returns `None` if no room key was created for this room yet, e.g.
+    /// because no encrypted message was sent.
+    #[cfg(feature = "e2e-encryption")]
+    pub async fn outbound_group_session_info(&self) -> Result<Option<OutboundGroupSessionInfo>> {
+        let machine = self.client.olm_machine().await;
+        let machine = machine.as_ref().ok_or(Error::NoOlmMachine)?;
+        Ok(machine.outbound_group_session_info(self.inner.room_id()).await)
+    }
+
     /// Ban the user with `UserId` from this room.
     ///
     /// # Arguments
@@ -1350,6 +1651,16 @@ impl Room {
         // but before the /sync request could fetch the membership change event.
         self.mark_members_missing();
 
+        #[cfg(feature = "e2e-encryption")]
+        if self.client.inner.e2ee.encryption_settings.share_history_on_invite
+            && self.inner.is_encrypted()
+            && is_history_shared(&self.inner.history_visibility())
+        {
+            if let Err(e) = self.share_history(user_id).await {
+                warn!("Failed to share the room history with the invited user: {e}");
+            }
+        }
+
         Ok(())
     }
 
@@ -2362,11 +2673,9 @@ impl Room {
         C::StateKey: Borrow<K>,
         K: AsRef<str> + ?Sized,
     {
-        self.ensure_room_joined()?;
-        let request =
-            send_state_event::v3::Request::new(self.room_id().to_owned(), state_key, &content)?;
-        let response = self.client.send(request, None).await?;
-        Ok(response)
+        let event_type = content.event_type().to_string();
+        let content = Raw::new(&content)?.cast();
+        self.send_state_event_inner(&event_type, state_key.as_ref(), content).await
     }
 
     /// Send a raw room state event to the homeserver.
@@ -2409,14 +2718,60 @@ impl Room {
         event_type: &str,
         state_key: &str,
         content: impl IntoRawStateEventContent,
+    ) -> Result<send_state_event::v3::Response> {
+        self.send_state_event_inner(event_type, state_key, content.into_raw_state_event_content())
+            .await
+    }
+
+    /// Send a state event to the homeserver, encrypting it first if
+    /// [`EncryptionSettings::encrypt_state_events`] is enabled and the room is
+    /// encrypted.
+    ///
+    /// [`EncryptionSettings::encrypt_state_events`]: crate::encryption::EncryptionSettings::encrypt_state_events
+    async fn send_state_event_inner(
+        &self,
+        event_type: &str,
+        state_key: &str,
+        content: Raw<AnyStateEventContent>,
     ) -> Result<send_state_event::v3::Response> {
         self.ensure_room_joined()?;
 
+        #[cfg(feature = "e2e-encryption")]
+        if self.client.inner.e2ee.encryption_settings.encrypt_state_events
+            && !UNENCRYPTED_STATE_EVENT_TYPES.contains(&event_type)
+            && self.is_encrypted().await?
+        {
+            debug!("Sending encrypted state event because the room is encrypted.");
+
+            if !self.are_members_synced() {
+                self.sync_members().await?;
+            }
+
+            self.query_keys_for_untracked_users().await?;
+            self.preshare_room_key().await?;
+
+            let olm = self.client.olm_machine().await;
+            let olm = olm.as_ref().expect("Olm machine wasn't started");
+
+            let content = olm
+                .encrypt_state_event_raw(self.room_id(), event_type, state_key, &content)
+                .await?;
+
+            let request = send_state_event::v3::Request::new_raw(
+                self.room_id().to_owned(),
+                "m.room.encrypted".into(),
+                encrypted_state_key(event_type, state_key),
+                content.cast(),
+            );
+
+            return Ok(self.client.send(request, None).await?);
+        }
+
         let request = send_state_event::v3::Request::new_raw(
             self.room_id().to_owned(),
             event_type.into(),
             state_key.to_owned(),
-            content.into_raw_state_event_content(),
+            content,
         );
 
         Ok(self.client.send(request, None).await?)
@@ -12,6 +12,7 @@ use tokio::sync::{broadcast::channel, Mutex as AsyncMutex, RwLock as AsyncRwLock
 
 use super::{
     cache::{format_storage_key_prefix, restore_sliding_sync_state},
+    extensions::{CustomExtensions, SlidingSyncExtension, BUILT_IN_EXTENSIONS},
     sticky_parameters::SlidingSyncStickyManager,
     Error, SlidingSync, SlidingSyncInner, SlidingSyncListBuilder, SlidingSyncPositionMarkers,
     Version,
@@ -30,6 +31,7 @@ pub struct SlidingSyncBuilder {
     client: Client,
     lists: Vec<SlidingSyncListBuilder>,
     extensions: Option<http::request::Extensions>,
+    custom_extensions: Vec<Arc<dyn SlidingSyncExtension>>,
     subscriptions: BTreeMap<OwnedRoomId, http::request::RoomSubscription>,
     poll_timeout: Duration,
     network_timeout: Duration,
@@ -52,6 +54,7 @@ impl SlidingSyncBuilder {
                 client,
                 lists: Vec::new(),
                 extensions: None,
+                custom_extensions: Vec::new(),
                 subscriptions: BTreeMap::new(),
                 poll_timeout: Duration::from_secs(30),
                 network_timeout: Duration::from_secs(30),
@@ -185,6 +188,28 @@ impl SlidingSyncBuilder {
         self
     }
 
+    /// Add a custom extension.
+    ///
+    /// See [`SlidingSyncExtension`] to learn more.
+    ///
+    /// Returns an error if the name of the extension is the one of a built-in
+    /// extension, or of a custom extension that has already been added.
+    pub fn with_extension(
+        mut self,
+        extension: impl SlidingSyncExtension + 'static,
+    ) -> Result<Self, Error> {
+        let name = extension.name();
+
+        if BUILT_IN_EXTENSIONS.contains(&name)
+            || self.custom_extensions.iter().any(|existing| existing.name() == name)
+        {
+            return Err(Error::ExtensionNameCollision(name.to_owned()));
+        }
+
+        self.custom_extensions.push(Arc::new(extension));
+        Ok(self)
+    }
+
     /// Sets a custom timeout duration for the sliding sync polling endpoint.
     ///
     /// This is the maximum time to wait before the sliding sync server returns
@@ -288,6 +313,7 @@ impl SlidingSyncBuilder {
                     self.extensions.unwrap_or_default(),
                 ),
             )),
+            custom_extensions: StdRwLock::new(CustomExtensions::new(self.custom_extensions)),
 
             internal_channel: internal_channel_sender,
 
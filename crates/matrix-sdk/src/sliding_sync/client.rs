@@ -298,6 +298,9 @@ impl<'a> SlidingSyncResponseProcessor<'a> {
             // Some new keys might have been received, so trigger a backup if needed.
             self.client.encryption().backups().maybe_trigger_backup();
 
+            // The room key bundle of a room we joined might have arrived.
+            self.client.import_pending_room_key_bundles(&to_device_events).await;
+
             to_device_events
         } else {
             Vec::new()
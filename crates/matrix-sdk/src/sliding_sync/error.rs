@@ -41,6 +41,11 @@ pub enum Error {
     #[error("SlidingSync's internal channel is broken")]
     InternalChannelIsBroken,
 
+    /// A custom extension has the same name as a built-in extension, or as
+    /// another custom extension.
+    #[error("The name of the custom extension `{0}` is already used by another extension")]
+    ExtensionNameCollision(String),
+
     /// The name of the Sliding Sync instance is too long.
     #[error("The Sliding Sync instance's identifier must be less than 16 chars long")]
     InvalidSlidingSyncIdentifier,
@@ -0,0 +1,285 @@
+//! Custom sliding sync extensions.
+//!
+//! Sliding sync has a few built-in extensions (`e2ee`, `to_device`,
+//! `account_data`, `typing`, `receipts`), which are configured on the
+//! [`SlidingSyncBuilder`](super::SlidingSyncBuilder) directly. Other
+//! extensions, for instance ones defined by an MSC that isn't supported by
+//! Ruma yet, can be implemented outside of the SDK with the
+//! [`SlidingSyncExtension`] trait.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use bytes::BufMut;
+use matrix_sdk_common::{AsyncTraitDeps, BoxFuture};
+use ruma::{
+    api::{
+        error::{FromHttpResponseError, IntoHttpError},
+        IncomingResponse, MatrixVersion, Metadata, OutgoingRequest, SendAccessToken,
+    },
+    serde::Raw,
+    TransactionId,
+};
+use serde::Deserialize;
+use serde_json::{Map as JsonMap, Value as JsonValue};
+
+use super::sticky_parameters::{LazyTransactionId, SlidingSyncStickyManager, StickyData};
+use crate::{Client, Result};
+
+/// The names of the extensions supported by Ruma, which can't be used by a
+/// custom extension.
+pub(super) const BUILT_IN_EXTENSIONS: &[&str] =
+    &["to_device", "e2ee", "account_data", "receipts", "typing"];
+
+/// A custom sliding sync extension.
+///
+/// An extension contributes a payload to the `extensions` object of every
+/// sliding sync request, under the key returned by [`Self::name`], and
+/// receives the value found under the same key in the `extensions` object of
+/// the responses.
+///
+/// The payload returned by [`Self::sticky_request`] is a sticky parameter: it
+/// is sent once, and then only after the sliding sync session has expired, or
+/// after the extension has been invalidated with
+/// [`SlidingSync::invalidate_extension`](super::SlidingSync::invalidate_extension).
+/// Values that must be sent with every request (e.g. a `since` token) must be
+/// returned by [`Self::request`].
+///
+/// Implementations are expected to keep their own state, if they need some,
+/// with interior mutability.
+pub trait SlidingSyncExtension: AsyncTraitDeps {
+    /// The name of this extension, i.e. its key in the `extensions` objects of
+    /// the requests and the responses.
+    ///
+    /// It must not collide with one of the built-in extensions, or with
+    /// another custom extension.
+    fn name(&self) -> &str;
+
+    /// The sticky configuration of this extension, e.g. `{"enabled": true}`.
+    fn sticky_request(&self) -> JsonMap<String, JsonValue>;
+
+    /// The non-sticky part of the request, that is sent with every request.
+    ///
+    /// It is merged with the sticky configuration, if it has to be sent too.
+    fn request(&self) -> JsonMap<String, JsonValue> {
+        JsonMap::new()
+    }
+
+    /// Handle the part of the response that belongs to this extension.
+    ///
+    /// This is only called if the response contains something for this
+    /// extension.
+    fn handle_response<'a>(
+        &'a self,
+        client: &'a Client,
+        response: Raw<JsonValue>,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// The sliding sync session has expired, and the sticky configuration of
+    /// this extension is going to be sent again.
+    ///
+    /// Extensions can use this to reset their state, if it's tied to the
+    /// session.
+    fn on_session_expired(&self) {}
+}
+
+/// Sticky data for a single custom extension.
+#[derive(Debug)]
+pub(super) struct CustomExtension {
+    extension: Arc<dyn SlidingSyncExtension>,
+}
+
+impl StickyData for CustomExtension {
+    type Request = BTreeMap<String, JsonMap<String, JsonValue>>;
+
+    fn apply(&self, request: &mut Self::Request) {
+        request.insert(self.extension.name().to_owned(), self.extension.sticky_request());
+    }
+}
+
+/// All the custom extensions of a sliding sync instance, each one having its
+/// own sticky parameters.
+#[derive(Debug, Default)]
+pub(super) struct CustomExtensions {
+    extensions: BTreeMap<String, SlidingSyncStickyManager<CustomExtension>>,
+}
+
+impl CustomExtensions {
+    /// Create a new set of custom extensions.
+    ///
+    /// The names of the extensions are expected to be unique, this is checked
+    /// by [`SlidingSyncBuilder::with_extension`](super::SlidingSyncBuilder::with_extension).
+    pub fn new(extensions: Vec<Arc<dyn SlidingSyncExtension>>) -> Self {
+        Self {
+            extensions: extensions
+                .into_iter()
+                .map(|extension| {
+                    (
+                        extension.name().to_owned(),
+                        SlidingSyncStickyManager::new(CustomExtension { extension }),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether there is no custom extension at all.
+    pub fn is_empty(&self) -> bool {
+        self.extensions.is_empty()
+    }
+
+    /// Compute the payload of all the custom extensions for the next request.
+    ///
+    /// Extensions with nothing to send are omitted.
+    pub fn generate_request(
+        &mut self,
+        txn_id: &mut LazyTransactionId,
+    ) -> BTreeMap<String, JsonMap<String, JsonValue>> {
+        let mut request = BTreeMap::new();
+
+        for (name, sticky) in self.extensions.iter_mut() {
+            sticky.maybe_apply(&mut request, txn_id);
+
+            let non_sticky = sticky.data().extension.request();
+
+            if !non_sticky.is_empty() {
+                request.entry(name.clone()).or_default().extend(non_sticky);
+            }
+        }
+
+        request
+    }
+
+    /// Commit the sticky parameters of the extensions, if the transaction id
+    /// matches the one of the request they were sent with.
+    pub fn maybe_commit(&mut self, txn_id: &TransactionId) {
+        for sticky in self.extensions.values_mut() {
+            sticky.maybe_commit(txn_id);
+        }
+    }
+
+    /// Invalidate the sticky parameters of the extension with the given name.
+    ///
+    /// Returns `false` if there's no such extension.
+    pub fn invalidate(&mut self, name: &str) -> bool {
+        match self.extensions.get_mut(name) {
+            Some(sticky) => {
+                sticky.data_mut();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The session has expired: invalidate all the sticky parameters, and let
+    /// the extensions know.
+    pub fn expire_session(&mut self) {
+        for sticky in self.extensions.values_mut() {
+            sticky.data_mut().extension.on_session_expired();
+        }
+    }
+
+    /// Get the extensions that have some data in the response.
+    pub fn extensions_for_response(
+        &self,
+        mut response: BTreeMap<String, Raw<JsonValue>>,
+    ) -> Vec<(Arc<dyn SlidingSyncExtension>, Raw<JsonValue>)> {
+        self.extensions
+            .iter()
+            .filter_map(|(name, sticky)| {
+                Some((sticky.data().extension.clone(), response.remove(name)?))
+            })
+            .collect()
+    }
+}
+
+/// A sliding sync request, along with the payload of the custom extensions.
+///
+/// The custom payloads are merged into the `extensions` object of the request
+/// body when it's serialized.
+#[derive(Clone, Debug)]
+pub(super) struct RequestWithCustomExtensions<R> {
+    pub request: R,
+    pub extensions: BTreeMap<String, JsonMap<String, JsonValue>>,
+}
+
+impl<R> OutgoingRequest for RequestWithCustomExtensions<R>
+where
+    R: OutgoingRequest,
+{
+    type EndpointError = R::EndpointError;
+    type IncomingResponse = ResponseWithCustomExtensions<R::IncomingResponse>;
+
+    const METADATA: Metadata = R::METADATA;
+
+    fn try_into_http_request<T: Default + BufMut>(
+        self,
+        base_url: &str,
+        access_token: SendAccessToken<'_>,
+        considering_versions: &[MatrixVersion],
+    ) -> Result<http::Request<T>, IntoHttpError> {
+        let request = self.request.try_into_http_request::<Vec<u8>>(
+            base_url,
+            access_token,
+            considering_versions,
+        )?;
+
+        let (parts, body) = request.into_parts();
+
+        let body = if self.extensions.is_empty() {
+            body
+        } else {
+            let mut json_body: JsonMap<String, JsonValue> = serde_json::from_slice(&body)?;
+
+            let extensions =
+                json_body.entry("extensions").or_insert_with(|| JsonValue::Object(JsonMap::new()));
+
+            if let JsonValue::Object(extensions) = extensions {
+                extensions.extend(
+                    self.extensions
+                        .into_iter()
+                        .map(|(name, payload)| (name, JsonValue::Object(payload))),
+                );
+            }
+
+            serde_json::to_vec(&json_body)?
+        };
+
+        let mut new_body = T::default();
+        new_body.put_slice(&body);
+
+        Ok(http::Request::from_parts(parts, new_body))
+    }
+}
+
+/// A sliding sync response, along with the raw `extensions` object, so that
+/// custom extensions can read their part of it.
+#[derive(Debug)]
+pub(super) struct ResponseWithCustomExtensions<R> {
+    pub response: R,
+    pub extensions: BTreeMap<String, Raw<JsonValue>>,
+}
+
+impl<R> IncomingResponse for ResponseWithCustomExtensions<R>
+where
+    R: IncomingResponse,
+{
+    type EndpointError = R::EndpointError;
+
+    fn try_from_http_response<T: AsRef<[u8]>>(
+        response: http::Response<T>,
+    ) -> Result<Self, FromHttpResponseError<Self::EndpointError>> {
+        #[derive(Deserialize)]
+        struct RawExtensions {
+            #[serde(default)]
+            extensions: BTreeMap<String, Raw<JsonValue>>,
+        }
+
+        // Errors are handled by the inner response, so it's fine to default to
+        // nothing here.
+        let extensions = serde_json::from_slice::<RawExtensions>(response.body().as_ref())
+            .map(|raw| raw.extensions)
+            .unwrap_or_default();
+
+        Ok(Self { response: R::try_from_http_response(response)?, extensions })
+    }
+}
@@ -19,6 +19,7 @@ mod builder;
 mod cache;
 mod client;
 mod error;
+mod extensions;
 mod list;
 mod room;
 mod sticky_parameters;
@@ -39,7 +40,9 @@ pub use matrix_sdk_base::sliding_sync::http;
 use matrix_sdk_common::{deserialized_responses::SyncTimelineEvent, timer};
 use ruma::{
     api::{client::error::ErrorKind, OutgoingRequest},
-    assign, OwnedEventId, OwnedRoomId, RoomId,
+    assign,
+    serde::Raw,
+    OwnedEventId, OwnedRoomId, RoomId,
 };
 use serde::{Deserialize, Serialize};
 use tokio::{
@@ -50,10 +53,14 @@ use tracing::{debug, error, info, instrument, trace, warn, Instrument, Span};
 
 #[cfg(feature = "e2e-encryption")]
 use self::utils::JoinHandleExt as _;
-pub use self::{builder::*, client::VersionBuilderError, error::*, list::*, room::*};
+pub use self::{
+    builder::*, client::VersionBuilderError, error::*, extensions::SlidingSyncExtension, list::*,
+    room::*,
+};
 use self::{
     cache::restore_sliding_sync_state,
     client::SlidingSyncResponseProcessor,
+    extensions::{CustomExtensions, RequestWithCustomExtensions},
     sticky_parameters::{LazyTransactionId, SlidingSyncStickyManager, StickyData},
 };
 use crate::{config::RequestConfig, Client, HttpError, Result};
@@ -122,6 +129,9 @@ pub(super) struct SlidingSyncInner {
     /// Request parameters that are sticky.
     sticky: StdRwLock<SlidingSyncStickyManager<SlidingSyncStickyParameters>>,
 
+    /// Custom extensions, each one with its own sticky parameters.
+    custom_extensions: StdRwLock<CustomExtensions>,
+
     /// Internal channel used to pass messages between Sliding Sync and other
     /// types.
     internal_channel: Sender<SlidingSyncInternalMessage>,
@@ -184,6 +194,24 @@ impl SlidingSync {
         }
     }
 
+    /// Invalidate the sticky parameters of the custom extension with the given
+    /// name, so that its sticky configuration is sent again with the next
+    /// request.
+    ///
+    /// Extensions should be invalidated when their configuration changes.
+    /// Returns `false` if there's no such extension.
+    pub fn invalidate_extension(&self, name: &str, cancel_in_flight_request: bool) -> bool {
+        let found = self.inner.custom_extensions.write().unwrap().invalidate(name);
+
+        if found && cancel_in_flight_request {
+            self.inner.internal_channel_send_if_possible(
+                SlidingSyncInternalMessage::SyncLoopSkipOverCurrentIteration,
+            );
+        }
+
+        found
+    }
+
     /// Lookup a specific room
     pub async fn get_room(&self, room_id: &RoomId) -> Option<SlidingSyncRoom> {
         self.inner.rooms.read().await.get(room_id).cloned()
@@ -429,6 +457,37 @@ impl SlidingSync {
         Ok(update_summary)
     }
 
+    /// Handle the custom extensions' parts of a response.
+    ///
+    /// Errors from the extensions are logged, but don't interrupt the sync
+    /// loop.
+    async fn handle_custom_extensions_response(
+        &self,
+        txn_id: Option<&str>,
+        extensions: BTreeMap<String, Raw<serde_json::Value>>,
+    ) {
+        let extensions = {
+            let mut custom_extensions = self.inner.custom_extensions.write().unwrap();
+
+            if custom_extensions.is_empty() {
+                return;
+            }
+
+            // Commit sticky parameters, if needed.
+            if let Some(txn_id) = txn_id {
+                custom_extensions.maybe_commit(txn_id.into());
+            }
+
+            custom_extensions.extensions_for_response(extensions)
+        };
+
+        for (extension, response) in extensions {
+            if let Err(error) = extension.handle_response(&self.inner.client, response).await {
+                error!(name = extension.name(), ?error, "Failed to handle a custom extension");
+            }
+        }
+    }
+
     async fn generate_sync_request(
         &self,
         txn_id: &mut LazyTransactionId,
@@ -562,6 +621,7 @@ impl SlidingSync {
     async fn send_sync_request<Request>(
         &self,
         request: Request,
+        custom_extensions: BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
         request_config: RequestConfig,
         mut position_guard: OwnedMutexGuard<SlidingSyncPositionMarkers>,
     ) -> Result<UpdateSummary>
@@ -577,7 +637,8 @@ impl SlidingSync {
     {
         debug!("Sending request");
 
-        // Prepare the request.
+        // Prepare the request, along with the custom extensions.
+        let request = RequestWithCustomExtensions { request, extensions: custom_extensions };
         let request =
             self.inner.client.send(request, Some(request_config)).with_homeserver_override(
                 self.inner.version.overriding_url().map(ToString::to_string),
@@ -640,10 +701,13 @@ impl SlidingSync {
         #[cfg(not(feature = "e2e-encryption"))]
         let response = request.await?;
 
+        // Split the custom extensions' data from the response.
+        let custom_extensions = response.extensions;
+
         // The code manipulates `Request` and `Response` from MSC4186 because it's the
         // future standard. But this function may have received a `Request` from MSC4186
         // or MSC3575. We need to get back an MSC4186 `Response`.
-        let response = Into::<http::msc4186::Response>::into(response);
+        let response = Into::<http::msc4186::Response>::into(response.response);
 
         debug!("Received response");
 
@@ -667,9 +731,14 @@ impl SlidingSync {
             // ensure responses are handled one at a time. At this point we still own
             // `position_guard`, so we're fine.
 
+            let txn_id = response.txn_id.clone();
+
             // Handle the response.
             let updates = this.handle_response(response, &mut position_guard).await?;
 
+            // Handle the custom extensions.
+            this.handle_custom_extensions_response(txn_id.as_deref(), custom_extensions).await;
+
             this.cache_to_storage(&position_guard).await?;
 
             // Release the position guard lock.
@@ -705,8 +774,15 @@ impl SlidingSync {
 
     #[instrument(skip_all, fields(pos))]
     async fn sync_once(&self) -> Result<UpdateSummary> {
+        let mut txn_id = LazyTransactionId::new();
+
+        // Generate the custom extensions first, so that the transaction id is known
+        // when generating the request, if the custom extensions need one.
+        let custom_extensions =
+            self.inner.custom_extensions.write().unwrap().generate_request(&mut txn_id);
+
         let (request, request_config, position_guard) =
-            self.generate_sync_request(&mut LazyTransactionId::new()).await?;
+            self.generate_sync_request(&mut txn_id).await?;
 
         // The code manipulates `Request` and `Response` from MSC4186 because it's
         // the future standard (at the time of writing: 2024-09-09). Let's check if
@@ -714,12 +790,14 @@ impl SlidingSync {
         let summaries = if !self.inner.version.is_native() {
             self.send_sync_request(
                 Into::<http::msc3575::Request>::into(request),
+                custom_extensions,
                 request_config,
                 position_guard,
             )
             .await?
         } else {
-            self.send_sync_request(request, request_config, position_guard).await?
+            self.send_sync_request(request, custom_extensions, request_config, position_guard)
+                .await?
         };
 
         // Notify a new sync was received
@@ -839,6 +917,8 @@ impl SlidingSync {
             sticky.data_mut().room_subscriptions.clear();
         }
 
+        self.inner.custom_extensions.write().unwrap().expire_session();
+
         self.inner.lists.read().await.values().for_each(|list| list.invalidate_sticky_data());
     }
 }
@@ -1102,7 +1182,7 @@ mod tests {
     use assert_matches::assert_matches;
     use event_listener::Listener;
     use futures_util::{future::join_all, pin_mut, StreamExt};
-    use matrix_sdk_common::deserialized_responses::SyncTimelineEvent;
+    use matrix_sdk_common::{deserialized_responses::SyncTimelineEvent, BoxFuture};
     use matrix_sdk_test::async_test;
     use ruma::{
         api::client::error::ErrorKind, assign, owned_room_id, room_id, serde::Raw, uint,
@@ -1116,11 +1196,13 @@ mod tests {
     use super::{
         compute_limited, http,
         sticky_parameters::{LazyTransactionId, SlidingSyncStickyManager},
-        FrozenSlidingSync, SlidingSync, SlidingSyncList, SlidingSyncListBuilder, SlidingSyncMode,
-        SlidingSyncRoom, SlidingSyncStickyParameters, Version,
+        Error, FrozenSlidingSync, SlidingSync, SlidingSyncExtension, SlidingSyncList,
+        SlidingSyncListBuilder, SlidingSyncMode, SlidingSyncRoom, SlidingSyncStickyParameters,
+        Version,
     };
     use crate::{
-        sliding_sync::cache::restore_sliding_sync_state, test_utils::logged_in_client, Result,
+        sliding_sync::cache::restore_sliding_sync_state, test_utils::logged_in_client, Client,
+        Result,
     };
 
     #[derive(Copy, Clone)]
@@ -3083,6 +3165,135 @@ mod tests {
         Ok(())
     }
 
+    #[async_test]
+    async fn test_custom_extension() -> Result<()> {
+        #[derive(Debug, Default)]
+        struct TestExtension {
+            responses: Mutex<Vec<serde_json::Value>>,
+        }
+
+        impl SlidingSyncExtension for Arc<TestExtension> {
+            fn name(&self) -> &str {
+                "org.example.test"
+            }
+
+            fn sticky_request(&self) -> serde_json::Map<String, serde_json::Value> {
+                assert_matches!(json!({ "enabled": true }), serde_json::Value::Object(map) => map)
+            }
+
+            fn request(&self) -> serde_json::Map<String, serde_json::Value> {
+                let since = self.responses.lock().unwrap().len().to_string();
+                assert_matches!(json!({ "since": since }), serde_json::Value::Object(map) => map)
+            }
+
+            fn handle_response<'a>(
+                &'a self,
+                _client: &'a Client,
+                response: Raw<serde_json::Value>,
+            ) -> BoxFuture<'a, Result<()>> {
+                Box::pin(async move {
+                    self.responses.lock().unwrap().push(response.deserialize()?);
+                    Ok(())
+                })
+            }
+        }
+
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+
+        let extension = Arc::new(TestExtension::default());
+        let sliding_sync =
+            client.sliding_sync("test")?.with_extension(extension.clone())?.build().await?;
+
+        let _mock_guard = Mock::given(SlidingSyncMatcher)
+            .respond_with(|request: &Request| {
+                let request: serde_json::Value = request.body_json().unwrap();
+                let txn_id = request.get("txn_id").cloned();
+
+                ResponseTemplate::new(200).set_body_json(json!({
+                    "pos": "0",
+                    "txn_id": txn_id,
+                    "lists": {},
+                    "rooms": {},
+                    "extensions": {
+                        "org.example.test": {
+                            "echo": request["extensions"]["org.example.test"],
+                        },
+                    },
+                }))
+            })
+            .mount_as_scoped(&server)
+            .await;
+
+        sliding_sync.sync_once().await?;
+        sliding_sync.sync_once().await?;
+
+        // The sticky configuration has been sent only once, and the non-sticky part
+        // has been sent every time.
+        assert_eq!(
+            *extension.responses.lock().unwrap(),
+            vec![
+                json!({ "echo": { "enabled": true, "since": "0" } }),
+                json!({ "echo": { "since": "1" } }),
+            ]
+        );
+
+        // Invalidating the extension sends its sticky configuration again.
+        assert!(sliding_sync.invalidate_extension("org.example.test", false));
+        assert!(!sliding_sync.invalidate_extension("org.example.unknown", false));
+
+        sliding_sync.sync_once().await?;
+
+        assert_eq!(
+            extension.responses.lock().unwrap().last(),
+            Some(&json!({ "echo": { "enabled": true, "since": "2" } }))
+        );
+
+        Ok(())
+    }
+
+    #[async_test]
+    async fn test_custom_extension_name_collisions_are_rejected() -> Result<()> {
+        #[derive(Debug)]
+        struct NamedExtension(&'static str);
+
+        impl SlidingSyncExtension for NamedExtension {
+            fn name(&self) -> &str {
+                self.0
+            }
+
+            fn sticky_request(&self) -> serde_json::Map<String, serde_json::Value> {
+                serde_json::Map::new()
+            }
+
+            fn handle_response<'a>(
+                &'a self,
+                _client: &'a Client,
+                _response: Raw<serde_json::Value>,
+            ) -> BoxFuture<'a, Result<()>> {
+                Box::pin(async { Ok(()) })
+            }
+        }
+
+        let server = MockServer::start().await;
+        let client = logged_in_client(Some(server.uri())).await;
+
+        // A built-in extension can't be replaced.
+        assert_matches!(
+            client.sliding_sync("test")?.with_extension(NamedExtension("e2ee")),
+            Err(Error::ExtensionNameCollision(name)) => assert_eq!(name, "e2ee")
+        );
+
+        // Neither can another custom extension.
+        let builder = client.sliding_sync("test")?.with_extension(NamedExtension("org.example"))?;
+        assert_matches!(
+            builder.with_extension(NamedExtension("org.example")),
+            Err(Error::ExtensionNameCollision(name)) => assert_eq!(name, "org.example")
+        );
+
+        Ok(())
+    }
+
     #[async_test]
     async fn test_sync_beat_is_notified_on_sync_response() -> Result<()> {
         let server = MockServer::start().await;
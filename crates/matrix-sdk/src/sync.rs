@@ -151,6 +151,10 @@ impl Client {
         #[cfg(feature = "e2e-encryption")]
         self.encryption().backups().maybe_trigger_backup();
 
+        // The room key bundle of a room we joined might have arrived.
+        #[cfg(feature = "e2e-encryption")]
+        self.import_pending_room_key_bundles(&response.to_device).await;
+
         self.call_sync_response_handlers(&response).await?;
 
         Ok(response)
@@ -172,6 +176,7 @@ impl Client {
         let now = Instant::now();
         self.handle_sync_events(HandlerKind::GlobalAccountData, None, account_data).await?;
         self.handle_sync_events(HandlerKind::Presence, None, presence).await?;
+        self.presence().notify(presence.iter().filter_map(|event| event.deserialize().ok()));
         self.handle_sync_events(HandlerKind::ToDevice, None, to_device).await?;
 
         // Ignore errors when there are no receivers.
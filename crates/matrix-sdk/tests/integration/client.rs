@@ -645,6 +645,62 @@ async fn test_cross_signing_status() {
     server.verify().await;
 }
 
+#[cfg(feature = "e2e-encryption")]
+#[async_test]
+async fn test_rotate_cross_signing_keys() {
+    use matrix_sdk::encryption::CrossSigningKeyRotation;
+
+    let (client, server) = logged_in_client_with_server().await;
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/unstable/keys/device_signing/upload"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/unstable/keys/signatures/upload"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "failures": {}
+        })))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/keys/upload"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "one_time_key_counts": {}
+        })))
+        .mount(&server)
+        .await;
+
+    client.encryption().bootstrap_cross_signing(None).await.unwrap();
+
+    let export_keys = || async {
+        let olm_machine = client.olm_machine_for_testing().await;
+        olm_machine.as_ref().unwrap().export_cross_signing_keys().await.unwrap().unwrap()
+    };
+    let previous = export_keys().await;
+
+    client
+        .encryption()
+        .rotate_cross_signing_keys(CrossSigningKeyRotation::SelfSigning, None)
+        .await
+        .unwrap();
+
+    let rotated = export_keys().await;
+    assert_eq!(rotated.master_key, previous.master_key);
+    assert_ne!(rotated.self_signing_key, previous.self_signing_key);
+    assert_eq!(rotated.user_signing_key, previous.user_signing_key);
+
+    let status = client.encryption().cross_signing_status().await.unwrap();
+    assert!(status.is_complete());
+
+    server.verify().await;
+}
+
 #[cfg(feature = "e2e-encryption")]
 #[async_test]
 async fn test_encrypt_room_event() {
@@ -1,7 +1,10 @@
 mod backups;
 mod cross_signing;
 mod recovery;
+mod room_keys;
 mod secret_storage;
+mod state_events;
+mod to_device;
 mod verification;
 
 /// The backup key, which is also returned (encrypted) as part of the secret
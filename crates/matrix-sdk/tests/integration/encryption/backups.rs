@@ -12,7 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{fs::File, io::Write, sync::Arc, time::Duration};
+use std::{
+    fs::File,
+    io::Write,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
 use assert_matches::assert_matches;
@@ -25,7 +30,7 @@ use matrix_sdk::{
         types::EventEncryptionAlgorithm,
     },
     encryption::{
-        backups::{futures::SteadyStateError, BackupState, UploadState},
+        backups::{futures::SteadyStateError, BackupState, DownloadProgress, UploadState},
         secret_storage::SecretStore,
         BackupDownloadStrategy, EncryptionSettings,
     },
@@ -44,13 +49,13 @@ use ruma::{
 };
 use serde_json::{json, Value};
 use tempfile::tempdir;
-use tokio::spawn;
+use tokio::{spawn, sync::mpsc};
 use vodozemac::{
     olm::IdentityKeys, Curve25519PublicKey, Curve25519SecretKey, Ed25519PublicKey, Ed25519SecretKey,
 };
 use wiremock::{
     matchers::{header, method, path, path_regex},
-    Mock, ResponseTemplate,
+    Mock, Request, ResponseTemplate,
 };
 
 use crate::{
@@ -987,43 +992,27 @@ async fn test_enable_from_secret_storage() {
 
     mock_query_key_backup(&server).await;
 
+    // The room we know about covers the whole backup, which isn't downloaded at
+    // once.
     Mock::given(method("GET"))
-        .and(path("_matrix/client/r0/room_keys/keys"))
+        .and(path(format!("_matrix/client/r0/room_keys/keys/{room_id}")))
         .and(header("authorization", "Bearer 1234"))
         .respond_with(ResponseTemplate::new(200).set_body_json(json!({
-            "rooms": {
-                room_id: {
-                    "sessions": {
-                        "64H7XKokIx0ASkYDHZKlT5zd/Zccz/cQspPNdvnNULA": {
-                            "first_message_index": 0,
-                            "forwarded_count": 0,
-                            "is_verified": true,
-                            "session_data": {
-                                "ciphertext": "UaxxJxPZN5jqhSoFw59s83KlK0k77KJRxowPUC3P2/bS+TIBXw2y\
-                                               qMHCpv01s+8mE95XU6RZO2/elktHiW1/mzx/2vqb4pFuARtj3rxF\
-                                               zCBO7cpVhmrSU6uKW9KH2HirZMZzyXLqr3v6xoOTe5roIF5scPR0\
-                                               cWxPcS/4+BZz4xGhGCVuTPFjWDszY1/iz4JAVosAF7XZLGh7aVhF\
-                                               +ciDDoaaqwkD2nnMUlGEl2uchWuZv7v2q9Pmmd+qzRCdLx5c+GK3\
-                                               OyT8qCSxubOvuSruwTliBl++drlMnh4vRO8UKPTuMNvEN89YKiSC\
-                                               MVzXVDCS6tnjligxUENYkyUqYCKdASLDFs1cCXJDED16oQGonkU8\
-                                               Lf7ccGg6XboJCmJfobrmDc3s/9IymtKaxquA2Vw2pW8Otoy4x9PK\
-                                               17xHLo2nT2nf3Amp6xaCYx+tblGkLIqw8H3YZZVPVuKAVpPdAhgC\
-                                               +aJA9n8qow3BLcCJSdGRMSV9MquidGgbEA/DCd6Eq3jokshcXR4v\
-                                               Ma5nT4CokeZ6OdAtMWgZSaGltyNNoc+b6hk6AqcYaoMslG58DC32\
-                                               EVSiFFwtSpKx7I6+J+hlV813Vx6IK0DoqTcYyVm4kFMvKnIoyAKJ\
-                                               yoCSik4NQpL7DcokDhs56UJ1LcDgQTnGLqhH2Q",
-                                "ephemeral": "+KmnQw7ECkCD+s2Hc0hhntT8n9zTLJvFHgX7g3XKBjs",
-                                "mac": "xdzih3IkRv4"
-                            }
-                        }
-                    }
-                }
+            "sessions": {
+                "64H7XKokIx0ASkYDHZKlT5zd/Zccz/cQspPNdvnNULA": backed_up_room_key()
             }
         })))
         .expect(1)
         .mount(&server)
         .await;
 
+    Mock::given(method("GET"))
+        .and(path("_matrix/client/r0/room_keys/keys"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "rooms": {} })))
+        .expect(0)
+        .mount(&server)
+        .await;
+
     let room_key_stream = client.encryption().backups().room_keys_for_room_stream(room_id);
     pin_mut!(room_key_stream);
 
@@ -1421,6 +1410,421 @@ async fn test_enable_from_secret_storage_and_download_after_utd() {
     server.verify().await;
 }
 
+#[async_test]
+async fn test_download_all_room_keys_downloads_the_known_rooms_one_by_one() {
+    let user_id = user_id!("@example2:morpheus.localhost");
+    let room_id = room_id!("!DovneieKSTkdHKpIXy:morpheus.localhost");
+    let other_room_id = room_id!("!other:morpheus.localhost");
+
+    let session = MatrixSession {
+        meta: SessionMeta { user_id: user_id.into(), device_id: device_id!("DEVICEID").to_owned() },
+        tokens: MatrixSessionTokens { access_token: "1234".to_owned(), refresh_token: None },
+    };
+    let (builder, server) = test_client_builder_with_server().await;
+    let client =
+        builder.request_config(RequestConfig::new().disable_retry()).build().await.unwrap();
+
+    client.restore_session(session).await.unwrap();
+
+    let sync = SyncResponseBuilder::new()
+        .add_joined_room(JoinedRoomBuilder::new(room_id))
+        .add_joined_room(JoinedRoomBuilder::new(other_room_id))
+        .build_json_sync_response();
+    mock_sync(&server, sync, None).await;
+
+    client.sync_once(Default::default()).await.expect("We should be able to sync with the server");
+
+    init_client_secret_storage_and_backup(&client, &server).await;
+
+    // The whole backup is never downloaded at once.
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/room_keys/keys"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "rooms": {} })))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path(format!("/_matrix/client/r0/room_keys/keys/{room_id}")))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "sessions": {
+                "64H7XKokIx0ASkYDHZKlT5zd/Zccz/cQspPNdvnNULA": {
+                    "first_message_index": 0,
+                    "forwarded_count": 0,
+                    "is_verified": true,
+                    "session_data": {
+                        "ciphertext": "UaxxJxPZN5jqhSoFw59s83KlK0k77KJRxowPUC3P2/bS+TIBXw2y\
+                                       qMHCpv01s+8mE95XU6RZO2/elktHiW1/mzx/2vqb4pFuARtj3rxF\
+                                       zCBO7cpVhmrSU6uKW9KH2HirZMZzyXLqr3v6xoOTe5roIF5scPR0\
+                                       cWxPcS/4+BZz4xGhGCVuTPFjWDszY1/iz4JAVosAF7XZLGh7aVhF\
+                                       +ciDDoaaqwkD2nnMUlGEl2uchWuZv7v2q9Pmmd+qzRCdLx5c+GK3\
+                                       OyT8qCSxubOvuSruwTliBl++drlMnh4vRO8UKPTuMNvEN89YKiSC\
+                                       MVzXVDCS6tnjligxUENYkyUqYCKdASLDFs1cCXJDED16oQGonkU8\
+                                       Lf7ccGg6XboJCmJfobrmDc3s/9IymtKaxquA2Vw2pW8Otoy4x9PK\
+                                       17xHLo2nT2nf3Amp6xaCYx+tblGkLIqw8H3YZZVPVuKAVpPdAhgC\
+                                       +aJA9n8qow3BLcCJSdGRMSV9MquidGgbEA/DCd6Eq3jokshcXR4v\
+                                       Ma5nT4CokeZ6OdAtMWgZSaGltyNNoc+b6hk6AqcYaoMslG58DC32\
+                                       EVSiFFwtSpKx7I6+J+hlV813Vx6IK0DoqTcYyVm4kFMvKnIoyAKJ\
+                                       yoCSik4NQpL7DcokDhs56UJ1LcDgQTnGLqhH2Q",
+                        "ephemeral": "+KmnQw7ECkCD+s2Hc0hhntT8n9zTLJvFHgX7g3XKBjs",
+                        "mac": "xdzih3IkRv4"
+                    }
+                }
+            }
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    // The backup doesn't contain any room keys for the other room.
+    Mock::given(method("GET"))
+        .and(path(format!("/_matrix/client/r0/room_keys/keys/{other_room_id}")))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+            "errcode": "M_NOT_FOUND",
+            "error": "No room_keys found"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let backups = client.encryption().backups();
+    backups.download_all_room_keys().await.expect("We should be able to download the room keys");
+
+    let progress_stream = backups.download_progress_stream();
+    pin_mut!(progress_stream);
+
+    let progress = progress_stream.next().await.unwrap().unwrap();
+    assert_eq!(
+        progress,
+        DownloadProgress { rooms_done: 2, total_rooms: 2, keys_imported: 1, keys_not_covered: 0 }
+    );
+
+    server.verify().await;
+}
+
+#[async_test]
+async fn test_download_all_room_keys_reports_the_room_keys_of_unknown_rooms() {
+    let user_id = user_id!("@example2:morpheus.localhost");
+    let room_id = room_id!("!DovneieKSTkdHKpIXy:morpheus.localhost");
+
+    let session = MatrixSession {
+        meta: SessionMeta { user_id: user_id.into(), device_id: device_id!("DEVICEID").to_owned() },
+        tokens: MatrixSessionTokens { access_token: "1234".to_owned(), refresh_token: None },
+    };
+    let (builder, server) = test_client_builder_with_server().await;
+    let client =
+        builder.request_config(RequestConfig::new().disable_retry()).build().await.unwrap();
+
+    client.restore_session(session).await.unwrap();
+
+    init_client_secret_storage_and_backup(&client, &server).await;
+
+    // We don't know about any room yet, but the whole backup is never downloaded
+    // at once.
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/room_keys/keys"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "rooms": {} })))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let backups = client.encryption().backups();
+    backups.download_all_room_keys().await.expect("We should be able to download the room keys");
+
+    {
+        let progress_stream = backups.download_progress_stream();
+        pin_mut!(progress_stream);
+
+        // The room key of the backup is reported as not covered.
+        let progress = progress_stream.next().await.unwrap().unwrap();
+        assert_eq!(
+            progress,
+            DownloadProgress {
+                rooms_done: 0,
+                total_rooms: 0,
+                keys_imported: 0,
+                keys_not_covered: 1
+            }
+        );
+    }
+
+    // Once we know about the room, downloading again covers it.
+    let sync = SyncResponseBuilder::new()
+        .add_joined_room(JoinedRoomBuilder::new(room_id))
+        .build_json_sync_response();
+    mock_sync(&server, sync, None).await;
+    client.sync_once(Default::default()).await.expect("We should be able to sync with the server");
+
+    Mock::given(method("GET"))
+        .and(path(format!("/_matrix/client/r0/room_keys/keys/{room_id}")))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "sessions": {
+                "64H7XKokIx0ASkYDHZKlT5zd/Zccz/cQspPNdvnNULA": backed_up_room_key()
+            }
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    backups.download_all_room_keys().await.expect("We should be able to download the room keys");
+
+    let progress_stream = backups.download_progress_stream();
+    pin_mut!(progress_stream);
+
+    let progress = progress_stream.next().await.unwrap().unwrap();
+    assert_eq!(
+        progress,
+        DownloadProgress { rooms_done: 1, total_rooms: 1, keys_imported: 1, keys_not_covered: 0 }
+    );
+
+    server.verify().await;
+}
+
+/// Several undecryptable events using the same room key should result in a
+/// single download from the backup.
+#[async_test]
+async fn test_download_after_utd_is_deduplicated_per_room_key() {
+    let user_id = user_id!("@example2:morpheus.localhost");
+    let room_id = room_id!("!DovneieKSTkdHKpIXy:morpheus.localhost");
+    let event_id = event_id!("$JbFHtZpEJiH8uaajZjPLz0QUZc1xtBR9rPGBOjF6WFM");
+    let other_event_id = event_id!("$other_event_with_the_same_room_key");
+
+    let session = MatrixSession {
+        meta: SessionMeta { user_id: user_id.into(), device_id: device_id!("DEVICEID").to_owned() },
+        tokens: MatrixSessionTokens { access_token: "1234".to_owned(), refresh_token: None },
+    };
+    let (builder, server) = test_client_builder_with_server().await;
+    let encryption_settings = EncryptionSettings {
+        backup_download_strategy: BackupDownloadStrategy::AfterDecryptionFailure,
+        ..Default::default()
+    };
+    let client = builder
+        .request_config(RequestConfig::new().disable_retry())
+        .with_encryption_settings(encryption_settings)
+        .build()
+        .await
+        .unwrap();
+
+    client.restore_session(session).await.unwrap();
+
+    let sync = SyncResponseBuilder::new()
+        .add_joined_room(JoinedRoomBuilder::new(room_id))
+        .build_json_sync_response();
+    mock_sync(&server, sync, None).await;
+
+    client.sync_once(Default::default()).await.expect("We should be able to sync with the server");
+
+    init_client_secret_storage_and_backup(&client, &server).await;
+
+    let event_content = json!({
+        "algorithm": "m.megolm.v1.aes-sha2",
+        "ciphertext": "AwgAEpABhetEzzZzyYrxtEVUtlJnZtJcURBlQUQJ9irVeklCTs06LwgTMQj61PMUS4Vy\
+                       YOX+PD67+hhU40/8olOww+Ud0m2afjMjC3wFX+4fFfSkoWPVHEmRVucfcdSF1RSB4EmK\
+                       PIP4eo1X6x8kCIMewBvxl2sI9j4VNvDvAN7M3zkLJfFLOFHbBviI4FN7hSFHFeM739Zg\
+                       iwxEs3hIkUXEiAfrobzaMEM/zY7SDrTdyffZndgJo7CZOVhoV6vuaOhmAy4X2t4UnbuV\
+                       JGJjKfV57NAhp8W+9oT7ugwO",
+        "device_id": "KIUVQQSDTM",
+        "sender_key": "LvryVyoCjdONdBCi2vvoSbI34yTOx7YrCFACUEKoXnc",
+        "session_id": "64H7XKokIx0ASkYDHZKlT5zd/Zccz/cQspPNdvnNULA"
+    });
+
+    mock_get_event(room_id, event_id, event_content.clone(), &server).await;
+    mock_get_event(room_id, other_event_id, event_content, &server).await;
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/r0/room_keys/keys/!DovneieKSTkdHKpIXy:morpheus.localhost/64H7XKokIx0ASkYDHZKlT5zd%2FZccz%2FcQspPNdvnNULA"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "first_message_index": 0,
+            "forwarded_count": 0,
+            "is_verified": true,
+            "session_data": {
+                "ciphertext": "UaxxJxPZN5jqhSoFw59s83KlK0k77KJRxowPUC3P2/bS+TIBXw2y\
+                               qMHCpv01s+8mE95XU6RZO2/elktHiW1/mzx/2vqb4pFuARtj3rxF\
+                               zCBO7cpVhmrSU6uKW9KH2HirZMZzyXLqr3v6xoOTe5roIF5scPR0\
+                               cWxPcS/4+BZz4xGhGCVuTPFjWDszY1/iz4JAVosAF7XZLGh7aVhF\
+                               +ciDDoaaqwkD2nnMUlGEl2uchWuZv7v2q9Pmmd+qzRCdLx5c+GK3\
+                               OyT8qCSxubOvuSruwTliBl++drlMnh4vRO8UKPTuMNvEN89YKiSC\
+                               MVzXVDCS6tnjligxUENYkyUqYCKdASLDFs1cCXJDED16oQGonkU8\
+                               Lf7ccGg6XboJCmJfobrmDc3s/9IymtKaxquA2Vw2pW8Otoy4x9PK\
+                               17xHLo2nT2nf3Amp6xaCYx+tblGkLIqw8H3YZZVPVuKAVpPdAhgC\
+                               +aJA9n8qow3BLcCJSdGRMSV9MquidGgbEA/DCd6Eq3jokshcXR4v\
+                               Ma5nT4CokeZ6OdAtMWgZSaGltyNNoc+b6hk6AqcYaoMslG58DC32\
+                               EVSiFFwtSpKx7I6+J+hlV813Vx6IK0DoqTcYyVm4kFMvKnIoyAKJ\
+                               yoCSik4NQpL7DcokDhs56UJ1LcDgQTnGLqhH2Q",
+                "ephemeral": "+KmnQw7ECkCD+s2Hc0hhntT8n9zTLJvFHgX7g3XKBjs",
+                "mac": "xdzih3IkRv4"
+            }
+        })))
+        // Both events use the same room key, so it should be downloaded only once.
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let room_key_stream = client.encryption().backups().room_keys_for_room_stream(room_id);
+    pin_mut!(room_key_stream);
+
+    let room = client.get_room(room_id).expect("We should have access to the room after the sync");
+
+    for event_id in [event_id, other_event_id] {
+        let event = room
+            .event(event_id, None)
+            .await
+            .expect("We should be able to fetch our encrypted event");
+
+        assert_matches!(
+            event.encryption_info(),
+            None,
+            "We should not be able to decrypt the event right away"
+        );
+    }
+
+    // Wait for the key to be downloaded from backup.
+    timeout(room_key_stream.next(), Duration::from_secs(5))
+        .await
+        .expect("did not get a room key stream update within 5 seconds")
+        .expect("room_key_stream.next() returned None")
+        .expect("room_key_stream.next() returned an error");
+
+    for event_id in [event_id, other_event_id] {
+        let event = room
+            .event(event_id, None)
+            .await
+            .expect("We should be able to fetch our encrypted event");
+
+        assert_matches!(event.encryption_info(), Some(..), "The event should now be decrypted");
+    }
+
+    server.verify().await;
+}
+
+/// A burst of undecryptable events using different room keys should not result
+/// in more than four room keys being downloaded from the backup at the same
+/// time.
+#[async_test]
+async fn test_download_after_utd_is_rate_limited() {
+    let user_id = user_id!("@example2:morpheus.localhost");
+    let room_id = room_id!("!DovneieKSTkdHKpIXy:morpheus.localhost");
+
+    let session = MatrixSession {
+        meta: SessionMeta { user_id: user_id.into(), device_id: device_id!("DEVICEID").to_owned() },
+        tokens: MatrixSessionTokens { access_token: "1234".to_owned(), refresh_token: None },
+    };
+    let (builder, server) = test_client_builder_with_server().await;
+    let encryption_settings = EncryptionSettings {
+        backup_download_strategy: BackupDownloadStrategy::AfterDecryptionFailure,
+        ..Default::default()
+    };
+    let client = builder
+        .request_config(RequestConfig::new().disable_retry())
+        .with_encryption_settings(encryption_settings)
+        .build()
+        .await
+        .unwrap();
+
+    client.restore_session(session).await.unwrap();
+
+    let sync = SyncResponseBuilder::new()
+        .add_joined_room(JoinedRoomBuilder::new(room_id))
+        .build_json_sync_response();
+    mock_sync(&server, sync, None).await;
+
+    client.sync_once(Default::default()).await.expect("We should be able to sync with the server");
+
+    init_client_secret_storage_and_backup(&client, &server).await;
+
+    // Every room key download takes a while, so the downloads pile up. Record when
+    // each of them reaches the homeserver.
+    let response_delay = Duration::from_secs(2);
+    let (download_sender, mut download_receiver) = mpsc::unbounded_channel();
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/room_keys/keys/[^/]+/[^/]+$"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(move |_: &Request| {
+            let _ = download_sender.send(Instant::now());
+
+            ResponseTemplate::new(404)
+                .set_body_json(json!({
+                    "errcode": "M_NOT_FOUND",
+                    "error": "No room key found",
+                }))
+                .set_delay(response_delay)
+        })
+        .expect(5)
+        .mount(&server)
+        .await;
+
+    let room = client.get_room(room_id).expect("We should have access to the room after the sync");
+
+    // Five undecryptable events, each one using a different room key.
+    for i in 0..5 {
+        let event_id = EventId::parse(format!("$event{i}:morpheus.localhost")).unwrap();
+
+        Mock::given(method("GET"))
+            .and(path(format!("_matrix/client/r0/rooms/{room_id}/event/{event_id}")))
+            .and(header("authorization", "Bearer 1234"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "content": {
+                    "algorithm": "m.megolm.v1.aes-sha2",
+                    "ciphertext": "AwgAEpABhetEzzZzyYrxtEVUtlJnZtJcURBlQUQJ9irVeklCTs06LwgTMQj61PMUS4Vy\
+                                   YOX+PD67+hhU40/8olOww+Ud0m2afjMjC3wFX+4fFfSkoWPVHEmRVucfcdSF1RSB4EmK\
+                                   PIP4eo1X6x8kCIMewBvxl2sI9j4VNvDvAN7M3zkLJfFLOFHbBviI4FN7hSFHFeM739Zg\
+                                   iwxEs3hIkUXEiAfrobzaMEM/zY7SDrTdyffZndgJo7CZOVhoV6vuaOhmAy4X2t4UnbuV\
+                                   JGJjKfV57NAhp8W+9oT7ugwO",
+                    "device_id": "KIUVQQSDTM",
+                    "sender_key": "LvryVyoCjdONdBCi2vvoSbI34yTOx7YrCFACUEKoXnc",
+                    "session_id": format!("session{i}"),
+                },
+                "event_id": event_id,
+                "origin_server_ts": 1698579035927u64,
+                "sender": "@example2:morpheus.localhost",
+                "type": "m.room.encrypted",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let event = room
+            .event(&event_id, None)
+            .await
+            .expect("We should be able to fetch our encrypted event");
+
+        assert_matches!(
+            event.encryption_info(),
+            None,
+            "We should not be able to decrypt the event right away"
+        );
+    }
+
+    // Wait for all the downloads to reach the homeserver.
+    let mut download_starts = Vec::new();
+    for _ in 0..5 {
+        let start = timeout(download_receiver.recv(), response_delay * 5)
+            .await
+            .expect("All the room keys should eventually be downloaded")
+            .unwrap();
+        download_starts.push(start);
+    }
+
+    // A download only starts once another one finished, i.e. once its response
+    // delay elapsed, so no more than four of them can start within that delay.
+    for &start in &download_starts {
+        let concurrent_downloads = download_starts
+            .iter()
+            .filter(|&&other| other >= start && other < start + response_delay)
+            .count();
+        assert!(
+            concurrent_downloads <= 4,
+            "At most four room keys should be downloaded at the same time"
+        );
+    }
+
+    server.verify().await;
+}
+
 /// Even if we have a key to the session, we should still attempt a backup
 /// download if the UTD message has a lower megolm ratchet index than we have.
 #[async_test]
@@ -1617,11 +2021,12 @@ async fn mock_get_event(
         .await;
 }
 
-/// Add a mock for a `GET /_matrix/client/r0/room_keys/version` request; return
-/// some suitable backup data.
+/// Add a mock for the `GET /_matrix/client/r0/room_keys/version` and `GET
+/// /_matrix/client/r0/room_keys/version/6` requests; return some suitable
+/// backup data.
 async fn mock_query_key_backup(server: &wiremock::MockServer) {
     Mock::given(method("GET"))
-        .and(path("_matrix/client/r0/room_keys/version"))
+        .and(path_regex(r"^/_matrix/client/r0/room_keys/version(/6)?$"))
         .and(header("authorization", "Bearer 1234"))
         .respond_with(ResponseTemplate::new(200).set_body_json(json!({
             "algorithm": "m.megolm_backup.v1.curve25519-aes-sha2",
@@ -1637,6 +2042,33 @@ async fn mock_query_key_backup(server: &wiremock::MockServer) {
         .await;
 }
 
+/// The content of the room key of the `64H7XKokIx0ASkYDHZKlT5zd/Zccz/cQspPNdvnNULA`
+/// session, as stored in the backup mocked by [`mock_query_key_backup`].
+fn backed_up_room_key() -> Value {
+    json!({
+        "first_message_index": 0,
+        "forwarded_count": 0,
+        "is_verified": true,
+        "session_data": {
+            "ciphertext": "UaxxJxPZN5jqhSoFw59s83KlK0k77KJRxowPUC3P2/bS+TIBXw2y\
+                           qMHCpv01s+8mE95XU6RZO2/elktHiW1/mzx/2vqb4pFuARtj3rxF\
+                           zCBO7cpVhmrSU6uKW9KH2HirZMZzyXLqr3v6xoOTe5roIF5scPR0\
+                           cWxPcS/4+BZz4xGhGCVuTPFjWDszY1/iz4JAVosAF7XZLGh7aVhF\
+                           +ciDDoaaqwkD2nnMUlGEl2uchWuZv7v2q9Pmmd+qzRCdLx5c+GK3\
+                           OyT8qCSxubOvuSruwTliBl++drlMnh4vRO8UKPTuMNvEN89YKiSC\
+                           MVzXVDCS6tnjligxUENYkyUqYCKdASLDFs1cCXJDED16oQGonkU8\
+                           Lf7ccGg6XboJCmJfobrmDc3s/9IymtKaxquA2Vw2pW8Otoy4x9PK\
+                           17xHLo2nT2nf3Amp6xaCYx+tblGkLIqw8H3YZZVPVuKAVpPdAhgC\
+                           +aJA9n8qow3BLcCJSdGRMSV9MquidGgbEA/DCd6Eq3jokshcXR4v\
+                           Ma5nT4CokeZ6OdAtMWgZSaGltyNNoc+b6hk6AqcYaoMslG58DC32\
+                           EVSiFFwtSpKx7I6+J+hlV813Vx6IK0DoqTcYyVm4kFMvKnIoyAKJ\
+                           yoCSik4NQpL7DcokDhs56UJ1LcDgQTnGLqhH2Q",
+            "ephemeral": "+KmnQw7ECkCD+s2Hc0hhntT8n9zTLJvFHgX7g3XKBjs",
+            "mac": "xdzih3IkRv4"
+        }
+    })
+}
+
 /// Encrypt the given session with the backup key, and add a mock for a `GET
 /// /_matrix/client/r0/room_keys/keys/{}/{}` request which will return it.
 async fn mock_download_session_from_key_backup(
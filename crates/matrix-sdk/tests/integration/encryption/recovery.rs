@@ -12,12 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::BTreeMap,
+    iter,
+    sync::{Arc, Mutex},
+};
 
 use assert_matches2::assert_let;
 use futures_util::StreamExt;
 use matrix_sdk::{
     config::RequestConfig,
+    crypto::{EncryptionSettings, OlmMachine},
     encryption::{
         backups::BackupState,
         recovery::{EnableProgress, RecoveryState},
@@ -29,7 +34,17 @@ use matrix_sdk::{
 };
 use matrix_sdk_base::SessionMeta;
 use matrix_sdk_test::async_test;
-use ruma::{api::client::uiaa, device_id, user_id, UserId};
+use ruma::{
+    api::client::{
+        keys::{claim_keys, get_keys},
+        uiaa,
+    },
+    device_id,
+    encryption::OneTimeKey,
+    room_id,
+    serde::Raw,
+    user_id, OwnedDeviceId, OwnedOneTimeKeyId, UserId,
+};
 use serde::Deserialize;
 use serde_json::{json, Value};
 use tokio::spawn;
@@ -53,6 +68,8 @@ async fn test_client(user_id: &UserId) -> (Client, wiremock::MockServer) {
             auto_enable_cross_signing: true,
             backup_download_strategy: BackupDownloadStrategy::Manual,
             auto_enable_backups: true,
+            share_history_on_invite: false,
+            encrypt_state_events: false,
         })
         .build()
         .await
@@ -469,6 +486,196 @@ async fn test_recovery_setup_without_wait() {
     server.verify().await
 }
 
+/// Enable recovery, which creates a dehydrated device as well, and return the
+/// dehydrated device that got uploaded.
+async fn enable_with_dehydrated_device(
+    user_id: &UserId,
+    client: &Client,
+    server: &wiremock::MockServer,
+) -> Value {
+    let dehydrated_device = Arc::new(Mutex::new(None));
+
+    Mock::given(method("PUT"))
+        .and(path("_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device"))
+        .and(header("authorization", "Bearer 1234"))
+        .and({
+            let dehydrated_device = dehydrated_device.clone();
+            move |request: &wiremock::Request| {
+                let content: Value = request.body_json().expect("The body should be a JSON body");
+                *dehydrated_device.lock().unwrap() = Some(content);
+
+                true
+            }
+        })
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "device_id": "DEHYDRATED",
+        })))
+        .expect(1)
+        .named("dehydrated device PUT")
+        .mount(&server)
+        .await;
+
+    Mock::given(method("GET"))
+        .and(path(format!("_matrix/client/r0/user/{user_id}/account_data/org.matrix.msc3814")))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+            "errcode": "M_NOT_FOUND",
+            "error": "Account data not found"
+        })))
+        .expect(2)
+        .named("dehydrated device pickle key GET")
+        .mount(&server)
+        .await;
+
+    Mock::given(method("PUT"))
+        .and(path(format!("_matrix/client/r0/user/{user_id}/account_data/org.matrix.msc3814")))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .named("dehydrated device pickle key PUT")
+        .mount(server)
+        .await;
+
+    enable(user_id, client, server, false).await;
+
+    let dehydrated_device = dehydrated_device.lock().unwrap().take();
+    dehydrated_device.expect("A dehydrated device should have been uploaded")
+}
+
+/// Serve the given dehydrated device, and the given to-device events it
+/// received, in a single batch.
+async fn mock_dehydrated_device(
+    server: &wiremock::MockServer,
+    dehydrated_device: &Value,
+    events: Vec<Value>,
+) {
+    let device_id = dehydrated_device["device_id"].as_str().unwrap();
+
+    Mock::given(method("GET"))
+        .and(path("_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "device_id": device_id,
+            "device_data": dehydrated_device["device_data"],
+        })))
+        .expect(1)
+        .named("dehydrated device GET")
+        .mount(server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path(format!(
+            "_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device/{device_id}/events"
+        )))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "events": events,
+        })))
+        .expect(1)
+        .named("dehydrated device events POST")
+        .mount(server)
+        .await;
+}
+
+#[async_test]
+async fn test_recovery_setup_with_dehydrated_device() {
+    let user_id = user_id!("@example:morpheus.localhost");
+    let (client, server) = test_client(user_id).await;
+
+    let dehydrated_device = enable_with_dehydrated_device(user_id, &client, &server).await;
+
+    let dehydrated_devices = client.encryption().dehydrated_devices();
+    assert!(dehydrated_devices.has_pickle_key().await.unwrap());
+
+    // The dehydrated device we uploaded can be rehydrated.
+    mock_dehydrated_device(&server, &dehydrated_device, Vec::new()).await;
+
+    let imported_room_keys = dehydrated_devices.rehydrate().await.unwrap();
+    assert_eq!(imported_room_keys, 0);
+
+    server.verify().await
+}
+
+#[async_test]
+async fn test_rehydration_imports_the_room_keys_of_the_dehydrated_device() {
+    let user_id = user_id!("@example:morpheus.localhost");
+    let room_id = room_id!("!test:morpheus.localhost");
+    let (client, server) = test_client(user_id).await;
+
+    let dehydrated_device = enable_with_dehydrated_device(user_id, &client, &server).await;
+    let device_id: OwnedDeviceId = dehydrated_device["device_id"].as_str().unwrap().into();
+
+    // Bob learns about the dehydrated device and claims one of its one-time keys.
+    let bob = OlmMachine::new(user_id!("@bob:morpheus.localhost"), device_id!("BOBDEVICE")).await;
+
+    bob.update_tracked_users(iter::once(user_id)).await.unwrap();
+    let (request_id, _) = bob.query_keys_for_users(iter::once(user_id));
+    let mut keys_query_response = get_keys::v3::Response::new();
+    keys_query_response.device_keys = BTreeMap::from([(
+        user_id.to_owned(),
+        BTreeMap::from([(
+            device_id.clone(),
+            serde_json::from_value(dehydrated_device["device_keys"].clone()).unwrap(),
+        )]),
+    )]);
+    bob.mark_request_as_sent(&request_id, &keys_query_response).await.unwrap();
+
+    let (request_id, _) = bob
+        .get_missing_sessions(iter::once(user_id))
+        .await
+        .unwrap()
+        .expect("Bob should claim a one-time key of the dehydrated device");
+    let one_time_keys: BTreeMap<OwnedOneTimeKeyId, Raw<OneTimeKey>> =
+        serde_json::from_value(dehydrated_device["one_time_keys"].clone()).unwrap();
+    let keys_claim_response = claim_keys::v3::Response::new(BTreeMap::from([(
+        user_id.to_owned(),
+        BTreeMap::from([(device_id.clone(), one_time_keys.into_iter().take(1).collect())]),
+    )]));
+    bob.mark_request_as_sent(&request_id, &keys_claim_response).await.unwrap();
+
+    // Bob sends a room key to the dehydrated device, while we're not around.
+    let requests = bob
+        .share_room_key(room_id, iter::once(user_id), EncryptionSettings::default())
+        .await
+        .unwrap();
+    let events: Vec<Value> = requests
+        .iter()
+        .flat_map(|request| {
+            request.messages.values().flat_map(BTreeMap::values).map(|content| {
+                json!({
+                    "content": content,
+                    "sender": bob.user_id(),
+                    "type": request.event_type,
+                })
+            })
+        })
+        .collect();
+    assert_eq!(events.len(), 1, "The room key should be sent to the dehydrated device");
+
+    // The homeserver doesn't send a batch token along with the events, we should
+    // stop asking for more events.
+    mock_dehydrated_device(&server, &dehydrated_device, events).await;
+
+    let imported_room_keys = client.encryption().dehydrated_devices().rehydrate().await.unwrap();
+    assert_eq!(imported_room_keys, 1);
+
+    let bob_room_keys =
+        bob.store().export_room_keys(|session| session.room_id() == room_id).await.unwrap();
+    let session_id = &bob_room_keys[0].session_id;
+
+    let olm_machine = client.olm_machine_for_testing().await;
+    let room_key = olm_machine
+        .as_ref()
+        .unwrap()
+        .store()
+        .get_inbound_group_session(room_id, session_id)
+        .await
+        .unwrap();
+    assert!(room_key.is_some(), "The room key of Bob should have been imported");
+
+    server.verify().await
+}
+
 #[async_test]
 async fn test_backups_enabling() {
     let user_id = user_id!("@example:morpheus.localhost");
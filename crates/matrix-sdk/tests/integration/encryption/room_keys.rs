@@ -0,0 +1,82 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use matrix_sdk::test_utils::logged_in_client_with_server;
+use matrix_sdk_test::{
+    async_test, JoinedRoomBuilder, StateTestEvent, SyncResponseBuilder, DEFAULT_TEST_ROOM_ID,
+};
+use ruma::events::room::message::RoomMessageEventContent;
+use serde_json::json;
+use wiremock::{
+    matchers::{header, method, path_regex},
+    Mock, ResponseTemplate,
+};
+
+use crate::mock_sync;
+
+#[async_test]
+async fn test_outbound_group_session_info() {
+    let (client, server) = logged_in_client_with_server().await;
+
+    let sync = SyncResponseBuilder::new()
+        .add_joined_room(
+            JoinedRoomBuilder::default()
+                .add_state_event(StateTestEvent::Member)
+                .add_state_event(StateTestEvent::PowerLevels)
+                .add_state_event(StateTestEvent::Encryption),
+        )
+        .build_json_sync_response();
+    mock_sync(&server, sync, None).await;
+    client.sync_once(Default::default()).await.unwrap();
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/members"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "chunk": [] })))
+        .mount(&server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/r0/keys/query"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "device_keys": {} })))
+        .mount(&server)
+        .await;
+
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/send/m.room.encrypted/"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "event_id": "$event" })))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let room = client.get_room(&DEFAULT_TEST_ROOM_ID).unwrap();
+
+    // No room key was created yet.
+    assert!(room.outbound_group_session_info().await.unwrap().is_none());
+
+    room.send(RoomMessageEventContent::text_plain("Hello")).await.unwrap();
+    room.send(RoomMessageEventContent::text_plain("World")).await.unwrap();
+
+    let info = room.outbound_group_session_info().await.unwrap().unwrap();
+    assert_eq!(info.room_id, *DEFAULT_TEST_ROOM_ID);
+    assert_eq!(info.message_count, 2);
+    assert!(!info.invalidated);
+
+    room.discard_room_key().await.unwrap();
+
+    let info = room.outbound_group_session_info().await.unwrap().unwrap();
+    assert!(info.invalidated);
+}
@@ -1,4 +1,7 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
 
 use assert_matches::assert_matches;
 use matrix_sdk::{
@@ -18,7 +21,7 @@ use ruma::{
     },
     user_id, UserId,
 };
-use serde_json::json;
+use serde_json::{json, Value};
 use wiremock::{
     matchers::{header, method, path, path_regex},
     Mock, MockServer, ResponseTemplate,
@@ -663,3 +666,200 @@ async fn test_is_secret_storage_enabled() {
         );
     }
 }
+
+/// Mock the account data endpoints of the server with an in-memory store of
+/// the account data events.
+async fn mock_account_data_store(server: &MockServer) -> Arc<Mutex<BTreeMap<String, Value>>> {
+    let account_data: Arc<Mutex<BTreeMap<String, Value>>> = Default::default();
+
+    let event_type = |request: &wiremock::Request| {
+        request.url.path_segments().unwrap().last().unwrap().to_owned()
+    };
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"_matrix/client/r0/user/.*/account_data/.*"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with({
+            let account_data = account_data.to_owned();
+
+            move |request: &wiremock::Request| match account_data
+                .lock()
+                .unwrap()
+                .get(&event_type(request))
+            {
+                Some(content) => ResponseTemplate::new(200).set_body_json(content),
+                None => ResponseTemplate::new(404).set_body_json(json!({
+                    "errcode": "M_NOT_FOUND",
+                    "error": "Account data not found"
+                })),
+            }
+        })
+        .mount(server)
+        .await;
+
+    Mock::given(method("PUT"))
+        .and(path_regex(r"_matrix/client/r0/user/.*/account_data/.*"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with({
+            let account_data = account_data.to_owned();
+
+            move |request: &wiremock::Request| {
+                let content: Value = request.body_json().unwrap();
+                account_data.lock().unwrap().insert(event_type(request), content);
+
+                ResponseTemplate::new(200).set_body_json(json!({}))
+            }
+        })
+        .mount(server)
+        .await;
+
+    account_data
+}
+
+#[async_test]
+async fn test_rotate_secret_storage_key() {
+    let (client, server) = logged_in_client_with_server().await;
+    mock_account_data_store(&server).await;
+
+    let secret_storage = client.encryption().secret_storage();
+
+    let old_store = secret_storage
+        .create_secret_store()
+        .await
+        .expect("We should be able to create a new secret store");
+    old_store.put_secret("foo", "It's a secret to everybody").await.unwrap();
+
+    let new_store = old_store
+        .rotate_key()
+        .with_secrets(["foo"])
+        .await
+        .expect("We should be able to rotate the secret storage key");
+
+    assert_ne!(old_store.key_id(), new_store.key_id());
+    assert_eq!(
+        secret_storage.default_key_id().await.unwrap().as_deref(),
+        Some(new_store.key_id()),
+        "The new key should be the default key"
+    );
+
+    // The secret is now encrypted under both keys.
+    let mut key_ids = secret_storage.secret_key_ids("foo").await.unwrap();
+    key_ids.sort();
+    let mut expected_key_ids = vec![old_store.key_id().to_owned(), new_store.key_id().to_owned()];
+    expected_key_ids.sort();
+    assert_eq!(key_ids, expected_key_ids);
+
+    assert_eq!(
+        new_store.get_secret("foo").await.unwrap().as_deref(),
+        Some("It's a secret to everybody")
+    );
+
+    // The old key isn't the default one anymore, but it can still be used to read
+    // the secrets.
+    let reopened_old_store = secret_storage
+        .open_secret_store_with_key_id(old_store.key_id(), &old_store.secret_storage_key())
+        .await
+        .expect("We should be able to open the secret store of the old key");
+    assert_eq!(
+        reopened_old_store.get_secret("foo").await.unwrap().as_deref(),
+        Some("It's a secret to everybody")
+    );
+}
+
+#[async_test]
+async fn test_rotate_secret_storage_key_and_delete_old_key() {
+    let (client, server) = logged_in_client_with_server().await;
+    let account_data = mock_account_data_store(&server).await;
+
+    let secret_storage = client.encryption().secret_storage();
+
+    let old_store = secret_storage
+        .create_secret_store()
+        .await
+        .expect("We should be able to create a new secret store");
+    old_store.put_secret("foo", "It's a secret to everybody").await.unwrap();
+
+    let new_store = old_store
+        .rotate_key()
+        .with_passphrase("It's a new secret to everybody")
+        .with_secrets(["foo"])
+        .delete_old_key()
+        .await
+        .expect("We should be able to rotate the secret storage key");
+
+    assert_eq!(
+        secret_storage.secret_key_ids("foo").await.unwrap(),
+        vec![new_store.key_id().to_owned()],
+        "The secret should only be encrypted under the new key"
+    );
+
+    assert_eq!(
+        account_data
+            .lock()
+            .unwrap()
+            .get(&format!("m.secret_storage.key.{}", old_store.key_id()))
+            .unwrap(),
+        &json!({}),
+        "The description of the old key should have been emptied"
+    );
+
+    let new_store = secret_storage
+        .open_secret_store("It's a new secret to everybody")
+        .await
+        .expect("We should be able to open the secret store with the new passphrase");
+    assert_eq!(
+        new_store.get_secret("foo").await.unwrap().as_deref(),
+        Some("It's a secret to everybody")
+    );
+}
+
+#[async_test]
+async fn test_rotate_secret_storage_key_keeps_the_old_key_if_a_secret_would_be_lost() {
+    let (client, server) = logged_in_client_with_server().await;
+    let account_data = mock_account_data_store(&server).await;
+
+    let secret_storage = client.encryption().secret_storage();
+
+    let old_store = secret_storage
+        .create_secret_store()
+        .await
+        .expect("We should be able to create a new secret store");
+    old_store.put_secret("foo", "It's a secret to everybody").await.unwrap();
+
+    // The homeserver doesn't persist the re-encrypted secret.
+    Mock::given(method("PUT"))
+        .and(path_regex(r"_matrix/client/r0/user/.*/account_data/foo"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+
+    let result = old_store.rotate_key().with_secrets(["foo"]).delete_old_key().await;
+
+    assert_matches!(
+        result,
+        Err(SecretStorageError::SecretsNotReEncrypted { secret_names }) => {
+            assert_eq!(secret_names, vec![SecretName::from("foo")]);
+        }
+    );
+
+    assert_eq!(
+        secret_storage.default_key_id().await.unwrap().as_deref(),
+        Some(old_store.key_id()),
+        "The old key should still be the default key"
+    );
+    assert_ne!(
+        account_data
+            .lock()
+            .unwrap()
+            .get(&format!("m.secret_storage.key.{}", old_store.key_id()))
+            .unwrap(),
+        &json!({}),
+        "The description of the old key should have been kept"
+    );
+    assert_eq!(
+        old_store.get_secret("foo").await.unwrap().as_deref(),
+        Some("It's a secret to everybody")
+    );
+}
@@ -0,0 +1,104 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use matrix_sdk::{
+    config::RequestConfig,
+    encryption::EncryptionSettings,
+    test_utils::{set_client_session, test_client_builder_with_server},
+};
+use matrix_sdk_test::{
+    async_test, JoinedRoomBuilder, StateTestEvent, SyncResponseBuilder, DEFAULT_TEST_ROOM_ID,
+};
+use ruma::events::room::join_rules::{JoinRule, RoomJoinRulesEventContent};
+use serde_json::json;
+use wiremock::{
+    matchers::{body_partial_json, header, method, path_regex},
+    Mock, ResponseTemplate,
+};
+
+use crate::mock_sync;
+
+#[async_test]
+async fn test_state_events_are_encrypted_when_enabled() {
+    let (builder, server) = test_client_builder_with_server().await;
+    let client = builder
+        .request_config(RequestConfig::new().disable_retry())
+        .with_encryption_settings(EncryptionSettings {
+            encrypt_state_events: true,
+            ..Default::default()
+        })
+        .build()
+        .await
+        .unwrap();
+    set_client_session(&client).await;
+
+    let sync = SyncResponseBuilder::new()
+        .add_joined_room(
+            JoinedRoomBuilder::default()
+                .add_state_event(StateTestEvent::Member)
+                .add_state_event(StateTestEvent::PowerLevels)
+                .add_state_event(StateTestEvent::Encryption),
+        )
+        .build_json_sync_response();
+    mock_sync(&server, sync, None).await;
+    client.sync_once(Default::default()).await.unwrap();
+
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/members"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "chunk": [] })))
+        .mount(&server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/r0/keys/query"))
+        .and(header("authorization", "Bearer 1234"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "device_keys": {} })))
+        .mount(&server)
+        .await;
+
+    // The topic is sent as an encrypted state event, its type and state key are
+    // packed into the state key of the `m.room.encrypted` event.
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/r0/rooms/.*/state/m.room.encrypted/m.room.topic(:|%3A)$",
+        ))
+        .and(header("authorization", "Bearer 1234"))
+        .and(body_partial_json(json!({ "algorithm": "m.megolm.v1.aes-sha2" })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "event_id": "$topic" })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    // The join rules are needed by the server, so they're sent in the clear.
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/rooms/.*/state/m.room.join_rules/?$"))
+        .and(header("authorization", "Bearer 1234"))
+        .and(body_partial_json(json!({ "join_rule": "invite" })))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({ "event_id": "$join_rules" })),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let room = client.get_room(&DEFAULT_TEST_ROOM_ID).unwrap();
+
+    let response = room.set_room_topic("Secret plans").await.unwrap();
+    assert_eq!(response.event_id, "$topic");
+
+    let response =
+        room.send_state_event(RoomJoinRulesEventContent::new(JoinRule::Invite)).await.unwrap();
+    assert_eq!(response.event_id, "$join_rules");
+}
@@ -0,0 +1,219 @@
+// Copyright 2024 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::BTreeMap, iter};
+
+use as_variant::as_variant;
+use matrix_sdk::{
+    config::RequestConfig,
+    crypto::{
+        types::events::room_key_withheld::WithheldCode, CollectStrategy, EncryptionSyncChanges,
+        OlmMachine, OutgoingRequests,
+    },
+    test_utils::{set_client_session, test_client_builder_with_server},
+    Client,
+};
+use matrix_sdk_test::async_test;
+use ruma::{
+    api::client::keys::get_keys, device_id, serde::Raw, uint, user_id, OneTimeKeyAlgorithm,
+};
+use serde_json::{json, Value};
+use wiremock::{
+    matchers::{method, path, path_regex},
+    Mock, MockServer, ResponseTemplate,
+};
+
+/// Create the device of Bob, and let the homeserver serve its keys so an Olm
+/// session can be established with it.
+async fn mock_bob_device(server: &MockServer) -> OlmMachine {
+    let bob = OlmMachine::new(user_id!("@bob:example.org"), device_id!("BOBDEVICE")).await;
+
+    // Let Bob generate the one-time keys which are part of the keys upload.
+    let one_time_keys_counts = BTreeMap::from([(OneTimeKeyAlgorithm::SignedCurve25519, uint!(0))]);
+    bob.receive_sync_changes(EncryptionSyncChanges {
+        to_device_events: Vec::new(),
+        changed_devices: &Default::default(),
+        one_time_keys_counts: &one_time_keys_counts,
+        unused_fallback_keys: None,
+        next_batch_token: None,
+    })
+    .await
+    .unwrap();
+
+    let upload = bob
+        .outgoing_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .find_map(|r| as_variant!(r.request(), OutgoingRequests::KeysUpload(u) => u.clone()))
+        .expect("Bob should want to upload the device keys");
+    let device_keys = upload.device_keys.expect("The keys upload should contain the device keys");
+    let (key_id, one_time_key) =
+        upload.one_time_keys.into_iter().next().expect("Bob should have a one-time key");
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/keys/query"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "device_keys": { bob.user_id().as_str(): { bob.device_id().as_str(): device_keys } },
+        })))
+        .mount(server)
+        .await;
+
+    Mock::given(method("POST"))
+        .and(path("/_matrix/client/r0/keys/claim"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "one_time_keys": {
+                bob.user_id().as_str(): {
+                    bob.device_id().as_str(): { key_id.as_str(): one_time_key },
+                },
+            },
+        })))
+        .mount(server)
+        .await;
+
+    bob
+}
+
+/// Let Bob know about the device of the client, so the events it sends can be
+/// decrypted.
+async fn receive_client_device_keys(bob: &OlmMachine, client: &Client) {
+    let user_id = client.user_id().unwrap();
+    let device = client.encryption().get_own_device().await.unwrap().unwrap();
+
+    bob.update_tracked_users(iter::once(user_id)).await.unwrap();
+    let (request_id, _) = bob.query_keys_for_users(iter::once(user_id));
+
+    let mut response = get_keys::v3::Response::new();
+    response.device_keys = BTreeMap::from([(
+        user_id.to_owned(),
+        BTreeMap::from([(
+            device.device_id().to_owned(),
+            Raw::new(device.as_device_keys()).unwrap(),
+        )]),
+    )]);
+    bob.mark_request_as_sent(&request_id, &response).await.unwrap();
+}
+
+#[async_test]
+async fn test_encrypt_and_send_to_device() {
+    let (builder, server) = test_client_builder_with_server().await;
+    let client =
+        builder.request_config(RequestConfig::new().disable_retry()).build().await.unwrap();
+    set_client_session(&client).await;
+
+    let bob = mock_bob_device(&server).await;
+    receive_client_device_keys(&bob, &client).await;
+
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/sendToDevice/m.room.encrypted/.*"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    client.encryption().request_user_identity(bob.user_id()).await.unwrap();
+    let device =
+        client.encryption().get_device(bob.user_id(), bob.device_id()).await.unwrap().unwrap();
+    let content = Raw::new(&json!({ "secret": "It's a secret to everybody" })).unwrap().cast();
+
+    let failures = client
+        .encryption()
+        .encrypt_and_send_to_device(vec![&device], "org.example.secret", content)
+        .await
+        .unwrap();
+
+    assert!(failures.is_empty());
+    server.verify().await;
+
+    // The event was encrypted for Bob's device, using the Olm session we just
+    // established.
+    let requests = server.received_requests().await.unwrap();
+    let request = requests
+        .iter()
+        .find(|r| r.url.path().starts_with("/_matrix/client/r0/sendToDevice/"))
+        .unwrap();
+    let body: Value = request.body_json().unwrap();
+    let encrypted = &body["messages"][bob.user_id().as_str()][bob.device_id().as_str()];
+    assert_eq!(encrypted["algorithm"], "m.olm.v1.curve25519-aes-sha2");
+
+    let event = Raw::new(&json!({
+        "sender": client.user_id().unwrap(),
+        "type": "m.room.encrypted",
+        "content": encrypted,
+    }))
+    .unwrap()
+    .cast();
+    let (decrypted, _) = bob
+        .receive_sync_changes(EncryptionSyncChanges {
+            to_device_events: vec![event],
+            changed_devices: &Default::default(),
+            one_time_keys_counts: &Default::default(),
+            unused_fallback_keys: None,
+            next_batch_token: None,
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(decrypted.len(), 1);
+    let decrypted: Value = decrypted[0].deserialize_as().unwrap();
+    assert_eq!(decrypted["type"], "org.example.secret");
+    assert_eq!(decrypted["content"], json!({ "secret": "It's a secret to everybody" }));
+}
+
+#[async_test]
+async fn test_encrypt_and_send_to_device_skips_untrusted_devices() {
+    let (builder, server) = test_client_builder_with_server().await;
+    let client = builder
+        .request_config(RequestConfig::new().disable_retry())
+        .with_room_key_recipient_strategy(CollectStrategy::DeviceBasedStrategy {
+            only_allow_trusted_devices: true,
+            error_on_verified_user_problem: false,
+        })
+        .build()
+        .await
+        .unwrap();
+    set_client_session(&client).await;
+
+    let bob = mock_bob_device(&server).await;
+
+    // Nothing should be sent, since the only device isn't trusted enough.
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/r0/sendToDevice/m.room.encrypted/.*"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    // Bob's device isn't verified, so it isn't trusted enough.
+    client.encryption().request_user_identity(bob.user_id()).await.unwrap();
+    let device =
+        client.encryption().get_device(bob.user_id(), bob.device_id()).await.unwrap().unwrap();
+    let content = Raw::new(&json!({ "secret": "It's a secret to everybody" })).unwrap().cast();
+
+    let failures = client
+        .encryption()
+        .encrypt_and_send_to_device(vec![&device], "org.example.secret", content)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        failures,
+        BTreeMap::from([(
+            (bob.user_id().to_owned(), bob.device_id().to_owned()),
+            WithheldCode::Unverified
+        )])
+    );
+
+    server.verify().await;
+}
@@ -0,0 +1,82 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use matrix_sdk_crypto::{store::CryptoStore, OlmMachine};
+use matrix_sdk_sqlite::SqliteCryptoStore;
+
+/// A command line tool checking the integrity of an SQLite crypto store, and
+/// optionally repairing it.
+///
+/// Make sure that no client is using the store while it's being checked.
+#[derive(Parser, Debug)]
+struct Cli {
+    /// The directory containing the crypto store.
+    #[clap(value_parser)]
+    path: PathBuf,
+
+    /// The passphrase the store was encrypted with, if any.
+    #[clap(long)]
+    passphrase: Option<String>,
+
+    /// Repair the problems that were found, removing the entries which can't
+    /// be decoded anymore.
+    #[clap(long, action)]
+    repair: bool,
+
+    /// Enable verbose logging output.
+    #[clap(short, long, action)]
+    verbose: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    if cli.verbose {
+        tracing_subscriber::fmt::init();
+    }
+
+    let store = Arc::new(SqliteCryptoStore::open(&cli.path, cli.passphrase.as_deref()).await?);
+
+    let Some(account) = store.load_account().await? else {
+        bail!("The store at {} doesn't contain an account", cli.path.display());
+    };
+
+    let machine =
+        OlmMachine::with_store(account.user_id(), account.device_id(), store, None).await?;
+    let report = machine.check_store_integrity(cli.repair).await?;
+
+    for entry in &report.undecodable_entries {
+        println!("Undecodable entry ({:?}): {}", entry.kind, entry.error);
+    }
+
+    if !report.inbound_group_sessions_checked {
+        println!("The inbound group sessions couldn't be checked, run again with --repair");
+    }
+
+    for room_id in &report.orphaned_outbound_group_sessions {
+        println!("Outbound group session without a matching inbound group session in {room_id}");
+    }
+
+    println!("Olm sessions with unknown devices: {}", report.olm_sessions_from_unknown_devices);
+    println!(
+        "Inbound group sessions from unknown devices: {}",
+        report.inbound_group_sessions_from_unknown_devices
+    );
+    println!("Inbound group sessions with outdated sender data: {}", report.outdated_sender_data);
+    println!(
+        "Inbound group sessions wrongly marked as backed up: {}",
+        report.inconsistent_backup_flags
+    );
+
+    if !report.has_problems() {
+        println!("No problem found");
+    } else if report.repaired {
+        println!("The problems were repaired");
+    } else {
+        println!("Run again with --repair to repair the problems");
+    }
+
+    Ok(())
+}
@@ -836,6 +836,9 @@ impl App {
                     VirtualTimelineItem::ReadMarker => {
                         content.push("Read marker".to_owned());
                     }
+                    VirtualTimelineItem::IdentityChange { user_id, changed_to, .. } => {
+                        content.push(format!("Identity of {user_id} changed: {changed_to:?}"));
+                    }
                 },
             }
         }
@@ -962,6 +965,8 @@ async fn configure_client(server_name: String, config_path: String) -> anyhow::R
             auto_enable_cross_signing: true,
             backup_download_strategy: BackupDownloadStrategy::AfterDecryptionFailure,
             auto_enable_backups: true,
+            share_history_on_invite: false,
+            encrypt_state_events: false,
         });
 
     if let Ok(proxy_url) = env::var("PROXY") {
@@ -786,6 +786,8 @@ async fn test_secret_gossip_after_interactive_verification() -> Result<()> {
         auto_enable_cross_signing: true,
         auto_enable_backups: true,
         backup_download_strategy: BackupDownloadStrategy::OneShot,
+        share_history_on_invite: false,
+        encrypt_state_events: false,
     };
 
     let first_client = SyncTokenAwareClient::new(